@@ -0,0 +1,59 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use ors_fuzz::ansi::{Color, DecodeResult, Decoder, EscapeSequence, Sgr};
+
+fuzz_target!(|data: &[u8]| {
+    let mut decoder = Decoder::new();
+    for ch in String::from_utf8_lossy(data).chars() {
+        if let Some(result) = decoder.add_char(ch) {
+            check_result(result);
+            assert!(decoder.is_idle(), "{:?} is returned before completion", result);
+        }
+    }
+});
+
+fn check_result(result: DecodeResult) {
+    match result {
+        DecodeResult::Just(ch) => assert!(
+            matches!(ch, '\x08' | '\x09' | '\x0a' | '\x7f' | ' '..='~'),
+            "Unexpected character: {:?}",
+            ch
+        ),
+        DecodeResult::EscapeSequence(es) => check_escape_sequence(es),
+    }
+}
+
+fn check_escape_sequence(es: EscapeSequence) {
+    match es {
+        EscapeSequence::Sgr(a) => check_sgr(a),
+        EscapeSequence::Sgr2(a, b) => {
+            check_sgr(a);
+            check_sgr(b);
+        }
+        EscapeSequence::Sgr3(a, b, c) => {
+            check_sgr(a);
+            check_sgr(b);
+            check_sgr(c);
+        }
+        EscapeSequence::Function(n, _) => {
+            assert!((1..=12).contains(&n), "Function key out of range: {}", n)
+        }
+        _ => {}
+    }
+}
+
+fn check_sgr(sgr: Sgr) {
+    match sgr {
+        Sgr::Fg(c) | Sgr::Bg(c) => check_color(c),
+        _ => {}
+    }
+}
+
+fn check_color(color: Color) {
+    match color {
+        Color::Rgb(n) => assert!(n < 216, "Rgb out of range: {}", n),
+        Color::Grayscale(n) => assert!(n < 24, "Grayscale out of range: {}", n),
+        _ => {}
+    }
+}
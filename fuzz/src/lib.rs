@@ -0,0 +1,8 @@
+//! Host builds of kernel modules that can be fuzzed.
+//!
+//! These modules are included directly from the kernel crates, since `ors-common` is built with
+//! nightly features and the kernel itself is not buildable for the host. Each module must depend
+//! only on `core` (and `log`).
+
+#[path = "../../ors-common/src/term/ansi.rs"]
+pub mod ansi;
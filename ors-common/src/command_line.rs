@@ -0,0 +1,49 @@
+use core::{slice, str};
+
+/// Boot parameters passed from the loader to the kernel, such as `memtest=quick`.
+#[repr(C)]
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone)]
+pub struct CommandLine {
+    pub ptr: *const u8,
+    pub len: u64,
+}
+
+impl CommandLine {
+    pub fn as_str(&self) -> &str {
+        if self.len == 0 {
+            return "";
+        }
+        let bytes = unsafe { slice::from_raw_parts(self.ptr, self.len as usize) };
+        str::from_utf8(bytes).unwrap_or("")
+    }
+
+    /// Get the value of the parameter `key=value`.
+    /// Returns `Some("")` for a parameter without a value.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        parse(self.as_str(), key)
+    }
+}
+
+/// Get the value of the parameter `key=value` in the whitespace-separated parameters `s`.
+pub fn parse<'a>(s: &'a str, key: &str) -> Option<&'a str> {
+    s.split_whitespace()
+        .find_map(|param| match param.split_once('=') {
+            Some((k, v)) if k == key => Some(v),
+            None if param == key => Some(""),
+            _ => None,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse("memtest=quick", "memtest"), Some("quick"));
+        assert_eq!(parse(" foo\nmemtest=full bar=1 ", "memtest"), Some("full"));
+        assert_eq!(parse("foo bar=1", "foo"), Some(""));
+        assert_eq!(parse("foo bar=1", "baz"), None);
+        assert_eq!(parse("", "memtest"), None);
+    }
+}
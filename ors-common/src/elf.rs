@@ -0,0 +1,419 @@
+//! ELF relocation and segment permissions shared by the loader and the kernel.
+
+use core::fmt;
+use core::ops::Range;
+use core::ptr;
+use core::slice;
+use goblin::elf::program_header::{PF_W, PF_X};
+use goblin::elf::reloc::{
+    Reloc, R_X86_64_64, R_X86_64_GLOB_DAT, R_X86_64_JUMP_SLOT, R_X86_64_NONE, R_X86_64_RELATIVE,
+};
+use goblin::elf::{header, Elf};
+use goblin::elf64;
+
+pub use goblin::elf::program_header::PT_LOAD;
+
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone)]
+pub enum ElfError {
+    UnsupportedRelocation(u32),
+    UndefinedSymbol(usize),
+    OutOfRange(u64),
+    WritableExecutableSegment(usize),
+}
+
+impl fmt::Display for ElfError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnsupportedRelocation(ty) => write!(f, "Unsupported relocation type: {}", ty),
+            Self::UndefinedSymbol(index) => write!(f, "Undefined symbol: #{}", index),
+            Self::OutOfRange(offset) => write!(f, "Relocation out of range: 0x{:x}", offset),
+            Self::WritableExecutableSegment(index) => {
+                write!(f, "Segment {} is both writable and executable", index)
+            }
+        }
+    }
+}
+
+/// Apply the dynamic relocations (`DT_RELA` and `DT_REL`) of the ELF loaded at `base`.
+///
+/// # Safety
+///
+/// The caller must ensure that every `PT_LOAD` segment of `elf` is already loaded at
+/// `base + p_vaddr` and is writable.
+pub unsafe fn apply_relocations(src: &[u8], elf: &Elf, base: usize) -> Result<(), ElfError> {
+    for r in elf.dynrelas.iter().chain(elf.pltrelocs.iter()) {
+        apply_relocation(src, elf, base, &r)?;
+    }
+    for r in elf.dynrels.iter() {
+        apply_relocation(src, elf, base, &r)?;
+    }
+    Ok(())
+}
+
+unsafe fn apply_relocation(src: &[u8], elf: &Elf, base: usize, r: &Reloc) -> Result<(), ElfError> {
+    if r.r_type == R_X86_64_NONE {
+        return Ok(());
+    }
+    let addend = match r.r_addend {
+        Some(addend) => addend,
+        // DT_REL relocations hold the addend in the relocated field
+        None => implicit_addend(src, elf, r.r_offset)?,
+    };
+    let symbol = || match elf.dynsyms.get(r.r_sym) {
+        Some(sym) if sym.st_value != 0 => (base as u64)
+            .checked_add(sym.st_value)
+            .ok_or(ElfError::OutOfRange(r.r_offset)),
+        _ => Err(ElfError::UndefinedSymbol(r.r_sym)),
+    };
+    let value = match r.r_type {
+        R_X86_64_64 => symbol()?.wrapping_add(addend as u64),
+        R_X86_64_GLOB_DAT | R_X86_64_JUMP_SLOT => symbol()?,
+        R_X86_64_RELATIVE => (base as u64).wrapping_add(addend as u64),
+        ty => return Err(ElfError::UnsupportedRelocation(ty)),
+    };
+    let dest = match (base as u64).checked_add(r.r_offset) {
+        Some(dest) if is_loaded(elf, r.r_offset, 8) => dest,
+        _ => return Err(ElfError::OutOfRange(r.r_offset)),
+    };
+    ptr::write_unaligned(dest as *mut u64, value);
+    Ok(())
+}
+
+fn implicit_addend(src: &[u8], elf: &Elf, vaddr: u64) -> Result<i64, ElfError> {
+    let ph = elf
+        .program_headers
+        .iter()
+        .filter(|ph| ph.p_type == PT_LOAD)
+        .find(|ph| contains(ph.p_vaddr, ph.p_filesz, vaddr, 8))
+        .ok_or(ElfError::OutOfRange(vaddr))?;
+    let ofs = (ph.p_offset + (vaddr - ph.p_vaddr)) as usize;
+    match src.get(ofs..ofs + 8) {
+        Some(bytes) => Ok(i64::from_le_bytes(bytes.try_into().unwrap())),
+        None => Err(ElfError::OutOfRange(vaddr)),
+    }
+}
+
+fn is_loaded(elf: &Elf, vaddr: u64, len: u64) -> bool {
+    elf.program_headers
+        .iter()
+        .filter(|ph| ph.p_type == PT_LOAD)
+        .any(|ph| contains(ph.p_vaddr, ph.p_memsz, vaddr, len))
+}
+
+/// Whether `start..start + size` contains `vaddr..vaddr + len`. Ranges that overflow, which only
+/// malformed ELFs have, contain nothing.
+fn contains(start: u64, size: u64, vaddr: u64, len: u64) -> bool {
+    match (start.checked_add(size), vaddr.checked_add(len)) {
+        (Some(end), Some(vend)) => start <= vaddr && vend <= end,
+        _ => false,
+    }
+}
+
+/// Access permissions of a loaded segment. Segments are always readable.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct Permissions {
+    pub writable: bool,
+    pub executable: bool,
+}
+
+impl Permissions {
+    /// The permissions requested by the `p_flags` of the segment at `index`. Segments that are
+    /// both writable and executable are rejected (W^X).
+    pub fn from_flags(index: usize, p_flags: u32) -> Result<Self, ElfError> {
+        let permissions = Self {
+            writable: p_flags & PF_W != 0,
+            executable: p_flags & PF_X != 0,
+        };
+        if permissions.writable && permissions.executable {
+            return Err(ElfError::WritableExecutableSegment(index));
+        }
+        Ok(permissions)
+    }
+
+    /// The permissions allowing every access allowed by either of them, for a page shared by
+    /// two segments.
+    pub fn union(self, other: Self) -> Self {
+        Self {
+            writable: self.writable || other.writable,
+            executable: self.executable || other.executable,
+        }
+    }
+}
+
+/// The address ranges (relative to the base address) and the permissions of the `PT_LOAD`
+/// segments, in which the loaded segments should be mapped.
+pub fn segment_permissions<'a>(
+    elf: &'a Elf,
+) -> impl Iterator<Item = Result<(Range<u64>, Permissions), ElfError>> + 'a {
+    elf.program_headers
+        .iter()
+        .enumerate()
+        .filter(|(_, ph)| ph.p_type == PT_LOAD)
+        .map(|(i, ph)| {
+            let permissions = Permissions::from_flags(i, ph.p_flags)?;
+            Ok((ph.p_vaddr..ph.p_vaddr + ph.p_memsz, permissions))
+        })
+}
+
+/// The program headers of the ELF image whose ELF header is loaded at `ehdr`, such as the
+/// kernel itself. Returns `None` if `ehdr` does not point to an ELF header.
+///
+/// # Safety
+///
+/// The caller must ensure that the program headers are loaded along with the ELF header.
+pub unsafe fn loaded_program_headers<'a>(
+    ehdr: *const u8,
+) -> Option<&'a [elf64::program_header::ProgramHeader]> {
+    let header = &*(ehdr as *const elf64::header::Header);
+    if &header.e_ident[..header::SELFMAG] != header::ELFMAG {
+        return None;
+    }
+    let phdr = ehdr.add(header.e_phoff as usize) as *const elf64::program_header::ProgramHeader;
+    Some(slice::from_raw_parts(phdr, header.e_phnum as usize))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+    use alloc::vec::Vec;
+    use goblin::elf::dynamic::{
+        DT_JMPREL, DT_NULL, DT_PLTREL, DT_PLTRELSZ, DT_REL, DT_RELA, DT_RELAENT, DT_RELASZ,
+        DT_RELENT, DT_RELSZ, DT_STRSZ, DT_STRTAB, DT_SYMENT, DT_SYMTAB,
+    };
+    use goblin::elf::program_header::{PF_R, PT_DYNAMIC};
+    use goblin::elf::reloc::R_X86_64_PC32;
+
+    // Layout of the PIE fixture. The whole image is a single PT_LOAD segment at vaddr 0, so
+    // the loaded image is identical to the file.
+    const DYNSYM: usize = 0x100;
+    const DYNSTR: usize = 0x140;
+    const DYNAMIC: usize = 0x180;
+    const RELOCS: usize = 0x280;
+    const DATA: usize = 0x400;
+    const IMAGE_SIZE: usize = 0x500;
+    /// The value of the dynamic symbol #1.
+    const SYMBOL: u64 = 0x300;
+
+    struct Rel {
+        offset: u64,
+        ty: u32,
+        sym: u32,
+        addend: i64,
+    }
+
+    fn rel(offset: usize, ty: u32, sym: u32, addend: i64) -> Rel {
+        Rel {
+            offset: offset as u64,
+            ty,
+            sym,
+            addend,
+        }
+    }
+
+    fn put(image: &mut [u8], ofs: usize, bytes: &[u8]) {
+        image[ofs..ofs + bytes.len()].copy_from_slice(bytes);
+    }
+
+    /// Build a minimal `ET_DYN` image with the relocations in `DT_RELA`, `DT_REL`, and
+    /// `DT_JMPREL` (as RELA) respectively.
+    fn fixture(rela: &[Rel], rel: &[Rel], plt: &[Rel]) -> Vec<u8> {
+        let mut image = vec![0u8; IMAGE_SIZE];
+
+        put(&mut image, 0, header::ELFMAG);
+        put(&mut image, 4, &[2, 1, 1]); // ELFCLASS64, ELFDATA2LSB, EV_CURRENT
+        put(&mut image, 16, &header::ET_DYN.to_le_bytes());
+        put(&mut image, 18, &header::EM_X86_64.to_le_bytes());
+        put(&mut image, 20, &1u32.to_le_bytes());
+        put(&mut image, 32, &64u64.to_le_bytes()); // e_phoff
+        put(&mut image, 52, &64u16.to_le_bytes()); // e_ehsize
+        put(&mut image, 54, &56u16.to_le_bytes()); // e_phentsize
+        put(&mut image, 56, &2u16.to_le_bytes()); // e_phnum
+
+        let phdr = |image: &mut [u8], i: usize, ty: u32, flags: u32, ofs: usize, size: usize| {
+            let p = 64 + i * 56;
+            put(image, p, &ty.to_le_bytes());
+            put(image, p + 4, &flags.to_le_bytes());
+            for field in [8, 16, 24] {
+                put(image, p + field, &(ofs as u64).to_le_bytes()); // p_offset, p_vaddr, p_paddr
+            }
+            put(image, p + 32, &(size as u64).to_le_bytes()); // p_filesz
+            put(image, p + 40, &(size as u64).to_le_bytes()); // p_memsz
+        };
+        phdr(&mut image, 0, PT_LOAD, PF_R | PF_W, 0, IMAGE_SIZE);
+        phdr(
+            &mut image,
+            1,
+            PT_DYNAMIC,
+            PF_R | PF_W,
+            DYNAMIC,
+            RELOCS - DYNAMIC,
+        );
+
+        put(&mut image, DYNSYM + 24, &1u32.to_le_bytes()); // st_name
+        put(&mut image, DYNSYM + 24 + 4, &[0x12]); // STB_GLOBAL, STT_FUNC
+        put(&mut image, DYNSYM + 24 + 8, &SYMBOL.to_le_bytes());
+        put(&mut image, DYNSTR, b"\0f\0");
+
+        let mut dynamic = vec![
+            (DT_SYMTAB, DYNSYM as u64),
+            (DT_SYMENT, 24),
+            (DT_STRTAB, DYNSTR as u64),
+            (DT_STRSZ, 3),
+        ];
+        let mut ofs = RELOCS;
+        let mut section = |entries: &[Rel], is_rela: bool| {
+            let start = ofs;
+            for r in entries {
+                put(&mut image, ofs, &r.offset.to_le_bytes());
+                put(&mut image, ofs + 8, &r.ty.to_le_bytes());
+                put(&mut image, ofs + 12, &r.sym.to_le_bytes());
+                if is_rela {
+                    put(&mut image, ofs + 16, &r.addend.to_le_bytes());
+                    ofs += 24;
+                } else {
+                    // DT_REL relocations hold the addend in the relocated field
+                    put(&mut image, r.offset as usize, &r.addend.to_le_bytes());
+                    ofs += 16;
+                }
+            }
+            (start as u64, (ofs - start) as u64)
+        };
+        if !rela.is_empty() {
+            let (start, size) = section(rela, true);
+            dynamic.extend([(DT_RELA, start), (DT_RELASZ, size), (DT_RELAENT, 24)]);
+        }
+        if !rel.is_empty() {
+            let (start, size) = section(rel, false);
+            dynamic.extend([(DT_REL, start), (DT_RELSZ, size), (DT_RELENT, 16)]);
+        }
+        if !plt.is_empty() {
+            let (start, size) = section(plt, true);
+            dynamic.extend([
+                (DT_JMPREL, start),
+                (DT_PLTRELSZ, size),
+                (DT_PLTREL, DT_RELA),
+            ]);
+        }
+        assert!(ofs <= DATA);
+        dynamic.push((DT_NULL, 0));
+        assert!(DYNAMIC + dynamic.len() * 16 <= RELOCS);
+        for (i, (tag, val)) in dynamic.into_iter().enumerate() {
+            put(&mut image, DYNAMIC + i * 16, &tag.to_le_bytes());
+            put(&mut image, DYNAMIC + i * 16 + 8, &val.to_le_bytes());
+        }
+        image
+    }
+
+    /// Load `src` (by copying it, as its only segment is at vaddr 0) and relocate it.
+    fn relocate(src: &[u8]) -> (Vec<u8>, u64, Result<(), ElfError>) {
+        let elf = Elf::parse(src).unwrap();
+        let mut image = src.to_vec();
+        let base = image.as_mut_ptr() as usize;
+        let result = unsafe { apply_relocations(src, &elf, base) };
+        (image, base as u64, result)
+    }
+
+    fn read(image: &[u8], ofs: usize) -> u64 {
+        u64::from_le_bytes(image[ofs..ofs + 8].try_into().unwrap())
+    }
+
+    #[test]
+    fn test_apply_rela() {
+        let src = fixture(
+            &[
+                rel(DATA, R_X86_64_RELATIVE, 0, 0x10),
+                rel(DATA + 8, R_X86_64_64, 1, 4),
+                rel(DATA + 16, R_X86_64_GLOB_DAT, 1, 0),
+                rel(0, R_X86_64_NONE, 0, 0),
+            ],
+            &[],
+            &[rel(DATA + 24, R_X86_64_JUMP_SLOT, 1, 0)],
+        );
+        let (image, base, result) = relocate(&src);
+        assert_eq!(result, Ok(()));
+        assert_eq!(read(&image, DATA), base + 0x10);
+        assert_eq!(read(&image, DATA + 8), base + SYMBOL + 4);
+        assert_eq!(read(&image, DATA + 16), base + SYMBOL);
+        assert_eq!(read(&image, DATA + 24), base + SYMBOL);
+        assert_eq!(&image[..64], &src[..64]);
+    }
+
+    #[test]
+    fn test_apply_rel() {
+        let src = fixture(
+            &[],
+            &[
+                rel(DATA, R_X86_64_RELATIVE, 0, 0x20),
+                rel(DATA + 8, R_X86_64_64, 1, 8),
+            ],
+            &[],
+        );
+        let (image, base, result) = relocate(&src);
+        assert_eq!(result, Ok(()));
+        assert_eq!(read(&image, DATA), base + 0x20);
+        assert_eq!(read(&image, DATA + 8), base + SYMBOL + 8);
+    }
+
+    #[test]
+    fn test_apply_invalid_relocations() {
+        let out_of_range = (IMAGE_SIZE - 4) as u64;
+        let src = fixture(&[rel(IMAGE_SIZE - 4, R_X86_64_RELATIVE, 0, 0)], &[], &[]);
+        assert_eq!(relocate(&src).2, Err(ElfError::OutOfRange(out_of_range)));
+        let overflowing = usize::MAX - 4;
+        let src = fixture(&[rel(overflowing, R_X86_64_RELATIVE, 0, 0)], &[], &[]);
+        assert_eq!(
+            relocate(&src).2,
+            Err(ElfError::OutOfRange(overflowing as u64))
+        );
+
+        // The implicit addend of a DT_REL relocation is read from the file
+        let mut src = fixture(&[], &[rel(DATA, R_X86_64_RELATIVE, 0, 0)], &[]);
+        put(&mut src, RELOCS, &out_of_range.to_le_bytes());
+        assert_eq!(relocate(&src).2, Err(ElfError::OutOfRange(out_of_range)));
+
+        let src = fixture(&[rel(DATA, R_X86_64_GLOB_DAT, 0, 0)], &[], &[]);
+        assert_eq!(relocate(&src).2, Err(ElfError::UndefinedSymbol(0)));
+
+        let src = fixture(&[rel(DATA, R_X86_64_PC32, 1, 0)], &[], &[]);
+        assert_eq!(
+            relocate(&src).2,
+            Err(ElfError::UnsupportedRelocation(R_X86_64_PC32))
+        );
+    }
+
+    #[test]
+    fn test_permissions() {
+        let p = |writable, executable| Permissions {
+            writable,
+            executable,
+        };
+        assert_eq!(Permissions::from_flags(0, PF_R | PF_X), Ok(p(false, true)));
+        assert_eq!(Permissions::from_flags(1, PF_R), Ok(p(false, false)));
+        assert_eq!(Permissions::from_flags(2, PF_R | PF_W), Ok(p(true, false)));
+        assert_eq!(
+            Permissions::from_flags(3, PF_R | PF_W | PF_X),
+            Err(ElfError::WritableExecutableSegment(3))
+        );
+        assert_eq!(p(false, true).union(p(true, false)), p(true, true));
+        assert_eq!(p(false, false).union(p(true, false)), p(true, false));
+    }
+
+    #[test]
+    fn test_loaded_program_headers() {
+        let mut image = [0u64; 32];
+        let header = image.as_mut_ptr() as *mut elf64::header::Header;
+        let phdr =
+            unsafe { image.as_mut_ptr().add(8) } as *mut elf64::program_header::ProgramHeader;
+        assert!(unsafe { loaded_program_headers(header as *const u8) }.is_none());
+        unsafe {
+            (*header).e_ident[..header::SELFMAG].copy_from_slice(header::ELFMAG);
+            (*header).e_phoff = 64;
+            (*header).e_phnum = 2;
+            (*phdr.add(1)).p_flags = PF_R | PF_W;
+        }
+        let headers = unsafe { loaded_program_headers(header as *const u8) }.unwrap();
+        assert_eq!(headers.len(), 2);
+        assert_eq!(headers[1].p_flags, PF_R | PF_W);
+    }
+}
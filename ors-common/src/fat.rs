@@ -0,0 +1,190 @@
+//! On-disk encoding of FAT32 structures, shared by the kernel and the host-side image builder.
+
+/// Size of a directory entry in bytes.
+pub const DIR_ENTRY_SIZE: usize = 32;
+
+/// Number of UTF-16 characters of a long file name stored in an LFN entry.
+pub const LFN_ENTRY_CHARS: usize = 13;
+
+pub const ATTR_READ_ONLY: u8 = 0x01;
+pub const ATTR_HIDDEN: u8 = 0x02;
+pub const ATTR_SYSTEM: u8 = 0x04;
+pub const ATTR_VOLUME_ID: u8 = 0x08;
+pub const ATTR_DIRECTORY: u8 = 0x10;
+pub const ATTR_ARCHIVE: u8 = 0x20;
+pub const ATTR_LONG_FILE_NAME: u8 = 0x0f;
+pub const ATTR_LONG_FILE_NAME_MASK: u8 = 0x3f;
+
+/// NT_RES flag indicating that the base of the short name is displayed in lower case.
+pub const NT_RES_BASE_LOWER: u8 = 0x08;
+/// NT_RES flag indicating that the extension of the short name is displayed in lower case.
+pub const NT_RES_EXT_LOWER: u8 = 0x10;
+
+/// LFN_ORD flag of the LFN entry holding the last part of a long file name.
+pub const LAST_LONG_ENTRY: u8 = 0x40;
+
+/// Offsets of the characters of the name part in an LFN entry.
+const LFN_CHAR_OFFSETS: [usize; LFN_ENTRY_CHARS] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+
+/// FAT entry value of the end of a cluster chain.
+pub const END_OF_CHAIN: u32 = 0x0fffffff;
+
+/// FAT entry value of FAT[0], which holds the media type in its low byte.
+pub const MEDIA_ENTRY: u32 = 0x0ffffff8;
+
+/// Bit of FAT[1] that is set while the volume is cleanly unmounted, and cleared while it is
+/// mounted for writing. The volume is dirty if this is cleared.
+pub const CLEAN_SHUTDOWN: u32 = 0x08000000;
+
+/// Checksum of a short name, which is stored in every LFN entry of the corresponding long name.
+pub fn lfn_checksum(name: &[u8; 11]) -> u8 {
+    name.iter().fold(0u8, |sum, c| {
+        (sum >> 1).wrapping_add(sum << 7).wrapping_add(*c)
+    })
+}
+
+/// Encode an LFN entry holding the `order`-th (1-origin) part of a long file name.
+pub fn encode_lfn_entry(
+    order: u8,
+    last: bool,
+    checksum: u8,
+    part: &[u16; LFN_ENTRY_CHARS],
+) -> [u8; DIR_ENTRY_SIZE] {
+    let mut buf = [0; DIR_ENTRY_SIZE];
+    buf[0] = order | if last { LAST_LONG_ENTRY } else { 0 };
+    buf[11] = ATTR_LONG_FILE_NAME;
+    buf[13] = checksum;
+    for (c, offset) in part.iter().zip(LFN_CHAR_OFFSETS) {
+        buf[offset..offset + 2].copy_from_slice(&c.to_le_bytes());
+    }
+    buf
+}
+
+/// Encode a short file name entry. Timestamps are left zero.
+pub fn encode_sfn_entry(
+    name: &[u8; 11],
+    attr: u8,
+    nt_res: u8,
+    cluster: u32,
+    file_size: u32,
+) -> [u8; DIR_ENTRY_SIZE] {
+    let mut buf = [0; DIR_ENTRY_SIZE];
+    buf[0..11].copy_from_slice(name);
+    buf[11] = attr;
+    buf[12] = nt_res;
+    buf[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+    buf[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+    buf[28..32].copy_from_slice(&file_size.to_le_bytes());
+    buf
+}
+
+/// Parameters of a FAT32 boot sector.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct BootSectorParams {
+    pub bytes_per_sector: u16,
+    pub sectors_per_cluster: u8,
+    pub reserved_sectors: u16,
+    pub num_fats: u8,
+    pub total_sectors: u32,
+    pub fat_size: u32,
+    pub root_cluster: u32,
+    pub fs_info: u16,
+    pub backup_boot_sector: u16,
+    pub volume_id: u32,
+    pub volume_label: [u8; 11],
+}
+
+impl BootSectorParams {
+    /// The minimum size of a buffer given to `encode`.
+    pub const SIZE: usize = 512;
+
+    /// Encode the boot sector into the first `SIZE` bytes of `buf`.
+    pub fn encode(&self, buf: &mut [u8]) {
+        let buf = &mut buf[0..Self::SIZE];
+        buf.fill(0);
+        buf[0..3].copy_from_slice(&[0xeb, 0x58, 0x90]);
+        buf[3..11].copy_from_slice(b"MSWIN4.1");
+        buf[11..13].copy_from_slice(&self.bytes_per_sector.to_le_bytes());
+        buf[13] = self.sectors_per_cluster;
+        buf[14..16].copy_from_slice(&self.reserved_sectors.to_le_bytes());
+        buf[16] = self.num_fats;
+        buf[21] = 0xf8; // Media
+        buf[32..36].copy_from_slice(&self.total_sectors.to_le_bytes());
+        buf[36..40].copy_from_slice(&self.fat_size.to_le_bytes());
+        buf[44..48].copy_from_slice(&self.root_cluster.to_le_bytes());
+        buf[48..50].copy_from_slice(&self.fs_info.to_le_bytes());
+        buf[50..52].copy_from_slice(&self.backup_boot_sector.to_le_bytes());
+        buf[64] = 0x80; // Drive number
+        buf[66] = 0x29; // Extended boot signature
+        buf[67..71].copy_from_slice(&self.volume_id.to_le_bytes());
+        buf[71..82].copy_from_slice(&self.volume_label);
+        buf[82..90].copy_from_slice(b"FAT32   ");
+        buf[510..512].copy_from_slice(&[0x55, 0xaa]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lfn_checksum() {
+        assert_eq!(lfn_checksum(b"FILE    TXT"), 0x19);
+        assert_ne!(lfn_checksum(b"FILE    TXT"), lfn_checksum(b"FILE    TXU"));
+    }
+
+    #[test]
+    fn test_encode_lfn_entry() {
+        let mut part = [0xffff; LFN_ENTRY_CHARS];
+        for (i, c) in "Hello.txt".encode_utf16().enumerate() {
+            part[i] = c;
+        }
+        part[9] = 0x0000;
+        let buf = encode_lfn_entry(2, true, 0x12, &part);
+        assert_eq!(buf[0], 0x42);
+        assert_eq!(buf[11], ATTR_LONG_FILE_NAME);
+        assert_eq!(buf[13], 0x12);
+        assert_eq!(&buf[1..11], b"H\0e\0l\0l\0o\0");
+        assert_eq!(&buf[14..20], b".\0t\0x\0");
+        assert_eq!(&buf[20..24], &[b't', 0, 0, 0]);
+        assert_eq!(&buf[26..28], &[0, 0]);
+        assert_eq!(&buf[28..32], &[0xff; 4]);
+    }
+
+    #[test]
+    fn test_encode_sfn_entry() {
+        let buf = encode_sfn_entry(b"FILE    TXT", ATTR_ARCHIVE, 0, 0x12345, 100);
+        assert_eq!(&buf[0..11], b"FILE    TXT");
+        assert_eq!(buf[11], ATTR_ARCHIVE);
+        assert_eq!(&buf[20..22], &[0x01, 0x00]);
+        assert_eq!(&buf[26..28], &[0x45, 0x23]);
+        assert_eq!(&buf[28..32], &[100, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_encode_boot_sector() {
+        let params = BootSectorParams {
+            bytes_per_sector: 4096,
+            sectors_per_cluster: 8,
+            reserved_sectors: 32,
+            num_fats: 2,
+            total_sectors: 1000,
+            fat_size: 3,
+            root_cluster: 2,
+            fs_info: 1,
+            backup_boot_sector: 6,
+            volume_id: 0xdeadbeef,
+            volume_label: *b"NO NAME    ",
+        };
+        let mut buf = [0xcc; 600];
+        params.encode(&mut buf);
+        assert_eq!(&buf[11..13], &4096u16.to_le_bytes());
+        assert_eq!(buf[13], 8);
+        assert_eq!(&buf[32..36], &1000u32.to_le_bytes());
+        assert_eq!(&buf[50..52], &6u16.to_le_bytes());
+        assert_eq!(&buf[67..71], &0xdeadbeefu32.to_le_bytes());
+        assert_eq!(&buf[71..82], b"NO NAME    ");
+        assert_eq!(&buf[510..512], &[0x55, 0xaa]);
+        assert_eq!(buf[512], 0xcc);
+    }
+}
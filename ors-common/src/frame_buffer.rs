@@ -1,3 +1,5 @@
+use core::ptr;
+
 #[repr(C)]
 #[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy)]
 pub enum PixelFormat {
@@ -13,3 +15,58 @@ pub struct FrameBuffer {
     pub resolution: (u32, u32), // (horizontal, vertical)
     pub format: PixelFormat,
 }
+
+impl FrameBuffer {
+    /// Passed by the loader when no frame buffer is available.
+    pub const NONE: Self = Self {
+        frame_buffer: ptr::null_mut(),
+        stride: 0,
+        resolution: (0, 0),
+        format: PixelFormat::Rgb,
+    };
+
+    pub fn is_none(&self) -> bool {
+        self.frame_buffer.is_null()
+    }
+
+    /// Check that the frame buffer can be drawn on.
+    pub fn check(&self) -> Result<(), &'static str> {
+        if self.is_none() {
+            return Err("no frame buffer");
+        }
+        if self.resolution.0 == 0 || self.resolution.1 == 0 {
+            return Err("zero resolution");
+        }
+        if self.stride < self.resolution.0 {
+            return Err("stride is smaller than the width");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        let mut buf = [0u8; 16];
+        let fb = FrameBuffer {
+            frame_buffer: buf.as_mut_ptr(),
+            stride: 2,
+            resolution: (2, 2),
+            format: PixelFormat::Bgr,
+        };
+        assert_eq!(fb.check(), Ok(()));
+        assert!(FrameBuffer::NONE.is_none());
+        assert!(FrameBuffer::NONE.check().is_err());
+        assert!(FrameBuffer { stride: 0, ..fb }.check().is_err());
+        assert!(FrameBuffer { stride: 1, ..fb }.check().is_err());
+        assert!(FrameBuffer {
+            resolution: (2, 0),
+            ..fb
+        }
+        .check()
+        .is_err());
+    }
+}
@@ -2,9 +2,13 @@
 #![feature(maybe_uninit_array_assume_init)] // for non_contiguous
 #![no_std]
 
-#[cfg(test)]
 extern crate alloc;
 
+pub mod command_line;
+pub mod elf;
+pub mod fat;
 pub mod frame_buffer;
 pub mod memory_map;
 pub mod non_contiguous;
+pub mod pattern;
+pub mod term;
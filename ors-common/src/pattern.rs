@@ -0,0 +1,651 @@
+//! Pattern matching of strings given by users, such as filters of shell commands.
+//!
+//! `Glob` and `Lite` (a small subset of regular expressions) are both compiled into a program of
+//! a non-deterministic automaton, which is simulated over the input with explicit state sets.
+//! Thus matching takes `O(input * pattern)` time and `O(pattern)` memory regardless of the
+//! pattern, and hostile patterns such as `(a*)*b` never cause backtracking blowups.
+
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use core::iter::Peekable;
+use core::ops::Range;
+use core::str::Chars;
+
+/// Maximum nesting of groups in a `Lite` pattern, which bounds the recursion of the compiler.
+const MAX_DEPTH: usize = 32;
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum PatternError {
+    /// A `[` without the closing `]`.
+    UnclosedClass,
+    /// A range of a class whose end is smaller than its start, such as `[z-a]`.
+    InvalidRange(char, char),
+    /// A `*`, `+` or `?` with nothing to repeat.
+    NothingToRepeat,
+    /// A `(` without the closing `)`.
+    UnclosedGroup,
+    /// A `)` without the opening `(`.
+    UnopenedGroup,
+    /// A `\` at the end of the pattern.
+    TrailingBackslash,
+    /// Groups are nested deeper than `MAX_DEPTH`.
+    TooDeep,
+}
+
+impl fmt::Display for PatternError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnclosedClass => write!(f, "Unclosed character class"),
+            Self::InvalidRange(a, b) => write!(f, "Invalid range {}-{}", a, b),
+            Self::NothingToRepeat => write!(f, "Nothing to repeat"),
+            Self::UnclosedGroup => write!(f, "Unclosed group"),
+            Self::UnopenedGroup => write!(f, "Unopened group"),
+            Self::TrailingBackslash => write!(f, "Trailing backslash"),
+            Self::TooDeep => write!(f, "Groups nested too deep"),
+        }
+    }
+}
+
+/// A shell-style wildcard pattern matching the whole input: `*` matches any string, `?` matches
+/// any character, and `[abc]`, `[a-z]` or `[!a-z]` matches a character in (or not in) the class.
+#[derive(Debug, Clone)]
+pub struct Glob {
+    program: Program,
+}
+
+impl Glob {
+    pub fn compile(pattern: &str) -> Result<Self, PatternError> {
+        Self::compile_with(pattern, false)
+    }
+
+    /// Compile a pattern that matches ASCII letters case-insensitively, as FAT file names.
+    pub fn compile_ignore_case(pattern: &str) -> Result<Self, PatternError> {
+        Self::compile_with(pattern, true)
+    }
+
+    fn compile_with(pattern: &str, ignore_case: bool) -> Result<Self, PatternError> {
+        let mut insts = Vec::from([Inst::Bol]);
+        let mut chars = pattern.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '*' => {
+                    let l = insts.len();
+                    insts.push(Inst::Split(l + 1, l + 3));
+                    insts.push(Inst::Any);
+                    insts.push(Inst::Jmp(l));
+                }
+                '?' => insts.push(Inst::Any),
+                '[' => insts.push(Inst::Class(Class::parse(&mut chars, '!', false)?)),
+                c => insts.push(Inst::Char(c)),
+            }
+        }
+        insts.push(Inst::Eol);
+        insts.push(Inst::Match);
+        Ok(Self {
+            program: Program { insts, ignore_case },
+        })
+    }
+
+    pub fn is_match(&self, s: &str) -> bool {
+        self.program.find(s).is_some()
+    }
+
+    /// Since a glob matches the whole input, this is `Some(0..s.len())` if `s` matches.
+    pub fn find(&self, s: &str) -> Option<Range<usize>> {
+        self.program.find(s)
+    }
+}
+
+/// A small subset of regular expressions: literals, `.`, `*`, `+`, `?`, anchors `^` and `$`,
+/// classes such as `[a-z]` or `[^a-z]`, alternation with `|`, grouping with `(...)`, and `\` to
+/// escape the special characters. There are no captures and no backreferences.
+///
+/// A pattern matches anywhere in the input unless anchored. `find` returns the leftmost match,
+/// and the longest one among the matches starting there.
+#[derive(Debug, Clone)]
+pub struct Lite {
+    program: Program,
+}
+
+impl Lite {
+    pub fn compile(pattern: &str) -> Result<Self, PatternError> {
+        let mut chars = pattern.chars().peekable();
+        let node = parse_alt(&mut chars, 0)?;
+        if chars.next().is_some() {
+            // parse_alt stops only at the end or at a `)`
+            return Err(PatternError::UnopenedGroup);
+        }
+        let mut insts = Vec::new();
+        node.emit(&mut insts);
+        insts.push(Inst::Match);
+        Ok(Self {
+            program: Program {
+                insts,
+                ignore_case: false,
+            },
+        })
+    }
+
+    pub fn is_match(&self, s: &str) -> bool {
+        self.program.find(s).is_some()
+    }
+
+    pub fn find(&self, s: &str) -> Option<Range<usize>> {
+        self.program.find(s)
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Node {
+    Empty,
+    Char(char),
+    Any,
+    Class(Class),
+    Bol,
+    Eol,
+    Concat(Vec<Node>),
+    Alt(Vec<Node>),
+    Star(Box<Node>),
+    Plus(Box<Node>),
+    Quest(Box<Node>),
+}
+
+fn parse_alt(chars: &mut Peekable<Chars>, depth: usize) -> Result<Node, PatternError> {
+    let mut alts = Vec::from([parse_concat(chars, depth)?]);
+    while chars.next_if_eq(&'|').is_some() {
+        alts.push(parse_concat(chars, depth)?);
+    }
+    Ok(if alts.len() == 1 {
+        alts.pop().unwrap()
+    } else {
+        Node::Alt(alts)
+    })
+}
+
+fn parse_concat(chars: &mut Peekable<Chars>, depth: usize) -> Result<Node, PatternError> {
+    let mut nodes = Vec::new();
+    while let Some(c) = chars.next_if(|c| *c != '|' && *c != ')') {
+        let node = match c {
+            '*' | '+' | '?' => {
+                let node = match nodes.pop() {
+                    Some(Node::Bol | Node::Eol) | None => {
+                        return Err(PatternError::NothingToRepeat)
+                    }
+                    Some(node) => Box::new(node),
+                };
+                match c {
+                    '*' => Node::Star(node),
+                    '+' => Node::Plus(node),
+                    _ => Node::Quest(node),
+                }
+            }
+            '.' => Node::Any,
+            '^' => Node::Bol,
+            '$' => Node::Eol,
+            '[' => Node::Class(Class::parse(chars, '^', true)?),
+            '(' => {
+                if MAX_DEPTH <= depth {
+                    return Err(PatternError::TooDeep);
+                }
+                let node = parse_alt(chars, depth + 1)?;
+                if chars.next() != Some(')') {
+                    return Err(PatternError::UnclosedGroup);
+                }
+                node
+            }
+            '\\' => Node::Char(chars.next().ok_or(PatternError::TrailingBackslash)?),
+            c => Node::Char(c),
+        };
+        nodes.push(node);
+    }
+    Ok(match nodes.len() {
+        0 => Node::Empty,
+        1 => nodes.pop().unwrap(),
+        _ => Node::Concat(nodes),
+    })
+}
+
+impl Node {
+    fn emit(self, insts: &mut Vec<Inst>) {
+        match self {
+            Self::Empty => {}
+            Self::Char(c) => insts.push(Inst::Char(c)),
+            Self::Any => insts.push(Inst::Any),
+            Self::Class(class) => insts.push(Inst::Class(class)),
+            Self::Bol => insts.push(Inst::Bol),
+            Self::Eol => insts.push(Inst::Eol),
+            Self::Concat(nodes) => {
+                for node in nodes {
+                    node.emit(insts);
+                }
+            }
+            Self::Alt(nodes) => {
+                // Split(a, next); a; Jmp(end); next: Split(b, next); b; Jmp(end); ...; z; end:
+                let mut jumps = Vec::new();
+                let last = nodes.len() - 1;
+                for (i, node) in nodes.into_iter().enumerate() {
+                    if i == last {
+                        node.emit(insts);
+                    } else {
+                        let split = insts.len();
+                        insts.push(Inst::Split(split + 1, 0));
+                        node.emit(insts);
+                        jumps.push(insts.len());
+                        insts.push(Inst::Jmp(0));
+                        insts[split] = Inst::Split(split + 1, insts.len());
+                    }
+                }
+                let end = insts.len();
+                for jump in jumps {
+                    insts[jump] = Inst::Jmp(end);
+                }
+            }
+            Self::Star(node) => {
+                let split = insts.len();
+                insts.push(Inst::Split(split + 1, 0));
+                node.emit(insts);
+                insts.push(Inst::Jmp(split));
+                insts[split] = Inst::Split(split + 1, insts.len());
+            }
+            Self::Plus(node) => {
+                let start = insts.len();
+                node.emit(insts);
+                insts.push(Inst::Split(start, insts.len() + 1));
+            }
+            Self::Quest(node) => {
+                let split = insts.len();
+                insts.push(Inst::Split(split + 1, 0));
+                node.emit(insts);
+                insts[split] = Inst::Split(split + 1, insts.len());
+            }
+        }
+    }
+}
+
+/// A set of characters such as `[a-z_]`.
+#[derive(Debug, Clone)]
+struct Class {
+    negated: bool,
+    ranges: Vec<(char, char)>,
+}
+
+impl Class {
+    /// Parse a class after the opening `[`. A `]` right after the `[` (and the `negation`) is a
+    /// member rather than the end, and so is a `-` at either end.
+    fn parse(
+        chars: &mut Peekable<Chars>,
+        negation: char,
+        escapes: bool,
+    ) -> Result<Self, PatternError> {
+        let negated = chars.next_if_eq(&negation).is_some();
+        let mut ranges = Vec::new();
+        let mut first = true;
+        loop {
+            let c = match chars.next() {
+                Some(']') if !first => break,
+                Some('\\') if escapes => chars.next().ok_or(PatternError::UnclosedClass)?,
+                Some(c) => c,
+                None => return Err(PatternError::UnclosedClass),
+            };
+            first = false;
+            let mut lookahead = chars.clone();
+            let end = match (lookahead.next(), lookahead.next()) {
+                (Some('-'), Some(']')) | (Some('-'), None) => c,
+                (Some('-'), Some('\\')) if escapes => {
+                    chars.nth(1);
+                    chars.next().ok_or(PatternError::UnclosedClass)?
+                }
+                (Some('-'), Some(end)) => {
+                    chars.nth(1);
+                    end
+                }
+                _ => c,
+            };
+            if end < c {
+                return Err(PatternError::InvalidRange(c, end));
+            }
+            ranges.push((c, end));
+        }
+        Ok(Self { negated, ranges })
+    }
+
+    fn contains(&self, c: char, ignore_case: bool) -> bool {
+        let in_ranges = |c: char| self.ranges.iter().any(|(a, b)| (*a..=*b).contains(&c));
+        let found = in_ranges(c)
+            || ignore_case
+                && (in_ranges(c.to_ascii_lowercase()) || in_ranges(c.to_ascii_uppercase()));
+        found != self.negated
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Inst {
+    Char(char),
+    Any,
+    Class(Class),
+    /// Matches only at the start of the input.
+    Bol,
+    /// Matches only at the end of the input.
+    Eol,
+    Split(usize, usize),
+    Jmp(usize),
+    Match,
+}
+
+#[derive(Debug, Clone)]
+struct Program {
+    insts: Vec<Inst>,
+    ignore_case: bool,
+}
+
+impl Program {
+    fn find(&self, s: &str) -> Option<Range<usize>> {
+        let n = self.insts.len();
+        let mut clist = Threads::new(n);
+        let mut nlist = Threads::new(n);
+        let mut stack = Vec::with_capacity(n);
+        let mut best: Option<Range<usize>> = None;
+
+        for c in s.char_indices().map(Some).chain([None]) {
+            let pos = c.map_or(s.len(), |(i, _)| i);
+            // Matches starting later are never preferred over the found one
+            if best.is_none() {
+                self.add(&mut clist, &mut stack, 0, pos, pos, s.len());
+            }
+            if clist.is_empty() && best.is_some() {
+                break;
+            }
+            nlist.clear();
+            for i in 0..clist.len() {
+                let (pc, start) = clist.get(i);
+                if best.as_ref().map_or(false, |r| r.start < start) {
+                    continue;
+                }
+                let consumed = match (&self.insts[pc], c) {
+                    (Inst::Match, _) => {
+                        if best
+                            .as_ref()
+                            .map_or(true, |r| start < r.start || r.end < pos)
+                        {
+                            best = Some(start..pos);
+                        }
+                        continue;
+                    }
+                    (Inst::Char(a), Some((_, c))) if self.ignore_case => a.eq_ignore_ascii_case(&c),
+                    (Inst::Char(a), Some((_, c))) => *a == c,
+                    (Inst::Any, Some(_)) => true,
+                    (Inst::Class(class), Some((_, c))) => class.contains(c, self.ignore_case),
+                    _ => false,
+                };
+                if let (true, Some((_, c))) = (consumed, c) {
+                    let next = pos + c.len_utf8();
+                    self.add(&mut nlist, &mut stack, pc + 1, start, next, s.len());
+                }
+            }
+            core::mem::swap(&mut clist, &mut nlist);
+        }
+        best
+    }
+
+    /// Add the thread at `pc` and the threads reachable from it without consuming a character.
+    fn add(
+        &self,
+        threads: &mut Threads,
+        stack: &mut Vec<usize>,
+        pc: usize,
+        start: usize,
+        pos: usize,
+        len: usize,
+    ) {
+        stack.push(pc);
+        while let Some(pc) = stack.pop() {
+            if !threads.insert(pc, start) {
+                continue;
+            }
+            match self.insts[pc] {
+                Inst::Bol if pos == 0 => stack.push(pc + 1),
+                Inst::Eol if pos == len => stack.push(pc + 1),
+                Inst::Jmp(a) => stack.push(a),
+                Inst::Split(a, b) => {
+                    // `a` is visited first
+                    stack.push(b);
+                    stack.push(a);
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// An ordered set of threads, each of which is a program counter and the start of the match.
+/// Only one thread is kept for each program counter: the first one, which started earliest.
+#[derive(Debug)]
+struct Threads {
+    dense: Vec<(usize, usize)>,
+    sparse: Vec<usize>,
+}
+
+impl Threads {
+    fn new(n: usize) -> Self {
+        Self {
+            dense: Vec::with_capacity(n),
+            sparse: vec![0; n],
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.dense.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.dense.is_empty()
+    }
+
+    fn get(&self, i: usize) -> (usize, usize) {
+        self.dense[i]
+    }
+
+    fn insert(&mut self, pc: usize, start: usize) -> bool {
+        let i = self.sparse[pc];
+        if i < self.dense.len() && self.dense[i].0 == pc {
+            return false;
+        }
+        self.sparse[pc] = self.dense.len();
+        self.dense.push((pc, start));
+        true
+    }
+
+    fn clear(&mut self) {
+        self.dense.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::ToString;
+
+    fn glob(p: &str) -> Glob {
+        Glob::compile(p).unwrap()
+    }
+
+    fn lite(p: &str) -> Lite {
+        Lite::compile(p).unwrap()
+    }
+
+    #[test]
+    fn test_glob() {
+        assert!(glob("").is_match(""));
+        assert!(!glob("").is_match("a"));
+        assert!(glob("*").is_match(""));
+        assert!(glob("*").is_match("anything"));
+        assert!(glob("*.txt").is_match("a.txt"));
+        assert!(glob("*.txt").is_match(".txt"));
+        assert!(!glob("*.txt").is_match("a.txt.bak"));
+        assert!(glob("a*b*c").is_match("abc"));
+        assert!(glob("a*b*c").is_match("axxbyyc"));
+        assert!(!glob("a*b*c").is_match("axxbyy"));
+        assert!(glob("?").is_match("あ"));
+        assert!(!glob("?").is_match(""));
+        assert!(!glob("?").is_match("ab"));
+        assert!(glob("??.rs").is_match("ab.rs"));
+        assert!(glob("**a**").is_match("xxaxx"));
+        assert_eq!(glob("*.rs").find("main.rs"), Some(0..7));
+        assert_eq!(glob("*.rs").find("main.rst"), None);
+        // Case sensitivity
+        assert!(!glob("*.TXT").is_match("a.txt"));
+        let g = Glob::compile_ignore_case("*.TXT").unwrap();
+        assert!(g.is_match("a.txt"));
+        assert!(g.is_match("A.Txt"));
+        assert!(!g.is_match("a.txb"));
+    }
+
+    #[test]
+    fn test_glob_class() {
+        let g = glob("[abc]");
+        assert!(g.is_match("a") && g.is_match("c") && !g.is_match("d") && !g.is_match(""));
+        let g = glob("[a-cx]");
+        assert!(g.is_match("b") && g.is_match("x") && !g.is_match("d"));
+        let g = glob("[!a-c]");
+        assert!(!g.is_match("b") && g.is_match("d") && !g.is_match(""));
+        // `]` first and `-` at either end are members
+        let g = glob("[]a]");
+        assert!(g.is_match("]") && g.is_match("a") && !g.is_match("b"));
+        let g = glob("[!]]");
+        assert!(!g.is_match("]") && g.is_match("a"));
+        let g = glob("[-a]");
+        assert!(g.is_match("-") && g.is_match("a"));
+        let g = glob("[a-]");
+        assert!(g.is_match("-") && g.is_match("a") && !g.is_match("b"));
+        // Special characters are literal in classes
+        let g = glob("[*?]");
+        assert!(g.is_match("*") && g.is_match("?") && !g.is_match("a"));
+        assert!(glob("file[0-9][0-9].log").is_match("file42.log"));
+        let g = Glob::compile_ignore_case("[a-c]").unwrap();
+        assert!(g.is_match("B") && !g.is_match("D"));
+        let g = Glob::compile_ignore_case("[!a-c]").unwrap();
+        assert!(!g.is_match("B") && g.is_match("D"));
+
+        assert_eq!(
+            Glob::compile("[abc").err(),
+            Some(PatternError::UnclosedClass)
+        );
+        assert_eq!(Glob::compile("[").err(), Some(PatternError::UnclosedClass));
+        assert_eq!(Glob::compile("[]").err(), Some(PatternError::UnclosedClass));
+        assert_eq!(Glob::compile("[!").err(), Some(PatternError::UnclosedClass));
+        assert_eq!(
+            Glob::compile("[z-a]").err(),
+            Some(PatternError::InvalidRange('z', 'a'))
+        );
+    }
+
+    #[test]
+    fn test_lite() {
+        assert_eq!(lite("").find("abc"), Some(0..0));
+        assert_eq!(lite("").find(""), Some(0..0));
+        assert_eq!(lite("b").find("abc"), Some(1..2));
+        assert_eq!(lite("bc").find("abc"), Some(1..3));
+        assert_eq!(lite("x").find("abc"), None);
+        assert_eq!(lite("a.c").find("xxabcxx"), Some(2..5));
+        assert_eq!(lite("a.c").find("ac"), None);
+        assert_eq!(lite("ab*").find("xabbbx"), Some(1..5));
+        assert_eq!(lite("ab*").find("xax"), Some(1..2));
+        assert_eq!(lite("ab+").find("xax"), None);
+        assert_eq!(lite("ab+").find("xabbx"), Some(1..4));
+        assert_eq!(lite("ab?c").find("ac abc abbc"), Some(0..2));
+        assert_eq!(lite("colou?r").find("my color"), Some(3..8));
+        // Leftmost, then longest
+        assert_eq!(lite("a|ab").find("xab"), Some(1..3));
+        assert_eq!(lite("b|ab").find("xab"), Some(1..3));
+        assert_eq!(lite("a.*z|b").find("abzz"), Some(0..4));
+        assert_eq!(lite("x*").find("abc"), Some(0..0));
+        // Multibyte characters
+        assert_eq!(lite("い.").find("あいう"), Some(3..9));
+        assert_eq!(lite(".$").find("あいう"), Some(6..9));
+    }
+
+    #[test]
+    fn test_lite_anchors() {
+        assert_eq!(lite("^ab").find("abab"), Some(0..2));
+        assert_eq!(lite("^b").find("abab"), None);
+        assert_eq!(lite("ab$").find("abab"), Some(2..4));
+        assert_eq!(lite("a$").find("abab"), None);
+        assert_eq!(lite("^$").find(""), Some(0..0));
+        assert_eq!(lite("^$").find("a"), None);
+        assert_eq!(lite("^").find("abc"), Some(0..0));
+        assert_eq!(lite("$").find("abc"), Some(3..3));
+        assert_eq!(lite("^a|b$").find("cab"), Some(2..3));
+        assert_eq!(lite("(^a|b)c").find("bc"), Some(0..2));
+        assert_eq!(lite("a^b").find("ab"), None);
+    }
+
+    #[test]
+    fn test_lite_groups_and_classes() {
+        assert_eq!(lite("(ab)+").find("xababx"), Some(1..5));
+        assert_eq!(lite("(a|b)*c").find("xabbac"), Some(1..6));
+        assert_eq!(lite("gr(a|e)y").find("grey"), Some(0..4));
+        assert_eq!(lite("()").find("a"), Some(0..0));
+        assert_eq!(lite("a||b").find("b"), Some(0..1));
+        assert_eq!(lite("a||b").find("xb"), Some(0..0));
+        assert_eq!(lite("[0-9]+").find("abc 123 45"), Some(4..7));
+        assert_eq!(lite("[^ ]+").find("  word  "), Some(2..6));
+        assert_eq!(lite("[]]").find("a]"), Some(1..2));
+        assert_eq!(lite("[\\]x]+").find("a]x]"), Some(1..4));
+        assert_eq!(lite("[a\\-z]+").find("b-az"), Some(1..4));
+        assert_eq!(lite("[.*]+").find("a.*b"), Some(1..3));
+        // Escapes
+        assert_eq!(lite("a\\.b").find("axb a.b"), Some(4..7));
+        assert_eq!(lite("\\(\\)").find("f()"), Some(1..3));
+        assert_eq!(lite("\\\\").find("a\\b"), Some(1..2));
+        assert_eq!(lite("1\\+1").find("1+1"), Some(0..3));
+    }
+
+    #[test]
+    fn test_lite_errors() {
+        let err = |p: &str| Lite::compile(p).err();
+        assert_eq!(err("*a"), Some(PatternError::NothingToRepeat));
+        assert_eq!(err("a|+"), Some(PatternError::NothingToRepeat));
+        assert_eq!(err("(*)"), Some(PatternError::NothingToRepeat));
+        assert_eq!(err("^*"), Some(PatternError::NothingToRepeat));
+        assert_eq!(err("(a"), Some(PatternError::UnclosedGroup));
+        assert_eq!(err("a)"), Some(PatternError::UnopenedGroup));
+        assert_eq!(err(")("), Some(PatternError::UnopenedGroup));
+        assert_eq!(err("a\\"), Some(PatternError::TrailingBackslash));
+        assert_eq!(err("[a"), Some(PatternError::UnclosedClass));
+        assert_eq!(err("[a\\"), Some(PatternError::UnclosedClass));
+        assert_eq!(err("[9-0]"), Some(PatternError::InvalidRange('9', '0')));
+
+        let nested = |depth: usize| "(".repeat(depth) + "a" + &")".repeat(depth);
+        assert!(Lite::compile(&nested(MAX_DEPTH)).is_ok());
+        assert_eq!(err(&nested(MAX_DEPTH + 1)), Some(PatternError::TooDeep));
+        assert_eq!(
+            PatternError::InvalidRange('9', '0').to_string(),
+            "Invalid range 9-0"
+        );
+    }
+
+    #[test]
+    fn test_repeated_quantifiers() {
+        assert_eq!(lite("a**").find("aaa"), Some(0..3));
+        assert_eq!(lite("a+?").find("aab"), Some(0..2));
+        assert_eq!(lite("a+?").find("baa"), Some(0..0));
+        assert_eq!(lite("(a*)*").find("aab"), Some(0..2));
+        assert_eq!(lite("(a*)+b").find("aab"), Some(0..3));
+        assert_eq!(lite("(|a)*b").find("aab"), Some(0..3));
+    }
+
+    #[test]
+    fn test_pathological() {
+        // These take exponential time with a backtracking matcher
+        let input = "a".repeat(5000);
+        assert!(!lite("(a*)*b").is_match(&input));
+        assert!(!lite("(a|a)*b").is_match(&input));
+        assert!(!lite("(a|aa)+$b").is_match(&input));
+        let p = "a?".repeat(30) + &"a".repeat(30);
+        assert_eq!(lite(&p).find(&"a".repeat(30)), Some(0..30));
+        assert!(!glob(&("*a".repeat(50) + "b")).is_match(&input));
+        assert!(!glob("*a*a*a*a*a*a*a*a*b").is_match(&input));
+    }
+}
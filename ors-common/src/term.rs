@@ -0,0 +1,118 @@
+//! The terminal emulation of the console, which turns the output text into a grid of cells.
+//!
+//! This module is independent of the task scheduler, the interrupts and the frame buffer, so
+//! that the whole pipeline from the output text to the cells can be tested on the host. The
+//! kernel feeds the console output to a `Terminal`, and renders its `Grid` to the screen.
+
+use core::cmp::Ordering;
+
+pub mod ansi;
+mod grid;
+mod terminal;
+pub mod theme;
+
+pub use grid::{Cell, Damage, Grid};
+pub use terminal::{Backend, Terminal};
+
+/// Colors of cells, resolved by the color scheme of the terminal.
+pub type Rgb = (u8, u8, u8);
+
+/// Ranges of East Asian wide characters, which occupy two columns. This is a subset of the
+/// characters whose East_Asian_Width is W or F, sorted by the first code point.
+const WIDE_RANGES: &[(u32, u32)] = &[
+    (0x1100, 0x115f),   // Hangul Jamo
+    (0x2e80, 0x303e),   // CJK Radicals Supplement .. CJK Symbols and Punctuation
+    (0x3041, 0x33ff),   // Hiragana .. CJK Compatibility
+    (0x3400, 0x4dbf),   // CJK Unified Ideographs Extension A
+    (0x4e00, 0x9fff),   // CJK Unified Ideographs
+    (0xa000, 0xa4cf),   // Yi Syllables, Yi Radicals
+    (0xac00, 0xd7a3),   // Hangul Syllables
+    (0xf900, 0xfaff),   // CJK Compatibility Ideographs
+    (0xfe30, 0xfe4f),   // CJK Compatibility Forms
+    (0xff00, 0xff60),   // Fullwidth Forms
+    (0xffe0, 0xffe6),   // Fullwidth Signs
+    (0x1f300, 0x1f64f), // Miscellaneous Symbols and Pictographs, Emoticons
+    (0x1f900, 0x1f9ff), // Supplemental Symbols and Pictographs
+    (0x20000, 0x2fffd), // Supplementary Ideographic Plane
+    (0x30000, 0x3fffd), // Tertiary Ideographic Plane
+];
+
+/// The number of columns occupied by the character in monospace text.
+pub fn char_width(ch: char) -> u8 {
+    let ch = ch as u32;
+    let is_wide = WIDE_RANGES
+        .binary_search_by(|&(start, end)| {
+            if end < ch {
+                Ordering::Less
+            } else if ch < start {
+                Ordering::Greater
+            } else {
+                Ordering::Equal
+            }
+        })
+        .is_ok();
+    if is_wide {
+        2
+    } else {
+        1
+    }
+}
+
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy, Hash)]
+pub enum FontStyle {
+    Normal,
+    Bold,
+    Italic,
+    BoldItalic,
+}
+
+impl FontStyle {
+    pub fn is_bold(self) -> bool {
+        matches!(self, Self::Bold | Self::BoldItalic)
+    }
+
+    pub fn is_italic(self) -> bool {
+        matches!(self, Self::Italic | Self::BoldItalic)
+    }
+
+    pub fn with_bold(self, bold: bool) -> Self {
+        Self::from_flags(bold, self.is_italic())
+    }
+
+    pub fn with_italic(self, italic: bool) -> Self {
+        Self::from_flags(self.is_bold(), italic)
+    }
+
+    fn from_flags(bold: bool, italic: bool) -> Self {
+        match (bold, italic) {
+            (false, false) => Self::Normal,
+            (true, false) => Self::Bold,
+            (false, true) => Self::Italic,
+            (true, true) => Self::BoldItalic,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_char_width() {
+        assert_eq!(char_width('a'), 1);
+        assert_eq!(char_width('~'), 1);
+        assert_eq!(char_width('α'), 1);
+        assert_eq!(char_width('\u{10ff}'), 1);
+        assert_eq!(char_width('\u{1100}'), 2);
+        assert_eq!(char_width('あ'), 2);
+        assert_eq!(char_width('漢'), 2);
+        assert_eq!(char_width('\u{4e00}'), 2);
+        assert_eq!(char_width('\u{9fff}'), 2);
+        assert_eq!(char_width('한'), 2);
+        assert_eq!(char_width('Ａ'), 2); // fullwidth
+        assert_eq!(char_width('ｱ'), 1); // halfwidth
+        assert_eq!(char_width('\u{3fffd}'), 2);
+        assert_eq!(char_width('\u{3fffe}'), 1);
+        assert!(WIDE_RANGES.windows(2).all(|w| w[0].1 < w[1].0));
+    }
+}
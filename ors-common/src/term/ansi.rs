@@ -1,7 +1,8 @@
 //! This module handles a subset of ANSI escape codes.
+//!
+//! This module only depends on `core` and `log` so that it can also be built for the host by the
+//! fuzz targets in `fuzz/`.
 
-use super::Input;
-use core::convert::{TryFrom, TryInto};
 use log::trace;
 
 #[derive(Debug)]
@@ -9,8 +10,14 @@ pub struct Decoder {
     state: State,
 }
 
+impl Default for Decoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Decoder {
-    pub fn new() -> Self {
+    pub const fn new() -> Self {
         Self { state: State::Init }
     }
 
@@ -18,9 +25,11 @@ impl Decoder {
         use State::*;
 
         fn param(n: Option<u32>, ch: char) -> Option<u32> {
+            // Too large parameters are saturated, and then rejected as unsupported values
+            let d = ch.to_digit(10).unwrap();
             Some(match n {
-                Some(n) => n * 10 + ch.to_digit(10).unwrap(),
-                None => ch.to_digit(10).unwrap(),
+                Some(n) => n.saturating_mul(10).saturating_add(d),
+                None => d,
             })
         }
 
@@ -30,6 +39,12 @@ impl Decoder {
                 self.complete_state(DecodeResult::Just(ch))
             }
             ('[', Esc) => self.continue_state(Csi(None)), // Control Sequence Introducer
+            ('O', Esc) => self.continue_state(Ss3),       // Single Shift 3
+            ('P'..='S', Ss3) => self.complete_state(DecodeResult::EscapeSequence(
+                EscapeSequence::Function(ch as u8 - b'P' + 1, 1),
+            )),
+            ('?', Csi(None)) => self.continue_state(CsiPrivate(None)),
+            ('0'..='9', CsiPrivate(n)) => self.continue_state(CsiPrivate(param(n, ch))),
             ('0'..='9', Csi(n)) => self.continue_state(Csi(param(n, ch))),
             ('0'..='9', Csi2(n, m)) => self.continue_state(Csi2(n, param(m, ch))),
             ('0'..='9', Csi3(n, m, l)) => self.continue_state(Csi3(n, m, param(l, ch))),
@@ -41,20 +56,42 @@ impl Decoder {
             }
             (c, Csi(n)) => match EscapeSequence::from_csi(n, None, None, c) {
                 Ok(es) => self.complete_state(DecodeResult::EscapeSequence(es)),
-                Err(()) => self.incomplete_state(ch),
+                Err(Unsupported) => self.incomplete_state(ch),
             },
             (c, Csi2(n, m)) => match EscapeSequence::from_csi(n, m, None, c) {
                 Ok(es) => self.complete_state(DecodeResult::EscapeSequence(es)),
-                Err(()) => self.incomplete_state(ch),
+                Err(Unsupported) => self.incomplete_state(ch),
             },
             (c, Csi3(n, m, l)) => match EscapeSequence::from_csi(n, m, l, c) {
                 Ok(es) => self.complete_state(DecodeResult::EscapeSequence(es)),
-                Err(()) => self.incomplete_state(ch),
+                Err(Unsupported) => self.incomplete_state(ch),
+            },
+            (c, CsiPrivate(n)) => match EscapeSequence::from_private_csi(n, c) {
+                Ok(es) => self.complete_state(DecodeResult::EscapeSequence(es)),
+                Err(Unsupported) => self.incomplete_state(ch),
             },
             _ => self.incomplete_state(ch),
         }
     }
 
+    /// Whether the decoder is not in the middle of an escape sequence.
+    pub fn is_idle(&self) -> bool {
+        self.state == State::Init
+    }
+
+    /// Abandon the escape sequence in the middle, such as when the rest of it does not arrive in
+    /// time. A lone ESC is decoded as is.
+    pub fn flush(&mut self) -> Option<DecodeResult> {
+        match core::mem::replace(&mut self.state, State::Init) {
+            State::Init => None,
+            State::Esc => Some(DecodeResult::Just('\x1b')),
+            state => {
+                trace!("ansi: Incomplete escape sequence: {:?}", state);
+                None
+            }
+        }
+    }
+
     fn continue_state(&mut self, state: State) -> Option<DecodeResult> {
         self.state = state;
         None
@@ -89,9 +126,11 @@ impl Decoder {
 enum State {
     Init,
     Esc,                                         // ^[
+    Ss3,                                         // ^[ O
     Csi(Option<u32>),                            // ^[ [ n
     Csi2(Option<u32>, Option<u32>),              // ^[ [ n ; m
     Csi3(Option<u32>, Option<u32>, Option<u32>), // ^[ [ n ; m ; l
+    CsiPrivate(Option<u32>),                     // ^[ [ ? n
 }
 
 #[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy, Hash)]
@@ -100,17 +139,7 @@ pub enum DecodeResult {
     EscapeSequence(EscapeSequence),
 }
 
-impl TryFrom<DecodeResult> for Input {
-    type Error = ();
-
-    fn try_from(value: DecodeResult) -> Result<Self, Self::Error> {
-        match value {
-            DecodeResult::Just(a) => Ok(Input::Char(a)),
-            DecodeResult::EscapeSequence(s) => s.try_into(),
-        }
-    }
-}
-
+#[cfg_attr(fuzzing, derive(arbitrary::Arbitrary))]
 #[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy, Hash)]
 pub enum EscapeSequence {
     CursorUp(u32),
@@ -124,6 +153,7 @@ pub enum EscapeSequence {
     EraseInDisplay(u32),
     EraseInLine(u32),
     HorizontalVerticalPosition(u32, u32),
+    DeviceStatusReport, // Cursor Position Report is requested
     Sgr(Sgr),
     Sgr2(Sgr, Sgr),
     Sgr3(Sgr, Sgr, Sgr),
@@ -133,10 +163,24 @@ pub enum EscapeSequence {
     End,
     PgUp,
     PgDn,
+    AlternateScreen(bool), // switch to (true) or back from (false) the alternate screen
+    /// Function key F1-F12 with the xterm-style modifier parameter, 1 + (Shift: 1 | Alt: 2 |
+    /// Ctrl: 4). F1-F4 are `ESC O P`-`S` or `ESC [ 1 ; m P`-`S` (xterm) and `ESC [ 11 ~`-`14 ~`
+    /// (vt220), and F5-F12 are `ESC [ n ~` or `ESC [ n ; m ~`.
+    Function(u8, u32),
 }
 
+/// The parameters or the final character of an escape sequence are not supported.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct Unsupported;
+
 impl EscapeSequence {
-    pub fn from_csi(n: Option<u32>, m: Option<u32>, l: Option<u32>, ch: char) -> Result<Self, ()> {
+    pub fn from_csi(
+        n: Option<u32>,
+        m: Option<u32>,
+        l: Option<u32>,
+        ch: char,
+    ) -> Result<Self, Unsupported> {
         use EscapeSequence::*;
         Ok(match ch {
             'A' => CursorUp(n.unwrap_or(1)),
@@ -151,7 +195,9 @@ impl EscapeSequence {
             'K' => EraseInLine(n.unwrap_or(0)),
             'f' => HorizontalVerticalPosition(n.unwrap_or(1), m.unwrap_or(1)),
             'm' => Self::from_sgr_params(n.unwrap_or(0), m, l)?,
-            '~' => match n.ok_or(())? {
+            'n' if n == Some(6) => DeviceStatusReport,
+            'P'..='S' if n == Some(1) => Function(ch as u8 - b'P' + 1, m.unwrap_or(1)),
+            '~' => match n.ok_or(Unsupported)? {
                 1 => Home,
                 2 => Insert,
                 3 => Delete,
@@ -160,13 +206,25 @@ impl EscapeSequence {
                 6 => PgDn,
                 7 => Home,
                 8 => End,
-                _ => Err(())?,
+                n @ 11..=15 => Function(n as u8 - 10, m.unwrap_or(1)),
+                n @ 17..=21 => Function(n as u8 - 11, m.unwrap_or(1)),
+                n @ 23..=24 => Function(n as u8 - 12, m.unwrap_or(1)),
+                _ => return Err(Unsupported),
             },
-            _ => Err(())?,
+            _ => return Err(Unsupported),
+        })
+    }
+
+    /// Parse a DEC private mode sequence, `ESC [ ? n h` or `ESC [ ? n l`.
+    pub fn from_private_csi(n: Option<u32>, ch: char) -> Result<Self, Unsupported> {
+        Ok(match (n.ok_or(Unsupported)?, ch) {
+            (1049, 'h') => Self::AlternateScreen(true),
+            (1049, 'l') => Self::AlternateScreen(false),
+            _ => return Err(Unsupported),
         })
     }
 
-    pub fn from_sgr_params(n: u32, m: Option<u32>, l: Option<u32>) -> Result<Self, ()> {
+    pub fn from_sgr_params(n: u32, m: Option<u32>, l: Option<u32>) -> Result<Self, Unsupported> {
         Ok(match (n, m, l) {
             (38, Some(5), Some(n)) => Self::Sgr(Sgr::Fg(Color::from_256(n)?)),
             (48, Some(5), Some(n)) => Self::Sgr(Sgr::Bg(Color::from_256(n)?)),
@@ -177,34 +235,13 @@ impl EscapeSequence {
                 Sgr::from_param(m)?,
                 Sgr::from_param(l)?,
             ),
-            _ => Err(())?,
-        })
-    }
-}
-
-impl TryFrom<EscapeSequence> for Input {
-    type Error = ();
-
-    fn try_from(value: EscapeSequence) -> Result<Self, Self::Error> {
-        Ok(match value {
-            EscapeSequence::CursorUp(1) => Input::ArrowUp,
-            EscapeSequence::CursorDown(1) => Input::ArrowDown,
-            EscapeSequence::CursorForward(1) => Input::ArrowRight,
-            EscapeSequence::CursorBack(1) => Input::ArrowLeft,
-            EscapeSequence::CursorPreviousLine(1) => Input::End,
-            EscapeSequence::CursorPosition(1, 1) => Input::Home,
-            EscapeSequence::Home => Input::Home,
-            EscapeSequence::Insert => Input::Insert,
-            EscapeSequence::Delete => Input::Char('\x7f'),
-            EscapeSequence::End => Input::End,
-            EscapeSequence::PgUp => Input::PageUp,
-            EscapeSequence::PgDn => Input::PageDown,
-            _ => Err(())?,
+            _ => return Err(Unsupported),
         })
     }
 }
 
 /// Select Graphic Rendition
+#[cfg_attr(fuzzing, derive(arbitrary::Arbitrary))]
 #[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy, Hash)]
 pub enum Sgr {
     Reset,
@@ -222,7 +259,7 @@ pub enum Sgr {
 }
 
 impl Sgr {
-    pub fn from_param(n: u32) -> Result<Self, ()> {
+    pub fn from_param(n: u32) -> Result<Self, Unsupported> {
         use NamedColor::*;
         use NamedColorVariation::*;
         use Sgr::*;
@@ -277,11 +314,12 @@ impl Sgr {
             105 => Bg(Color::Named(Magenta, ForceBrighter)),
             106 => Bg(Color::Named(Cyan, ForceBrighter)),
             107 => Bg(Color::Named(White, ForceBrighter)),
-            _ => Err(())?,
+            _ => return Err(Unsupported),
         })
     }
 }
 
+#[cfg_attr(fuzzing, derive(arbitrary::Arbitrary))]
 #[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy, Hash)]
 pub enum Color {
     Default,
@@ -291,7 +329,7 @@ pub enum Color {
 }
 
 impl Color {
-    pub fn from_256(n: u32) -> Result<Color, ()> {
+    pub fn from_256(n: u32) -> Result<Color, Unsupported> {
         use Color::*;
         use NamedColor::*;
         use NamedColorVariation::*;
@@ -314,7 +352,7 @@ impl Color {
             15 => Named(White, ForceBrighter),
             16..=231 => Rgb((n - 16) as u8),
             232..=255 => Grayscale((n - 232) as u8),
-            _ => Err(())?,
+            _ => return Err(Unsupported),
         })
     }
 
@@ -337,6 +375,7 @@ impl Color {
     }
 }
 
+#[cfg_attr(fuzzing, derive(arbitrary::Arbitrary))]
 #[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy, Hash)]
 pub enum NamedColor {
     Black,
@@ -349,6 +388,7 @@ pub enum NamedColor {
     White,
 }
 
+#[cfg_attr(fuzzing, derive(arbitrary::Arbitrary))]
 #[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy, Hash)]
 pub enum NamedColorVariation {
     ForceDimmer,
@@ -365,11 +405,11 @@ impl NamedColorVariation {
 
 pub trait ColorScheme {
     fn get_fg(&self, color: Color) -> (u8, u8, u8) {
-        self.get(color).unwrap_or(self.foreground())
+        self.get(color).unwrap_or_else(|| self.foreground())
     }
 
     fn get_bg(&self, color: Color) -> (u8, u8, u8) {
-        self.get(color).unwrap_or(self.background())
+        self.get(color).unwrap_or_else(|| self.background())
     }
 
     fn get(&self, color: Color) -> Option<(u8, u8, u8)> {
@@ -404,6 +444,30 @@ pub trait ColorScheme {
         })
     }
 
+    /// The default colors followed by the 16 named colors.
+    fn palette(&self) -> [(u8, u8, u8); 18] {
+        [
+            self.foreground(),
+            self.background(),
+            self.black(),
+            self.red(),
+            self.green(),
+            self.yellow(),
+            self.blue(),
+            self.magenta(),
+            self.cyan(),
+            self.white(),
+            self.bright_black(),
+            self.bright_red(),
+            self.bright_green(),
+            self.bright_yellow(),
+            self.bright_blue(),
+            self.bright_magenta(),
+            self.bright_cyan(),
+            self.bright_white(),
+        ]
+    }
+
     fn foreground(&self) -> (u8, u8, u8);
     fn background(&self) -> (u8, u8, u8);
     fn black(&self) -> (u8, u8, u8);
@@ -423,3 +487,130 @@ pub trait ColorScheme {
     fn bright_cyan(&self) -> (u8, u8, u8);
     fn bright_white(&self) -> (u8, u8, u8);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    #[test]
+    fn test_device_status_report() {
+        let mut decoder = Decoder::new();
+        let results = "\x1b[6n".chars().filter_map(|ch| decoder.add_char(ch));
+        assert!(results.eq([DecodeResult::EscapeSequence(
+            EscapeSequence::DeviceStatusReport
+        )]));
+        assert_eq!(
+            EscapeSequence::from_csi(Some(5), None, None, 'n'),
+            Err(Unsupported)
+        );
+    }
+
+    #[test]
+    fn test_alternate_screen() {
+        let mut decoder = Decoder::new();
+        let results = "\x1b[?1049hA\x1b[?1049l\x1b[?25lB"
+            .chars()
+            .filter_map(|ch| decoder.add_char(ch))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            results,
+            [
+                DecodeResult::EscapeSequence(EscapeSequence::AlternateScreen(true)),
+                DecodeResult::Just('A'),
+                DecodeResult::EscapeSequence(EscapeSequence::AlternateScreen(false)),
+                DecodeResult::Just('l'), // unsupported private mode
+                DecodeResult::Just('B'),
+            ]
+        );
+        assert!(decoder.is_idle());
+    }
+
+    #[test]
+    fn test_function_keys() {
+        let cases = [
+            // xterm
+            ("\x1bOP", 1, 1),
+            ("\x1bOQ", 2, 1),
+            ("\x1bOR", 3, 1),
+            ("\x1bOS", 4, 1),
+            ("\x1b[1;3P", 1, 3),
+            ("\x1b[1;5Q", 2, 5),
+            ("\x1b[1;2R", 3, 2),
+            ("\x1b[1;3S", 4, 3),
+            // vt220
+            ("\x1b[11~", 1, 1),
+            ("\x1b[12~", 2, 1),
+            ("\x1b[13~", 3, 1),
+            ("\x1b[14~", 4, 1),
+            ("\x1b[15~", 5, 1),
+            ("\x1b[17~", 6, 1),
+            ("\x1b[18~", 7, 1),
+            ("\x1b[19~", 8, 1),
+            ("\x1b[20~", 9, 1),
+            ("\x1b[21~", 10, 1),
+            ("\x1b[23~", 11, 1),
+            ("\x1b[24~", 12, 1),
+            ("\x1b[15;3~", 5, 3),
+            ("\x1b[24;5~", 12, 5),
+        ];
+        for (s, n, m) in cases {
+            let mut decoder = Decoder::new();
+            let results = s
+                .chars()
+                .filter_map(|ch| decoder.add_char(ch))
+                .collect::<Vec<_>>();
+            assert_eq!(
+                results,
+                [DecodeResult::EscapeSequence(EscapeSequence::Function(n, m))],
+                "{:?}",
+                s
+            );
+            assert!(decoder.is_idle());
+        }
+
+        for n in [9, 10, 16, 22, 25] {
+            assert_eq!(
+                EscapeSequence::from_csi(Some(n), None, None, '~'),
+                Err(Unsupported)
+            );
+        }
+        assert_eq!(
+            EscapeSequence::from_csi(Some(2), None, None, 'P'),
+            Err(Unsupported)
+        );
+        let mut decoder = Decoder::new();
+        let results = "\x1bOTA"
+            .chars()
+            .filter_map(|ch| decoder.add_char(ch))
+            .collect::<Vec<_>>();
+        assert_eq!(results, [DecodeResult::Just('T'), DecodeResult::Just('A')]);
+    }
+
+    #[test]
+    fn test_flush() {
+        let mut decoder = Decoder::new();
+        assert_eq!(decoder.flush(), None);
+        assert_eq!(decoder.add_char('\x1b'), None);
+        assert_eq!(decoder.flush(), Some(DecodeResult::Just('\x1b')));
+        assert!(decoder.is_idle());
+        let results = "\x1b[1;"
+            .chars()
+            .filter_map(|ch| decoder.add_char(ch))
+            .collect::<Vec<_>>();
+        assert_eq!(results, []);
+        assert_eq!(decoder.flush(), None);
+        assert_eq!(decoder.add_char('A'), Some(DecodeResult::Just('A')));
+    }
+
+    #[test]
+    fn test_large_param() {
+        let mut decoder = Decoder::new();
+        let results = "\x1b[99999999999999mA"
+            .chars()
+            .filter_map(|ch| decoder.add_char(ch))
+            .collect::<Vec<_>>();
+        assert_eq!(results, [DecodeResult::Just('m'), DecodeResult::Just('A')]);
+        assert!(decoder.is_idle());
+    }
+}
@@ -0,0 +1,650 @@
+use super::{char_width, FontStyle, Rgb};
+use alloc::collections::VecDeque;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ops::Range;
+
+/// The cells of the screen and the cursor. The changes since the last `take_damage` are tracked
+/// so that only the changed part of the screen is rendered.
+#[derive(Debug)]
+pub struct Grid {
+    lines: VecDeque<Line>,
+    cursor: (usize, usize),
+    /// The cells and the cursor of the primary screen, saved while the alternate screen is shown.
+    primary: Option<SavedScreen>,
+    damage: RenderDiff,
+    /// The number of lines scrolled since the last `take_damage`.
+    scrolled: usize,
+}
+
+/// The cells and the cursor of a screen.
+type SavedScreen = (Vec<Vec<Cell>>, (usize, usize));
+
+/// The changes of the grid, see `Grid::take_damage`.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Damage {
+    /// The number of lines scrolled. The renderer must shift the rendered lines up by this number
+    /// (modulo the number of lines) before rendering the changed lines, since the scrolled lines
+    /// are reused at the bottom instead of being cleared entirely.
+    pub scrolled: usize,
+    /// The changed lines.
+    pub lines: Range<usize>,
+}
+
+impl Grid {
+    /// A grid of void cells, which are not reported as damaged so that whatever is on the screen
+    /// stays until it is overwritten.
+    pub fn new(columns: usize, lines: usize) -> Self {
+        let line = Line {
+            cells: vec![Cell::void(); columns],
+            damage: None,
+        };
+        Self {
+            lines: vec![line; lines].into(),
+            cursor: (0, 0),
+            primary: None,
+            damage: None,
+            scrolled: 0,
+        }
+    }
+
+    /// Resize the grid: lines are truncated or padded on the right, and the top lines are dropped
+    /// if the cursor line no longer fits. The whole grid is reported as damaged.
+    pub fn resize(&mut self, columns: usize, lines: usize) {
+        let cells = self.lines.iter().map(|l| l.cells.clone()).collect();
+        let (cells, cursor) = fit_grid(cells, self.cursor, columns, lines);
+        self.lines = cells
+            .into_iter()
+            .map(|cells| Line {
+                damage: (!cells.is_empty()).then(|| (0, cells.len())),
+                cells,
+            })
+            .collect();
+        self.cursor = cursor;
+        if let Some((cells, cursor)) = self.primary.take() {
+            self.primary = Some(fit_grid(cells, cursor, columns, lines));
+        }
+        self.damage = Some((0, self.lines.len()));
+        self.scrolled = 0;
+    }
+
+    /// The number of columns and lines.
+    pub fn size(&self) -> (usize, usize) {
+        (self.lines[0].cells.len(), self.lines.len())
+    }
+
+    pub fn line(&self, y: usize) -> &[Cell] {
+        &self.lines[y].cells
+    }
+
+    pub fn cursor(&self) -> (usize, usize) {
+        self.cursor
+    }
+
+    pub fn move_cursor(&mut self, dx: i32, dy: i32) {
+        let (x, y) = self.cursor;
+        let y = (y as i32 + dy).clamp(0, self.lines.len() as i32 - 1) as usize;
+        let x = (x as i32 + dx).clamp(0, self.lines[y].cells.len() as i32 - 1) as usize;
+        self.cursor = (x, y);
+    }
+
+    pub fn set_cursor(&mut self, x: Option<u32>, y: Option<u32>) {
+        let y = y
+            .map(|n| n as usize)
+            .unwrap_or(self.cursor.1)
+            .clamp(0, self.lines.len() - 1);
+        let x = x
+            .map(|n| n as usize)
+            .unwrap_or(self.cursor.0)
+            .clamp(0, self.lines[y].cells.len() - 1);
+        self.cursor = (x, y);
+    }
+
+    /// Erase cells around the cursor, as ED and EL do. The cursor cell itself is always erased.
+    /// Erased cells are reset to a space with the given colors and the normal font style.
+    pub fn erase(
+        &mut self,
+        fg: Rgb,
+        bg: Rgb,
+        before_cursor_lines: bool,
+        before_cursor_chars: bool,
+        after_cursor_chars: bool,
+        after_cursor_lines: bool,
+    ) {
+        let (x, y) = self.cursor;
+        // After writing the last column, the cursor stays on it until the next character wraps
+        let x = x.min(self.lines[y].cells.len() - 1);
+        let mut start = usize::MAX;
+        let mut end = 0;
+        if before_cursor_lines {
+            for (i, l) in self.lines.iter_mut().enumerate().take(y) {
+                if l.erase(fg, bg, 0..usize::MAX) {
+                    start = start.min(i);
+                    end = end.max(i + 1);
+                }
+            }
+        }
+        {
+            let a = if before_cursor_chars { 0 } else { x };
+            let b = if after_cursor_chars {
+                usize::MAX
+            } else {
+                x + 1
+            };
+            if self.lines[y].erase(fg, bg, a..b) {
+                start = start.min(y);
+                end = end.max(y + 1);
+            }
+        }
+        if after_cursor_lines {
+            for (i, l) in self.lines.iter_mut().enumerate().skip(y + 1) {
+                if l.erase(fg, bg, 0..usize::MAX) {
+                    start = start.min(i);
+                    end = end.max(i + 1);
+                }
+            }
+        }
+        if start < end {
+            extend_render_diff(&mut self.damage, start, end);
+        }
+    }
+
+    /// Switch to the alternate screen, which starts out blank with the cursor at the top-left.
+    /// Does nothing if the alternate screen is already shown.
+    pub fn enter_alternate_screen(&mut self, fg: Rgb, bg: Rgb) {
+        if self.primary.is_none() {
+            let cells = self.lines.iter().map(|l| l.cells.clone()).collect();
+            self.primary = Some((cells, self.cursor));
+            self.set_cursor(Some(0), Some(0));
+            self.erase(fg, bg, true, true, true, true);
+        }
+    }
+
+    /// Switch back to the primary screen, restoring its cells and cursor.
+    pub fn leave_alternate_screen(&mut self) {
+        if let Some((cells, cursor)) = self.primary.take() {
+            for (i, (line, cells)) in self.lines.iter_mut().zip(cells).enumerate() {
+                if line.restore(cells) {
+                    extend_render_diff(&mut self.damage, i, i + 1);
+                }
+            }
+            self.cursor = cursor;
+        }
+    }
+
+    /// Move the cursor to the beginning of the next line. At the bottom, the grid is scrolled up
+    /// and `scrolled_out` is called with the top line before it is erased.
+    pub fn next_line(&mut self, fg: Rgb, bg: Rgb, scrolled_out: &mut impl FnMut(&[Cell])) {
+        let (_, y) = self.cursor;
+        if y + 1 >= self.lines.len() {
+            let mut first_line = self.lines.pop_front().unwrap(); // remove the first line
+            scrolled_out(&first_line.cells);
+            first_line.erase(fg, bg, 0..usize::MAX);
+            self.lines.push_back(first_line);
+            self.damage = Some((0, self.lines.len())); // all lines
+            self.scrolled += 1;
+            self.cursor = (0, self.lines.len() - 1);
+        } else {
+            self.cursor = (0, y + 1);
+        }
+    }
+
+    pub fn put(
+        &mut self,
+        c: char,
+        fg: Rgb,
+        bg: Rgb,
+        style: FontStyle,
+        scrolled_out: &mut impl FnMut(&[Cell]),
+    ) {
+        let (x, y) = self.cursor;
+        match self.lines[y].put(c, fg, bg, style, x) {
+            LinePutResult::LineFeed => self.next_line(fg, bg, scrolled_out),
+            LinePutResult::Wrapping => {
+                self.next_line(fg, bg, scrolled_out);
+                self.put(c, fg, bg, style, scrolled_out);
+            }
+            LinePutResult::Next(changed, x) => {
+                self.cursor = (x, y);
+                if changed {
+                    extend_render_diff(&mut self.damage, y, y + 1);
+                }
+            }
+        }
+    }
+
+    /// Take the changes since the last call. The changed cells of each line in `Damage::lines`
+    /// are then taken by `take_line_damage`.
+    pub fn take_damage(&mut self) -> Option<Damage> {
+        let (a, b) = self.damage.take()?;
+        Some(Damage {
+            scrolled: core::mem::take(&mut self.scrolled),
+            lines: a..b,
+        })
+    }
+
+    /// Take the range of the changed cells of the line since the last call.
+    pub fn take_line_damage(&mut self, y: usize) -> Option<Range<usize>> {
+        let (a, b) = self.lines[y].damage.take()?;
+        Some(a..b)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Line {
+    cells: Vec<Cell>,
+    damage: RenderDiff,
+}
+
+impl Line {
+    fn erase(&mut self, fg: Rgb, bg: Rgb, range: Range<usize>) -> bool {
+        let mut start = usize::MAX;
+        let mut end = 0;
+        let range = range.start..range.end.min(self.cells.len());
+        if range.is_empty() {
+            return false;
+        }
+        // Wide characters partially covered by the range are broken
+        let broken = self.break_wide_char(range.start) | self.break_wide_char(range.end - 1);
+        for (i, c) in self
+            .cells
+            .iter_mut()
+            .enumerate()
+            .take(range.end)
+            .skip(range.start)
+        {
+            if c.erase(fg, bg) {
+                start = start.min(i);
+                end = end.max(i + 1);
+            }
+        }
+        if start < end {
+            extend_render_diff(&mut self.damage, start, end);
+            true
+        } else {
+            broken
+        }
+    }
+
+    fn restore(&mut self, cells: Vec<Cell>) -> bool {
+        let mut start = usize::MAX;
+        let mut end = 0;
+        for (i, (c, new_c)) in self.cells.iter_mut().zip(cells).enumerate() {
+            if c.update(new_c) {
+                start = start.min(i);
+                end = end.max(i + 1);
+            }
+        }
+        if start < end {
+            extend_render_diff(&mut self.damage, start, end);
+            true
+        } else {
+            false
+        }
+    }
+
+    fn put(&mut self, c: char, fg: Rgb, bg: Rgb, style: FontStyle, i: usize) -> LinePutResult {
+        // A wide character that does not fit in the line is treated as a narrow one
+        let width = if self.cells.len() < 2 {
+            1
+        } else {
+            char_width(c) as usize
+        };
+        if c == '\n' {
+            LinePutResult::LineFeed
+        } else if i + width > self.cells.len() {
+            LinePutResult::Wrapping
+        } else {
+            let mut changed = false;
+            for j in i..i + width {
+                changed |= self.break_wide_char(j);
+            }
+            let mut updated = self.cells[i].update(Cell::new(c, fg, bg, style));
+            if width == 2 {
+                updated |= self.cells[i + 1].update(Cell::wide_continue(fg, bg, style));
+            }
+            if updated {
+                extend_render_diff(&mut self.damage, i, i + width);
+            }
+            LinePutResult::Next(changed || updated, i + width)
+        }
+    }
+
+    /// Replace the wide character at `i`, or the wide character continued at `i`, with spaces.
+    fn break_wide_char(&mut self, i: usize) -> bool {
+        let start = match self.cells[i].is_wide_continue() {
+            true if i == 0 => return false,
+            true => i - 1,
+            false => i,
+        };
+        match self.cells.get(start + 1) {
+            Some(c) if c.is_wide_continue() => {
+                for c in &mut self.cells[start..start + 2] {
+                    c.value = ' ';
+                }
+                extend_render_diff(&mut self.damage, start, start + 2);
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum LinePutResult {
+    LineFeed,
+    Wrapping,
+    Next(bool, usize),
+}
+
+/// The value of the cell occupied by the right half of the preceding wide character.
+const WIDE_CONTINUE: char = '\u{10ffff}';
+
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy)]
+pub struct Cell {
+    pub value: char,
+    pub fg: Rgb,
+    pub bg: Rgb,
+    pub style: FontStyle,
+}
+
+impl Cell {
+    pub const fn new(value: char, fg: Rgb, bg: Rgb, style: FontStyle) -> Self {
+        Self {
+            value,
+            fg,
+            bg,
+            style,
+        }
+    }
+
+    /// The cell never written, which fills the grid initially.
+    pub const fn void() -> Self {
+        Self::new('\0', (255, 255, 255), (0, 0, 0), FontStyle::Normal)
+    }
+
+    /// The cell occupied by the right half of the preceding wide character, which renders as
+    /// blank by itself.
+    pub const fn wide_continue(fg: Rgb, bg: Rgb, style: FontStyle) -> Self {
+        Self::new(WIDE_CONTINUE, fg, bg, style)
+    }
+
+    pub fn is_wide_continue(&self) -> bool {
+        self.value == WIDE_CONTINUE
+    }
+
+    fn erase(&mut self, fg: Rgb, bg: Rgb) -> bool {
+        self.update(Self::new(' ', fg, bg, FontStyle::Normal))
+    }
+
+    fn update(&mut self, new_self: Self) -> bool {
+        if *self != new_self {
+            *self = new_self;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Fit the grid of cells and the cursor into `columns` x `lines`. Lines are truncated or padded
+/// on the right, and the top lines are dropped so that the cursor line is kept.
+fn fit_grid(
+    grid: Vec<Vec<Cell>>,
+    cursor: (usize, usize),
+    columns: usize,
+    lines: usize,
+) -> SavedScreen {
+    let skip = (cursor.1 + 1).saturating_sub(lines);
+    let mut grid = grid
+        .into_iter()
+        .skip(skip)
+        .take(lines)
+        .map(|mut cells| {
+            // A wide character whose right half is truncated is broken
+            if cells.get(columns).map_or(false, |c| c.is_wide_continue()) {
+                cells[columns - 1].value = ' ';
+            }
+            cells.resize(columns, Cell::void());
+            cells
+        })
+        .collect::<Vec<_>>();
+    grid.resize(lines, vec![Cell::void(); columns]);
+    // The cursor may be past the last column, see `erase`
+    let cursor = (cursor.0.min(columns), cursor.1 - skip);
+    (grid, cursor)
+}
+
+type RenderDiff = Option<(usize, usize)>;
+
+fn extend_render_diff(a: &mut RenderDiff, start: usize, end: usize) {
+    *a = match *a {
+        None => Some((start, end)),
+        Some((a, b)) => Some((a.min(start), b.max(end))),
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FG: Rgb = (10, 20, 30);
+    const BG: Rgb = (40, 50, 60);
+    const ERASE_FG: Rgb = (255, 255, 255);
+    const ERASE_BG: Rgb = (0, 0, 128);
+
+    fn put(grid: &mut Grid, c: char, style: FontStyle) {
+        grid.put(c, FG, BG, style, &mut |_| {});
+    }
+
+    // 5 columns, 3 lines
+    fn filled_grid() -> Grid {
+        let mut grid = Grid::new(5, 3);
+        for y in 0..3 {
+            grid.set_cursor(Some(0), Some(y));
+            for c in "abcde".chars() {
+                put(&mut grid, c, FontStyle::Bold);
+            }
+        }
+        grid
+    }
+
+    fn cells(grid: &Grid) -> Vec<Vec<Cell>> {
+        grid.lines.iter().map(|l| l.cells.clone()).collect()
+    }
+
+    fn expected_cells(erased: impl Fn(usize, usize) -> bool) -> Vec<Vec<Cell>> {
+        (0..3)
+            .map(|y| {
+                "abcde"
+                    .chars()
+                    .enumerate()
+                    .map(|(x, c)| {
+                        if erased(x, y) {
+                            Cell::new(' ', ERASE_FG, ERASE_BG, FontStyle::Normal)
+                        } else {
+                            Cell::new(c, FG, BG, FontStyle::Bold)
+                        }
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_erase() {
+        type Erased = fn((usize, usize), (usize, usize)) -> bool;
+        let modes: [(&str, [bool; 4], Erased); 6] = [
+            ("ED0", [false, false, true, true], |(x, y), (cx, cy)| {
+                y > cy || (y == cy && x >= cx)
+            }),
+            ("ED1", [true, true, false, false], |(x, y), (cx, cy)| {
+                y < cy || (y == cy && x <= cx)
+            }),
+            ("ED2", [true, true, true, true], |_, _| true),
+            ("EL0", [false, false, true, false], |(x, y), (cx, cy)| {
+                y == cy && x >= cx
+            }),
+            ("EL1", [false, true, false, false], |(x, y), (cx, cy)| {
+                y == cy && x <= cx
+            }),
+            ("EL2", [false, true, true, false], |(_, y), (_, cy)| y == cy),
+        ];
+        for (name, [a, b, c, d], erased) in modes {
+            for cx in [0, 2, 4] {
+                let mut grid = filled_grid();
+                grid.set_cursor(Some(cx as u32), Some(1));
+                grid.erase(ERASE_FG, ERASE_BG, a, b, c, d);
+                assert_eq!(
+                    cells(&grid),
+                    expected_cells(|x, y| erased((x, y), (cx, 1))),
+                    "{} at column {}",
+                    name,
+                    cx
+                );
+                assert_eq!(grid.cursor(), (cx, 1));
+            }
+        }
+    }
+
+    #[test]
+    fn test_erase_after_last_column() {
+        // The cursor is past the last column until the next character wraps
+        let mut grid = filled_grid();
+        assert_eq!(grid.cursor(), (5, 2));
+        grid.erase(ERASE_FG, ERASE_BG, false, false, true, false);
+        assert_eq!(cells(&grid), expected_cells(|x, y| (x, y) == (4, 2)));
+
+        let mut grid = filled_grid();
+        grid.erase(ERASE_FG, ERASE_BG, false, true, false, false);
+        assert_eq!(cells(&grid), expected_cells(|_, y| y == 2));
+    }
+
+    #[test]
+    fn test_alternate_screen() {
+        let mut grid = filled_grid();
+        grid.set_cursor(Some(2), Some(1));
+        grid.enter_alternate_screen(ERASE_FG, ERASE_BG);
+        assert_eq!(cells(&grid), expected_cells(|_, _| true));
+        assert_eq!(grid.cursor(), (0, 0));
+
+        put(&mut grid, 'x', FontStyle::Bold);
+        grid.enter_alternate_screen(ERASE_FG, ERASE_BG); // already shown
+        assert_eq!(grid.line(0)[0], Cell::new('x', FG, BG, FontStyle::Bold));
+
+        grid.take_damage();
+        grid.leave_alternate_screen();
+        assert_eq!(cells(&grid), expected_cells(|_, _| false));
+        assert_eq!(grid.cursor(), (2, 1));
+        assert_eq!(grid.take_damage().map(|d| d.lines), Some(0..3));
+        grid.leave_alternate_screen(); // already left
+        assert_eq!(cells(&grid), expected_cells(|_, _| false));
+    }
+
+    #[test]
+    fn test_wide_char() {
+        let c = |ch| Cell::new(ch, FG, BG, FontStyle::Normal);
+        let w = Cell::wide_continue(FG, BG, FontStyle::Normal);
+        let mut grid = filled_grid();
+        grid.set_cursor(Some(0), Some(0));
+        for ch in "a漢字".chars() {
+            put(&mut grid, ch, FontStyle::Normal);
+        }
+        assert_eq!(grid.cursor(), (5, 0));
+        assert_eq!(grid.line(0), [c('a'), c('漢'), w, c('字'), w]);
+
+        // A wide character that does not fit in the rest of the line wraps
+        grid.set_cursor(Some(4), Some(1));
+        put(&mut grid, '字', FontStyle::Normal);
+        assert_eq!(grid.cursor(), (2, 2));
+        assert_eq!(grid.line(2)[0..2], [c('字'), w]);
+
+        // Overwriting either half of a wide character breaks it
+        grid.set_cursor(Some(2), Some(0));
+        put(&mut grid, 'x', FontStyle::Normal);
+        assert_eq!(grid.line(0), [c('a'), c(' '), c('x'), c('字'), w]);
+        grid.set_cursor(Some(1), Some(2));
+        grid.erase(ERASE_FG, ERASE_BG, false, false, false, false);
+        let erased = Cell::new(' ', ERASE_FG, ERASE_BG, FontStyle::Normal);
+        assert_eq!(grid.line(2)[0..2], [c(' '), erased]);
+    }
+
+    #[test]
+    fn test_resize() {
+        let c = |ch| Cell::new(ch, FG, BG, FontStyle::Bold);
+        let mut grid = filled_grid();
+        grid.set_cursor(Some(1), Some(2));
+        grid.take_damage();
+
+        // 3 columns, 2 lines. The first line is dropped to keep the cursor line
+        grid.resize(3, 2);
+        assert_eq!(grid.size(), (3, 2));
+        assert_eq!(grid.cursor(), (1, 1));
+        assert_eq!(cells(&grid), [[c('a'), c('b'), c('c')]; 2]);
+        let damage = grid.take_damage().unwrap();
+        assert_eq!((damage.scrolled, damage.lines), (0, 0..2));
+        assert_eq!(grid.take_line_damage(1), Some(0..3));
+        assert_eq!(grid.take_damage(), None);
+
+        // A wide character cut by the right edge is broken
+        let mut grid = filled_grid();
+        grid.set_cursor(Some(2), Some(0));
+        put(&mut grid, '漢', FontStyle::Bold);
+        grid.set_cursor(Some(0), Some(0));
+        grid.resize(3, 2);
+        assert_eq!(grid.line(0), [c('a'), c('b'), c(' ')]);
+        assert_eq!(grid.cursor(), (0, 0));
+
+        // 8 columns, 6 lines. Lines are padded with void cells
+        let mut grid = filled_grid();
+        grid.enter_alternate_screen(ERASE_FG, ERASE_BG);
+        grid.resize(8, 6);
+        assert_eq!(grid.size(), (8, 6));
+        grid.leave_alternate_screen();
+        assert_eq!(grid.cursor(), (5, 2));
+        let padded = |y| {
+            let mut line = expected_cells(|_, _| false).swap_remove(y);
+            line.resize(8, Cell::void());
+            line
+        };
+        assert_eq!(cells(&grid)[0..3], [padded(0), padded(1), padded(2)]);
+        assert!(cells(&grid)[3..]
+            .iter()
+            .flatten()
+            .all(|c| *c == Cell::void()));
+        put(&mut grid, 'x', FontStyle::Bold);
+        assert_eq!(grid.line(2)[5], c('x'));
+    }
+
+    #[test]
+    fn test_damage() {
+        // Void cells are not damaged, so that the initial content of the screen is kept
+        let mut grid = Grid::new(5, 3);
+        assert_eq!(grid.take_damage(), None);
+
+        put(&mut grid, 'a', FontStyle::Normal);
+        put(&mut grid, 'b', FontStyle::Normal);
+        assert_eq!(grid.take_damage().map(|d| d.lines), Some(0..1));
+        assert_eq!(grid.take_line_damage(0), Some(0..2));
+        assert_eq!(grid.take_line_damage(0), None);
+        // Writing the same cell again changes nothing
+        grid.set_cursor(Some(0), None);
+        put(&mut grid, 'a', FontStyle::Normal);
+        assert_eq!(grid.take_damage(), None);
+
+        // Scrolling damages all lines, and the scrolled lines are passed before they are erased
+        let mut scrolled = Vec::new();
+        for ch in "\n\nc\n\n".chars() {
+            grid.put(ch, FG, BG, FontStyle::Normal, &mut |line| {
+                scrolled.push(line.iter().map(|c| c.value).collect::<Vec<_>>())
+            });
+        }
+        assert_eq!(scrolled.len(), 2);
+        assert_eq!(scrolled[0][0..2], ['a', 'b']);
+        assert_eq!(scrolled[1][0], '\0');
+        let damage = grid.take_damage().unwrap();
+        assert_eq!((damage.scrolled, damage.lines), (2, 0..3));
+        assert_eq!(grid.line(0)[0].value, 'c');
+        assert_eq!(grid.cursor(), (0, 2));
+    }
+}
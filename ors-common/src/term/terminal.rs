@@ -0,0 +1,403 @@
+use super::ansi::{self, Color, ColorScheme, DecodeResult, EscapeSequence, Sgr};
+use super::{Cell, FontStyle, Grid};
+use alloc::format;
+
+/// Where the terminal sends what is not drawn on the grid.
+pub trait Backend {
+    /// Reply to the application as if the terminal user typed `s`, such as the report of the
+    /// cursor position.
+    fn reply(&mut self, s: &str);
+
+    /// Called with each line scrolled off the top of the screen, before it is reused.
+    fn scrolled_out(&mut self, _line: &[Cell]) {}
+}
+
+/// A backend that discards everything.
+impl Backend for () {
+    fn reply(&mut self, _: &str) {}
+}
+
+/// Decodes the output text and applies it to the grid in the colors of the theme `S`.
+#[derive(Debug)]
+pub struct Terminal<S, B> {
+    grid: Grid,
+    decoder: ansi::Decoder,
+    theme: S,
+    fg: Color,
+    bg: Color,
+    font_style: FontStyle,
+    backend: B,
+}
+
+impl<S: ColorScheme, B: Backend> Terminal<S, B> {
+    pub fn new(columns: usize, lines: usize, theme: S, backend: B) -> Self {
+        Self {
+            grid: Grid::new(columns, lines),
+            decoder: ansi::Decoder::new(),
+            theme,
+            fg: Color::Default,
+            bg: Color::Default,
+            font_style: FontStyle::Normal,
+            backend,
+        }
+    }
+
+    pub fn grid(&self) -> &Grid {
+        &self.grid
+    }
+
+    pub fn grid_mut(&mut self) -> &mut Grid {
+        &mut self.grid
+    }
+
+    pub fn backend_mut(&mut self) -> &mut B {
+        &mut self.backend
+    }
+
+    pub fn theme(&self) -> &S {
+        &self.theme
+    }
+
+    /// Change the theme. The screen is cleared since the colors of written cells are fixed.
+    pub fn set_theme(&mut self, theme: S) {
+        self.theme = theme;
+        self.erase(true, true, true, true);
+        self.grid.set_cursor(Some(0), Some(0));
+    }
+
+    /// Get the cursor position as (x, y), 0-origin.
+    pub fn cursor_position(&self) -> (usize, usize) {
+        self.grid.cursor()
+    }
+
+    /// Get the number of columns and lines.
+    pub fn size(&self) -> (usize, usize) {
+        self.grid.size()
+    }
+
+    /// Resize the grid, see `Grid::resize`.
+    pub fn resize(&mut self, columns: usize, lines: usize) {
+        self.grid.resize(columns, lines);
+    }
+
+    /// Discard the escape sequence being decoded.
+    pub fn reset_decoder(&mut self) {
+        self.decoder = ansi::Decoder::new();
+    }
+
+    pub fn put_str(&mut self, s: &str) {
+        for ch in s.chars() {
+            match self.decoder.add_char(ch) {
+                Some(DecodeResult::Just(ch)) => self.put_char(ch),
+                Some(DecodeResult::EscapeSequence(es)) => self.handle_escape_sequence(es),
+                None => {}
+            }
+        }
+    }
+
+    pub fn put_char(&mut self, ch: char) {
+        let backend = &mut self.backend;
+        self.grid.put(
+            ch,
+            self.theme.get_fg(self.fg),
+            self.theme.get_bg(self.bg),
+            self.font_style,
+            &mut |line| backend.scrolled_out(line),
+        );
+    }
+
+    pub fn erase(
+        &mut self,
+        before_cursor_lines: bool,
+        before_cursor_chars: bool,
+        after_cursor_chars: bool,
+        after_cursor_lines: bool,
+    ) {
+        // Erased cells take the current background color, but nothing else of the current SGR
+        self.grid.erase(
+            self.theme.get_fg(Color::Default),
+            self.theme.get_bg(self.bg),
+            before_cursor_lines,
+            before_cursor_chars,
+            after_cursor_chars,
+            after_cursor_lines,
+        );
+    }
+
+    pub fn handle_escape_sequence(&mut self, es: EscapeSequence) {
+        use EscapeSequence::*;
+
+        match es {
+            CursorUp(n) => self.grid.move_cursor(0, -(n as i32)),
+            CursorDown(n) => self.grid.move_cursor(0, n as i32),
+            CursorForward(n) => self.grid.move_cursor(n as i32, 0),
+            CursorBack(n) => self.grid.move_cursor(-(n as i32), 0),
+            CursorNextLine(n) => self.grid.move_cursor(i32::MIN, n as i32),
+            CursorPreviousLine(n) => self.grid.move_cursor(i32::MIN, -(n as i32)),
+            CursorHorizontalAbsolute(n) => self.grid.set_cursor(Some(n - 1), None),
+            CursorPosition(n, m) => self.grid.set_cursor(Some(m - 1), Some(n - 1)),
+            EraseInDisplay(0) => self.erase(false, false, true, true),
+            EraseInDisplay(1) => self.erase(true, true, false, false),
+            EraseInDisplay(2) => self.erase(true, true, true, true),
+            EraseInLine(0) => self.erase(false, false, true, false),
+            EraseInLine(1) => self.erase(false, true, false, false),
+            EraseInLine(2) => self.erase(false, true, true, false),
+            HorizontalVerticalPosition(n, m) => self.grid.set_cursor(Some(m - 1), Some(n - 1)),
+            DeviceStatusReport => {
+                let (x, y) = self.cursor_position();
+                self.backend.reply(&format!("\x1b[{};{}R", y + 1, x + 1));
+            }
+            AlternateScreen(true) => self.grid.enter_alternate_screen(
+                self.theme.get_fg(Color::Default),
+                self.theme.get_bg(self.bg),
+            ),
+            AlternateScreen(false) => self.grid.leave_alternate_screen(),
+            Sgr(a) => self.handle_sgr(a),
+            Sgr2(a, b) => {
+                self.handle_sgr(a);
+                self.handle_sgr(b);
+            }
+            Sgr3(a, b, c) => {
+                self.handle_sgr(a);
+                self.handle_sgr(b);
+                self.handle_sgr(c);
+            }
+            _ => {}
+        }
+    }
+
+    pub fn handle_sgr(&mut self, sgr: Sgr) {
+        use Sgr::*;
+
+        match sgr {
+            Reset => {
+                self.fg = Color::Default;
+                self.bg = Color::Default;
+                self.font_style = FontStyle::Normal;
+            }
+            Bold => {
+                self.font_style = self.font_style.with_bold(true);
+                self.fg = self.fg.brighter();
+            }
+            Faint | ResetBoldFaint => {
+                self.font_style = self.font_style.with_bold(false);
+                self.fg = self.fg.dimmer();
+            }
+            Italic(b) => self.font_style = self.font_style.with_italic(b),
+            Underline(_) => {}     // Unsupported
+            Blinking(_) => {}      // Unsupported
+            Inverse(_) => {}       // Unsupported
+            Hidden(_) => {}        // Unsupported
+            Strikethrough(_) => {} // Unsupported
+            Fg(color) => {
+                self.fg = if self.font_style.is_bold() {
+                    color.brighter()
+                } else {
+                    color.dimmer()
+                }
+            }
+            Bg(color) => self.bg = color,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::theme::Theme;
+    use super::*;
+    use alloc::string::String;
+    use alloc::vec::Vec;
+
+    #[derive(Debug, Default)]
+    struct Recorder {
+        replies: String,
+        scrolled_out: Vec<String>,
+    }
+
+    impl Backend for Recorder {
+        fn reply(&mut self, s: &str) {
+            self.replies.push_str(s);
+        }
+
+        fn scrolled_out(&mut self, line: &[Cell]) {
+            self.scrolled_out.push(text(line));
+        }
+    }
+
+    type TestTerminal = Terminal<Theme, Recorder>;
+
+    /// The text of a line, where void cells are shown as `.`.
+    fn text(line: &[Cell]) -> String {
+        line.iter()
+            .filter(|c| !c.is_wide_continue())
+            .map(|c| if c.value == '\0' { '.' } else { c.value })
+            .collect()
+    }
+
+    fn snapshot(t: &TestTerminal) -> Vec<String> {
+        let (_, lines) = t.size();
+        (0..lines).map(|y| text(t.grid().line(y))).collect()
+    }
+
+    /// Feed the recorded output in chunks to a new terminal.
+    fn feed(columns: usize, lines: usize, chunks: &[&str]) -> TestTerminal {
+        let mut t = Terminal::new(columns, lines, Theme::Dracula, Recorder::default());
+        for s in chunks {
+            t.put_str(s);
+        }
+        t
+    }
+
+    /// (columns, lines, output, grid, cursor)
+    type GoldenCase<'a> = (usize, usize, &'a [&'a str], &'a [&'a str], (usize, usize));
+
+    #[test]
+    fn test_golden() {
+        let cases: &[GoldenCase] = &[
+            (5, 2, &["abc\nde"], &["abc..", "de..."], (2, 1)),
+            // Wrapping and scrolling
+            (4, 2, &["abcdefghij"], &["efgh", "ij  "], (2, 1)),
+            (4, 2, &["abcd"], &["abcd", "...."], (4, 0)),
+            (4, 2, &["a\nb\nc\n"], &["c   ", "    "], (0, 1)),
+            // Cursor movements are clamped to the screen
+            (
+                6,
+                3,
+                &["abc\x1b[2Dx\x1b[5By\x1b[9Az\x1b[99C!"],
+                &["axcz.!", "......", "..y..."],
+                (6, 0),
+            ),
+            (
+                6,
+                2,
+                &["abc\x1b[2;2Hx\x1b[1GY"],
+                &["abc...", "Yx...."],
+                (1, 1),
+            ),
+            (
+                6,
+                2,
+                &["\x1b[2;3fx\x1b[Fy\x1b[Ez"],
+                &["y.....", "z.x..."],
+                (1, 1),
+            ),
+            // Erasures
+            (6, 2, &["hello\x1b[3D\x1b[K"], &["he    ", "......"], (2, 0)),
+            (
+                6,
+                2,
+                &["hello\x1b[3D\x1b[1K"],
+                &["   lo.", "......"],
+                (2, 0),
+            ),
+            (
+                6,
+                2,
+                &["hello\x1b[3D\x1b[2K"],
+                &["      ", "......"],
+                (2, 0),
+            ),
+            (6, 2, &["ab\ncd\x1b[A\x1b[J"], &["ab    ", "      "], (2, 0)),
+            (6, 2, &["ab\ncd\x1b[1J"], &["      ", "   ..."], (2, 1)),
+            (6, 2, &["ab\ncd\x1b[2J"], &["      ", "      "], (2, 1)),
+            // Sequences split across writes
+            (
+                6,
+                1,
+                &["a\x1b", "[3", "1mb\x1b[", "2", "D"],
+                &["ab...."],
+                (0, 0),
+            ),
+            // Unsupported sequences are dropped up to the character that ends them
+            (8, 1, &["a\x1b[99Zb\x1b[?25lc"], &["aZblc..."], (5, 0)),
+            // Alternate screen
+            (4, 2, &["ab\x1b[?1049hcd"], &["cd  ", "    "], (2, 0)),
+            (
+                4,
+                2,
+                &["ab\x1b[?1049hcd\x1b[?1049l"],
+                &["ab..", "...."],
+                (2, 0),
+            ),
+            // Characters other than ASCII are not supported by the decoder
+            (5, 2, &["a漢字x"], &["ax...", "....."], (2, 0)),
+        ];
+        for (columns, lines, chunks, grid, cursor) in cases {
+            let t = feed(*columns, *lines, chunks);
+            assert_eq!(snapshot(&t), *grid, "{:?}", chunks);
+            assert_eq!(t.cursor_position(), *cursor, "{:?}", chunks);
+        }
+    }
+
+    #[test]
+    fn test_sgr() {
+        let theme = Theme::Dracula;
+        let t = feed(
+            8,
+            1,
+            &["a\x1b[31mb\x1b[1mc\x1b[22md\x1b[1;32me\x1b[0mf\x1b[3;44mg\x1b[23;49mh"],
+        );
+        let cells = t.grid().line(0);
+        let style = |c: &Cell| (c.value, c.fg, c.bg, c.style);
+        let (fg, bg) = (theme.foreground(), theme.background());
+        assert_eq!(
+            cells.iter().map(style).collect::<Vec<_>>(),
+            [
+                ('a', fg, bg, FontStyle::Normal),
+                ('b', theme.red(), bg, FontStyle::Normal),
+                ('c', theme.bright_red(), bg, FontStyle::Bold),
+                ('d', theme.red(), bg, FontStyle::Normal),
+                ('e', theme.bright_green(), bg, FontStyle::Bold),
+                ('f', fg, bg, FontStyle::Normal),
+                ('g', fg, theme.blue(), FontStyle::Italic),
+                ('h', fg, bg, FontStyle::Normal),
+            ]
+        );
+
+        // 256 colors
+        let t = feed(2, 1, &["\x1b[38;5;196ma\x1b[48;5;232mb"]);
+        let cells = t.grid().line(0);
+        assert_eq!(cells[0].fg, (255, 0, 0));
+        assert_eq!((cells[1].fg, cells[1].bg), ((255, 0, 0), (0, 0, 0)));
+
+        // Erased cells take the current background, but nothing else of the current SGR
+        let t = feed(3, 1, &["\x1b[1;31;44mab\x1b[D\x1b[K"]);
+        let cells = t.grid().line(0);
+        assert_eq!(
+            style(&cells[0]),
+            ('a', theme.bright_red(), theme.blue(), FontStyle::Bold)
+        );
+        assert_eq!(style(&cells[1]), (' ', fg, theme.blue(), FontStyle::Normal));
+        assert_eq!(cells[2], cells[1]);
+    }
+
+    #[test]
+    fn test_backend() {
+        let mut t = feed(6, 2, &["ab\ncd\x1b[6n"]);
+        assert_eq!(t.backend_mut().replies, "\x1b[2;3R");
+
+        // Lines are passed to the backend as they are scrolled off
+        t.put_str("\nef\ngh");
+        assert_eq!(t.backend_mut().scrolled_out, ["ab....", "cd...."]);
+        assert_eq!(snapshot(&t), ["ef    ", "gh    "]);
+    }
+
+    #[test]
+    fn test_reset() {
+        // A sequence interrupted by the reset is dropped
+        let mut t = feed(6, 1, &["a\x1b[3"]);
+        t.reset_decoder();
+        t.put_str("1mb");
+        assert_eq!(snapshot(&t), ["a1mb.."]);
+
+        // Changing the theme clears the screen
+        t.set_theme(Theme::GruvboxDark);
+        let bg = Theme::GruvboxDark.background();
+        assert!(t
+            .grid()
+            .line(0)
+            .iter()
+            .all(|c| c.value == ' ' && c.bg == bg));
+        assert_eq!(t.cursor_position(), (0, 0));
+    }
+}
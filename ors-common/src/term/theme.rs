@@ -0,0 +1,552 @@
+use super::ansi::ColorScheme;
+use core::fmt;
+
+/// Color themes of the console.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Theme {
+    OneMonokai,
+    SolarizedDark,
+    Dracula,
+    GruvboxDark,
+    Custom(CustomTheme),
+}
+
+impl Theme {
+    pub const NAMED: [Self; 4] = [
+        Self::OneMonokai,
+        Self::SolarizedDark,
+        Self::Dracula,
+        Self::GruvboxDark,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::OneMonokai => "one-monokai",
+            Self::SolarizedDark => "solarized-dark",
+            Self::Dracula => "dracula",
+            Self::GruvboxDark => "gruvbox-dark",
+            Self::Custom(_) => "custom",
+        }
+    }
+
+    /// Find a named theme.
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::NAMED.into_iter().find(|t| t.name() == name)
+    }
+
+    fn scheme(&self) -> &dyn ColorScheme {
+        match self {
+            Self::OneMonokai => &OneMonokai,
+            Self::SolarizedDark => &SolarizedDark,
+            Self::Dracula => &Dracula,
+            Self::GruvboxDark => &GruvboxDark,
+            Self::Custom(t) => t,
+        }
+    }
+}
+
+impl fmt::Display for Theme {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl ColorScheme for Theme {
+    fn foreground(&self) -> (u8, u8, u8) {
+        self.scheme().foreground()
+    }
+
+    fn background(&self) -> (u8, u8, u8) {
+        self.scheme().background()
+    }
+
+    fn black(&self) -> (u8, u8, u8) {
+        self.scheme().black()
+    }
+
+    fn red(&self) -> (u8, u8, u8) {
+        self.scheme().red()
+    }
+
+    fn green(&self) -> (u8, u8, u8) {
+        self.scheme().green()
+    }
+
+    fn yellow(&self) -> (u8, u8, u8) {
+        self.scheme().yellow()
+    }
+
+    fn blue(&self) -> (u8, u8, u8) {
+        self.scheme().blue()
+    }
+
+    fn magenta(&self) -> (u8, u8, u8) {
+        self.scheme().magenta()
+    }
+
+    fn cyan(&self) -> (u8, u8, u8) {
+        self.scheme().cyan()
+    }
+
+    fn white(&self) -> (u8, u8, u8) {
+        self.scheme().white()
+    }
+
+    fn bright_black(&self) -> (u8, u8, u8) {
+        self.scheme().bright_black()
+    }
+
+    fn bright_red(&self) -> (u8, u8, u8) {
+        self.scheme().bright_red()
+    }
+
+    fn bright_green(&self) -> (u8, u8, u8) {
+        self.scheme().bright_green()
+    }
+
+    fn bright_yellow(&self) -> (u8, u8, u8) {
+        self.scheme().bright_yellow()
+    }
+
+    fn bright_blue(&self) -> (u8, u8, u8) {
+        self.scheme().bright_blue()
+    }
+
+    fn bright_magenta(&self) -> (u8, u8, u8) {
+        self.scheme().bright_magenta()
+    }
+
+    fn bright_cyan(&self) -> (u8, u8, u8) {
+        self.scheme().bright_cyan()
+    }
+
+    fn bright_white(&self) -> (u8, u8, u8) {
+        self.scheme().bright_white()
+    }
+}
+
+/// A theme consisting of 16 user-specified colors. The foreground and background colors are
+/// taken from white and black respectively.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct CustomTheme {
+    colors: [(u8, u8, u8); 16],
+}
+
+impl CustomTheme {
+    /// Colors are in the order of black, red, green, yellow, blue, magenta, cyan, white,
+    /// followed by their bright variants.
+    pub const fn new(colors: [(u8, u8, u8); 16]) -> Self {
+        Self { colors }
+    }
+}
+
+impl ColorScheme for CustomTheme {
+    fn foreground(&self) -> (u8, u8, u8) {
+        self.colors[7]
+    }
+
+    fn background(&self) -> (u8, u8, u8) {
+        self.colors[0]
+    }
+
+    fn black(&self) -> (u8, u8, u8) {
+        self.colors[0]
+    }
+
+    fn red(&self) -> (u8, u8, u8) {
+        self.colors[1]
+    }
+
+    fn green(&self) -> (u8, u8, u8) {
+        self.colors[2]
+    }
+
+    fn yellow(&self) -> (u8, u8, u8) {
+        self.colors[3]
+    }
+
+    fn blue(&self) -> (u8, u8, u8) {
+        self.colors[4]
+    }
+
+    fn magenta(&self) -> (u8, u8, u8) {
+        self.colors[5]
+    }
+
+    fn cyan(&self) -> (u8, u8, u8) {
+        self.colors[6]
+    }
+
+    fn white(&self) -> (u8, u8, u8) {
+        self.colors[7]
+    }
+
+    fn bright_black(&self) -> (u8, u8, u8) {
+        self.colors[8]
+    }
+
+    fn bright_red(&self) -> (u8, u8, u8) {
+        self.colors[9]
+    }
+
+    fn bright_green(&self) -> (u8, u8, u8) {
+        self.colors[10]
+    }
+
+    fn bright_yellow(&self) -> (u8, u8, u8) {
+        self.colors[11]
+    }
+
+    fn bright_blue(&self) -> (u8, u8, u8) {
+        self.colors[12]
+    }
+
+    fn bright_magenta(&self) -> (u8, u8, u8) {
+        self.colors[13]
+    }
+
+    fn bright_cyan(&self) -> (u8, u8, u8) {
+        self.colors[14]
+    }
+
+    fn bright_white(&self) -> (u8, u8, u8) {
+        self.colors[15]
+    }
+}
+
+/// Parse a color in the form of `#RRGGBB`.
+pub fn parse_color(s: &str) -> Option<(u8, u8, u8)> {
+    let s = s.strip_prefix('#')?;
+    if s.len() != 6 || !s.is_ascii() {
+        return None;
+    }
+    let r = u8::from_str_radix(&s[0..2], 16).ok()?;
+    let g = u8::from_str_radix(&s[2..4], 16).ok()?;
+    let b = u8::from_str_radix(&s[4..6], 16).ok()?;
+    Some((r, g, b))
+}
+
+#[derive(Debug)]
+pub struct OneMonokai;
+
+impl ColorScheme for OneMonokai {
+    fn foreground(&self) -> (u8, u8, u8) {
+        (0xab, 0xb2, 0xbf)
+    }
+
+    fn background(&self) -> (u8, u8, u8) {
+        (0x28, 0x2c, 0x34)
+    }
+
+    fn black(&self) -> (u8, u8, u8) {
+        (0x2d, 0x31, 0x39)
+    }
+
+    fn red(&self) -> (u8, u8, u8) {
+        (0xe0, 0x6c, 0x75)
+    }
+
+    fn green(&self) -> (u8, u8, u8) {
+        (0x98, 0xc3, 0x79)
+    }
+
+    fn yellow(&self) -> (u8, u8, u8) {
+        (0xe5, 0xc0, 0x7b)
+    }
+
+    fn blue(&self) -> (u8, u8, u8) {
+        (0x52, 0x8b, 0xff)
+    }
+
+    fn magenta(&self) -> (u8, u8, u8) {
+        (0xc6, 0x78, 0xdd)
+    }
+
+    fn cyan(&self) -> (u8, u8, u8) {
+        (0x56, 0xb2, 0xc2)
+    }
+
+    fn white(&self) -> (u8, u8, u8) {
+        (0xd7, 0xda, 0xe0)
+    }
+
+    fn bright_black(&self) -> (u8, u8, u8) {
+        (0x7f, 0x84, 0x8e)
+    }
+
+    fn bright_red(&self) -> (u8, u8, u8) {
+        (0xf4, 0x47, 0x47)
+    }
+
+    fn bright_green(&self) -> (u8, u8, u8) {
+        (0x98, 0xc3, 0x79)
+    }
+
+    fn bright_yellow(&self) -> (u8, u8, u8) {
+        (0xe5, 0xc0, 0x7b)
+    }
+
+    fn bright_blue(&self) -> (u8, u8, u8) {
+        (0x52, 0x8b, 0xff)
+    }
+
+    fn bright_magenta(&self) -> (u8, u8, u8) {
+        (0x7e, 0x00, 0x97)
+    }
+
+    fn bright_cyan(&self) -> (u8, u8, u8) {
+        (0x56, 0xb6, 0xc2)
+    }
+
+    fn bright_white(&self) -> (u8, u8, u8) {
+        (0xd7, 0xda, 0xe0)
+    }
+}
+
+#[derive(Debug)]
+pub struct SolarizedDark;
+
+impl ColorScheme for SolarizedDark {
+    fn foreground(&self) -> (u8, u8, u8) {
+        (0x83, 0x94, 0x96)
+    }
+
+    fn background(&self) -> (u8, u8, u8) {
+        (0x00, 0x2b, 0x36)
+    }
+
+    fn black(&self) -> (u8, u8, u8) {
+        (0x07, 0x36, 0x42)
+    }
+
+    fn red(&self) -> (u8, u8, u8) {
+        (0xdc, 0x32, 0x2f)
+    }
+
+    fn green(&self) -> (u8, u8, u8) {
+        (0x85, 0x99, 0x00)
+    }
+
+    fn yellow(&self) -> (u8, u8, u8) {
+        (0xb5, 0x89, 0x00)
+    }
+
+    fn blue(&self) -> (u8, u8, u8) {
+        (0x26, 0x8b, 0xd2)
+    }
+
+    fn magenta(&self) -> (u8, u8, u8) {
+        (0xd3, 0x36, 0x82)
+    }
+
+    fn cyan(&self) -> (u8, u8, u8) {
+        (0x2a, 0xa1, 0x98)
+    }
+
+    fn white(&self) -> (u8, u8, u8) {
+        (0xee, 0xe8, 0xd5)
+    }
+
+    fn bright_black(&self) -> (u8, u8, u8) {
+        (0x00, 0x2b, 0x36)
+    }
+
+    fn bright_red(&self) -> (u8, u8, u8) {
+        (0xcb, 0x4b, 0x16)
+    }
+
+    fn bright_green(&self) -> (u8, u8, u8) {
+        (0x58, 0x6e, 0x75)
+    }
+
+    fn bright_yellow(&self) -> (u8, u8, u8) {
+        (0x65, 0x7b, 0x83)
+    }
+
+    fn bright_blue(&self) -> (u8, u8, u8) {
+        (0x83, 0x94, 0x96)
+    }
+
+    fn bright_magenta(&self) -> (u8, u8, u8) {
+        (0x6c, 0x71, 0xc4)
+    }
+
+    fn bright_cyan(&self) -> (u8, u8, u8) {
+        (0x93, 0xa1, 0xa1)
+    }
+
+    fn bright_white(&self) -> (u8, u8, u8) {
+        (0xfd, 0xf6, 0xe3)
+    }
+}
+
+#[derive(Debug)]
+pub struct Dracula;
+
+impl ColorScheme for Dracula {
+    fn foreground(&self) -> (u8, u8, u8) {
+        (0xf8, 0xf8, 0xf2)
+    }
+
+    fn background(&self) -> (u8, u8, u8) {
+        (0x28, 0x2a, 0x36)
+    }
+
+    fn black(&self) -> (u8, u8, u8) {
+        (0x21, 0x22, 0x2c)
+    }
+
+    fn red(&self) -> (u8, u8, u8) {
+        (0xff, 0x55, 0x55)
+    }
+
+    fn green(&self) -> (u8, u8, u8) {
+        (0x50, 0xfa, 0x7b)
+    }
+
+    fn yellow(&self) -> (u8, u8, u8) {
+        (0xf1, 0xfa, 0x8c)
+    }
+
+    fn blue(&self) -> (u8, u8, u8) {
+        (0xbd, 0x93, 0xf9)
+    }
+
+    fn magenta(&self) -> (u8, u8, u8) {
+        (0xff, 0x79, 0xc6)
+    }
+
+    fn cyan(&self) -> (u8, u8, u8) {
+        (0x8b, 0xe9, 0xfd)
+    }
+
+    fn white(&self) -> (u8, u8, u8) {
+        (0xf8, 0xf8, 0xf2)
+    }
+
+    fn bright_black(&self) -> (u8, u8, u8) {
+        (0x62, 0x72, 0xa4)
+    }
+
+    fn bright_red(&self) -> (u8, u8, u8) {
+        (0xff, 0x6e, 0x6e)
+    }
+
+    fn bright_green(&self) -> (u8, u8, u8) {
+        (0x69, 0xff, 0x94)
+    }
+
+    fn bright_yellow(&self) -> (u8, u8, u8) {
+        (0xff, 0xff, 0xa5)
+    }
+
+    fn bright_blue(&self) -> (u8, u8, u8) {
+        (0xd6, 0xac, 0xff)
+    }
+
+    fn bright_magenta(&self) -> (u8, u8, u8) {
+        (0xff, 0x92, 0xdf)
+    }
+
+    fn bright_cyan(&self) -> (u8, u8, u8) {
+        (0xa4, 0xff, 0xff)
+    }
+
+    fn bright_white(&self) -> (u8, u8, u8) {
+        (0xff, 0xff, 0xff)
+    }
+}
+
+#[derive(Debug)]
+pub struct GruvboxDark;
+
+impl ColorScheme for GruvboxDark {
+    fn foreground(&self) -> (u8, u8, u8) {
+        (0xeb, 0xdb, 0xb2)
+    }
+
+    fn background(&self) -> (u8, u8, u8) {
+        (0x28, 0x28, 0x28)
+    }
+
+    fn black(&self) -> (u8, u8, u8) {
+        (0x28, 0x28, 0x28)
+    }
+
+    fn red(&self) -> (u8, u8, u8) {
+        (0xcc, 0x24, 0x1d)
+    }
+
+    fn green(&self) -> (u8, u8, u8) {
+        (0x98, 0x97, 0x1a)
+    }
+
+    fn yellow(&self) -> (u8, u8, u8) {
+        (0xd7, 0x99, 0x21)
+    }
+
+    fn blue(&self) -> (u8, u8, u8) {
+        (0x45, 0x85, 0x88)
+    }
+
+    fn magenta(&self) -> (u8, u8, u8) {
+        (0xb1, 0x62, 0x86)
+    }
+
+    fn cyan(&self) -> (u8, u8, u8) {
+        (0x68, 0x9d, 0x6a)
+    }
+
+    fn white(&self) -> (u8, u8, u8) {
+        (0xa8, 0x99, 0x84)
+    }
+
+    fn bright_black(&self) -> (u8, u8, u8) {
+        (0x92, 0x83, 0x74)
+    }
+
+    fn bright_red(&self) -> (u8, u8, u8) {
+        (0xfb, 0x49, 0x34)
+    }
+
+    fn bright_green(&self) -> (u8, u8, u8) {
+        (0xb8, 0xbb, 0x26)
+    }
+
+    fn bright_yellow(&self) -> (u8, u8, u8) {
+        (0xfa, 0xbd, 0x2f)
+    }
+
+    fn bright_blue(&self) -> (u8, u8, u8) {
+        (0x83, 0xa5, 0x98)
+    }
+
+    fn bright_magenta(&self) -> (u8, u8, u8) {
+        (0xd3, 0x86, 0x9b)
+    }
+
+    fn bright_cyan(&self) -> (u8, u8, u8) {
+        (0x8e, 0xc0, 0x7c)
+    }
+
+    fn bright_white(&self) -> (u8, u8, u8) {
+        (0xeb, 0xdb, 0xb2)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_theme() {
+        assert_eq!(parse_color("#ff7900"), Some((0xff, 0x79, 0x00)));
+        assert_eq!(parse_color("ff7900"), None);
+        assert_eq!(parse_color("#ff790"), None);
+        assert_eq!(parse_color("#ff79zz"), None);
+        for t in Theme::NAMED {
+            assert_eq!(Theme::from_name(t.name()), Some(t));
+        }
+        assert_eq!(Dracula.background(), (0x28, 0x2a, 0x36));
+    }
+}
@@ -22,4 +22,45 @@ fn main() {
         .status()
         .unwrap();
     println!("cargo:rustc-link-lib=static=asm");
+    // Any rerun-if-changed replaces the default of rerunning on every change in the package
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=asm.s");
+
+    #[cfg(feature = "fat-fixtures")]
+    build_fat_fixtures(&out_dir);
+}
+
+/// fixtures/*.manifest -> *.img + *.rs, and fat_fixtures.rs listing all of them
+#[cfg(feature = "fat-fixtures")]
+fn build_fat_fixtures(out_dir: &std::path::Path) {
+    use std::fs;
+
+    // The whole directory is scanned, so that added or removed manifests are also noticed
+    println!("cargo:rerun-if-changed=fixtures");
+    let mut manifests = fs::read_dir("fixtures")
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().map_or(false, |ext| ext == "manifest"))
+        .collect::<Vec<_>>();
+    manifests.sort();
+
+    let mut index = String::from("const FIXTURES: &[Fixture] = &[\n");
+    for manifest in manifests {
+        let name = manifest.file_stem().unwrap().to_str().unwrap();
+        let fixture = ors_mkimage::build(&fs::read_to_string(&manifest).unwrap())
+            .unwrap_or_else(|e| panic!("{}: {}", manifest.display(), e));
+        let image = out_dir.join(format!("{}.img", name));
+        let expectations = out_dir.join(format!("{}.rs", name));
+        fs::write(&image, &fixture.image).unwrap();
+        fs::write(&expectations, fixture.expectations_source()).unwrap();
+        index.push_str(&format!(
+            "    Fixture {{ name: {:?}, sector_size: {}, image: include_bytes!({:?}), expectations: &include!({:?}) }},\n",
+            name,
+            fixture.sector_size,
+            image.display(),
+            expectations.display(),
+        ));
+    }
+    index.push_str("];\n");
+    fs::write(out_dir.join("fat_fixtures.rs"), index).unwrap();
 }
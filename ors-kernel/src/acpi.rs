@@ -1,20 +1,38 @@
+use crate::devices::qemu;
+use crate::fs::mount;
+use crate::interrupts;
+use crate::task;
 use crate::x64;
-use acpi::platform::address::AddressSpace;
+use acpi::fadt::Fadt;
+use acpi::platform::address::{AddressSpace, GenericAddress};
 use acpi::platform::interrupt::Apic;
 use acpi::platform::{PmTimer, ProcessorInfo};
+use acpi::sdt::Signature;
 use acpi::{AcpiHandler, AcpiTables, PlatformInfo};
+use core::sync::atomic::{AtomicBool, AtomicU16, Ordering};
+use log::{info, trace, warn};
 use spin::Once;
 
 static PLATFORM_INFO: Once<PlatformInfo> = Once::new();
+static PM1: Once<Pm1> = Once::new();
 
 /// Caller must ensure that the given rsdp is valid.
 pub unsafe fn initialize(handler: impl AcpiHandler, rsdp: usize) {
+    let tables = AcpiTables::from_rsdp(handler, rsdp).unwrap();
+    // https://wiki.osdev.org/FADT
+    match tables.get_sdt::<Fadt>(Signature::FADT) {
+        Ok(Some(fadt)) => match Pm1::from_fadt(&fadt) {
+            Some(pm1) => {
+                PM1.call_once(|| pm1);
+            }
+            None => warn!("acpi: Unsupported PM1 register blocks"),
+        },
+        Ok(None) => warn!("acpi: Could not find FADT"),
+        Err(e) => warn!("acpi: Invalid FADT: {:?}", e),
+    }
     PLATFORM_INFO.call_once(|| {
         // https://wiki.osdev.org/MADT
-        AcpiTables::from_rsdp(handler, rsdp)
-            .unwrap()
-            .platform_info()
-            .unwrap()
+        tables.platform_info().unwrap()
     });
 }
 
@@ -63,3 +81,192 @@ pub fn wait_milliseconds_with_pm_timer(msec: u32) {
     }
     while unsafe { time.read() } < end {}
 }
+
+/// ACPI fixed events handled by the kernel, each of which is a bit of the PM1 status register.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum FixedEvent {
+    PowerButton,
+    SleepButton,
+}
+
+impl FixedEvent {
+    pub const ALL: [Self; 2] = [Self::PowerButton, Self::SleepButton];
+
+    fn bit(self) -> u16 {
+        match self {
+            Self::PowerButton => 1 << 8,
+            Self::SleepButton => 1 << 9,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::PowerButton => "power button",
+            Self::SleepButton => "sleep button",
+        }
+    }
+}
+
+/// The PM1 register blocks and the SCI (System Control Interrupt) described in the FADT.
+#[derive(Debug, Clone, Copy)]
+struct Pm1 {
+    sci_interrupt: u16,
+    /// The status and enable registers of the PM1a and PM1b event blocks.
+    event_blocks: [Option<(u16, u16)>; 2],
+    /// The PM1a and PM1b control registers.
+    control_blocks: [Option<u16>; 2],
+    smi_cmd_port: u16,
+    acpi_enable: u8,
+    /// Fixed events that the platform implements, instead of control method devices.
+    events: u16,
+}
+
+impl Pm1 {
+    const SCI_EN: u16 = 1 << 0;
+
+    fn from_fadt(fadt: &Fadt) -> Option<Self> {
+        // Status and enable registers share each event block in halves
+        fn event_block(addr: GenericAddress) -> Option<(u16, u16)> {
+            let port = io_port(addr)?;
+            (addr.bit_width == 32).then(|| (port, port + 2))
+        }
+        fn io_port(addr: GenericAddress) -> Option<u16> {
+            // TODO: MMIO Support
+            (addr.address_space == AddressSpace::SystemIo && addr.address != 0)
+                .then(|| addr.address as u16)
+        }
+        let flags = fadt.flags;
+        let mut events = 0;
+        if !flags.power_button_is_control_method() {
+            events |= FixedEvent::PowerButton.bit();
+        }
+        if !flags.sleep_button_is_control_method() {
+            events |= FixedEvent::SleepButton.bit();
+        }
+        Some(Self {
+            sci_interrupt: fadt.sci_interrupt,
+            event_blocks: [
+                Some(event_block(fadt.pm1a_event_block().ok()?)?),
+                fadt.pm1b_event_block().ok()?.and_then(event_block),
+            ],
+            control_blocks: [
+                Some(io_port(fadt.pm1a_control_block().ok()?)?),
+                fadt.pm1b_control_block().ok()?.and_then(io_port),
+            ],
+            smi_cmd_port: fadt.smi_cmd_port as u16,
+            acpi_enable: fadt.acpi_enable,
+            events,
+        })
+    }
+
+    fn sci_enabled(&self) -> bool {
+        let control = unsafe { x64::Port::<u16>::new(self.control_blocks[0].unwrap()).read() };
+        control & Self::SCI_EN != 0
+    }
+
+    /// Transfer the ownership of the ACPI hardware registers from the firmware to the OS.
+    unsafe fn enter_acpi_mode(&self) -> bool {
+        if self.sci_enabled() {
+            return true;
+        }
+        if self.smi_cmd_port == 0 || self.acpi_enable == 0 {
+            return false;
+        }
+        x64::Port::<u8>::new(self.smi_cmd_port).write(self.acpi_enable);
+        for _ in 0..100 {
+            if self.sci_enabled() {
+                return true;
+            }
+            wait_milliseconds_with_pm_timer(10);
+        }
+        false
+    }
+
+    /// Read and clear the status bits of the enabled events.
+    unsafe fn take_status(&self) -> u16 {
+        let mut status = 0;
+        for (sts, en) in self.event_blocks.into_iter().flatten() {
+            let mut sts = x64::Port::<u16>::new(sts);
+            let s = sts.read() & x64::Port::<u16>::new(en).read();
+            // Status bits are cleared by writing 1
+            sts.write(s);
+            status |= s;
+        }
+        status
+    }
+}
+
+/// Events simulated by `simulate_fixed_event`, picked up by the next SCI.
+static SIMULATED_EVENTS: AtomicU16 = AtomicU16::new(0);
+
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+
+/// The Global System Interrupt of the SCI, if ACPI events are available.
+pub fn sci_interrupt() -> Option<u32> {
+    PM1.get().map(|pm1| pm1.sci_interrupt as u32)
+}
+
+/// Enable the fixed events implemented by the platform, which are then raised as SCIs.
+/// The SCI must be routed by `interrupts::initialize` beforehand.
+pub unsafe fn enable_fixed_events() {
+    let pm1 = match PM1.get() {
+        Some(pm1) => pm1,
+        None => return,
+    };
+    trace!("INITIALIZING ACPI fixed events");
+    if !pm1.enter_acpi_mode() {
+        warn!("acpi: Failed to enable ACPI mode, fixed events are unavailable");
+        return;
+    }
+    for (sts, en) in pm1.event_blocks.into_iter().flatten() {
+        // Stale events such as the button press that booted the machine are discarded
+        x64::Port::<u16>::new(sts).write(pm1.events);
+        let mut en = x64::Port::<u16>::new(en);
+        let enabled = en.read();
+        en.write(enabled | pm1.events);
+    }
+    for e in FixedEvent::ALL {
+        if pm1.events & e.bit() == 0 {
+            info!("acpi: The {} is not a fixed event", e.name());
+        }
+    }
+}
+
+/// Called by the SCI handler. Events are acknowledged here, but handled later by the work queue
+/// since the SCI handler runs with interrupts disabled.
+pub fn handle_sci() {
+    let pm1 = match PM1.get() {
+        Some(pm1) => pm1,
+        None => return,
+    };
+    let status = unsafe { pm1.take_status() } | SIMULATED_EVENTS.swap(0, Ordering::SeqCst);
+    if status != 0 {
+        // Dropped events are counted by the work queue
+        task::workqueue::schedule(handle_fixed_events, status as u64);
+    }
+}
+
+/// Simulate `event` as if it is signaled by the hardware. The event is delivered by an SCI
+/// sent to the current CPU, so it goes through the same path as the hardware ones.
+pub fn simulate_fixed_event(event: FixedEvent) -> bool {
+    if PM1.get().is_none() {
+        return false;
+    }
+    SIMULATED_EVENTS.fetch_or(event.bit(), Ordering::SeqCst);
+    interrupts::send_acpi_sci_to_self();
+    true
+}
+
+fn handle_fixed_events(status: u64) {
+    for e in FixedEvent::ALL {
+        if status as u16 & e.bit() == 0 {
+            continue;
+        }
+        info!("acpi: The {} is pressed", e.name());
+        if e == FixedEvent::PowerButton && !SHUTTING_DOWN.swap(true, Ordering::SeqCst) {
+            info!("acpi: Shutting down");
+            mount::shutdown();
+            qemu::exit(qemu::ExitCode::Success);
+        }
+    }
+}
@@ -1,29 +1,239 @@
+use crate::config;
 use crate::paging::{as_phys_addr, as_virt_addr};
-use crate::phys_memory::{frame_manager, Frame};
+use crate::phys_memory::{frame_manager, Frame, Tag};
 use crate::sync::spin::Spin;
 use crate::x64;
 use alloc::alloc::{GlobalAlloc, Layout};
-use core::ptr;
-use log::trace;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::{mem, ptr};
+use log::{trace, Level};
 
 #[derive(Debug)]
 enum AllocationMode {
     Block(usize),
     Frame(usize),
+    /// Frames for an alignment larger than `Frame::SIZE`: (number of frames, alignment).
+    /// Extra frames are allocated to align the returned pointer, and the address of the
+    /// allocated frames is stored just before the returned pointer.
+    AlignedFrame(usize, usize),
 }
 
 impl From<Layout> for AllocationMode {
     fn from(l: Layout) -> Self {
+        // The size of a layout never exceeds isize::MAX, thus the count never overflows
+        let count_for_bytes = |bytes| Frame::count_for_bytes(bytes).unwrap();
+        if l.align() > Frame::SIZE {
+            let num = count_for_bytes(l.size()) + l.align() / Frame::SIZE;
+            return Self::AlignedFrame(num, l.align());
+        }
         let size = l.size().max(l.align());
         match BLOCK_SIZES.iter().position(|s| *s >= size) {
             Some(index) => Self::Block(index),
-            None => Self::Frame((size + Frame::SIZE - 1) / Frame::SIZE),
+            None => Self::Frame(count_for_bytes(size)),
         }
     }
 }
 
 const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
 
+pub const DEFAULT_HEAP_RESERVATION: usize = 64 * 1024 * 1024; // 64MiB
+const MAX_HEAP_RESERVATION_FRAMES: usize = 256 * 1024 * 1024 / Frame::SIZE; // 256MiB
+
+type MapLine = usize;
+const BITS_PER_MAP_LINE: usize = 8 * mem::size_of::<MapLine>();
+const MAP_LINE_COUNT: usize = MAX_HEAP_RESERVATION_FRAMES / BITS_PER_MAP_LINE;
+
+static HEAP_REGION: Spin<HeapRegion> = Spin::new(HeapRegion::new());
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// The number of bytes to reserve for the value of the `heap=<MiB>` boot parameter. A missing or
+/// malformed parameter falls back to `DEFAULT_HEAP_RESERVATION`.
+pub fn heap_reservation(value: Option<&str>) -> usize {
+    match value {
+        Some(s) => parse_heap_reservation(s).unwrap_or_else(|| {
+            log::warn!("Malformed boot parameter heap={}, using the default", s);
+            DEFAULT_HEAP_RESERVATION
+        }),
+        None => DEFAULT_HEAP_RESERVATION,
+    }
+}
+
+fn parse_heap_reservation(s: &str) -> Option<usize> {
+    s.parse::<usize>().ok()?.checked_mul(1024 * 1024)
+}
+
+/// Reserve a contiguous region of frames for the kernel heap.
+/// Reserving up-front prevents heap frames from fragmenting the frames used for DMA.
+/// The size is truncated to the supported maximum, and halved until the reservation succeeds.
+pub fn reserve_heap(bytes: usize) {
+    trace!("INITIALIZING Heap reservation");
+    let mut region = HEAP_REGION.lock();
+    let mut num_frames = Frame::count_for_bytes(bytes)
+        .unwrap_or(usize::MAX)
+        .min(MAX_HEAP_RESERVATION_FRAMES);
+    while 0 < num_frames && !region.reserve(num_frames) {
+        num_frames /= 2;
+    }
+}
+
+/// Usage of the kernel heap in bytes.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy)]
+pub struct HeapInfo {
+    pub reserved: usize,
+    /// Bytes used by the heap, including frames outside of the reserved region.
+    pub used: usize,
+    /// Bytes used by the heap outside of the reserved region.
+    pub overflow: usize,
+    /// The high-water mark of `used`.
+    pub peak: usize,
+    /// The number of allocations so far, to measure the allocations of an operation.
+    pub allocations: usize,
+}
+
+pub fn heap_info() -> HeapInfo {
+    HEAP_REGION.lock().info()
+}
+
+fn allocate_heap_frames(num_frames: usize, tag: Tag) -> Option<Frame> {
+    let mut region = HEAP_REGION.lock();
+    if let Some(frame) = region.allocate(num_frames) {
+        return Some(frame);
+    }
+    // Fall back to the global frame manager when the region is exhausted
+    let frame = frame_manager().allocate_tagged(num_frames, tag).ok()?;
+    region.add_overflow(num_frames);
+    Some(frame)
+}
+
+fn free_heap_frames(frame: Frame, num_frames: usize, tag: Tag) {
+    let mut region = HEAP_REGION.lock();
+    if !region.free(frame, num_frames) {
+        frame_manager().free_tagged(frame, num_frames, tag);
+        region.overflow_frames -= num_frames;
+    }
+}
+
+/// A contiguous region of frames dedicated to the kernel heap, managed by a first-fit bitmap.
+struct HeapRegion {
+    start: Option<Frame>,
+    num_frames: usize,
+    alloc_map: [MapLine; MAP_LINE_COUNT],
+    used_frames: usize,
+    overflow_frames: usize,
+    peak_frames: usize,
+}
+
+impl HeapRegion {
+    const fn new() -> Self {
+        Self {
+            start: None,
+            num_frames: 0,
+            alloc_map: [0; MAP_LINE_COUNT],
+            used_frames: 0,
+            overflow_frames: 0,
+            peak_frames: 0,
+        }
+    }
+
+    fn reserve(&mut self, num_frames: usize) -> bool {
+        assert!(self.start.is_none(), "Heap is already reserved");
+        match frame_manager().allocate_tagged(num_frames, Tag::HeapReserved) {
+            Ok(frame) => {
+                self.start = Some(frame);
+                self.num_frames = num_frames;
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    #[cfg(test)]
+    fn release(&mut self) {
+        if let Some(start) = self.start.take() {
+            frame_manager().free_tagged(start, self.num_frames, Tag::HeapReserved);
+            self.num_frames = 0;
+        }
+    }
+
+    fn info(&self) -> HeapInfo {
+        HeapInfo {
+            reserved: self.num_frames * Frame::SIZE,
+            used: (self.used_frames + self.overflow_frames) * Frame::SIZE,
+            overflow: self.overflow_frames * Frame::SIZE,
+            peak: self.peak_frames * Frame::SIZE,
+            allocations: ALLOCATIONS.load(Ordering::Relaxed),
+        }
+    }
+
+    fn index_of(&self, frame: Frame) -> Option<usize> {
+        let start = self.start?;
+        if frame < start || start.offset(self.num_frames) <= frame {
+            return None;
+        }
+        let offset = frame.phys_addr() - start.phys_addr();
+        Some(offset as usize / Frame::SIZE)
+    }
+
+    fn allocate(&mut self, num_frames: usize) -> Option<Frame> {
+        let start = self.start?;
+        // Doing the first fit allocation
+        let mut i = 0;
+        'search: while i + num_frames <= self.num_frames {
+            for j in i..i + num_frames {
+                if self.get_bit(j) {
+                    i = j + 1;
+                    continue 'search;
+                }
+            }
+            for j in i..i + num_frames {
+                self.set_bit(j, true);
+            }
+            self.used_frames += num_frames;
+            self.update_peak();
+            return Some(start.offset(i));
+        }
+        None
+    }
+
+    /// Returns false if the frames are not in this region.
+    fn free(&mut self, frame: Frame, num_frames: usize) -> bool {
+        match self.index_of(frame) {
+            Some(i) => {
+                for j in i..i + num_frames {
+                    self.set_bit(j, false);
+                }
+                self.used_frames -= num_frames;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn add_overflow(&mut self, num_frames: usize) {
+        self.overflow_frames += num_frames;
+        self.update_peak();
+    }
+
+    fn update_peak(&mut self) {
+        self.peak_frames = self
+            .peak_frames
+            .max(self.used_frames + self.overflow_frames);
+    }
+
+    fn get_bit(&self, i: usize) -> bool {
+        (self.alloc_map[i / BITS_PER_MAP_LINE] & (1 << (i % BITS_PER_MAP_LINE))) != 0
+    }
+
+    fn set_bit(&mut self, i: usize, allocated: bool) {
+        let line = &mut self.alloc_map[i / BITS_PER_MAP_LINE];
+        if allocated {
+            *line |= 1 << (i % BITS_PER_MAP_LINE);
+        } else {
+            *line &= !(1 << (i % BITS_PER_MAP_LINE));
+        }
+    }
+}
+
 pub struct KernelAllocator {
     available_blocks: Spin<[*mut u8; BLOCK_SIZES.len()]>,
 }
@@ -40,6 +250,7 @@ unsafe impl Sync for KernelAllocator {}
 
 unsafe impl GlobalAlloc for KernelAllocator {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
         match layout.into() {
             AllocationMode::Block(index) => {
                 let mut available_blocks = self.available_blocks.lock();
@@ -50,32 +261,70 @@ unsafe impl GlobalAlloc for KernelAllocator {
                 if !ptr.is_null() {
                     available_blocks[index] = (ptr as *const u64).read() as *mut u8;
                 }
-                trace!(
-                    "allocator: allocate block (size = {}) -> {:?}",
-                    BLOCK_SIZES[index],
-                    x64::VirtAddr::from_ptr(ptr)
-                );
+                if config::TRACE_ALLOCATIONS {
+                    trace!(
+                        "allocator: allocate block (size = {}) -> {:?}",
+                        BLOCK_SIZES[index],
+                        x64::VirtAddr::from_ptr(ptr)
+                    );
+                }
                 ptr
             }
-            AllocationMode::Frame(num) => match frame_manager().allocate(num) {
-                Ok(frame) => {
+            AllocationMode::Frame(num) => match allocate_heap_frames(num, Tag::HeapLarge) {
+                Some(frame) => {
                     let addr = as_virt_addr(frame.phys_addr()).unwrap();
-                    trace!("allocator: allocate frame (num = {}) -> {:?}", num, addr);
+                    if config::TRACE_ALLOCATIONS {
+                        trace!("allocator: allocate frame (num = {}) -> {:?}", num, addr);
+                    }
                     addr.as_mut_ptr()
                 }
-                Err(_) => ptr::null_mut(),
+                None => {
+                    log_rate_limited!(Level::Warn, (), "allocator: Out of frames (num = {})", num);
+                    ptr::null_mut()
+                }
             },
+            AllocationMode::AlignedFrame(num, align) => {
+                match allocate_heap_frames(num, Tag::HeapLarge) {
+                    Some(frame) => {
+                        let base = as_virt_addr(frame.phys_addr()).unwrap();
+                        // There is always a room for the header since base < addr <= base + align
+                        let addr = (base + 1u64).align_up(align as u64);
+                        (addr - 8u64).as_mut_ptr::<u64>().write(base.as_u64());
+                        if config::TRACE_ALLOCATIONS {
+                            trace!(
+                                "allocator: allocate aligned frame (num = {}, align = {}) -> {:?}",
+                                num,
+                                align,
+                                addr
+                            );
+                        }
+                        addr.as_mut_ptr()
+                    }
+                    None => {
+                        log_rate_limited!(
+                            Level::Warn,
+                            (),
+                            "allocator: Out of frames (num = {}, align = {})",
+                            num,
+                            align
+                        );
+                        ptr::null_mut()
+                    }
+                }
+            }
         }
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
         match layout.into() {
             AllocationMode::Block(index) => {
-                trace!(
-                    "allocator: deallocate block (size = {}) -> {:?}",
-                    BLOCK_SIZES[index],
-                    x64::VirtAddr::from_ptr(ptr)
-                );
+                if config::TRACE_ALLOCATIONS {
+                    trace!(
+                        "allocator: deallocate block (size = {}) -> {:?}",
+                        BLOCK_SIZES[index],
+                        x64::VirtAddr::from_ptr(ptr)
+                    );
+                }
                 let mut available_blocks = self.available_blocks.lock();
                 let next = available_blocks[index];
                 (ptr as *mut u64).write(next as u64);
@@ -83,9 +332,25 @@ unsafe impl GlobalAlloc for KernelAllocator {
             }
             AllocationMode::Frame(num) => {
                 let addr = x64::VirtAddr::from_ptr(ptr as *const u8);
-                trace!("allocator: deallocate frame (num = {}) -> {:?}", num, addr);
+                if config::TRACE_ALLOCATIONS {
+                    trace!("allocator: deallocate frame (num = {}) -> {:?}", num, addr);
+                }
                 let frame = Frame::from_phys_addr(as_phys_addr(addr).unwrap());
-                frame_manager().free(frame, num);
+                free_heap_frames(frame, num, Tag::HeapLarge);
+            }
+            AllocationMode::AlignedFrame(num, align) => {
+                let addr = x64::VirtAddr::from_ptr(ptr as *const u8);
+                if config::TRACE_ALLOCATIONS {
+                    trace!(
+                        "allocator: deallocate aligned frame (num = {}, align = {}) -> {:?}",
+                        num,
+                        align,
+                        addr
+                    );
+                }
+                let base = x64::VirtAddr::new((addr - 8u64).as_ptr::<u64>().read());
+                let frame = Frame::from_phys_addr(as_phys_addr(base).unwrap());
+                free_heap_frames(frame, num, Tag::HeapLarge);
             }
         }
     }
@@ -95,15 +360,25 @@ fn allocate_frame_for_block(index: usize) -> *mut u8 {
     let block_size = BLOCK_SIZES[index];
     let num_blocks_per_frame = Frame::SIZE / block_size;
     // NOTE: Frames for AllocationMode::Block are never deallocated
-    let ptr: *mut u8 = match frame_manager().allocate(1) {
-        Ok(frame) => as_virt_addr(frame.phys_addr()).unwrap().as_mut_ptr(),
-        Err(_) => return ptr::null_mut(),
+    let ptr: *mut u8 = match allocate_heap_frames(1, Tag::HeapBlock) {
+        Some(frame) => as_virt_addr(frame.phys_addr()).unwrap().as_mut_ptr(),
+        None => {
+            log_rate_limited!(
+                Level::Warn,
+                (),
+                "allocator: Out of frames for blocks (size = {})",
+                block_size
+            );
+            return ptr::null_mut();
+        }
     };
-    trace!(
-        "allocator: allocate_frame_for_block(size = {}) -> {:?}",
-        block_size,
-        x64::VirtAddr::from_ptr(ptr)
-    );
+    if config::TRACE_ALLOCATIONS {
+        trace!(
+            "allocator: allocate_frame_for_block(size = {}) -> {:?}",
+            block_size,
+            x64::VirtAddr::from_ptr(ptr)
+        );
+    }
     for i in 0..num_blocks_per_frame {
         let current = unsafe { ptr.add(i * block_size) };
         let next = if i == num_blocks_per_frame - 1 {
@@ -118,9 +393,85 @@ fn allocate_frame_for_block(index: usize) -> *mut u8 {
 
 #[cfg(test)]
 mod tests {
+    use super::*;
     use alloc::boxed::Box;
+    use alloc::vec;
+    use alloc::vec::Vec;
     use log::info;
 
+    fn frame_of<T>(ptr: *const T) -> Frame {
+        let addr = as_phys_addr(x64::VirtAddr::from_ptr(ptr)).unwrap();
+        unsafe { Frame::from_phys_addr(addr) }
+    }
+
+    #[test_case]
+    fn test_heap_region() {
+        info!("TESTING allocator::test_heap_region");
+        assert_eq!(parse_heap_reservation("16"), Some(16 * 1024 * 1024));
+        assert_eq!(parse_heap_reservation("0"), Some(0));
+        assert_eq!(parse_heap_reservation("16M"), None);
+
+        let baseline = frame_manager().tagged_frames(Tag::HeapReserved);
+        let mut region = HeapRegion::new();
+        assert_eq!(region.allocate(1), None);
+        assert!(region.reserve(8));
+        assert_eq!(region.info().reserved, 8 * Frame::SIZE);
+        assert_eq!(
+            frame_manager().tagged_frames(Tag::HeapReserved),
+            baseline + 8
+        );
+
+        let a = region.allocate(3).unwrap();
+        let b = region.allocate(3).unwrap();
+        assert_eq!(region.allocate(3), None);
+        assert!(region.free(a, 3));
+        let c = region.allocate(2).unwrap();
+        assert_eq!(c, a); // first fit
+        assert_eq!(region.info().used, 5 * Frame::SIZE);
+        assert_eq!(region.info().peak, 6 * Frame::SIZE);
+        assert!(!region.free(b.offset(100), 1)); // out of the region
+        assert!(region.free(b, 3));
+        assert!(region.free(c, 2));
+        assert_eq!(region.info().used, 0);
+
+        region.release();
+        assert_eq!(frame_manager().tagged_frames(Tag::HeapReserved), baseline);
+    }
+
+    #[test_case]
+    fn test_heap_reservation() {
+        info!("TESTING allocator::test_heap_reservation");
+        let reserved = heap_info().reserved;
+        if reserved == 0 {
+            return; // heap=0
+        }
+
+        // Heavy heap churn stays within the reservation
+        let mut boxes = Vec::new();
+        for i in 0..64 {
+            boxes.push(vec![0u8; 4096 * (1 + i % 4)].into_boxed_slice());
+            if i % 2 == 0 {
+                boxes.swap_remove(i / 4);
+            }
+        }
+        for b in boxes.iter() {
+            assert!(HEAP_REGION.lock().index_of(frame_of(b.as_ptr())).is_some());
+        }
+        let info = heap_info();
+        assert_eq!(info.reserved, reserved);
+        assert!(info.used <= info.peak);
+
+        // Other allocations still find contiguous frames outside the reservation
+        let frames = frame_manager().allocate(16).unwrap();
+        assert!(HEAP_REGION.lock().index_of(frames).is_none());
+        assert!(HEAP_REGION.lock().index_of(frames.offset(15)).is_none());
+        frame_manager().free(frames, 16);
+
+        let used = heap_info().used;
+        drop(boxes);
+        assert!(heap_info().used < used);
+    }
+
     #[test_case]
     fn test_frame() {
         info!("TESTING allocator::test_frame");
@@ -140,6 +491,26 @@ mod tests {
         drop(f);
     }
 
+    #[test_case]
+    fn test_aligned_frame() {
+        info!("TESTING allocator::test_aligned_frame");
+
+        #[repr(align(65536))]
+        struct Aligned([u8; 65536]);
+
+        let a = Box::new(Aligned([0; 65536]));
+        let b = Box::new(Aligned([1; 65536]));
+        assert_eq!(&*a as *const Aligned as usize % 65536, 0);
+        assert_eq!(&*b as *const Aligned as usize % 65536, 0);
+        assert!(a.0.iter().all(|x| *x == 0));
+        assert!(b.0.iter().all(|x| *x == 1));
+        drop(a);
+        let c = Box::new(Aligned([2; 65536]));
+        assert_eq!(&*c as *const Aligned as usize % 65536, 0);
+        drop(b);
+        drop(c);
+    }
+
     #[test_case]
     fn test_block1() {
         info!("TESTING allocator::test_block1");
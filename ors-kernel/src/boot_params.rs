@@ -0,0 +1,119 @@
+//! Boot parameters such as `memtest=quick`, which are passed by the loader, or by QEMU through
+//! `devices::fw_cfg` if the loader passes none.
+//!
+//! The loader passes the parameters by a `CommandLine` on its stack, which is reported as free
+//! memory and reused by the frame manager (and overwritten by the memory test). They are copied
+//! into static storage by `initialize` before the frame manager is initialized.
+
+use ors_common::command_line::{self, CommandLine};
+use spin::Once;
+
+/// Maximum length of the boot parameters. Longer parameters are truncated.
+pub const MAX_LEN: usize = 1024;
+
+static PARAMS: Once<heapless::String<MAX_LEN>> = Once::new();
+
+/// Copy the boot parameters passed by the loader. This must be called before the frame manager
+/// is initialized, and does not allocate.
+pub fn initialize(cl: &CommandLine) {
+    PARAMS.call_once(|| {
+        let s = match cl.as_str() {
+            "" => match crate::devices::fw_cfg::command_line() {
+                Some(s) => {
+                    log::info!("Boot parameters from fw_cfg: {}", s);
+                    s
+                }
+                None => "",
+            },
+            s => s,
+        };
+        let params = copy(s);
+        if params.len() < s.len() {
+            log::warn!("Boot parameters are truncated to {} bytes", MAX_LEN);
+        }
+        params
+    });
+}
+
+/// Copy `s` as long as it fits, at a char boundary.
+fn copy(s: &str) -> heapless::String<MAX_LEN> {
+    let mut params = heapless::String::new();
+    for ch in s.chars() {
+        if params.push(ch).is_err() {
+            break;
+        }
+    }
+    params
+}
+
+/// The whole boot parameters, or an empty string before `initialize`.
+pub fn as_str() -> &'static str {
+    PARAMS.get().map_or("", |params| params.as_str())
+}
+
+/// Get the value of the parameter `key=value`.
+/// Returns `Some("")` for a parameter without a value.
+pub fn get(key: &str) -> Option<&'static str> {
+    command_line::parse(as_str(), key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::allocator::{heap_reservation, DEFAULT_HEAP_RESERVATION};
+    use crate::memtest;
+    use alloc::format;
+    use log::info;
+
+    #[test_case]
+    fn test_copy() {
+        info!("TESTING boot_params::test_copy");
+        // The copy does not refer to the source, which is overwritten like the loader stack
+        let mut buf = *b"memtest=full heap=64";
+        let cl = CommandLine {
+            ptr: buf.as_ptr(),
+            len: buf.len() as u64,
+        };
+        let params = copy(cl.as_str());
+        buf.fill(0x55);
+        assert_eq!(command_line::parse(&params, "heap"), Some("64"));
+        assert_eq!(command_line::parse(&params, "memtest"), Some("full"));
+
+        // Truncated at a char boundary
+        let long = "é".repeat(MAX_LEN);
+        assert_eq!(copy(&long).len(), MAX_LEN);
+        let long = format!("a{}", long);
+        assert_eq!(copy(&long).len(), MAX_LEN - 1);
+    }
+
+    #[test_case]
+    fn test_malformed_params() {
+        info!("TESTING boot_params::test_malformed_params");
+        let heap = |s: &str| heap_reservation(command_line::parse(&copy(s), "heap"));
+        let memtest =
+            |s: &str| command_line::parse(&copy(s), "memtest").and_then(memtest::Mode::parse);
+
+        // Missing
+        assert_eq!(heap(""), DEFAULT_HEAP_RESERVATION);
+        assert_eq!(heap("memtest=quick heapsize=8"), DEFAULT_HEAP_RESERVATION);
+        assert_eq!(memtest("heap=8"), None);
+
+        // Malformed values fall back to the defaults instead of failing the boot
+        for s in [
+            "heap",
+            "heap=",
+            "heap=16M",
+            "heap=-1",
+            "heap=0x10",
+            "heap=99999999999999",
+        ] {
+            assert_eq!(heap(s), DEFAULT_HEAP_RESERVATION, "{}", s);
+        }
+        assert_eq!(memtest("memtest"), None);
+        assert_eq!(memtest("memtest=Quick"), None);
+
+        assert_eq!(heap("memtest=full heap=8"), 8 * 1024 * 1024);
+        assert_eq!(heap(" heap=0 "), 0);
+        assert_eq!(memtest("memtest=full heap=8"), Some(memtest::Mode::Full));
+    }
+}
@@ -0,0 +1,126 @@
+//! Compile-time diagnostics profiles.
+//!
+//! A profile is selected by one of the cargo features `diag-min`, `diag-default`, and `diag-full`
+//! (`diag-default` is used if none is given, and the more verbose one wins if several are given).
+//! Each diagnostic checks the corresponding constant, so disabled paths compile to nothing.
+//!
+//! | constant            | diag-min | diag-default | diag-full |
+//! |---------------------|----------|--------------|-----------|
+//! | `LOG_LEVEL`         | Warn     | Info         | Trace     |
+//! | `TRACE_ALLOCATIONS` | off      | off          | on        |
+//! | `LOCK_DIAGNOSTICS`  | off      | on           | on        |
+//! | `TRACE_CATEGORIES`  | none     | all          | all       |
+//! | `ENSURE_IS_FATAL`   | no (log) | no (log)     | yes       |
+
+use crate::trace::Category;
+use core::fmt;
+use log::LevelFilter;
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Profile {
+    Min,
+    Default,
+    Full,
+}
+
+impl Profile {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Min => "diag-min",
+            Self::Default => "diag-default",
+            Self::Full => "diag-full",
+        }
+    }
+}
+
+pub const PROFILE: Profile = if cfg!(feature = "diag-full") {
+    Profile::Full
+} else if cfg!(feature = "diag-default") || !cfg!(feature = "diag-min") {
+    Profile::Default
+} else {
+    Profile::Min
+};
+
+/// The maximum log level set at boot.
+pub const LOG_LEVEL: LevelFilter = match PROFILE {
+    Profile::Min => LevelFilter::Warn,
+    Profile::Default => LevelFilter::Info,
+    Profile::Full => LevelFilter::Trace,
+};
+
+/// Log every allocation and deallocation of the heap and of physical frames at trace level.
+pub const TRACE_ALLOCATIONS: bool = matches!(PROFILE, Profile::Full);
+
+/// Record the contention of `sync::mutex::Mutex` as trace events, and track the owner task of the
+/// mutex to detect recursive locking.
+pub const LOCK_DIAGNOSTICS: bool = !matches!(PROFILE, Profile::Min);
+
+/// Categories of `trace_event!` compiled in. Events of the other categories are never recorded.
+pub const TRACE_CATEGORIES: &[Category] = match PROFILE {
+    Profile::Min => &[],
+    Profile::Default | Profile::Full => &Category::ALL,
+};
+
+/// Whether a failed `kensure!` panics. Otherwise it is only logged as a warning.
+pub const ENSURE_IS_FATAL: bool = matches!(PROFILE, Profile::Full);
+
+/// Check an invariant whose violation is recoverable. Depending on `ENSURE_IS_FATAL`, a failure
+/// panics or is logged with the given message.
+macro_rules! kensure {
+    ($cond:expr, $( $t:tt )*) => {
+        if !$cond {
+            if $crate::config::ENSURE_IS_FATAL {
+                panic!($( $t )*);
+            } else {
+                ::log::warn!($( $t )*);
+            }
+        }
+    };
+}
+
+/// A summary of the configuration, printed at boot.
+pub fn describe() -> impl fmt::Display {
+    Description
+}
+
+struct Description;
+
+impl fmt::Display for Description {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let on_off = |b| if b { "on" } else { "off" };
+        write!(
+            f,
+            "config: {} (log level = {}, allocation tracing = {}, lock diagnostics = {}, kensure = {}, trace categories =",
+            PROFILE.name(),
+            LOG_LEVEL,
+            on_off(TRACE_ALLOCATIONS),
+            on_off(LOCK_DIAGNOSTICS),
+            if ENSURE_IS_FATAL { "fatal" } else { "log" },
+        )?;
+        if TRACE_CATEGORIES.is_empty() {
+            write!(f, " none")?;
+        }
+        for c in TRACE_CATEGORIES {
+            write!(f, " {}", c.name())?;
+        }
+        write!(f, ")")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::format;
+    use log::info;
+
+    #[test_case]
+    fn test_describe() {
+        info!("TESTING config::test_describe");
+        let description = format!("{}", describe());
+        assert!(description.contains(PROFILE.name()));
+        for c in Category::ALL {
+            assert_eq!(crate::trace::is_compiled(c), TRACE_CATEGORIES.contains(&c));
+            assert_eq!(description.contains(c.name()), TRACE_CATEGORIES.contains(&c));
+        }
+    }
+}
@@ -1,30 +1,181 @@
-use crate::graphics::ScreenBuffer;
-use crate::interrupts::{ticks, TIMER_FREQ};
+use crate::boot_params;
+use crate::devices;
+use crate::devices::serial;
+use crate::fs::fat;
+use crate::fs::mount;
+use crate::graphics::display::{self, ConsoleSurface};
+use crate::graphics::{
+    bmp, CacheStats, FontError, FontFace, FrameBuffer, MonospaceFont, ScreenBuffer, VecBuffer,
+};
+use crate::interrupts::{deadline_after, ticks, Deadline, TIMER_FREQ};
 use crate::sync::queue::Queue;
+use crate::sync::spin::Spin;
 use crate::task;
 use alloc::boxed::Box;
-use core::convert::TryInto;
-use core::fmt;
-use core::sync::atomic::{AtomicBool, Ordering};
-use log::trace;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::convert::{TryFrom, TryInto};
+use core::fmt::{self, Write as _};
+use core::ops::Deref;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU8, AtomicUsize, Ordering};
+use log::{info, trace, warn};
+use ors_common::frame_buffer::FrameBuffer as RawFrameBuffer;
+use ors_common::term::ansi;
 
-mod ansi;
 mod kbd;
+mod mirror;
 mod screen;
-mod theme;
+
+pub use kbd::Typematic;
+pub use ors_common::term::theme::{parse_color, CustomTheme, Theme};
 
 const OUT_CHUNK_SIZE: usize = 64;
+const OUT_SHARED_THRESHOLD: usize = 4 * OUT_CHUNK_SIZE;
+const EARLY_OUT_SIZE: usize = 8192;
+const LOGO_PATH: &str = "/ors-logo.bmp";
+const MAX_PENDING_REPEATS: usize = 2;
+/// How long the rest of an escape sequence from COM1 is waited for.
+const ESCAPE_TIMEOUT: usize = TIMER_FREQ / 10;
+const RENDER_FREQ: usize = 30;
+const RENDER_INTERVAL: usize = TIMER_FREQ / RENDER_FREQ;
+/// Font files larger than this are not loaded.
+const MAX_FONT_FILE_SIZE: usize = 4 * 1024 * 1024;
+
+/// The font loaded at boot unless specified by `font=<path>` of the command line.
+pub const DEFAULT_FONT_PATH: &str = "/fonts";
 
-static IN: Queue<Input, 128> = Queue::new();
-static OUT: Queue<heapless::String<OUT_CHUNK_SIZE>, 128> = Queue::new();
+static IN: Queue<(Input, bool), 128> = Queue::named("console.in");
+static PENDING_REPEATS: AtomicUsize = AtomicUsize::new(0);
+static TYPEMATIC: AtomicU8 = AtomicU8::new(Typematic::DEFAULT.code());
+static OUT: Queue<OutChunk, 128> = Queue::named("console.out");
 static OUT_READY: AtomicBool = AtomicBool::new(false);
-static RAW_IN: Queue<RawInput, 128> = Queue::new();
+static MIRROR_FILTER: Spin<mirror::Filter> = Spin::new(mirror::Filter::new());
+static OUTPUT_MODE: AtomicU8 = AtomicU8::new(OutputMode::Both as u8);
+static EARLY_OUT: Spin<EarlyOut> = Spin::new(EarlyOut::new());
+static RAW_IN: Queue<RawInput, 128> = Queue::named("console.raw_in");
+static THEME: Spin<Theme> = Spin::new(Theme::OneMonokai);
+static STATS: Spin<Stats> = Spin::new(Stats::new());
+static OUTPUT_RESTARTS: AtomicUsize = AtomicUsize::new(0);
+static OUTPUT_CHARS: AtomicUsize = AtomicUsize::new(0);
+static SERIAL_ONLY: AtomicBool = AtomicBool::new(false);
+// The size of a serial terminal is unknown, so the common 80x24 is assumed
+static COLUMNS: AtomicUsize = AtomicUsize::new(80);
+static LINES: AtomicUsize = AtomicUsize::new(24);
+
+/// The screen of the console output task. This is kept outside of the task so that the screen
+/// survives restarts of the task. Only the (single) console output task accesses it.
+static SCREEN: AtomicPtr<screen::Screen<ConsoleSurface<ScreenBuffer>, Theme>> =
+    AtomicPtr::new(ptr::null_mut());
+/// The font to be applied by the console output task. `Some(None)` reverts to the embedded font.
+static FONT_CHANGE: Spin<Option<Option<MonospaceFont<'static>>>> = Spin::new(None);
+static LOGO: Spin<Option<VecBuffer>> = Spin::new(None);
+
+/// Where the console output goes, selected by `console=<mode>` of the command line.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum OutputMode {
+    Screen,
+    Serial,
+    /// The screen, and a copy to the serial port without the screen-oriented escape sequences.
+    Both,
+}
+
+impl OutputMode {
+    pub const ALL: [Self; 3] = [Self::Screen, Self::Serial, Self::Both];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Screen => "screen",
+            Self::Serial => "serial",
+            Self::Both => "both",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|m| m.name() == name)
+    }
+}
+
+impl Default for OutputMode {
+    fn default() -> Self {
+        Self::Both
+    }
+}
 
-pub fn initialize(buf: ScreenBuffer) {
+impl fmt::Display for OutputMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// Choose the screen for the console output. The console falls back to the serial port if the
+/// frame buffer is absent or unusable, or if `mode` is `OutputMode::Serial` (`console=serial`).
+/// This should be called early so that the output before `initialize` is not duplicated.
+pub fn select_output(fb: &RawFrameBuffer, mode: OutputMode) -> Option<ScreenBuffer> {
+    let screen = if mode == OutputMode::Serial {
+        warn!("console: Serial-only mode is forced");
+        None
+    } else {
+        match ScreenBuffer::try_from(*fb) {
+            Ok(screen) => Some(screen),
+            Err(e) => {
+                warn!("console: Falling back to serial-only mode: {}", e);
+                None
+            }
+        }
+    };
+    SERIAL_ONLY.store(screen.is_none(), Ordering::Release);
+    let mode = if screen.is_some() {
+        mode
+    } else {
+        OutputMode::Serial
+    };
+    OUTPUT_MODE.store(mode as u8, Ordering::Release);
+    screen
+}
+
+/// Whether the console has no screen, so that the output always goes to the serial port.
+pub fn is_serial_only() -> bool {
+    SERIAL_ONLY.load(Ordering::Acquire)
+}
+
+pub fn output_mode() -> OutputMode {
+    OutputMode::ALL[OUTPUT_MODE.load(Ordering::Acquire) as usize]
+}
+
+/// Change where the console output goes. Returns `false` if the console has no screen, in which
+/// case only `OutputMode::Serial` is available.
+pub fn set_output_mode(mode: OutputMode) -> bool {
+    if is_serial_only() && mode != OutputMode::Serial {
+        return false;
+    }
+    OUTPUT_MODE.store(mode as u8, Ordering::Release);
+    true
+}
+
+/// The number of columns and lines of the console.
+pub fn size() -> (usize, usize) {
+    (
+        COLUMNS.load(Ordering::Acquire),
+        LINES.load(Ordering::Acquire),
+    )
+}
+
+/// Start the console tasks. The console draws into the console surface of the display if
+/// `graphics::display` is initialized, or outputs to the serial port otherwise.
+pub fn initialize() {
     trace!("INITIALIZING console");
-    let buf = Box::into_raw(Box::new(buf)) as u64;
-    task::scheduler().add(task::Priority::MAX, handle_output, buf);
-    task::scheduler().add(task::Priority::MAX, handle_raw_input, 0);
+    devices::ps2::set_typematic(typematic().code());
+    // The console tasks are restarted on panic so that the system keeps running
+    let policy = task::RestartPolicy::DEFAULT;
+    if display::get().is_some() {
+        task::scheduler().add_supervised(task::Priority::MAX, handle_output, 0, policy);
+    } else {
+        task::scheduler().add_supervised(task::Priority::MAX, handle_serial_output, 0, policy);
+    }
+    task::scheduler().add_supervised(task::Priority::MAX, handle_raw_input, 0, policy);
 }
 
 #[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy)]
@@ -40,68 +191,568 @@ pub enum Input {
     ArrowDown,
     ArrowLeft,
     ArrowRight,
+    /// Function key F1-F12.
+    Function(u8),
+    /// Function key F1-F12 with Alt, such as for switching virtual terminals.
+    AltFunction(u8),
+    MediaKey(MediaKey),
+}
+
+impl TryFrom<ansi::DecodeResult> for Input {
+    type Error = ();
+
+    fn try_from(value: ansi::DecodeResult) -> Result<Self, Self::Error> {
+        match value {
+            ansi::DecodeResult::Just(a) => Ok(Input::Char(a)),
+            ansi::DecodeResult::EscapeSequence(s) => s.try_into(),
+        }
+    }
+}
+
+impl TryFrom<ansi::EscapeSequence> for Input {
+    type Error = ();
+
+    fn try_from(value: ansi::EscapeSequence) -> Result<Self, Self::Error> {
+        Ok(match value {
+            ansi::EscapeSequence::CursorUp(1) => Input::ArrowUp,
+            ansi::EscapeSequence::CursorDown(1) => Input::ArrowDown,
+            ansi::EscapeSequence::CursorForward(1) => Input::ArrowRight,
+            ansi::EscapeSequence::CursorBack(1) => Input::ArrowLeft,
+            ansi::EscapeSequence::CursorPreviousLine(1) => Input::End,
+            ansi::EscapeSequence::CursorPosition(1, 1) => Input::Home,
+            ansi::EscapeSequence::Home => Input::Home,
+            ansi::EscapeSequence::Insert => Input::Insert,
+            ansi::EscapeSequence::Delete => Input::Char('\x7f'),
+            ansi::EscapeSequence::End => Input::End,
+            ansi::EscapeSequence::PgUp => Input::PageUp,
+            ansi::EscapeSequence::PgDn => Input::PageDown,
+            ansi::EscapeSequence::Function(n, 1) => Input::Function(n),
+            ansi::EscapeSequence::Function(n, 3) => Input::AltFunction(n),
+            _ => Err(())?,
+        })
+    }
+}
+
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy, Hash)]
+pub enum MediaKey {
+    Play,
+    Pause,
+    VolumeUp,
+    VolumeDown,
+    Mute,
+}
+
+impl MediaKey {
+    pub const ALL: [Self; 5] = [
+        Self::Play,
+        Self::Pause,
+        Self::VolumeUp,
+        Self::VolumeDown,
+        Self::Mute,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Play => "play",
+            Self::Pause => "pause",
+            Self::VolumeUp => "volup",
+            Self::VolumeDown => "voldown",
+            Self::Mute => "mute",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|k| k.name() == name)
+    }
+}
+
+/// Wait for an input to the console.
+pub fn read_input() -> Input {
+    let (input, repeat) = IN.dequeue();
+    if repeat {
+        PENDING_REPEATS.fetch_sub(1, Ordering::AcqRel);
+    }
+    input
+}
+
+pub fn typematic() -> Typematic {
+    Typematic::from_code(TYPEMATIC.load(Ordering::Acquire))
 }
 
-pub fn input_queue() -> &'static Queue<Input, 128> {
-    &IN
+/// Change the key repeat settings of the keyboard. The software repeat follows the same settings.
+pub fn set_typematic(t: Typematic) {
+    TYPEMATIC.store(t.code(), Ordering::Release);
+    devices::ps2::set_typematic(t.code());
+}
+
+pub fn active_theme() -> Theme {
+    *THEME.lock()
+}
+
+/// Change the theme of the console. The console is cleared when the theme is applied.
+pub fn set_theme(t: Theme) {
+    *THEME.lock() = t;
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum FontLoadError {
+    SerialOnly,
+    NotMounted(String),
+    Fs(String, fat::Error),
+    TooLarge(String, usize),
+    Font(String, FontError),
+    /// A cell of the font size does not fit in the screen.
+    TooLargeForScreen(u32),
+}
+
+impl fmt::Display for FontLoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::SerialOnly => write!(f, "The console has no screen"),
+            Self::NotMounted(path) => write!(f, "{}: No file system is mounted", path),
+            Self::Fs(path, e) => write!(f, "{}: {}", path, e),
+            Self::TooLarge(path, size) => write!(
+                f,
+                "{}: Too large font file ({} bytes, at most {} bytes)",
+                path, size, MAX_FONT_FILE_SIZE
+            ),
+            Self::Font(path, e) => write!(f, "{}: {}", path, e),
+            Self::TooLargeForScreen(size) => write!(f, "Font size {} is too large", size),
+        }
+    }
+}
+
+/// Load the font at `path` and use it for the console in `size` (the embedded font size by
+/// default). `path` is either a font file used for both normal and bold text, or a directory
+/// containing `normal.ttf` and optionally `bold.ttf`. The current font is kept on failure.
+/// The font is applied asynchronously by the console output task.
+pub fn load_font(path: &str, size: Option<u32>) -> Result<(), FontLoadError> {
+    let display = display::get().ok_or(FontLoadError::SerialOnly)?;
+    let size = size.unwrap_or(screen::FONT_SIZE);
+    let (normal, bold) = read_font_faces(path)?;
+    let font = MonospaceFont::with_faces(size, normal, bold, display.format())
+        .map_err(|e| FontLoadError::Font(String::from(path), e))?;
+    if display.width() < font.unit_width() as usize || display.height() < size as usize {
+        Err(FontLoadError::TooLargeForScreen(size))?;
+    }
+    *FONT_CHANGE.lock() = Some(Some(font));
+    Ok(())
+}
+
+/// Revert the console to the embedded font.
+pub fn reset_font() {
+    *FONT_CHANGE.lock() = Some(None);
+}
+
+/// Load the font specified by the boot parameters `font=<path>` and `fontsize=<size>`, or the one
+/// at `DEFAULT_FONT_PATH` if exists. This is called after the file systems are mounted.
+pub fn load_initial_font() {
+    if display::get().is_none() {
+        return;
+    }
+    let path = boot_params::get("font");
+    let size = boot_params::get("fontsize").and_then(|s| s.parse().ok());
+    match load_font(path.unwrap_or(DEFAULT_FONT_PATH), size) {
+        Ok(()) => info!(
+            "console: Font loaded from {}",
+            path.unwrap_or(DEFAULT_FONT_PATH)
+        ),
+        // The embedded font is used silently unless a font is explicitly specified
+        Err(FontLoadError::NotMounted(_) | FontLoadError::Fs(_, fat::Error::NotFound(_)))
+            if path.is_none() => {}
+        Err(e) => warn!("console: Failed to load the font: {}", e),
+    }
+}
+
+fn read_font_faces(path: &str) -> Result<(FontFace<'static>, FontFace<'static>), FontLoadError> {
+    let (m, relative) =
+        mount::resolve(path).ok_or_else(|| FontLoadError::NotMounted(String::from(path)))?;
+    let read = |relative: &str, path: &str| {
+        let fs_error = |e| FontLoadError::Fs(String::from(path), e);
+        let file = m.fs.open(relative).map_err(fs_error)?;
+        let reader = file
+            .reader()
+            .ok_or_else(|| fs_error(fat::Error::IsADirectory(String::from(file.name()))))?;
+        if MAX_FONT_FILE_SIZE < file.file_size() {
+            Err(FontLoadError::TooLarge(
+                String::from(path),
+                file.file_size(),
+            ))?;
+        }
+        reader.read_to_end().map_err(fs_error)
+    };
+    let parse = |data, path: &str| {
+        FontFace::from_vec(data).map_err(|e| FontLoadError::Font(String::from(path), e))
+    };
+
+    if m.fs.open_dir(&relative).is_err() {
+        let data = read(&relative, path)?;
+        return Ok((parse(data.clone(), path)?, parse(data, path)?));
+    }
+    let normal_path = format!("{}/normal.ttf", path.trim_end_matches('/'));
+    let normal = read(&format!("{}/normal.ttf", relative), &normal_path)?;
+    let bold_path = format!("{}/bold.ttf", path.trim_end_matches('/'));
+    let bold = match read(&format!("{}/bold.ttf", relative), &bold_path) {
+        Ok(bold) => parse(bold, &bold_path)?,
+        Err(FontLoadError::Fs(_, fat::Error::NotFound(_))) => parse(normal.clone(), &normal_path)?,
+        Err(e) => Err(e)?,
+    };
+    Ok((parse(normal, &normal_path)?, bold))
+}
+
+/// Statistics of the console. `glyph_cache` is updated every time the screen is rendered.
+#[derive(Debug, Clone, Copy)]
+pub struct Stats {
+    pub glyph_cache: CacheStats,
+    /// Number of times the console output task has been restarted.
+    pub output_restarts: usize,
+    /// Number of characters decoded by the console output task.
+    pub output_chars: usize,
+    pub serial: serial::Stats,
+}
+
+impl Stats {
+    const fn new() -> Self {
+        Self {
+            glyph_cache: CacheStats {
+                entries: 0,
+                capacity: 0,
+                bytes: 0,
+                hits: 0,
+                misses: 0,
+            },
+            output_restarts: 0,
+            output_chars: 0,
+            serial: serial::Stats {
+                received: 0,
+                overruns: 0,
+                parity_errors: 0,
+                framing_errors: 0,
+                breaks: 0,
+            },
+        }
+    }
+}
+
+pub fn stats() -> Stats {
+    Stats {
+        output_restarts: OUTPUT_RESTARTS.load(Ordering::Acquire),
+        output_chars: OUTPUT_CHARS.load(Ordering::Acquire),
+        serial: serial::stats(),
+        ..*STATS.lock()
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
 pub struct ConsoleWrite;
 
 impl fmt::Write for ConsoleWrite {
-    fn write_str(&mut self, mut s: &str) -> fmt::Result {
-        if OUT_READY.load(Ordering::Acquire) {
-            while s.len() > 0 {
-                let mut i = s.len().min(OUT_CHUNK_SIZE);
-                while !s.is_char_boundary(i) {
-                    i -= 1;
-                }
-                let (chunk, next_s) = s.split_at(i);
-                OUT.enqueue(chunk.into());
-                s = next_s;
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if !OUT_READY.load(Ordering::Acquire) {
+            // OUT_READY is set while EARLY_OUT is locked, so the output is never lost here
+            let mut early_out = EARLY_OUT.lock();
+            if !OUT_READY.load(Ordering::Acquire) {
+                early_out.write(s);
+                return Ok(());
             }
         }
+        OutChunk::split(s, |chunk| OUT.enqueue(chunk));
         Ok(())
     }
 }
 
-extern "C" fn handle_output(buf: u64) -> ! {
-    const RENDER_FREQ: usize = 30;
-    const RENDER_INTERVAL: usize = TIMER_FREQ / RENDER_FREQ;
+/// A unit of the console output passed to the console output task.
+#[derive(Debug)]
+enum OutChunk {
+    Inline(heapless::String<OUT_CHUNK_SIZE>),
+    /// Large writes are copied at once instead of being split into many inline chunks.
+    Shared(Arc<str>),
+}
+
+impl OutChunk {
+    fn split(mut s: &str, mut f: impl FnMut(Self)) {
+        if s.len() >= OUT_SHARED_THRESHOLD {
+            f(Self::Shared(Arc::from(s)));
+            return;
+        }
+        while s.len() > 0 {
+            let (chunk, next_s) = s.split_at(Self::inline_len(s));
+            f(Self::Inline(chunk.into()));
+            s = next_s;
+        }
+    }
+
+    /// Length of the leading inline chunk of `s`. An escape sequence is not split into two chunks
+    /// unless it does not fit in a single chunk.
+    fn inline_len(s: &str) -> usize {
+        if s.len() <= OUT_CHUNK_SIZE {
+            return s.len();
+        }
+        let mut i = OUT_CHUNK_SIZE;
+        while !s.is_char_boundary(i) {
+            i -= 1;
+        }
+        match s[..i].rfind('\x1b') {
+            Some(e) if e > 0 && !is_complete_escape_sequence(&s[e..i]) => e,
+            _ => i,
+        }
+    }
+}
+
+impl Deref for OutChunk {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        match self {
+            Self::Inline(s) => s,
+            Self::Shared(s) => s,
+        }
+    }
+}
+
+/// Whether `s`, which starts with ESC, contains the end of the escape sequence.
+fn is_complete_escape_sequence(s: &str) -> bool {
+    let mut chars = s.chars().skip(1);
+    match chars.next() {
+        // Parameter and intermediate bytes continue until the final byte
+        Some('[') => chars.any(|ch| matches!(ch, '@'..='~')),
+        Some('O') => chars.next().is_some(),
+        Some(_) => true,
+        None => false,
+    }
+}
+
+extern "C" fn handle_output(_: u64) -> ! {
+    let display = display::get().unwrap();
+    let screen = match unsafe { SCREEN.load(Ordering::Acquire).as_mut() } {
+        Some(screen) => {
+            OUTPUT_RESTARTS.fetch_add(1, Ordering::AcqRel);
+            screen.reset_decoder();
+            put_str(screen, "\n[console: restarted]\n");
+            screen
+        }
+        None => {
+            let screen = Box::leak(Box::new(prepare_screen(display)));
+            SCREEN.store(screen, Ordering::Release);
+            let (columns, lines) = screen.size();
+            COLUMNS.store(columns, Ordering::Release);
+            LINES.store(lines, Ordering::Release);
+
+            for s in take_early_out() {
+                put_output(screen, &s);
+            }
+            screen
+        }
+    };
+    let mut next_render = Deadline::at(0);
+
+    loop {
+        let theme = active_theme();
+        if *screen.theme() != theme {
+            screen.set_theme(theme);
+        }
+
+        let font = FONT_CHANGE.lock().take();
+        if let Some(font) = font {
+            // The screen is cleared by the font change, which must be drawn as the console
+            let font = font.unwrap_or_else(|| screen::default_font(display.format()));
+            display.draw_console(|| screen.set_font(font));
+            let (columns, lines) = screen.size();
+            COLUMNS.store(columns, Ordering::Release);
+            LINES.store(lines, Ordering::Release);
+        }
+
+        // The logo stays on the screen until it is overwritten by the console output
+        let logo = LOGO.lock().take();
+        if let Some(logo) = logo {
+            display.draw_console(|| screen.draw_centered(&logo));
+        }
+
+        if next_render.is_expired() {
+            display.draw_console(|| screen.render());
+            STATS.lock().glyph_cache = screen.font_stats();
+            next_render = deadline_after(RENDER_INTERVAL);
+        }
+
+        if let Some(out) = OUT.dequeue_timeout(output_timeout(next_render, ticks())) {
+            put_output(screen, &out);
+        }
+    }
+}
+
+/// Put the output to where the output mode directs. In `OutputMode::Both`, the copy to the serial
+/// port has already been written by `mirror_to_serial`.
+fn put_output<T: FrameBuffer>(screen: &mut screen::Screen<T, Theme>, s: &str) {
+    match output_mode() {
+        OutputMode::Screen | OutputMode::Both => put_str(screen, s),
+        OutputMode::Serial => put_serial(s),
+    }
+}
+
+/// Copy the output of the kernel to the serial port in `OutputMode::Both`, without the
+/// screen-oriented escape sequences (see `mirror::Filter`). This is called by the writer instead
+/// of the console output task, so that the output is captured even if the task is stuck or not
+/// yet started. Each write is copied at once, in the order of the writes.
+pub fn mirror_to_serial(s: &str) {
+    if output_mode() != OutputMode::Both {
+        return;
+    }
+    let mut filter = MIRROR_FILTER.lock();
+    let _ = filter.filter(s, &mut *devices::serial::default_port());
+}
 
-    let buf = unsafe { Box::from_raw(buf as *mut ScreenBuffer) };
-    let mut screen = screen::Screen::new(*buf, theme::OneMonokai);
-    let mut next_render_ticks = 0;
-    let mut decoder = ansi::Decoder::new();
+/// The timeout of waiting for output until the next render. Even if the render is overdue, such
+/// as when rendering took longer than `RENDER_INTERVAL`, this is at least 1 tick so that the
+/// output task never busy-spins.
+fn output_timeout(next_render: Deadline, now: usize) -> usize {
+    next_render.remaining_at(now).clamp(1, RENDER_INTERVAL)
+}
 
-    OUT_READY.store(true, Ordering::SeqCst);
+/// Console output task of the serial-only mode. The output, including escape sequences, is passed
+/// through to the serial port as is.
+extern "C" fn handle_serial_output(_: u64) -> ! {
+    // OUT_READY is set only by the first console output task
+    if OUT_READY.load(Ordering::Acquire) {
+        OUTPUT_RESTARTS.fetch_add(1, Ordering::AcqRel);
+        put_serial("\n[console: restarted]\n");
+    } else {
+        for s in take_early_out() {
+            put_serial(&s);
+        }
+    }
 
     loop {
-        let t = ticks();
-        if next_render_ticks <= t {
-            screen.render();
-            next_render_ticks = ticks() + RENDER_INTERVAL;
-        }
-
-        if let Some(out) = OUT.dequeue_timeout(next_render_ticks - t) {
-            for ch in out.chars() {
-                match decoder.add_char(ch) {
-                    Some(ansi::DecodeResult::Just(ch)) => screen.put_char(ch),
-                    Some(ansi::DecodeResult::EscapeSequence(es)) => {
-                        screen.handle_escape_sequence(es)
-                    }
-                    None => {}
-                }
+        let out = OUT.dequeue();
+        put_serial(&out);
+    }
+}
+
+fn put_serial(s: &str) {
+    OUTPUT_CHARS.fetch_add(s.chars().count(), Ordering::Relaxed);
+    let _ = devices::serial::default_port().write_str(s);
+}
+
+/// Switch the console output from EARLY_OUT to OUT, and take the output written before this point
+/// (followed by a notice of the dropped output, if any). They must be processed prior to any
+/// subsequent output.
+fn take_early_out() -> impl Iterator<Item = String> {
+    let (early_out, dropped_bytes) = {
+        let mut early_out = EARLY_OUT.lock();
+        OUT_READY.store(true, Ordering::SeqCst);
+        early_out.take()
+    };
+    let message = if dropped_bytes > 0 {
+        Some(format!(
+            "[console: {} bytes of early output dropped]\n",
+            dropped_bytes
+        ))
+    } else {
+        None
+    };
+    core::iter::once(early_out).chain(message)
+}
+
+/// Create the screen on the console surface, taken by the first console output task.
+fn prepare_screen(
+    display: &'static display::Display<ScreenBuffer>,
+) -> screen::Screen<'static, ConsoleSurface<'static, ScreenBuffer>, Theme> {
+    let buf = display.console_surface().unwrap();
+    screen::Screen::new(buf, active_theme())
+}
+
+fn put_str<T: FrameBuffer>(screen: &mut screen::Screen<T, Theme>, s: &str) {
+    OUTPUT_CHARS.fetch_add(s.chars().count(), Ordering::Relaxed);
+    #[cfg(test)]
+    if s.contains(tests::PANIC_SEQUENCE) {
+        panic!("console: Panic requested by the magic sequence");
+    }
+    screen.put_str(s);
+}
+
+/// Output written before the console output task is ready.
+/// On overflow, the oldest output is dropped to keep the newest output.
+struct EarlyOut {
+    buf: heapless::Deque<u8, EARLY_OUT_SIZE>,
+    dropped_bytes: usize,
+}
+
+impl EarlyOut {
+    const fn new() -> Self {
+        Self {
+            buf: heapless::Deque::new(),
+            dropped_bytes: 0,
+        }
+    }
+
+    fn write(&mut self, s: &str) {
+        for b in s.bytes() {
+            if self.buf.is_full() {
+                self.buf.pop_front();
+                self.dropped_bytes += 1;
             }
+            let _ = self.buf.push_back(b);
+        }
+    }
+
+    /// Take the buffered output and the number of dropped bytes.
+    fn take(&mut self) -> (String, usize) {
+        let len = self.buf.len();
+        // Dropping may leave a partial UTF-8 sequence at the beginning
+        let bytes = self
+            .buf
+            .iter()
+            .copied()
+            .skip_while(|b| (b & 0xc0) == 0x80)
+            .collect::<Vec<_>>();
+        let dropped_bytes = self.dropped_bytes + (len - bytes.len());
+        *self = Self::new();
+        let s = String::from_utf8(bytes).unwrap_or_default();
+        (s, dropped_bytes)
+    }
+}
+
+/// Load the startup logo at `LOGO_PATH` if exists. This is called after the file systems are
+/// mounted, and the logo is drawn asynchronously by the console output task.
+pub fn load_logo() {
+    if display::get().is_none() {
+        return;
+    }
+    let data = match read_logo() {
+        Ok(Some(data)) => data,
+        Ok(None) => return,
+        Err(e) => {
+            trace!("console: Failed to read {}: {}", LOGO_PATH, e);
+            return;
         }
+    };
+    match bmp::load(&data) {
+        Ok(logo) => *LOGO.lock() = Some(logo),
+        Err(e) => trace!("console: Failed to load {}: {}", LOGO_PATH, e),
     }
 }
 
+fn read_logo() -> Result<Option<Vec<u8>>, fat::Error> {
+    let (m, relative) = match mount::resolve(LOGO_PATH) {
+        Some(resolved) => resolved,
+        None => return Ok(None),
+    };
+    let file = match m.fs.open(&relative) {
+        Ok(file) => file,
+        Err(fat::Error::NotFound(_)) => return Ok(None),
+        Err(e) => Err(e)?,
+    };
+    let buf = match file.reader() {
+        Some(reader) => reader.read_to_end()?,
+        None => return Ok(None), // directory
+    };
+    Ok(Some(buf))
+}
+
 #[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy, Hash)]
 pub enum RawInput {
     Kbd(u8),
     Com1(u8),
+    MediaKey(MediaKey),
 }
 
 pub fn accept_raw_input(input: RawInput) {
@@ -113,13 +764,53 @@ pub fn accept_raw_input(input: RawInput) {
 extern "C" fn handle_raw_input(_: u64) -> ! {
     let mut kbd_decoder = kbd::Decoder::new();
     let mut com1_decoder = ansi::Decoder::new();
+    let mut repeater = kbd::Repeater::new();
+    // A lone ESC cannot be told from the start of an escape sequence until this expires
+    let mut escape_deadline = None::<Deadline>;
 
     loop {
-        let input = RAW_IN.dequeue();
+        if let Some(input) = repeater.poll(ticks(), typematic()) {
+            enqueue_input(input, true);
+        }
+        if escape_deadline.map_or(false, |d| d.is_expired()) {
+            escape_deadline = None;
+            if let Some(input) = com1_decoder.flush().and_then(|r| r.try_into().ok()) {
+                enqueue_input(input, false);
+            }
+        }
+        let deadline = match (repeater.deadline().map(Deadline::at), escape_deadline) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        let input = match deadline {
+            Some(deadline) => match RAW_IN.dequeue_timeout(deadline.remaining().max(1)) {
+                Some(input) => input,
+                None => continue,
+            },
+            None => RAW_IN.dequeue(),
+        };
         if let Some(input) = match input {
-            RawInput::Kbd(input) => kbd_decoder.add(input),
+            RawInput::Kbd(input) => match kbd_decoder.add(input) {
+                Some(kbd::Event::Down { input, repeated }) => {
+                    if repeater.press(input, repeated, ticks(), typematic()) {
+                        Some(input)
+                    } else {
+                        None
+                    }
+                }
+                Some(kbd::Event::Up(input)) => {
+                    repeater.release(input);
+                    None
+                }
+                None => None,
+            },
+            RawInput::MediaKey(key) => Some(Input::MediaKey(key)),
             RawInput::Com1(0x7f) => Some(Input::Char('\x08')), // DEL -> BS
             RawInput::Com1(0x0d) => Some(Input::Char('\x0A')), // CR  -> LF
+            // Control characters other than BS, HT, LF and ESC are typed with Ctrl
+            RawInput::Com1(input @ (0x01..=0x07 | 0x0b | 0x0c | 0x0e..=0x1a)) => {
+                Some(Input::Ctrl(char::from(b'a' + input - 1)))
+            }
             RawInput::Com1(input) if input <= 0x7e => com1_decoder
                 .add_char(char::from(input))
                 .and_then(|input| input.try_into().ok()),
@@ -128,7 +819,239 @@ extern "C" fn handle_raw_input(_: u64) -> ! {
                 None
             }
         } {
-            let _ = IN.try_enqueue(input);
+            enqueue_input(input, false);
+        }
+        if let RawInput::Com1(_) = input {
+            escape_deadline = match com1_decoder.is_idle() {
+                true => None,
+                false => Some(deadline_after(ESCAPE_TIMEOUT)),
+            };
+        }
+    }
+}
+
+fn enqueue_input(input: Input, repeat: bool) {
+    if repeat {
+        // Repeats are dropped instead of piling up while the reader is busy
+        if PENDING_REPEATS.fetch_add(1, Ordering::AcqRel) >= MAX_PENDING_REPEATS {
+            PENDING_REPEATS.fetch_sub(1, Ordering::AcqRel);
+            return;
+        }
+    }
+    if IN.try_enqueue((input, repeat)).is_err() && repeat {
+        PENDING_REPEATS.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::sync::atomic::AtomicUsize;
+    use log::info;
+
+    /// The magic sequence which makes the console output task panic, to test its recovery.
+    pub(super) const PANIC_SEQUENCE: &str = "\x1b[4242~";
+
+    /// Wait until `f` holds for up to a second.
+    fn wait_until(f: impl Fn() -> bool) -> bool {
+        for _ in 0..TIMER_FREQ {
+            if f() {
+                return true;
+            }
+            task::scheduler().sleep(1);
+        }
+        f()
+    }
+
+    #[test_case]
+    fn test_output_timeout() {
+        info!("TESTING console::test_output_timeout");
+        let next_render = Deadline::after(100, RENDER_INTERVAL);
+        assert_eq!(output_timeout(next_render, 100), RENDER_INTERVAL);
+        assert_eq!(output_timeout(next_render, 101), RENDER_INTERVAL - 1);
+        // Render overruns: the deadline has passed by the time the output task waits
+        for now in [
+            100 + RENDER_INTERVAL,
+            100 + 10 * RENDER_INTERVAL,
+            usize::MAX,
+        ] {
+            assert_eq!(output_timeout(next_render, now), 1);
+        }
+        assert_eq!(output_timeout(Deadline::at(usize::MAX), 0), RENDER_INTERVAL);
+    }
+
+    #[test_case]
+    fn test_output_restart() {
+        info!("TESTING console::test_output_restart");
+        let restarts = stats().output_restarts;
+        cprint!("{}", PANIC_SEQUENCE);
+        assert!(wait_until(|| stats().output_restarts == restarts + 1));
+
+        // The restarted task keeps rendering on the same screen
+        let chars = stats().output_chars;
+        cprintln!("console: recovered");
+        assert!(wait_until(|| stats().output_chars >= chars + 19));
+    }
+
+    #[test_case]
+    fn test_function_key_input() {
+        info!("TESTING console::test_function_key_input");
+        let input = |s: &str| {
+            let mut decoder = ansi::Decoder::new();
+            let result = s.chars().filter_map(|ch| decoder.add_char(ch)).next();
+            Input::try_from(result.unwrap())
+        };
+        assert_eq!(input("\x1bOP"), Ok(Input::Function(1)));
+        assert_eq!(input("\x1b[14~"), Ok(Input::Function(4)));
+        assert_eq!(input("\x1b[24~"), Ok(Input::Function(12)));
+        assert_eq!(input("\x1b[1;3S"), Ok(Input::AltFunction(4)));
+        assert_eq!(input("\x1b[17;3~"), Ok(Input::AltFunction(6)));
+        // Other modifiers are not supported
+        assert_eq!(input("\x1b[17;5~"), Err(()));
+    }
+
+    #[test_case]
+    fn test_early_out() {
+        info!("TESTING console::test_early_out");
+        let mut early_out = EarlyOut::new();
+        early_out.write("hello, ");
+        early_out.write("world");
+        assert_eq!(early_out.take(), (String::from("hello, world"), 0));
+        assert_eq!(early_out.take(), (String::new(), 0));
+
+        // The newest output is kept on overflow
+        for _ in 0..EARLY_OUT_SIZE {
+            early_out.write("a");
         }
+        early_out.write("αb"); // 'α' is 2 bytes
+        let (s, dropped_bytes) = early_out.take();
+        assert_eq!(s.len(), EARLY_OUT_SIZE);
+        assert!(s.ends_with("aαb"));
+        assert_eq!(dropped_bytes, 3);
+
+        // A partial UTF-8 sequence at the beginning is dropped
+        for _ in 0..EARLY_OUT_SIZE / 2 - 1 {
+            early_out.write("α");
+        }
+        early_out.write("abc");
+        let (s, dropped_bytes) = early_out.take();
+        assert_eq!(s.len(), EARLY_OUT_SIZE - 1);
+        assert!(s.starts_with("α") && s.ends_with("αabc"));
+        assert_eq!(dropped_bytes, 2);
+    }
+
+    fn split_out(s: &str) -> Vec<OutChunk> {
+        let mut chunks = Vec::new();
+        OutChunk::split(s, |chunk| chunks.push(chunk));
+        chunks
+    }
+
+    fn concat(chunks: &[OutChunk]) -> String {
+        chunks.iter().map(|chunk| &**chunk).collect()
+    }
+
+    #[test_case]
+    fn test_out_chunk_split() {
+        info!("TESTING console::test_out_chunk_split");
+        let s = "a".repeat(100);
+        let chunks = split_out(&s);
+        assert_eq!(chunks.len(), 2);
+        assert!(matches!(chunks[0], OutChunk::Inline(_)));
+        assert_eq!(chunks[0].len(), OUT_CHUNK_SIZE);
+        assert_eq!(concat(&chunks), s);
+
+        let s = "a".repeat(OUT_SHARED_THRESHOLD);
+        let chunks = split_out(&s);
+        assert_eq!(chunks.len(), 1);
+        assert!(matches!(chunks[0], OutChunk::Shared(_)));
+        assert_eq!(&*chunks[0], s);
+
+        // Escape sequences across the chunk boundary are moved to the next chunk
+        let s = format!("{}\x1b[38;5;100mb", "a".repeat(OUT_CHUNK_SIZE - 4));
+        let chunks = split_out(&s);
+        assert_eq!(&*chunks[0], "a".repeat(OUT_CHUNK_SIZE - 4));
+        assert_eq!(&*chunks[1], "\x1b[38;5;100mb");
+
+        // ... unless they are completed in the chunk
+        let s = format!("{}\x1b[1mbbbb", "a".repeat(OUT_CHUNK_SIZE - 6));
+        let chunks = split_out(&s);
+        assert_eq!(chunks[0].len(), OUT_CHUNK_SIZE);
+        assert_eq!(concat(&chunks), s);
+
+        // Multi-byte characters are not split
+        let s = format!("{}αβ", "a".repeat(OUT_CHUNK_SIZE - 1));
+        let chunks = split_out(&s);
+        assert_eq!(chunks[0].len(), OUT_CHUNK_SIZE - 1);
+        assert_eq!(concat(&chunks), s);
+
+        assert!(is_complete_escape_sequence("\x1b[1;2m"));
+        assert!(is_complete_escape_sequence("\x1bx"));
+        assert!(!is_complete_escape_sequence("\x1b[1;2"));
+        assert!(!is_complete_escape_sequence("\x1b[?10"));
+        assert!(!is_complete_escape_sequence("\x1b[?1;2 "));
+        assert!(is_complete_escape_sequence("\x1b[?1;2 q"));
+        assert!(!is_complete_escape_sequence("\x1bO"));
+        assert!(is_complete_escape_sequence("\x1bOP"));
+        assert!(!is_complete_escape_sequence("\x1b"));
+    }
+
+    static TEST_OUT: Queue<OutChunk, 8> = Queue::new();
+    static TEST_WRITERS_DONE: AtomicUsize = AtomicUsize::new(0);
+    const TEST_WRITES: usize = 20;
+
+    /// Write `TEST_WRITES` strings of alternately small and large sizes, consisting of
+    /// ascending characters from `base`.
+    fn test_writes(base: u8) -> impl Iterator<Item = String> {
+        (0..TEST_WRITES).map(move |i| {
+            let ch = char::from(base + i as u8);
+            let len = if i % 2 == 0 {
+                10
+            } else {
+                OUT_SHARED_THRESHOLD + 10
+            };
+            core::iter::repeat(ch).take(len).collect()
+        })
+    }
+
+    extern "C" fn test_writer(base: u64) -> ! {
+        for s in test_writes(base as u8) {
+            OutChunk::split(&s, |chunk| TEST_OUT.enqueue(chunk));
+        }
+        TEST_WRITERS_DONE.fetch_add(1, Ordering::SeqCst);
+        loop {
+            task::scheduler().sleep(1000);
+        }
+    }
+
+    #[test_case]
+    fn test_out_chunk_order() {
+        info!("TESTING console::test_out_chunk_order");
+        task::scheduler().add(task::Priority::L1, test_writer, b'a' as u64);
+        task::scheduler().add(task::Priority::L1, test_writer, b'A' as u64);
+
+        let mut out = String::new();
+        let (mut inline, mut shared) = (0, 0);
+        loop {
+            let done = TEST_WRITERS_DONE.load(Ordering::SeqCst) == 2;
+            match TEST_OUT.dequeue_timeout(1) {
+                Some(chunk) => {
+                    match chunk {
+                        OutChunk::Inline(_) => inline += 1,
+                        OutChunk::Shared(_) => shared += 1,
+                    }
+                    out.push_str(&chunk);
+                }
+                None if done => break,
+                None => {}
+            }
+        }
+        assert_eq!(inline, TEST_WRITES);
+        assert_eq!(shared, TEST_WRITES);
+
+        // The output of each writer is kept in order
+        let lower = out.chars().filter(|c| c.is_ascii_lowercase());
+        let upper = out.chars().filter(|c| c.is_ascii_uppercase());
+        assert!(lower.eq(test_writes(b'a').flat_map(|s| s.chars().collect::<Vec<_>>())));
+        assert!(upper.eq(test_writes(b'A').flat_map(|s| s.chars().collect::<Vec<_>>())));
     }
 }
@@ -1,12 +1,28 @@
 use super::Input;
+use crate::interrupts::TIMER_FREQ;
+use core::fmt;
 use log::trace;
 use pc_keyboard::layouts::Jis109Key;
 use pc_keyboard::{DecodedKey, HandleControl, KeyCode, KeyState, Keyboard, ScancodeSet1};
 
+const ACK: u8 = 0xfa;
+const RESEND: u8 = 0xfe;
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Event {
+    /// A key is pressed. `repeated` is true for the typematic repeats of a key that is held down.
+    Down { input: Input, repeated: bool },
+    /// A key subject to the software repeat is released.
+    Up(Input),
+}
+
 pub struct Decoder {
     inner: Keyboard<Jis109Key, ScancodeSet1>,
     lctrl: bool,
     rctrl: bool,
+    lalt: bool,
+    ralt: bool,
+    held: Option<KeyCode>,
 }
 
 impl Decoder {
@@ -15,44 +31,335 @@ impl Decoder {
             inner: Keyboard::new(Jis109Key, ScancodeSet1, HandleControl::Ignore),
             lctrl: false,
             rctrl: false,
+            lalt: false,
+            ralt: false,
+            held: None,
         }
     }
 
-    pub fn add(&mut self, byte: u8) -> Option<Input> {
-        if let Ok(Some(e)) = self.inner.add_byte(byte) {
-            if e.code == KeyCode::ControlLeft {
-                self.lctrl = e.state == KeyState::Down;
-            }
-            if e.code == KeyCode::ControlRight {
-                self.rctrl = e.state == KeyState::Down;
+    pub fn add(&mut self, byte: u8) -> Option<Event> {
+        if byte == ACK || byte == RESEND {
+            // Responses to the commands sent to the keyboard
+            return None;
+        }
+        let e = self.inner.add_byte(byte).ok()??;
+        if e.code == KeyCode::ControlLeft {
+            self.lctrl = e.state == KeyState::Down;
+        }
+        if e.code == KeyCode::ControlRight {
+            self.rctrl = e.state == KeyState::Down;
+        }
+        if e.code == KeyCode::AltLeft {
+            self.lalt = e.state == KeyState::Down;
+        }
+        if e.code == KeyCode::AltRight {
+            self.ralt = e.state == KeyState::Down;
+        }
+        let code = e.code;
+        if e.state == KeyState::Up {
+            // process_keyevent is still necessary to keep track of the modifiers
+            self.inner.process_keyevent(e);
+            if self.held == Some(code) {
+                self.held = None;
             }
-            match self.inner.process_keyevent(e)? {
-                DecodedKey::RawKey(KeyCode::Insert) => Some(Input::Insert),
-                DecodedKey::RawKey(KeyCode::Home) => Some(Input::Home),
-                DecodedKey::RawKey(KeyCode::End) => Some(Input::End),
-                DecodedKey::RawKey(KeyCode::PageUp) => Some(Input::PageUp),
-                DecodedKey::RawKey(KeyCode::PageDown) => Some(Input::PageDown),
-                DecodedKey::RawKey(KeyCode::ArrowUp) => Some(Input::ArrowUp),
-                DecodedKey::RawKey(KeyCode::ArrowDown) => Some(Input::ArrowDown),
-                DecodedKey::RawKey(KeyCode::ArrowLeft) => Some(Input::ArrowLeft),
-                DecodedKey::RawKey(KeyCode::ArrowRight) => Some(Input::ArrowRight),
-                DecodedKey::Unicode(
-                    // BS | HT | LF | DEL | printable characters
-                    c @ ('\x08' | '\x09' | '\x0a' | '\x7f' | ' '..='~'),
-                ) => {
-                    if self.lctrl || self.rctrl {
-                        Some(Input::Ctrl(c))
-                    } else {
-                        Some(Input::Char(c))
-                    }
+            return repeatable_input(code).map(Event::Up);
+        }
+        let repeated = self.held == Some(code);
+        self.held = Some(code);
+        let input = match self.inner.process_keyevent(e)? {
+            DecodedKey::RawKey(KeyCode::Insert) => Input::Insert,
+            DecodedKey::RawKey(KeyCode::Home) => Input::Home,
+            DecodedKey::RawKey(KeyCode::End) => Input::End,
+            DecodedKey::RawKey(KeyCode::PageUp) => Input::PageUp,
+            DecodedKey::RawKey(KeyCode::PageDown) => Input::PageDown,
+            DecodedKey::RawKey(KeyCode::ArrowUp) => Input::ArrowUp,
+            DecodedKey::RawKey(KeyCode::ArrowDown) => Input::ArrowDown,
+            DecodedKey::RawKey(KeyCode::ArrowLeft) => Input::ArrowLeft,
+            DecodedKey::RawKey(KeyCode::ArrowRight) => Input::ArrowRight,
+            DecodedKey::Unicode(
+                // BS | HT | LF | DEL | printable characters
+                c @ ('\x08' | '\x09' | '\x0a' | '\x7f' | ' '..='~'),
+            ) => {
+                if self.lctrl || self.rctrl {
+                    Input::Ctrl(c)
+                } else {
+                    Input::Char(c)
                 }
-                key => {
-                    trace!("kbd: Unhandled key: {:?}", key);
-                    None
+            }
+            DecodedKey::RawKey(code) => match function_key(code) {
+                Some(n) if self.lalt || self.ralt => Input::AltFunction(n),
+                Some(n) => Input::Function(n),
+                None => {
+                    trace!("kbd: Unhandled key: {:?}", code);
+                    return None;
                 }
+            },
+            key => {
+                trace!("kbd: Unhandled key: {:?}", key);
+                return None;
             }
-        } else {
-            None
+        };
+        Some(Event::Down { input, repeated })
+    }
+}
+
+/// The number of a function key, 1 for F1.
+fn function_key(code: KeyCode) -> Option<u8> {
+    Some(match code {
+        KeyCode::F1 => 1,
+        KeyCode::F2 => 2,
+        KeyCode::F3 => 3,
+        KeyCode::F4 => 4,
+        KeyCode::F5 => 5,
+        KeyCode::F6 => 6,
+        KeyCode::F7 => 7,
+        KeyCode::F8 => 8,
+        KeyCode::F9 => 9,
+        KeyCode::F10 => 10,
+        KeyCode::F11 => 11,
+        KeyCode::F12 => 12,
+        _ => None?,
+    })
+}
+
+/// The input of a key that is repeated by `Repeater` instead of the keyboard.
+fn repeatable_input(code: KeyCode) -> Option<Input> {
+    Some(match code {
+        KeyCode::ArrowUp => Input::ArrowUp,
+        KeyCode::ArrowDown => Input::ArrowDown,
+        KeyCode::ArrowLeft => Input::ArrowLeft,
+        KeyCode::ArrowRight => Input::ArrowRight,
+        KeyCode::Backspace => Input::Char('\x08'),
+        KeyCode::Delete => Input::Char('\x7f'),
+        _ => None?,
+    })
+}
+
+fn is_repeatable(input: Input) -> bool {
+    matches!(
+        input,
+        Input::ArrowUp
+            | Input::ArrowDown
+            | Input::ArrowLeft
+            | Input::ArrowRight
+            | Input::Char('\x08')
+            | Input::Char('\x7f')
+    )
+}
+
+/// Typematic delay and rate of the keyboard, encoded as the argument of the PS/2 command 0xF3.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct Typematic(u8);
+
+impl Typematic {
+    /// 500ms, 10.9cps. This is the default of PS/2 keyboards.
+    pub const DEFAULT: Self = Self(0x2b);
+
+    pub const DELAYS_MS: [usize; 4] = [250, 500, 750, 1000];
+
+    pub fn from_code(code: u8) -> Self {
+        Self(code & 0x7f)
+    }
+
+    /// The delay is rounded to one of `DELAYS_MS`, and the rate is rounded to the nearest rate
+    /// supported by the keyboard, which is between 2.0 and 30.0 characters per second.
+    pub fn new(delay_ms: usize, rate_cps: usize) -> Option<Self> {
+        if !(2..=30).contains(&rate_cps) {
+            return None;
         }
+        let delay = Self::DELAYS_MS.iter().position(|d| *d == delay_ms)? as u8;
+        let period_us = 1000000 / rate_cps;
+        let rate = (0..32)
+            .min_by_key(|r| Self(*r).period_us().abs_diff(period_us))
+            .unwrap();
+        Some(Self(delay << 5 | rate))
+    }
+
+    pub const fn code(self) -> u8 {
+        self.0
+    }
+
+    pub fn delay_ms(self) -> usize {
+        Self::DELAYS_MS[(self.0 >> 5 & 0x3) as usize]
+    }
+
+    /// Interval between repeats, as (8 + A) * 2^B * 4.17ms.
+    pub fn period_us(self) -> usize {
+        let a = (self.0 & 0x7) as usize;
+        let b = (self.0 >> 3 & 0x3) as usize;
+        (8 + a) * (1 << b) * 4170
+    }
+
+    pub fn delay_ticks(self) -> usize {
+        (self.delay_ms() * TIMER_FREQ / 1000).max(1)
+    }
+
+    pub fn interval_ticks(self) -> usize {
+        (self.period_us() * TIMER_FREQ / 1000000).max(1)
+    }
+}
+
+impl fmt::Display for Typematic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let rate = 10000000 / self.period_us();
+        write!(
+            f,
+            "delay={}ms rate={}.{}cps",
+            self.delay_ms(),
+            rate / 10,
+            rate % 10
+        )
+    }
+}
+
+/// Software key repeat for the keys that `is_repeatable`, driven by the timer ticks.
+/// Hardware repeats are unreliable over virtual keyboards, so they are suppressed for these keys.
+#[derive(Debug)]
+pub struct Repeater {
+    held: Option<(Input, usize)>,
+}
+
+impl Repeater {
+    pub fn new() -> Self {
+        Self { held: None }
+    }
+
+    /// Returns whether the pressed key should be delivered.
+    pub fn press(&mut self, input: Input, repeated: bool, now: usize, t: Typematic) -> bool {
+        if repeated && matches!(self.held, Some((i, _)) if i == input) {
+            return false;
+        }
+        self.held = is_repeatable(input).then(|| (input, now + t.delay_ticks()));
+        true
+    }
+
+    pub fn release(&mut self, input: Input) {
+        if matches!(self.held, Some((i, _)) if i == input) {
+            self.held = None;
+        }
+    }
+
+    /// The tick at which the next repeat is due.
+    pub fn deadline(&self) -> Option<usize> {
+        self.held.map(|(_, next)| next)
+    }
+
+    /// Returns the held input if a repeat is due at `now`. Repeats missed while the caller was
+    /// busy are collapsed into one.
+    pub fn poll(&mut self, now: usize, t: Typematic) -> Option<Input> {
+        let (input, next) = self.held.as_mut()?;
+        if now < *next {
+            return None;
+        }
+        *next = now + t.interval_ticks();
+        Some(*input)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::format;
+    use log::info;
+
+    #[test_case]
+    fn test_typematic() {
+        info!("TESTING console::kbd::test_typematic");
+        assert_eq!(Typematic::DEFAULT.delay_ms(), 500);
+        assert_eq!(Typematic::DEFAULT.period_us(), 91740);
+        assert_eq!(Typematic::new(500, 11), Some(Typematic::DEFAULT));
+        assert_eq!(Typematic::new(250, 30).map(|t| t.code()), Some(0x00));
+        assert_eq!(Typematic::new(1000, 2).map(|t| t.code()), Some(0x7f));
+        assert_eq!(Typematic::new(300, 10), None);
+        assert_eq!(Typematic::new(500, 31), None);
+        assert_eq!(Typematic::new(500, 1), None);
+        assert_eq!(Typematic::from_code(0xff).code(), 0x7f);
+        assert_eq!(
+            format!("{}", Typematic::DEFAULT),
+            "delay=500ms rate=10.9cps"
+        );
+        assert_eq!(Typematic::new(250, 30).unwrap().interval_ticks(), 8);
+    }
+
+    #[test_case]
+    fn test_function_keys() {
+        info!("TESTING console::kbd::test_function_keys");
+        let scancodes = [
+            0x3b, 0x3c, 0x3d, 0x3e, 0x3f, 0x40, 0x41, 0x42, 0x43, 0x44, 0x57, 0x58,
+        ];
+        let mut d = Decoder::new();
+        let down = |input| {
+            Some(Event::Down {
+                input,
+                repeated: false,
+            })
+        };
+        for (i, code) in scancodes.into_iter().enumerate() {
+            let n = i as u8 + 1;
+            assert_eq!(d.add(code), down(Input::Function(n)), "F{}", n);
+            assert_eq!(d.add(code | 0x80), None);
+        }
+
+        // Alt+Fn by either of the Alt keys
+        for alt in [&[0x38][..], &[0xe0, 0x38][..]] {
+            for b in alt {
+                assert_eq!(d.add(*b), None);
+            }
+            for (i, code) in scancodes.into_iter().enumerate() {
+                assert_eq!(d.add(code), down(Input::AltFunction(i as u8 + 1)));
+                assert_eq!(d.add(code | 0x80), None);
+            }
+            for b in &alt[..alt.len() - 1] {
+                assert_eq!(d.add(*b), None);
+            }
+            assert_eq!(d.add(alt[alt.len() - 1] | 0x80), None);
+            assert_eq!(d.add(0x3b), down(Input::Function(1)));
+            assert_eq!(d.add(0xbb), None);
+        }
+    }
+
+    #[test_case]
+    fn test_repeater() {
+        info!("TESTING console::kbd::test_repeater");
+        let t = Typematic::new(500, 25).unwrap();
+        let (delay, interval) = (t.delay_ticks(), t.interval_ticks());
+        let mut r = Repeater::new();
+        assert_eq!(r.deadline(), None);
+        assert_eq!(r.poll(1000, t), None);
+
+        assert!(r.press(Input::ArrowLeft, false, 100, t));
+        assert_eq!(r.deadline(), Some(100 + delay));
+        assert_eq!(r.poll(100 + delay - 1, t), None);
+        assert_eq!(r.poll(100 + delay, t), Some(Input::ArrowLeft));
+        assert_eq!(r.poll(100 + delay, t), None);
+        assert_eq!(r.deadline(), Some(100 + delay + interval));
+
+        // Hardware repeats of the held key are suppressed
+        assert!(!r.press(Input::ArrowLeft, true, 100 + delay + 1, t));
+        assert_eq!(r.deadline(), Some(100 + delay + interval));
+
+        // Missed repeats are collapsed
+        let late = 100 + delay + 10 * interval;
+        assert_eq!(r.poll(late, t), Some(Input::ArrowLeft));
+        assert_eq!(r.poll(late, t), None);
+
+        // Key-up cancels the repeat, but only of the same key
+        r.release(Input::ArrowRight);
+        assert!(r.deadline().is_some());
+        r.release(Input::ArrowLeft);
+        assert_eq!(r.deadline(), None);
+        assert_eq!(r.poll(late + 1000, t), None);
+
+        // A different key cancels the repeat
+        assert!(r.press(Input::Char('\x08'), false, 2000, t));
+        assert!(r.press(Input::Char('a'), false, 2001, t));
+        assert_eq!(r.deadline(), None);
+        assert_eq!(r.poll(3000, t), None);
+
+        // Other keys are left to the hardware repeat
+        assert!(r.press(Input::Char('a'), true, 3001, t));
+        assert!(r.press(Input::ArrowUp, false, 4000, t));
+        assert!(r.press(Input::ArrowDown, true, 4001, t));
+        assert_eq!(r.deadline(), Some(4001 + delay));
     }
 }
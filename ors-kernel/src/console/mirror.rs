@@ -0,0 +1,121 @@
+//! The copy of the console output sent to the serial port while the screen is also in use.
+//!
+//! The screen-oriented escape sequences, such as cursor movements and erasures by the line editor
+//! of the shell, make no sense in the serial log, so they are stripped from the copy. Colors are
+//! kept since host terminals render them.
+
+use super::ansi::{self, DecodeResult, EscapeSequence};
+use core::fmt;
+
+/// The maximum length of an escape sequence kept by `Filter`. Longer sequences are dropped, since
+/// they are not SGR sequences that the decoder supports.
+const MAX_SEQUENCE_LEN: usize = 32;
+
+/// This does not allocate, since the copy is written from the start of the boot.
+#[derive(Debug)]
+pub struct Filter {
+    decoder: ansi::Decoder,
+    /// The characters of the escape sequence being decoded.
+    pending: heapless::String<MAX_SEQUENCE_LEN>,
+    overflowed: bool,
+}
+
+impl Filter {
+    pub const fn new() -> Self {
+        Self {
+            decoder: ansi::Decoder::new(),
+            pending: heapless::String::new(),
+            overflowed: false,
+        }
+    }
+
+    /// Write the text and the SGR sequences of `s` to `out`. Escape sequences across calls are
+    /// handled by the internal state.
+    pub fn filter(&mut self, s: &str, out: &mut impl fmt::Write) -> fmt::Result {
+        for ch in s.chars() {
+            // ESC always starts a new sequence, aborting the unfinished one
+            if ch == '\x1b' {
+                self.clear();
+            }
+            self.overflowed |= self.pending.push(ch).is_err();
+            match self.decoder.add_char(ch) {
+                Some(DecodeResult::Just(ch)) => out.write_char(ch)?,
+                Some(DecodeResult::EscapeSequence(es)) if is_sgr(es) && !self.overflowed => {
+                    out.write_str(&self.pending)?
+                }
+                Some(DecodeResult::EscapeSequence(_)) => {}
+                None if !self.decoder.is_idle() => continue,
+                // Characters unsupported by the decoder, such as CR
+                None => {}
+            }
+            self.clear();
+        }
+        Ok(())
+    }
+
+    fn clear(&mut self) {
+        self.pending.clear();
+        self.overflowed = false;
+    }
+}
+
+fn is_sgr(es: EscapeSequence) -> bool {
+    matches!(
+        es,
+        EscapeSequence::Sgr(_) | EscapeSequence::Sgr2(_, _) | EscapeSequence::Sgr3(_, _, _)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::String;
+    use log::info;
+
+    fn filter_all(chunks: &[&str]) -> String {
+        let mut filter = Filter::new();
+        let mut out = String::new();
+        for s in chunks {
+            filter.filter(s, &mut out).unwrap();
+        }
+        out
+    }
+
+    #[test_case]
+    fn test_filter() {
+        info!("TESTING console::mirror::test_filter");
+        assert_eq!(filter_all(&["abc\n"]), "abc\n");
+        assert_eq!(
+            filter_all(&["\x1b[31mred\x1b[0m \x1b[1;4mb\x1b[38;5;100mc\x1b[m\n"]),
+            "\x1b[31mred\x1b[0m \x1b[1;4mb\x1b[38;5;100mc\x1b[m\n"
+        );
+        // Cursor movements and erasures are stripped
+        assert_eq!(
+            filter_all(&["\x1b[2K\x1b[1Gab\x1b[D\x1b[3;4Hc\x1b[J\x1b[?1049hd\x1b[?1049l"]),
+            "abcd"
+        );
+        assert_eq!(
+            filter_all(&["> ls\x1b[2K", "\x1b[G\x1b[7m \x1b[27m\x1b[2K\x1b[1G> ls\n"]),
+            "> ls\x1b[7m \x1b[27m> ls\n"
+        );
+
+        // Sequences split across writes
+        assert_eq!(
+            filter_all(&["a\x1b", "[3", "2mb\x1b[", "5", "A"]),
+            "a\x1b[32mb"
+        );
+        // Unsupported sequences are dropped up to the character that ends them, which is taken
+        // as text as on the screen
+        assert_eq!(
+            filter_all(&["a\x1b[99Zb\rc\x1b[1\x1b[33md"]),
+            "aZbc\x1b[33md"
+        );
+        assert_eq!(filter_all(&["a\x1bxb"]), "axb");
+        // Too long sequences are dropped even if they are decoded
+        assert_eq!(filter_all(&["a\x1b[", &"0".repeat(32), "1mb"]), "ab");
+        assert_eq!(
+            filter_all(&["a\x1b[", &"0".repeat(28), "1mb"]),
+            "a\x1b[00000000000000000000000000001mb"
+        );
+    }
+}
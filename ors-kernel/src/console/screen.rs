@@ -1,126 +1,115 @@
-use super::ansi::{Color, ColorScheme, EscapeSequence, Sgr};
-use crate::graphics::{FontStyle, FrameBuffer, MonospaceFont, MonospaceTextBuffer};
+use super::ansi::{Color, ColorScheme};
+use super::Input;
+use crate::graphics::{
+    CacheStats, FrameBuffer, FrameBufferExt, FrameBufferFormat, MonospaceFont, MonospaceTextBuffer,
+    Rect,
+};
+use ors_common::term::{Backend, Terminal};
 
-const FONT_SIZE: u32 = 14;
+pub const FONT_SIZE: u32 = 14;
 static FONT_NORMAL: &[u8] = include_bytes!("Tamzen7x14r.ttf");
 static FONT_BOLD: &[u8] = include_bytes!("Tamzen7x14b.ttf");
 
+/// The font embedded in the kernel image, which is used unless another font is loaded.
+pub fn default_font(format: FrameBufferFormat) -> MonospaceFont<'static> {
+    MonospaceFont::new(FONT_SIZE, FONT_NORMAL, FONT_BOLD, format)
+}
+
+/// Replies of the terminal are typed into the console input.
+#[derive(Debug)]
+pub struct InputReply;
+
+impl Backend for InputReply {
+    fn reply(&mut self, s: &str) {
+        for ch in s.chars() {
+            let _ = super::IN.try_enqueue((Input::Char(ch), false));
+        }
+    }
+}
+
+/// The terminal shown on the frame buffer `T` in the theme `S`.
 pub struct Screen<'a, T, S> {
+    terminal: Terminal<S, InputReply>,
     buf: MonospaceTextBuffer<'a, T>,
-    theme: S,
-    fg: Color,
-    bg: Color,
-    font_style: FontStyle,
 }
 
 impl<'a, T: FrameBuffer, S: ColorScheme> Screen<'a, T, S> {
     pub fn new(buf: T, theme: S) -> Self {
         let format = buf.format();
-        Self {
-            buf: MonospaceTextBuffer::new(
-                buf,
-                MonospaceFont::new(FONT_SIZE, FONT_NORMAL, FONT_BOLD, format),
-            ),
-            theme,
-            fg: Color::Default,
-            bg: Color::Default,
-            font_style: FontStyle::Normal,
-        }
+        let buf = MonospaceTextBuffer::new(buf, default_font(format));
+        let (columns, lines) = buf.size();
+        let mut screen = Self {
+            terminal: Terminal::new(columns, lines, theme, InputReply),
+            buf,
+        };
+        screen.prepare_font();
+        screen
     }
 
-    pub fn render(&mut self) {
-        self.buf.render();
+    /// Key the glyph cache by the theme colors and rasterize printable ASCII characters in the
+    /// default colors in advance.
+    fn prepare_font(&mut self) {
+        let palette = self
+            .terminal
+            .theme()
+            .palette()
+            .map(crate::graphics::Color::from);
+        let font = self.buf.font_mut();
+        font.set_palette(&palette);
+        font.prewarm(' '..='~', palette[0], palette[1]);
     }
 
-    pub fn put_char(&mut self, ch: char) {
-        self.buf.put(
-            ch,
-            self.theme.get_fg(self.fg).into(),
-            self.theme.get_bg(self.bg).into(),
-            self.font_style,
-        );
+    /// Replace the font. The characters on the screen are kept as far as they fit in the new size.
+    /// The whole screen is cleared, and returned as the rectangle drawn.
+    pub fn set_font(&mut self, font: MonospaceFont<'a>) -> Option<Rect> {
+        let bg = self.terminal.theme().get_bg(Color::Default);
+        self.buf.set_font(font, bg.into());
+        let (columns, lines) = self.buf.size();
+        self.terminal.resize(columns, lines);
+        self.prepare_font();
+        Some(self.buf.buf_mut().rect())
     }
 
-    pub fn erase(
-        &mut self,
-        before_cursor_lines: bool,
-        before_cursor_chars: bool,
-        after_cursor_chars: bool,
-        after_cursor_lines: bool,
-    ) {
-        self.buf.erase(
-            self.theme.get_bg(self.bg).into(),
-            before_cursor_lines,
-            before_cursor_chars,
-            after_cursor_chars,
-            after_cursor_lines,
-        );
+    pub fn font_stats(&self) -> CacheStats {
+        self.buf.font().cache_stats()
     }
 
-    pub fn handle_escape_sequence(&mut self, es: EscapeSequence) {
-        use EscapeSequence::*;
-
-        match es {
-            CursorUp(n) => self.buf.move_cursor(0, -(n as i32)),
-            CursorDown(n) => self.buf.move_cursor(0, n as i32),
-            CursorForward(n) => self.buf.move_cursor(n as i32, 0),
-            CursorBack(n) => self.buf.move_cursor(-(n as i32), 0),
-            CursorNextLine(n) => self.buf.move_cursor(i32::MIN, n as i32),
-            CursorPreviousLine(n) => self.buf.move_cursor(i32::MIN, -(n as i32)),
-            CursorHorizontalAbsolute(n) => self.buf.set_cursor(Some(n - 1), None),
-            CursorPosition(n, m) => self.buf.set_cursor(Some(m - 1), Some(n - 1)),
-            EraseInDisplay(0) => self.erase(false, false, true, true),
-            EraseInDisplay(1) => self.erase(true, true, false, false),
-            EraseInDisplay(2) => self.erase(true, true, true, true),
-            EraseInLine(0) => self.erase(false, false, true, false),
-            EraseInLine(1) => self.erase(false, true, false, false),
-            EraseInLine(2) => self.erase(false, true, true, false),
-            HorizontalVerticalPosition(n, m) => self.buf.set_cursor(Some(m - 1), Some(n - 1)),
-            Sgr(a) => self.handle_sgr(a),
-            Sgr2(a, b) => {
-                self.handle_sgr(a);
-                self.handle_sgr(b);
-            }
-            Sgr3(a, b, c) => {
-                self.handle_sgr(a);
-                self.handle_sgr(b);
-                self.handle_sgr(c);
-            }
-            _ => {}
-        }
+    pub fn theme(&self) -> &S {
+        self.terminal.theme()
     }
 
-    pub fn handle_sgr(&mut self, sgr: Sgr) {
-        use Sgr::*;
-
-        match sgr {
-            Reset => {
-                self.fg = Color::Default;
-                self.bg = Color::Default;
-                self.font_style = FontStyle::Normal;
-            }
-            Bold => {
-                self.font_style = FontStyle::Bold;
-                self.fg = self.fg.brighter();
-            }
-            Faint | ResetBoldFaint => {
-                self.font_style = FontStyle::Normal;
-                self.fg = self.fg.dimmer();
-            }
-            Italic(_) => {}        // Unsupported
-            Underline(_) => {}     // Unsupported
-            Blinking(_) => {}      // Unsupported
-            Inverse(_) => {}       // Unsupported
-            Hidden(_) => {}        // Unsupported
-            Strikethrough(_) => {} // Unsupported
-            Fg(color) => {
-                self.fg = if self.font_style.is_bold() {
-                    color.brighter()
-                } else {
-                    color.dimmer()
-                }
-            }
-            Bg(color) => self.bg = color,
-        }
+    /// Change the theme. The screen is cleared since the colors of drawn characters are fixed.
+    pub fn set_theme(&mut self, theme: S) {
+        self.terminal.set_theme(theme);
+        self.prepare_font();
+    }
+
+    /// Get the number of columns and lines.
+    pub fn size(&self) -> (usize, usize) {
+        self.terminal.size()
+    }
+
+    /// Render the changed part of the screen, returning the rectangle rendered.
+    pub fn render(&mut self) -> Option<Rect> {
+        self.buf.render(self.terminal.grid_mut())
+    }
+
+    /// Draw `image` at the center of the screen under the text, returning the rectangle drawn.
+    /// The image stays until it is overwritten by the text.
+    pub fn draw_centered(&mut self, image: &impl FrameBuffer) -> Option<Rect> {
+        let buf = self.buf.buf_mut();
+        let x = (buf.width() as i32 - image.width() as i32) / 2;
+        let y = (buf.height() as i32 - image.height() as i32) / 2;
+        buf.blit(x, y, image);
+        Some(image.rect().offset(x, y))
+    }
+
+    pub fn put_str(&mut self, s: &str) {
+        self.terminal.put_str(s);
+    }
+
+    /// Discard the escape sequence being decoded, since the panic may be caused by its state.
+    pub fn reset_decoder(&mut self) {
+        self.terminal.reset_decoder();
     }
 }
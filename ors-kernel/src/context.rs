@@ -91,6 +91,11 @@ impl Context {
         self.saved.store(false, Ordering::SeqCst);
     }
 
+    /// Whether the context has been saved.
+    pub fn is_saved(&self) -> bool {
+        self.saved.load(Ordering::SeqCst)
+    }
+
     /// Wait until the context has been saved.
     pub fn wait_saved(&self) {
         while !self.saved.load(Ordering::Relaxed) {
@@ -7,6 +7,10 @@ use crate::x64;
 use ors_common::non_contiguous::Array;
 use spin::{Mutex, Once};
 
+/// The maximum number of CPUs including the boot strap processor.
+pub const MAX_COUNT: usize = 1 + MAX_APPLICATION_PROCESSORS;
+const MAX_APPLICATION_PROCESSORS: usize = 64;
+
 static SYSTEM_INFO: Once<SystemInfo> = Once::new();
 static BOOT_STRAP_CPU_STATE: Mutex<CpuState> = Mutex::new(CpuState::new());
 
@@ -14,7 +18,7 @@ static BOOT_STRAP_CPU_STATE: Mutex<CpuState> = Mutex::new(CpuState::new());
 struct SystemInfo {
     lapic: x64::LApic,
     boot_strap_lapic_id: u32,
-    application_cpu_state: Array<u32, Mutex<CpuState>, 64>,
+    application_cpu_state: Array<u32, Mutex<CpuState>, MAX_APPLICATION_PROCESSORS>,
 }
 
 pub fn initialize() {
@@ -73,6 +77,24 @@ impl Cpu {
             .map(|kind| Self(kind))
     }
 
+    /// The position of this CPU in `Cpu::list()`. The boot strap processor is always 0.
+    pub fn index(self) -> usize {
+        match self.0 {
+            CpuKind::BootStrap(_) => 0,
+            CpuKind::Application(lapic_id) => {
+                let info = SYSTEM_INFO
+                    .get()
+                    .expect("Non-BSP CPU found before cpu::initialize");
+                let i = info
+                    .application_cpu_state
+                    .iter()
+                    .position(|(id, _)| *id == lapic_id)
+                    .expect("Unknown CPU");
+                1 + i
+            }
+        }
+    }
+
     pub fn lapic_id(self) -> Option<u32> {
         match self.0 {
             CpuKind::BootStrap(Some(lapic_id)) => Some(lapic_id),
@@ -118,6 +140,7 @@ impl CpuState {
 pub struct CpuThreadState {
     pub ncli: u32,  // Depth of pushcli (processing with interrupts disabled) nesting
     pub zcli: bool, // Were interrupts disabled before pushcli?
+    pub nint: u32,  // Depth of interrupt handlers being processed, see `interrupts::InterruptLevel`
 }
 
 impl CpuThreadState {
@@ -125,6 +148,7 @@ impl CpuThreadState {
         Self {
             ncli: 0,
             zcli: false, // interrupts are enabled by default
+            nint: 0,
         }
     }
 }
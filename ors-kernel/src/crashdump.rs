@@ -0,0 +1,233 @@
+//! Crash dump written to a fixed sector range of the first VirtIO block on kernel panic.
+//!
+//! The dump area is located after the FAT file system of the disk image
+//! (see qemu/make_image.sh), and currently holds the state of the frame manager.
+//! If the boot volume has `DUMP_FILE` preallocated (e.g. by `fallocate /ors.crashdump 8M`), the
+//! sectors of the file are used as the dump area instead, so that the dump can be copied out of
+//! the image as a file. The file is held open while the kernel is running, so that it cannot be
+//! removed while its sectors are used as the dump area.
+
+use crate::devices::virtio::block::{self, Block, IoPriority};
+use crate::fs::fat;
+use crate::fs::mount;
+use crate::fs::volume::Sector;
+use crate::phys_memory::{try_frame_manager, BitmapFrameManager, DumpWrite};
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use log::{info, warn};
+use spin::Once;
+
+/// The first sector of the dump area (= 200MiB).
+pub const DUMP_START_SECTOR: u64 = 200 * 1024 * 1024 / Block::SECTOR_SIZE as u64;
+/// The number of sectors of the dump area (= 8MiB).
+pub const DUMP_SECTOR_COUNT: u64 = 8 * 1024 * 1024 / Block::SECTOR_SIZE as u64;
+
+/// The file in the boot volume used as the dump area if it exists.
+pub const DUMP_FILE: &str = "/ors.crashdump";
+
+const FIXED_AREA: [(u64, u64); 1] = [(DUMP_START_SECTOR, DUMP_SECTOR_COUNT)];
+
+/// The dump area as runs of (first sector, number of sectors) of the first VirtIO block.
+static AREA: Once<Vec<(u64, u64)>> = Once::new();
+
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy)]
+pub enum Error {
+    NoBlock,
+    Busy,
+    Io(block::Error),
+    InvalidDump,
+    TooLarge,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoBlock => write!(f, "No block device"),
+            Self::Busy => write!(f, "Frame manager is locked"),
+            Self::Io(e) => write!(f, "I/O error: {:?}", e),
+            Self::InvalidDump => write!(f, "No valid dump"),
+            Self::TooLarge => write!(f, "Dump does not fit in the dump area"),
+        }
+    }
+}
+
+/// Locate the dump area. The sectors of `DUMP_FILE` are derived again on every boot, since they
+/// are no longer valid once the file is truncated or removed. This must be called after the boot
+/// volume is mounted, otherwise the fixed sector range is used.
+pub fn initialize() {
+    AREA.call_once(|| match file_area() {
+        Ok(Some(area)) => {
+            info!(
+                "crashdump: {} ({} runs) is the dump area",
+                DUMP_FILE,
+                area.len()
+            );
+            area
+        }
+        Ok(None) => FIXED_AREA.to_vec(),
+        Err(e) => {
+            warn!("crashdump: Failed to locate {}: {}", DUMP_FILE, e);
+            FIXED_AREA.to_vec()
+        }
+    });
+}
+
+/// The sectors of `DUMP_FILE` up to its size, if it exists in the boot volume. The file is held
+/// open once it is used as the dump area, see `fat::File::hold_open`.
+fn file_area() -> Result<Option<Vec<(u64, u64)>>, fat::Error> {
+    let (m, path) = match mount::resolve(DUMP_FILE) {
+        // The dump is written to the first block, which is entirely the boot volume
+        Some((m, path)) if m.device == 0 => (m, path),
+        _ => return Ok(None),
+    };
+    let file = match m.fs.open(&path) {
+        Ok(file) if !file.is_dir() => file,
+        Ok(_) | Err(fat::Error::NotFound(_)) => return Ok(None),
+        Err(e) => Err(e)?,
+    };
+    let mut rest = Sector::count_for_bytes(file.file_size(), Block::SECTOR_SIZE)?.get();
+    let mut area = Vec::new();
+    for (sector, count) in file.cluster_runs()? {
+        if rest == 0 {
+            break;
+        }
+        let count = count.min(rest);
+        area.push((sector.index() as u64, count as u64));
+        rest -= count;
+    }
+    if area.is_empty() {
+        return Ok(None);
+    }
+    file.hold_open();
+    Ok(Some(area))
+}
+
+fn area() -> &'static [(u64, u64)] {
+    AREA.get().map_or(&FIXED_AREA, |area| area.as_slice())
+}
+
+/// The first sector of the dump area.
+pub fn start_sector() -> u64 {
+    area()[0].0
+}
+
+fn capacity(area: &[(u64, u64)]) -> usize {
+    area.iter().map(|(_, count)| *count as usize).sum::<usize>() * Block::SECTOR_SIZE
+}
+
+/// The sector of the block at `index` of the dump area.
+fn locate(area: &[(u64, u64)], mut index: u64) -> Option<u64> {
+    for (start, count) in area {
+        if index < *count {
+            return Some(start + index);
+        }
+        index -= count;
+    }
+    None
+}
+
+/// Write a crash dump. This is called from the panic handler and never blocks or allocates.
+pub fn write() -> Result<(), Error> {
+    let block = block::try_list()
+        .and_then(|blocks| blocks.get(0))
+        .ok_or(Error::NoBlock)?;
+    let fm = try_frame_manager().ok_or(Error::Busy)?;
+    let area = area();
+    if capacity(area) < fm.dump_size() {
+        Err(Error::TooLarge)?;
+    }
+    let mut w = SectorWriter {
+        block,
+        area,
+        index: 0,
+        buf: [0; Block::SECTOR_SIZE],
+        len: 0,
+        error: None,
+    };
+    let result = fm.write_dump(&mut w).and_then(|_| w.flush());
+    match (result, w.error) {
+        (Ok(()), _) => Ok(()),
+        (Err(_), Some(e)) => Err(Error::Io(e)),
+        (Err(_), None) => Err(Error::Io(block::Error::Unknown)),
+    }
+}
+
+/// Read the frame manager state from the crash dump.
+pub fn read_frame_manager() -> Result<Box<BitmapFrameManager>, Error> {
+    let block = block::try_list()
+        .and_then(|blocks| blocks.get(0))
+        .ok_or(Error::NoBlock)?;
+    let area = area();
+    let mut header = [0; Block::SECTOR_SIZE];
+    block
+        .read(area[0].0, &mut header, IoPriority::Normal)
+        .map_err(Error::Io)?;
+    let size =
+        BitmapFrameManager::dump_size_from_header(&header).map_err(|_| Error::InvalidDump)?;
+    if capacity(area) < size {
+        Err(Error::InvalidDump)?;
+    }
+    let num_sectors = (size + Block::SECTOR_SIZE - 1) / Block::SECTOR_SIZE;
+    let mut data = vec![0; num_sectors * Block::SECTOR_SIZE];
+    let mut rest = &mut data[..];
+    for (start, count) in area {
+        if rest.is_empty() {
+            break;
+        }
+        let len = rest.len().min(*count as usize * Block::SECTOR_SIZE);
+        let (buf, tail) = rest.split_at_mut(len);
+        block
+            .read(*start, buf, IoPriority::Normal)
+            .map_err(Error::Io)?;
+        rest = tail;
+    }
+    BitmapFrameManager::read_dump(&data).map_err(|_| Error::InvalidDump)
+}
+
+/// Write the dump sector by sector without heap allocation.
+struct SectorWriter {
+    block: &'static Block,
+    area: &'static [(u64, u64)],
+    /// The index of the sector to be written in the dump area.
+    index: u64,
+    buf: [u8; Block::SECTOR_SIZE],
+    len: usize,
+    error: Option<block::Error>,
+}
+
+impl SectorWriter {
+    fn flush(&mut self) -> Result<(), fmt::Error> {
+        if self.len == 0 {
+            return Ok(());
+        }
+        self.buf[self.len..].fill(0);
+        let result = match locate(self.area, self.index) {
+            Some(sector) => unsafe { self.block.write_polling(sector, &self.buf) },
+            None => Err(block::Error::OutOfRange),
+        };
+        if let Err(e) = result {
+            self.error = Some(e);
+            return Err(fmt::Error);
+        }
+        self.index += 1;
+        self.len = 0;
+        Ok(())
+    }
+}
+
+impl DumpWrite for SectorWriter {
+    fn write(&mut self, mut buf: &[u8]) -> Result<(), fmt::Error> {
+        while !buf.is_empty() {
+            let n = buf.len().min(self.buf.len() - self.len);
+            self.buf[self.len..self.len + n].copy_from_slice(&buf[0..n]);
+            self.len += n;
+            buf = &buf[n..];
+            if self.len == self.buf.len() {
+                self.flush()?;
+            }
+        }
+        Ok(())
+    }
+}
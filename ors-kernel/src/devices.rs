@@ -1,4 +1,6 @@
+pub mod fw_cfg;
 pub mod pci;
+pub mod ps2;
 pub mod qemu;
 pub mod serial;
 pub mod virtio;
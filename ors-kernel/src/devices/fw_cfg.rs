@@ -0,0 +1,326 @@
+//! QEMU firmware configuration device (fw_cfg), through which the host passes named files to the
+//! guest, such as `-fw_cfg name=opt/ors/cmdline,string=memtest=quick`.
+//!
+//! Only the port-based interface is used: an item is selected by writing its key to the selector
+//! port, and its content is read from the data port byte by byte. The read position is a state of
+//! the device, so every selection and the reads following it are done while `FW_CFG` is locked.
+//! Nothing here allocates until the content of a file is read into a `Vec`, so that the boot
+//! parameters can be read before the heap is ready.
+
+use crate::sync::spin::Spin;
+use crate::x64;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use core::ops::ControlFlow;
+use core::str;
+use log::warn;
+use spin::Once;
+
+const SELECTOR_PORT: u16 = 0x510;
+const DATA_PORT: u16 = 0x511;
+
+const SELECT_SIGNATURE: u16 = 0x0000;
+const SELECT_FILE_DIR: u16 = 0x0019;
+const SIGNATURE: [u8; 4] = *b"QEMU";
+
+/// Size of an entry of the file directory: size (u32), select (u16), reserved (u16), name.
+const FILE_ENTRY_SIZE: usize = 64;
+/// Maximum length of a file name, including the terminating NUL.
+pub const NAME_LEN: usize = 56;
+/// The file directory with more files than this is considered to be broken.
+const MAX_FILES: u32 = 4096;
+
+/// The file of the boot parameters used when the loader passes none, see `command_line`.
+pub const CMDLINE_FILE: &str = "opt/ors/cmdline";
+const CMDLINE_LEN: usize = 1024;
+
+static FW_CFG: Spin<FwCfg> = Spin::new(FwCfg::new());
+static PRESENT: Once<bool> = Once::new();
+static CMDLINE: Once<Option<heapless::String<CMDLINE_LEN>>> = Once::new();
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Error {
+    /// The file is larger than the limit given by the caller.
+    TooLarge(usize),
+    NotUtf8,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TooLarge(size) => write!(f, "File too large: {} bytes", size),
+            Self::NotUtf8 => write!(f, "File is not UTF-8"),
+        }
+    }
+}
+
+struct FwCfg {
+    selector: x64::Port<u16>,
+    data: x64::Port<u8>,
+}
+
+impl FwCfg {
+    const fn new() -> Self {
+        Self {
+            selector: x64::Port::new(SELECTOR_PORT),
+            data: x64::Port::new(DATA_PORT),
+        }
+    }
+
+    fn select(&mut self, key: u16) {
+        unsafe { self.selector.write(key) };
+    }
+
+    fn read(&mut self, buf: &mut [u8]) {
+        for b in buf.iter_mut() {
+            *b = unsafe { self.data.read() };
+        }
+    }
+}
+
+/// Whether the device exists. On machines without it, the data port reads as `0xff`, which never
+/// matches the signature.
+pub fn is_present() -> bool {
+    *PRESENT.call_once(|| {
+        let mut fw_cfg = FW_CFG.lock();
+        fw_cfg.select(SELECT_SIGNATURE);
+        let mut signature = [0; 4];
+        fw_cfg.read(&mut signature);
+        signature == SIGNATURE
+    })
+}
+
+/// An entry of the file directory.
+#[derive(PartialEq, Eq, Clone, Copy)]
+pub struct FwCfgFile {
+    name: [u8; NAME_LEN],
+    name_len: usize,
+    size: u32,
+    select: u16,
+}
+
+impl FwCfgFile {
+    /// Parse an entry of the file directory, whose integers are big-endian. Entries without a
+    /// NUL-terminated UTF-8 name are rejected.
+    fn parse(entry: &[u8; FILE_ENTRY_SIZE]) -> Option<Self> {
+        let size = u32::from_be_bytes(entry[0..4].try_into().unwrap());
+        let select = u16::from_be_bytes(entry[4..6].try_into().unwrap());
+        let mut name = [0; NAME_LEN];
+        name.copy_from_slice(&entry[8..]);
+        let name_len = name.iter().position(|b| *b == 0)?;
+        if name_len == 0 || str::from_utf8(&name[..name_len]).is_err() {
+            return None;
+        }
+        Some(Self {
+            name,
+            name_len,
+            size,
+            select,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        // Checked by `parse`
+        str::from_utf8(&self.name[..self.name_len]).unwrap()
+    }
+
+    pub fn size(&self) -> usize {
+        self.size as usize
+    }
+
+    /// Read the first `buf.len()` bytes of the file at most. Returns the number of bytes read.
+    pub fn read(&self, buf: &mut [u8]) -> usize {
+        let len = buf.len().min(self.size());
+        let mut fw_cfg = FW_CFG.lock();
+        fw_cfg.select(self.select);
+        fw_cfg.read(&mut buf[..len]);
+        len
+    }
+
+    /// Read the whole file. The size is checked against `max` before the buffer is allocated.
+    pub fn read_to_vec(&self, max: usize) -> Result<Vec<u8>, Error> {
+        if max < self.size() {
+            Err(Error::TooLarge(self.size()))?;
+        }
+        let mut buf = vec![0; self.size()];
+        self.read(&mut buf);
+        Ok(buf)
+    }
+
+    pub fn read_to_string(&self, max: usize) -> Result<String, Error> {
+        String::from_utf8(self.read_to_vec(max)?).map_err(|_| Error::NotUtf8)
+    }
+}
+
+impl fmt::Debug for FwCfgFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FwCfgFile")
+            .field("name", &self.name())
+            .field("size", &self.size)
+            .field("select", &self.select)
+            .finish()
+    }
+}
+
+/// Call `f` with each entry of the file directory read by `read`, until `f` breaks.
+fn scan_dir<T>(
+    mut read: impl FnMut(&mut [u8]),
+    mut f: impl FnMut(FwCfgFile) -> ControlFlow<T>,
+) -> Option<T> {
+    let mut count = [0; 4];
+    read(&mut count);
+    let count = u32::from_be_bytes(count);
+    if MAX_FILES < count {
+        warn!("fw_cfg: Broken file directory with {} files", count);
+        return None;
+    }
+    let mut entry = [0; FILE_ENTRY_SIZE];
+    for _ in 0..count {
+        read(&mut entry);
+        if let Some(file) = FwCfgFile::parse(&entry) {
+            if let ControlFlow::Break(t) = f(file) {
+                return Some(t);
+            }
+        }
+    }
+    None
+}
+
+fn for_each_file<T>(f: impl FnMut(FwCfgFile) -> ControlFlow<T>) -> Option<T> {
+    if !is_present() {
+        return None;
+    }
+    let mut fw_cfg = FW_CFG.lock();
+    fw_cfg.select(SELECT_FILE_DIR);
+    scan_dir(|buf| fw_cfg.read(buf), f)
+}
+
+pub fn find(name: &str) -> Option<FwCfgFile> {
+    for_each_file(|file| match file.name() == name {
+        true => ControlFlow::Break(file),
+        false => ControlFlow::Continue(()),
+    })
+}
+
+pub fn files() -> Vec<FwCfgFile> {
+    let mut files = Vec::new();
+    for_each_file(|file| {
+        files.push(file);
+        ControlFlow::<()>::Continue(())
+    });
+    files
+}
+
+/// The boot parameters in `CMDLINE_FILE`. This is read once without allocation, since the boot
+/// parameters are needed before the heap is ready.
+pub fn command_line() -> Option<&'static str> {
+    let cmdline = CMDLINE.call_once(|| {
+        let file = find(CMDLINE_FILE)?;
+        let mut buf = [0; CMDLINE_LEN];
+        if CMDLINE_LEN < file.size() {
+            warn!(
+                "fw_cfg: {} is too large ({} bytes)",
+                CMDLINE_FILE,
+                file.size()
+            );
+            return None;
+        }
+        let len = file.read(&mut buf);
+        let s = str::from_utf8(&buf[..len]).ok()?;
+        // `string=` of QEMU may be followed by NUL
+        Some(heapless::String::from(s.trim_end_matches('\0')))
+    });
+    cmdline.as_deref()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::info;
+
+    /// An entry of the file directory as QEMU lays it out.
+    fn entry(size: u32, select: u16, name: &str) -> [u8; FILE_ENTRY_SIZE] {
+        let mut entry = [0; FILE_ENTRY_SIZE];
+        entry[0..4].copy_from_slice(&size.to_be_bytes());
+        entry[4..6].copy_from_slice(&select.to_be_bytes());
+        entry[8..8 + name.len()].copy_from_slice(name.as_bytes());
+        entry
+    }
+
+    #[test_case]
+    fn test_parse_file() {
+        info!("TESTING devices::fw_cfg::test_parse_file");
+        // Captured from `-fw_cfg name=opt/ors/cmdline,string=memtest=quick`
+        let mut captured = [0; FILE_ENTRY_SIZE];
+        captured[..24].copy_from_slice(b"\x00\x00\x00\x0d\x00\x2a\x00\x00opt/ors/cmdline\x00");
+        let file = FwCfgFile::parse(&captured).unwrap();
+        assert_eq!(file.name(), "opt/ors/cmdline");
+        assert_eq!(file.size(), 13);
+        assert_eq!(file.select, 0x2a);
+        assert_eq!(
+            FwCfgFile::parse(&entry(13, 0x2a, "opt/ors/cmdline")),
+            Some(file)
+        );
+
+        // The longest name
+        let name = "a".repeat(NAME_LEN - 1);
+        let file = FwCfgFile::parse(&entry(0, 0x20, &name)).unwrap();
+        assert_eq!(file.name(), name);
+        // Without NUL, empty, or not UTF-8
+        let mut e = entry(0, 0x20, "");
+        e[8..].fill(b'a');
+        assert!(FwCfgFile::parse(&e).is_none());
+        assert!(FwCfgFile::parse(&entry(0, 0x20, "")).is_none());
+        let mut e = entry(0, 0x20, "ab");
+        e[8] = 0xff;
+        assert!(FwCfgFile::parse(&e).is_none());
+    }
+
+    #[test_case]
+    fn test_scan_dir() {
+        info!("TESTING devices::fw_cfg::test_scan_dir");
+        let mut dir = 3u32.to_be_bytes().to_vec();
+        dir.extend_from_slice(&entry(8, 0x20, "bootorder"));
+        dir.extend_from_slice(&entry(0, 0x21, ""));
+        dir.extend_from_slice(&entry(5, 0x22, "opt/ors/test"));
+        let scan = |dir: &[u8], name: &str| {
+            let mut rest = dir;
+            scan_dir(
+                |buf| {
+                    let (head, tail) = rest.split_at(buf.len());
+                    buf.copy_from_slice(head);
+                    rest = tail;
+                },
+                |file| match file.name() == name {
+                    true => ControlFlow::Break((file.select, file.size())),
+                    false => ControlFlow::Continue(()),
+                },
+            )
+        };
+        assert_eq!(scan(&dir, "opt/ors/test"), Some((0x22, 5)));
+        assert_eq!(scan(&dir, "bootorder"), Some((0x20, 8)));
+        assert_eq!(scan(&dir, "opt/ors/none"), None);
+
+        // The count is validated before the entries are read
+        let broken = u32::MAX.to_be_bytes();
+        assert_eq!(scan(&broken, "bootorder"), None);
+    }
+
+    /// Passed by `qemu/run_image.sh`.
+    #[test_case]
+    fn test_qemu_file() {
+        info!("TESTING devices::fw_cfg::test_qemu_file");
+        if !is_present() {
+            info!("fw_cfg: No device, skipped");
+            return;
+        }
+        let file = find("opt/ors/test").expect("opt/ors/test is not passed");
+        assert_eq!(file.read_to_vec(0), Err(Error::TooLarge(file.size())));
+        let s = file.read_to_string(64).unwrap();
+        info!("fw_cfg: opt/ors/test = {}", s);
+        assert_eq!(s.trim_end_matches('\0'), "fw_cfg-ok");
+        assert!(files().iter().any(|f| f.name() == "opt/ors/test"));
+    }
+}
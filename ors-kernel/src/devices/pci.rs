@@ -1,13 +1,16 @@
 #![allow(dead_code)]
 
+use crate::interrupts::Cli;
 use crate::x64;
 use bit_field::BitField;
-use core::ptr;
+use core::{fmt, ptr};
 use derive_new::new;
 use heapless::Vec;
 use log::trace;
 use spin::Once;
 
+pub mod names;
+
 static DEVICES: Once<Vec<Device, 32>> = Once::new();
 
 pub fn initialize_devices() {
@@ -61,11 +64,36 @@ impl ConfigData {
     }
 }
 
+/// The maximum number of BARs of a device.
+const MAX_BARS: usize = 6;
+
 #[derive(Debug, Clone, Copy, new)]
 pub struct Device {
     pub bus: u8,
     pub device: u8,
     pub function: u8,
+    /// Probed once by `scan`, see `bar_info`.
+    #[new(value = "[None; MAX_BARS]")]
+    bars: [Option<BarInfo>; MAX_BARS],
+}
+
+/// Devices are identified by their addresses.
+impl PartialEq for Device {
+    fn eq(&self, other: &Self) -> bool {
+        (self.bus, self.device, self.function) == (other.bus, other.device, other.function)
+    }
+}
+
+impl Eq for Device {}
+
+impl fmt::Display for Device {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:02x}:{:02x}.{:x}",
+            self.bus, self.device, self.function
+        )
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -74,6 +102,16 @@ pub enum ScanError {
 }
 
 impl Device {
+    /// Parse a `bus:device.function` selector in hexadecimal, such as `00:1f.2`.
+    pub fn parse_selector(s: &str) -> Option<Self> {
+        let (bus, rest) = s.split_once(':')?;
+        let (device, function) = rest.split_once('.')?;
+        let bus = u8::from_str_radix(bus, 16).ok()?;
+        let device = u8::from_str_radix(device, 16).ok().filter(|d| *d < 32)?;
+        let function = u8::from_str_radix(function, 16).ok().filter(|f| *f < 8)?;
+        Some(Self::new(bus, device, function))
+    }
+
     unsafe fn read(self, addr: u8) -> u32 {
         ConfigAddress::new(self.bus, self.device, self.function, addr).write();
         ConfigData::read().0
@@ -84,6 +122,16 @@ impl Device {
         ConfigData(value).write();
     }
 
+    /// Read the configuration space from `offset` into `buf`. Both `offset` and the length of
+    /// `buf` must be multiples of 4, and the range must be in the first 256 bytes.
+    pub unsafe fn read_config(self, offset: usize, buf: &mut [u8]) {
+        assert!(offset % 4 == 0 && buf.len() % 4 == 0 && offset + buf.len() <= 256);
+        for (i, chunk) in buf.chunks_exact_mut(4).enumerate() {
+            let value = self.read((offset + i * 4) as u8);
+            chunk.copy_from_slice(&value.to_le_bytes());
+        }
+    }
+
     pub unsafe fn vendor_id(self) -> u16 {
         self.read(0x00) as u16
     }
@@ -96,6 +144,14 @@ impl Device {
         (self.read(0x00) >> 16) as u16
     }
 
+    /// Vendor and device names, see `names::Description`.
+    pub unsafe fn description(self) -> names::Description {
+        names::Description {
+            vendor_id: self.vendor_id(),
+            device_id: self.device_id(),
+        }
+    }
+
     pub unsafe fn is_virtio(self) -> bool {
         // NOTE: Should this be named is_transitional_virtio?
         let vendor_id = self.vendor_id();
@@ -103,6 +159,25 @@ impl Device {
         vendor_id == 0x1af4 && 0x1000 <= device_id && device_id <= 0x103f
     }
 
+    /// Whether this is a modern (non-transitional) VirtIO device, which only provides the
+    /// modern interface.
+    pub unsafe fn is_modern_virtio(self) -> bool {
+        let vendor_id = self.vendor_id();
+        let device_id = self.device_id();
+        vendor_id == 0x1af4 && 0x1040 <= device_id && device_id <= 0x107f
+    }
+
+    /// VirtIO device type (e.g. 2 for block devices, 18 for input devices).
+    pub unsafe fn virtio_device_type(self) -> Option<u16> {
+        if self.is_modern_virtio() {
+            Some(self.device_id() - 0x1040)
+        } else if self.is_virtio() {
+            Some(self.subsystem_id())
+        } else {
+            None
+        }
+    }
+
     pub unsafe fn command(self) -> u16 {
         self.read(0x04) as u16
     }
@@ -139,22 +214,51 @@ impl Device {
     pub unsafe fn read_bar(self, index: u8) -> Bar {
         assert!(index < self.num_bars());
 
-        // https://wiki.osdev.org/PCI#Base_Address_Registers
         let bar = self.read(base_address_register_address(index));
-        if (bar & 0x1) != 0 {
-            let bar = (bar & !0x3) as u16;
-            Bar::IoPort(bar)
-        } else {
-            if (bar & 0x4) != 0 {
-                let bar_lower = (bar as u64) & !0xf;
-                let bar_upper = self.read(base_address_register_address(index + 1));
-                let bar_upper = (bar_upper as u64) << 32;
-                Bar::MemoryAddress(bar_lower | bar_upper)
-            } else {
-                let bar = (bar as u64) & !0xf;
-                Bar::MemoryAddress(bar)
-            }
+        Bar::decode(bar, || self.read(base_address_register_address(index + 1)))
+    }
+
+    /// The type and the size of the BAR, probed during the boot enumeration. Returns `None` if the
+    /// BAR is not implemented or is the upper half of a 64-bit BAR, or if this device is not the
+    /// one obtained from `devices`.
+    pub fn bar_info(&self, index: u8) -> Option<BarInfo> {
+        self.bars.get(index as usize).copied().flatten()
+    }
+
+    /// Probe the type and the size of the BAR by writing all 1s to it.
+    /// Returns `None` if the BAR is not implemented or is the upper half of a 64-bit BAR.
+    /// Decoding of the device is disabled during the probe, which must be done before any driver
+    /// uses the device, since the BAR is temporarily relocated.
+    unsafe fn probe_bar(self, index: u8) -> Option<BarInfo> {
+        assert!(index < self.num_bars());
+        if 0 < index && self.is_64bit_bar(index - 1) {
+            return None;
         }
+        let addr = base_address_register_address(index);
+        let _cli = Cli::new();
+        let command = self.command();
+        self.write(0x04, (command & !0x3) as u32); // Writing 0 to status bits does not clear them
+        let bar = self.read(addr);
+        self.write(addr, 0xffff_ffff);
+        let mask = self.read(addr);
+        self.write(addr, bar);
+        let (upper, upper_mask) = if (bar & 0x7) == 0x4 && index + 1 < self.num_bars() {
+            let addr = base_address_register_address(index + 1);
+            let upper = self.read(addr);
+            self.write(addr, 0xffff_ffff);
+            let upper_mask = self.read(addr);
+            self.write(addr, upper);
+            (upper, upper_mask)
+        } else {
+            (0, 0)
+        };
+        self.write(0x04, command as u32);
+        BarInfo::decode(bar, upper, mask, upper_mask)
+    }
+
+    unsafe fn is_64bit_bar(self, index: u8) -> bool {
+        let bar = self.read(base_address_register_address(index));
+        (bar & 0x7) == 0x4
     }
 
     pub unsafe fn bus_numbers(self) -> (u8, u8) {
@@ -245,7 +349,10 @@ impl Device {
         function: u8,
         dest: &mut Vec<Self, N>,
     ) -> Result<(), ScanError> {
-        let d = Self::new(bus, device, function);
+        let mut d = Self::new(bus, device, function);
+        for i in 0..d.num_bars() {
+            d.bars[i as usize] = d.probe_bar(i);
+        }
         dest.push(d).map_err(|_| ScanError::Full)?;
 
         if d.device_type().is_standard_pci_to_pci_bridge() {
@@ -257,13 +364,30 @@ impl Device {
     }
 }
 
-#[derive(PartialEq, Eq, Debug, Clone)]
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
 pub enum Bar {
     MemoryAddress(u64),
     IoPort(u16),
 }
 
 impl Bar {
+    /// Decode a Base Address Register. `upper` is called to read the next register
+    /// only if `bar` is the lower half of a 64-bit memory address.
+    fn decode(bar: u32, upper: impl FnOnce() -> u32) -> Self {
+        // https://wiki.osdev.org/PCI#Base_Address_Registers
+        if (bar & 0x1) != 0 {
+            let bar = (bar & !0x3) as u16;
+            Bar::IoPort(bar)
+        } else if (bar & 0x4) != 0 {
+            let bar_lower = (bar as u64) & !0xf;
+            let bar_upper = (upper() as u64) << 32;
+            Bar::MemoryAddress(bar_lower | bar_upper)
+        } else {
+            let bar = (bar as u64) & !0xf;
+            Bar::MemoryAddress(bar)
+        }
+    }
+
     pub fn mmio_base(self) -> Option<usize> {
         match self {
             Bar::MemoryAddress(addr) => Some(addr as usize),
@@ -279,6 +403,48 @@ impl Bar {
     }
 }
 
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum BarKind {
+    Io,
+    Memory32,
+    Memory64,
+}
+
+/// A BAR decoded with its size.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct BarInfo {
+    pub bar: Bar,
+    pub kind: BarKind,
+    pub prefetchable: bool,
+    pub size: u64,
+}
+
+impl BarInfo {
+    /// Decode a BAR from its values before and after writing all 1s to it.
+    /// `upper` and `upper_mask` are the values of the next BAR, used only for 64-bit BARs.
+    fn decode(bar: u32, upper: u32, mask: u32, upper_mask: u32) -> Option<Self> {
+        if mask == 0 {
+            return None;
+        }
+        let decoded = Bar::decode(bar, || upper);
+        let (kind, size) = if (bar & 0x1) != 0 {
+            let size = (!(mask & !0x3)).wrapping_add(1) & 0xffff;
+            (BarKind::Io, size as u64)
+        } else if (bar & 0x4) != 0 {
+            let mask = ((upper_mask as u64) << 32) | (mask & !0xf) as u64;
+            (BarKind::Memory64, (!mask).wrapping_add(1))
+        } else {
+            (BarKind::Memory32, (!(mask & !0xf)).wrapping_add(1) as u64)
+        };
+        Some(Self {
+            bar: decoded,
+            kind,
+            prefetchable: (bar & 0x1) == 0 && (bar & 0x8) != 0,
+            size,
+        })
+    }
+}
+
 #[derive(Debug, Clone, Copy, new)]
 pub struct DeviceType {
     pub class_code: u8,
@@ -328,6 +494,24 @@ pub struct Capability {
 }
 
 impl Capability {
+    pub fn device(self) -> Device {
+        self.device
+    }
+
+    /// Offset of this capability structure in the configuration space.
+    pub fn pointer(self) -> u8 {
+        self.pointer
+    }
+
+    pub unsafe fn name(self) -> &'static str {
+        capability_name(self.id())
+    }
+
+    /// Read a 32-bit word at `offset` from the beginning of this capability structure.
+    pub unsafe fn read(self, offset: u8) -> u32 {
+        self.device.read(self.pointer + offset)
+    }
+
     pub unsafe fn id(self) -> u8 {
         self.device.read(self.pointer) as u8
     }
@@ -356,6 +540,50 @@ impl Capability {
     }
 }
 
+/// Name of the capability for display.
+pub fn capability_name(id: u8) -> &'static str {
+    match id {
+        0x01 => "Power Management",
+        0x03 => "VPD",
+        0x05 => "MSI",
+        0x09 => "Vendor Specific",
+        0x0d => "Bridge Subsystem Vendor ID",
+        0x10 => "PCI Express",
+        0x11 => "MSI-X",
+        0x12 => "SATA",
+        0x13 => "Advanced Features",
+        _ => "Unknown",
+    }
+}
+
+/// Names of the bits of the command register.
+pub const COMMAND_BITS: [(usize, &str); 10] = [
+    (0, "I/O"),
+    (1, "Mem"),
+    (2, "BusMaster"),
+    (3, "SpecCycle"),
+    (4, "MemWINV"),
+    (5, "VGASnoop"),
+    (6, "ParErr"),
+    (8, "SERR"),
+    (9, "FastB2B"),
+    (10, "DisINTx"),
+];
+
+/// Names of the bits of the status register. DEVSEL timing (bits 9-10) is not included.
+pub const STATUS_BITS: [(usize, &str); 10] = [
+    (3, "INTx"),
+    (4, "Cap"),
+    (5, "66MHz"),
+    (7, "FastB2B"),
+    (8, "ParErr"),
+    (11, ">TAbort"),
+    (12, "<TAbort"),
+    (13, "<MAbort"),
+    (14, ">SERR"),
+    (15, "<PERR"),
+];
+
 #[derive(Debug, Clone, Copy, new)]
 pub struct MsiX {
     device: Device,
@@ -381,12 +609,12 @@ impl MsiX {
     }
 
     /// Table BAR Indicator
-    unsafe fn table_bir(self) -> u8 {
-        self.device.read(self.pointer + 0x04) as u8
+    pub unsafe fn table_bir(self) -> u8 {
+        self.device.read(self.pointer + 0x04) as u8 & 0x7
     }
 
-    unsafe fn table_offset(self) -> u32 {
-        self.device.read(self.pointer + 0x04) >> 8
+    pub unsafe fn table_offset(self) -> u32 {
+        self.device.read(self.pointer + 0x04) & !0x7
     }
 
     unsafe fn table_bar(self) -> Bar {
@@ -403,11 +631,11 @@ impl MsiX {
 
     /// Pending Bit Array BAR Indicator
     pub unsafe fn pba_bir(self) -> u8 {
-        self.device.read(self.pointer + 0x08) as u8
+        self.device.read(self.pointer + 0x08) as u8 & 0x7
     }
 
     pub unsafe fn pba_offset(self) -> u32 {
-        self.device.read(self.pointer + 0x08) >> 8
+        self.device.read(self.pointer + 0x08) & !0x7
     }
 }
 
@@ -484,3 +712,88 @@ impl MsiXTableEntry {
         ptr::write_volatile(self.ptr.add(3), value)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::ToString;
+    use log::info;
+
+    #[test_case]
+    fn test_config_address() {
+        info!("TESTING devices::pci::test_config_address");
+        assert_eq!(ConfigAddress::new(0, 0, 0, 0).0, 0x8000_0000);
+        assert_eq!(ConfigAddress::new(1, 2, 3, 0x10).0, 0x8001_1310);
+        assert_eq!(ConfigAddress::new(0xff, 0x1f, 0x7, 0xfc).0, 0x80ff_fffc);
+    }
+
+    #[test_case]
+    fn test_bar_decode() {
+        info!("TESTING devices::pci::test_bar_decode");
+        assert_eq!(Bar::decode(0xc041, || unreachable!()), Bar::IoPort(0xc040));
+        assert_eq!(
+            Bar::decode(0xfebd_1008, || unreachable!()),
+            Bar::MemoryAddress(0xfebd_1000)
+        );
+        assert_eq!(
+            Bar::decode(0xfe00_000c, || 0x1),
+            Bar::MemoryAddress(0x1_fe00_0000)
+        );
+        assert_eq!(Bar::IoPort(0xc040).mmio_base(), None);
+        assert_eq!(Bar::MemoryAddress(0x1000).io_port(), None);
+    }
+
+    #[test_case]
+    fn test_bar_info_decode() {
+        info!("TESTING devices::pci::test_bar_info_decode");
+        assert_eq!(BarInfo::decode(0, 0, 0, 0), None);
+        assert_eq!(
+            BarInfo::decode(0xc041, 0, 0xffff_ffe1, 0),
+            Some(BarInfo {
+                bar: Bar::IoPort(0xc040),
+                kind: BarKind::Io,
+                prefetchable: false,
+                size: 0x20,
+            })
+        );
+        assert_eq!(
+            BarInfo::decode(0xfebd_1000, 0, 0xffff_f000, 0),
+            Some(BarInfo {
+                bar: Bar::MemoryAddress(0xfebd_1000),
+                kind: BarKind::Memory32,
+                prefetchable: false,
+                size: 0x1000,
+            })
+        );
+        assert_eq!(
+            BarInfo::decode(0xfe00_000c, 0x1, 0xffff_c00c, 0xffff_ffff),
+            Some(BarInfo {
+                bar: Bar::MemoryAddress(0x1_fe00_0000),
+                kind: BarKind::Memory64,
+                prefetchable: true,
+                size: 0x4000,
+            })
+        );
+    }
+
+    #[test_case]
+    fn test_parse_selector() {
+        info!("TESTING devices::pci::test_parse_selector");
+        assert_eq!(
+            Device::parse_selector("00:1f.2"),
+            Some(Device::new(0, 0x1f, 2))
+        );
+        assert_eq!(
+            Device::parse_selector("ff:00.7"),
+            Some(Device::new(0xff, 0, 7))
+        );
+        assert_eq!(Device::parse_selector("0:3.0"), Some(Device::new(0, 3, 0)));
+        assert_eq!(Device::parse_selector("00:20.0"), None);
+        assert_eq!(Device::parse_selector("00:00.8"), None);
+        assert_eq!(Device::parse_selector("100:00.0"), None);
+        assert_eq!(Device::parse_selector("00.00:0"), None);
+        assert_eq!(Device::parse_selector("00:00"), None);
+        assert_eq!(Device::parse_selector("xx:00.0"), None);
+        assert_eq!(Device::new(0, 0x1f, 2).to_string(), "00:1f.2");
+    }
+}
@@ -0,0 +1,114 @@
+//! Names of well-known PCI vendors and devices, mainly the ones emulated by QEMU.
+
+use core::fmt;
+
+pub fn vendor_name(vendor_id: u16) -> Option<&'static str> {
+    Some(match vendor_id {
+        0x1002 => "AMD/ATI",
+        0x1022 => "AMD",
+        0x10de => "NVIDIA",
+        0x10ec => "Realtek",
+        0x1234 => "QEMU",
+        0x14e4 => "Broadcom",
+        0x15ad => "VMware",
+        0x1af4 => "Red Hat (VirtIO)",
+        0x1b36 => "Red Hat (QEMU)",
+        0x8086 => "Intel",
+        _ => None?,
+    })
+}
+
+pub fn device_name(vendor_id: u16, device_id: u16) -> Option<&'static str> {
+    Some(match (vendor_id, device_id) {
+        // Intel chipsets of QEMU i440fx and q35 machines
+        (0x8086, 0x100e) => "82540EM Gigabit Ethernet Controller",
+        (0x8086, 0x10d3) => "82574L Gigabit Network Connection",
+        (0x8086, 0x1237) => "440FX PMC",
+        (0x8086, 0x2415) => "82801AA AC'97 Audio Controller",
+        (0x8086, 0x2918) => "ICH9 LPC Interface",
+        (0x8086, 0x2922) => "ICH9 SATA Controller (AHCI)",
+        (0x8086, 0x2930) => "ICH9 SMBus Controller",
+        (0x8086, 0x2934..=0x2939) => "ICH9 USB UHCI Controller",
+        (0x8086, 0x293a) => "ICH9 USB2 EHCI Controller",
+        (0x8086, 0x293e) => "ICH9 HD Audio Controller",
+        (0x8086, 0x29c0) => "82G33 DRAM Controller",
+        (0x8086, 0x7000) => "82371SB PIIX3 ISA",
+        (0x8086, 0x7010) => "82371SB PIIX3 IDE",
+        (0x8086, 0x7020) => "82371SB PIIX3 USB",
+        (0x8086, 0x7113) => "82371AB PIIX4 ACPI",
+        // VirtIO (transitional devices use 0x1000 - 0x103f)
+        (0x1af4, 0x1000) => "VirtIO network device (transitional)",
+        (0x1af4, 0x1001) => "VirtIO block device (transitional)",
+        (0x1af4, 0x1002) => "VirtIO memory balloon (transitional)",
+        (0x1af4, 0x1003) => "VirtIO console (transitional)",
+        (0x1af4, 0x1005) => "VirtIO RNG (transitional)",
+        (0x1af4, 0x1041) => "VirtIO network device",
+        (0x1af4, 0x1042) => "VirtIO block device",
+        (0x1af4, 0x1043) => "VirtIO console",
+        (0x1af4, 0x1044) => "VirtIO RNG",
+        (0x1af4, 0x1045) => "VirtIO memory balloon",
+        (0x1af4, 0x1048) => "VirtIO SCSI",
+        (0x1af4, 0x1050) => "VirtIO GPU",
+        (0x1af4, 0x1052) => "VirtIO input",
+        // QEMU
+        (0x1234, 0x1111) => "QEMU Standard VGA",
+        (0x1b36, 0x0001) => "QEMU PCI-PCI Bridge",
+        (0x1b36, 0x0008) => "QEMU PCIe Host Bridge",
+        (0x1b36, 0x000c) => "QEMU PCIe Root Port",
+        (0x1b36, 0x000d) => "QEMU XHCI Host Controller",
+        _ => None?,
+    })
+}
+
+/// A device described as `Intel 8086:2918 (ICH9 LPC Interface)`. Unknown names are omitted.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct Description {
+    pub vendor_id: u16,
+    pub device_id: u16,
+}
+
+impl fmt::Display for Description {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(vendor) = vendor_name(self.vendor_id) {
+            write!(f, "{} ", vendor)?;
+        }
+        write!(f, "{:04x}:{:04x}", self.vendor_id, self.device_id)?;
+        if let Some(device) = device_name(self.vendor_id, self.device_id) {
+            write!(f, " ({})", device)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::format;
+    use log::info;
+
+    #[test_case]
+    fn test_description() {
+        info!("TESTING devices::pci::names::test_description");
+        let d = |vendor_id, device_id| {
+            format!(
+                "{}",
+                Description {
+                    vendor_id,
+                    device_id
+                }
+            )
+        };
+        assert_eq!(d(0x8086, 0x2918), "Intel 8086:2918 (ICH9 LPC Interface)");
+        assert_eq!(d(0x8086, 0xffff), "Intel 8086:ffff");
+        assert_eq!(
+            d(0x1af4, 0x1042),
+            "Red Hat (VirtIO) 1af4:1042 (VirtIO block device)"
+        );
+        assert_eq!(d(0xabcd, 0x0001), "abcd:0001");
+        assert_eq!(
+            device_name(0x8086, 0x2936),
+            Some("ICH9 USB UHCI Controller")
+        );
+        assert_eq!(device_name(0x1022, 0x1042), None);
+    }
+}
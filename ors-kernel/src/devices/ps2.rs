@@ -0,0 +1,28 @@
+use crate::x64::Port;
+
+const DATA_PORT: u16 = 0x60;
+const STATUS_PORT: u16 = 0x64;
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+const WAIT_LIMIT: usize = 100000;
+
+const SET_TYPEMATIC: u8 = 0xf3;
+
+/// Set the typematic rate and delay of the PS/2 keyboard in the format of the command 0xF3.
+/// The keyboard responds with ACK bytes, which arrive at the keyboard interrupt handler.
+pub fn set_typematic(code: u8) {
+    send_to_keyboard(SET_TYPEMATIC);
+    send_to_keyboard(code & 0x7f);
+}
+
+fn send_to_keyboard(byte: u8) {
+    let mut status = Port::<u8>::new(STATUS_PORT);
+    let mut data = Port::<u8>::new(DATA_PORT);
+    for _ in 0..WAIT_LIMIT {
+        if unsafe { status.read() } & STATUS_INPUT_FULL == 0 {
+            unsafe { data.write(byte) };
+            return;
+        }
+        core::hint::spin_loop();
+    }
+    log::warn!("ps2: Timed out while sending {:#04x} to the keyboard", byte);
+}
@@ -1,9 +1,38 @@
+//! 16550 UART driver.
+//!
+//! Received bytes are drained from the RX FIFO by the interrupt handler through `handle_interrupt`.
+//! Output is written by polling the line status. The interrupt handler dispatches on the
+//! Interrupt Identification Register, so that THR empty interrupts can be handled by the same
+//! dispatch when the output is buffered and driven by interrupts.
+
 use crate::sync::spin::{Spin, SpinGuard};
-pub use uart_16550::SerialPort as Port;
+use crate::x64;
+use core::fmt;
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
 
 const DEFAULT_PORT_ADDRESS: u16 = 0x3f8;
 
+// Registers by the offset from the base address
+const DATA: u16 = 0;
+const INTERRUPT_ENABLE: u16 = 1;
+const INTERRUPT_ID: u16 = 2; // read
+const FIFO_CONTROL: u16 = 2; // write
+const LINE_CONTROL: u16 = 3;
+const MODEM_CONTROL: u16 = 4;
+const LINE_STATUS: u16 = 5;
+const MODEM_STATUS: u16 = 6;
+
+/// The rate of the UART clock divided by 16, which is the baud rate of the divisor 1.
+const MAX_BAUD_RATE: u32 = 115200;
+pub const DEFAULT_BAUD_RATE: u32 = MAX_BAUD_RATE;
+
+/// Upper bound of the interrupts dispatched by a single `handle_interrupt`, to return from the
+/// interrupt handler even if the device keeps reporting interrupts.
+const MAX_DISPATCHES: usize = 16;
+
 static DEFAULT_PORT: Spin<Port> = Spin::new(unsafe { Port::new(DEFAULT_PORT_ADDRESS) });
+static DEFAULT_PORT_STATS: PortStats = PortStats::new();
+static DEFAULT_PORT_BAUD_RATE: AtomicU32 = AtomicU32::new(DEFAULT_BAUD_RATE);
 
 pub fn default_port() -> SpinGuard<'static, Port> {
     DEFAULT_PORT.lock()
@@ -14,3 +43,356 @@ pub fn default_port() -> SpinGuard<'static, Port> {
 pub fn raw_default_port() -> Port {
     unsafe { Port::new(DEFAULT_PORT_ADDRESS) }
 }
+
+/// Statistics of the default port.
+pub fn stats() -> Stats {
+    DEFAULT_PORT_STATS.snapshot()
+}
+
+pub fn baud_rate() -> u32 {
+    DEFAULT_PORT_BAUD_RATE.load(Ordering::Relaxed)
+}
+
+/// Reprogram the baud rate of the default port. Fails if the rate cannot be derived from the
+/// UART clock exactly.
+pub fn set_baud_rate(baud_rate: u32) -> Result<(), Error> {
+    let divisor = divisor_for(baud_rate).ok_or(Error::UnsupportedBaudRate(baud_rate))?;
+    // The lock masks the interrupts, so that the interrupt handler never observes DLAB set
+    default_port().set_divisor(divisor);
+    DEFAULT_PORT_BAUD_RATE.store(baud_rate, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Handle an interrupt of the default port. Received bytes are passed to `receive`.
+pub fn handle_interrupt(mut receive: impl FnMut(u8)) {
+    let mut port = default_port();
+    for _ in 0..MAX_DISPATCHES {
+        match InterruptId::from_iir(port.read(INTERRUPT_ID)) {
+            None => break,
+            Some(InterruptId::LineStatus | InterruptId::ReceivedData | InterruptId::Timeout) => {
+                port.drain(&DEFAULT_PORT_STATS, &mut receive)
+            }
+            // Not enabled, the output is written by polling
+            Some(InterruptId::TransmitterEmpty) => {}
+            Some(InterruptId::ModemStatus) => {
+                port.read(MODEM_STATUS);
+            }
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Error {
+    UnsupportedBaudRate(u32),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnsupportedBaudRate(rate) => write!(f, "Unsupported baud rate: {}", rate),
+        }
+    }
+}
+
+/// Counts of the received bytes and the errors reported by the Line Status Register.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub struct Stats {
+    pub received: u64,
+    /// Bytes lost since the RX FIFO was full.
+    pub overruns: u64,
+    pub parity_errors: u64,
+    pub framing_errors: u64,
+    pub breaks: u64,
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "received: {}, overruns: {}, parity errors: {}, framing errors: {}, breaks: {}",
+            self.received, self.overruns, self.parity_errors, self.framing_errors, self.breaks
+        )
+    }
+}
+
+#[derive(Debug)]
+struct PortStats {
+    received: AtomicU64,
+    overruns: AtomicU64,
+    parity_errors: AtomicU64,
+    framing_errors: AtomicU64,
+    breaks: AtomicU64,
+}
+
+impl PortStats {
+    const fn new() -> Self {
+        Self {
+            received: AtomicU64::new(0),
+            overruns: AtomicU64::new(0),
+            parity_errors: AtomicU64::new(0),
+            framing_errors: AtomicU64::new(0),
+            breaks: AtomicU64::new(0),
+        }
+    }
+
+    fn record(&self, status: LineStatus) {
+        let count = |counter: &AtomicU64, flag: u8| {
+            if status.contains(flag) {
+                counter.fetch_add(1, Ordering::Relaxed);
+            }
+        };
+        count(&self.overruns, LineStatus::OVERRUN_ERROR);
+        count(&self.parity_errors, LineStatus::PARITY_ERROR);
+        count(&self.framing_errors, LineStatus::FRAMING_ERROR);
+        count(&self.breaks, LineStatus::BREAK_INTERRUPT);
+    }
+
+    fn snapshot(&self) -> Stats {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        Stats {
+            received: load(&self.received),
+            overruns: load(&self.overruns),
+            parity_errors: load(&self.parity_errors),
+            framing_errors: load(&self.framing_errors),
+            breaks: load(&self.breaks),
+        }
+    }
+}
+
+/// A port-mapped 16550 UART.
+#[derive(Debug)]
+pub struct Port {
+    base: u16,
+}
+
+impl Port {
+    const FIFO_ENABLE: u8 = 0x01;
+    const FIFO_CLEAR_RX: u8 = 0x02;
+    const FIFO_CLEAR_TX: u8 = 0x04;
+    const FIFO_RX_TRIGGER_14: u8 = 0xc0;
+    const LINE_8N1: u8 = 0x03;
+    const LINE_DLAB: u8 = 0x80;
+    /// DTR, RTS, and OUT2, which connects the interrupt line.
+    const MODEM_DTR_RTS_OUT2: u8 = 0x0b;
+    const INTERRUPT_RECEIVED_DATA: u8 = 0x01;
+    const INTERRUPT_LINE_STATUS: u8 = 0x04;
+
+    /// This function is unsafe because the caller must ensure that the given base address
+    /// really points to a serial port device.
+    pub const unsafe fn new(base: u16) -> Self {
+        Self { base }
+    }
+
+    /// Initialize the port with 8-N-1 at `DEFAULT_BAUD_RATE`, the FIFOs with the RX trigger
+    /// level of 14 bytes, and the interrupts of received data and line status.
+    pub fn init(&mut self) {
+        self.write(INTERRUPT_ENABLE, 0);
+        self.write(LINE_CONTROL, Self::LINE_8N1);
+        self.set_divisor(divisor_for(DEFAULT_BAUD_RATE).unwrap());
+        self.write(
+            FIFO_CONTROL,
+            Self::FIFO_ENABLE
+                | Self::FIFO_CLEAR_RX
+                | Self::FIFO_CLEAR_TX
+                | Self::FIFO_RX_TRIGGER_14,
+        );
+        self.write(MODEM_CONTROL, Self::MODEM_DTR_RTS_OUT2);
+        self.write(
+            INTERRUPT_ENABLE,
+            Self::INTERRUPT_RECEIVED_DATA | Self::INTERRUPT_LINE_STATUS,
+        );
+    }
+
+    /// Program the divisor latch. The interrupts of the port are disabled while DLAB is set,
+    /// since the registers of the interrupts are shadowed by the divisor latch.
+    fn set_divisor(&mut self, divisor: u16) {
+        // Changing the divisor garbles the bytes being transmitted
+        while !self.line_status().contains(LineStatus::TRANSMITTER_EMPTY) {
+            core::hint::spin_loop();
+        }
+        let interrupts = self.read(INTERRUPT_ENABLE);
+        let line = self.read(LINE_CONTROL);
+        self.write(INTERRUPT_ENABLE, 0);
+        self.write(LINE_CONTROL, line | Self::LINE_DLAB);
+        let [lo, hi] = divisor.to_le_bytes();
+        self.write(DATA, lo); // DLL
+        self.write(INTERRUPT_ENABLE, hi); // DLM
+        self.write(LINE_CONTROL, line & !Self::LINE_DLAB);
+        self.write(INTERRUPT_ENABLE, interrupts);
+    }
+
+    fn line_status(&mut self) -> LineStatus {
+        LineStatus(self.read(LINE_STATUS))
+    }
+
+    /// Read the received bytes until the RX FIFO becomes empty, counting the errors. Reading
+    /// the Line Status Register clears the errors.
+    fn drain(&mut self, stats: &PortStats, mut receive: impl FnMut(u8)) {
+        loop {
+            let status = self.line_status();
+            stats.record(status);
+            if !status.contains(LineStatus::DATA_READY) {
+                break;
+            }
+            let byte = self.read(DATA);
+            stats.received.fetch_add(1, Ordering::Relaxed);
+            receive(byte);
+        }
+    }
+
+    /// Sends a byte on the serial port.
+    pub fn send(&mut self, data: u8) {
+        match data {
+            8 | 0x7f => {
+                self.send_raw(8);
+                self.send_raw(b' ');
+                self.send_raw(8);
+            }
+            _ => self.send_raw(data),
+        }
+    }
+
+    /// Sends a raw byte on the serial port, intended for binary data.
+    pub fn send_raw(&mut self, data: u8) {
+        while !self.line_status().contains(LineStatus::OUTPUT_EMPTY) {
+            core::hint::spin_loop();
+        }
+        self.write(DATA, data);
+    }
+
+    /// Receives a byte on the serial port.
+    pub fn receive(&mut self) -> u8 {
+        while !self.line_status().contains(LineStatus::DATA_READY) {
+            core::hint::spin_loop();
+        }
+        self.read(DATA)
+    }
+
+    fn read(&mut self, register: u16) -> u8 {
+        unsafe { x64::Port::<u8>::new(self.base + register).read() }
+    }
+
+    fn write(&mut self, register: u16, value: u8) {
+        unsafe { x64::Port::<u8>::new(self.base + register).write(value) }
+    }
+}
+
+impl fmt::Write for Port {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            self.send(byte);
+        }
+        Ok(())
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+struct LineStatus(u8);
+
+impl LineStatus {
+    const DATA_READY: u8 = 0x01;
+    const OVERRUN_ERROR: u8 = 0x02;
+    const PARITY_ERROR: u8 = 0x04;
+    const FRAMING_ERROR: u8 = 0x08;
+    const BREAK_INTERRUPT: u8 = 0x10;
+    const OUTPUT_EMPTY: u8 = 0x20;
+    const TRANSMITTER_EMPTY: u8 = 0x40;
+
+    fn contains(self, flag: u8) -> bool {
+        self.0 & flag == flag
+    }
+}
+
+/// The interrupts of the Interrupt Identification Register, in the order of the priority.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+enum InterruptId {
+    LineStatus,
+    ReceivedData,
+    /// Received bytes below the trigger level have been left in the RX FIFO.
+    Timeout,
+    TransmitterEmpty,
+    ModemStatus,
+}
+
+impl InterruptId {
+    fn from_iir(iir: u8) -> Option<Self> {
+        if iir & 0x01 != 0 {
+            return None; // No interrupt is pending
+        }
+        match (iir >> 1) & 0x07 {
+            0b011 => Some(Self::LineStatus),
+            0b010 => Some(Self::ReceivedData),
+            0b110 => Some(Self::Timeout),
+            0b001 => Some(Self::TransmitterEmpty),
+            0b000 => Some(Self::ModemStatus),
+            _ => None,
+        }
+    }
+}
+
+/// The divisor of the baud rate, if the rate can be derived exactly.
+fn divisor_for(baud_rate: u32) -> Option<u16> {
+    if baud_rate == 0 || MAX_BAUD_RATE % baud_rate != 0 {
+        return None;
+    }
+    u16::try_from(MAX_BAUD_RATE / baud_rate).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::info;
+
+    #[test_case]
+    fn test_divisor_for() {
+        info!("TESTING devices::serial::test_divisor_for");
+        assert_eq!(divisor_for(115200), Some(1));
+        assert_eq!(divisor_for(38400), Some(3));
+        assert_eq!(divisor_for(9600), Some(12));
+        assert_eq!(divisor_for(50), Some(2304));
+        assert_eq!(divisor_for(0), None);
+        assert_eq!(divisor_for(230400), None);
+        assert_eq!(divisor_for(100000), None);
+    }
+
+    #[test_case]
+    fn test_interrupt_id() {
+        info!("TESTING devices::serial::test_interrupt_id");
+        // The FIFO bits (0xc0) are set while the FIFOs are enabled
+        assert_eq!(InterruptId::from_iir(0xc1), None);
+        assert_eq!(InterruptId::from_iir(0xc6), Some(InterruptId::LineStatus));
+        assert_eq!(InterruptId::from_iir(0xc4), Some(InterruptId::ReceivedData));
+        assert_eq!(InterruptId::from_iir(0xcc), Some(InterruptId::Timeout));
+        assert_eq!(
+            InterruptId::from_iir(0xc2),
+            Some(InterruptId::TransmitterEmpty)
+        );
+        assert_eq!(InterruptId::from_iir(0xc0), Some(InterruptId::ModemStatus));
+    }
+
+    #[test_case]
+    fn test_port_stats() {
+        info!("TESTING devices::serial::test_port_stats");
+        let stats = PortStats::new();
+        stats.record(LineStatus(
+            LineStatus::DATA_READY | LineStatus::OUTPUT_EMPTY,
+        ));
+        stats.record(LineStatus(
+            LineStatus::DATA_READY | LineStatus::OVERRUN_ERROR | LineStatus::FRAMING_ERROR,
+        ));
+        stats.record(LineStatus(
+            LineStatus::BREAK_INTERRUPT | LineStatus::OVERRUN_ERROR,
+        ));
+        stats.record(LineStatus(LineStatus::PARITY_ERROR));
+        assert_eq!(
+            stats.snapshot(),
+            Stats {
+                received: 0,
+                overruns: 2,
+                parity_errors: 1,
+                framing_errors: 1,
+                breaks: 1,
+            }
+        );
+    }
+}
@@ -1,11 +1,18 @@
 //! VirtIO Drivers
 //!
-//! ors implements VirtIO Legacy Driver:
+//! ors implements VirtIO Legacy Driver, and the modern PCI transport for VirtIO 1.0+ devices:
 //! https://docs.oasis-open.org/virtio/virtio/v1.1/virtio-v1.1.pdf
 
 pub mod block;
 mod configuration;
+pub mod input;
+#[cfg(test)]
+mod mock;
+pub mod modern;
 mod queue;
+mod transport;
 
 pub use configuration::Configuration;
+pub use modern::ModernConfiguration;
 pub use queue::{Buffer, VirtQueue};
+pub use transport::{ConfigValue, PciTransport, Transport};
@@ -1,35 +1,94 @@
-use super::{Buffer, Configuration, VirtQueue};
+use super::{Buffer, PciTransport, Transport, VirtQueue};
 use crate::cpu::Cpu;
 use crate::devices::pci;
-use crate::interrupts::virtio_block_irq;
+use crate::interrupts::{ticks, virtio_block_irq, TIMER_FREQ};
+use crate::sync::once::Once;
 use crate::sync::spin::Spin;
 use crate::task;
-use core::mem;
-use core::sync::atomic::{fence, Ordering};
+use crate::trace::Category;
+use alloc::boxed::Box;
+use alloc::format;
+use core::sync::atomic::{fence, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use core::{mem, ptr};
 use derive_new::new;
 use heapless::Vec;
-use log::trace;
-use spin::Once;
+use log::{trace, Level};
+
+mod iosched;
+
+use iosched::{Elevator, Request};
+pub use iosched::{IoPriority, QueueStats, WaitStats};
 
 static BLOCKS: Once<Vec<Block, 8>> = Once::new();
 
+const DEVICE_TYPE: u16 = 2;
+const NUM_REQUEST_CHANNELS: usize = 8;
+const INITIALIZE_TIMEOUT: usize = 5 * TIMER_FREQ;
+const FEATURE_WRITE_ZEROES: u64 = 1 << 14;
+const CONFIG_MAX_WRITE_ZEROES_SECTORS: u16 = 0x30;
+/// Number of sectors written at once by the software fallback of `Block::write_zeros`.
+const ZEROS_SECTORS: usize = 8;
+/// Every request consists of a header, a body and a footer.
+const DESCRIPTORS_PER_REQUEST: usize = 3;
+
+/// Source of the software fallback of `Block::write_zeros`. A page-aligned page is used so that
+/// it is physically contiguous.
+#[repr(align(4096))]
+struct Zeros([u8; ZEROS_SECTORS * Block::SECTOR_SIZE]);
+
+static ZEROS: Zeros = Zeros([0; ZEROS_SECTORS * Block::SECTOR_SIZE]);
+
+/// Detect the blocks. The timeout only applies to other contexts waiting for the detection, since
+/// the detection runs in the first caller. Device operations that may hang on a device not
+/// responding are bounded by themselves, see `ModernConfiguration::initialize`.
 pub fn initialize() {
-    BLOCKS.call_once(|| {
-        trace!("INITIALIZING VirtIO Blocks");
-        unsafe { Block::scan::<8>() }
-    });
+    let result = BLOCKS.call_once_with_timeout(
+        || {
+            trace!("INITIALIZING VirtIO Blocks");
+            unsafe { Block::scan::<8>() }
+        },
+        INITIALIZE_TIMEOUT,
+    );
+    if let Err(e) = result {
+        trace!("virtio: Initialization of blocks is not completed: {}", e);
+    }
 }
 
-pub fn list() -> &'static Vec<Block, 8> {
-    BLOCKS
-        .get()
-        .expect("block::list is called before block::initialize")
+/// The detected blocks, or `None` if the detection is not completed, such as when it is given up
+/// by the timeout.
+pub fn try_list() -> Option<&'static Vec<Block, 8>> {
+    BLOCKS.get()
 }
 
 #[derive(Debug)]
 pub struct Block {
-    configuration: Configuration,
-    requestq: Spin<VirtQueue<Option<task::WaitChannel>>>,
+    transport: PciTransport,
+    requestq: Spin<RequestQueue>,
+    request_channels: RequestChannels,
+    /// Maximum sectors of a single write zeroes command, or 0 if the command is unavailable.
+    max_write_zeroes_sectors: AtomicU32,
+    /// The capacity last observed by `observe_capacity`.
+    last_capacity: AtomicU64,
+    /// Number of capacity changes observed since initialization.
+    capacity_generation: AtomicU64,
+    counters: Counters,
+}
+
+/// Requests completed by a device since boot.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub struct Stats {
+    pub reads: u64,
+    pub writes: u64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    reads: AtomicU64,
+    writes: AtomicU64,
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
 }
 
 impl Block {
@@ -37,7 +96,7 @@ impl Block {
         let mut blocks = Vec::new();
 
         for device in pci::devices() {
-            if device.is_virtio() && device.subsystem_id() == 0x02 {
+            if device.virtio_device_type() == Some(DEVICE_TYPE) {
                 match Block::from_pci_device(*device, blocks.len()) {
                     Ok(block) => match blocks.push(block) {
                         Ok(()) => {}
@@ -70,24 +129,76 @@ impl Block {
             return Err("MSI-X unsupported");
         }
 
-        let configuration = Configuration::from_pci_device(device)?;
-        configuration.initialize(Self::negotiate)?;
-        let requestq = Spin::new(VirtQueue::new(configuration, 0, Some(0))?);
-        configuration.set_driver_ok();
+        let transport = PciTransport::from_pci_device(device)?;
+        let mut features = 0;
+        transport.initialize(|f| {
+            features = Self::negotiate(f);
+            features
+        })?;
+        if transport.read_device_specific::<u32>(0x0).is_none() {
+            return Err("Device configuration not found");
+        }
+        let max_write_zeroes_sectors = if (features & FEATURE_WRITE_ZEROES) != 0 {
+            transport
+                .read_device_specific::<u32>(CONFIG_MAX_WRITE_ZEROES_SECTORS)
+                .unwrap_or(0)
+        } else {
+            0
+        };
+        let requestq = Spin::new(RequestQueue::new(VirtQueue::new(transport, 0, Some(0))?));
+        transport.set_driver_ok();
+        trace!(
+            "virtio: virtio-blk{} uses the {} interface",
+            index,
+            if transport.is_modern() {
+                "modern"
+            } else {
+                "legacy"
+            }
+        );
+
+        // The name of the wait channels, which lives as long as the device
+        let request_name = Box::leak(format!("virtio-blk{}.request", index).into_boxed_str());
 
-        Ok(Self {
-            configuration,
+        let block = Self {
+            transport,
             requestq,
-        })
+            request_channels: RequestChannels::new(request_name),
+            max_write_zeroes_sectors: AtomicU32::new(max_write_zeroes_sectors),
+            last_capacity: AtomicU64::new(0),
+            capacity_generation: AtomicU64::new(0),
+            counters: Default::default(),
+        };
+        block
+            .last_capacity
+            .store(block.capacity(), Ordering::Relaxed);
+        Ok(block)
     }
 
     /// Capacity of the device (expressed in `Self::SECTOR_SIZE` sectors)
     pub fn capacity(&self) -> u64 {
-        let lower = unsafe { self.configuration.read_device_specific::<u32>(0x0) } as u64;
-        let upper = unsafe { self.configuration.read_device_specific::<u32>(0x4) } as u64;
+        // The existence of the device-specific configuration is checked on initialization
+        let read = |offset| unsafe { self.transport.read_device_specific::<u32>(offset) };
+        let lower = read(0x0).unwrap_or(0) as u64;
+        let upper = read(0x4).unwrap_or(0) as u64;
         lower | (upper << 32)
     }
 
+    /// A counter bumped whenever a change of the capacity is observed. Since configuration change
+    /// notifications are not handled, the capacity is checked each time requests are collected.
+    pub fn capacity_generation(&self) -> u64 {
+        self.capacity_generation.load(Ordering::Acquire)
+    }
+
+    fn observe_capacity(&self) {
+        let capacity = self.capacity();
+        let last = self.last_capacity.swap(capacity, Ordering::AcqRel);
+        if last != capacity {
+            trace!("virtio: Capacity changed from {} to {}", last, capacity);
+            self.capacity_generation.fetch_add(1, Ordering::AcqRel);
+        }
+    }
+
     fn check_capacity(&self, sector: u64, len: usize) -> Result<(), Error> {
         let num_additional_sectors = (len.max(1) - 1) / Self::SECTOR_SIZE;
         if sector + (num_additional_sectors as u64) < self.capacity() {
@@ -97,98 +208,527 @@ impl Block {
         }
     }
 
+    /// Submit a request accessing `range` (a pair of the first sector and the number of sectors),
+    /// and wait for its completion.
     fn request(
         &self,
         header: RequestHeader,
-        body: Buffer<Option<task::WaitChannel>>,
+        body: Buffer<Option<Completion>>,
+        range: (u64, u64),
+        priority: IoPriority,
     ) -> Result<(), Error> {
         let mut footer = RequestFooter::new(0);
-        let complete_channel = task::WaitChannel::from_ptr(&footer);
+        let complete_channel = self.request_channels.acquire();
+        let (sector, len) = (header.sector, body.len);
+        let completion = Completion::Wake(complete_channel);
+        let write = header.ty != RequestHeader::IN;
 
-        let mut buffers = [
+        let buffers = [
             Buffer::from_ref(&header, None).unwrap(),
             body,
-            Buffer::from_ref_mut(&mut footer, Some(complete_channel)).unwrap(),
-        ]
-        .into_iter();
+            Buffer::from_ref_mut(&mut footer, Some(completion)).unwrap(),
+        ];
+        let request = Request::new(range.0, range.1, write, priority, buffers);
 
         let mut requestq = self.requestq.lock();
-        loop {
-            match requestq.transfer(buffers) {
-                Ok(()) => break,
-                Err(b) => {
-                    buffers = b;
-                    task::scheduler().block(self.queue_wait_channel(), None, requestq);
-                    requestq = self.requestq.lock();
-                }
-            }
+        if requestq.submit(request, ticks()) && requestq.virtqueue.needs_notification() {
+            unsafe { self.transport.notify(0) };
         }
-        unsafe { self.configuration.set_queue_notify(0) };
+        trace_event!(Category::Block, "submit sector={} len={}", sector, len);
 
         task::scheduler().block(complete_channel, None, requestq);
         fence(Ordering::SeqCst);
-        footer.into_result()
+        trace_event!(Category::Block, "complete sector={} len={}", sector, len);
+        self.request_channels.release(complete_channel);
+        let result = footer.into_result();
+        if let Err(e) = result {
+            log_rate_limited!(
+                Level::Warn,
+                (header.ty, e),
+                "virtio: Request (type = {}) at sector {} failed: {:?}",
+                header.ty,
+                sector,
+                e
+            );
+        }
+        result
     }
 
-    fn queue_wait_channel(&self) -> task::WaitChannel {
-        task::WaitChannel::from_ptr(self)
+    /// Submit the requests together: they are queued under a single lock acquisition, put into
+    /// the virtqueue in the order of the I/O scheduler with a single notification, and the caller
+    /// is woken once when all of them are completed. If the virtqueue runs out of descriptors, the
+    /// rest follow as descriptors are freed.
+    ///
+    /// The result of each request is stored in it, and the first error is returned.
+    pub fn submit_batch(&self, reqs: &mut [BlockRequest]) -> Result<(), Error> {
+        for req in reqs.iter_mut() {
+            req.result = match self.check_capacity(req.sector, req.len()) {
+                Ok(()) if req.len() == 0 => Some(Ok(())), // nothing to transfer
+                Ok(()) => None,
+                Err(e) => Some(Err(e)),
+            };
+        }
+        let headers = reqs
+            .iter()
+            .map(|req| RequestHeader::new(req.ty(), 0, req.sector))
+            .collect::<Box<[_]>>();
+        let mut footers = reqs
+            .iter()
+            .map(|_| RequestFooter::new(RequestFooter::STATUS_PENDING))
+            .collect::<Box<[_]>>();
+        // Counts down on each completion, see `Block::collect`
+        let remaining = AtomicUsize::new(0);
+        let complete_channel = self.request_channels.acquire();
+        let completion = Completion::Batch(&remaining, complete_channel);
+
+        let mut requestq = self.requestq.lock();
+        let now = ticks();
+        for (i, req) in reqs.iter_mut().enumerate() {
+            if req.result.is_some() {
+                continue;
+            }
+            let (write, body) = match req.body {
+                RequestBody::Read(ref mut buf) => (false, Buffer::from_bytes_mut(buf, None)),
+                RequestBody::Write(buf) => (true, Buffer::from_bytes(buf, None)),
+            };
+            let buffers = [
+                Buffer::from_ref(&headers[i], None).unwrap(),
+                body.unwrap(),
+                Buffer::from_ref_mut(&mut footers[i], Some(completion)).unwrap(),
+            ];
+            let num_sectors = sectors_for_bytes(req.len());
+            requestq.push(
+                Request::new(req.sector, num_sectors, write, req.priority, buffers),
+                now,
+            );
+            // The completion is not collected until requestq is unlocked
+            remaining.fetch_add(1, Ordering::SeqCst);
+        }
+        if requestq.dispatch(now) != 0 && requestq.virtqueue.needs_notification() {
+            unsafe { self.transport.notify(0) };
+        }
+        trace_event!(Category::Block, "submit batch of {}", reqs.len());
+
+        while remaining.load(Ordering::SeqCst) != 0 {
+            task::scheduler().block(complete_channel, None, requestq);
+            requestq = self.requestq.lock();
+        }
+        drop(requestq);
+        fence(Ordering::SeqCst);
+        trace_event!(Category::Block, "complete batch of {}", reqs.len());
+        self.request_channels.release(complete_channel);
+
+        let mut first_error = None;
+        for (req, footer) in reqs.iter_mut().zip(footers.iter()) {
+            let result = match req.result {
+                Some(result) => result,
+                None => {
+                    let result = footer.into_result();
+                    match result {
+                        Ok(()) => self.count(&req.body),
+                        Err(e) => log_rate_limited!(
+                            Level::Warn,
+                            e,
+                            "virtio: Batched request at sector {} failed: {:?}",
+                            req.sector,
+                            e
+                        ),
+                    }
+                    result
+                }
+            };
+            req.result = Some(result);
+            if let Err(e) = result {
+                first_error.get_or_insert(e);
+            }
+        }
+        match first_error {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
     }
 
-    /// Read data from this device.
-    pub fn read(&self, sector: u64, buf: &mut [u8]) -> Result<(), Error> {
+    fn count(&self, body: &RequestBody) {
+        let (requests, bytes) = match body {
+            RequestBody::Read(_) => (&self.counters.reads, &self.counters.bytes_read),
+            RequestBody::Write(_) => (&self.counters.writes, &self.counters.bytes_written),
+        };
+        requests.fetch_add(1, Ordering::Relaxed);
+        bytes.fetch_add(body.len() as u64, Ordering::Relaxed);
+    }
+
+    /// Read data from this device. `priority` biases the order among the requests waiting for
+    /// the device.
+    pub fn read(&self, sector: u64, buf: &mut [u8], priority: IoPriority) -> Result<(), Error> {
         self.check_capacity(sector, buf.len())?;
+        let len = buf.len();
         let header = RequestHeader::new(RequestHeader::IN, 0, sector);
         let body = Buffer::from_bytes_mut(buf, None).unwrap();
-        self.request(header, body)
+        self.request(header, body, (sector, sectors_for_bytes(len)), priority)?;
+        self.counters.reads.fetch_add(1, Ordering::Relaxed);
+        self.counters
+            .bytes_read
+            .fetch_add(len as u64, Ordering::Relaxed);
+        Ok(())
     }
 
-    /// Write data into this device.
-    pub fn write(&self, sector: u64, buf: &[u8]) -> Result<(), Error> {
+    /// Write data into this device. `priority` biases the order among the requests waiting for
+    /// the device.
+    pub fn write(&self, sector: u64, buf: &[u8], priority: IoPriority) -> Result<(), Error> {
         self.check_capacity(sector, buf.len())?;
         let header = RequestHeader::new(RequestHeader::OUT, 0, sector);
         let body = Buffer::from_bytes(buf, None).unwrap();
-        self.request(header, body)
+        let range = (sector, sectors_for_bytes(buf.len()));
+        self.request(header, body, range, priority)?;
+        self.counters.writes.fetch_add(1, Ordering::Relaxed);
+        self.counters
+            .bytes_written
+            .fetch_add(buf.len() as u64, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Fill `count` sectors from `sector` with zeros. This is done by a write zeroes command per
+    /// range if the device offers VIRTIO_BLK_F_WRITE_ZEROES, and by ordinary writes otherwise.
+    pub fn write_zeros(&self, sector: u64, count: u64) -> Result<(), Error> {
+        match sector.checked_add(count) {
+            Some(end) if end <= self.capacity() => {}
+            _ => Err(Error::OutOfRange)?,
+        }
+        let (mut sector, mut count) = (sector, count);
+        while count != 0 {
+            let max = self.max_write_zeroes_sectors.load(Ordering::Relaxed) as u64;
+            if max == 0 {
+                break;
+            }
+            let n = count.min(max);
+            match self.write_zeroes_command(sector, n) {
+                Ok(()) => {
+                    sector += n;
+                    count -= n;
+                }
+                Err(Error::Unsupported) => {
+                    trace!("virtio: Write zeroes is rejected, falling back to writes");
+                    self.max_write_zeroes_sectors.store(0, Ordering::Relaxed);
+                }
+                Err(e) => Err(e)?,
+            }
+        }
+        while count != 0 {
+            let n = count.min(ZEROS_SECTORS as u64);
+            let zeros = &ZEROS.0[..n as usize * Self::SECTOR_SIZE];
+            self.write(sector, zeros, IoPriority::Bulk)?;
+            sector += n;
+            count -= n;
+        }
+        Ok(())
+    }
+
+    fn write_zeroes_command(&self, sector: u64, count: u64) -> Result<(), Error> {
+        // The sector of the header is unused for commands other than IN and OUT
+        let header = RequestHeader::new(RequestHeader::WRITE_ZEROES, 0, 0);
+        let segment = WriteZeroesSegment::new(sector, count as u32, 0);
+        let body = Buffer::from_ref(&segment, None).unwrap();
+        self.request(header, body, (sector, count), IoPriority::Bulk)?;
+        self.counters.writes.fetch_add(1, Ordering::Relaxed);
+        self.counters
+            .bytes_written
+            .fetch_add(count * Self::SECTOR_SIZE as u64, Ordering::Relaxed);
+        Ok(())
+    }
+
+    pub fn queue_stats(&self) -> QueueStats {
+        let requestq = self.requestq.lock();
+        requestq.elevator.stats(requestq.in_flight)
+    }
+
+    pub fn stats(&self) -> Stats {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        Stats {
+            reads: load(&self.counters.reads),
+            writes: load(&self.counters.writes),
+            bytes_read: load(&self.counters.bytes_read),
+            bytes_written: load(&self.counters.bytes_written),
+        }
+    }
+
+    /// Write data into this device by polling the completion, without the task scheduler.
+    /// This is intended for situations where tasks cannot be blocked, such as kernel panics.
+    /// Completions of other in-flight requests collected while polling are discarded.
+    ///
+    /// Caller must not resume the normal operation after this method failed with
+    /// `Error::Timeout`, since the device may still access the request afterwards.
+    pub unsafe fn write_polling(&self, sector: u64, buf: &[u8]) -> Result<(), Error> {
+        const MAX_POLLS: usize = 100_000_000;
+        self.check_capacity(sector, buf.len())?;
+        let header = RequestHeader::new(RequestHeader::OUT, 0, sector);
+        let mut footer = RequestFooter::new(RequestFooter::STATUS_PENDING);
+        let buffers = [
+            Buffer::from_ref(&header, None).unwrap(),
+            Buffer::from_bytes(buf, None).unwrap(),
+            Buffer::from_ref_mut(&mut footer, None).unwrap(),
+        ];
+
+        // The request queue may be locked by the code being interrupted
+        let mut requestq = self.requestq.try_lock().ok_or(Error::Busy)?;
+        requestq
+            .virtqueue
+            .transfer(buffers.into_iter())
+            .map_err(|_| Error::Busy)?;
+        self.transport.notify(0);
+        for _ in 0..MAX_POLLS {
+            requestq.virtqueue.collect(|_| {});
+            if ptr::read_volatile(&footer.status) != RequestFooter::STATUS_PENDING {
+                fence(Ordering::SeqCst);
+                return footer.into_result();
+            }
+            core::hint::spin_loop();
+        }
+        Err(Error::Timeout)
     }
 
     /// Collect the processed requests.
     /// This method is supposed to be called from Used Buffer Notification (interrupt).
     pub fn collect(&self) {
+        // Before waking the tasks, so that they see the capacity of the completed requests
+        self.observe_capacity();
         let mut requestq = self.requestq.lock();
-        requestq.collect(|chan| {
-            if let Some(chan) = chan {
-                task::scheduler().release(chan);
+        let notify = requestq.collect(ticks(), |completion| match completion {
+            Completion::Wake(chan) => task::scheduler().release(chan),
+            Completion::Batch(remaining, chan) => {
+                // The batch is alive until the remaining count reaches zero
+                if unsafe { &*remaining }.fetch_sub(1, Ordering::SeqCst) == 1 {
+                    task::scheduler().release(chan);
+                }
             }
         });
-        task::scheduler().release(self.queue_wait_channel());
+        if notify {
+            unsafe { self.transport.notify(0) };
+        }
     }
 
-    fn negotiate(features: u32) -> u32 {
+    fn negotiate(features: u64) -> u64 {
         // TODO: Understand the detailed semantics of these features
         // Currently we only support features that are enabled in xv6-riscv
-        const RO: u32 = 1 << 5;
-        const SCSI: u32 = 1 << 7;
-        const CONFIG_WCE: u32 = 1 << 11;
-        const MQ: u32 = 1 << 12;
-        const ANY_LAYOUT: u32 = 1 << 27;
+        const RO: u64 = 1 << 5;
+        const SCSI: u64 = 1 << 7;
+        const CONFIG_WCE: u64 = 1 << 11;
+        const MQ: u64 = 1 << 12;
+        const ANY_LAYOUT: u64 = 1 << 27;
         features & !RO & !SCSI & !CONFIG_WCE & !MQ & !ANY_LAYOUT
     }
 
     pub const SECTOR_SIZE: usize = 512;
 }
 
+/// Number of sectors touched by `len` bytes.
+fn sectors_for_bytes(len: usize) -> u64 {
+    ((len + Block::SECTOR_SIZE - 1) / Block::SECTOR_SIZE) as u64
+}
+
+/// A freelist of the channels for waiting for the completions of requests of a device.
+#[derive(Debug)]
+struct RequestChannels {
+    free: Spin<Vec<task::WaitChannel, NUM_REQUEST_CHANNELS>>,
+    name: &'static str,
+}
+
+impl RequestChannels {
+    fn new(name: &'static str) -> Self {
+        let mut free = Vec::new();
+        while !free.is_full() {
+            let _ = free.push(task::scheduler().issue_named_wait_channel(name));
+        }
+        Self {
+            free: Spin::new(free),
+            name,
+        }
+    }
+
+    /// Take a channel for waiting for the completion of a request.
+    /// Each in-flight request owns a distinct channel until `release` is called, so a completion
+    /// never wakes a task waiting for another request.
+    fn acquire(&self) -> task::WaitChannel {
+        match self.free.lock().pop() {
+            Some(chan) => chan,
+            None => task::scheduler().issue_named_wait_channel(self.name),
+        }
+    }
+
+    fn release(&self, chan: task::WaitChannel) {
+        // Channels that do not fit in the freelist are simply discarded
+        let _ = self.free.lock().push(chan);
+    }
+}
+
+/// The descriptor chain of a request.
+type Chain = [Buffer<Option<Completion>>; DESCRIPTORS_PER_REQUEST];
+
+/// The virtqueue of the requests, in front of which the requests wait for descriptors in the
+/// order of the I/O scheduler.
+#[derive(Debug)]
+struct RequestQueue {
+    virtqueue: VirtQueue<Option<Completion>>,
+    elevator: Elevator<Chain>,
+    /// Requests put into `virtqueue` through `elevator`.
+    in_flight: usize,
+}
+
+impl RequestQueue {
+    fn new(virtqueue: VirtQueue<Option<Completion>>) -> Self {
+        Self {
+            virtqueue,
+            elevator: Elevator::new(),
+            in_flight: 0,
+        }
+    }
+
+    fn has_room(&self) -> bool {
+        DESCRIPTORS_PER_REQUEST <= self.virtqueue.num_free_descriptors()
+    }
+
+    fn transfer(&mut self, chain: Chain) {
+        let result = self.virtqueue.transfer(chain.into_iter());
+        assert!(result.is_ok(), "virtio: Not enough descriptors");
+        self.in_flight += 1;
+    }
+
+    /// Put the request into the virtqueue, or queue it if other requests are waiting or there is
+    /// no room. Returns whether any request is put into the virtqueue.
+    fn submit(&mut self, request: Request<Chain>, now: usize) -> bool {
+        if self.elevator.is_empty() && self.has_room() {
+            // Nothing to order
+            let chain = self.elevator.bypass(request);
+            self.transfer(chain);
+            true
+        } else {
+            self.elevator.push(request, now);
+            self.dispatch(now) != 0
+        }
+    }
+
+    fn push(&mut self, request: Request<Chain>, now: usize) {
+        self.elevator.push(request, now);
+    }
+
+    /// Collect the completed requests, and fill the freed descriptors with the pending requests.
+    /// Returns whether the device must be notified.
+    fn collect(&mut self, now: usize, mut complete: impl FnMut(Completion)) -> bool {
+        let mut completed = 0;
+        self.virtqueue.collect(|completion| {
+            if let Some(completion) = completion {
+                completed += 1;
+                complete(completion);
+            }
+        });
+        self.in_flight = self.in_flight.saturating_sub(completed);
+        self.dispatch(now) != 0 && self.virtqueue.needs_notification()
+    }
+
+    /// Move as many pending requests as possible into the virtqueue, returning the number of
+    /// them. The caller must notify the device if any, unless the device suppresses it.
+    fn dispatch(&mut self, now: usize) -> usize {
+        let mut n = 0;
+        while self.has_room() {
+            match self.elevator.pop(now) {
+                Some(chain) => self.transfer(chain),
+                None => break,
+            }
+            n += 1;
+        }
+        n
+    }
+}
+
 unsafe impl Sync for Block {}
 
 unsafe impl Send for Block {}
 
-#[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy)]
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy, Hash)]
 #[non_exhaustive]
 pub enum Error {
     Io,
     Unsupported,
     OutOfRange,
+    Busy,
+    Timeout,
     Unknown,
 }
 
+/// What to do on the completion of a request, associated with its footer.
+#[derive(Debug, Clone, Copy)]
+enum Completion {
+    /// Wake the task waiting on the channel.
+    Wake(task::WaitChannel),
+    /// Count down the remaining requests of a batch, and wake the task waiting on the channel
+    /// after the last one.
+    Batch(*const AtomicUsize, task::WaitChannel),
+}
+
+/// A request of `Block::submit_batch`.
+#[derive(Debug)]
+pub struct BlockRequest<'a> {
+    sector: u64,
+    body: RequestBody<'a>,
+    priority: IoPriority,
+    result: Option<Result<(), Error>>,
+}
+
+#[derive(Debug)]
+enum RequestBody<'a> {
+    Read(&'a mut [u8]),
+    Write(&'a [u8]),
+}
+
+impl<'a> RequestBody<'a> {
+    fn len(&self) -> usize {
+        match self {
+            Self::Read(buf) => buf.len(),
+            Self::Write(buf) => buf.len(),
+        }
+    }
+}
+
+impl<'a> BlockRequest<'a> {
+    pub fn read(sector: u64, buf: &'a mut [u8]) -> Self {
+        Self {
+            sector,
+            body: RequestBody::Read(buf),
+            priority: IoPriority::Normal,
+            result: None,
+        }
+    }
+
+    pub fn write(sector: u64, buf: &'a [u8]) -> Self {
+        Self {
+            sector,
+            body: RequestBody::Write(buf),
+            priority: IoPriority::Normal,
+            result: None,
+        }
+    }
+
+    /// Set the priority of the request, which is `IoPriority::Normal` by default.
+    pub fn with_priority(self, priority: IoPriority) -> Self {
+        Self { priority, ..self }
+    }
+
+    /// The result of the request, or `None` if it has not been submitted yet.
+    pub fn result(&self) -> Option<Result<(), Error>> {
+        self.result
+    }
+
+    fn len(&self) -> usize {
+        self.body.len()
+    }
+
+    fn ty(&self) -> u32 {
+        match self.body {
+            RequestBody::Read(_) => RequestHeader::IN,
+            RequestBody::Write(_) => RequestHeader::OUT,
+        }
+    }
+}
+
 #[repr(C)]
 #[derive(Debug, new)]
 struct RequestHeader {
@@ -200,10 +740,19 @@ struct RequestHeader {
 impl RequestHeader {
     const IN: u32 = 0;
     const OUT: u32 = 1;
+    const WRITE_ZEROES: u32 = 13;
 }
 
 #[repr(C)]
 #[derive(Debug, new)]
+struct WriteZeroesSegment {
+    sector: u64,
+    num_sectors: u32,
+    flags: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, new)]
 struct RequestFooter {
     status: u8,
 }
@@ -221,4 +770,424 @@ impl RequestFooter {
     const STATUS_OK: u8 = 0;
     const STATUS_IOERR: u8 = 1;
     const STATUS_UNSUPP: u8 = 2;
+    /// Not a status defined by the specification, used to detect the completion by polling.
+    const STATUS_PENDING: u8 = 0xff;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::virtio::mock::{MockBlock, MockVirtioDevice};
+    use crate::task::Priority;
+    use alloc::vec;
+    use alloc::vec::Vec;
+    use log::info;
+
+    const NUM_SECTORS: usize = 64;
+
+    /// A device whose virtqueue has room for 2 requests.
+    fn setup() -> (MockVirtioDevice<MockBlock>, RequestQueue) {
+        let mut config = vec![0; 0x34];
+        config[0..8].copy_from_slice(&(NUM_SECTORS as u64).to_le_bytes());
+        config[0x30..0x34].copy_from_slice(&16u32.to_le_bytes());
+        let features = FEATURE_WRITE_ZEROES | (1 << 5);
+        let device = MockVirtioDevice::new(8, features, config, MockBlock::new(NUM_SECTORS));
+        let transport = device.transport();
+        unsafe { transport.initialize(Block::negotiate) }.unwrap();
+        let virtqueue = unsafe { VirtQueue::new(transport, 0, Some(0)) }.unwrap();
+        unsafe { transport.set_driver_ok() };
+        (device, RequestQueue::new(virtqueue))
+    }
+
+    /// A request with its buffers, which must be alive until the completion.
+    struct TestRequest {
+        header: RequestHeader,
+        body: Vec<u8>,
+        footer: RequestFooter,
+        /// The pair of the first sector and the number of sectors.
+        range: (u64, u64),
+    }
+
+    impl TestRequest {
+        fn new(ty: u32, sector: u64, body: Vec<u8>) -> Box<Self> {
+            let range = (sector, sectors_for_bytes(body.len()));
+            Box::new(Self {
+                header: RequestHeader::new(ty, 0, sector),
+                body,
+                footer: RequestFooter::new(RequestFooter::STATUS_PENDING),
+                range,
+            })
+        }
+
+        fn read(sector: u64, num_sectors: usize) -> Box<Self> {
+            Self::new(RequestHeader::IN, sector, vec![0; num_sectors * 512])
+        }
+
+        fn write(sector: u64, num_sectors: usize, fill: u8) -> Box<Self> {
+            Self::new(RequestHeader::OUT, sector, vec![fill; num_sectors * 512])
+        }
+
+        fn write_zeroes(sector: u64, num_sectors: u64) -> Box<Self> {
+            let mut body = Vec::new();
+            body.extend_from_slice(&sector.to_le_bytes());
+            body.extend_from_slice(&(num_sectors as u32).to_le_bytes());
+            body.extend_from_slice(&0u32.to_le_bytes());
+            let mut req = Self::new(RequestHeader::WRITE_ZEROES, 0, body);
+            req.range = (sector, num_sectors);
+            req
+        }
+
+        fn request(&mut self, priority: IoPriority) -> Request<Chain> {
+            self.request_waking(priority, task::scheduler().issue_wait_channel())
+        }
+
+        /// Same as `request`, but the completion wakes the tasks waiting on `chan`.
+        fn request_waking(
+            &mut self,
+            priority: IoPriority,
+            chan: task::WaitChannel,
+        ) -> Request<Chain> {
+            self.request_completing(priority, Completion::Wake(chan))
+        }
+
+        fn request_completing(
+            &mut self,
+            priority: IoPriority,
+            completion: Completion,
+        ) -> Request<Chain> {
+            let write = self.header.ty != RequestHeader::IN;
+            let body = match write {
+                true => Buffer::from_bytes(&self.body, None),
+                false => Buffer::from_bytes_mut(&mut self.body, None),
+            };
+            let buffers = [
+                Buffer::from_ref(&self.header, None).unwrap(),
+                body.unwrap(),
+                Buffer::from_ref_mut(&mut self.footer, Some(completion)).unwrap(),
+            ];
+            Request::new(self.range.0, self.range.1, write, priority, buffers)
+        }
+
+        fn result(&self) -> Option<Result<(), Error>> {
+            match unsafe { ptr::read_volatile(&self.footer.status) } {
+                RequestFooter::STATUS_PENDING => None,
+                _ => Some(self.footer.into_result()),
+            }
+        }
+    }
+
+    /// Serve the requests as the device and the interrupt handler would, notifying the device
+    /// only when the driver decides to. Returns the number of completions.
+    fn drain(device: &MockVirtioDevice<MockBlock>, q: &mut RequestQueue, notify: bool) -> usize {
+        let mut notify = notify;
+        let mut completed = 0;
+        while q.in_flight != 0 {
+            if notify {
+                unsafe { device.transport().notify(0) };
+            }
+            assert!(device.run() != 0, "Requests are lost");
+            notify = q.collect(0, |_| completed += 1);
+        }
+        assert!(q.elevator.is_empty());
+        completed
+    }
+
+    /// Submit the requests one by one, and wait for all of them.
+    fn run(
+        device: &MockVirtioDevice<MockBlock>,
+        q: &mut RequestQueue,
+        reqs: &mut [Box<TestRequest>],
+    ) {
+        let mut notify = false;
+        for req in reqs.iter_mut() {
+            notify |=
+                q.submit(req.request(IoPriority::Normal), 0) && q.virtqueue.needs_notification();
+        }
+        assert_eq!(drain(device, q, notify), reqs.len());
+    }
+
+    #[test_case]
+    fn test_block_mock_requests() {
+        info!("TESTING devices::virtio::block::test_block_mock_requests");
+        let (device, mut q) = setup();
+        assert!(device.is_driver_ok());
+        assert_eq!(device.driver_features(), FEATURE_WRITE_ZEROES);
+
+        let mut reqs = [
+            TestRequest::write(3, 2, 0xaa),
+            TestRequest::write(NUM_SECTORS as u64 - 1, 2, 0xbb),
+            TestRequest::new(0xff, 0, vec![0; 512]),
+        ];
+        run(&device, &mut q, &mut reqs);
+        assert_eq!(reqs[0].result(), Some(Ok(())));
+        assert_eq!(reqs[1].result(), Some(Err(Error::Io)));
+        assert_eq!(reqs[2].result(), Some(Err(Error::Unsupported)));
+
+        device.with_personality(|b| b.faulty_sectors.push(2));
+        let mut reqs = [
+            TestRequest::read(3, 2),
+            TestRequest::read(1, 2),
+            TestRequest::write_zeroes(4, 1),
+        ];
+        run(&device, &mut q, &mut reqs);
+        assert_eq!(reqs[0].result(), Some(Ok(())));
+        assert!(reqs[0].body.iter().all(|b| *b == 0xaa));
+        assert_eq!(reqs[1].result(), Some(Err(Error::Io)));
+        assert_eq!(reqs[2].result(), Some(Ok(())));
+        device.with_personality(|b| {
+            assert!(b.sector(3).iter().all(|b| *b == 0xaa));
+            assert!(b.sector(4).iter().all(|b| *b == 0));
+            assert!(b.sector(NUM_SECTORS as u64 - 1).iter().all(|b| *b == 0));
+        });
+    }
+
+    #[test_case]
+    fn test_block_mock_batching() {
+        info!("TESTING devices::virtio::block::test_block_mock_batching");
+        let (device, mut q) = setup();
+        device.set_reorder(true);
+
+        // More requests than the descriptors: the rest wait in the elevator
+        let mut writes = (0..6)
+            .map(|i| TestRequest::write(i * 8, 8, i as u8 + 1))
+            .collect::<Vec<_>>();
+        for req in writes.iter_mut() {
+            q.push(req.request(IoPriority::Normal), 0);
+        }
+        assert_eq!(q.dispatch(0), 2);
+        assert_eq!(q.elevator.stats(q.in_flight).pending, 4);
+        assert!(q.virtqueue.needs_notification());
+        assert_eq!(drain(&device, &mut q, true), 6);
+        assert!(writes.iter().all(|req| req.result() == Some(Ok(()))));
+
+        let mut reads = (0..6)
+            .map(|i| TestRequest::read(i * 8, 8))
+            .collect::<Vec<_>>();
+        run(&device, &mut q, &mut reads);
+        for (i, req) in reads.iter().enumerate() {
+            assert_eq!(req.result(), Some(Ok(())));
+            assert!(req.body.iter().all(|b| *b == i as u8 + 1));
+        }
+        assert_eq!(q.virtqueue.num_free_descriptors(), 8);
+    }
+
+    #[test_case]
+    fn test_block_mock_batch_completion() {
+        info!("TESTING devices::virtio::block::test_block_mock_batch_completion");
+        let (device, mut q) = setup();
+        device.set_reorder(true);
+        device.with_personality(|b| b.faulty_sectors.push(17));
+
+        // Queued at once and dispatched together, as `Block::submit_batch` does
+        let remaining = AtomicUsize::new(0);
+        let chan = task::scheduler().issue_wait_channel();
+        let mut reqs = (0..6)
+            .map(|i| TestRequest::write(i * 8, 8, i as u8 + 1))
+            .collect::<Vec<_>>();
+        for req in reqs.iter_mut() {
+            let completion = Completion::Batch(&remaining, chan);
+            q.push(req.request_completing(IoPriority::Normal, completion), 0);
+            remaining.fetch_add(1, Ordering::SeqCst);
+        }
+        assert_eq!(q.dispatch(0), 2);
+        assert!(q.virtqueue.needs_notification());
+
+        // Counted down on each completion, and the waiter is woken only after the last one
+        let (mut completed, mut wakes) = (0, 0);
+        let mut notify = true;
+        while q.in_flight != 0 {
+            if notify {
+                unsafe { device.transport().notify(0) };
+            }
+            assert!(device.run() != 0, "Requests are lost");
+            notify = q.collect(0, |completion| match completion {
+                Completion::Batch(r, c) => {
+                    assert_eq!(c, chan);
+                    completed += 1;
+                    if unsafe { &*r }.fetch_sub(1, Ordering::SeqCst) == 1 {
+                        wakes += 1;
+                    }
+                }
+                Completion::Wake(_) => unreachable!(),
+            });
+        }
+        assert_eq!(completed, 6);
+        assert_eq!(wakes, 1);
+        assert_eq!(remaining.load(Ordering::SeqCst), 0);
+
+        // Every request has its own result
+        for (i, req) in reqs.iter().enumerate() {
+            match i {
+                2 => assert_eq!(req.result(), Some(Err(Error::Io))),
+                _ => assert_eq!(req.result(), Some(Ok(()))),
+            }
+        }
+        device.with_personality(|b| {
+            for i in 0..6 {
+                let fill = if i == 2 { 0 } else { i as u8 + 1 };
+                assert!(b.sector(i * 8).iter().all(|b| *b == fill));
+            }
+        });
+        assert_eq!(q.virtqueue.num_free_descriptors(), 8);
+    }
+
+    #[test_case]
+    fn test_block_mock_notification_suppression() {
+        info!("TESTING devices::virtio::block::test_block_mock_notification_suppression");
+        let (device, mut q) = setup();
+        device.set_delay(2);
+
+        let mut a = TestRequest::write(0, 1, 1);
+        let mut b = TestRequest::write(1, 1, 2);
+        let mut c = TestRequest::write(2, 1, 3);
+        assert!(q.submit(a.request(IoPriority::Normal), 0));
+        assert!(q.virtqueue.needs_notification());
+        unsafe { device.transport().notify(0) };
+
+        // Submitted while the device is working on the first request
+        assert!(device.begin());
+        assert_eq!(device.fetch(), 1);
+        assert!(q.submit(b.request(IoPriority::Normal), 0));
+        assert!(!q.virtqueue.needs_notification());
+        assert_eq!(device.complete(), 0);
+        assert_eq!(device.fetch(), 1);
+        assert_eq!(device.complete(), 0);
+        assert_eq!(device.complete(), 1);
+        assert!(!q.collect(0, |_| {}));
+        assert!(q.submit(c.request(IoPriority::Normal), 0));
+        assert!(!q.virtqueue.needs_notification());
+        assert_eq!(device.complete(), 1);
+
+        // The device finds the last request, whose notification was skipped, on its re-check
+        assert!(device.end());
+        assert_eq!(device.fetch(), 1);
+        assert_eq!(device.complete() + device.complete() + device.complete(), 1);
+        assert!(!device.end());
+        assert!(!q.collect(0, |_| {}));
+        assert_eq!(q.in_flight, 0);
+        for (req, fill) in [(a, 1), (b, 2), (c, 3)] {
+            assert_eq!(req.result(), Some(Ok(())));
+            device.with_personality(|d| assert_eq!(d.sector(fill - 1)[0], fill as u8));
+        }
+        assert_eq!(device.notifications(), 1);
+    }
+
+    /// A device shared by the tasks of `test_block_mock_channel_recycling`, served in the same
+    /// way as `Block`.
+    struct SharedDevice {
+        device: MockVirtioDevice<MockBlock>,
+        requestq: Spin<RequestQueue>,
+        channels: RequestChannels,
+    }
+
+    impl SharedDevice {
+        fn new(name: &'static str) -> &'static Self {
+            let (device, requestq) = setup();
+            Box::leak(Box::new(Self {
+                device,
+                requestq: Spin::new(requestq),
+                channels: RequestChannels::new(name),
+            }))
+        }
+
+        /// Submit the request and wait for its completion, as `Block::request` does.
+        fn transfer(&self, req: &mut TestRequest) {
+            let chan = self.channels.acquire();
+            let mut requestq = self.requestq.lock();
+            let request = req.request_waking(IoPriority::Normal, chan);
+            if requestq.submit(request, ticks()) && requestq.virtqueue.needs_notification() {
+                unsafe { self.device.transport().notify(0) };
+            }
+            loop {
+                task::scheduler().block(chan, None, requestq);
+                requestq = self.requestq.lock();
+                if req.result().is_some() {
+                    break;
+                }
+                // Woken by the completion of another request
+                RECYCLING_MISWAKES.fetch_add(1, Ordering::SeqCst);
+            }
+            drop(requestq);
+            self.channels.release(chan);
+        }
+
+        /// Process the notified requests and collect them, as the interrupt handler does.
+        fn serve(&self) {
+            self.device.run();
+            let mut requestq = self.requestq.lock();
+            let notify = requestq.collect(ticks(), |completion| match completion {
+                Completion::Wake(chan) => task::scheduler().release(chan),
+                Completion::Batch(_, _) => unreachable!(),
+            });
+            if notify {
+                unsafe { self.device.transport().notify(0) };
+            }
+        }
+    }
+
+    /// Workers per device, more than the requests that fit in the virtqueue.
+    const RECYCLING_WORKERS: u64 = 4;
+    const RECYCLING_ROUNDS: usize = 50;
+    static RECYCLING_DONE: AtomicU64 = AtomicU64::new(0);
+    static RECYCLING_MISWAKES: AtomicU64 = AtomicU64::new(0);
+    static RECYCLING_FAILURES: AtomicU64 = AtomicU64::new(0);
+
+    struct RecyclingWorker {
+        device: &'static SharedDevice,
+        sector: u64,
+    }
+
+    extern "C" fn recycle_requests(arg: u64) -> ! {
+        let worker = unsafe { Box::from_raw(arg as *mut RecyclingWorker) };
+        for round in 0..RECYCLING_ROUNDS {
+            // Distinct among the workers of both devices within a round
+            let fill = (round as u64 * 2 * RECYCLING_WORKERS + worker.sector) as u8;
+            let mut write = TestRequest::write(worker.sector, 1, fill);
+            worker.device.transfer(&mut write);
+            let mut read = TestRequest::read(worker.sector, 1);
+            worker.device.transfer(&mut read);
+            if write.result() != Some(Ok(()))
+                || read.result() != Some(Ok(()))
+                || read.body.iter().any(|b| *b != fill)
+            {
+                RECYCLING_FAILURES.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+        RECYCLING_DONE.fetch_add(1, Ordering::SeqCst);
+        drop(worker);
+        task::scheduler().exit()
+    }
+
+    #[test_case]
+    fn test_block_mock_channel_recycling() {
+        info!("TESTING devices::virtio::block::test_block_mock_channel_recycling");
+        let devices = [
+            SharedDevice::new("test.blk0.request"),
+            SharedDevice::new("test.blk1.request"),
+        ];
+        // Request channels are recycled through the freelist of each device, and the footers
+        // are reused at the same stack addresses of the workers
+        for (i, device) in devices.iter().enumerate() {
+            for w in 0..RECYCLING_WORKERS {
+                let worker = Box::new(RecyclingWorker {
+                    device,
+                    sector: i as u64 * RECYCLING_WORKERS + w,
+                });
+                let arg = Box::into_raw(worker) as u64;
+                task::scheduler().add(Priority::MAX, recycle_requests, arg);
+            }
+        }
+        while RECYCLING_DONE.load(Ordering::SeqCst) < 2 * RECYCLING_WORKERS {
+            for device in devices.iter() {
+                device.serve();
+            }
+            task::scheduler().sleep(1);
+        }
+        assert_eq!(RECYCLING_MISWAKES.load(Ordering::SeqCst), 0);
+        assert_eq!(RECYCLING_FAILURES.load(Ordering::SeqCst), 0);
+        for device in devices.iter() {
+            assert_eq!(device.requestq.lock().in_flight, 0);
+            assert_eq!(device.channels.free.lock().len(), NUM_REQUEST_CHANNELS);
+        }
+    }
 }
@@ -0,0 +1,362 @@
+//! Ordering of the block requests waiting for the descriptors of the virtqueue.
+//!
+//! Pending requests are served in ascending order of sectors from the end of the last served
+//! request, wrapping around at the end (C-SCAN), with higher priorities first. A request pending
+//! for `MAX_PENDING_TICKS` is served before anything else, so that no request starves. A request
+//! is never served before an earlier request that overlaps it unless both of them are reads.
+//!
+//! Requests already in the virtqueue may be completed in any order by the device, thus callers
+//! that depend on the order of their requests must wait for the completion of each of them.
+
+use crate::interrupts::TIMER_FREQ;
+use alloc::vec::Vec;
+
+/// A request pending for this duration (in ticks) is served first.
+pub const MAX_PENDING_TICKS: usize = TIMER_FREQ / 4;
+
+/// The class of a request, which biases the order of pending requests.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy, Hash)]
+pub enum IoPriority {
+    /// Transfers nobody is eagerly waiting for, such as zero fills.
+    Bulk,
+    /// Ordinary reads and writes.
+    Normal,
+    /// Metadata and commits, which other operations wait on.
+    Metadata,
+}
+
+impl IoPriority {
+    pub const COUNT: usize = 3;
+
+    pub fn iter() -> impl Iterator<Item = Self> {
+        [Self::Bulk, Self::Normal, Self::Metadata].into_iter()
+    }
+
+    pub fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// The time that requests spent in the pending queue, per priority.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub struct WaitStats {
+    /// Number of served requests, including the ones served without waiting.
+    pub count: u64,
+    pub total_ticks: u64,
+    pub max_ticks: u64,
+}
+
+impl WaitStats {
+    fn record(&mut self, ticks: usize) {
+        self.count += 1;
+        self.total_ticks += ticks as u64;
+        self.max_ticks = self.max_ticks.max(ticks as u64);
+    }
+}
+
+/// Snapshot of the queue of a device.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub struct QueueStats {
+    /// Requests waiting for descriptors.
+    pub pending: usize,
+    /// Requests in the virtqueue.
+    pub in_flight: usize,
+    /// The largest `pending` observed since boot.
+    pub max_pending: usize,
+    /// Indexed by `IoPriority::index`.
+    pub waits: [WaitStats; IoPriority::COUNT],
+}
+
+/// A request given to `Elevator`. `payload` is what is actually put into the virtqueue.
+#[derive(Debug)]
+pub(super) struct Request<T> {
+    sector: u64,
+    end: u64,
+    write: bool,
+    priority: IoPriority,
+    payload: T,
+}
+
+impl<T> Request<T> {
+    pub(super) fn new(
+        sector: u64,
+        num_sectors: u64,
+        write: bool,
+        priority: IoPriority,
+        payload: T,
+    ) -> Self {
+        Self {
+            sector,
+            end: sector.saturating_add(num_sectors),
+            write,
+            priority,
+            payload,
+        }
+    }
+
+    /// Whether the order of the requests must be kept.
+    fn conflicts(&self, other: &Self) -> bool {
+        (self.write || other.write) && self.sector < other.end && other.sector < self.end
+    }
+}
+
+#[derive(Debug)]
+struct Pending<T> {
+    seq: u64,
+    queued_at: usize,
+    request: Request<T>,
+}
+
+#[derive(Debug)]
+pub(super) struct Elevator<T> {
+    /// Sorted by sector, and then by the submission order.
+    pending: Vec<Pending<T>>,
+    /// Where the elevator is. The end of the last served request.
+    head: u64,
+    next_seq: u64,
+    max_pending: usize,
+    waits: [WaitStats; IoPriority::COUNT],
+}
+
+impl<T> Elevator<T> {
+    pub(super) fn new() -> Self {
+        Self {
+            pending: Vec::new(),
+            head: 0,
+            next_seq: 0,
+            max_pending: 0,
+            waits: [WaitStats::default(); IoPriority::COUNT],
+        }
+    }
+
+    pub(super) fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    pub(super) fn push(&mut self, request: Request<T>, now: usize) {
+        let i = self
+            .pending
+            .partition_point(|p| p.request.sector <= request.sector);
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.pending.insert(
+            i,
+            Pending {
+                seq,
+                queued_at: now,
+                request,
+            },
+        );
+        self.max_pending = self.max_pending.max(self.pending.len());
+    }
+
+    /// Record a request served without being pending. Only valid when nothing is pending.
+    pub(super) fn bypass(&mut self, request: Request<T>) -> T {
+        debug_assert!(self.is_empty());
+        self.head = request.end;
+        self.waits[request.priority.index()].record(0);
+        request.payload
+    }
+
+    /// Take the request to be served next.
+    pub(super) fn pop(&mut self, now: usize) -> Option<T> {
+        let i = self.next_index(now)?;
+        let p = self.pending.remove(i);
+        self.head = p.request.end;
+        self.waits[p.request.priority.index()].record(now.wrapping_sub(p.queued_at));
+        Some(p.request.payload)
+    }
+
+    fn next_index(&self, now: usize) -> Option<usize> {
+        // The oldest request never conflicts with earlier ones
+        let (oldest, p) = self.pending.iter().enumerate().min_by_key(|(_, p)| p.seq)?;
+        if MAX_PENDING_TICKS <= now.wrapping_sub(p.queued_at) {
+            return Some(oldest);
+        }
+        let n = self.pending.len();
+        let start = self
+            .pending
+            .partition_point(|p| p.request.sector < self.head);
+        let mut next: Option<usize> = None;
+        for i in (start..n).chain(0..start) {
+            let priority = self.pending[i].request.priority;
+            if next.map_or(true, |j| self.pending[j].request.priority < priority)
+                && self.is_ready(i)
+            {
+                next = Some(i);
+            }
+        }
+        next
+    }
+
+    /// Whether no earlier pending requests conflict with the `i`-th one.
+    fn is_ready(&self, i: usize) -> bool {
+        let p = &self.pending[i];
+        self.pending
+            .iter()
+            .all(|q| p.seq <= q.seq || !p.request.conflicts(&q.request))
+    }
+
+    pub(super) fn stats(&self, in_flight: usize) -> QueueStats {
+        QueueStats {
+            pending: self.pending.len(),
+            in_flight,
+            max_pending: self.max_pending,
+            waits: self.waits,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::collections::VecDeque;
+    use alloc::vec;
+    use log::info;
+
+    const R: bool = false;
+    const W: bool = true;
+
+    /// A device that serves up to `slots` requests at once, each of which takes a tick.
+    struct MockDevice {
+        slots: usize,
+        elevator: Elevator<u64>,
+        in_flight: VecDeque<u64>,
+        served: Vec<u64>,
+        now: usize,
+    }
+
+    impl MockDevice {
+        fn new(slots: usize) -> Self {
+            Self {
+                slots,
+                elevator: Elevator::new(),
+                in_flight: VecDeque::new(),
+                served: Vec::new(),
+                now: 0,
+            }
+        }
+
+        fn submit(&mut self, sector: u64, len: u64, write: bool, priority: IoPriority) {
+            let request = Request::new(sector, len, write, priority, sector);
+            if self.elevator.is_empty() && self.in_flight.len() < self.slots {
+                let sector = self.elevator.bypass(request);
+                self.in_flight.push_back(sector);
+            } else {
+                self.elevator.push(request, self.now);
+                self.dispatch();
+            }
+        }
+
+        fn dispatch(&mut self) {
+            while self.in_flight.len() < self.slots {
+                match self.elevator.pop(self.now) {
+                    Some(sector) => self.in_flight.push_back(sector),
+                    None => break,
+                }
+            }
+        }
+
+        fn tick(&mut self) {
+            self.now += 1;
+            if let Some(sector) = self.in_flight.pop_front() {
+                self.served.push(sector);
+            }
+            self.dispatch();
+        }
+
+        fn drain(&mut self) -> Vec<u64> {
+            while !self.in_flight.is_empty() {
+                self.tick();
+            }
+            core::mem::take(&mut self.served)
+        }
+    }
+
+    #[test_case]
+    fn test_elevator_order() {
+        info!("TESTING devices::virtio::block::iosched::test_elevator_order");
+        let mut dev = MockDevice::new(1);
+        for sector in [500, 100, 300, 700, 200] {
+            dev.submit(sector, 8, R, IoPriority::Normal);
+        }
+        assert_eq!(dev.drain(), vec![500, 700, 100, 200, 300]);
+
+        // The elevator continues upward from the last request, and then wraps around
+        dev.submit(300, 8, W, IoPriority::Normal);
+        for sector in [100, 400, 50, 900] {
+            dev.submit(sector, 8, W, IoPriority::Normal);
+        }
+        assert_eq!(dev.drain(), vec![300, 400, 900, 50, 100]);
+
+        // The degenerate case: every request is served on submission
+        for sector in [30, 20, 10] {
+            dev.submit(sector, 1, R, IoPriority::Normal);
+            dev.tick();
+        }
+        assert_eq!(dev.drain(), vec![30, 20, 10]);
+        let stats = dev.elevator.stats(0);
+        assert_eq!(stats.pending, 0);
+        assert_eq!(stats.max_pending, 4);
+        assert_eq!(stats.waits[IoPriority::Normal.index()].count, 13);
+    }
+
+    #[test_case]
+    fn test_elevator_priority() {
+        info!("TESTING devices::virtio::block::iosched::test_elevator_priority");
+        let mut dev = MockDevice::new(1);
+        dev.submit(0, 8, R, IoPriority::Normal);
+        dev.submit(10, 8, W, IoPriority::Bulk);
+        dev.submit(20, 8, R, IoPriority::Normal);
+        dev.submit(30, 8, W, IoPriority::Metadata);
+        dev.submit(5, 1, W, IoPriority::Metadata);
+        assert_eq!(dev.drain(), vec![0, 30, 5, 20, 10]);
+        let stats = dev.elevator.stats(0);
+        assert_eq!(stats.waits[IoPriority::Bulk.index()].max_ticks, 4);
+        assert_eq!(stats.waits[IoPriority::Metadata.index()].total_ticks, 1 + 2);
+    }
+
+    #[test_case]
+    fn test_elevator_aging() {
+        info!("TESTING devices::virtio::block::iosched::test_elevator_aging");
+        let mut dev = MockDevice::new(1);
+        dev.submit(0, 8, R, IoPriority::Normal);
+        dev.submit(1000, 8, R, IoPriority::Bulk);
+        // A stream of metadata requests, which would starve the bulk request
+        for i in 0..2 * MAX_PENDING_TICKS {
+            dev.submit(i as u64, 1, W, IoPriority::Metadata);
+            dev.tick();
+        }
+        let served = dev.drain();
+        let position = served.iter().position(|s| *s == 1000).unwrap();
+        assert_eq!(position, MAX_PENDING_TICKS);
+        let stats = dev.elevator.stats(0);
+        assert_eq!(
+            stats.waits[IoPriority::Bulk.index()].max_ticks,
+            MAX_PENDING_TICKS as u64
+        );
+    }
+
+    #[test_case]
+    fn test_elevator_overlap() {
+        info!("TESTING devices::virtio::block::iosched::test_elevator_overlap");
+        let mut dev = MockDevice::new(1);
+        dev.submit(0, 8, R, IoPriority::Normal);
+        dev.submit(100, 8, W, IoPriority::Bulk);
+        dev.submit(104, 8, W, IoPriority::Metadata); // overlaps 100
+        dev.submit(200, 8, R, IoPriority::Normal);
+        dev.submit(96, 8, R, IoPriority::Metadata); // overlaps 100
+        dev.submit(150, 8, R, IoPriority::Normal);
+        dev.submit(150, 8, R, IoPriority::Metadata); // overlaps 150, but both are reads
+
+        // The metadata requests overlapping the bulk write wait for it
+        assert_eq!(dev.drain(), vec![0, 150, 200, 150, 100, 96, 104]);
+
+        // Requests are sorted by sector, but overlapping writes keep their order
+        dev.submit(0, 8, R, IoPriority::Normal);
+        dev.submit(50, 8, W, IoPriority::Normal);
+        dev.submit(40, 16, W, IoPriority::Normal);
+        dev.submit(30, 8, W, IoPriority::Normal);
+        assert_eq!(dev.drain(), vec![0, 30, 50, 40]);
+    }
+}
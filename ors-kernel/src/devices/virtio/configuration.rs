@@ -1,4 +1,6 @@
+use super::transport::{ConfigValue, Transport};
 use crate::devices::pci;
+use crate::phys_memory::Frame;
 use crate::x64;
 
 // const DEVICE_STATUS_FAILED: u8 = 128; // something went wrong in the guest
@@ -44,30 +46,6 @@ impl Configuration {
         x64::Port::new(self.addr + offset).write(value)
     }
 
-    /// Perform general driver initialization.
-    /// After calling this, caller must perform device-specific setup (including virtqueue setup)
-    /// and then call `Configuration::set_driver_ok`.
-    pub unsafe fn initialize(self, negotiate: impl FnOnce(u32) -> u32) -> Result<(), &'static str> {
-        // 3.1.1 Driver Requirements: Device Initialization
-        self.set_device_status(self.device_status() | DEVICE_STATUS_ACKNOWLEDGE);
-        self.set_device_status(self.device_status() | DEVICE_STATUS_DRIVER);
-        const RING_INDIRECT_DESC: u32 = 1 << 28;
-        const RING_EVENT_IDX: u32 = 1 << 29;
-        let features = self.device_features();
-        self.set_driver_features(negotiate(features) & !RING_INDIRECT_DESC & !RING_EVENT_IDX);
-        self.set_device_status(self.device_status() | DEVICE_STATUS_FEATURES_OK);
-
-        if (self.device_status() & DEVICE_STATUS_FEATURES_OK) == 0 {
-            return Err("FEATURES_OK");
-        }
-
-        Ok(())
-    }
-
-    pub unsafe fn set_driver_ok(self) {
-        self.set_device_status(self.device_status() | DEVICE_STATUS_DRIVER_OK);
-    }
-
     unsafe fn device_features(self) -> u32 {
         self.read(0)
     }
@@ -84,7 +62,7 @@ impl Configuration {
         self.write(0x08, value)
     }
 
-    pub unsafe fn queue_size(self) -> u32 {
+    pub unsafe fn queue_size(self) -> u16 {
         self.read(0x0c)
     }
 
@@ -139,3 +117,68 @@ impl Configuration {
         self.write(self.device_specific_offset() + offset, value)
     }
 }
+
+impl Transport for Configuration {
+    unsafe fn initialize(self, negotiate: impl FnOnce(u64) -> u64) -> Result<(), &'static str> {
+        // 3.1.1 Driver Requirements: Device Initialization
+        self.set_device_status(self.device_status() | DEVICE_STATUS_ACKNOWLEDGE);
+        self.set_device_status(self.device_status() | DEVICE_STATUS_DRIVER);
+        const RING_INDIRECT_DESC: u32 = 1 << 28;
+        const RING_EVENT_IDX: u32 = 1 << 29;
+        // Only the lower 32 bits of the features are available on the legacy interface
+        let features = negotiate(self.device_features() as u64) as u32;
+        self.set_driver_features(features & !RING_INDIRECT_DESC & !RING_EVENT_IDX);
+        self.set_device_status(self.device_status() | DEVICE_STATUS_FEATURES_OK);
+
+        if (self.device_status() & DEVICE_STATUS_FEATURES_OK) == 0 {
+            return Err("FEATURES_OK");
+        }
+
+        Ok(())
+    }
+
+    unsafe fn set_driver_ok(self) {
+        self.set_device_status(self.device_status() | DEVICE_STATUS_DRIVER_OK);
+    }
+
+    unsafe fn select_queue(self, queue_index: u16) -> Result<u16, &'static str> {
+        self.set_queue_select(queue_index);
+        Ok(self.queue_size())
+    }
+
+    /// The legacy interface only takes the page number of the descriptor table. The other parts
+    /// must be placed in the legacy layout.
+    unsafe fn set_queue_addresses(
+        self,
+        desc: x64::PhysAddr,
+        _driver: x64::PhysAddr,
+        _device: x64::PhysAddr,
+    ) -> Result<(), &'static str> {
+        if !desc.is_aligned(Frame::SIZE as u64) {
+            return Err("Queue is not aligned to pages");
+        }
+        self.set_queue_address((desc.as_u64() / Frame::SIZE as u64) as u32);
+        Ok(())
+    }
+
+    unsafe fn set_queue_msix_vector(self, vector: u16) {
+        Configuration::set_queue_msix_vector(self, vector)
+    }
+
+    unsafe fn enable_queue(self) {
+        // Queues are enabled by setting the address on the legacy interface
+    }
+
+    unsafe fn notify(self, queue_index: u16) {
+        self.set_queue_notify(queue_index)
+    }
+
+    unsafe fn read_device_specific<T: ConfigValue>(self, offset: u16) -> Option<T> {
+        Some(Configuration::read_device_specific(self, offset))
+    }
+
+    unsafe fn write_device_specific<T: ConfigValue>(self, offset: u16, value: T) -> Option<()> {
+        Configuration::write_device_specific(self, offset, value);
+        Some(())
+    }
+}
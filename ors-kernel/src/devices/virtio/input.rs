@@ -0,0 +1,192 @@
+//! VirtIO input device driver. Only media keys are handled at the moment.
+
+use super::{Buffer, ModernConfiguration, Transport, VirtQueue};
+use crate::console::{self, MediaKey, RawInput};
+use crate::cpu::Cpu;
+use crate::devices::pci;
+use crate::interrupts::virtio_input_irq;
+use crate::sync::once::Once;
+use crate::sync::spin::Spin;
+use alloc::boxed::Box;
+use core::cell::UnsafeCell;
+use core::{mem, ptr};
+use heapless::Vec;
+use log::trace;
+
+static INPUTS: Once<Vec<InputDevice, 4>> = Once::new();
+
+const DEVICE_TYPE: u16 = 18;
+const EVENT_BUFFER_COUNT: usize = 64;
+
+// Event types and codes are the same as evdev (linux/input-event-codes.h)
+const EV_KEY: u16 = 0x01;
+const KEY_MUTE: u16 = 113;
+const KEY_VOLUMEDOWN: u16 = 114;
+const KEY_VOLUMEUP: u16 = 115;
+const KEY_PLAYPAUSE: u16 = 164;
+const KEY_PLAYCD: u16 = 200;
+const KEY_PAUSECD: u16 = 201;
+const KEY_PLAY: u16 = 207;
+
+const KEY_PRESSED: u32 = 1;
+
+pub fn initialize() {
+    INPUTS.call_once(|| {
+        trace!("INITIALIZING VirtIO Inputs");
+        unsafe { InputDevice::scan::<4>() }
+    });
+}
+
+pub fn list() -> &'static Vec<InputDevice, 4> {
+    INPUTS
+        .get()
+        .expect("input::list is called before input::initialize")
+}
+
+#[derive(Debug)]
+pub struct InputDevice {
+    configuration: ModernConfiguration,
+    eventq: Spin<VirtQueue<usize>>,
+    events: Box<[UnsafeCell<Event>; EVENT_BUFFER_COUNT]>, // written by the device
+}
+
+impl InputDevice {
+    unsafe fn scan<const N: usize>() -> Vec<Self, N> {
+        let mut inputs = Vec::new();
+
+        for device in pci::devices() {
+            if device.virtio_device_type() == Some(DEVICE_TYPE) {
+                match InputDevice::from_pci_device(*device, inputs.len()) {
+                    Ok(input) => match inputs.push(input) {
+                        Ok(()) => {}
+                        Err(input) => {
+                            // FIXME: To remove mem::forget, we need to reset the device
+                            mem::forget(input);
+                            trace!("virtio: More than {} inputs are unsupported", N);
+                        }
+                    },
+                    Err(msg) => trace!("virtio: Failed to initialize input: {}", msg),
+                }
+            }
+        }
+
+        inputs
+    }
+
+    unsafe fn from_pci_device(device: pci::Device, index: usize) -> Result<Self, &'static str> {
+        if let Some(msi_x) = device.msi_x() {
+            if msi_x.table().len() == 0 {
+                return Err("MSI-X support does not have enough table entries");
+            }
+
+            let bsp = Cpu::boot_strap().lapic_id().unwrap();
+            let irq = virtio_input_irq(index).ok_or("IRQ numbers exhausted")?;
+            msi_x.table().entry(0).enable(bsp, irq); // for eventq
+            msi_x.enable();
+        } else {
+            // Interrupts other than MSI-X is not implemented
+            return Err("MSI-X unsupported");
+        }
+
+        let configuration = ModernConfiguration::from_pci_device(device)?;
+        configuration.initialize(|_| 0)?;
+        let mut eventq = VirtQueue::new(configuration, 0, Some(0))?;
+        let mut events = Box::new([(); EVENT_BUFFER_COUNT].map(|_| UnsafeCell::default()));
+        for (i, event) in events.iter_mut().enumerate() {
+            let buffer = Buffer::from_ref_mut(event.get_mut(), i).unwrap();
+            if eventq.transfer(core::iter::once(buffer)).is_err() {
+                break; // the queue is smaller than EVENT_BUFFER_COUNT
+            }
+        }
+        configuration.set_driver_ok();
+        configuration.set_queue_notify(0);
+
+        Ok(Self {
+            configuration,
+            eventq: Spin::new(eventq),
+            events,
+        })
+    }
+
+    /// Collect the received events and give the buffers back to the device.
+    /// This method is supposed to be called from Used Buffer Notification (interrupt).
+    pub fn collect(&self) {
+        let mut eventq = self.eventq.lock();
+        let mut received = Vec::<usize, EVENT_BUFFER_COUNT>::new();
+        eventq.collect(|i| {
+            let _ = received.push(i);
+        });
+        for i in received {
+            // The device has returned the buffer, so it is not written until it is given back
+            let event = unsafe { &mut *self.events[i].get() };
+            if let Some(key) = unsafe { ptr::read_volatile(event) }.media_key() {
+                console::accept_raw_input(RawInput::MediaKey(key));
+            }
+            let buffer = Buffer::from_ref_mut(event, i).unwrap();
+            let _ = eventq.transfer(core::iter::once(buffer));
+        }
+        unsafe { self.configuration.set_queue_notify(0) };
+    }
+}
+
+unsafe impl Sync for InputDevice {}
+
+unsafe impl Send for InputDevice {}
+
+/// `virtio_input_event`
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+struct Event {
+    ty: u16,
+    code: u16,
+    value: u32,
+}
+
+impl Event {
+    fn media_key(self) -> Option<MediaKey> {
+        if self.ty != EV_KEY || self.value != KEY_PRESSED {
+            return None; // key releases and auto-repeats are ignored
+        }
+        match self.code {
+            KEY_PLAYPAUSE | KEY_PLAYCD | KEY_PLAY => Some(MediaKey::Play),
+            KEY_PAUSECD => Some(MediaKey::Pause),
+            KEY_VOLUMEUP => Some(MediaKey::VolumeUp),
+            KEY_VOLUMEDOWN => Some(MediaKey::VolumeDown),
+            KEY_MUTE => Some(MediaKey::Mute),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::info;
+
+    #[test_case]
+    fn test_media_key() {
+        info!("TESTING devices::virtio::input::test_media_key");
+        let key = |code, value| Event {
+            ty: EV_KEY,
+            code,
+            value,
+        };
+        assert_eq!(key(KEY_PLAYPAUSE, 1).media_key(), Some(MediaKey::Play));
+        assert_eq!(key(KEY_PAUSECD, 1).media_key(), Some(MediaKey::Pause));
+        assert_eq!(key(KEY_VOLUMEUP, 1).media_key(), Some(MediaKey::VolumeUp));
+        assert_eq!(
+            key(KEY_VOLUMEDOWN, 1).media_key(),
+            Some(MediaKey::VolumeDown)
+        );
+        assert_eq!(key(KEY_MUTE, 1).media_key(), Some(MediaKey::Mute));
+        assert_eq!(key(KEY_MUTE, 0).media_key(), None); // released
+        assert_eq!(key(KEY_MUTE, 2).media_key(), None); // auto-repeat
+        assert_eq!(key(30 /* KEY_A */, 1).media_key(), None);
+        let syn = Event {
+            ty: 0,
+            code: 0,
+            value: 0,
+        };
+        assert_eq!(syn.media_key(), None);
+    }
+}
@@ -0,0 +1,512 @@
+//! A software VirtIO device for testing the drivers in the kernel test framework.
+//!
+//! `MockVirtioDevice` implements `Transport` by recording the driver's configuration, and serves
+//! the split virtqueue (queue 0) allocated by the driver by accessing the shared memory as a
+//! device would. What the device does with each descriptor chain is defined by a `Personality`.
+//!
+//! The device is passive: it works only when a test calls `run` or the finer-grained steps
+//! (`begin`, `fetch`, `complete`, `end`), so that tests can interleave the driver and the device
+//! at arbitrary points. As with real devices, the device starts working only after an Available
+//! Buffer Notification, and suppresses further notifications (VIRTQ_USED_F_NO_NOTIFY) while it
+//! is working.
+
+use super::transport::{ConfigValue, Transport};
+use crate::paging::as_virt_addr;
+use crate::sync::spin::Spin;
+use crate::x64;
+use alloc::collections::VecDeque;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{fence, Ordering};
+use core::{mem, ptr, slice};
+use derive_new::new;
+
+const DESC_F_NEXT: u16 = 1;
+const DESC_F_WRITE: u16 = 2;
+const USED_F_NO_NOTIFY: u16 = 1;
+
+/// What a device does with descriptor chains.
+pub(super) trait Personality {
+    /// Process a descriptor chain, returning the number of bytes written into the chain.
+    fn process(&mut self, chain: &[MockBuffer]) -> u32;
+}
+
+/// A buffer referred by a descriptor, seen from the device.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, new)]
+pub(super) struct MockBuffer {
+    pub(super) addr: x64::PhysAddr,
+    pub(super) len: usize,
+    /// Device write-only.
+    pub(super) write: bool,
+}
+
+impl MockBuffer {
+    pub(super) unsafe fn bytes(&self) -> &[u8] {
+        let ptr = as_virt_addr(self.addr).unwrap().as_ptr();
+        slice::from_raw_parts(ptr, self.len)
+    }
+
+    pub(super) unsafe fn bytes_mut(&self) -> &mut [u8] {
+        assert!(self.write, "mock: Writing to a device read-only buffer");
+        let ptr = as_virt_addr(self.addr).unwrap().as_mut_ptr();
+        slice::from_raw_parts_mut(ptr, self.len)
+    }
+}
+
+/// A personality that does nothing but records the chains.
+#[derive(Debug, Default)]
+pub(super) struct Sink {
+    pub(super) chains: Vec<Vec<MockBuffer>>,
+}
+
+impl Personality for Sink {
+    fn process(&mut self, chain: &[MockBuffer]) -> u32 {
+        self.chains.push(chain.to_vec());
+        0
+    }
+}
+
+/// The personality of a block device (virtio-blk) on an in-memory disk.
+#[derive(Debug)]
+pub(super) struct MockBlock {
+    pub(super) disk: Vec<u8>,
+    /// Requests touching these sectors fail with VIRTIO_BLK_S_IOERR.
+    pub(super) faulty_sectors: Vec<u64>,
+}
+
+impl MockBlock {
+    pub(super) const SECTOR_SIZE: usize = 512;
+    pub(super) const T_IN: u32 = 0;
+    pub(super) const T_OUT: u32 = 1;
+    pub(super) const T_WRITE_ZEROES: u32 = 13;
+    pub(super) const S_OK: u8 = 0;
+    pub(super) const S_IOERR: u8 = 1;
+    pub(super) const S_UNSUPP: u8 = 2;
+
+    pub(super) fn new(num_sectors: usize) -> Self {
+        Self {
+            disk: vec![0; num_sectors * Self::SECTOR_SIZE],
+            faulty_sectors: Vec::new(),
+        }
+    }
+
+    pub(super) fn sector(&self, sector: u64) -> &[u8] {
+        let offset = sector as usize * Self::SECTOR_SIZE;
+        &self.disk[offset..offset + Self::SECTOR_SIZE]
+    }
+
+    /// The byte range of the disk accessed by a request.
+    fn range(&self, sector: u64, len: usize) -> Option<(usize, usize)> {
+        let start = (sector as usize).checked_mul(Self::SECTOR_SIZE)?;
+        let end = start.checked_add(len)?;
+        let num_sectors = (len + Self::SECTOR_SIZE - 1) / Self::SECTOR_SIZE;
+        let faulty = self
+            .faulty_sectors
+            .iter()
+            .any(|s| sector <= *s && *s < sector + num_sectors as u64);
+        (end <= self.disk.len() && !faulty).then(|| (start, end))
+    }
+
+    unsafe fn execute(&mut self, ty: u32, sector: u64, body: &MockBuffer) -> (u8, u32) {
+        match ty {
+            Self::T_IN if body.write => match self.range(sector, body.len) {
+                Some((start, end)) => {
+                    body.bytes_mut().copy_from_slice(&self.disk[start..end]);
+                    (Self::S_OK, body.len as u32)
+                }
+                None => (Self::S_IOERR, 0),
+            },
+            Self::T_OUT if !body.write => match self.range(sector, body.len) {
+                Some((start, end)) => {
+                    self.disk[start..end].copy_from_slice(body.bytes());
+                    (Self::S_OK, 0)
+                }
+                None => (Self::S_IOERR, 0),
+            },
+            Self::T_WRITE_ZEROES if !body.write && body.len == 16 => {
+                let segment = body.bytes();
+                let sector = u64::from_le_bytes(segment[0..8].try_into().unwrap());
+                let num_sectors = u32::from_le_bytes(segment[8..12].try_into().unwrap());
+                match self.range(sector, num_sectors as usize * Self::SECTOR_SIZE) {
+                    Some((start, end)) => {
+                        self.disk[start..end].fill(0);
+                        (Self::S_OK, 0)
+                    }
+                    None => (Self::S_IOERR, 0),
+                }
+            }
+            _ => (Self::S_UNSUPP, 0),
+        }
+    }
+}
+
+impl Personality for MockBlock {
+    fn process(&mut self, chain: &[MockBuffer]) -> u32 {
+        // header (16 bytes, read-only), body, footer (1 byte status, write-only)
+        let (header, body, footer) = match chain {
+            [header, body, footer] if header.len == 16 && !header.write && footer.write => {
+                (header, body, footer)
+            }
+            _ => panic!("mock: Malformed block request: {:?}", chain),
+        };
+        unsafe {
+            let header = header.bytes();
+            let ty = u32::from_le_bytes(header[0..4].try_into().unwrap());
+            let sector = u64::from_le_bytes(header[8..16].try_into().unwrap());
+            let (status, written) = self.execute(ty, sector, body);
+            footer.bytes_mut()[0] = status;
+            written + 1
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(super) struct MockVirtioDevice<P> {
+    state: Spin<State<P>>,
+}
+
+#[derive(Debug)]
+struct State<P> {
+    personality: P,
+    config: Vec<u8>,
+    device_features: u64,
+    driver_features: u64,
+    driver_ok: bool,
+    queue_size: u16,
+    selected_queue: u16,
+    /// Addresses of the descriptor table, the available ring, and the used ring.
+    addresses: Option<(x64::PhysAddr, x64::PhysAddr, x64::PhysAddr)>,
+    enabled: bool,
+    msix_vector: Option<u16>,
+
+    last_avail_idx: u16,
+    used_idx: u16,
+    /// Fetched chains with the number of steps they have been in progress.
+    in_progress: VecDeque<(u16, usize)>,
+    /// Set by a notification, cleared by `begin`.
+    kicked: bool,
+    notifications: usize,
+
+    /// Number of steps taken by each chain before completion.
+    delay: usize,
+    /// Complete the chains ready at the same step in the reverse order.
+    reorder: bool,
+}
+
+impl<P: Personality> MockVirtioDevice<P> {
+    pub(super) fn new(
+        queue_size: u16,
+        device_features: u64,
+        config: Vec<u8>,
+        personality: P,
+    ) -> Self {
+        Self {
+            state: Spin::new(State {
+                personality,
+                config,
+                device_features,
+                driver_features: 0,
+                driver_ok: false,
+                queue_size,
+                selected_queue: 0,
+                addresses: None,
+                enabled: false,
+                msix_vector: None,
+                last_avail_idx: 0,
+                used_idx: 0,
+                in_progress: VecDeque::new(),
+                kicked: false,
+                notifications: 0,
+                delay: 0,
+                reorder: false,
+            }),
+        }
+    }
+
+    pub(super) fn transport(&self) -> MockTransport<'_, P> {
+        MockTransport(self)
+    }
+
+    pub(super) fn set_delay(&self, steps: usize) {
+        self.state.lock().delay = steps;
+    }
+
+    pub(super) fn set_reorder(&self, reorder: bool) {
+        self.state.lock().reorder = reorder;
+    }
+
+    pub(super) fn with_personality<R>(&self, f: impl FnOnce(&mut P) -> R) -> R {
+        f(&mut self.state.lock().personality)
+    }
+
+    pub(super) fn driver_features(&self) -> u64 {
+        self.state.lock().driver_features
+    }
+
+    pub(super) fn is_driver_ok(&self) -> bool {
+        self.state.lock().driver_ok
+    }
+
+    pub(super) fn msix_vector(&self) -> Option<u16> {
+        self.state.lock().msix_vector
+    }
+
+    /// Number of Available Buffer Notifications received.
+    pub(super) fn notifications(&self) -> usize {
+        self.state.lock().notifications
+    }
+
+    /// Number of chains fetched but not completed yet.
+    pub(super) fn num_in_progress(&self) -> usize {
+        self.state.lock().in_progress.len()
+    }
+
+    /// Pretend that `n` chains have been processed since the queue is enabled, by advancing the
+    /// indices of both rings. The driver must skip the same number of indices by
+    /// `VirtQueue::skip_indices` before transferring anything.
+    pub(super) fn skip_indices(&self, n: u16) {
+        let mut s = self.state.lock();
+        s.last_avail_idx = s.last_avail_idx.wrapping_add(n);
+        s.used_idx = s.used_idx.wrapping_add(n);
+    }
+
+    /// Serve the queue as a device would on a notification: repeat steps until nothing is in
+    /// progress, and then re-check the available ring after re-enabling notifications.
+    /// Returns the number of completed chains. Does nothing unless notified.
+    pub(super) fn run(&self) -> usize {
+        let mut n = 0;
+        if self.begin() {
+            loop {
+                self.fetch();
+                n += self.complete();
+                if self.num_in_progress() == 0 && !self.end() {
+                    break;
+                }
+            }
+        }
+        n
+    }
+
+    /// Start working on a notification, suppressing further notifications. Returns whether
+    /// the device was notified.
+    pub(super) fn begin(&self) -> bool {
+        let mut s = self.state.lock();
+        if !mem::replace(&mut s.kicked, false) {
+            return false;
+        }
+        s.set_used_flags(USED_F_NO_NOTIFY);
+        true
+    }
+
+    /// Stop working, re-enabling notifications. Since the driver may have skipped the
+    /// notification of the chains made available while notifications are suppressed, the
+    /// available ring is checked again after that. Returns whether the device must continue
+    /// working, in which case notifications remain suppressed.
+    pub(super) fn end(&self) -> bool {
+        let mut s = self.state.lock();
+        s.set_used_flags(0);
+        fence(Ordering::SeqCst);
+        if s.avail_idx() != s.last_avail_idx {
+            s.set_used_flags(USED_F_NO_NOTIFY);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Take the chains newly made available by the driver. Returns the number of them.
+    pub(super) fn fetch(&self) -> usize {
+        self.state.lock().fetch()
+    }
+
+    /// Advance the chains in progress by a step, and complete the ones that took the delay.
+    /// Returns the number of completed chains.
+    pub(super) fn complete(&self) -> usize {
+        self.state.lock().complete()
+    }
+}
+
+impl<P: Personality> State<P> {
+    fn ring_addresses(&self) -> (x64::PhysAddr, x64::PhysAddr, x64::PhysAddr) {
+        assert!(self.enabled, "mock: The queue is not enabled");
+        self.addresses.unwrap()
+    }
+
+    unsafe fn ptr<T>(addr: x64::PhysAddr) -> *mut T {
+        as_virt_addr(addr).unwrap().as_mut_ptr()
+    }
+
+    fn avail_idx(&self) -> u16 {
+        let (_, avail, _) = self.ring_addresses();
+        unsafe { ptr::read_volatile(Self::ptr(avail + 2u64)) }
+    }
+
+    fn set_used_flags(&mut self, flags: u16) {
+        let (_, _, used) = self.ring_addresses();
+        unsafe { ptr::write_volatile(Self::ptr(used), flags) };
+    }
+
+    fn fetch(&mut self) -> usize {
+        let (_, avail, _) = self.ring_addresses();
+        let idx = self.avail_idx();
+        fence(Ordering::SeqCst);
+        let mut n = 0;
+        while self.last_avail_idx != idx {
+            let slot = (self.last_avail_idx % self.queue_size) as u64;
+            let head: u16 = unsafe { ptr::read_volatile(Self::ptr(avail + 4u64 + 2 * slot)) };
+            assert!(head < self.queue_size, "mock: Invalid descriptor {}", head);
+            self.in_progress.push_back((head, 0));
+            self.last_avail_idx = self.last_avail_idx.wrapping_add(1);
+            n += 1;
+        }
+        n
+    }
+
+    fn complete(&mut self) -> usize {
+        let delay = self.delay;
+        let mut ready = Vec::new();
+        for _ in 0..self.in_progress.len() {
+            let (head, steps) = self.in_progress.pop_front().unwrap();
+            if delay < steps + 1 {
+                ready.push(head);
+            } else {
+                self.in_progress.push_back((head, steps + 1));
+            }
+        }
+        if self.reorder {
+            ready.reverse();
+        }
+        for head in ready.iter() {
+            let chain = self.chain(*head);
+            let len = self.personality.process(&chain);
+            self.push_used(*head, len);
+        }
+        ready.len()
+    }
+
+    fn chain(&self, head: u16) -> Vec<MockBuffer> {
+        let (desc, _, _) = self.ring_addresses();
+        let mut chain = Vec::new();
+        let mut i = head;
+        loop {
+            assert!(
+                chain.len() < self.queue_size as usize,
+                "mock: Descriptor chain loops"
+            );
+            let d = desc + 16 * i as u64;
+            let (addr, len, flags, next) = unsafe {
+                (
+                    ptr::read_volatile(Self::ptr::<u64>(d)),
+                    ptr::read_volatile(Self::ptr::<u32>(d + 8u64)),
+                    ptr::read_volatile(Self::ptr::<u16>(d + 12u64)),
+                    ptr::read_volatile(Self::ptr::<u16>(d + 14u64)),
+                )
+            };
+            chain.push(MockBuffer {
+                addr: x64::PhysAddr::new(addr),
+                len: len as usize,
+                write: (flags & DESC_F_WRITE) != 0,
+            });
+            if (flags & DESC_F_NEXT) == 0 {
+                break;
+            }
+            assert!(next < self.queue_size, "mock: Invalid descriptor {}", next);
+            i = next;
+        }
+        chain
+    }
+
+    fn push_used(&mut self, head: u16, len: u32) {
+        let (_, _, used) = self.ring_addresses();
+        let slot = (self.used_idx % self.queue_size) as u64;
+        unsafe {
+            ptr::write_volatile(Self::ptr(used + 4u64 + 8 * slot), head as u32);
+            ptr::write_volatile(Self::ptr(used + 8u64 + 8 * slot), len);
+        }
+        fence(Ordering::SeqCst);
+        self.used_idx = self.used_idx.wrapping_add(1);
+        unsafe { ptr::write_volatile(Self::ptr(used + 2u64), self.used_idx) };
+    }
+}
+
+/// The transport of `MockVirtioDevice`, which only supports queue 0.
+#[derive(Debug)]
+pub(super) struct MockTransport<'a, P>(&'a MockVirtioDevice<P>);
+
+impl<'a, P> Clone for MockTransport<'a, P> {
+    fn clone(&self) -> Self {
+        Self(self.0)
+    }
+}
+
+impl<'a, P> Copy for MockTransport<'a, P> {}
+
+impl<'a, P: Personality> Transport for MockTransport<'a, P> {
+    unsafe fn initialize(self, negotiate: impl FnOnce(u64) -> u64) -> Result<(), &'static str> {
+        let device_features = self.0.state.lock().device_features;
+        let driver_features = negotiate(device_features);
+        let mut s = self.0.state.lock();
+        if (driver_features & !s.device_features) != 0 {
+            return Err("Feature negotiation failed");
+        }
+        s.driver_features = driver_features;
+        Ok(())
+    }
+
+    unsafe fn set_driver_ok(self) {
+        self.0.state.lock().driver_ok = true;
+    }
+
+    unsafe fn select_queue(self, queue_index: u16) -> Result<u16, &'static str> {
+        let mut s = self.0.state.lock();
+        s.selected_queue = queue_index;
+        Ok(if queue_index == 0 { s.queue_size } else { 0 })
+    }
+
+    unsafe fn set_queue_addresses(
+        self,
+        desc: x64::PhysAddr,
+        driver: x64::PhysAddr,
+        device: x64::PhysAddr,
+    ) -> Result<(), &'static str> {
+        let mut s = self.0.state.lock();
+        assert_eq!(s.selected_queue, 0);
+        s.addresses = Some((desc, driver, device));
+        Ok(())
+    }
+
+    unsafe fn set_queue_msix_vector(self, vector: u16) {
+        self.0.state.lock().msix_vector = Some(vector);
+    }
+
+    unsafe fn enable_queue(self) {
+        let mut s = self.0.state.lock();
+        assert!(
+            s.addresses.is_some(),
+            "mock: Enabling a queue without rings"
+        );
+        s.enabled = true;
+    }
+
+    unsafe fn notify(self, queue_index: u16) {
+        assert_eq!(queue_index, 0);
+        let mut s = self.0.state.lock();
+        s.notifications += 1;
+        s.kicked = true;
+    }
+
+    unsafe fn read_device_specific<T: ConfigValue>(self, offset: u16) -> Option<T> {
+        let s = self.0.state.lock();
+        let offset = offset as usize;
+        let bytes = s.config.get(offset..offset + mem::size_of::<T>())?;
+        Some(ptr::read_unaligned(bytes.as_ptr() as *const T))
+    }
+
+    unsafe fn write_device_specific<T: ConfigValue>(self, offset: u16, value: T) -> Option<()> {
+        let mut s = self.0.state.lock();
+        let offset = offset as usize;
+        let bytes = s.config.get_mut(offset..offset + mem::size_of::<T>())?;
+        ptr::write_unaligned(bytes.as_mut_ptr() as *mut T, value);
+        Some(())
+    }
+}
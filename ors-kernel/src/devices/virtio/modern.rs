@@ -0,0 +1,484 @@
+//! VirtIO over PCI Bus, the modern (non-legacy) interface.
+//!
+//! Unlike the legacy interface, the device configuration structures are located by
+//! vendor-specific PCI capabilities and accessed through memory-mapped BAR regions.
+
+use super::transport::{ConfigValue, Transport};
+use crate::devices::pci;
+use crate::paging::as_virt_addr;
+use crate::x64;
+use core::ptr;
+
+// const DEVICE_STATUS_FAILED: u8 = 128;
+const DEVICE_STATUS_ACKNOWLEDGE: u8 = 1;
+const DEVICE_STATUS_DRIVER: u8 = 2;
+const DEVICE_STATUS_FEATURES_OK: u8 = 8;
+const DEVICE_STATUS_DRIVER_OK: u8 = 4;
+
+/// The device reset is given up after this number of polls of the device status. Ticks cannot be
+/// used for the timeout since devices are initialized while interrupts are disabled.
+const RESET_POLLS: usize = 1_000_000;
+
+const FEATURE_DEVICE_SPECIFIC_MASK: u64 = (1 << 24) - 1;
+/// Indicates compliance with VirtIO 1.0+. Modern drivers must always accept this feature.
+pub const FEATURE_VERSION_1: u64 = 1 << 32;
+
+// 4.1.4 Virtio Structure PCI Capabilities
+const PCI_CAP_COMMON_CFG: u8 = 1;
+const PCI_CAP_NOTIFY_CFG: u8 = 2;
+const PCI_CAP_ISR_CFG: u8 = 3;
+const PCI_CAP_DEVICE_CFG: u8 = 4;
+// const PCI_CAP_PCI_CFG: u8 = 5;
+
+/// A region of a BAR described by a `virtio_pci_cap`.
+#[derive(Debug, Clone, Copy)]
+struct Region {
+    ptr: *mut u8,
+    len: u32,
+}
+
+// The region points to device memory that is not owned by any particular task.
+unsafe impl Send for Region {}
+unsafe impl Sync for Region {}
+
+impl Region {
+    unsafe fn from_capability(device: pci::Device, cap: VirtioCap) -> Result<Self, &'static str> {
+        let base = device
+            .read_bar(cap.bar)
+            .mmio_base()
+            .ok_or("VirtIO capability refers to a non-MMIO BAR")?;
+        let addr = x64::PhysAddr::new(base as u64 + cap.offset as u64);
+        let ptr = as_virt_addr(addr)
+            .ok_or("VirtIO capability region is not mapped")?
+            .as_mut_ptr();
+        Ok(Self {
+            ptr,
+            len: cap.length,
+        })
+    }
+
+    unsafe fn read<T: Copy>(self, offset: u32) -> T {
+        debug_assert!(offset as usize + core::mem::size_of::<T>() <= self.len as usize);
+        ptr::read_volatile(self.ptr.add(offset as usize) as *const T)
+    }
+
+    unsafe fn write<T: Copy>(self, offset: u32, value: T) {
+        debug_assert!(offset as usize + core::mem::size_of::<T>() <= self.len as usize);
+        ptr::write_volatile(self.ptr.add(offset as usize) as *mut T, value)
+    }
+}
+
+/// `virtio_pci_cap`, or `virtio_pci_notify_cap` for the notification structure.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+struct VirtioCap {
+    cfg_type: u8,
+    bar: u8,
+    offset: u32,
+    length: u32,
+    notify_off_multiplier: u32,
+}
+
+impl VirtioCap {
+    const VENDOR_SPECIFIC: u8 = 0x09;
+
+    /// Parse the capability at `pointer` in the configuration space `config`. Returns `None` if
+    /// it is not a VirtIO structure capability that the driver can use.
+    fn parse(config: &[u8; 256], pointer: u8) -> Option<Self> {
+        let cap = config.get(pointer as usize..pointer as usize + 16)?;
+        if cap[0] != Self::VENDOR_SPECIFIC {
+            return None;
+        }
+        let read_u32 = |bytes: &[u8]| u32::from_le_bytes(bytes.try_into().unwrap());
+        let cfg_type = cap[3];
+        let bar = cap[4];
+        if bar > 5 {
+            return None; // > values 0x6 to 0x7 are reserved for future use
+        }
+        let notify_off_multiplier = if cfg_type == PCI_CAP_NOTIFY_CFG {
+            if cap[2] < 20 {
+                return None;
+            }
+            let i = pointer as usize + 16;
+            read_u32(config.get(i..i + 4)?)
+        } else {
+            0
+        };
+        Some(Self {
+            cfg_type,
+            bar,
+            offset: read_u32(&cap[8..12]),
+            length: read_u32(&cap[12..16]),
+            notify_off_multiplier,
+        })
+    }
+}
+
+/// Locations of the VirtIO structures described by the capabilities.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+struct Structures {
+    common: Option<VirtioCap>,
+    notify: Option<VirtioCap>,
+    isr: Option<VirtioCap>,
+    device: Option<VirtioCap>,
+}
+
+impl Structures {
+    /// Walk the capability list from `first` in the configuration space `config`.
+    fn parse(config: &[u8; 256], first: u8) -> Self {
+        // Bounds the walk on a malformed (e.g. circular) list
+        const MAX_CAPABILITIES: usize = 48;
+
+        let mut structures = Self::default();
+        let mut pointer = first & !0x3;
+        for _ in 0..MAX_CAPABILITIES {
+            if pointer == 0 {
+                break;
+            }
+            let cap = VirtioCap::parse(config, pointer);
+            pointer = config.get(pointer as usize + 1).map_or(0, |p| *p & !0x3);
+            if let Some(cap) = cap {
+                // > The driver SHOULD use the first instance of each virtio structure type they can support.
+                // PCI_CAP_PCI_CFG is an alternative access method, unused
+                let slot = match cap.cfg_type {
+                    PCI_CAP_COMMON_CFG => &mut structures.common,
+                    PCI_CAP_NOTIFY_CFG => &mut structures.notify,
+                    PCI_CAP_ISR_CFG => &mut structures.isr,
+                    PCI_CAP_DEVICE_CFG => &mut structures.device,
+                    _ => continue,
+                };
+                if slot.is_none() {
+                    *slot = Some(cap);
+                }
+            }
+        }
+        structures
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ModernConfiguration {
+    common: Region,
+    notify: Region,
+    notify_off_multiplier: u32,
+    isr: Option<Region>,
+    device: Option<Region>,
+}
+
+impl ModernConfiguration {
+    pub unsafe fn from_pci_device(device: pci::Device) -> Result<Self, &'static str> {
+        let first = device
+            .capability_pointer()
+            .ok_or("PCI capabilities not found")?;
+        let mut config = [0; 256];
+        device.read_config(0, &mut config);
+        let structures = Structures::parse(&config, first);
+
+        let common = structures
+            .common
+            .ok_or("VirtIO common configuration not found")?;
+        let notify = structures
+            .notify
+            .ok_or("VirtIO notification structure not found")?;
+        Ok(Self {
+            common: Region::from_capability(device, common)?,
+            notify: Region::from_capability(device, notify)?,
+            notify_off_multiplier: notify.notify_off_multiplier,
+            isr: match structures.isr {
+                Some(cap) => Some(Region::from_capability(device, cap)?),
+                None => None,
+            },
+            device: match structures.device {
+                Some(cap) => Some(Region::from_capability(device, cap)?),
+                None => None,
+            },
+        })
+    }
+
+    pub unsafe fn device_feature_select(self) -> u32 {
+        self.common.read(0x00)
+    }
+
+    pub unsafe fn set_device_feature_select(self, value: u32) {
+        self.common.write(0x00, value)
+    }
+
+    pub unsafe fn driver_feature_select(self) -> u32 {
+        self.common.read(0x08)
+    }
+
+    pub unsafe fn set_driver_feature_select(self, value: u32) {
+        self.common.write(0x08, value)
+    }
+
+    /// Read the whole 64-bit feature bits by selecting each 32-bit half.
+    pub unsafe fn device_features(self) -> u64 {
+        self.set_device_feature_select(0);
+        let lower = self.common.read::<u32>(0x04) as u64;
+        self.set_device_feature_select(1);
+        let upper = self.common.read::<u32>(0x04) as u64;
+        lower | (upper << 32)
+    }
+
+    unsafe fn set_driver_features(self, value: u64) {
+        self.set_driver_feature_select(0);
+        self.common.write(0x0c, value as u32);
+        self.set_driver_feature_select(1);
+        self.common.write(0x0c, (value >> 32) as u32);
+    }
+
+    pub unsafe fn set_config_msix_vector(self, value: u16) {
+        self.common.write(0x10, value)
+    }
+
+    pub unsafe fn num_queues(self) -> u16 {
+        self.common.read(0x12)
+    }
+
+    unsafe fn device_status(self) -> u8 {
+        self.common.read(0x14)
+    }
+
+    unsafe fn set_device_status(self, value: u8) {
+        self.common.write(0x14, value)
+    }
+
+    pub unsafe fn config_generation(self) -> u8 {
+        self.common.read(0x15)
+    }
+
+    pub unsafe fn queue_select(self) -> u16 {
+        self.common.read(0x16)
+    }
+
+    pub unsafe fn set_queue_select(self, value: u16) {
+        self.common.write(0x16, value)
+    }
+
+    /// On the modern interface, the driver may reduce the queue size by writing a smaller value.
+    pub unsafe fn queue_size(self) -> u16 {
+        self.common.read(0x18)
+    }
+
+    pub unsafe fn set_queue_size(self, value: u16) {
+        self.common.write(0x18, value)
+    }
+
+    pub unsafe fn set_queue_msix_vector(self, value: u16) {
+        self.common.write(0x1a, value)
+    }
+
+    pub unsafe fn queue_enable(self) -> bool {
+        self.common.read::<u16>(0x1c) != 0
+    }
+
+    pub unsafe fn set_queue_enable(self) {
+        self.common.write(0x1c, 1u16)
+    }
+
+    pub unsafe fn queue_notify_off(self) -> u16 {
+        self.common.read(0x1e)
+    }
+
+    pub unsafe fn set_queue_desc(self, addr: x64::PhysAddr) {
+        self.write_u64(0x20, addr.as_u64())
+    }
+
+    pub unsafe fn set_queue_driver(self, addr: x64::PhysAddr) {
+        self.write_u64(0x28, addr.as_u64())
+    }
+
+    pub unsafe fn set_queue_device(self, addr: x64::PhysAddr) {
+        self.write_u64(0x30, addr.as_u64())
+    }
+
+    unsafe fn write_u64(self, offset: u32, value: u64) {
+        // 64-bit fields are accessed as two 32-bit halves for portability.
+        self.common.write(offset, value as u32);
+        self.common.write(offset + 4, (value >> 32) as u32);
+    }
+
+    /// Send an Available Buffer Notification for the `queue_index`-th queue.
+    pub unsafe fn set_queue_notify(self, queue_index: u16) {
+        self.set_queue_select(queue_index);
+        let offset = self.queue_notify_off() as u32 * self.notify_off_multiplier;
+        self.notify.write(offset, queue_index)
+    }
+
+    /// ISR status. Unused when MSI-X is enabled.
+    pub unsafe fn isr_status(self) -> Option<u8> {
+        Some(self.isr?.read(0))
+    }
+
+    pub unsafe fn read_device_specific<T: Copy>(self, offset: u32) -> Option<T> {
+        Some(self.device?.read(offset))
+    }
+
+    pub unsafe fn write_device_specific<T: Copy>(self, offset: u32, value: T) -> Option<()> {
+        self.device?.write(offset, value);
+        Some(())
+    }
+}
+
+impl Transport for ModernConfiguration {
+    unsafe fn initialize(self, negotiate: impl FnOnce(u64) -> u64) -> Result<(), &'static str> {
+        // 3.1.1 Driver Requirements: Device Initialization
+        self.set_device_status(0); // reset
+        let mut polls = 0;
+        while self.device_status() != 0 {
+            polls += 1;
+            if RESET_POLLS <= polls {
+                return Err("Device reset timed out");
+            }
+            core::hint::spin_loop();
+        }
+        self.set_device_status(self.device_status() | DEVICE_STATUS_ACKNOWLEDGE);
+        self.set_device_status(self.device_status() | DEVICE_STATUS_DRIVER);
+        let features = self.device_features();
+        if (features & FEATURE_VERSION_1) == 0 {
+            return Err("VIRTIO_F_VERSION_1 is not offered");
+        }
+        // Reserved feature bits other than VIRTIO_F_VERSION_1 (e.g. VIRTIO_F_INDIRECT_DESC,
+        // VIRTIO_F_RING_PACKED) are unsupported
+        let features = negotiate(features) & FEATURE_DEVICE_SPECIFIC_MASK;
+        self.set_driver_features(features | FEATURE_VERSION_1);
+        self.set_device_status(self.device_status() | DEVICE_STATUS_FEATURES_OK);
+
+        if (self.device_status() & DEVICE_STATUS_FEATURES_OK) == 0 {
+            return Err("FEATURES_OK");
+        }
+
+        Ok(())
+    }
+
+    unsafe fn set_driver_ok(self) {
+        self.set_device_status(self.device_status() | DEVICE_STATUS_DRIVER_OK);
+    }
+
+    unsafe fn select_queue(self, queue_index: u16) -> Result<u16, &'static str> {
+        self.set_queue_select(queue_index);
+        if self.queue_enable() {
+            return Err("Queue is already enabled");
+        }
+        Ok(self.queue_size())
+    }
+
+    unsafe fn set_queue_addresses(
+        self,
+        desc: x64::PhysAddr,
+        driver: x64::PhysAddr,
+        device: x64::PhysAddr,
+    ) -> Result<(), &'static str> {
+        self.set_queue_desc(desc);
+        self.set_queue_driver(driver);
+        self.set_queue_device(device);
+        Ok(())
+    }
+
+    unsafe fn set_queue_msix_vector(self, vector: u16) {
+        ModernConfiguration::set_queue_msix_vector(self, vector)
+    }
+
+    unsafe fn enable_queue(self) {
+        self.set_queue_enable()
+    }
+
+    unsafe fn notify(self, queue_index: u16) {
+        self.set_queue_notify(queue_index)
+    }
+
+    unsafe fn read_device_specific<T: ConfigValue>(self, offset: u16) -> Option<T> {
+        ModernConfiguration::read_device_specific(self, offset as u32)
+    }
+
+    unsafe fn write_device_specific<T: ConfigValue>(self, offset: u16, value: T) -> Option<()> {
+        ModernConfiguration::write_device_specific(self, offset as u32, value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::info;
+
+    /// Put a `virtio_pci_cap` at `pointer`, followed by `extra` bytes.
+    fn put_cap(config: &mut [u8; 256], pointer: u8, next: u8, cfg_type: u8, bar: u8, extra: &[u8]) {
+        let p = pointer as usize;
+        config[p..p + 4].copy_from_slice(&[0x09, next, 16 + extra.len() as u8, cfg_type]);
+        config[p + 4] = bar;
+        config[p + 8..p + 12].copy_from_slice(&(0x1000 * cfg_type as u32).to_le_bytes());
+        config[p + 12..p + 16].copy_from_slice(&0x1000u32.to_le_bytes());
+        config[p + 16..p + 16 + extra.len()].copy_from_slice(extra);
+    }
+
+    fn cap(cfg_type: u8, bar: u8, notify_off_multiplier: u32) -> Option<VirtioCap> {
+        Some(VirtioCap {
+            cfg_type,
+            bar,
+            offset: 0x1000 * cfg_type as u32,
+            length: 0x1000,
+            notify_off_multiplier,
+        })
+    }
+
+    #[test_case]
+    fn test_parse_structures() {
+        info!("TESTING devices::virtio::modern::test_parse_structures");
+        // A capability list similar to what QEMU provides: MSI-X, then VirtIO structures
+        let mut config = [0; 256];
+        config[0x98..0x9c].copy_from_slice(&[0x11, 0x84, 0x0b, 0x80]); // MSI-X
+        put_cap(
+            &mut config,
+            0x84,
+            0x70,
+            5, /* PCI_CAP_PCI_CFG */
+            0,
+            &[0; 4],
+        );
+        put_cap(
+            &mut config,
+            0x70,
+            0x60,
+            PCI_CAP_NOTIFY_CFG,
+            4,
+            &4u32.to_le_bytes(),
+        );
+        put_cap(&mut config, 0x60, 0x50, PCI_CAP_DEVICE_CFG, 4, &[]);
+        put_cap(&mut config, 0x50, 0x40, PCI_CAP_ISR_CFG, 4, &[]);
+        put_cap(&mut config, 0x40, 0xb0, PCI_CAP_COMMON_CFG, 4, &[]);
+        put_cap(&mut config, 0xb0, 0x00, PCI_CAP_COMMON_CFG, 2, &[]); // ignored
+        assert_eq!(
+            Structures::parse(&config, 0x98),
+            Structures {
+                common: cap(PCI_CAP_COMMON_CFG, 4, 0),
+                notify: cap(PCI_CAP_NOTIFY_CFG, 4, 4),
+                isr: cap(PCI_CAP_ISR_CFG, 4, 0),
+                device: cap(PCI_CAP_DEVICE_CFG, 4, 0),
+            }
+        );
+
+        // Capabilities with reserved BARs and truncated notification capabilities are skipped
+        let mut config = [0; 256];
+        put_cap(&mut config, 0x40, 0x50, PCI_CAP_COMMON_CFG, 6, &[]);
+        put_cap(&mut config, 0x50, 0x60, PCI_CAP_NOTIFY_CFG, 4, &[]);
+        put_cap(&mut config, 0x60, 0x00, PCI_CAP_COMMON_CFG, 1, &[]);
+        assert_eq!(
+            Structures::parse(&config, 0x40),
+            Structures {
+                common: cap(PCI_CAP_COMMON_CFG, 1, 0),
+                ..Structures::default()
+            }
+        );
+
+        // A circular list terminates
+        let mut config = [0; 256];
+        put_cap(&mut config, 0x40, 0x40, PCI_CAP_ISR_CFG, 0, &[]);
+        assert_eq!(
+            Structures::parse(&config, 0x40),
+            Structures {
+                isr: cap(PCI_CAP_ISR_CFG, 0, 0),
+                ..Structures::default()
+            }
+        );
+        assert_eq!(Structures::parse(&config, 0), Structures::default());
+    }
+}
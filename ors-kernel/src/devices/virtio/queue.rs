@@ -1,11 +1,10 @@
-use super::Configuration;
+use super::Transport;
 use crate::paging::{as_phys_addr, as_virt_addr};
-use crate::phys_memory::{frame_manager, Frame};
+use crate::phys_memory::{frame_manager, Frame, Tag};
 use crate::x64;
 use alloc::vec::Vec;
-use core::mem;
-use core::ptr;
 use core::sync::atomic::{fence, Ordering};
+use core::{mem, ptr};
 use derive_new::new;
 
 #[derive(Debug)]
@@ -23,31 +22,47 @@ pub struct VirtQueue<T> {
 }
 
 impl<T> VirtQueue<T> {
-    /// Prepare the `queue_index`-th queue for the specified `configuration`.
+    /// Prepare the `queue_index`-th queue over the specified `transport`.
+    /// The queue is enabled before returning.
     pub unsafe fn new(
-        configuration: Configuration,
+        transport: impl Transport,
         queue_index: u16,
         msi_x_vector: Option<u16>,
     ) -> Result<Self, &'static str> {
-        configuration.set_queue_select(queue_index);
-        let queue_size = configuration.queue_size() as usize;
+        let queue_size = transport.select_queue(queue_index)? as usize;
         if queue_size == 0 {
             return Err("Queue is unavailable");
         }
 
+        // The legacy layout also satisfies the alignment requirements of the split virtqueue,
+        // so the same layout is used for both interfaces.
+        let queue = Self::allocate(queue_size)?;
         let layout = Self::compute_layout(queue_size);
-        let frame = frame_manager()
-            .allocate(layout.num_frames)
-            .map_err(|_| "Cannot allocate frame for this queue")?;
+        let base = queue.frame.phys_addr();
+        transport.set_queue_addresses(
+            base + layout.descriptor_table_offset,
+            base + layout.available_ring_offset,
+            base + layout.used_ring_offset,
+        )?;
 
-        let base_ptr: *mut u8 = as_virt_addr(frame.phys_addr()).unwrap().as_mut_ptr();
-        ptr::write_bytes(base_ptr, 0, Frame::SIZE * layout.num_frames); // zeroing
+        if let Some(vector) = msi_x_vector {
+            transport.set_queue_msix_vector(vector);
+        }
 
-        configuration.set_queue_address((frame.phys_addr().as_u64() / Frame::SIZE as u64) as u32);
+        transport.enable_queue();
+        Ok(queue)
+    }
 
-        if let Some(vector) = msi_x_vector {
-            configuration.set_queue_msix_vector(vector);
+    unsafe fn allocate(queue_size: usize) -> Result<Self, &'static str> {
+        let layout = Self::compute_layout(queue_size);
+        let frame = frame_manager()
+            .allocate_tagged(layout.num_frames, Tag::VirtQueue)
+            .map_err(|_| "Cannot allocate frame for this queue")?;
+
+        for i in 0..layout.num_frames {
+            frame.offset(i).as_slice_mut().unwrap().fill(0); // zeroing
         }
+        let base_ptr: *mut u8 = as_virt_addr(frame.phys_addr()).unwrap().as_mut_ptr();
 
         let descriptor_table = base_ptr.add(layout.descriptor_table_offset) as *mut Descriptor;
         let available_ring = base_ptr.add(layout.available_ring_offset) as *mut AvailableRing;
@@ -82,20 +97,17 @@ impl<T> VirtQueue<T> {
         // > bytes, but de-pending on the transport; henceforth referred to as Queue Align) and consists
         // > of three parts:
         // > | Descriptor Table | Available Ring (..padding..) | Used Ring |
-        fn align(x: usize) -> usize {
-            (x + Frame::SIZE - 1) & !(Frame::SIZE - 1)
-        }
-
         let descriptor_table_size = 16 * queue_size;
         let available_ring_size = 6 + 2 * queue_size;
         let used_ring_size = 6 + 8 * queue_size;
-        let a = align(descriptor_table_size + available_ring_size);
-        let b = align(used_ring_size);
+        // The sizes are small enough since queue_size is at most 2^15
+        let a = Frame::count_for_bytes(descriptor_table_size + available_ring_size).unwrap();
+        let b = Frame::count_for_bytes(used_ring_size).unwrap();
         VirtQueueLayout {
-            num_frames: (a + b) / Frame::SIZE,
+            num_frames: a + b,
             descriptor_table_offset: 0,
             available_ring_offset: descriptor_table_size,
-            used_ring_offset: a,
+            used_ring_offset: a * Frame::SIZE,
         }
     }
 
@@ -130,6 +142,33 @@ impl<T> VirtQueue<T> {
         }
     }
 
+    pub fn num_free_descriptors(&self) -> usize {
+        self.num_free_descriptors
+    }
+
+    /// Whether the device needs an Available Buffer Notification for the buffers transferred.
+    /// The device may suppress notifications while it is processing the available ring, on the
+    /// condition that it checks the ring again after re-enabling notifications.
+    pub fn needs_notification(&self) -> bool {
+        // The device must see the available ring index before we see the flags; otherwise both
+        // of us may miss the buffers
+        fence(Ordering::SeqCst);
+        let flags = unsafe { ptr::read_volatile(&(*self.used_ring).flags) };
+        (flags & UsedRing::NO_NOTIFY) == 0
+    }
+
+    /// Pretend that `n` buffers have been exchanged, by advancing the indices of both rings.
+    /// Only for testing the wrap-around of the indices, before anything is transferred.
+    #[cfg(test)]
+    pub(super) fn skip_indices(&mut self, n: u16) {
+        assert_eq!(self.num_free_descriptors, self.queue_size);
+        unsafe {
+            *self.available_ring_idx() = (*self.available_ring_idx()).wrapping_add(n);
+            *self.used_ring_idx() = (*self.used_ring_idx()).wrapping_add(n);
+        }
+        self.last_used_idx = self.last_used_idx.wrapping_add(n);
+    }
+
     /// Transfer the buffers to the device by allocating descriptors and put them to the available ring.
     /// This method does not send an Available Buffer Notification.
     pub fn transfer<I: ExactSizeIterator<Item = Buffer<T>>>(
@@ -220,7 +259,7 @@ impl<T> VirtQueue<T> {
 impl<T> Drop for VirtQueue<T> {
     fn drop(&mut self) {
         let layout = Self::compute_layout(self.queue_size);
-        frame_manager().free(self.frame, layout.num_frames);
+        frame_manager().free_tagged(self.frame, layout.num_frames, Tag::VirtQueue);
     }
 }
 
@@ -338,14 +377,202 @@ struct AvailableRing {
 // driver read-only
 #[repr(C)]
 struct UsedRing {
-    _flags: u16, // This can be used by device to supress Available Buffer Notification
+    flags: u16, // This can be used by device to supress Available Buffer Notification
     idx: u16,
     ring: [UsedElem; 0],
     // used_event: u16,
 }
 
+impl UsedRing {
+    const NO_NOTIFY: u16 = 1;
+}
+
 #[repr(C)]
 struct UsedElem {
     idx: u32,
     _len: u32, // Length of the Descriptor-chain. This value is unreliable in legacy interface.
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::virtio::mock::{MockBuffer, MockVirtioDevice, Sink};
+    use alloc::vec;
+    use log::info;
+
+    const QUEUE_SIZE: u16 = 8;
+
+    fn setup() -> (MockVirtioDevice<Sink>, VirtQueue<u32>) {
+        let device = MockVirtioDevice::new(QUEUE_SIZE, 0, Vec::new(), Sink::default());
+        let queue = unsafe { VirtQueue::new(device.transport(), 0, Some(3)) }.unwrap();
+        (device, queue)
+    }
+
+    /// A chain of `n` buffers referring distinct parts of `data`, associated with `first..`.
+    fn chain(data: &[u8; 128], first: u32, n: usize) -> impl ExactSizeIterator<Item = Buffer<u32>> {
+        let addr = as_phys_addr(x64::VirtAddr::from_ptr(data)).unwrap();
+        (0..n).map(move |i| Buffer::new(addr + 8 * i, 8, i % 2 == 1, first + i as u32))
+    }
+
+    fn kick(device: &MockVirtioDevice<Sink>, queue: &VirtQueue<u32>) {
+        if queue.needs_notification() {
+            unsafe { device.transport().notify(0) };
+        }
+    }
+
+    fn collect(queue: &mut VirtQueue<u32>) -> Vec<u32> {
+        let mut collected = Vec::new();
+        queue.collect(|id| collected.push(id));
+        collected
+    }
+
+    #[test_case]
+    fn test_virtqueue_exhaustion() {
+        info!("TESTING devices::virtio::queue::test_virtqueue_exhaustion");
+        let data = [0; 128];
+        let (device, mut queue) = setup();
+        assert_eq!(device.msix_vector(), Some(3));
+        assert!(queue.transfer(chain(&data, 0, 3)).is_ok());
+        assert!(queue.transfer(chain(&data, 3, 3)).is_ok());
+        // Rejected as a whole, giving the buffers back
+        let rest = queue.transfer(chain(&data, 6, 3)).err().unwrap();
+        assert_eq!(rest.len(), 3);
+        assert_eq!(queue.num_free_descriptors(), 2);
+        assert!(queue.transfer(chain(&data, 6, 2)).is_ok());
+        assert_eq!(queue.num_free_descriptors(), 0);
+        assert!(queue.transfer(chain(&data, 8, 1)).is_err());
+
+        kick(&device, &queue);
+        assert_eq!(device.run(), 3);
+        assert_eq!(collect(&mut queue), (0..8).collect::<Vec<_>>());
+        assert_eq!(queue.num_free_descriptors(), QUEUE_SIZE as usize);
+
+        // Every freed descriptor can be chained again
+        assert!(queue.transfer(chain(&data, 8, 8)).is_ok());
+        kick(&device, &queue);
+        assert_eq!(device.run(), 1);
+        assert_eq!(collect(&mut queue), (8..16).collect::<Vec<_>>());
+        let expected = chain(&data, 0, 8)
+            .map(|b| MockBuffer::new(b.addr, b.len, b.write))
+            .collect::<Vec<_>>();
+        device.with_personality(|sink| {
+            let lens = sink.chains.iter().map(|c| c.len()).collect::<Vec<_>>();
+            assert_eq!(lens, vec![3, 3, 2, 8]);
+            assert_eq!(sink.chains[3], expected);
+        });
+    }
+
+    #[test_case]
+    fn test_virtqueue_associated_data() {
+        info!("TESTING devices::virtio::queue::test_virtqueue_associated_data");
+        let data = [0; 128];
+        let (device, mut queue) = setup();
+        device.set_reorder(true);
+        device.set_delay(1);
+        let mut next = 0;
+        // The free list is shuffled by out-of-order completions in each round
+        for round in 0..4 {
+            let mut expected = Vec::new();
+            let mut chains = Vec::new();
+            for n in [1, 3, 2, 2].into_iter().cycle().skip(round).take(3) {
+                assert!(queue.transfer(chain(&data, next, n)).is_ok());
+                chains.push((next..next + n as u32).collect::<Vec<_>>());
+                next += n as u32;
+            }
+            for c in chains.iter().rev() {
+                expected.extend_from_slice(c);
+            }
+            kick(&device, &queue);
+            assert!(device.begin());
+            assert_eq!(device.fetch(), 3);
+            assert_eq!(device.complete(), 0);
+            assert!(collect(&mut queue).is_empty());
+            assert_eq!(device.complete(), 3);
+            assert!(!device.end());
+            // Each chain is collected in order, and the chains in the order of completion
+            assert_eq!(collect(&mut queue), expected);
+            assert_eq!(queue.num_free_descriptors(), QUEUE_SIZE as usize);
+        }
+        assert!(queue.transfer(chain(&data, 0, 8)).is_ok());
+        kick(&device, &queue);
+        assert_eq!(device.run(), 1);
+        assert_eq!(collect(&mut queue), (0..8).collect::<Vec<_>>());
+    }
+
+    #[test_case]
+    fn test_virtqueue_notification_suppression() {
+        info!("TESTING devices::virtio::queue::test_virtqueue_notification_suppression");
+        let data = [0; 128];
+        let (device, mut queue) = setup();
+
+        // An idle device needs a notification
+        assert!(queue.transfer(chain(&data, 0, 1)).is_ok());
+        assert!(queue.needs_notification());
+        kick(&device, &queue);
+        assert_eq!(device.notifications(), 1);
+
+        // A working device does not
+        assert!(device.begin());
+        assert_eq!(device.fetch(), 1);
+        assert!(queue.transfer(chain(&data, 1, 1)).is_ok());
+        assert!(!queue.needs_notification());
+        assert_eq!(device.complete(), 1);
+        // ...since it checks the ring again after re-enabling notifications
+        assert!(device.end());
+        assert_eq!(device.fetch(), 1);
+        assert_eq!(device.complete(), 1);
+
+        // Transferred after the device re-enabled notifications
+        assert!(!device.end());
+        assert!(queue.transfer(chain(&data, 2, 1)).is_ok());
+        kick(&device, &queue);
+        assert_eq!(device.notifications(), 2);
+
+        // Transferred before the device re-enabled notifications: the re-check of the device
+        // finds the buffer, and the device keeps notifications suppressed
+        assert!(device.begin());
+        assert_eq!(device.fetch(), 1);
+        assert_eq!(device.complete(), 1);
+        assert!(queue.transfer(chain(&data, 3, 1)).is_ok());
+        assert!(device.end());
+        assert!(!queue.needs_notification());
+        assert_eq!(device.fetch(), 1);
+        assert_eq!(device.complete(), 1);
+        assert!(!device.end());
+        assert!(queue.transfer(chain(&data, 4, 1)).is_ok());
+        kick(&device, &queue);
+        assert_eq!(device.run(), 1);
+        assert_eq!(device.notifications(), 3);
+
+        // Nothing is lost
+        assert_eq!(collect(&mut queue), vec![0, 1, 2, 3, 4]);
+        assert_eq!(device.run(), 0);
+    }
+
+    #[test_case]
+    fn test_virtqueue_wrap_around() {
+        info!("TESTING devices::virtio::queue::test_virtqueue_wrap_around");
+        let data = [0; 128];
+        let (device, mut queue) = setup();
+        let n = u16::MAX - 2;
+        queue.skip_indices(n);
+        device.skip_indices(n);
+        device.set_reorder(true);
+
+        let mut next = 0;
+        for _ in 0..3 {
+            let mut expected = Vec::new();
+            for len in [1, 2, 1, 3] {
+                assert!(queue.transfer(chain(&data, next, len)).is_ok());
+                expected.push((next..next + len as u32).collect::<Vec<_>>());
+                next += len as u32;
+            }
+            kick(&device, &queue);
+            assert_eq!(device.run(), 4);
+            let expected = expected.into_iter().rev().flatten().collect::<Vec<_>>();
+            assert_eq!(collect(&mut queue), expected);
+            assert_eq!(queue.num_free_descriptors(), QUEUE_SIZE as usize);
+        }
+        assert_eq!(queue.last_used_idx, n.wrapping_add(12));
+    }
+}
@@ -0,0 +1,139 @@
+use super::{Configuration, ModernConfiguration};
+use crate::devices::pci;
+use crate::x64;
+
+/// Operations of a VirtIO transport that drivers depend on.
+/// Queue operations apply to the queue selected by the last `select_queue`.
+pub trait Transport: Copy {
+    /// Perform general driver initialization, accepting the features returned by `negotiate`.
+    /// After calling this, caller must perform device-specific setup (including virtqueue setup)
+    /// and then call `Transport::set_driver_ok`.
+    unsafe fn initialize(self, negotiate: impl FnOnce(u64) -> u64) -> Result<(), &'static str>;
+
+    unsafe fn set_driver_ok(self);
+
+    /// Select the `queue_index`-th queue and returns its size. 0 means that the queue is unavailable.
+    unsafe fn select_queue(self, queue_index: u16) -> Result<u16, &'static str>;
+
+    /// Tell the device where the descriptor table, the available ring (driver area), and the
+    /// used ring (device area) of the selected queue are.
+    unsafe fn set_queue_addresses(
+        self,
+        desc: x64::PhysAddr,
+        driver: x64::PhysAddr,
+        device: x64::PhysAddr,
+    ) -> Result<(), &'static str>;
+
+    unsafe fn set_queue_msix_vector(self, vector: u16);
+
+    /// Make the selected queue available to the device.
+    unsafe fn enable_queue(self);
+
+    /// Send an Available Buffer Notification for the `queue_index`-th queue.
+    unsafe fn notify(self, queue_index: u16);
+
+    /// Returns `None` if the device does not have the device-specific configuration.
+    unsafe fn read_device_specific<T: ConfigValue>(self, offset: u16) -> Option<T>;
+
+    unsafe fn write_device_specific<T: ConfigValue>(self, offset: u16, value: T) -> Option<()>;
+}
+
+/// Values that can be read from and written to device-specific configurations.
+pub trait ConfigValue: Copy + x64::PortRead + x64::PortWrite {}
+
+impl ConfigValue for u8 {}
+impl ConfigValue for u16 {}
+impl ConfigValue for u32 {}
+
+/// A transport of VirtIO over PCI bus, chosen for each device.
+#[derive(Debug, Clone, Copy)]
+pub enum PciTransport {
+    Legacy(Configuration),
+    Modern(ModernConfiguration),
+}
+
+impl PciTransport {
+    /// Transitional devices are driven through the legacy interface as before, and the others
+    /// through the modern interface.
+    pub unsafe fn from_pci_device(device: pci::Device) -> Result<Self, &'static str> {
+        if device.is_virtio() && device.read_bar(0).io_port().is_some() {
+            Ok(Self::Legacy(Configuration::from_pci_device(device)?))
+        } else {
+            Ok(Self::Modern(ModernConfiguration::from_pci_device(device)?))
+        }
+    }
+
+    pub fn is_modern(self) -> bool {
+        matches!(self, Self::Modern(_))
+    }
+}
+
+impl Transport for PciTransport {
+    unsafe fn initialize(self, negotiate: impl FnOnce(u64) -> u64) -> Result<(), &'static str> {
+        match self {
+            Self::Legacy(c) => c.initialize(negotiate),
+            Self::Modern(c) => c.initialize(negotiate),
+        }
+    }
+
+    unsafe fn set_driver_ok(self) {
+        match self {
+            Self::Legacy(c) => c.set_driver_ok(),
+            Self::Modern(c) => c.set_driver_ok(),
+        }
+    }
+
+    unsafe fn select_queue(self, queue_index: u16) -> Result<u16, &'static str> {
+        match self {
+            Self::Legacy(c) => c.select_queue(queue_index),
+            Self::Modern(c) => c.select_queue(queue_index),
+        }
+    }
+
+    unsafe fn set_queue_addresses(
+        self,
+        desc: x64::PhysAddr,
+        driver: x64::PhysAddr,
+        device: x64::PhysAddr,
+    ) -> Result<(), &'static str> {
+        match self {
+            Self::Legacy(c) => c.set_queue_addresses(desc, driver, device),
+            Self::Modern(c) => c.set_queue_addresses(desc, driver, device),
+        }
+    }
+
+    unsafe fn set_queue_msix_vector(self, vector: u16) {
+        match self {
+            Self::Legacy(c) => Transport::set_queue_msix_vector(c, vector),
+            Self::Modern(c) => Transport::set_queue_msix_vector(c, vector),
+        }
+    }
+
+    unsafe fn enable_queue(self) {
+        match self {
+            Self::Legacy(c) => c.enable_queue(),
+            Self::Modern(c) => c.enable_queue(),
+        }
+    }
+
+    unsafe fn notify(self, queue_index: u16) {
+        match self {
+            Self::Legacy(c) => c.notify(queue_index),
+            Self::Modern(c) => c.notify(queue_index),
+        }
+    }
+
+    unsafe fn read_device_specific<T: ConfigValue>(self, offset: u16) -> Option<T> {
+        match self {
+            Self::Legacy(c) => Transport::read_device_specific(c, offset),
+            Self::Modern(c) => Transport::read_device_specific(c, offset),
+        }
+    }
+
+    unsafe fn write_device_specific<T: ConfigValue>(self, offset: u16, value: T) -> Option<()> {
+        match self {
+            Self::Legacy(c) => Transport::write_device_specific(c, offset, value),
+            Self::Modern(c) => Transport::write_device_specific(c, offset, value),
+        }
+    }
+}
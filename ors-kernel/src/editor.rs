@@ -0,0 +1,206 @@
+//! A minimal full-screen text editor on the console.
+//!
+//! Characters other than printable ASCII are shown as `?` (tabs as a space), since the cursor
+//! math assumes that each character occupies a single column.
+
+use crate::console::{self, read_input, Input};
+use alloc::format;
+use alloc::string::String;
+use core::fmt::{self, Write as _};
+
+mod buffer;
+
+use buffer::{Buffer, Viewport};
+
+static ENTER: &str = "\x1b[?1049h\x1b[H\x1b[2J";
+static LEAVE: &str = "\x1b[0m\x1b[2J\x1b[?1049l";
+static CURSOR_START: &str = "\x1b[30;47m";
+static CURSOR_END: &str = "\x1b[0m";
+static LINE_NUMBER_START: &str = "\x1b[90m";
+static LINE_NUMBER_END: &str = "\x1b[0m";
+static STATUS_START: &str = "\x1b[30;47m";
+static STATUS_END: &str = "\x1b[0m";
+
+/// Edit the text on the alternate screen until the user quits. `save` is called with the whole
+/// text on Ctrl-S.
+pub fn edit<E: fmt::Display>(name: &str, text: &str, mut save: impl FnMut(&str) -> Result<(), E>) {
+    let mut editor = Editor::new(name, Buffer::new(text));
+    kprint!("{}", ENTER);
+
+    loop {
+        editor.render();
+        let input = read_input();
+        editor.message.clear();
+        match input {
+            Input::Ctrl('s') => editor.save(&mut save),
+            Input::Ctrl('q') if !editor.buffer.is_modified() => break,
+            Input::Ctrl('q') => {
+                editor
+                    .message
+                    .push_str("Save changes? (y: save and quit, n: discard, other: cancel)");
+                editor.render();
+                editor.message.clear();
+                match read_input() {
+                    Input::Char('y' | 'Y') => {
+                        editor.save(&mut save);
+                        if !editor.buffer.is_modified() {
+                            break;
+                        }
+                    }
+                    Input::Char('n' | 'N') => break,
+                    _ => {}
+                }
+            }
+            Input::Char('\n') => editor.buffer.split_line(),
+            Input::Char('\x08' /* BS */) => editor.buffer.backspace(),
+            Input::Char('\x7f' /* DEL */) => editor.buffer.delete(),
+            Input::Char(c @ ('\t' | ' '..='~')) => editor.buffer.insert(c),
+            Input::Home => editor.buffer.move_home(),
+            Input::End => editor.buffer.move_end(),
+            Input::PageUp => editor.buffer.move_up(editor.viewport.lines),
+            Input::PageDown => editor.buffer.move_down(editor.viewport.lines),
+            Input::ArrowUp => editor.buffer.move_up(1),
+            Input::ArrowDown => editor.buffer.move_down(1),
+            Input::ArrowLeft => editor.buffer.move_left(),
+            Input::ArrowRight => editor.buffer.move_right(),
+            _ => {}
+        }
+    }
+
+    kprint!("{}", LEAVE);
+}
+
+#[derive(Debug)]
+struct Editor<'a> {
+    name: &'a str,
+    buffer: Buffer,
+    viewport: Viewport,
+    /// The width of the line numbers, including the following space.
+    gutter: usize,
+    /// The line on which the cursor was drawn last time.
+    cursor_y: Option<usize>,
+    /// A message shown in the status bar until the next input.
+    message: String,
+}
+
+impl<'a> Editor<'a> {
+    fn new(name: &'a str, buffer: Buffer) -> Self {
+        Self {
+            name,
+            buffer,
+            viewport: Viewport::new(1, 1),
+            gutter: 0,
+            cursor_y: None,
+            message: String::new(),
+        }
+    }
+
+    fn save<E: fmt::Display>(&mut self, save: &mut impl FnMut(&str) -> Result<(), E>) {
+        let text = self.buffer.text();
+        match save(&text) {
+            Ok(()) => {
+                self.buffer.mark_saved();
+                let _ = write!(self.message, "Saved {} bytes", text.len());
+            }
+            Err(e) => {
+                let _ = write!(self.message, "Failed to save: {}", e);
+            }
+        }
+    }
+
+    /// Redraw the lines changed since the last call, the lines on which the cursor moved, and
+    /// the status bar. The whole screen is redrawn when the viewport is scrolled or resized.
+    fn render(&mut self) {
+        let (columns, lines) = console::size();
+        let gutter = format!("{}", self.buffer.len()).len().max(3) + 1;
+        let mut redraw_all = self.gutter != gutter;
+        self.gutter = gutter;
+        // The last column is left blank, since erasing after the last column erases it
+        redraw_all |= self
+            .viewport
+            .resize(columns.saturating_sub(gutter + 1), lines.saturating_sub(1));
+        redraw_all |= self.viewport.follow(self.buffer.cursor());
+        let changes = self.buffer.take_changes().unwrap_or(0..0);
+        let (_, cursor_y) = self.buffer.cursor();
+
+        let mut s = String::new();
+        for row in 0..self.viewport.lines {
+            let y = self.viewport.top + row;
+            if redraw_all || changes.contains(&y) || y == cursor_y || Some(y) == self.cursor_y {
+                self.render_line(&mut s, row, y);
+            }
+        }
+        self.cursor_y = Some(cursor_y);
+        self.render_status(&mut s, columns, self.viewport.lines);
+
+        // Place the cursor of the terminal, which is visible on the serial port
+        if let Some((x, y)) = self.viewport.to_screen(self.buffer.cursor()) {
+            let _ = write!(s, "\x1b[{};{}H", y + 1, gutter + x + 1);
+        }
+        kprint!("{}", s);
+    }
+
+    fn render_line(&self, s: &mut String, row: usize, y: usize) {
+        let _ = write!(s, "\x1b[{};1H", row + 1);
+        if let Some(line) = self.buffer.line(y) {
+            let _ = write!(
+                s,
+                "{}{:>w$}│{}",
+                LINE_NUMBER_START,
+                y + 1,
+                LINE_NUMBER_END,
+                w = self.gutter - 1
+            );
+            let (cursor_x, cursor_y) = self.buffer.cursor();
+            let cursor_x = if cursor_y == y { Some(cursor_x) } else { None };
+            let chars = line.chars().chain(core::iter::once(' ')); // the cursor at the end
+            for (x, c) in chars
+                .enumerate()
+                .skip(self.viewport.left)
+                .take(self.viewport.columns)
+            {
+                let c = match c {
+                    '\t' => ' ',
+                    ' '..='~' => c,
+                    _ => '?',
+                };
+                if Some(x) == cursor_x {
+                    let _ = write!(s, "{}{}{}", CURSOR_START, c, CURSOR_END);
+                } else {
+                    s.push(c);
+                }
+            }
+        }
+        s.push_str("\x1b[K");
+    }
+
+    fn render_status(&self, s: &mut String, columns: usize, row: usize) {
+        let (x, y) = self.buffer.cursor();
+        let left = if self.message.is_empty() {
+            format!(
+                " {}{}",
+                self.name,
+                if self.buffer.is_modified() {
+                    " [+]"
+                } else {
+                    ""
+                }
+            )
+        } else {
+            format!(" {}", self.message)
+        };
+        let right = format!("{}:{}  ^S Save  ^Q Quit ", y + 1, x + 1);
+        let status = match columns.checked_sub(left.len() + right.len()) {
+            Some(pad) => format!("{}{:pad$}{}", left, "", right, pad = pad),
+            None => format!("{:.w$}", left, w = columns),
+        };
+        let _ = write!(
+            s,
+            "\x1b[{};1H{}{}{}",
+            row + 1,
+            STATUS_START,
+            status,
+            STATUS_END
+        );
+    }
+}
@@ -0,0 +1,390 @@
+//! Editing operations and cursor math of the editor, independent of the console.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::ops::Range;
+
+/// Lines of text with a cursor. Positions are (x, y), where x is counted in characters.
+#[derive(Debug)]
+pub struct Buffer {
+    lines: Vec<String>,
+    cursor: (usize, usize),
+    /// The column the cursor returns to when moving vertically across shorter lines.
+    goal_x: usize,
+    modified: bool,
+    changes: Option<(usize, usize)>,
+}
+
+impl Buffer {
+    /// Create a buffer of the text. A trailing newline leaves an empty last line, which is
+    /// written back as is by `text`.
+    pub fn new(text: &str) -> Self {
+        Self {
+            lines: text.split('\n').map(String::from).collect(),
+            cursor: (0, 0),
+            goal_x: 0,
+            modified: false,
+            changes: None,
+        }
+    }
+
+    /// The text of the buffer. A newline is appended to a non-empty last line.
+    pub fn text(&self) -> String {
+        let mut text = self.lines.join("\n");
+        if !self.lines.last().unwrap().is_empty() {
+            text.push('\n');
+        }
+        text
+    }
+
+    pub fn len(&self) -> usize {
+        self.lines.len()
+    }
+
+    pub fn line(&self, y: usize) -> Option<&str> {
+        self.lines.get(y).map(|l| l.as_str())
+    }
+
+    pub fn cursor(&self) -> (usize, usize) {
+        self.cursor
+    }
+
+    /// Whether the buffer has been modified since it was created or saved.
+    pub fn is_modified(&self) -> bool {
+        self.modified
+    }
+
+    pub fn mark_saved(&mut self) {
+        self.modified = false;
+    }
+
+    /// Take the range of lines changed since the last call. The range may extend past the last
+    /// line when lines are removed.
+    pub fn take_changes(&mut self) -> Option<Range<usize>> {
+        self.changes.take().map(|(a, b)| a..b)
+    }
+
+    pub fn insert(&mut self, c: char) {
+        let (x, y) = self.cursor;
+        let i = byte_index(&self.lines[y], x);
+        self.lines[y].insert(i, c);
+        self.set_x(x + 1);
+        self.changed(y, y + 1);
+    }
+
+    /// Split the line at the cursor, moving the cursor to the beginning of the new line.
+    pub fn split_line(&mut self) {
+        let (x, y) = self.cursor;
+        let i = byte_index(&self.lines[y], x);
+        let rest = self.lines[y].split_off(i);
+        self.lines.insert(y + 1, rest);
+        self.cursor = (0, y + 1);
+        self.goal_x = 0;
+        self.changed(y, self.lines.len());
+    }
+
+    /// Delete the character before the cursor, or join the line to the previous line at the
+    /// beginning of a line.
+    pub fn backspace(&mut self) {
+        let (x, y) = self.cursor;
+        if 0 < x {
+            let i = byte_index(&self.lines[y], x - 1);
+            self.lines[y].remove(i);
+            self.set_x(x - 1);
+            self.changed(y, y + 1);
+        } else if 0 < y {
+            let line = self.lines.remove(y);
+            let x = self.line_len(y - 1);
+            self.lines[y - 1].push_str(&line);
+            self.cursor = (x, y - 1);
+            self.goal_x = x;
+            self.changed(y - 1, self.lines.len() + 1);
+        }
+    }
+
+    /// Delete the character at the cursor, or join the next line to the line at the end of a
+    /// line.
+    pub fn delete(&mut self) {
+        let (x, y) = self.cursor;
+        if x < self.line_len(y) {
+            let i = byte_index(&self.lines[y], x);
+            self.lines[y].remove(i);
+            self.changed(y, y + 1);
+        } else if y + 1 < self.lines.len() {
+            let line = self.lines.remove(y + 1);
+            self.lines[y].push_str(&line);
+            self.changed(y, self.lines.len() + 1);
+        }
+    }
+
+    pub fn move_left(&mut self) {
+        let (x, y) = self.cursor;
+        if 0 < x {
+            self.set_x(x - 1);
+        } else if 0 < y {
+            self.cursor = (0, y - 1);
+            self.set_x(self.line_len(y - 1));
+        }
+    }
+
+    pub fn move_right(&mut self) {
+        let (x, y) = self.cursor;
+        if x < self.line_len(y) {
+            self.set_x(x + 1);
+        } else if y + 1 < self.lines.len() {
+            self.cursor = (0, y + 1);
+            self.goal_x = 0;
+        }
+    }
+
+    pub fn move_up(&mut self, n: usize) {
+        self.set_y(self.cursor.1.saturating_sub(n));
+    }
+
+    pub fn move_down(&mut self, n: usize) {
+        self.set_y((self.cursor.1 + n).min(self.lines.len() - 1));
+    }
+
+    pub fn move_home(&mut self) {
+        self.set_x(0);
+    }
+
+    pub fn move_end(&mut self) {
+        self.set_x(self.line_len(self.cursor.1));
+    }
+
+    fn line_len(&self, y: usize) -> usize {
+        self.lines[y].chars().count()
+    }
+
+    fn set_x(&mut self, x: usize) {
+        self.cursor.0 = x;
+        self.goal_x = x;
+    }
+
+    fn set_y(&mut self, y: usize) {
+        self.cursor = (self.goal_x.min(self.line_len(y)), y);
+    }
+
+    fn changed(&mut self, start: usize, end: usize) {
+        self.modified = true;
+        self.changes = match self.changes {
+            None => Some((start, end)),
+            Some((a, b)) => Some((a.min(start), b.max(end))),
+        };
+    }
+}
+
+fn byte_index(line: &str, x: usize) -> usize {
+    line.char_indices().nth(x).map_or(line.len(), |(i, _)| i)
+}
+
+/// The part of the buffer shown on the screen.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct Viewport {
+    pub left: usize,
+    pub top: usize,
+    pub columns: usize,
+    pub lines: usize,
+}
+
+impl Viewport {
+    pub fn new(columns: usize, lines: usize) -> Self {
+        Self {
+            left: 0,
+            top: 0,
+            columns: columns.max(1),
+            lines: lines.max(1),
+        }
+    }
+
+    /// Change the size of the viewport. Returns whether the size is changed.
+    pub fn resize(&mut self, columns: usize, lines: usize) -> bool {
+        let old = *self;
+        self.columns = columns.max(1);
+        self.lines = lines.max(1);
+        *self != old
+    }
+
+    /// Scroll the viewport by the minimum amount so that the position is visible. Returns whether
+    /// the viewport is scrolled.
+    pub fn follow(&mut self, (x, y): (usize, usize)) -> bool {
+        let old = *self;
+        self.left = follow(self.left, self.columns, x);
+        self.top = follow(self.top, self.lines, y);
+        *self != old
+    }
+
+    /// The position in the viewport of the position in the buffer, if visible.
+    pub fn to_screen(&self, (x, y): (usize, usize)) -> Option<(usize, usize)> {
+        let x = x.checked_sub(self.left).filter(|x| *x < self.columns)?;
+        let y = y.checked_sub(self.top).filter(|y| *y < self.lines)?;
+        Some((x, y))
+    }
+}
+
+fn follow(start: usize, len: usize, i: usize) -> usize {
+    if i < start {
+        i
+    } else if start + len <= i {
+        i + 1 - len
+    } else {
+        start
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::info;
+
+    fn buffer_at(text: &str, cursor: (usize, usize)) -> Buffer {
+        let mut buf = Buffer::new(text);
+        buf.move_down(cursor.1);
+        for _ in 0..cursor.0 {
+            buf.move_right();
+        }
+        assert_eq!(buf.cursor(), cursor);
+        buf
+    }
+
+    #[test_case]
+    fn test_text() {
+        info!("TESTING editor::buffer::test_text");
+        for (text, lines, saved) in [
+            ("", 1, ""),
+            ("abc", 1, "abc\n"),
+            ("abc\n", 2, "abc\n"),
+            ("abc\n\ndef\n", 4, "abc\n\ndef\n"),
+        ] {
+            let buf = Buffer::new(text);
+            assert_eq!(buf.len(), lines, "{:?}", text);
+            assert_eq!(buf.text(), saved, "{:?}", text);
+            assert!(!buf.is_modified());
+        }
+    }
+
+    #[test_case]
+    fn test_insert() {
+        info!("TESTING editor::buffer::test_insert");
+        let mut buf = buffer_at("ac\n漢字\n", (1, 0));
+        buf.insert('b');
+        assert_eq!(buf.line(0), Some("abc"));
+        assert_eq!(buf.cursor(), (2, 0));
+        assert_eq!(buf.take_changes(), Some(0..1));
+        assert_eq!(buf.take_changes(), None);
+        assert!(buf.is_modified());
+
+        // The cursor is counted in characters, not in bytes
+        buf.move_down(1);
+        buf.insert('と');
+        assert_eq!(buf.line(1), Some("漢字と"));
+        assert_eq!(buf.cursor(), (3, 1));
+        assert_eq!(buf.text(), "abc\n漢字と\n");
+
+        buf.mark_saved();
+        assert!(!buf.is_modified());
+    }
+
+    #[test_case]
+    fn test_split_and_join() {
+        info!("TESTING editor::buffer::test_split_and_join");
+        let mut buf = buffer_at("abcd\nef", (2, 0));
+        buf.split_line();
+        assert_eq!(buf.text(), "ab\ncd\nef\n");
+        assert_eq!(buf.cursor(), (0, 1));
+        assert_eq!(buf.take_changes(), Some(0..3));
+
+        buf.backspace();
+        assert_eq!(buf.text(), "abcd\nef\n");
+        assert_eq!(buf.cursor(), (2, 0));
+        assert_eq!(buf.take_changes(), Some(0..3)); // the last line is cleared
+
+        buf.move_end();
+        buf.delete();
+        assert_eq!(buf.text(), "abcdef\n");
+        assert_eq!(buf.cursor(), (4, 0));
+        assert_eq!(buf.take_changes(), Some(0..2));
+
+        // Nothing to join at both ends of the buffer
+        buf.move_end();
+        buf.delete();
+        buf.move_home();
+        buf.backspace();
+        assert_eq!(buf.text(), "abcdef\n");
+        assert_eq!(buf.take_changes(), None);
+    }
+
+    #[test_case]
+    fn test_delete_chars() {
+        info!("TESTING editor::buffer::test_delete_chars");
+        let mut buf = buffer_at("a漢b", (2, 0));
+        buf.backspace();
+        assert_eq!(buf.line(0), Some("ab"));
+        assert_eq!(buf.cursor(), (1, 0));
+        buf.delete();
+        assert_eq!(buf.line(0), Some("a"));
+        assert_eq!(buf.cursor(), (1, 0));
+        assert_eq!(buf.take_changes(), Some(0..1));
+    }
+
+    #[test_case]
+    fn test_move() {
+        info!("TESTING editor::buffer::test_move");
+        let mut buf = buffer_at("abcdef\nab\n\nabcd", (5, 0));
+        // The column is kept across shorter lines
+        buf.move_down(1);
+        assert_eq!(buf.cursor(), (2, 1));
+        buf.move_down(1);
+        assert_eq!(buf.cursor(), (0, 2));
+        buf.move_down(1);
+        assert_eq!(buf.cursor(), (4, 3));
+        buf.move_down(10);
+        assert_eq!(buf.cursor(), (4, 3));
+        buf.move_up(10);
+        assert_eq!(buf.cursor(), (5, 0));
+
+        // Horizontal moves wrap around line ends and reset the column to keep
+        buf.move_right();
+        buf.move_right();
+        assert_eq!(buf.cursor(), (0, 1));
+        buf.move_left();
+        assert_eq!(buf.cursor(), (6, 0));
+        buf.move_home();
+        buf.move_left();
+        assert_eq!(buf.cursor(), (0, 0));
+        buf.move_end();
+        buf.move_down(1);
+        buf.move_left();
+        buf.move_down(2);
+        assert_eq!(buf.cursor(), (1, 3));
+        buf.move_end();
+        buf.move_right();
+        assert_eq!(buf.cursor(), (4, 3));
+
+        assert!(!buf.is_modified());
+        assert_eq!(buf.take_changes(), None);
+    }
+
+    #[test_case]
+    fn test_viewport() {
+        info!("TESTING editor::buffer::test_viewport");
+        let mut v = Viewport::new(10, 5);
+        assert!(!v.follow((9, 4)));
+        assert_eq!(v.to_screen((9, 4)), Some((9, 4)));
+        assert_eq!(v.to_screen((10, 4)), None);
+
+        assert!(v.follow((10, 7)));
+        assert_eq!((v.left, v.top), (1, 3));
+        assert_eq!(v.to_screen((10, 7)), Some((9, 4)));
+        assert_eq!(v.to_screen((0, 7)), None);
+        assert!(v.follow((0, 0)));
+        assert_eq!((v.left, v.top), (0, 0));
+
+        assert!(!v.resize(10, 5));
+        assert!(v.resize(4, 0));
+        assert_eq!((v.columns, v.lines), (4, 1));
+        v.follow((4, 2));
+        assert_eq!((v.left, v.top), (1, 2));
+    }
+}
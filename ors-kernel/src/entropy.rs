@@ -0,0 +1,322 @@
+//! Entropy pool fed by the timing of interrupts and device events.
+//!
+//! `add` is called from interrupt handlers. It records a timestamp and a value into a per-CPU
+//! buffer without taking any lock. The buffers are flushed into the pool from task context when
+//! random values are requested. The pool is not cryptographically strong: it is intended for
+//! kernel needs such as volume ids and stack placement.
+
+use crate::cpu::{self, Cpu};
+use crate::interrupts::ticks;
+use crate::sync::spin::Spin;
+use crate::x64::cpuid;
+use core::arch::x86_64::_rdtsc;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use log::warn;
+
+const BUFFER_SIZE: usize = 32;
+
+/// The number of events to be mixed before the pool is considered to be seeded.
+pub const MIN_EVENTS: usize = 64;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_BUFFER: CpuBuffer = CpuBuffer::new();
+static BUFFERS: [CpuBuffer; cpu::MAX_COUNT] = [EMPTY_BUFFER; cpu::MAX_COUNT];
+
+static POOL: Spin<Pool<SipMixer>> = Spin::new(Pool::new(SipMixer::new()));
+static WARNED: AtomicBool = AtomicBool::new(false);
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Source {
+    Timer,
+    Keyboard,
+    Com1,
+    VirtIOBlock,
+    VirtIOInput,
+}
+
+/// Record an event. This is safe to call from interrupt handlers since it never takes a lock.
+pub fn add(source: Source, value: u64) {
+    if let Some(buffer) = BUFFERS.get(Cpu::current().index()) {
+        buffer.push(source, value, timestamp());
+    }
+}
+
+/// The TSC, or timer ticks on CPUs without it, which carry far less jitter.
+fn timestamp() -> u64 {
+    if cpuid::info().features.tsc {
+        unsafe { _rdtsc() }
+    } else {
+        ticks() as u64
+    }
+}
+
+/// Take a random value from the pool. If not enough events have been mixed into the pool yet,
+/// a value of a xorshift generator seeded by the TSC is returned instead.
+pub fn random_u64() -> u64 {
+    let mut pool = POOL.lock();
+    pool.flush(&BUFFERS);
+    if let Some(value) = pool.take() {
+        return value;
+    }
+    let (value, mixed_events) = (pool.fallback(), pool.mixed_events);
+    drop(pool);
+    if !WARNED.swap(true, Ordering::SeqCst) {
+        warn!(
+            "entropy: Only {} events are mixed, falling back to xorshift",
+            mixed_events
+        );
+    }
+    value
+}
+
+/// Fill the buffer with random bytes. See `random_u64`.
+pub fn fill_bytes(buf: &mut [u8]) {
+    for chunk in buf.chunks_mut(8) {
+        let value = random_u64().to_le_bytes();
+        chunk.copy_from_slice(&value[..chunk.len()]);
+    }
+}
+
+/// Whether enough events have been mixed into the pool.
+pub fn is_seeded() -> bool {
+    let mut pool = POOL.lock();
+    pool.flush(&BUFFERS);
+    pool.mixed_events >= MIN_EVENTS
+}
+
+/// The number of events mixed into the pool so far.
+pub fn mixed_events() -> usize {
+    let mut pool = POOL.lock();
+    pool.flush(&BUFFERS);
+    pool.mixed_events
+}
+
+/// A ring of recent events of a CPU. Events that are not flushed before being overwritten are
+/// lost, which is acceptable since they are only a source of entropy.
+#[derive(Debug)]
+struct CpuBuffer {
+    events: [AtomicU64; BUFFER_SIZE],
+    added: AtomicUsize, // The total number of events added, the next event is placed at here
+    flushed: AtomicUsize, // The value of `added` at the last flush
+}
+
+impl CpuBuffer {
+    const fn new() -> Self {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const ZERO: AtomicU64 = AtomicU64::new(0);
+        Self {
+            events: [ZERO; BUFFER_SIZE],
+            added: AtomicUsize::new(0),
+            flushed: AtomicUsize::new(0),
+        }
+    }
+
+    fn push(&self, source: Source, value: u64, timestamp: u64) {
+        // The low bits of the timestamp carry most of the jitter, so the value is kept away from them
+        let event = timestamp ^ value.rotate_left(24) ^ ((source as u64) << 56);
+        let i = self.added.fetch_add(1, Ordering::Relaxed);
+        self.events[i % BUFFER_SIZE].store(event, Ordering::Relaxed);
+    }
+}
+
+/// A mixing function of the pool. This can be replaced with a stronger one without changing
+/// the rest of the pool.
+pub trait Mixer {
+    /// Mix a word into the state.
+    fn absorb(&mut self, word: u64);
+
+    /// Derive a word from the state. The state is updated so that the output does not reveal it.
+    fn squeeze(&mut self) -> u64;
+}
+
+/// A 256-bit state permuted by SipRounds.
+#[derive(Debug, Clone)]
+pub struct SipMixer {
+    v: [u64; 4],
+}
+
+impl SipMixer {
+    pub const fn new() -> Self {
+        // "somepseudorandomlygeneratedbytes", the initial state of SipHash
+        Self {
+            v: [
+                0x736f6d6570736575,
+                0x646f72616e646f6d,
+                0x6c7967656e657261,
+                0x7465646279746573,
+            ],
+        }
+    }
+
+    fn round(&mut self) {
+        let [v0, v1, v2, v3] = &mut self.v;
+        *v0 = v0.wrapping_add(*v1);
+        *v1 = v1.rotate_left(13) ^ *v0;
+        *v0 = v0.rotate_left(32);
+        *v2 = v2.wrapping_add(*v3);
+        *v3 = v3.rotate_left(16) ^ *v2;
+        *v0 = v0.wrapping_add(*v3);
+        *v3 = v3.rotate_left(21) ^ *v0;
+        *v2 = v2.wrapping_add(*v1);
+        *v1 = v1.rotate_left(17) ^ *v2;
+        *v2 = v2.rotate_left(32);
+    }
+}
+
+impl Default for SipMixer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Mixer for SipMixer {
+    fn absorb(&mut self, word: u64) {
+        self.v[3] ^= word;
+        self.round();
+        self.round();
+        self.v[0] ^= word;
+    }
+
+    fn squeeze(&mut self) -> u64 {
+        self.v[2] ^= 0xff;
+        for _ in 0..4 {
+            self.round();
+        }
+        let output = self.v[0] ^ self.v[1] ^ self.v[2] ^ self.v[3];
+        self.absorb(output);
+        output
+    }
+}
+
+#[derive(Debug)]
+struct Pool<M> {
+    mixer: M,
+    mixed_events: usize,
+    xorshift: u64,
+}
+
+impl<M> Pool<M> {
+    const fn new(mixer: M) -> Self {
+        Self {
+            mixer,
+            mixed_events: 0,
+            xorshift: 0,
+        }
+    }
+}
+
+impl<M: Mixer> Pool<M> {
+    fn flush(&mut self, buffers: &[CpuBuffer]) {
+        for buffer in buffers {
+            let added = buffer.added.load(Ordering::Relaxed);
+            let flushed = buffer.flushed.swap(added, Ordering::Relaxed);
+            let count = added.wrapping_sub(flushed).min(BUFFER_SIZE);
+            for i in added - count..added {
+                let event = buffer.events[i % BUFFER_SIZE].load(Ordering::Relaxed);
+                self.mixer.absorb(event);
+            }
+            self.mixed_events += count;
+        }
+    }
+
+    fn take(&mut self) -> Option<u64> {
+        if MIN_EVENTS <= self.mixed_events {
+            Some(self.mixer.squeeze())
+        } else {
+            None
+        }
+    }
+
+    fn fallback(&mut self) -> u64 {
+        if self.xorshift == 0 {
+            self.xorshift = timestamp() | 1;
+        }
+        let mut x = self.xorshift;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.xorshift = x;
+        x
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interrupts::Cli;
+    use log::info;
+
+    #[test_case]
+    fn test_mixer() {
+        info!("TESTING entropy::test_mixer");
+        let mut a = SipMixer::new();
+        let mut b = SipMixer::new();
+        for i in 0..16 {
+            a.absorb(i);
+            b.absorb(i);
+        }
+        let x = a.squeeze();
+        assert_eq!(x, b.squeeze());
+        assert_ne!(x, a.squeeze());
+
+        let mut c = SipMixer::new();
+        for i in 0..16 {
+            c.absorb(if i == 15 { 16 } else { i });
+        }
+        assert_ne!(x, c.squeeze());
+    }
+
+    #[test_case]
+    fn test_flush() {
+        info!("TESTING entropy::test_flush");
+        let buffers = [CpuBuffer::new(), CpuBuffer::new()];
+        let mut pool = Pool::new(SipMixer::new());
+        pool.flush(&buffers);
+        assert_eq!(pool.mixed_events, 0);
+        assert_eq!(pool.take(), None);
+
+        for i in 0..10 {
+            buffers[0].push(Source::Timer, i, 1000 + i);
+        }
+        buffers[1].push(Source::Keyboard, 0x1c, 2000);
+        pool.flush(&buffers);
+        assert_eq!(pool.mixed_events, 11);
+        pool.flush(&buffers);
+        assert_eq!(pool.mixed_events, 11);
+
+        // Events overwritten before being flushed are not counted
+        for i in 0..BUFFER_SIZE as u64 * 3 {
+            buffers[1].push(Source::Com1, i, 3000 + i);
+        }
+        pool.flush(&buffers);
+        assert_eq!(pool.mixed_events, 11 + BUFFER_SIZE);
+        assert!(MIN_EVENTS <= pool.mixed_events);
+        let x = pool.take().unwrap();
+        assert_ne!(x, pool.take().unwrap());
+    }
+
+    #[test_case]
+    fn test_fill_bytes() {
+        info!("TESTING entropy::test_fill_bytes");
+        let mut buf = [0u8; 21];
+        fill_bytes(&mut buf);
+        let mut other = [0u8; 21];
+        fill_bytes(&mut other);
+        assert_ne!(buf, other);
+    }
+
+    #[cfg(debug_assertions)]
+    #[test_case]
+    fn test_add_without_lock() {
+        info!("TESTING entropy::test_add_without_lock");
+        let cli = Cli::new(); // Prevent other interrupts from acquiring locks during the test
+        let mixed_events = mixed_events();
+        let count = crate::interrupts::cli_count();
+        for i in 0..BUFFER_SIZE as u64 * 2 {
+            add(Source::Timer, i);
+        }
+        assert_eq!(crate::interrupts::cli_count(), count);
+        drop(cli);
+        assert!(mixed_events + BUFFER_SIZE <= super::mixed_events());
+    }
+}
@@ -1,2 +1,8 @@
 pub mod fat;
+pub mod mount;
 pub mod volume;
+
+/// Mount the file systems. This must be called after `devices::virtio::block::initialize`.
+pub fn initialize() {
+    mount::initialize();
+}
@@ -1,36 +1,93 @@
 //! FAT File System implementation.
 
-use super::volume::{Sector, Volume, VolumeError};
+use super::volume::{
+    CacheStats, DynVolume, Overflow, Sector, Volume, VolumeError, VolumeErrorKind,
+};
+use crate::task;
+use alloc::format;
 use alloc::string::String;
+use alloc::vec;
 use alloc::vec::Vec;
+use audit::Operation;
 use core::fmt;
-use dir_entry::{DirEntry, LfnReader, ReadLfnResult, SfnEntry};
+use core::ops::ControlFlow;
+use dir_entry::{DirEntry, EntryError, LfnReader, ReadLfnResult, SfnEntry};
+use dir_slots::Scan;
 use fat_entry::FatEntry;
-use low_level::{BufferedCluster, Cluster, DirEntries, Root};
+use log::Level;
+use low_level::{
+    BufferedCluster, ChainWalker, Cluster, ClusterCount, DirEntries, Reservation, Root, Visited,
+};
 
+pub mod audit;
 mod boot_sector;
+pub mod compare;
+mod date;
 mod dir_entry;
+mod dir_slots;
 mod fat_entry;
+#[cfg(all(test, feature = "fat-fixtures"))]
+mod fixtures;
 mod low_level;
 
 pub use boot_sector::{BootSector, Error as BootSectorError};
+pub use date::{AccessTimePolicy, Clock, Date};
+pub use low_level::ChainError;
+
+/// Default number of clusters read or written by `FileReader` and `FileWriter` between yield
+/// points (approximately 256 KiB with 4 KiB clusters).
+pub const YIELD_INTERVAL: usize = 64;
+
+/// Maximum depth of directories walked by `FileSystem::changed_files`. Deeper directories, which
+/// are most likely caused by a broken directory structure, are skipped.
+pub const MAX_SCAN_DEPTH: usize = 32;
+
+/// The file size is stored in 32 bits in the directory entry.
+pub const MAX_FILE_SIZE: usize = u32::MAX as usize;
+
+/// Maximum number of clusters allocated at once by `FileWriter` and `File::preallocate`, which
+/// bounds the memory used by the search of unused clusters.
+const MAX_ALLOCATION_CLUSTERS: usize = 1024;
 
 // TODO:
 // * FAT12/16 Support
 // * Handle bpb_num_fats (Currently FAT copies are completely untouched)
 // * Handle _bpb_fs_info to reduce FAT traversal
-// * Handle _bpb_bk_boot_sec correctly
 // * Better error recovering
 
 /// Errors that occur during FAT file system operations.
-#[derive(PartialEq, Eq, Debug)]
+#[derive(PartialEq, Eq, Debug, Clone)]
 pub enum Error {
     Volume(VolumeError),
     BootSector(BootSectorError),
     Full,
+    /// The FAT is inconsistent, such as a cluster chain with a cycle.
+    CorruptedVolume(ChainError),
     DirectoryNotEmpty,
     FileAlreadyExists,
     InvalidFileName,
+    /// A cluster number out of the data area is found, such as in a broken directory entry.
+    InvalidCluster(usize),
+    BrokenDirEntry,
+    OutOfRange,
+    NotFound(String),
+    NotADirectory(String),
+    IsADirectory(String),
+    /// The size exceeds `MAX_FILE_SIZE`.
+    FileTooLarge,
+    /// The file has readers or writers, see `FileSystem::open_files`.
+    FileInUse(String),
+    /// The volume became smaller than the file system, see `FileSystem::revalidate`.
+    VolumeShrunk,
+    /// The file system is closed, see `FileSystem::close`.
+    Unmounted,
+}
+
+impl Error {
+    /// Whether this is caused by an access beyond the end of the resized volume.
+    pub fn is_resized(&self) -> bool {
+        matches!(self, Self::Volume(e) if e.kind == VolumeErrorKind::Resized)
+    }
 }
 
 impl From<VolumeError> for Error {
@@ -45,20 +102,61 @@ impl From<BootSectorError> for Error {
     }
 }
 
+impl From<ChainError> for Error {
+    fn from(e: ChainError) -> Self {
+        Self::CorruptedVolume(e)
+    }
+}
+
+impl From<Overflow> for Error {
+    fn from(_: Overflow) -> Self {
+        Self::FileTooLarge
+    }
+}
+
+impl From<EntryError> for Error {
+    fn from(_: EntryError) -> Self {
+        Self::BrokenDirEntry
+    }
+}
+
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Self::Volume(e) => write!(f, "{}", e),
             Self::BootSector(e) => write!(f, "{}", e),
             Self::Full => write!(f, "Full"),
+            Self::CorruptedVolume(e) => write!(f, "Corrupted volume: {}", e),
             Self::DirectoryNotEmpty => write!(f, "Directory not empty"),
             Self::FileAlreadyExists => write!(f, "File with the same name already exists"),
             Self::InvalidFileName => write!(f, "Invalid file name"),
+            Self::InvalidCluster(n) => write!(f, "Invalid cluster number: {}", n),
+            Self::BrokenDirEntry => write!(f, "Broken directory entry"),
+            Self::OutOfRange => write!(f, "Out of range"),
+            Self::NotFound(name) => write!(f, "Not found: {}", name),
+            Self::NotADirectory(name) => write!(f, "Not a directory: {}", name),
+            Self::IsADirectory(name) => write!(f, "Is a directory: {}", name),
+            Self::FileTooLarge => write!(f, "File too large"),
+            Self::FileInUse(name) => write!(f, "File in use: {}", name),
+            Self::VolumeShrunk => write!(f, "Read-only since the volume shrank"),
+            Self::Unmounted => write!(f, "The file system is unmounted"),
         }
     }
 }
 
+/// A file system over a volume of any type, see `DynVolume`.
+pub type DynFileSystem = FileSystem<DynVolume>;
+
+// Mounted file systems are shared by every task
+const _: fn() = || {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<DynFileSystem>();
+};
+
 /// Entry point of the FAT File System.
+///
+/// A file system can be shared by multiple tasks: operations that modify the FAT or directory
+/// entries are serialized internally. Concurrent writes to the same file are not coordinated.
 #[derive(Debug)]
 pub struct FileSystem<V> {
     root: Root<V>,
@@ -71,14 +169,121 @@ impl<V: Volume> FileSystem<V> {
         })
     }
 
+    /// Load the file system for writing. The volume is marked dirty until `mark_clean` is called,
+    /// so that an unclean unmount, such as a crash, can be detected by `was_dirty` on the next
+    /// load. Use `new` for read-only volumes.
+    pub fn mount_rw(volume: V) -> Result<Self, Error> {
+        let fs = Self::new(volume)?;
+        fs.root.set_dirty(true)?;
+        fs.set_access_time_policy(AccessTimePolicy::default());
+        Ok(fs)
+    }
+
+    pub fn access_time_policy(&self) -> AccessTimePolicy {
+        self.root.access_time_policy()
+    }
+
+    /// Set when the last access dates of files are updated. This is `AccessTimePolicy::Never`
+    /// for file systems loaded by `new`, and `AccessTimePolicy::Relative` by `mount_rw`.
+    /// The update is written like other directory entry changes, through the sector cache.
+    pub fn set_access_time_policy(&self, policy: AccessTimePolicy) {
+        self.root.set_access_time_policy(policy);
+    }
+
+    /// Set the source of the current date. Without a clock, no dates are recorded.
+    pub fn set_clock(&self, clock: Clock) {
+        self.root.set_clock(clock);
+    }
+
     pub fn commit(&self) -> Result<(), Error> {
         self.root.commit()
     }
 
+    /// Whether the volume was not cleanly unmounted when this file system is loaded. A consistency
+    /// check of the volume is advisable if so.
+    pub fn was_dirty(&self) -> bool {
+        self.root.was_dirty()
+    }
+
+    /// Commit the changes and mark the volume as cleanly unmounted. This must be the last
+    /// operation on a file system loaded by `mount_rw`.
+    pub fn mark_clean(&self) -> Result<(), Error> {
+        self.commit()?;
+        self.root.set_dirty(false)
+    }
+
+    /// Commit the changes, mark the volume as cleanly unmounted if it is loaded by `mount_rw`,
+    /// and release the file system. Fails with `Error::FileInUse` while any file has readers or
+    /// writers, and then the file system is given back with the error.
+    pub fn unmount(self) -> Result<(), (Self, Error)> {
+        match self.close() {
+            Ok(()) => Ok(()),
+            Err(e) => Err((self, e)),
+        }
+    }
+
+    /// Same as `unmount`, but for a file system that may still be referred to, such as by the
+    /// mount table. Every access, including reads, fails with `Error::Unmounted` once closed.
+    pub fn close(&self) -> Result<(), Error> {
+        self.root.close()
+    }
+
     pub fn boot_sector(&self) -> &BootSector {
         self.root.boot_sector()
     }
 
+    /// The number of unused clusters. This is counted by scanning the FAT for the first time, and
+    /// then maintained by every allocation and release.
+    pub fn free_cluster_count(&self) -> Result<usize, Error> {
+        self.root.fat().free_cluster_count()
+    }
+
+    /// Counters of the sector cache of this file system.
+    pub fn cache_stats(&self) -> CacheStats {
+        self.root.cache_stats()
+    }
+
+    /// Release the sectors cached beyond the usual size of the sector cache, see
+    /// `BufferedVolume::trim`. Returns the number of released sectors.
+    pub fn trim_cache(&self) -> usize {
+        self.root.trim_cache()
+    }
+
+    /// Commit the changes and release the cached sectors of `runs`, so that data written directly
+    /// to the volume on the runs, such as the crash dump on a preallocated file, is read through
+    /// this file system. Sectors in use by readers or writers at the moment are kept cached.
+    pub fn commit_and_evict(&self, runs: &[(Sector, usize)]) -> Result<(), Error> {
+        self.commit()?;
+        for (sector, count) in runs {
+            self.root.evict_cache(*sector, *count);
+        }
+        Ok(())
+    }
+
+    /// Whether this file system is mounted with the backup boot sector since the primary one is
+    /// broken. The primary one is never repaired implicitly, see `repair_boot_sector`.
+    pub fn boot_sector_recovered(&self) -> bool {
+        self.root.is_boot_sector_recovered()
+    }
+
+    /// Copy the backup boot sector over the broken primary one. Returns `false` if there is nothing
+    /// to repair.
+    pub fn repair_boot_sector(&self) -> Result<bool, Error> {
+        self.root.repair_boot_sector()
+    }
+
+    /// Check the boot sector against the current size of the volume, which may be resized. If the
+    /// volume is smaller than the file system, the file system becomes read-only and every
+    /// mutation fails with `Error::VolumeShrunk`. Returns whether the file system is writable.
+    pub fn revalidate(&self) -> bool {
+        self.root.revalidate()
+    }
+
+    /// Files that currently have readers or writers.
+    pub fn open_files(&self) -> Vec<OpenFile> {
+        self.root.open_files().clone()
+    }
+
     pub fn root_dir(&self) -> Dir<V> {
         let cluster = self.boot_sector().root_dir_cluster();
         Dir {
@@ -86,6 +291,97 @@ impl<V: Volume> FileSystem<V> {
             cluster,
         }
     }
+
+    /// Open the file at `path`, which is relative to the root directory.
+    /// `..` is resolved by the on-disk parent entry, and `..` at the root directory stays at the
+    /// root. A path ending with `..` opens the entry of that directory in its parent.
+    pub fn open(&self, path: &str) -> Result<File<V>, Error> {
+        let components = normalize_path(path);
+        match components.split_last() {
+            Some((&"..", _)) => self.walk(&components)?.entry(),
+            Some((name, dir_components)) => self.walk(dir_components)?.find(name),
+            None => Err(Error::NotFound(String::from("/"))),
+        }
+    }
+
+    pub fn open_dir(&self, path: &str) -> Result<Dir<V>, Error> {
+        self.walk(&normalize_path(path))
+    }
+
+    /// Open the file at `path`, or create an empty file there if it does not exist.
+    pub fn open_or_create(&self, path: &str) -> Result<File<V>, Error> {
+        let components = normalize_path(path);
+        let (name, dir_components) = components.split_last().ok_or(Error::InvalidFileName)?;
+        if *name == ".." {
+            return self.walk(&components)?.entry();
+        }
+        let mut dir = self.walk(dir_components)?;
+        match dir.find(name) {
+            // Another task may create the file in the meantime
+            Err(Error::NotFound(_)) => match dir.create_file(name) {
+                Ok(()) | Err(Error::FileAlreadyExists) => dir.find(name),
+                Err(e) => Err(e),
+            },
+            result => result,
+        }
+    }
+
+    /// Paths of the files whose archive attribute is set, that is, files modified since the
+    /// attribute was last cleared. Paths are relative to the root directory and start with `/`.
+    pub fn changed_files(&self) -> impl Iterator<Item = String> + '_ {
+        let mut stack = vec![(String::new(), self.root_dir().files())];
+        core::iter::from_fn(move || loop {
+            let (dir_path, files) = stack.last_mut()?;
+            let file = match files.next() {
+                Some(file) => file,
+                None => {
+                    stack.pop();
+                    continue;
+                }
+            };
+            let path = format!("{}/{}", dir_path, file.name());
+            if file.is_dir() {
+                if let Some(dir) = file.as_dir() {
+                    if stack.len() < MAX_SCAN_DEPTH {
+                        stack.push((path, dir.files()));
+                    }
+                }
+            } else if file.archive() {
+                return Some(path);
+            }
+        })
+    }
+
+    fn walk(&self, components: &[&str]) -> Result<Dir<V>, Error> {
+        let mut dir = self.root_dir();
+        for name in components {
+            if *name == ".." {
+                if let Some(parent) = dir.parent()? {
+                    dir = parent;
+                }
+                continue;
+            }
+            dir = dir
+                .find_map(name, |e| e.as_dir())?
+                .ok_or_else(|| Error::NotADirectory(String::from(*name)))?;
+        }
+        Ok(dir)
+    }
+}
+
+/// Split the path into components, removing `.` and empty components. `..` is left to be
+/// resolved through the directory entries.
+fn normalize_path(path: &str) -> Vec<&str> {
+    path.split('/')
+        .filter(|c| !matches!(*c, "" | "."))
+        .collect()
+}
+
+/// File names are compared case-insensitively in FAT.
+fn name_eq(a: &str, b: &str) -> bool {
+    a.chars()
+        .flat_map(char::to_lowercase)
+        .eq(b.chars().flat_map(char::to_lowercase))
 }
 
 #[derive(Debug)]
@@ -95,38 +391,267 @@ pub struct Dir<'a, V> {
 }
 
 impl<'a, V: Volume> Dir<'a, V> {
+    /// Files in this directory, excluding `.` and `..`.
     pub fn files(&self) -> DirIter<'a, V> {
         DirIter {
             root: self.root,
             dir: self.cluster,
             inner: self.root.dir_entries(self.cluster),
+            reader: LfnReader::new(),
+            include_dot_entries: false,
+        }
+    }
+
+    /// Files in this directory, including `.` and `..`, see `File::is_dot_entry`.
+    pub fn entries(&self) -> DirIter<'a, V> {
+        DirIter {
+            include_dot_entries: true,
+            ..self.files()
+        }
+    }
+
+    /// Find a file by name. An exact match is preferred over a case-insensitive match.
+    pub fn find(&self, name: &str) -> Result<File<'a, V>, Error> {
+        self.find_map(name, |e| e.to_file())
+    }
+
+    /// Same as `find`, but converts the entry by `f` instead of constructing a `File`.
+    fn find_map<T>(
+        &self,
+        name: &str,
+        mut f: impl FnMut(&EntryRef<'_, 'a, V>) -> T,
+    ) -> Result<T, Error> {
+        let mut found = None;
+        let result = self.for_each_entry(|e| {
+            if e.name() == name {
+                found = Some(f(&e));
+                return ControlFlow::Break(());
+            }
+            if found.is_none() && name_eq(e.name(), name) {
+                found = Some(f(&e));
+            }
+            ControlFlow::Continue(())
+        });
+        // An error ends the scan as in `DirIter`, the rest of the directory is considered missing
+        if let Err(e) = result {
+            log_rate_limited!(Level::Trace, (), "{}", e);
         }
+        found.ok_or_else(|| Error::NotFound(String::from(name)))
+    }
+
+    /// Call `f` for each file in this directory, excluding `.` and `..`, until `f` breaks.
+    /// Unlike `DirIter`, this reuses a single buffer for the names and reports errors during the
+    /// iteration. Use `EntryRef::to_file` to keep an entry.
+    pub fn for_each_entry(
+        &self,
+        mut f: impl FnMut(EntryRef<'_, 'a, V>) -> ControlFlow<()>,
+    ) -> Result<(), Error> {
+        self.scan_entries(|name, sfn, entry_location, (c, n)| {
+            // An entry referring to a cluster out of the data area is broken and never exposed
+            if matches!(sfn.cluster(), Some(c) if !self.root.boot_sector().is_cluster_available(c))
+            {
+                return ControlFlow::Continue(());
+            }
+            f(EntryRef {
+                root: self.root,
+                dir: self.cluster,
+                name,
+                entry_location,
+                last_entry: (sfn, c, n),
+            })
+        })
+    }
+
+    /// Whether this is the root directory. The root directory has no `.` and `..` entries.
+    pub fn is_root(&self) -> bool {
+        self.cluster == self.root.boot_sector().root_dir_cluster()
+    }
+
+    /// The entry of this directory in its parent directory.
+    pub fn entry(&self) -> Result<File<'a, V>, Error> {
+        let parent = self
+            .parent()?
+            .ok_or_else(|| Error::NotFound(String::from("/")))?;
+        parent
+            .files()
+            .find(|f| f.is_dir() && f.last_entry.0.cluster() == Some(self.cluster))
+            .ok_or_else(|| Error::NotFound(String::from("..")))
     }
 
     pub fn parent(&self) -> Result<Option<Dir<'a, V>>, Error> {
         let root_dir_cluster = self.root.boot_sector().root_dir_cluster();
-        Ok(if self.cluster == root_dir_cluster {
+        Ok(if self.is_root() {
             None
         } else {
             match self.root.cluster(self.cluster).read_dir_entry(1)? {
                 DirEntry::Sfn(sfn) => Some(Dir {
                     root: self.root,
-                    cluster: sfn.cluster().unwrap_or(root_dir_cluster),
+                    cluster: match sfn.cluster() {
+                        Some(c) if self.root.boot_sector().is_cluster_available(c) => c,
+                        Some(c) => Err(Error::InvalidCluster(c.index()))?,
+                        None => root_dir_cluster,
+                    },
                 }),
                 _ => None, // TODO: How should we handle the broken directory
             }
         })
     }
 
-    fn check_name_conflict(&self, name: &str) -> Result<(), Error> {
+    /// Check whether this directory has no files except `.` and `..`.
+    /// A directory that cannot be read is not considered empty.
+    pub fn is_empty(&self) -> bool {
+        matches!(self.scan_names(|_| true), Ok(false))
+    }
+
+    pub fn file_count(&self) -> Result<usize, Error> {
+        let mut count = 0;
+        self.scan_names(|_| {
+            count += 1;
+            false
+        })?;
+        Ok(count)
+    }
+
+    /// Whether a file of `name` exists, compared case-insensitively as `find` does.
+    pub fn contains(&self, name: &str) -> Result<bool, Error> {
+        self.scan_names(|n| name_eq(n, name))
+    }
+
+    /// Scan the file names in this directory until `f` returns true.
+    /// Unlike `for_each_entry`, this also counts the entries referring to broken clusters.
+    fn scan_names(&self, mut f: impl FnMut(&str) -> bool) -> Result<bool, Error> {
+        let mut found = false;
+        self.scan_entries(|name, _, _, _| {
+            found = f(name);
+            if found {
+                ControlFlow::Break(())
+            } else {
+                ControlFlow::Continue(())
+            }
+        })?;
+        Ok(found)
+    }
+
+    /// Scan the names and the SFN entries in this directory, excluding `.` and `..`, with the
+    /// locations of the first and the last entries of each file.
+    fn scan_entries(
+        &self,
+        mut f: impl FnMut(&str, SfnEntry, (Cluster, usize), (Cluster, usize)) -> ControlFlow<()>,
+    ) -> Result<(), Error> {
+        let mut entries = self.root.dir_entries(self.cluster);
+        let mut reader = LfnReader::new();
+        let mut start = None;
+        while let Some((c, n, mut entry)) = entries.try_next()? {
+            loop {
+                let entry_location = *start.get_or_insert((c, n));
+                match reader.read(entry) {
+                    ReadLfnResult::Meta(DirEntry::UnusedTerminal) => return Ok(()),
+                    ReadLfnResult::Incomplete => break,
+                    ReadLfnResult::Complete(name, sfn) => {
+                        start = None;
+                        if !matches!(name, "." | "..")
+                            && f(name, sfn, entry_location, (c, n)).is_break()
+                        {
+                            return Ok(());
+                        }
+                    }
+                    ReadLfnResult::Broken(e) => {
+                        start = None;
+                        entry = e;
+                        continue;
+                    }
+                    // An orphaned LFN entry is simply skipped
+                    ReadLfnResult::Meta(_) | ReadLfnResult::Orphaned(_) => start = None,
+                }
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Rewrite the entries of this directory densely, preserving their order, and release the
+    /// clusters that are no longer needed from the chain of this directory.
+    /// Compaction moves the entries, so every `File` and `DirIter` of this directory obtained
+    /// before the compaction refers to wrong locations and must not be used afterwards.
+    /// Fails with `Error::FileInUse` if a file in this directory has readers or writers, since
+    /// they refer to the directory entries by their locations.
+    pub fn compact(&self) -> Result<CompactReport, Error> {
+        let _lock = self.root.lock_dirs();
+        let open_files = self.root.open_files();
+        if let Some(file) = open_files.iter().find(|f| f.dir == self.cluster) {
+            Err(Error::FileInUse(file.name.clone()))?;
+        }
+        let mut live_entries = Vec::new();
+        let mut unused_entries = 0;
+        let mut entries = self.root.dir_entries(self.cluster);
+        while let Some((_, _, entry)) = entries.try_next()? {
+            match entry {
+                DirEntry::Unused => unused_entries += 1,
+                DirEntry::UnusedTerminal => break,
+                entry => live_entries.push(entry),
+            }
+        }
+
+        // Live entries always fit in the current chain since they are rewritten in place
+        let mut chain = self.root.chain_after(self.cluster);
+        let mut c = self.root.cluster(self.cluster);
+        let mut n = 0;
+        for entry in live_entries
+            .iter()
+            .copied()
+            .chain([DirEntry::UnusedTerminal])
+        {
+            if c.dir_entries_count() <= n {
+                match chain.try_next()? {
+                    Some(next) => (c, n) = (self.root.cluster(next), 0),
+                    None => break, // The end of the chain also terminates the directory
+                }
+            }
+            c.write_dir_entry(n, entry)?;
+            n += 1;
+        }
+
+        let mut released_clusters = 0;
+        if let Some(next) = self.root.chained_cluster(c.cluster()).get()? {
+            self.root.fat().walk_chain(next.cluster(), |_, _, _| {
+                released_clusters += 1;
+                Ok(true)
+            })?;
+            self.root.chained_cluster(c.cluster()).release()?;
+        }
+        Ok(CompactReport {
+            live_entries: live_entries.len(),
+            unused_entries,
+            released_clusters,
+        })
+    }
+
+    /// Names are compared case-insensitively, since `find` could reach only one of the files
+    /// of names differing only in case. The file whose entries end at `except` is ignored, so that
+    /// a file can be renamed only in case.
+    fn check_name_conflict(
+        &self,
+        name: &str,
+        except: Option<(Cluster, usize)>,
+    ) -> Result<(), Error> {
         // FIXME: We also need to check SFN name conflict
-        if self.files().any(|f| f.name() == name) {
+        let mut conflict = false;
+        self.scan_entries(|n, _, _, last| {
+            conflict = name_eq(n, name) && Some(last) != except;
+            if conflict {
+                ControlFlow::Break(())
+            } else {
+                ControlFlow::Continue(())
+            }
+        })?;
+        if conflict {
             Err(Error::FileAlreadyExists)
         } else {
             Ok(())
         }
     }
 
+    /// Must be called while holding `Root::lock_dirs`.
     fn insert_dir_entries(
         &mut self,
         entries: impl ExactSizeIterator<Item = DirEntry>,
@@ -135,36 +660,43 @@ impl<'a, V: Volume> Dir<'a, V> {
         if required_len == 0 {
             return Ok(());
         }
+        // Find a run of Unused entries by the summaries of the slots, without reading the
+        // directory entries
+        let mut chain = self.root.chain(Some(self.cluster));
         let mut writable_start = (self.cluster, 0);
         let mut writable_len = 0;
-        for (c, n, entry) in self.root.dir_entries(self.cluster) {
-            match entry {
-                DirEntry::Unused => {
-                    if writable_len == 0 {
-                        writable_start = (c, n);
-                    }
-                    writable_len += 1;
-                    if writable_len == required_len {
-                        // Found a enough writable space (starting at writable_start) for entries
-                        break;
-                    }
-                }
-                DirEntry::UnusedTerminal => {
-                    if writable_len == 0 {
-                        writable_start = (c, n);
-                    }
-                    // We don't have a enough space (writable_len != required_len) for entries
-                    break;
-                }
-                _ => writable_len = 0,
+        while let Some(cluster) = chain.try_next()? {
+            let scan = self
+                .root
+                .with_dir_slots(cluster, |slots| slots.scan(writable_len, required_len))?;
+            let (len, start) = match scan {
+                Scan::Found(start) => (required_len, start),
+                Scan::Terminal(start) => (0, start),
+                Scan::Continue(len, start) => (len, start),
+            };
+            if let Some(n) = start {
+                writable_start = (cluster, n);
+            }
+            writable_len = len;
+            if !matches!(scan, Scan::Continue(..)) {
+                break;
+            }
+            if writable_len == 0 {
+                // Extend the chain after the last entry, unless the chain continues
+                let count = self.root.cluster(cluster).dir_entries_count();
+                writable_start = (cluster, count);
             }
         }
+        // Entries written into a run of Unused entries keep the terminal after the run, if any.
+        // Otherwise they replace the terminal, which is moved after them even if they end exactly
+        // at the end of a cluster: the terminal is then the first entry of the next cluster, which
+        // is zero-filled if newly allocated so that no stale entries follow it
         let terminal = (writable_len != required_len).then(|| DirEntry::UnusedTerminal);
         let (c, mut n) = writable_start;
         let mut c = self.root.cluster(c);
         for entry in entries.chain(terminal) {
             if c.dir_entries_count() <= n {
-                c = self.root.chained_cluster(c.cluster()).prepare()?;
+                c = self.root.chained_cluster(c.cluster()).prepare_zeroed()?;
                 n = 0;
             }
             c.write_dir_entry(n, entry)?;
@@ -174,29 +706,46 @@ impl<'a, V: Volume> Dir<'a, V> {
     }
 
     pub fn create_file(&mut self, name: &str) -> Result<(), Error> {
-        self.check_name_conflict(name)?;
-        let entries =
-            DirEntry::lfn_sequence(name, SfnEntry::new()).ok_or(Error::InvalidFileName)?;
+        let audit = audit::begin(self.root, Operation::CreateFile, self.cluster, name);
+        let result = self.create_file_entry(name);
+        audit::end(audit, &result);
+        result
+    }
+
+    fn create_file_entry(&mut self, name: &str) -> Result<(), Error> {
+        let _lock = self.root.lock_dirs();
+        self.check_name_conflict(name, None)?;
+        let mut sfn = SfnEntry::new();
+        sfn.mark_archive();
+        let entries = DirEntry::lfn_sequence(name, sfn).ok_or(Error::InvalidFileName)?;
         self.insert_dir_entries(entries.into_iter())
     }
 
     pub fn create_dir(&mut self, name: &str) -> Result<(), Error> {
-        self.check_name_conflict(name)?;
+        let audit = audit::begin(self.root, Operation::CreateDir, self.cluster, name);
+        let result = self.create_dir_entry(name);
+        audit::end(audit, &result);
+        result
+    }
+
+    fn create_dir_entry(&mut self, name: &str) -> Result<(), Error> {
+        let _lock = self.root.lock_dirs();
+        self.check_name_conflict(name, None)?;
         let mut entries =
             DirEntry::lfn_sequence(name, SfnEntry::new()).ok_or(Error::InvalidFileName)?;
         let c = self.root.fat().allocate()?;
         {
-            let is_root = self.cluster == self.root.boot_sector().root_dir_cluster();
-            let current_dir = SfnEntry::current(Some(c));
-            let parent_dir = SfnEntry::parent((!is_root).then(|| self.cluster));
+            let current_dir = SfnEntry::current(Some(c))?;
+            let parent_dir = SfnEntry::parent((!self.is_root()).then(|| self.cluster))?;
             let mut c = self.root.cluster(c);
+            c.fill_zeros()?;
             c.write_dir_entry(0, DirEntry::Sfn(current_dir))?;
             c.write_dir_entry(1, DirEntry::Sfn(parent_dir))?;
             c.write_dir_entry(2, DirEntry::UnusedTerminal)?;
         }
         if let Some(DirEntry::Sfn(ref mut sfn)) = entries.last_mut() {
             sfn.set_is_directory(true);
-            sfn.set_cluster(Some(c));
+            sfn.set_cluster(Some(c))?;
         } else {
             panic!();
         }
@@ -204,30 +753,57 @@ impl<'a, V: Volume> Dir<'a, V> {
     }
 }
 
+/// The result of `Dir::compact`.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct CompactReport {
+    /// Entries kept, including LFN entries and `.` and `..`.
+    pub live_entries: usize,
+    /// Unused entries removed.
+    pub unused_entries: usize,
+    pub released_clusters: usize,
+}
+
+impl fmt::Display for CompactReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} entries kept, {} unused entries removed, {} clusters released",
+            self.live_entries, self.unused_entries, self.released_clusters
+        )
+    }
+}
+
 #[derive(Debug)]
 pub struct DirIter<'a, V> {
     root: &'a Root<V>,
     dir: Cluster,
     inner: DirEntries<'a, V>,
+    reader: LfnReader,
+    include_dot_entries: bool,
 }
 
 impl<'a, V: Volume> Iterator for DirIter<'a, V> {
     type Item = File<'a, V>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let mut reader = LfnReader::Init;
+        self.reader.reset();
         let (mut sc, mut sn, mut entry) = self.inner.next()?;
         let (mut ec, mut en) = (sc, sn);
         let (name, sfn) = loop {
-            match reader.read(entry) {
+            match self.reader.read(entry) {
                 ReadLfnResult::Meta(DirEntry::UnusedTerminal) => return None,
-                ReadLfnResult::Meta(_) => return self.next(),
+                // An orphaned LFN entry is simply skipped
+                ReadLfnResult::Meta(_) | ReadLfnResult::Orphaned(_) => return self.next(),
                 ReadLfnResult::Incomplete => (ec, en, entry) = self.inner.next()?,
-                ReadLfnResult::Complete(name, sfn) => break (name, sfn),
-                ReadLfnResult::Broken(_, e) => (sc, sn, entry) = (ec, en, e),
+                ReadLfnResult::Complete(name, sfn) => break (String::from(name), sfn),
+                ReadLfnResult::Broken(e) => (sc, sn, entry) = (ec, en, e),
             }
         };
-        if matches!(name.as_str(), "." | "..") {
+        if !self.include_dot_entries && matches!(name.as_str(), "." | "..") {
+            return self.next();
+        }
+        // An entry referring to a cluster out of the data area is broken and never exposed
+        if matches!(sfn.cluster(), Some(c) if !self.root.boot_sector().is_cluster_available(c)) {
             return self.next();
         }
         Some(File {
@@ -240,22 +816,93 @@ impl<'a, V: Volume> Iterator for DirIter<'a, V> {
     }
 }
 
+/// A file visited by `Dir::for_each_entry`. The name is borrowed from the buffer of the
+/// iteration, and is valid only during the call of the closure.
+#[derive(Debug)]
+pub struct EntryRef<'e, 'a, V> {
+    root: &'a Root<V>,
+    dir: Cluster,
+    name: &'e str,
+    entry_location: (Cluster, usize),
+    last_entry: (SfnEntry, Cluster, usize),
+}
+
+impl<'e, 'a, V: Volume> EntryRef<'e, 'a, V> {
+    pub fn name(&self) -> &'e str {
+        self.name
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.last_entry.0.is_directory()
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.last_entry.0.is_read_only()
+    }
+
+    pub fn is_hidden(&self) -> bool {
+        self.last_entry.0.is_hidden()
+    }
+
+    pub fn is_system(&self) -> bool {
+        self.last_entry.0.is_system()
+    }
+
+    pub fn archive(&self) -> bool {
+        self.last_entry.0.archive()
+    }
+
+    pub fn file_size(&self) -> usize {
+        self.last_entry.0.file_size()
+    }
+
+    pub fn first_cluster(&self) -> Option<usize> {
+        self.last_entry.0.cluster().map(|c| c.index())
+    }
+
+    pub fn as_dir(&self) -> Option<Dir<'a, V>> {
+        if !self.is_dir() {
+            return None;
+        }
+        Some(Dir {
+            root: self.root,
+            cluster: self.last_entry.0.cluster()?,
+        })
+    }
+
+    /// Construct a `File` of this entry, which allocates the name.
+    pub fn to_file(&self) -> File<'a, V> {
+        File {
+            root: self.root,
+            dir: self.dir,
+            name: String::from(self.name),
+            entry_location: self.entry_location,
+            last_entry: self.last_entry,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct File<'a, V> {
     root: &'a Root<V>,
     dir: Cluster,
-    name: String, // must not be "." or ".."
+    name: String, // "." or ".." only if this is a dot entry
     entry_location: (Cluster, usize),
     last_entry: (SfnEntry, Cluster, usize),
 }
 
 impl<'a, V: Volume> File<'a, V> {
     fn write_back(&mut self) -> Result<(), Error> {
-        self.last_entry.0.mark_archive();
-        let (entry, c, n) = self.last_entry;
-        self.root
-            .cluster(c)
-            .write_dir_entry(n, DirEntry::Sfn(entry))
+        let (_, c, n) = self.last_entry;
+        let _lock = self.root.lock_entries();
+        let mut cluster = self.root.cluster(c);
+        // The last access date is updated by readers of other `File`s, see `touch_access_date`
+        if let DirEntry::Sfn(entry) = cluster.read_dir_entry(n)? {
+            if let Some(date) = entry.last_access_date() {
+                self.last_entry.0.set_last_access_date(date);
+            }
+        }
+        cluster.write_dir_entry(n, DirEntry::Sfn(self.last_entry.0))
     }
 
     fn parent(&self) -> Dir<'a, V> {
@@ -265,6 +912,10 @@ impl<'a, V: Volume> File<'a, V> {
         }
     }
 
+    fn audit(&self, op: Operation) -> Option<audit::Pending> {
+        audit::begin(self.root, op, self.dir, &self.name)
+    }
+
     pub fn name(&self) -> &str {
         self.name.as_str()
     }
@@ -281,48 +932,151 @@ impl<'a, V: Volume> File<'a, V> {
         self.last_entry.0.is_system()
     }
 
-    // TODO: set_is_read_only, set_is_hidden, set_is_system
+    pub fn set_is_read_only(&mut self, is_read_only: bool) -> Result<(), Error> {
+        let audit = self.audit(Operation::SetReadOnly(is_read_only));
+        self.last_entry.0.set_is_read_only(is_read_only);
+        let result = self.write_back();
+        audit::end(audit, &result);
+        result
+    }
+
+    pub fn set_is_hidden(&mut self, is_hidden: bool) -> Result<(), Error> {
+        let audit = self.audit(Operation::SetHidden(is_hidden));
+        self.last_entry.0.set_is_hidden(is_hidden);
+        let result = self.write_back();
+        audit::end(audit, &result);
+        result
+    }
+
+    pub fn set_is_system(&mut self, is_system: bool) -> Result<(), Error> {
+        let audit = self.audit(Operation::SetSystem(is_system));
+        self.last_entry.0.set_is_system(is_system);
+        let result = self.write_back();
+        audit::end(audit, &result);
+        result
+    }
 
+    /// Whether the file has been created or modified since the archive attribute was cleared.
+    /// Metadata-only changes such as `set_is_hidden` do not set the attribute.
     pub fn archive(&self) -> bool {
         self.last_entry.0.archive()
     }
 
+    /// Clear the archive attribute, typically after the file is backed up.
+    pub fn clear_archive(&mut self) -> Result<(), Error> {
+        let audit = self.audit(Operation::ClearArchive);
+        self.last_entry.0.clear_archive();
+        let result = self.write_back();
+        audit::end(audit, &result);
+        result
+    }
+
     pub fn is_dir(&self) -> bool {
         self.last_entry.0.is_directory()
     }
 
+    /// Whether this is the `.` or `..` entry of a directory, see `Dir::entries`.
+    pub fn is_dot_entry(&self) -> bool {
+        matches!(self.name.as_str(), "." | "..")
+    }
+
     pub fn as_dir(&self) -> Option<Dir<'a, V>> {
-        if self.is_dir() {
-            Some(Dir {
-                root: self.root,
-                cluster: self.last_entry.0.cluster()?,
-            })
-        } else {
-            None
+        if !self.is_dir() {
+            return None;
         }
+        let cluster = match self.last_entry.0.cluster() {
+            Some(c) => c,
+            // `..` of a first-level directory refers to the root directory by cluster 0
+            None if self.is_dot_entry() => self.root.boot_sector().root_dir_cluster(),
+            None => None?,
+        };
+        Some(Dir {
+            root: self.root,
+            cluster,
+        })
     }
 
     pub fn file_size(&self) -> usize {
         self.last_entry.0.file_size()
     }
 
-    fn set_file_size(&mut self, size: usize) -> Result<(), Error> {
-        self.last_entry.0.set_file_size(size);
-        self.write_back()
+    /// Collect the metadata of this file. This walks the entire cluster chain of the file.
+    pub fn metadata(&self) -> Metadata {
+        let sfn = self.last_entry.0;
+        let (is_irreversible, sfn_name) = sfn.name();
+        let mut cluster_count = 0;
+        let mut runs = Vec::<(usize, usize)>::new();
+        let chain_error = sfn.cluster().and_then(|start| {
+            self.root
+                .fat()
+                .walk_chain(start, |_, c, _| {
+                    cluster_count += 1;
+                    match runs.last_mut() {
+                        Some((s, len)) if *s + *len == c.index() => *len += 1,
+                        _ => runs.push((c.index(), 1)),
+                    }
+                    Ok(true)
+                })
+                .err()
+        });
+        Metadata {
+            name: self.name.clone(),
+            sfn_name,
+            is_irreversible,
+            size: self.file_size(),
+            is_dir: self.is_dir(),
+            is_read_only: self.is_read_only(),
+            is_hidden: self.is_hidden(),
+            is_system: self.is_system(),
+            archive: self.archive(),
+            last_access_date: sfn.last_access_date(),
+            first_cluster: sfn.cluster().map(|c| c.index()),
+            cluster_count,
+            runs,
+            chain_error,
+        }
     }
 
-    // cluster, prepare_cluster, and release_cluster correspond to low_level::ChainedCluster methods
+    /// The sectors of the cluster chain of this file as runs of contiguous sectors, in the order
+    /// of the content. The runs are valid only while the chain is unchanged: once the file is
+    /// truncated, overwritten, or removed, its clusters may be reused by other files, thus the
+    /// runs must be derived again rather than kept across such changes. Writes to the runs bypass
+    /// the sector cache, so they are not seen through the sectors of the file cached before.
+    pub fn cluster_runs(&self) -> Result<ClusterRuns, Error> {
+        let bs = self.root.boot_sector();
+        let mut runs = ClusterRuns::new();
+        if let Some(start) = self.last_entry.0.cluster() {
+            self.root.fat().walk_chain(start, |_, c, _| {
+                let sector = bs.cluster_location(c);
+                match runs.last_mut() {
+                    Some((s, len)) if s.offset(*len) == sector => *len += bs.cluster_size(),
+                    _ => runs.push((sector, bs.cluster_size())),
+                }
+                Ok(true)
+            })?;
+        }
+        Ok(runs)
+    }
 
-    fn cluster(&self) -> Option<BufferedCluster<'a, V>> {
-        Some(self.root.cluster(self.last_entry.0.cluster()?))
+    fn set_file_size(&mut self, size: usize) -> Result<(), Error> {
+        // Only called when the content is written, so this is where the archive is marked
+        self.last_entry.0.set_file_size(size)?;
+        self.last_entry.0.mark_archive();
+        self.write_back()
     }
 
-    fn prepare_cluster(&mut self) -> Result<BufferedCluster<'a, V>, Error> {
+    // prepare_cluster and release_cluster correspond to low_level::ChainedCluster methods
+
+    fn prepare_cluster(
+        &mut self,
+        count: usize,
+        reservation: Option<&mut Reservation<'a, V>>,
+    ) -> Result<BufferedCluster<'a, V>, Error> {
         match self.last_entry.0.cluster() {
             Some(c) => Ok(self.root.cluster(c)),
             None => {
-                let c = self.root.fat().allocate()?;
-                self.last_entry.0.set_cluster(Some(c));
+                let (c, _) = self.root.fat().allocate_chain(count, reservation)?;
+                self.last_entry.0.set_cluster(Some(c))?;
                 self.write_back()?;
                 Ok(self.root.cluster(c))
             }
@@ -331,7 +1085,7 @@ impl<'a, V: Volume> File<'a, V> {
 
     fn release_cluster(&mut self) -> Result<(), Error> {
         if let Some(c) = self.last_entry.0.cluster() {
-            self.last_entry.0.set_cluster(None);
+            self.last_entry.0.set_cluster(None)?;
             self.write_back()?;
             self.root.fat().release(c)?;
         }
@@ -342,64 +1096,272 @@ impl<'a, V: Volume> File<'a, V> {
         if self.is_dir() {
             None
         } else {
+            self.open(Access::Read);
+            self.touch_access_date();
             Some(FileReader {
                 root: self.root,
+                entry: self.sfn_location(),
                 rest_size: self.file_size(),
-                cursor: self.cluster().map(|c| (c, 0)),
+                chain: self.root.chain(self.last_entry.0.cluster()),
+                cursor: None,
+                yield_point: YieldPoint::new(YIELD_INTERVAL),
             })
         }
     }
 
-    pub fn overwriter(&'a mut self) -> Option<FileWriter<'a, V>> {
-        if self.is_dir() {
-            None
-        } else {
-            Some(FileWriter {
+    /// Keep this file open for reading for the lifetime of the file system, so that removing or
+    /// moving it fails with `Error::FileInUse`. This is for files whose clusters are written
+    /// without going through the file system, such as the crash dump area.
+    pub fn hold_open(&self) {
+        self.open(Access::Read);
+    }
+
+    /// Update the last access date as far as the access time policy allows. The archive
+    /// attribute is left as is, and failures are ignored since reading does not depend on it.
+    fn touch_access_date(&self) {
+        if self.root.access_time_policy() == AccessTimePolicy::Never {
+            return;
+        }
+        let (_, c, n) = self.last_entry;
+        let _lock = self.root.lock_entries();
+        let mut cluster = self.root.cluster(c);
+        // The entry is read again since `last_entry` may be outdated by other `File`s
+        let mut entry = match cluster.read_dir_entry(n) {
+            Ok(DirEntry::Sfn(entry)) => entry,
+            _ => return,
+        };
+        if let Some(date) = self.root.access_date_update(entry.last_access_date()) {
+            entry.set_last_access_date(date);
+            let _ = cluster.write_dir_entry(n, DirEntry::Sfn(entry));
+        }
+    }
+
+    pub fn overwriter(&'a mut self) -> Option<FileWriter<'a, V>> {
+        if self.is_dir() {
+            None
+        } else {
+            let truncate = self.audit(Operation::Truncate);
+            self.open(Access::Write);
+            let visited = Visited::new(self.root.boot_sector().cluster_count());
+            Some(FileWriter {
                 file: self,
                 total_size: 0,
                 cursor: None,
+                visited,
+                chain_error: None,
+                reservation: None,
+                truncate,
+                yield_point: YieldPoint::new(YIELD_INTERVAL),
             })
         }
     }
 
+    /// Same as `overwriter`, but the clusters required to write `len` bytes are reserved up
+    /// front, so that the writer does not run out of space halfway due to other writers.
+    /// Fails with `Error::Full` without writing anything if there is not enough space.
+    /// Writing more than `len` bytes allocates clusters as `overwriter` does.
+    pub fn overwriter_with_size_hint(&'a mut self, len: usize) -> Result<FileWriter<'a, V>, Error> {
+        if self.is_dir() {
+            Err(Error::IsADirectory(self.name.clone()))?;
+        }
+        // The clusters of the current content are reused
+        let cluster_bytes = self.root.boot_sector().cluster_bytes();
+        let clusters = |size| ClusterCount::for_bytes(size, cluster_bytes).map(|c| c.get());
+        let n = clusters(len)?.saturating_sub(clusters(self.file_size())?);
+        let reservation = self.root.fat().reserve(n)?;
+        let mut writer = self.overwriter().unwrap();
+        writer.reservation = Some(reservation);
+        Ok(writer)
+    }
+
+    /// Extend this file to `len` bytes without writing the content, which reads as zeros. The
+    /// clusters are allocated in batches and zero-filled on the volume without going through the
+    /// sector cache, which is much cheaper than writing zeros by `overwriter`. Fails with
+    /// `Error::Full` without changing anything if there is not enough space. A file of `len`
+    /// bytes or more is left as is.
+    /// Returns the runs of the file, see `cluster_runs`, so that the file can be written
+    /// directly to the volume later, such as by the crash dump.
+    pub fn preallocate(&mut self, len: usize) -> Result<ClusterRuns, Error> {
+        if self.is_dir() {
+            Err(Error::IsADirectory(self.name.clone()))?;
+        }
+        if MAX_FILE_SIZE < len {
+            Err(Error::FileTooLarge)?;
+        }
+        let entry = self.sfn_location();
+        let open_files = self.root.open_files();
+        if open_files
+            .iter()
+            .any(|f| f.entry == entry && f.writers != 0)
+        {
+            Err(Error::FileInUse(self.name.clone()))?;
+        }
+        drop(open_files);
+        if self.file_size() < len {
+            let audit = self.audit(Operation::Preallocate(len));
+            self.open(Access::Write);
+            let result = self.extend_zeroed(len);
+            File::close(self.root, entry, Access::Write);
+            audit::end(audit, &result);
+            result?;
+        }
+        self.cluster_runs()
+    }
+
+    fn extend_zeroed(&mut self, len: usize) -> Result<(), Error> {
+        let size = self.file_size();
+        let cluster_bytes = self.root.boot_sector().cluster_bytes();
+        let required = ClusterCount::for_bytes(len, cluster_bytes)?.get();
+        // The chain may be longer than the content if a writer is interrupted
+        let mut chain = self.root.chain(self.last_entry.0.cluster());
+        let mut clusters = Vec::new();
+        while clusters.len() < required {
+            match chain.try_next()? {
+                Some(c) => clusters.push(c),
+                None => break,
+            }
+        }
+        let mut reservation = self.root.fat().reserve(required - clusters.len())?;
+
+        let mut yield_point = YieldPoint::new(YIELD_INTERVAL);
+        for (i, c) in clusters.iter().enumerate() {
+            let mut c = self.root.cluster(*c);
+            match size.saturating_sub(i * cluster_bytes) {
+                0 => c.fill_zeros()?,
+                offset if offset < cluster_bytes => {
+                    c.write(offset, &vec![0; cluster_bytes - offset])?
+                }
+                _ => {}
+            }
+            yield_point.tick();
+        }
+        let mut last = clusters.last().copied();
+        if clusters.len() == required {
+            // `required` is not 0 since the file is extended
+            self.root.chained_cluster(last.unwrap()).release()?;
+        }
+        for i in clusters.len()..required {
+            let count = (required - i).min(MAX_ALLOCATION_CLUSTERS);
+            let mut c = match last {
+                Some(c) => self
+                    .root
+                    .chained_cluster(c)
+                    .prepare_chain(count, Some(&mut reservation))?,
+                None => self.prepare_cluster(count, Some(&mut reservation))?,
+            };
+            c.fill_zeros()?;
+            last = Some(c.cluster());
+            yield_point.tick();
+        }
+        self.set_file_size(len)
+    }
+
     pub fn appender(&'a mut self) -> Option<FileWriter<'a, V>> {
         if self.is_dir() {
             None
         } else {
             // Same as overwriter except the cursor is at the end of self.cluster()
             let mut total_size = 0;
-            let cursor = self.cluster().map(|mut c| {
+            let mut visited = Visited::new(self.root.boot_sector().cluster_count());
+            // A broken chain is reported by the first write
+            let mut chain_error = None;
+            let cursor = self.last_entry.0.cluster().map(|start| {
+                let cluster_bytes = self.root.boot_sector().cluster_bytes();
                 let mut rest_size = self.file_size();
-                while c.size() < rest_size {
-                    match self.root.chained_cluster(c.cluster()).get() {
-                        Ok(Some(next_c)) => {
-                            total_size += c.size();
-                            rest_size -= c.size();
-                            c = next_c;
+                let mut chain = self.root.chain_after(start);
+                let mut last = start;
+                while cluster_bytes < rest_size {
+                    match chain.try_next() {
+                        Ok(Some(c)) => last = c,
+                        Ok(None) => break,
+                        Err(e) => {
+                            chain_error = Some(e);
+                            break;
                         }
-                        _ => rest_size = c.size(), // FIXME: How should we handle the broken cluster chain?
                     }
+                    total_size += cluster_bytes;
+                    rest_size -= cluster_bytes;
                 }
+                visited = chain.into_visited();
+                let rest_size = rest_size.min(cluster_bytes);
                 total_size += rest_size;
-                (c, rest_size)
+                ClusterCursor::new(self.root.cluster(last), rest_size)
             });
+            self.open(Access::Write);
             Some(FileWriter {
                 file: self,
                 total_size,
                 cursor,
+                visited,
+                chain_error,
+                reservation: None,
+                truncate: None,
+                yield_point: YieldPoint::new(YIELD_INTERVAL),
             })
         }
     }
 
+    /// The location of the SFN entry, which identifies this file in `Root::open_files`.
+    fn sfn_location(&self) -> (Cluster, usize) {
+        let (_, c, n) = self.last_entry;
+        (c, n)
+    }
+
+    fn open(&self, access: Access) {
+        let entry = self.sfn_location();
+        let mut open_files = self.root.open_files();
+        let file = match open_files.iter_mut().position(|f| f.entry == entry) {
+            Some(i) => &mut open_files[i],
+            None => {
+                open_files.push(OpenFile {
+                    name: self.name.clone(),
+                    readers: 0,
+                    writers: 0,
+                    dir: self.dir,
+                    entry,
+                });
+                open_files.last_mut().unwrap()
+            }
+        };
+        *file.count_mut(access) += 1;
+    }
+
+    fn close(root: &Root<V>, entry: (Cluster, usize), access: Access) {
+        let mut open_files = root.open_files();
+        if let Some(i) = open_files.iter().position(|f| f.entry == entry) {
+            *open_files[i].count_mut(access) -= 1;
+            if open_files[i].readers == 0 && open_files[i].writers == 0 {
+                open_files.swap_remove(i);
+            }
+        }
+    }
+
+    fn check_not_in_use(&self, open_files: &[OpenFile]) -> Result<(), Error> {
+        if open_files.iter().any(|f| f.entry == self.sfn_location()) {
+            Err(Error::FileInUse(self.name.clone()))?;
+        }
+        Ok(())
+    }
+
     fn dir_entry_locations(
         &self,
-    ) -> impl Iterator<Item = (BufferedCluster<'a, V>, usize, usize)> + 'a {
+    ) -> impl Iterator<Item = Result<(BufferedCluster<'a, V>, usize, usize), Error>> + 'a {
         let (start_c, start_offset) = self.entry_location;
         let (_, end_c, end_offset) = self.last_entry;
-        let mut next_c = Some(self.root.cluster(start_c));
+        let mut chain = self.root.chain(Some(start_c));
+        let mut done = false;
         let root = self.root;
         core::iter::from_fn(move || {
-            let c = core::mem::take(&mut next_c)?;
+            if done {
+                return None;
+            }
+            let c = match chain.try_next() {
+                Ok(c) => root.cluster(c?),
+                Err(e) => {
+                    done = true;
+                    return Some(Err(e));
+                }
+            };
             let i = match c.cluster() == start_c {
                 true => start_offset,
                 false => 0,
@@ -408,16 +1370,112 @@ impl<'a, V: Volume> File<'a, V> {
                 true => end_offset,
                 false => c.dir_entries_count() - 1,
             };
-            next_c = if c.cluster() == end_c {
-                None
-            } else {
-                root.chained_cluster(c.cluster()).get().ok().flatten()
-            };
-            Some((c, i, j))
+            done = c.cluster() == end_c;
+            Some(Ok((c, i, j)))
         })
     }
 
-    pub fn remove(mut self, recursive: bool) -> Result<(), Error> {
+    /// Overwrite the entries of this file with `Unused`. If no live entries follow them, the
+    /// directory is terminated instead at the first of them, or at the start of the run of
+    /// `Unused` entries right before them, so that later scans stop there.
+    /// Must be called while holding `Root::lock_dirs`.
+    fn release_dir_entries(&self) -> Result<(), Error> {
+        let (_, end_c, end_n) = self.last_entry;
+        let mut following = self.root.dir_entries_at(end_c, end_n + 1);
+        let is_last = loop {
+            match following.try_next()? {
+                Some((_, _, DirEntry::Unused)) => {}
+                Some((_, _, DirEntry::UnusedTerminal)) | None => break true,
+                Some(_) => break false,
+            }
+        };
+        let entry = match is_last {
+            true => DirEntry::UnusedTerminal,
+            false => DirEntry::Unused,
+        };
+        for location in self.dir_entry_locations() {
+            let (mut c, i, j) = location?;
+            for offset in i..=j {
+                c.write_dir_entry(offset, entry)?;
+            }
+        }
+        if is_last {
+            // From the back, so that no `Unused` entry is left after a terminal on failures
+            for (c, n) in self.preceding_unused_entries()?.into_iter().rev() {
+                self.root
+                    .cluster(c)
+                    .write_dir_entry(n, DirEntry::UnusedTerminal)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Locations of the run of `Unused` entries right before the entries of this file.
+    fn preceding_unused_entries(&self) -> Result<Vec<(Cluster, usize)>, Error> {
+        let mut entries = self.root.dir_entries(self.dir);
+        let mut run = Vec::new();
+        while let Some((c, n, entry)) = entries.try_next()? {
+            if (c, n) == self.entry_location {
+                break;
+            }
+            match entry {
+                DirEntry::Unused => run.push((c, n)),
+                _ => run.clear(),
+            }
+        }
+        Ok(run)
+    }
+
+    /// Copy this file into `dest` as a new file named `name`.
+    pub fn copy_to<W: Volume>(&self, dest: &mut Dir<'_, W>, name: &str) -> Result<(), Error> {
+        self.copy_to_with_progress(dest, name, |_, _| {})
+    }
+
+    /// Same as `copy_to`, but `cb` is called with the number of bytes copied so far and the total
+    /// number of bytes after each cluster is copied. The task yields after each cluster.
+    pub fn copy_to_with_progress<W: Volume>(
+        &self,
+        dest: &mut Dir<'_, W>,
+        name: &str,
+        cb: impl Fn(usize, usize),
+    ) -> Result<(), Error> {
+        let mut reader = self
+            .reader()
+            .ok_or_else(|| Error::IsADirectory(self.name.clone()))?
+            .with_yield_interval(0);
+        dest.create_file(name)?;
+        let mut file = dest.find(name)?;
+        let mut writer = file.overwriter().unwrap().with_yield_interval(0);
+
+        let total = self.file_size();
+        let mut done = 0;
+        let mut buf = vec![0; self.root.boot_sector().cluster_bytes()];
+        loop {
+            let len = reader.read(&mut buf)?;
+            if len == 0 {
+                break;
+            }
+            writer.write(&buf[0..len])?;
+            done += len;
+            cb(done, total);
+            task::scheduler().r#yield();
+        }
+        Ok(())
+    }
+
+    /// Remove this file. With `recursive`, the files in the directory are removed (and recorded
+    /// in the audit log) one by one first.
+    pub fn remove(self, recursive: bool) -> Result<(), Error> {
+        let audit = self.audit(Operation::Remove);
+        let result = self.remove_entry(recursive);
+        audit::end(audit, &result);
+        result
+    }
+
+    fn remove_entry(mut self, recursive: bool) -> Result<(), Error> {
+        if self.is_dot_entry() {
+            Err(Error::InvalidFileName)?;
+        }
         if let Some(dir) = self.as_dir() {
             for file in dir.files() {
                 if recursive {
@@ -427,17 +1485,27 @@ impl<'a, V: Volume> File<'a, V> {
                 }
             }
         }
+        let _lock = self.root.lock_dirs();
+        let open_files = self.root.open_files();
+        self.check_not_in_use(&open_files)?;
         self.release_cluster()?;
-
-        for (mut c, i, j) in self.dir_entry_locations() {
-            for offset in i..=j {
-                c.write_dir_entry(offset, DirEntry::Unused)?;
-            }
-        }
-        Ok(())
+        self.release_dir_entries()
     }
 
     pub fn mv(self, dir: Option<Dir<'a, V>>, name: Option<&str>) -> Result<(), Error> {
+        let audit = self.audit(Operation::Rename).map(|a| {
+            let dest_dir = dir.as_ref().map_or(self.dir, |d| d.cluster);
+            a.with_dest(dest_dir, name.unwrap_or(&self.name))
+        });
+        let result = self.move_entries(dir, name);
+        audit::end(audit, &result);
+        result
+    }
+
+    fn move_entries(self, dir: Option<Dir<'a, V>>, name: Option<&str>) -> Result<(), Error> {
+        if self.is_dot_entry() {
+            Err(Error::InvalidFileName)?;
+        }
         let (name, mut dir, entries) = match name {
             Some(name) if name != self.name => {
                 let dir = dir.unwrap_or_else(|| self.parent());
@@ -451,59 +1519,133 @@ impl<'a, V: Volume> File<'a, V> {
                     _ => return Ok(()),
                 };
                 // Since there is no name change, just move the DirEntry sequence
-                let entries = self
-                    .dir_entry_locations()
-                    .flat_map(|(mut c, i, j)| (i..=j).map(move |offset| c.read_dir_entry(offset)))
-                    .collect::<Result<Vec<_>, _>>()?;
+                let mut entries = Vec::new();
+                for location in self.dir_entry_locations() {
+                    let (mut c, i, j) = location?;
+                    for offset in i..=j {
+                        entries.push(c.read_dir_entry(offset)?);
+                    }
+                }
                 (self.name.as_str(), dir, entries)
             }
         };
-        dir.check_name_conflict(name)?;
-        for (mut c, i, j) in self.dir_entry_locations() {
-            for offset in i..=j {
-                c.write_dir_entry(offset, DirEntry::Unused)?;
+        let _lock = self.root.lock_dirs();
+        self.check_not_in_use(&self.root.open_files())?;
+        let (_, c, n) = self.last_entry;
+        dir.check_name_conflict(name, Some((c, n)))?;
+        // `..` of a directory right under the root directory refers to cluster 0
+        let parent_dir = SfnEntry::parent((!dir.is_root()).then(|| dir.cluster))?;
+        self.release_dir_entries()?;
+        if dir.cluster != self.dir {
+            if let Some(moved_dir) = self.as_dir() {
+                let mut c = self.root.cluster(moved_dir.cluster);
+                c.write_dir_entry(1, DirEntry::Sfn(parent_dir))?;
             }
         }
         dir.insert_dir_entries(entries.into_iter())
     }
 }
 
+/// Metadata of a file, see `File::metadata`.
+#[derive(Debug, Clone)]
+pub struct Metadata {
+    pub name: String,
+    /// Short file name (8.3 format) stored in the SFN entry.
+    pub sfn_name: String,
+    /// Whether the short file name contains characters that cannot be restored.
+    pub is_irreversible: bool,
+    pub size: usize,
+    pub is_dir: bool,
+    pub is_read_only: bool,
+    pub is_hidden: bool,
+    pub is_system: bool,
+    pub archive: bool,
+    pub last_access_date: Option<Date>,
+    pub first_cluster: Option<usize>,
+    pub cluster_count: usize,
+    /// Contiguous runs of the cluster chain as (first cluster, number of clusters).
+    /// The number of runs indicates the fragmentation of the file.
+    pub runs: Vec<(usize, usize)>,
+    /// An error occurred while walking the cluster chain, such as `Error::CorruptedVolume`.
+    pub chain_error: Option<Error>,
+}
+
+/// Runs of contiguous sectors as (first sector, number of sectors), see `File::cluster_runs`.
+pub type ClusterRuns = Vec<(Sector, usize)>;
+
+/// A file that has readers or writers, see `FileSystem::open_files`.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct OpenFile {
+    pub name: String,
+    pub readers: usize,
+    pub writers: usize,
+    dir: Cluster,
+    /// The location of the SFN entry of the file.
+    entry: (Cluster, usize),
+}
+
+impl OpenFile {
+    fn count_mut(&mut self, access: Access) -> &mut usize {
+        match access {
+            Access::Read => &mut self.readers,
+            Access::Write => &mut self.writers,
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+enum Access {
+    Read,
+    Write,
+}
+
 #[derive(Debug)]
-pub struct FileReader<'a, V> {
+pub struct FileReader<'a, V: Volume> {
     root: &'a Root<V>,
+    entry: (Cluster, usize),
     rest_size: usize,
-    cursor: Option<(BufferedCluster<'a, V>, usize)>,
+    chain: ChainWalker<'a, V>,
+    cursor: Option<ClusterCursor<'a, V>>,
+    yield_point: YieldPoint,
 }
 
 impl<'a, V: Volume> FileReader<'a, V> {
+    /// Yield to other tasks every `interval` clusters read. 0 disables yielding.
+    pub fn with_yield_interval(mut self, interval: usize) -> Self {
+        self.yield_point = YieldPoint::new(interval);
+        self
+    }
+
     pub fn read(&mut self, mut buf: &mut [u8]) -> Result<usize, Error> {
         let mut total_read = 0;
         while buf.len() != 0 && self.rest_size != 0 {
-            let (mut c, offset) = match core::mem::take(&mut self.cursor) {
+            let mut cursor = match core::mem::take(&mut self.cursor) {
                 Some(cursor) => cursor,
-                None => break,
+                None => match self.chain.try_next()? {
+                    Some(c) => ClusterCursor::new(self.root.cluster(c), 0),
+                    None => break,
+                },
             };
-            let l = buf.len().min(self.rest_size).min(c.size() - offset);
-            c.read(offset, &mut buf[0..l])?;
+            let l = buf.len().min(self.rest_size).min(cursor.remaining());
+            cursor.read(&mut buf[0..l])?;
             buf = &mut buf[l..];
             total_read += l;
             self.rest_size -= l;
 
-            self.cursor = if l == c.size() - offset {
-                self.root
-                    .chained_cluster(c.cluster())
-                    .get()?
-                    .map(|c| (c, 0))
+            self.cursor = if cursor.is_at_end() {
+                self.yield_point.tick();
+                None
             } else {
-                Some((c, offset + l))
+                Some(cursor)
             };
         }
         Ok(total_read)
     }
 
     pub fn read_to_end(mut self) -> Result<Vec<u8>, Error> {
-        let mut buf = Vec::new();
-        let mut tmp = [0; 4096];
+        let mut buf = Vec::with_capacity(self.rest_size);
+        // Reading cluster by cluster minimizes the number of chain traversals
+        let mut tmp = vec![0; self.root.boot_sector().cluster_bytes()];
         while {
             let len = self.read(&mut tmp)?;
             buf.extend_from_slice(&tmp[0..len]);
@@ -513,26 +1655,94 @@ impl<'a, V: Volume> FileReader<'a, V> {
     }
 }
 
+impl<'a, V: Volume> Drop for FileReader<'a, V> {
+    fn drop(&mut self) {
+        File::close(self.root, self.entry, Access::Read);
+    }
+}
+
 #[derive(Debug)]
 pub struct FileWriter<'a, V: Volume> {
     file: &'a mut File<'a, V>,
     total_size: usize,
-    cursor: Option<(BufferedCluster<'a, V>, usize)>,
+    cursor: Option<ClusterCursor<'a, V>>,
+    visited: Visited,
+    /// Set when the cluster chain is found broken. Every write fails from then on.
+    chain_error: Option<Error>,
+    /// Clusters reserved by `File::overwriter_with_size_hint`.
+    reservation: Option<Reservation<'a, V>>,
+    /// The audit of `File::overwriter`, finished when the old content is released on drop.
+    truncate: Option<audit::Pending>,
+    yield_point: YieldPoint,
 }
 
 impl<'a, V: Volume> FileWriter<'a, V> {
+    /// Yield to other tasks every `interval` clusters written. 0 disables yielding.
+    pub fn with_yield_interval(mut self, interval: usize) -> Self {
+        self.yield_point = YieldPoint::new(interval);
+        self
+    }
+
     pub fn write(&mut self, mut buf: &[u8]) -> Result<(), Error> {
+        if let Some(ref e) = self.chain_error {
+            Err(e.clone())?;
+        }
+        // Fail before writing a part of `buf` if the volume has shrunk in the meantime
+        self.file.root.check_writable()?;
         while !buf.is_empty() {
-            let (mut c, offset) = match core::mem::take(&mut self.cursor) {
-                Some((c, offset)) if offset < c.size() => (c, offset),
-                Some((c, _)) => (self.file.root.chained_cluster(c.cluster()).prepare()?, 0),
-                None => (self.file.prepare_cluster()?, 0),
+            let mut cursor = match core::mem::take(&mut self.cursor) {
+                Some(cursor) if !cursor.is_at_end() => cursor,
+                prev => {
+                    let prev = prev.map(|cursor| cursor.cluster.cluster());
+                    let c = self.next_cluster(prev, buf.len())?;
+                    self.visit(c.cluster())?;
+                    ClusterCursor::new(c, 0)
+                }
             };
-            let l = buf.len().min(c.size() - offset);
-            c.write(offset, &buf[0..l])?;
+            let l = buf.len().min(cursor.remaining());
+            cursor.write(&buf[0..l])?;
             buf = &buf[l..];
             self.total_size += l;
-            self.cursor = Some((c, offset + l));
+            if cursor.is_at_end() {
+                self.yield_point.tick();
+            }
+            self.cursor = Some(cursor);
+        }
+        Ok(())
+    }
+
+    /// The cluster chained after `prev`, or the first cluster of the file if `None`. At the end
+    /// of the chain, the clusters to write `len` more bytes are allocated at once, so that their
+    /// FAT entries are written together rather than interleaved with the data. If there are not
+    /// enough unused clusters, a single cluster is allocated to write as much as possible.
+    fn next_cluster(
+        &mut self,
+        prev: Option<Cluster>,
+        len: usize,
+    ) -> Result<BufferedCluster<'a, V>, Error> {
+        let cluster_bytes = self.file.root.boot_sector().cluster_bytes();
+        let reserved = self.reservation.as_ref().map_or(0, |r| r.count());
+        let count = ClusterCount::for_bytes(len, cluster_bytes)
+            .map_or(usize::MAX, |c| c.get())
+            .max(reserved)
+            .clamp(1, MAX_ALLOCATION_CLUSTERS);
+        let mut prepare = |count| match prev {
+            Some(c) => {
+                let chained = self.file.root.chained_cluster(c);
+                chained.prepare_chain(count, self.reservation.as_mut())
+            }
+            None => self.file.prepare_cluster(count, self.reservation.as_mut()),
+        };
+        match prepare(count) {
+            Err(Error::Full) if 1 < count => prepare(1),
+            result => result,
+        }
+    }
+
+    fn visit(&mut self, c: Cluster) -> Result<(), Error> {
+        if let Err(e) = self.visited.visit(c) {
+            self.chain_error = Some(e.into());
+            Err(e)?;
         }
         Ok(())
     }
@@ -540,27 +1750,1891 @@ impl<'a, V: Volume> FileWriter<'a, V> {
 
 impl<'a, V: Volume> Drop for FileWriter<'a, V> {
     fn drop(&mut self) {
-        let _ = match self.cursor {
-            Some((ref c, _)) => self.file.root.chained_cluster(c.cluster()).release(),
-            None => self.file.release_cluster(),
+        // A broken chain is left as is, since the clusters after the cursor may include the
+        // clusters before it
+        let result = match self.chain_error {
+            Some(ref e) => Err(e.clone()),
+            None => {
+                let released = match self.cursor {
+                    Some(ref cursor) => {
+                        let c = cursor.cluster.cluster();
+                        self.file.root.chained_cluster(c).release()
+                    }
+                    None => self.file.release_cluster(),
+                };
+                let sized = self.file.set_file_size(self.total_size); // TODO: Handle error
+                released.and(sized)
+            }
         };
-        let _ = self.file.set_file_size(self.total_size); // TODO: Handle error
+        audit::end(self.truncate.take(), &result);
+        File::close(self.file.root, self.file.sfn_location(), Access::Write);
+    }
+}
+
+/// A position in the cluster chain of a file: the cluster and the byte offset within it.
+#[derive(Debug)]
+struct ClusterCursor<'a, V> {
+    cluster: BufferedCluster<'a, V>,
+    offset: usize,
+}
+
+impl<'a, V: Volume> ClusterCursor<'a, V> {
+    fn new(cluster: BufferedCluster<'a, V>, offset: usize) -> Self {
+        debug_assert!(offset <= cluster.size());
+        Self { cluster, offset }
+    }
+
+    /// Number of bytes from the cursor to the end of the cluster.
+    fn remaining(&self) -> usize {
+        self.cluster.size() - self.offset
+    }
+
+    fn is_at_end(&self) -> bool {
+        self.remaining() == 0
+    }
+
+    /// Read `buf.len()` bytes, which must not exceed `remaining`, and advance the cursor.
+    fn read(&mut self, buf: &mut [u8]) -> Result<(), Error> {
+        self.cluster.read(self.offset, buf)?;
+        self.offset += buf.len();
+        Ok(())
+    }
+
+    /// Write `buf`, which must not exceed `remaining`, and advance the cursor. A whole cluster
+    /// of zeros is written by `BufferedCluster::fill_zeros`.
+    fn write(&mut self, buf: &[u8]) -> Result<(), Error> {
+        if buf.len() == self.cluster.size() && buf.iter().all(|b| *b == 0) {
+            self.cluster.fill_zeros()?;
+        } else {
+            self.cluster.write(self.offset, buf)?;
+        }
+        self.offset += buf.len();
+        Ok(())
+    }
+}
+
+/// Cooperative yield point to keep long-running file operations from starving other tasks.
+#[derive(Debug, Clone, Copy)]
+struct YieldPoint {
+    interval: usize,
+    count: usize,
+}
+
+impl YieldPoint {
+    fn new(interval: usize) -> Self {
+        Self { interval, count: 0 }
+    }
+
+    /// Count a cluster and yield if `interval` clusters have been counted.
+    fn tick(&mut self) {
+        if self.interval == 0 {
+            return;
+        }
+        self.count += 1;
+        if self.count == self.interval {
+            self.count = 0;
+            task::scheduler().r#yield();
+        }
     }
 }
 
 trait SliceExt {
-    fn array<const N: usize>(&self, offset: usize) -> [u8; N];
-    fn copy_from_array<const N: usize>(&mut self, offset: usize, array: [u8; N]);
+    /// The `N` bytes at `offset`, or `None` if they are out of the slice.
+    fn try_array<const N: usize>(&self, offset: usize) -> Option<[u8; N]>;
+
+    /// Write the `N` bytes at `offset`. Returns `None` without writing if they are out of the slice.
+    fn try_copy_from_array<const N: usize>(&mut self, offset: usize, array: [u8; N]) -> Option<()>;
+
+    /// Same as `try_copy_from_array`, but panics if out of the slice. Only for serializing into
+    /// buffers whose size is fixed, never for offsets derived from disk data.
+    fn copy_from_array<const N: usize>(&mut self, offset: usize, array: [u8; N]) {
+        self.try_copy_from_array(offset, array)
+            .expect("copy_from_array: out of range")
+    }
 }
 
 impl SliceExt for [u8] {
-    fn array<const N: usize>(&self, offset: usize) -> [u8; N] {
-        let mut ret = [0; N];
-        ret.copy_from_slice(&self[offset..offset + N]);
-        ret
+    fn try_array<const N: usize>(&self, offset: usize) -> Option<[u8; N]> {
+        self.get(offset..offset.checked_add(N)?)?.try_into().ok()
     }
 
-    fn copy_from_array<const N: usize>(&mut self, offset: usize, array: [u8; N]) {
-        self[offset..offset + N].copy_from_slice(&array);
+    fn try_copy_from_array<const N: usize>(&mut self, offset: usize, array: [u8; N]) -> Option<()> {
+        self.get_mut(offset..offset.checked_add(N)?)?
+            .copy_from_slice(&array);
+        Some(())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::allocator;
+    use crate::fs::volume::mem::MemVolume;
+    use crate::sync::spin::Spin;
+    use alloc::boxed::Box;
+    use alloc::collections::BTreeSet;
+    use core::cell::{Cell, RefCell};
+    use core::sync::atomic::{AtomicBool, AtomicU16, Ordering};
+    use log::info;
+    use ors_common::fat::{self, BootSectorParams};
+
+    const RSVD_SEC_CNT: usize = 33;
+    const BK_BOOT_SEC: usize = 6;
+    const CLUSTER_COUNT: usize = 16;
+
+    /// Create an empty FAT32 volume with the given geometry.
+    pub(crate) fn format_volume(sector_size: usize, sec_per_clus: usize) -> MemVolume {
+        format_volume_with_clusters(sector_size, sec_per_clus, CLUSTER_COUNT)
+    }
+
+    fn format_volume_with_clusters(
+        sector_size: usize,
+        sec_per_clus: usize,
+        cluster_count: usize,
+    ) -> MemVolume {
+        let fat_size = Sector::count_for_bytes((cluster_count + 2) * 4, sector_size)
+            .unwrap()
+            .get();
+        let total = RSVD_SEC_CNT + fat_size * 2 + cluster_count * sec_per_clus;
+        let volume = MemVolume::new(sector_size, total);
+
+        let mut buf = vec![0; sector_size];
+        BootSectorParams {
+            bytes_per_sector: u16::try_from(sector_size).unwrap(),
+            sectors_per_cluster: u8::try_from(sec_per_clus).unwrap(),
+            reserved_sectors: u16::try_from(RSVD_SEC_CNT).unwrap(),
+            num_fats: 2,
+            total_sectors: u32::try_from(total).unwrap(),
+            fat_size: u32::try_from(fat_size).unwrap(),
+            root_cluster: 2,
+            fs_info: 1,
+            backup_boot_sector: u16::try_from(BK_BOOT_SEC).unwrap(),
+            volume_id: 0,
+            volume_label: *b"NO NAME    ",
+        }
+        .encode(&mut buf);
+        volume.write(Sector::from_index(0), &buf).unwrap();
+        volume.write(Sector::from_index(BK_BOOT_SEC), &buf).unwrap();
+
+        // FAT[0] and FAT[1] are reserved, FAT[2] is used by the root directory
+        let mut buf = vec![0; sector_size];
+        buf.copy_from_array(0, fat::MEDIA_ENTRY.to_le_bytes());
+        buf.copy_from_array(4, fat::END_OF_CHAIN.to_le_bytes());
+        buf.copy_from_array(8, fat::END_OF_CHAIN.to_le_bytes());
+        volume
+            .write(Sector::from_index(RSVD_SEC_CNT), &buf)
+            .unwrap();
+        volume
+    }
+
+    #[test_case]
+    fn test_geometry_matrix() {
+        info!("TESTING fs::fat::test_geometry_matrix");
+        for sector_size in [512, 4096] {
+            for sec_per_clus in [1, 8, 64] {
+                let fs = FileSystem::new(format_volume(sector_size, sec_per_clus)).unwrap();
+                let mut root = fs.root_dir();
+                assert!(root.is_empty());
+
+                // Enough entries to span multiple clusters on small cluster sizes
+                for i in 0..20 {
+                    root.create_file(&format!("file{}", i)).unwrap();
+                }
+                assert!(!root.is_empty());
+                assert_eq!(root.file_count(), Ok(20));
+                assert_eq!(root.contains("file19"), Ok(true));
+                assert_eq!(root.contains("file20"), Ok(false));
+                assert_eq!(root.create_file("file3"), Err(Error::FileAlreadyExists));
+
+                let cluster_bytes = fs.boot_sector().cluster_bytes();
+                let data = (0..cluster_bytes * 5 / 2)
+                    .map(|i| (i % 251) as u8)
+                    .collect::<Vec<_>>();
+                root.create_file("data").unwrap();
+                {
+                    let mut file = root.files().find(|f| f.name() == "data").unwrap();
+                    file.overwriter().unwrap().write(&data).unwrap();
+                }
+                let file = root.files().find(|f| f.name() == "data").unwrap();
+                assert_eq!(file.file_size(), data.len());
+                assert_eq!(file.reader().unwrap().read_to_end(), Ok(data));
+                let metadata = file.metadata();
+                assert_eq!(metadata.cluster_count, 3);
+                assert_eq!(metadata.chain_error, None);
+
+                root.create_dir("dir").unwrap();
+                let dir = root.files().find(|f| f.name() == "dir").unwrap();
+                assert!(dir.as_dir().unwrap().is_empty());
+                fs.commit().unwrap();
+            }
+        }
+    }
+
+    #[test_case]
+    fn test_chain_loop() {
+        info!("TESTING fs::fat::test_chain_loop");
+        let fs = FileSystem::new(format_volume(512, 1)).unwrap();
+        let mut root = fs.root_dir();
+        root.create_file("loop").unwrap();
+        {
+            let mut file = root.files().find(|f| f.name() == "loop").unwrap();
+            file.overwriter().unwrap().write(&[0; 1024]).unwrap();
+        }
+        let file = root.files().find(|f| f.name() == "loop").unwrap();
+        let metadata = file.metadata();
+        assert_eq!(metadata.cluster_count, 2);
+
+        // Make the second cluster point back to the first cluster
+        let first = Cluster::from_index(metadata.runs[0].0);
+        let second = fs.root.fat().read(first).unwrap().chain().unwrap();
+        fs.root.fat().write(second, FatEntry::from(first)).unwrap();
+        assert_eq!(
+            file.metadata().chain_error,
+            Some(Error::CorruptedVolume(ChainError::Cycle))
+        );
+    }
+
+    /// The clusters of the chain starting at `start`.
+    fn chain_of<V: Volume>(fs: &FileSystem<V>, start: Cluster) -> Vec<Cluster> {
+        let mut chain = Vec::new();
+        fs.root
+            .fat()
+            .walk_chain(start, |_, c, _| {
+                chain.push(c);
+                Ok(true)
+            })
+            .unwrap();
+        chain
+    }
+
+    #[test_case]
+    fn test_chain_cycles() {
+        info!("TESTING fs::fat::test_chain_cycles");
+        let cycle = Error::CorruptedVolume(ChainError::Cycle);
+
+        // The `from`-th cluster of the chain is chained back to the `to`-th cluster
+        for (from, to) in [(0, 0), (2, 2), (3, 0), (3, 1)] {
+            let fs = FileSystem::new(format_volume(512, 1)).unwrap();
+            let mut root = fs.root_dir();
+            root.create_file("file").unwrap();
+            {
+                let mut file = root.find("file").unwrap();
+                file.overwriter().unwrap().write(&[1; 512 * 5]).unwrap();
+            }
+            let file = root.find("file").unwrap();
+            let first = Cluster::from_index(file.metadata().first_cluster.unwrap());
+            let chain = chain_of(&fs, first);
+            assert_eq!(chain.len(), 5);
+            fs.root
+                .fat()
+                .write(chain[from], FatEntry::from(chain[to]))
+                .unwrap();
+
+            assert_eq!(file.metadata().chain_error, Some(cycle.clone()));
+            assert_eq!(file.reader().unwrap().read_to_end(), Err(cycle.clone()));
+            {
+                let mut file = root.find("file").unwrap();
+                let mut writer = file.appender().unwrap();
+                assert_eq!(writer.write(&[2; 10]), Err(cycle.clone()));
+            }
+            let file = root.find("file").unwrap();
+            assert_eq!(file.file_size(), 512 * 5); // left as is
+            assert_eq!(file.remove(false), Ok(()));
+            for c in &chain[..=from] {
+                assert_eq!(fs.root.fat().read(*c), Ok(FatEntry::Unused));
+            }
+        }
+
+        // Directories spanning 3 clusters, where the last cluster becomes unreachable
+        for (from, to) in [(1, 0), (1, 1)] {
+            let fs = FileSystem::new(format_volume(512, 1)).unwrap();
+            let mut root = fs.root_dir();
+            root.create_dir("dir").unwrap();
+            let mut dir = root.find("dir").unwrap().as_dir().unwrap();
+            let mut files = 0;
+            while chain_of(&fs, dir.cluster).len() < 3 {
+                dir.create_file(&format!("file{}", files)).unwrap();
+                files += 1;
+            }
+            let chain = chain_of(&fs, dir.cluster);
+            fs.root
+                .fat()
+                .write(chain[from], FatEntry::from(chain[to]))
+                .unwrap();
+
+            let mut count = 0;
+            let result = dir.for_each_entry(|_| {
+                count += 1;
+                ControlFlow::Continue(())
+            });
+            assert_eq!(result, Err(cycle.clone()));
+            assert!(count < files);
+            assert_eq!(dir.contains("missing"), Err(cycle.clone()));
+            assert_eq!(dir.create_file("new"), Err(cycle.clone()));
+            assert_eq!(dir.compact().map(|_| ()), Err(cycle.clone()));
+        }
+    }
+
+    #[test_case]
+    fn test_visited() {
+        info!("TESTING fs::fat::test_visited");
+        let c = Cluster::from_index;
+        let mut visited = Visited::new(16);
+        assert_eq!(visited.visit(c(2)), Ok(()));
+        assert_eq!(visited.visit(c(17)), Ok(()));
+        assert_eq!(visited.visit(c(100)), Ok(())); // out of the data area
+        assert_eq!(visited.visit(c(2)), Err(ChainError::Cycle));
+
+        // Only the number of steps is bounded on large volumes
+        let count = 1 << 20;
+        let mut visited = Visited::new(count);
+        for _ in 0..count {
+            assert_eq!(visited.visit(c(2)), Ok(()));
+        }
+        assert_eq!(visited.visit(c(3)), Err(ChainError::Cycle));
+    }
+
+    #[test_case]
+    fn test_yield_interval() {
+        info!("TESTING fs::fat::test_yield_interval");
+        let fs = FileSystem::new(format_volume(512, 1)).unwrap();
+        let mut root = fs.root_dir();
+        let data = (0..512 * 5 + 100)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+        root.create_file("data").unwrap();
+        {
+            let mut file = root.find("data").unwrap();
+            let mut writer = file.overwriter().unwrap().with_yield_interval(1);
+            for chunk in data.chunks(300) {
+                writer.write(chunk).unwrap();
+            }
+        }
+        let file = root.find("data").unwrap();
+        let reader = file.reader().unwrap().with_yield_interval(2);
+        assert_eq!(reader.read_to_end(), Ok(data.clone()));
+
+        let progress = RefCell::new(Vec::new());
+        root.create_dir("dir").unwrap();
+        let mut dir = root.find("dir").unwrap().as_dir().unwrap();
+        file.copy_to_with_progress(&mut dir, "copy", |done, total| {
+            progress.borrow_mut().push((done, total))
+        })
+        .unwrap();
+        let copy = dir.find("copy").unwrap();
+        assert_eq!(copy.reader().unwrap().read_to_end(), Ok(data.clone()));
+        assert_eq!(
+            progress.into_inner(),
+            [512, 1024, 1536, 2048, 2560, 2660].map(|done| (done, data.len()))
+        );
+        assert_eq!(
+            file.copy_to(&mut dir, "copy"),
+            Err(Error::FileAlreadyExists)
+        );
+        let dir_file = root.find("dir").unwrap();
+        assert_eq!(
+            dir_file.copy_to(&mut root, "dir2"),
+            Err(Error::IsADirectory(String::from("dir")))
+        );
+    }
+
+    #[test_case]
+    fn test_open() {
+        info!("TESTING fs::fat::test_open");
+        let fs = FileSystem::new(format_volume(512, 1)).unwrap();
+        fs.root_dir().create_dir("Dir").unwrap();
+        fs.open_dir("Dir").unwrap().create_file("File.txt").unwrap();
+        fs.open_dir("Dir").unwrap().create_dir("sub").unwrap();
+
+        assert_eq!(fs.open("Dir/File.txt").unwrap().name(), "File.txt");
+        assert_eq!(fs.open("/Dir/File.txt").unwrap().name(), "File.txt");
+        assert_eq!(fs.open("//Dir///File.txt").unwrap().name(), "File.txt");
+        assert_eq!(fs.open("Dir/sub/").unwrap().name(), "sub");
+        assert_eq!(
+            fs.open("./Dir/./sub/../File.txt").unwrap().name(),
+            "File.txt"
+        );
+        assert_eq!(fs.open("../../Dir/File.txt").unwrap().name(), "File.txt");
+        assert_eq!(fs.open("dir/file.TXT").unwrap().name(), "File.txt");
+        assert!(fs.open_dir("/../..").unwrap().contains("Dir").unwrap());
+        assert!(fs.open_dir("DIR/SUB/").unwrap().is_empty());
+
+        assert_eq!(
+            fs.open("Dir/missing/File.txt").unwrap_err(),
+            Error::NotFound(String::from("missing"))
+        );
+        assert_eq!(
+            fs.open("Dir/File.txt/x").unwrap_err(),
+            Error::NotADirectory(String::from("File.txt"))
+        );
+        assert_eq!(
+            fs.open("/").unwrap_err(),
+            Error::NotFound(String::from("/"))
+        );
+
+        // Names differing only in case conflict, except for renaming the file itself
+        assert_eq!(
+            fs.open_dir("Dir").unwrap().create_file("file.txt"),
+            Err(Error::FileAlreadyExists)
+        );
+        assert_eq!(
+            fs.open_dir("dir").unwrap().create_dir("SUB"),
+            Err(Error::FileAlreadyExists)
+        );
+        fs.open_dir("Dir").unwrap().create_file("other").unwrap();
+        assert_eq!(
+            fs.open("Dir/other").unwrap().mv(None, Some("FILE.TXT")),
+            Err(Error::FileAlreadyExists)
+        );
+        fs.open("Dir/File.txt")
+            .unwrap()
+            .mv(None, Some("file.TXT"))
+            .unwrap();
+        assert_eq!(fs.open("Dir/File.txt").unwrap().name(), "file.TXT");
+        fs.open("Dir/other").unwrap().remove(false).unwrap();
+
+        assert_eq!(fs.open_or_create("Dir/sub/new").unwrap().name(), "new");
+        assert_eq!(fs.open_or_create("dir/sub/NEW").unwrap().name(), "new");
+        assert_eq!(fs.open_dir("Dir/sub").unwrap().file_count(), Ok(1));
+    }
+
+    /// Counts the sectors read from the volume.
+    #[derive(Debug)]
+    struct CountingVolume {
+        inner: MemVolume,
+        reads: Cell<usize>,
+    }
+
+    impl Volume for CountingVolume {
+        fn sector_count(&self) -> usize {
+            self.inner.sector_count()
+        }
+
+        fn sector_size(&self) -> usize {
+            self.inner.sector_size()
+        }
+
+        fn read(&self, sector: Sector, buf: &mut [u8]) -> Result<(), VolumeError> {
+            self.reads.set(self.reads.get() + 1);
+            self.inner.read(sector, buf)
+        }
+
+        fn write(&self, sector: Sector, buf: &[u8]) -> Result<(), VolumeError> {
+            self.inner.write(sector, buf)
+        }
+    }
+
+    #[test_case]
+    fn test_release_dir_entries() {
+        info!("TESTING fs::fat::test_release_dir_entries");
+        let fs = FileSystem::new(format_volume(512, 1)).unwrap();
+        let mut root = fs.root_dir();
+        for name in ["a", "b", "c"] {
+            root.create_file(name).unwrap();
+        }
+        let root_cluster = root.cluster;
+        let raw_entries = || {
+            fs.root
+                .dir_entries(root_cluster)
+                .map(|(_, _, e)| match e {
+                    DirEntry::Unused => 'u',
+                    DirEntry::UnusedTerminal => 't',
+                    _ => 'f',
+                })
+                .collect::<String>()
+        };
+        assert_eq!(raw_entries(), "ffft");
+        root.find("b").unwrap().remove(false).unwrap();
+        assert_eq!(raw_entries(), "fuft");
+        // The terminal extends back over the Unused entries before the last file
+        root.find("c").unwrap().remove(false).unwrap();
+        assert_eq!(raw_entries(), "ft");
+        let (entries, _) = raw_chain_entries(&fs, root_cluster);
+        assert!(entries[1..].chars().all(|e| e == 't'));
+        root.find("a").unwrap().remove(false).unwrap();
+        assert_eq!(raw_entries(), "t");
+        root.create_file("d").unwrap();
+        root.create_file("e").unwrap();
+        root.find("e")
+            .unwrap()
+            .mv(Some(fs.open_dir("/").unwrap()), Some("e2"))
+            .unwrap();
+        assert_eq!(raw_entries(), "fft");
+        assert!(root.files().map(|f| String::from(f.name())).eq(["d", "e2"]));
+    }
+
+    /// The raw entries of every cluster in the chain of the directory, including those after the
+    /// terminal, which must be zero-filled.
+    fn raw_chain_entries<V: Volume>(fs: &FileSystem<V>, cluster: Cluster) -> (String, usize) {
+        let mut chain = fs.root.chain(Some(cluster));
+        let mut entries = String::new();
+        let mut clusters = 0;
+        while let Some(cluster) = chain.try_next().unwrap() {
+            let mut c = fs.root.cluster(cluster);
+            for n in 0..c.dir_entries_count() {
+                entries.push(match c.read_dir_entry(n).unwrap() {
+                    DirEntry::Unused => 'u',
+                    DirEntry::UnusedTerminal => 't',
+                    _ => 'f',
+                });
+            }
+            clusters += 1;
+        }
+        (entries, clusters)
+    }
+
+    #[test_case]
+    fn test_dir_growth_at_cluster_boundary() {
+        info!("TESTING fs::fat::test_dir_growth_at_cluster_boundary");
+        // 16 entries per cluster. With `.` and `..`, the entries of 13 files end right before the
+        // boundary, and those of 14 and 30 files end exactly at the boundary, which pushes the
+        // terminal into a new cluster
+        for (count, expected_clusters) in [(13, 1), (14, 2), (30, 3)] {
+            let volume = format_volume(512, 1);
+            {
+                let fs = FileSystem::new(&volume).unwrap();
+                let first = fs
+                    .boot_sector()
+                    .cluster_location(Cluster::from_index(3))
+                    .index();
+                for i in first..volume.sector_count() {
+                    volume.write(Sector::from_index(i), &[0xa5; 512]).unwrap();
+                }
+            }
+            let names = (0..count).map(|i| format!("F{:02}", i)).collect::<Vec<_>>();
+            let check = |fs: &FileSystem<&MemVolume>| {
+                let dir = fs.open_dir("dir").unwrap();
+                assert!(dir
+                    .files()
+                    .map(|f| String::from(f.name()))
+                    .eq(names.iter().cloned()));
+                let (entries, clusters) = raw_chain_entries(fs, dir.cluster);
+                assert_eq!(clusters, expected_clusters);
+                let live = entries.find('t').unwrap();
+                assert_eq!(live, count + 2);
+                assert!(entries[..live].chars().all(|e| e == 'f'));
+                assert!(entries[live..].chars().all(|e| e == 't'));
+            };
+            {
+                let fs = FileSystem::new(&volume).unwrap();
+                fs.root_dir().create_dir("dir").unwrap();
+                let mut dir = fs.open_dir("dir").unwrap();
+                for name in names.iter() {
+                    dir.create_file(name).unwrap();
+                }
+                check(&fs);
+                // The terminal moved back by the removal of the last entry is overwritten again
+                dir.find(names.last().unwrap())
+                    .unwrap()
+                    .remove(false)
+                    .unwrap();
+                dir.create_file(names.last().unwrap()).unwrap();
+                check(&fs);
+                fs.commit().unwrap();
+            }
+            check(&FileSystem::new(&volume).unwrap());
+        }
+    }
+
+    #[test_case]
+    fn test_reserved_entries() {
+        info!("TESTING fs::fat::test_reserved_entries");
+        let fs = FileSystem::new(format_volume(512, 1)).unwrap();
+        let mut table = fs.root.fat();
+        assert_eq!(table.read_reserved(0), Ok(fat::MEDIA_ENTRY));
+        assert_eq!(table.read_reserved(1), Ok(fat::END_OF_CHAIN));
+        assert_eq!(table.read_reserved(2), Err(Error::InvalidCluster(2)));
+        table
+            .update_reserved(1, |v| v & !fat::CLEAN_SHUTDOWN)
+            .unwrap();
+        assert_eq!(table.read_reserved(1), Ok(0x07ffffff));
+        // Cluster chains are not affected
+        assert_eq!(
+            table.entries().next(),
+            Some((Cluster::from_index(2), FatEntry::UsedEoc))
+        );
+        assert_eq!(table.read_reserved(0), Ok(fat::MEDIA_ENTRY));
+    }
+
+    #[test_case]
+    fn test_volume_dirty() {
+        info!("TESTING fs::fat::test_volume_dirty");
+        let volume = format_volume(512, 1);
+        let dirty = |volume: &MemVolume| {
+            let mut buf = [0; 512];
+            let fs = FileSystem::new(volume).unwrap();
+            let bs = fs.boot_sector();
+            volume.read(bs.fat_area_start(), &mut buf).unwrap();
+            assert_eq!(
+                fs.was_dirty(),
+                u32::from_le_bytes(buf[4..8].try_into().unwrap()) & fat::CLEAN_SHUTDOWN == 0
+            );
+            fs.was_dirty()
+        };
+        assert!(!dirty(&volume));
+
+        // Read-only loads do not mark the volume
+        FileSystem::new(&volume).unwrap().root_dir().files().count();
+        assert!(!dirty(&volume));
+
+        // Killed without unmount
+        let fs = FileSystem::mount_rw(&volume).unwrap();
+        assert!(!fs.was_dirty());
+        fs.root_dir().create_file("a").unwrap();
+        fs.commit().unwrap();
+        drop(fs);
+        assert!(dirty(&volume));
+        let fs = FileSystem::mount_rw(&volume).unwrap();
+        assert!(fs.was_dirty());
+        assert!(fs.open("a").is_ok());
+
+        // Cleanly unmounted
+        fs.mark_clean().unwrap();
+        drop(fs);
+        assert!(!dirty(&volume));
+        assert!(!FileSystem::mount_rw(&volume).unwrap().was_dirty());
+    }
+
+    #[test_case]
+    fn test_insert_dir_entries() {
+        info!("TESTING fs::fat::test_insert_dir_entries");
+        let fs = FileSystem::new(format_volume_with_clusters(512, 1, 128)).unwrap();
+        let mut root = fs.root_dir();
+        for i in 0..999 {
+            root.create_file(&format!("F{}", i)).unwrap();
+        }
+        let location = |name| fs.open(name).unwrap().sfn_location();
+
+        // Inserting the 1000th entry does not read the whole directory
+        let entries = DirEntry::lfn_sequence("F999", SfnEntry::new()).unwrap();
+        let sectors_read = fs.cache_stats().sectors_read;
+        {
+            let _lock = fs.root.lock_dirs();
+            root.insert_dir_entries(entries.into_iter()).unwrap();
+        }
+        assert!(fs.cache_stats().sectors_read - sectors_read <= 4);
+        assert_eq!(root.file_count(), Ok(1000));
+
+        // Holes are reused, even if they span clusters
+        let hole = location("F500");
+        fs.open("F500").unwrap().remove(false).unwrap();
+        root.create_file("G").unwrap();
+        assert_eq!(location("G"), hole);
+        let (c, n) = location("F15"); // the last entry of the first cluster
+        assert_eq!((c, n + 1), (root.cluster, 16));
+        for i in 14..18 {
+            fs.open(&format!("F{}", i)).unwrap().remove(false).unwrap();
+        }
+        root.create_file("A long name, 3 entries").unwrap(); // 2 LFN entries
+        assert_eq!(
+            fs.open("A long name, 3 entries").unwrap().entry_location.0,
+            root.cluster
+        );
+        assert_ne!(location("A long name, 3 entries").0, root.cluster);
+        root.create_file("H").unwrap();
+        root.create_file("I").unwrap();
+        assert_eq!(root.file_count(), Ok(999));
+        assert_eq!(
+            fs.open("I").unwrap().sfn_location().0,
+            fs.open("F999").unwrap().sfn_location().0
+        );
+    }
+
+    #[test_case]
+    fn test_for_each_entry() {
+        info!("TESTING fs::fat::test_for_each_entry");
+        let fs = FileSystem::new(format_volume_with_clusters(512, 1, 128)).unwrap();
+        let mut root = fs.root_dir();
+        for i in 0..200 {
+            root.create_file(&format!("A long file name {}", i))
+                .unwrap();
+        }
+        root.create_dir("dir").unwrap();
+        let names = |dir: &Dir<_>| {
+            let mut names = Vec::new();
+            dir.for_each_entry(|e| {
+                names.push(String::from(e.name()));
+                ControlFlow::Continue(())
+            })
+            .unwrap();
+            names
+        };
+        assert_eq!(names(&root).len(), 201);
+        assert!(root
+            .files()
+            .map(|f| String::from(f.name()))
+            .eq(names(&root)));
+        let mut count = 0;
+        root.for_each_entry(|_| {
+            count += 1;
+            if count == 3 {
+                ControlFlow::Break(())
+            } else {
+                ControlFlow::Continue(())
+            }
+        })
+        .unwrap();
+        assert_eq!(count, 3);
+        assert_eq!(
+            fs.open_dir("dir").unwrap().cluster,
+            root.find("DIR").unwrap().as_dir().unwrap().cluster
+        );
+
+        // The names are decoded into a single buffer
+        let allocations = || allocator::heap_info().allocations;
+        let start = allocations();
+        let mut size = 0;
+        root.for_each_entry(|e| {
+            size += e.file_size();
+            ControlFlow::Continue(())
+        })
+        .unwrap();
+        let for_each_allocations = allocations() - start;
+        let start = allocations();
+        root.files().for_each(drop);
+        let iter_allocations = allocations() - start;
+        assert_eq!(size, 0);
+        assert!(for_each_allocations < 16, "{}", for_each_allocations);
+        assert!(200 <= iter_allocations, "{}", iter_allocations);
+
+        // An orphaned LFN entry is skipped, and the SFN entry following it is exposed by its name
+        let (c, n) = fs.open("A long file name 0").unwrap().entry_location;
+        fs.root
+            .cluster(c)
+            .write_dir_entry(n, DirEntry::Unused)
+            .unwrap();
+        let names = names(&root);
+        assert_eq!(names.len(), 201);
+        assert!(!names.iter().any(|name| name == "A long file name 0"));
+        assert!(root.files().map(|f| String::from(f.name())).eq(names));
+    }
+
+    #[test_case]
+    fn test_compact() {
+        info!("TESTING fs::fat::test_compact");
+        let volume = CountingVolume {
+            inner: format_volume(512, 8),
+            reads: Cell::new(0),
+        };
+        let fs = FileSystem::new(&volume).unwrap();
+        fs.root_dir().create_dir("dir").unwrap();
+        let mut dir = fs.open_dir("dir").unwrap();
+        for i in 0..500 {
+            dir.create_file(&format!("f{}", i)).unwrap();
+        }
+        for i in 0..499 {
+            dir.find(&format!("f{}", i)).unwrap().remove(false).unwrap();
+        }
+        let dir_cluster = dir.cluster;
+        let chain_len = || {
+            let mut len = 0;
+            fs.root
+                .fat()
+                .walk_chain(dir_cluster, |_, _, _| {
+                    len += 1;
+                    Ok(true)
+                })
+                .unwrap();
+            len
+        };
+        let lookup_reads = || {
+            let reads = volume.reads.get();
+            assert_eq!(dir.contains("missing"), Ok(false));
+            volume.reads.get() - reads
+        };
+        // 502 entries (including `.` and `..`) of 128 entries per cluster
+        assert_eq!(chain_len(), 4);
+        let reads_before = lookup_reads();
+
+        assert_eq!(
+            dir.compact(),
+            Ok(CompactReport {
+                live_entries: 3,
+                unused_entries: 499,
+                released_clusters: 3,
+            })
+        );
+        assert_eq!(chain_len(), 1);
+        let reads_after = lookup_reads();
+        assert!(reads_after * 8 < reads_before);
+        assert!(dir.files().map(|f| String::from(f.name())).eq(["f499"]));
+        let names = dir.entries().map(|f| String::from(f.name()));
+        assert!(names.eq([".", "..", "f499"]));
+
+        // Compacting a dense directory changes nothing
+        assert_eq!(
+            dir.compact()
+                .map(|r| (r.unused_entries, r.released_clusters)),
+            Ok((0, 0))
+        );
+        for i in 0..200 {
+            dir.create_file(&format!("g{}", i)).unwrap();
+        }
+        assert_eq!(chain_len(), 2);
+        assert_eq!(dir.file_count(), Ok(201));
+    }
+
+    #[test_case]
+    fn test_dot_entries() {
+        info!("TESTING fs::fat::test_dot_entries");
+        let fs = FileSystem::new(format_volume(512, 1)).unwrap();
+        fs.root_dir().create_dir("a").unwrap();
+        fs.open_dir("a").unwrap().create_dir("b").unwrap();
+        fs.open_dir("a/b").unwrap().create_dir("c").unwrap();
+        fs.open_dir("a/b/c").unwrap().create_file("f").unwrap();
+        fs.root_dir().create_file("g").unwrap();
+        let cluster_of = |path| fs.open_dir(path).unwrap().cluster;
+
+        // Down three levels and back up
+        assert_eq!(fs.open("a/b/c/../../b/c/f").unwrap().name(), "f");
+        assert!(fs.open_dir("a/b/c/../../..").unwrap().is_root());
+        assert!(fs.open_dir("a/b/c/../../../..").unwrap().is_root());
+        assert_eq!(fs.open("a/b/c/../../../g").unwrap().name(), "g");
+        assert_eq!(fs.open("a/b/c/..").unwrap().name(), "b");
+        assert_eq!(fs.open("a/./b/c/../..").unwrap().name(), "a");
+        assert_eq!(
+            fs.open("a/..").unwrap_err(),
+            Error::NotFound(String::from("/"))
+        );
+        assert_eq!(fs.open_or_create("a/b/..").unwrap().name(), "a");
+        assert_eq!(fs.open_dir("a/b").unwrap().file_count(), Ok(1));
+
+        // Listing
+        let b = fs.open_dir("a/b").unwrap();
+        let entries = b.entries().collect::<Vec<_>>();
+        let names = entries.iter().map(|f| f.name()).collect::<Vec<_>>();
+        assert_eq!(names, [".", "..", "c"]);
+        assert!(entries[0].is_dot_entry() && entries[1].is_dot_entry());
+        assert!(!entries[2].is_dot_entry());
+        assert_eq!(entries[0].as_dir().unwrap().cluster, cluster_of("a/b"));
+        assert_eq!(entries[1].as_dir().unwrap().cluster, cluster_of("a"));
+        assert!(b.files().map(|f| String::from(f.name())).eq(["c"]));
+
+        let a = fs.open_dir("a").unwrap();
+        let dotdot = a.entries().find(|f| f.name() == "..").unwrap();
+        assert!(dotdot.as_dir().unwrap().is_root());
+        assert!(fs.root_dir().entries().all(|f| !f.is_dot_entry()));
+        assert_eq!(dotdot.remove(true), Err(Error::InvalidFileName));
+        assert!(fs.open("g").is_ok());
+    }
+
+    #[test_case]
+    fn test_archive() {
+        info!("TESTING fs::fat::test_archive");
+        let fs = FileSystem::new(format_volume(512, 1)).unwrap();
+        let mut root = fs.root_dir();
+        root.create_dir("dir").unwrap();
+        root.create_file("a").unwrap();
+        fs.open_dir("dir").unwrap().create_file("b").unwrap();
+        assert!(!fs.open("dir").unwrap().archive());
+        assert!(fs.changed_files().eq(["/dir/b", "/a"]));
+
+        for path in fs.changed_files().collect::<Vec<_>>() {
+            fs.open(&path).unwrap().clear_archive().unwrap();
+        }
+        assert_eq!(fs.changed_files().count(), 0);
+
+        for path in ["a", "dir/b"] {
+            let mut file = fs.open(path).unwrap();
+            file.overwriter().unwrap().write(b"hello").unwrap();
+        }
+        fs.open("a").unwrap().clear_archive().unwrap();
+        assert!(fs.changed_files().eq(["/dir/b"]));
+
+        // Metadata-only changes
+        let mut a = fs.open("a").unwrap();
+        a.set_is_hidden(true).unwrap();
+        a.set_is_system(true).unwrap();
+        fs.open("a")
+            .unwrap()
+            .mv(Some(fs.open_dir("dir").unwrap()), None)
+            .unwrap();
+        let a = fs.open("dir/a").unwrap();
+        assert!(a.is_hidden() && a.is_system() && !a.archive());
+        assert_eq!(a.reader().unwrap().read_to_end().unwrap(), b"hello");
+        assert!(fs.changed_files().eq(["/dir/b"]));
+    }
+
+    #[test_case]
+    fn test_access_time() {
+        info!("TESTING fs::fat::test_access_time");
+        static TODAY: AtomicU16 = AtomicU16::new(0);
+        fn clock() -> Option<Date> {
+            Date::from_raw(TODAY.load(Ordering::SeqCst))
+        }
+        let set_today =
+            |day| TODAY.store(Date::new(2022, 3, day).unwrap().to_raw(), Ordering::SeqCst);
+        let today = |day| Date::new(2022, 3, day);
+
+        assert_eq!(
+            FileSystem::new(format_volume(512, 1))
+                .unwrap()
+                .access_time_policy(),
+            AccessTimePolicy::Never
+        );
+        let fs = FileSystem::mount_rw(format_volume(512, 1)).unwrap();
+        assert_eq!(fs.access_time_policy(), AccessTimePolicy::Relative);
+        fs.set_clock(clock);
+        fs.root_dir().create_file("a").unwrap();
+        let mut a = fs.open("a").unwrap();
+        a.overwriter().unwrap().write(b"hello").unwrap();
+        fs.open("a").unwrap().clear_archive().unwrap();
+
+        let date = || fs.open("a").unwrap().metadata().last_access_date;
+        let read = || {
+            let data = fs.open("a").unwrap().reader().unwrap().read_to_end();
+            assert_eq!(data.unwrap(), b"hello");
+        };
+        // The number of sectors written back by a commit
+        let commit = || {
+            let written = fs.cache_stats().sectors_written;
+            fs.commit().unwrap();
+            fs.cache_stats().sectors_written - written
+        };
+        commit();
+
+        // Nothing is recorded while the date is unknown
+        read();
+        assert_eq!((date(), commit()), (None, 0));
+
+        set_today(14);
+        read();
+        assert_eq!(date(), today(14));
+        assert_eq!(commit(), 1);
+        read();
+        read();
+        assert_eq!((date(), commit()), (today(14), 0));
+        set_today(15);
+        read();
+        assert_eq!((date(), commit()), (today(15), 1));
+        // The date is never moved back
+        set_today(13);
+        read();
+        assert_eq!((date(), commit()), (today(15), 0));
+
+        fs.set_access_time_policy(AccessTimePolicy::OnRead);
+        read();
+        assert_eq!((date(), commit()), (today(13), 1));
+        set_today(16);
+        for _ in 0..3 {
+            read();
+            assert_eq!(date(), today(16));
+        }
+        commit();
+
+        fs.set_access_time_policy(AccessTimePolicy::Never);
+        set_today(17);
+        read();
+        read();
+        assert_eq!((date(), commit()), (today(16), 0));
+
+        // The archive attribute is not affected
+        assert!(!fs.open("a").unwrap().archive());
+        assert_eq!(fs.changed_files().count(), 0);
+
+        // Changes through a `File` opened before the read keep the date
+        fs.set_access_time_policy(AccessTimePolicy::Relative);
+        let mut a = fs.open("a").unwrap();
+        read();
+        assert_eq!(date(), today(17));
+        a.set_is_hidden(true).unwrap();
+        assert_eq!(date(), today(17));
+        a.overwriter().unwrap().write(b"world").unwrap();
+        assert_eq!(date(), today(17));
+    }
+
+    #[test_case]
+    fn test_root_dir() {
+        info!("TESTING fs::fat::test_root_dir");
+        let fs = FileSystem::new(format_volume(512, 1)).unwrap();
+        let mut root = fs.root_dir();
+        let root_dir_cluster = fs.boot_sector().root_dir_cluster();
+        assert_eq!(root_dir_cluster.index(), 2);
+        assert!(root.is_root());
+        assert!(root.parent().unwrap().is_none());
+
+        // 16 entries per cluster: the root directory chain must be extended
+        for i in 0..10 {
+            root.create_file(&format!("root{}", i)).unwrap();
+        }
+        let mut chain_len = 0;
+        fs.root
+            .fat()
+            .walk_chain(root_dir_cluster, |_, _, _| {
+                chain_len += 1;
+                Ok(true)
+            })
+            .unwrap();
+        assert!(2 <= chain_len);
+        assert_eq!(root.file_count(), Ok(10));
+        {
+            let mut file = root.find("root9").unwrap();
+            file.overwriter().unwrap().write(b"hello").unwrap();
+        }
+        assert_eq!(
+            root.find("root9").unwrap().reader().unwrap().read_to_end(),
+            Ok(b"hello".to_vec())
+        );
+        root.find("root0").unwrap().remove(false).unwrap();
+        assert_eq!(root.file_count(), Ok(9));
+        root.create_file("root0").unwrap();
+        assert_eq!(root.file_count(), Ok(10));
+
+        // `..` of a directory is updated on moving into/out of the root directory
+        root.create_dir("a").unwrap();
+        let a = fs.open_dir("a").unwrap();
+        assert!(a.parent().unwrap().unwrap().is_root());
+        root.create_dir("b").unwrap();
+        fs.open("b").unwrap().mv(Some(a), None).unwrap();
+        let b = fs.open_dir("a/b").unwrap();
+        assert_eq!(
+            b.parent().unwrap().unwrap().cluster,
+            fs.open_dir("a").unwrap().cluster
+        );
+        fs.open("a/b")
+            .unwrap()
+            .mv(Some(fs.root_dir()), None)
+            .unwrap();
+        assert!(fs
+            .open_dir("b")
+            .unwrap()
+            .parent()
+            .unwrap()
+            .unwrap()
+            .is_root());
+    }
+
+    /// Run a fixed sequence of operations and record the observations.
+    fn exercise<V: Volume>(fs: &FileSystem<V>) -> Vec<String> {
+        let mut log = Vec::new();
+        let mut root = fs.root_dir();
+        let data = (0..1500).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        root.create_dir("dir").unwrap();
+        root.create_file("data").unwrap();
+        {
+            let mut file = root.find("data").unwrap();
+            file.overwriter().unwrap().write(&data).unwrap();
+        }
+        let mut dir = fs.open_dir("dir").unwrap();
+        let file = fs.open("data").unwrap();
+        file.copy_to(&mut dir, "copy").unwrap();
+        file.mv(None, Some("renamed")).unwrap();
+        log.push(format!("{:?}", root.create_file("renamed")));
+        for file in root.files().chain(dir.files()) {
+            let metadata = file.metadata();
+            log.push(format!(
+                "{} {} {:?} {}",
+                metadata.name, metadata.size, metadata.runs, metadata.sfn_name
+            ));
+        }
+        let copy = fs.open("dir/copy").unwrap();
+        log.push(format!(
+            "{}",
+            copy.reader().unwrap().read_to_end() == Ok(data)
+        ));
+        fs.open("dir/copy").unwrap().remove(false).unwrap();
+        log.push(format!("{:?}", fs.open("dir/copy").err()));
+        fs.commit().unwrap();
+        log
+    }
+
+    fn volume_bytes(volume: &dyn Volume) -> Vec<u8> {
+        let mut bytes = vec![0; volume.sector_count() * volume.sector_size()];
+        for (i, buf) in bytes.chunks_mut(volume.sector_size()).enumerate() {
+            volume.read(Sector::from_index(i), buf).unwrap();
+        }
+        bytes
+    }
+
+    #[test_case]
+    fn test_dyn_volume() {
+        info!("TESTING fs::fat::test_dyn_volume");
+        let concrete = format_volume(512, 1);
+        let concrete_log = exercise(&FileSystem::new(&concrete).unwrap());
+        assert_eq!(concrete_log.len(), 6);
+
+        // Through a reference to the trait object
+        let by_ref = format_volume(512, 1);
+        let by_ref_log = exercise(&FileSystem::new(&by_ref as &dyn Volume).unwrap());
+        assert_eq!(by_ref_log, concrete_log);
+        assert!(volume_bytes(&by_ref) == volume_bytes(&concrete));
+
+        // Through a boxed trait object, as mounted file systems are
+        let boxed: DynFileSystem = FileSystem::new(Box::new(format_volume(512, 1)) as _).unwrap();
+        assert_eq!(exercise(&boxed), concrete_log);
+        let root = boxed.root_dir();
+        assert_eq!(root.file_count(), Ok(2));
+    }
+
+    #[test_case]
+    fn test_broken_dir_sector() {
+        info!("TESTING fs::fat::test_broken_dir_sector");
+        let volume = format_volume(512, 1);
+        let dir_sector = {
+            let fs = FileSystem::new(&volume).unwrap();
+            let mut root = fs.root_dir();
+            root.create_file("short.txt").unwrap();
+            root.create_file("A long file name.txt").unwrap();
+            root.create_dir("dir").unwrap();
+            fs.open_dir("dir").unwrap().create_file("inner").unwrap();
+            let mut file = root.find("short.txt").unwrap();
+            file.overwriter().unwrap().write(&[1; 700]).unwrap();
+            fs.commit().unwrap();
+            let bs = fs.boot_sector();
+            bs.cluster_location(bs.root_dir_cluster())
+        };
+        let mut original = [0; 512];
+        volume.read(dir_sector, &mut original).unwrap();
+
+        // xorshift32, to mutate the same bytes in every run
+        let mut state = 0x2545f491u32;
+        let mut rand = move || {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as usize
+        };
+        for _ in 0..256 {
+            let mut buf = original;
+            for _ in 0..1 + rand() % 8 {
+                // Mutate the live entries and the terminator following them
+                buf[rand() % (DirEntry::SIZE * 6)] = rand() as u8;
+            }
+            volume.write(dir_sector, &buf).unwrap();
+
+            let fs = FileSystem::new(&volume).unwrap();
+            let root = fs.root_dir();
+            for file in root.files() {
+                let _ = file.name();
+                let _ = file.metadata();
+                if let Some(dir) = file.as_dir() {
+                    let _ = dir.files().count();
+                }
+                if let Some(mut reader) = file.reader() {
+                    let mut buf = [0; 512];
+                    for _ in 0..4 {
+                        if !matches!(reader.read(&mut buf), Ok(n) if n != 0) {
+                            break;
+                        }
+                    }
+                }
+            }
+            let _ = root.file_count();
+            let _ = fs.open("dir/inner");
+        }
+    }
+
+    #[test_case]
+    fn test_write_zeros() {
+        info!("TESTING fs::fat::test_write_zeros");
+        let volume = format_volume(512, 2);
+        let garbage = [0xa5; 512];
+        {
+            let fs = FileSystem::new(&volume).unwrap();
+            let bs = fs.boot_sector();
+            let first = bs.cluster_location(Cluster::from_index(3)).index();
+            for i in first..volume.sector_count() {
+                volume.write(Sector::from_index(i), &garbage).unwrap();
+            }
+        }
+
+        let mut data = vec![1; 100];
+        data.extend(vec![0; 4096]);
+        data.extend(vec![2; 100]);
+        let dir_cluster = {
+            let fs = FileSystem::new(&volume).unwrap();
+            let mut root = fs.root_dir();
+            root.create_dir("dir").unwrap();
+            root.create_file("data").unwrap();
+            let mut file = root.find("data").unwrap();
+            let mut writer = file.overwriter().unwrap();
+            // Split into a partial cluster, whole zero clusters, and the rest
+            writer.write(&data[0..100]).unwrap();
+            writer.write(&data[100..1024]).unwrap();
+            let written = fs.cache_stats().sectors_written;
+            writer.write(&data[1024..3072]).unwrap();
+            assert_eq!(fs.cache_stats().sectors_written, written + 4);
+            writer.write(&data[3072..]).unwrap();
+            drop(writer);
+            assert_eq!(
+                fs.open("data").unwrap().reader().unwrap().read_to_end(),
+                Ok(data.clone())
+            );
+            fs.commit().unwrap();
+            fs.open_dir("dir").unwrap().cluster
+        };
+
+        let fs = FileSystem::new(&volume).unwrap();
+        let file = fs.open("data").unwrap();
+        assert_eq!(file.reader().unwrap().read_to_end(), Ok(data));
+
+        // New directory clusters are zero-filled beyond the entries
+        let dir_sector = fs.boot_sector().cluster_location(dir_cluster);
+        let mut buf = [0; 512];
+        volume.read(dir_sector.offset(1), &mut buf).unwrap();
+        assert!(buf.iter().all(|b| *b == 0));
+        volume.read(dir_sector, &mut buf).unwrap();
+        assert!(buf[DirEntry::SIZE * 2..].iter().all(|b| *b == 0));
+
+        // So are the clusters added when a directory grows
+        let mut dir = fs.open_dir("dir").unwrap();
+        for i in 0..20 {
+            dir.create_file(&format!("long name {:02}", i)).unwrap();
+        }
+        fs.commit().unwrap();
+        let second = fs.root.fat().read_chain(dir_cluster).unwrap().unwrap();
+        let second_sector = fs.boot_sector().cluster_location(second);
+        volume.read(second_sector.offset(1), &mut buf).unwrap();
+        assert!(buf.iter().all(|b| *b == 0));
+        assert_eq!(dir.file_count(), Ok(20));
+    }
+
+    struct Job {
+        fs: &'static FileSystem<MemVolume>,
+        prefix: &'static str,
+        done: AtomicBool,
+    }
+
+    const JOB_FILES: usize = 200;
+
+    fn job_data(prefix: &str, i: usize) -> Vec<u8> {
+        let seed = prefix.bytes().map(usize::from).sum::<usize>() + i;
+        (0..700).map(|j| ((seed + j) % 251) as u8).collect()
+    }
+
+    extern "C" fn run_job(arg: u64) -> ! {
+        let job = unsafe { &*(arg as *const Job) };
+        let mut dir = job.fs.root_dir();
+        for i in 0..JOB_FILES {
+            let name = format!("{} {}", job.prefix, i);
+            dir.create_file(&name).unwrap();
+            let mut file = dir.find(&name).unwrap();
+            file.overwriter()
+                .unwrap()
+                .write(&job_data(job.prefix, i))
+                .unwrap();
+            if i % 4 == 3 {
+                let name = format!("{} {}", job.prefix, i - 1);
+                dir.find(&name).unwrap().remove(false).unwrap();
+            }
+            task::scheduler().r#yield();
+        }
+        job.done.store(true, Ordering::SeqCst);
+        loop {
+            task::scheduler().sleep(100);
+        }
+    }
+
+    #[test_case]
+    fn test_concurrent_tasks() {
+        info!("TESTING fs::fat::test_concurrent_tasks");
+        let fs = Box::leak(Box::new(
+            FileSystem::new(format_volume_with_clusters(512, 1, 1024)).unwrap(),
+        ));
+        let jobs = ["a", "b"].map(|prefix| {
+            let job = Box::leak(Box::new(Job {
+                fs,
+                prefix,
+                done: AtomicBool::new(false),
+            }));
+            task::scheduler().add(task::Priority::MAX, run_job, job as *const Job as u64);
+            job
+        });
+        while !jobs.iter().all(|job| job.done.load(Ordering::SeqCst)) {
+            task::scheduler().sleep(1);
+        }
+
+        // Every file must have its own data, and there must be no cross-linked or lost clusters
+        let root = fs.root_dir();
+        let mut used = BTreeSet::new();
+        fs.root
+            .fat()
+            .walk_chain(root.cluster, |_, c, _| Ok(used.insert(c.index())))
+            .unwrap();
+        let mut count = 0;
+        for file in root.files() {
+            let (prefix, i) = file.name().split_once(' ').unwrap();
+            let i = i.parse::<usize>().unwrap();
+            assert!(i % 4 != 2, "{} is not removed", file.name());
+            assert_eq!(
+                file.reader().unwrap().read_to_end(),
+                Ok(job_data(prefix, i))
+            );
+            let metadata = file.metadata();
+            assert_eq!(metadata.chain_error, None);
+            assert_eq!(metadata.cluster_count, 2);
+            for (start, len) in metadata.runs {
+                for c in start..start + len {
+                    assert!(used.insert(c), "cluster {} is cross-linked", c);
+                }
+            }
+            count += 1;
+        }
+        assert_eq!(count, 2 * (JOB_FILES - JOB_FILES / 4));
+        for (c, entry) in fs.root.fat().entries() {
+            let is_used = !matches!(entry, FatEntry::Unused);
+            assert_eq!(is_used, used.contains(&c.index()), "cluster {} is lost", c);
+        }
+    }
+
+    #[test_case]
+    fn test_allocate_chain() {
+        info!("TESTING fs::fat::test_allocate_chain");
+        let volume = format_volume_with_clusters(512, 1, CLUSTER_COUNT);
+        let fs = FileSystem::new(&volume).unwrap();
+        let c = Cluster::from_index;
+        let chain = |start| {
+            let mut clusters = Vec::new();
+            fs.root
+                .fat()
+                .walk_chain(start, |_, c, _| {
+                    clusters.push(c.index());
+                    Ok(true)
+                })
+                .unwrap();
+            clusters
+        };
+        let mut fat = fs.root.fat();
+        // The root directory uses the cluster 2
+        let (head, tail) = fat.allocate_chain(CLUSTER_COUNT - 1, None).unwrap();
+        assert_eq!((head, tail), (c(3), c(CLUSTER_COUNT + 1)));
+        assert_eq!(chain(head), (3..=CLUSTER_COUNT + 1).collect::<Vec<_>>());
+        assert_eq!(fat.allocate_chain(1, None), Err(Error::Full));
+        fat.release(head).unwrap();
+
+        // Fragment the unused clusters
+        for _ in 0..CLUSTER_COUNT - 1 {
+            fat.allocate().unwrap();
+        }
+        for n in [4, 6, 8, 12, 13] {
+            fat.release(c(n)).unwrap();
+        }
+        assert_eq!(fat.free_cluster_count(), Ok(5));
+
+        // A run of consecutive clusters is preferred even if it is not the first
+        let (head, tail) = fat.allocate_chain(2, None).unwrap();
+        assert_eq!((head, tail), (c(12), c(13)));
+        assert_eq!(chain(head), [12, 13]);
+        // Otherwise the first unused clusters are taken
+        assert_eq!(fat.allocate_chain(4, None), Err(Error::Full));
+        let (head, tail) = fat.allocate_chain(3, None).unwrap();
+        assert_eq!((head, tail), (c(4), c(8)));
+        assert_eq!(chain(head), [4, 6, 8]);
+        assert_eq!(fat.free_cluster_count(), Ok(0));
+    }
+
+    #[test_case]
+    fn test_batched_fat_updates() {
+        info!("TESTING fs::fat::test_batched_fat_updates");
+        const CLUSTERS: usize = 2048;
+        const FILE_CLUSTERS: usize = MAX_ALLOCATION_CLUSTERS + 512;
+        let volume = format_volume_with_clusters(512, 1, CLUSTERS);
+        let fs = FileSystem::new(&volume).unwrap();
+        let data = (0..FILE_CLUSTERS * 512)
+            .map(|i| (i % 251) as u8 + 1)
+            .collect::<Vec<_>>();
+        // FAT sectors holding the entries of the file and the root directory
+        let fat_sectors = (FILE_CLUSTERS + 3) * 4 / 512 + 1;
+
+        // "a" is written at once, and "b" is written in chunks with the size hint
+        for (name, hint) in [("a", None), ("b", Some(data.len()))] {
+            fs.root_dir().create_file(name).unwrap();
+            fs.commit().unwrap();
+            let written = fs.cache_stats().sectors_written;
+            let mut file = fs.open(name).unwrap();
+            let mut writer = match hint {
+                Some(len) => file.overwriter_with_size_hint(len).unwrap(),
+                None => file.overwriter().unwrap(),
+            };
+            let chunk_size = hint.map_or(data.len(), |_| 64 * 1024);
+            for chunk in data.chunks(chunk_size) {
+                writer.write(chunk).unwrap();
+            }
+            drop(writer);
+            fs.commit().unwrap();
+            // Each FAT sector is written back about once, besides the data and the directory
+            let fat_written = fs.cache_stats().sectors_written - written - FILE_CLUSTERS as u64;
+            assert!(
+                fat_written <= 2 * fat_sectors as u64,
+                "{}: {} sectors written for {} FAT sectors",
+                name,
+                fat_written,
+                fat_sectors
+            );
+
+            let file = fs.open(name).unwrap();
+            assert_eq!(file.reader().unwrap().read_to_end(), Ok(data.clone()));
+            let start = file.last_entry.0.cluster().unwrap();
+            let mut clusters = 0;
+            fs.root
+                .fat()
+                .walk_chain(start, |_, _, _| {
+                    clusters += 1;
+                    Ok(true)
+                })
+                .unwrap();
+            assert_eq!(clusters, FILE_CLUSTERS);
+            fs.open(name).unwrap().remove(false).unwrap();
+        }
+        assert_eq!(fs.free_cluster_count(), Ok(CLUSTERS - 1));
+    }
+
+    #[test_case]
+    fn test_reservation() {
+        info!("TESTING fs::fat::test_reservation");
+        let volume = format_volume_with_clusters(512, 1, CLUSTER_COUNT);
+        let fs = FileSystem::new(&volume).unwrap();
+        let scan = || {
+            fs.root
+                .fat()
+                .entries()
+                .filter(|(_, entry)| matches!(entry, FatEntry::Unused))
+                .count()
+        };
+        // The root directory uses a cluster
+        assert_eq!(fs.free_cluster_count(), Ok(CLUSTER_COUNT - 1));
+
+        let mut root = fs.root_dir();
+        for name in ["a", "b", "c"] {
+            root.create_file(name).unwrap();
+        }
+        let mut a = root.find("a").unwrap();
+        let mut c = root.find("c").unwrap();
+        let mut a = a.overwriter_with_size_hint(512 * 12).unwrap();
+        // Only 3 clusters are left for the others
+        let mut b = root.find("b").unwrap();
+        assert_eq!(
+            b.overwriter_with_size_hint(512 * 4).err(),
+            Some(Error::Full)
+        );
+        let mut c = c.overwriter().unwrap();
+        a.write(&[1; 512 * 6]).unwrap();
+        c.write(&[2; 512 * 3]).unwrap();
+        assert_eq!(c.write(&[2]), Err(Error::Full));
+        a.write(&[1; 512 * 6]).unwrap();
+        // The reservation is used up
+        assert_eq!(a.write(&[1]), Err(Error::Full));
+        drop((a, c));
+        assert_eq!(fs.free_cluster_count(), Ok(0));
+        assert_eq!(scan(), 0);
+
+        // Unused reserved clusters are returned on drop
+        root.find("c").unwrap().remove(false).unwrap();
+        assert_eq!(fs.free_cluster_count(), Ok(3));
+        let mut b = root.find("b").unwrap();
+        drop(b.overwriter_with_size_hint(512 * 3).unwrap());
+        let mut b = root.find("b").unwrap();
+        let mut writer = b.overwriter().unwrap();
+        writer.write(&[3; 512 * 2]).unwrap();
+        drop(writer);
+        assert_eq!(fs.free_cluster_count(), Ok(1));
+        assert_eq!(scan(), 1);
+
+        // Rewriting a file needs only the clusters it does not have yet
+        let mut a = root.find("a").unwrap();
+        assert!(a.overwriter_with_size_hint(512 * 13).is_ok());
+        let mut a = root.find("a").unwrap();
+        assert_eq!(
+            a.overwriter_with_size_hint(512 * 14).err(),
+            Some(Error::Full)
+        );
+    }
+
+    struct RacingWriter {
+        fs: &'static FileSystem<MemVolume>,
+        name: &'static str,
+        fill: u8,
+        result: Spin<Option<Result<(), Error>>>,
+    }
+
+    /// More than half of the clusters left, so that only one of two writers can reserve them.
+    const RACING_CLUSTERS: usize = 10;
+
+    extern "C" fn write_racing(arg: u64) -> ! {
+        let w = unsafe { &*(arg as *const RacingWriter) };
+        let mut file = w.fs.open(w.name).unwrap();
+        let result = file
+            .overwriter_with_size_hint(512 * RACING_CLUSTERS)
+            .and_then(|mut writer| {
+                for _ in 0..RACING_CLUSTERS {
+                    writer.write(&[w.fill; 512])?;
+                    task::scheduler().r#yield();
+                }
+                Ok(())
+            });
+        *w.result.lock() = Some(result);
+        task::scheduler().exit()
+    }
+
+    #[test_case]
+    fn test_racing_reservations() {
+        info!("TESTING fs::fat::test_racing_reservations");
+        let fs = Box::leak(Box::new(FileSystem::new(format_volume(512, 1)).unwrap()));
+        let writers = [("a", 1), ("b", 2)].map(|(name, fill)| {
+            fs.root_dir().create_file(name).unwrap();
+            &*Box::leak(Box::new(RacingWriter {
+                fs,
+                name,
+                fill,
+                result: Spin::new(None),
+            }))
+        });
+        for w in writers {
+            let arg = w as *const RacingWriter as u64;
+            task::scheduler().add(task::Priority::MAX, write_racing, arg);
+        }
+        while writers.iter().any(|w| w.result.lock().is_none()) {
+            task::scheduler().sleep(1);
+        }
+
+        // One of them fails up front without writing anything, and the other completes
+        let results = writers.map(|w| w.result.lock().take().unwrap());
+        assert!(results.contains(&Ok(())), "{:?}", results);
+        assert!(results.contains(&Err(Error::Full)), "{:?}", results);
+        for (w, result) in writers.iter().zip(results) {
+            let file = fs.open(w.name).unwrap();
+            let data = file.reader().unwrap().read_to_end().unwrap();
+            match result {
+                Ok(()) => assert_eq!(data, vec![w.fill; 512 * RACING_CLUSTERS]),
+                Err(_) => assert!(data.is_empty()),
+            }
+        }
+        let scan = fs
+            .root
+            .fat()
+            .entries()
+            .filter(|(_, entry)| matches!(entry, FatEntry::Unused))
+            .count();
+        assert_eq!(scan, CLUSTER_COUNT - 1 - RACING_CLUSTERS);
+        assert_eq!(fs.free_cluster_count(), Ok(scan));
+    }
+
+    #[test_case]
+    fn test_preallocate() {
+        info!("TESTING fs::fat::test_preallocate");
+        let volume = format_volume_with_clusters(512, 1, CLUSTER_COUNT);
+        {
+            // Unused clusters hold garbage, which must not be seen through preallocated files
+            let fs = FileSystem::new(&volume).unwrap();
+            let first = fs
+                .boot_sector()
+                .cluster_location(Cluster::from_index(3))
+                .index();
+            for i in first..volume.sector_count() {
+                volume.write(Sector::from_index(i), &[0xa5; 512]).unwrap();
+            }
+        }
+        let fs = FileSystem::new(&volume).unwrap();
+        let c = Cluster::from_index;
+        let s = |n| fs.boot_sector().cluster_location(c(n));
+        let mut root = fs.root_dir();
+        root.create_file("a").unwrap();
+        root.create_dir("d").unwrap();
+        assert_eq!(
+            fs.open("d").unwrap().preallocate(512).err(),
+            Some(Error::IsADirectory("d".into()))
+        );
+        // Fragment the unused clusters into 4..=6, 9, 12..=13
+        let mut fat = fs.root.fat();
+        for _ in 0..CLUSTER_COUNT - 2 {
+            fat.allocate().unwrap();
+        }
+        for n in [4, 5, 6, 9, 12, 13] {
+            fat.release(c(n)).unwrap();
+        }
+        drop(fat);
+
+        let mut a = root.find("a").unwrap();
+        a.overwriter().unwrap().write(&[1; 100]).unwrap();
+        let mut a = root.find("a").unwrap();
+        assert_eq!(a.cluster_runs(), Ok(vec![(s(4), 1)]));
+        assert_eq!(a.preallocate(512 * 7), Err(Error::Full));
+        // Sizes beyond the directory entry are rejected before anything is reserved
+        assert_eq!(a.preallocate(usize::MAX), Err(Error::FileTooLarge));
+        assert_eq!(a.preallocate(MAX_FILE_SIZE + 1), Err(Error::FileTooLarge));
+        assert_eq!(a.file_size(), 100);
+        assert_eq!(fs.free_cluster_count(), Ok(5));
+
+        let len = 512 * 5 + 10;
+        let runs = a.preallocate(len).unwrap();
+        assert_eq!(runs, [(s(4), 3), (s(9), 1), (s(12), 2)]);
+        assert_eq!(a.file_size(), len);
+        assert_eq!(fs.free_cluster_count(), Ok(0));
+        let mut data = vec![1; 100];
+        data.resize(len, 0);
+        assert_eq!(
+            fs.open("a").unwrap().reader().unwrap().read_to_end(),
+            Ok(data)
+        );
+        // Preallocating a shorter length changes nothing
+        let mut a = fs.open("a").unwrap();
+        assert_eq!(a.preallocate(10), Ok(runs.clone()));
+        assert_eq!(a.file_size(), len);
+        assert_eq!(a.cluster_runs(), Ok(runs.clone()));
+
+        // Raw writes to the runs are read through the file system once the cache is evicted
+        let mut written = Vec::new();
+        for (sector, count) in runs.iter() {
+            for i in 0..*count {
+                let buf = [(written.len() / 512) as u8 + 2; 512];
+                volume.write(sector.offset(i), &buf).unwrap();
+                written.extend_from_slice(&buf);
+            }
+        }
+        written.truncate(len);
+        fs.commit_and_evict(&runs).unwrap();
+        assert_eq!(
+            fs.open("a").unwrap().reader().unwrap().read_to_end(),
+            Ok(written.clone())
+        );
+        drop(fs);
+        let fs = FileSystem::new(&volume).unwrap();
+        let a = fs.open("a").unwrap();
+        assert_eq!(a.cluster_runs(), Ok(runs));
+        assert_eq!(a.reader().unwrap().read_to_end(), Ok(written));
+
+        // The runs stay valid while the file is held open
+        a.hold_open();
+        let in_use = Err(Error::FileInUse(String::from("a")));
+        assert_eq!(fs.open("a").unwrap().remove(false), in_use);
+        assert_eq!(fs.open("a").unwrap().mv(None, Some("b")), in_use);
+    }
+
+    #[test_case]
+    fn test_boot_sector_backup() {
+        info!("TESTING fs::fat::test_boot_sector_backup");
+        let volume = format_volume(512, 1);
+        {
+            let fs = FileSystem::new(&volume).unwrap();
+            assert!(!fs.boot_sector_recovered());
+            assert_eq!(fs.repair_boot_sector(), Ok(false));
+            fs.root_dir().create_file("file").unwrap();
+            fs.commit().unwrap();
+        }
+        let mut original = [0; 512];
+        volume.read(Sector::from_index(0), &mut original).unwrap();
+
+        let zeroed = [0; 512];
+        let mut bad_signature = original;
+        bad_signature[510] = 0;
+        let mut bad_byts_per_sec = original;
+        bad_byts_per_sec.copy_from_array(11, 1000u16.to_le_bytes());
+        for broken in [zeroed, bad_signature, bad_byts_per_sec] {
+            volume.write(Sector::from_index(0), &broken).unwrap();
+            let fs = FileSystem::new(&volume).unwrap();
+            assert!(fs.boot_sector_recovered());
+            assert_eq!(
+                fs.boot_sector().backup_sector(),
+                Some(Sector::from_index(BK_BOOT_SEC))
+            );
+            assert!(fs.open("file").is_ok());
+
+            assert_eq!(fs.repair_boot_sector(), Ok(true));
+            assert!(!fs.boot_sector_recovered());
+            assert_eq!(fs.repair_boot_sector(), Ok(false));
+            let mut repaired = [0; 512];
+            volume.read(Sector::from_index(0), &mut repaired).unwrap();
+            assert!(repaired == original);
+            assert!(!FileSystem::new(&volume).unwrap().boot_sector_recovered());
+        }
+
+        // Neither the primary nor the backup can be used
+        volume.write(Sector::from_index(0), &[0; 512]).unwrap();
+        volume
+            .write(Sector::from_index(BK_BOOT_SEC), &[0; 512])
+            .unwrap();
+        assert_eq!(
+            FileSystem::new(&volume).err(),
+            Some(Error::BootSector(BootSectorError::SignatureMismatch))
+        );
+    }
+
+    #[test_case]
+    fn test_unmount() {
+        info!("TESTING fs::fat::test_unmount");
+        let volume = format_volume(512, 1);
+        let fs = FileSystem::mount_rw(&volume).unwrap();
+        fs.root_dir().create_file("a").unwrap();
+        fs.open("a")
+            .unwrap()
+            .overwriter()
+            .unwrap()
+            .write(&[1; 1000])
+            .unwrap();
+
+        // An open reader keeps the file system mounted and writable
+        let file = fs.open("a").unwrap();
+        let reader = file.reader().unwrap();
+        assert_eq!(fs.close(), Err(Error::FileInUse(String::from("a"))));
+        fs.root_dir().create_file("b").unwrap();
+        assert_eq!(reader.read_to_end().unwrap(), [1; 1000]);
+        drop(file);
+
+        // The changes are committed and the volume is marked clean
+        fs.root_dir().create_file("c").unwrap();
+        assert_eq!(fs.unmount().map_err(|(_, e)| e), Ok(()));
+        let fs = FileSystem::mount_rw(&volume).unwrap();
+        assert!(!fs.was_dirty());
+        assert_eq!(fs.root_dir().file_count(), Ok(3));
+        assert_eq!(
+            fs.open("a")
+                .unwrap()
+                .reader()
+                .unwrap()
+                .read_to_end()
+                .unwrap(),
+            [1; 1000]
+        );
+
+        // Closed file systems refuse every access, and never refill their sector cache
+        let a = fs.open("a").unwrap();
+        fs.close().unwrap();
+        let sectors_read = fs.cache_stats().sectors_read;
+        assert_eq!(a.reader().unwrap().read_to_end(), Err(Error::Unmounted));
+        assert_eq!(fs.open("b").map(|_| ()), Err(Error::Unmounted));
+        assert_eq!(fs.root_dir().create_file("d"), Err(Error::Unmounted));
+        assert_eq!(a.remove(false), Err(Error::Unmounted));
+        assert_eq!(fs.cache_stats().sectors_read, sectors_read);
+        assert_eq!(fs.close(), Ok(()));
+        drop(fs);
+        assert!(!FileSystem::new(&volume).unwrap().was_dirty());
+
+        // Read-only loads are released as they are
+        let fs = FileSystem::new(&volume).unwrap();
+        assert!(fs.unmount().is_ok());
+    }
+
+    #[test_case]
+    fn test_open_files() {
+        info!("TESTING fs::fat::test_open_files");
+        let fs = FileSystem::new(format_volume(512, 1)).unwrap();
+        let mut root = fs.root_dir();
+        root.create_file("a").unwrap();
+        root.create_dir("dir").unwrap();
+        fs.open("a")
+            .unwrap()
+            .overwriter()
+            .unwrap()
+            .write(&[1; 1000])
+            .unwrap();
+        assert_eq!(fs.open_files(), []);
+
+        // Another handle of the same file, as another task would have
+        let (file, file2) = (fs.open("a").unwrap(), fs.open("a").unwrap());
+        let reader = file.reader().unwrap();
+        let reader2 = file2.reader().unwrap();
+        let open_files = fs.open_files();
+        assert_eq!(open_files.len(), 1);
+        assert_eq!(
+            (open_files[0].name.as_str(), open_files[0].readers),
+            ("a", 2)
+        );
+        let in_use = Err(Error::FileInUse(String::from("a")));
+        assert_eq!(fs.open("a").unwrap().remove(false), in_use);
+        assert_eq!(fs.open("a").unwrap().mv(None, Some("b")), in_use);
+        assert_eq!(
+            fs.open("a").unwrap().mv(fs.open_dir("dir").ok(), None),
+            in_use
+        );
+        assert_eq!(
+            root.compact().err(),
+            Some(Error::FileInUse(String::from("a")))
+        );
+        drop(reader);
+        assert_eq!(fs.open("a").unwrap().remove(false), in_use);
+        assert_eq!(reader2.read_to_end().unwrap(), [1; 1000]);
+        assert_eq!(fs.open_files(), []);
+
+        fs.open("a").unwrap().mv(None, Some("b")).unwrap();
+        let mut file = fs.open("b").unwrap();
+        let writer = file.appender().unwrap();
+        assert_eq!(fs.open_files()[0].writers, 1);
+        assert_eq!(
+            fs.open("b").unwrap().remove(false),
+            Err(Error::FileInUse(String::from("b")))
+        );
+        // Moving to another directory is rejected as well
+        fs.open("b")
+            .unwrap()
+            .mv(fs.open_dir("dir").ok(), None)
+            .unwrap_err();
+        drop(writer);
+        fs.open("b")
+            .unwrap()
+            .mv(fs.open_dir("dir").ok(), None)
+            .unwrap();
+        let file = fs.open("dir/b").unwrap();
+        let reader = file.reader().unwrap();
+        assert_eq!(
+            fs.open("dir").unwrap().remove(true),
+            Err(Error::FileInUse(String::from("b")))
+        );
+        drop(reader);
+        fs.open("dir").unwrap().remove(true).unwrap();
+        assert_eq!(fs.open_files(), []);
+        assert_eq!(root.file_count(), Ok(0));
+    }
+
+    #[test_case]
+    fn test_volume_resize() {
+        info!("TESTING fs::fat::test_volume_resize");
+        let volume = format_volume(512, 1);
+        let total = volume.sector_count();
+        let fs = FileSystem::new(&volume).unwrap();
+        let mut root = fs.root_dir();
+        let write = |path, len| {
+            let mut file = fs.open_or_create(path)?;
+            let mut writer = file.overwriter().unwrap();
+            writer.write(&vec![1; len])
+        };
+        write("a", 1000).unwrap();
+        fs.commit().unwrap();
+
+        // Growing the volume does not affect the file system
+        volume.resize(total + 8);
+        assert!(fs.revalidate());
+        write("b", 512 * 11).unwrap(); // all but the last 2 clusters
+        fs.commit().unwrap();
+
+        // Shrinking the volume below the file system makes it read-only, which is noticed by the
+        // next write without waiting for an access beyond the end
+        let mut file = fs.open("a").unwrap();
+        let mut writer = file.appender().unwrap();
+        volume.resize(total - 4);
+        assert_eq!(writer.write(&[1; 10]), Err(Error::VolumeShrunk));
+        drop(writer);
+        assert!(fs
+            .open("b")
+            .unwrap()
+            .reader()
+            .unwrap()
+            .read_to_end()
+            .unwrap_err()
+            .is_resized());
+        assert!(!fs.revalidate());
+        assert_eq!(root.create_file("c"), Err(Error::VolumeShrunk));
+        assert_eq!(write("a", 10), Err(Error::VolumeShrunk));
+        assert_eq!(
+            fs.open("b").unwrap().remove(false),
+            Err(Error::VolumeShrunk)
+        );
+        fs.commit().unwrap();
+        let a = fs.open("a").unwrap();
+        assert_eq!(a.reader().unwrap().read_to_end().unwrap(), [1; 1000]);
+
+        // The file system stays read-only even if the volume grows back
+        volume.resize(total);
+        assert!(!fs.revalidate());
+        assert_eq!(root.create_file("c"), Err(Error::VolumeShrunk));
+        assert_eq!(
+            fs.open("b")
+                .unwrap()
+                .reader()
+                .unwrap()
+                .read_to_end()
+                .unwrap()
+                .len(),
+            512 * 11
+        );
     }
 }
@@ -0,0 +1,548 @@
+//! Audit log of file system mutations, compiled in by the `fat-audit` feature.
+//!
+//! The public mutation methods of `Dir` and `File` are the only hook points: each of them records
+//! exactly one `Record` with its result. Records are kept in a bounded ring in memory, and
+//! optionally appended to a file by the work queue. Building and storing a record never allocates
+//! and never blocks on I/O, so that auditing does not change the behavior of the audited
+//! operations. Without the feature, `begin` is a constant `None` and the hooks compile to nothing.
+
+use super::date::Date;
+use super::low_level::{Cluster, Root};
+use super::{Error, FileSystem};
+use crate::fs::mount;
+use crate::fs::volume::Volume;
+use crate::interrupts::{ticks, TIMER_FREQ};
+use crate::sync::spin::Spin;
+use crate::task::{self, workqueue, TaskId};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, Ordering};
+use log::warn;
+
+/// Whether the audit log is compiled in.
+pub const ENABLED: bool = cfg!(feature = "fat-audit");
+
+/// Number of records kept in memory. The oldest record is dropped on overflow.
+pub const CAPACITY: usize = 128;
+
+/// Names longer than this (in bytes) are truncated in records.
+pub const NAME_LEN: usize = 48;
+
+/// Delay of writing records to the sink, so that a burst of mutations is written at once.
+const FLUSH_DELAY: usize = TIMER_FREQ;
+
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+static RING: Spin<Ring> = Spin::new(Ring::new());
+static SINK: Spin<Option<Sink>> = Spin::new(None);
+static FLUSH_SCHEDULED: AtomicBool = AtomicBool::new(false);
+/// The task writing to the sink, whose mutations are not recorded, see `append_unaudited`.
+static SINK_WRITER: Spin<Option<TaskId>> = Spin::new(None);
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Operation {
+    CreateFile,
+    CreateDir,
+    Remove,
+    Rename,
+    /// The content of the file is replaced through `File::overwriter`. This is recorded when the
+    /// writer is dropped, where the clusters beyond the new content are released.
+    Truncate,
+    /// The file is extended to the size by `File::preallocate`.
+    Preallocate(usize),
+    SetReadOnly(bool),
+    SetHidden(bool),
+    SetSystem(bool),
+    ClearArchive,
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let flag = |set: bool| if set { '+' } else { '-' };
+        match self {
+            Self::CreateFile => write!(f, "create"),
+            Self::CreateDir => write!(f, "mkdir"),
+            Self::Remove => write!(f, "remove"),
+            Self::Rename => write!(f, "rename"),
+            Self::Truncate => write!(f, "truncate"),
+            Self::Preallocate(len) => write!(f, "preallocate {}", len),
+            Self::SetReadOnly(set) => write!(f, "attr {}r", flag(*set)),
+            Self::SetHidden(set) => write!(f, "attr {}h", flag(*set)),
+            Self::SetSystem(set) => write!(f, "attr {}s", flag(*set)),
+            Self::ClearArchive => write!(f, "attr -a"),
+        }
+    }
+}
+
+/// A file name truncated to `NAME_LEN` bytes.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Name {
+    name: heapless::String<NAME_LEN>,
+    truncated: bool,
+}
+
+impl Name {
+    fn new(s: &str) -> Self {
+        let mut name = heapless::String::new();
+        let mut truncated = false;
+        for ch in s.chars() {
+            if name.push(ch).is_err() {
+                truncated = true;
+                break;
+            }
+        }
+        Self { name, truncated }
+    }
+
+    pub fn as_str(&self) -> &str {
+        self.name.as_str()
+    }
+
+    pub fn is_truncated(&self) -> bool {
+        self.truncated
+    }
+}
+
+impl fmt::Display for Name {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name)?;
+        if self.truncated {
+            write!(f, "...")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Record {
+    /// Serial number of the record, in the order the operations completed.
+    pub seq: u64,
+    pub op: Operation,
+    /// The first cluster of the directory containing the file.
+    pub dir: usize,
+    pub name: Name,
+    /// The directory and the name after `Operation::Rename`.
+    pub dest: Option<(usize, Name)>,
+    pub task: Option<TaskId>,
+    /// Ticks and date when the operation started. The date is of the clock of the file system.
+    pub ticks: usize,
+    pub date: Option<Date>,
+    pub result: Result<(), &'static str>,
+}
+
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:>6} {:>10} ", self.seq, self.ticks)?;
+        match self.date {
+            Some(date) => write!(f, "{} ", date)?,
+            None => write!(f, "{:<10} ", "-")?,
+        }
+        match self.task {
+            Some(id) => write!(f, "{:>4} ", id.as_u64())?,
+            None => write!(f, "{:>4} ", "-")?,
+        }
+        write!(f, "{:<8} {}/{}", self.op, self.dir, self.name)?;
+        if let Some((dir, ref name)) = self.dest {
+            write!(f, " -> {}/{}", dir, name)?;
+        }
+        match self.result {
+            Ok(()) => write!(f, " ok"),
+            Err(e) => write!(f, " failed: {}", e),
+        }
+    }
+}
+
+/// A record of an operation in progress, see `begin`.
+#[derive(Debug)]
+pub(super) struct Pending(Record);
+
+/// Start recording `op` on `name` in `dir`. Returns `None` if auditing is inactive.
+#[inline]
+pub(super) fn begin<V: Volume>(
+    root: &Root<V>,
+    op: Operation,
+    dir: Cluster,
+    name: &str,
+) -> Option<Pending> {
+    if !is_active() || is_sink_writer() {
+        return None;
+    }
+    Some(Pending(Record {
+        seq: 0,
+        op,
+        dir: dir.index(),
+        name: Name::new(name),
+        dest: None,
+        task: task::current_id(),
+        ticks: ticks(),
+        date: root.today(),
+        result: Ok(()),
+    }))
+}
+
+impl Pending {
+    pub(super) fn with_dest(mut self, dir: Cluster, name: &str) -> Self {
+        self.0.dest = Some((dir.index(), Name::new(name)));
+        self
+    }
+}
+
+/// Finish recording the operation with its result.
+#[inline]
+pub(super) fn end<T>(pending: Option<Pending>, result: &Result<T, Error>) {
+    if let Some(Pending(mut record)) = pending {
+        record.result = result.as_ref().map(|_| ()).map_err(error_name);
+        push(record);
+    }
+}
+
+fn push(record: Record) {
+    RING.lock().push(record);
+    if SINK.lock().is_some() && !FLUSH_SCHEDULED.swap(true, Ordering::AcqRel) {
+        workqueue::schedule_delayed(flush, 0, FLUSH_DELAY);
+    }
+}
+
+/// A short description of the error without allocation.
+fn error_name(e: &Error) -> &'static str {
+    match e {
+        Error::Volume(_) => "volume error",
+        Error::BootSector(_) => "broken boot sector",
+        Error::Full => "full",
+        Error::CorruptedVolume(_) => "corrupted volume",
+        Error::DirectoryNotEmpty => "directory not empty",
+        Error::FileAlreadyExists => "file already exists",
+        Error::InvalidFileName => "invalid file name",
+        Error::InvalidCluster(_) => "invalid cluster",
+        Error::BrokenDirEntry => "broken directory entry",
+        Error::OutOfRange => "out of range",
+        Error::NotFound(_) => "not found",
+        Error::NotADirectory(_) => "not a directory",
+        Error::IsADirectory(_) => "is a directory",
+        Error::FileTooLarge => "file too large",
+        Error::FileInUse(_) => "file in use",
+        Error::VolumeShrunk => "volume shrunk",
+        Error::Unmounted => "unmounted",
+    }
+}
+
+fn is_sink_writer() -> bool {
+    let writer = *SINK_WRITER.lock();
+    writer.is_some() && writer == task::current_id()
+}
+
+pub fn is_active() -> bool {
+    ENABLED && ACTIVE.load(Ordering::Acquire)
+}
+
+/// Start or stop auditing. Returns false if the audit log is not compiled in.
+pub fn set_active(active: bool) -> bool {
+    ACTIVE.store(ENABLED && active, Ordering::Release);
+    ENABLED
+}
+
+/// The last `n` records, oldest first.
+pub fn records(n: usize) -> Vec<Record> {
+    let ring = RING.lock();
+    let skip = ring.records.len().saturating_sub(n);
+    ring.records.iter().skip(skip).cloned().collect()
+}
+
+/// The records after the `seq`-th one, oldest first.
+pub fn records_since(seq: u64) -> Vec<Record> {
+    let ring = RING.lock();
+    ring.records
+        .iter()
+        .filter(|r| seq < r.seq)
+        .cloned()
+        .collect()
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Default)]
+pub struct Stats {
+    /// Records made so far, which is also the `seq` of the last record.
+    pub recorded: u64,
+    /// Records dropped from the ring on overflow.
+    pub dropped: u64,
+    /// The file the records are appended to.
+    pub sink: Option<String>,
+    /// Records dropped from the ring before they were written to the sink.
+    pub sink_lost: u64,
+}
+
+pub fn stats() -> Stats {
+    let ring = RING.lock();
+    let (recorded, dropped) = (ring.next_seq - 1, ring.dropped);
+    drop(ring);
+    let sink = SINK.lock();
+    Stats {
+        recorded,
+        dropped,
+        sink: sink.as_ref().map(|s| s.path.clone()),
+        sink_lost: sink.as_ref().map_or(0, |s| s.lost),
+    }
+}
+
+/// Append the subsequent records to the file at the absolute `path`, or stop appending if
+/// `None`. The file is written by the work queue, and the sink is disabled when the file system
+/// becomes read-only or full.
+pub fn set_sink(path: Option<&str>) {
+    let flushed = RING.lock().next_seq - 1;
+    *SINK.lock() = path.map(|path| Sink {
+        path: String::from(path),
+        flushed,
+        lost: 0,
+    });
+}
+
+#[derive(Debug)]
+struct Ring {
+    records: heapless::Deque<Record, CAPACITY>,
+    next_seq: u64,
+    dropped: u64,
+}
+
+impl Ring {
+    const fn new() -> Self {
+        Self {
+            records: heapless::Deque::new(),
+            next_seq: 1,
+            dropped: 0,
+        }
+    }
+
+    fn push(&mut self, mut record: Record) {
+        record.seq = self.next_seq;
+        self.next_seq += 1;
+        if self.records.is_full() {
+            self.records.pop_front();
+            self.dropped += 1;
+        }
+        let _ = self.records.push_back(record);
+    }
+}
+
+#[derive(Debug)]
+struct Sink {
+    path: String,
+    /// The `seq` of the last record written.
+    flushed: u64,
+    lost: u64,
+}
+
+/// Write the records made since the last flush to the sink. Run by the work queue.
+fn flush(_: u64) {
+    FLUSH_SCHEDULED.store(false, Ordering::Release);
+    let (path, flushed) = match *SINK.lock() {
+        Some(ref sink) => (sink.path.clone(), sink.flushed),
+        None => return,
+    };
+    let records = records_since(flushed);
+    let last = match records.last() {
+        Some(r) => r.seq,
+        None => return,
+    };
+    let lost = records[0].seq - flushed - 1;
+
+    let (m, relative) = match mount::resolve(&path) {
+        Some((m, _)) if m.options.read_only => return disable_sink(&path, "read-only"),
+        Some(r) => r,
+        None => return disable_sink(&path, "not mounted"),
+    };
+    let mut text = String::new();
+    for r in records.iter() {
+        let _ = writeln!(text, "{}", r);
+    }
+    match append_unaudited(&m.fs, &relative, &text).and_then(|_| m.commit()) {
+        Ok(()) => {
+            if let Some(ref mut sink) = *SINK.lock() {
+                // The sink may be replaced in the meantime
+                if sink.path == path && sink.flushed == flushed {
+                    sink.flushed = last;
+                    sink.lost += lost;
+                }
+            }
+        }
+        Err(e) if disables_sink(&e) => disable_sink(&path, error_name(&e)),
+        // The records are retried on the next flush, as long as they are in the ring
+        Err(e) => warn!("audit: Failed to write {}: {}", path, e),
+    }
+}
+
+/// Append `text` to the file at `path`. The mutations made by this, such as the creation of the
+/// file, are not recorded, since each record would schedule another flush.
+fn append_unaudited<V: Volume>(fs: &FileSystem<V>, path: &str, text: &str) -> Result<(), Error> {
+    *SINK_WRITER.lock() = task::current_id();
+    let result = append(fs, path, text);
+    *SINK_WRITER.lock() = None;
+    result
+}
+
+fn append<V: Volume>(fs: &FileSystem<V>, path: &str, text: &str) -> Result<(), Error> {
+    let mut file = fs.open_or_create(path)?;
+    match file.appender() {
+        Some(mut writer) => writer.write(text.as_bytes())?,
+        None => Err(Error::IsADirectory(path.into()))?,
+    }
+    Ok(())
+}
+
+/// Whether the sink cannot be written anymore, as opposed to a transient failure.
+fn disables_sink(e: &Error) -> bool {
+    matches!(e, Error::Full | Error::VolumeShrunk | Error::Unmounted)
+}
+
+fn disable_sink(path: &str, reason: &str) {
+    let mut sink = SINK.lock();
+    if matches!(*sink, Some(ref s) if s.path == path) {
+        *sink = None;
+        drop(sink);
+        warn!("audit: Stopped writing to {} ({})", path, reason);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::fat::tests::format_volume;
+    use crate::fs::fat::FileSystem;
+    use alloc::vec;
+    use log::info;
+
+    fn last_seq() -> u64 {
+        RING.lock().next_seq - 1
+    }
+
+    #[test_case]
+    fn test_records() {
+        info!("TESTING fs::fat::audit::test_records");
+        let fs = FileSystem::mount_rw(format_volume(512, 1)).unwrap();
+        let root = fs.root_dir();
+        let root_cluster = root.cluster.index();
+        let since = last_seq();
+        if !ENABLED {
+            // Compiled out: the hooks never record anything
+            assert!(!set_active(true));
+            assert!(!is_active());
+            fs.root_dir().create_file("a").unwrap();
+            fs.open("a").unwrap().remove(false).unwrap();
+            assert!(records_since(since).is_empty());
+            return;
+        }
+
+        assert!(set_active(true));
+        let mut dir = fs.root_dir();
+        dir.create_file("a").unwrap();
+        dir.create_dir("d").unwrap();
+        assert_eq!(dir.create_file("a"), Err(Error::FileAlreadyExists));
+        let mut a = fs.open("a").unwrap();
+        a.set_is_hidden(true).unwrap();
+        a.clear_archive().unwrap();
+        a.overwriter().unwrap().write(b"hello").unwrap();
+        // Appending does not truncate
+        let mut b = fs.open("a").unwrap();
+        b.appender().unwrap().write(b"!").unwrap();
+        let d = fs.open_dir("d").unwrap();
+        let d_cluster = d.cluster.index();
+        fs.open("a").unwrap().mv(Some(d), Some("b")).unwrap();
+        let long = "x".repeat(NAME_LEN + 10);
+        fs.root_dir().create_file(&long).unwrap();
+        fs.open("d/b").unwrap().remove(false).unwrap();
+        set_active(false);
+        fs.root_dir().create_file("c").unwrap();
+
+        let records = records_since(since);
+        let summary = records
+            .iter()
+            .map(|r| (r.op, r.dir, r.name.as_str(), r.result))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            vec![
+                (Operation::CreateFile, root_cluster, "a", Ok(())),
+                (Operation::CreateDir, root_cluster, "d", Ok(())),
+                (
+                    Operation::CreateFile,
+                    root_cluster,
+                    "a",
+                    Err("file already exists")
+                ),
+                (Operation::SetHidden(true), root_cluster, "a", Ok(())),
+                (Operation::ClearArchive, root_cluster, "a", Ok(())),
+                (Operation::Truncate, root_cluster, "a", Ok(())),
+                (Operation::Rename, root_cluster, "a", Ok(())),
+                (
+                    Operation::CreateFile,
+                    root_cluster,
+                    &long[..NAME_LEN],
+                    Ok(())
+                ),
+                (Operation::Remove, d_cluster, "b", Ok(())),
+            ]
+        );
+        assert!(records.windows(2).all(|w| w[0].seq + 1 == w[1].seq));
+        assert!(records.iter().all(|r| r.task == task::current_id()));
+        assert!(records.iter().all(|r| r.date.is_none()));
+        assert_eq!(records[6].dest, Some((d_cluster, Name::new("b"))));
+        assert!(records
+            .iter()
+            .all(|r| r.dest.is_none() || r.op == Operation::Rename));
+        assert!(records[7].name.is_truncated() && !records[0].name.is_truncated());
+        assert_eq!(records_since(records[8].seq).len(), 0);
+    }
+
+    #[test_case]
+    fn test_ring() {
+        info!("TESTING fs::fat::audit::test_ring");
+        let record = |op| Record {
+            seq: 0,
+            op,
+            dir: 2,
+            name: Name::new("a"),
+            dest: None,
+            task: None,
+            ticks: 0,
+            date: None,
+            result: Ok(()),
+        };
+        let mut ring = Ring::new();
+        for _ in 0..CAPACITY + 3 {
+            ring.push(record(Operation::CreateFile));
+        }
+        assert_eq!(ring.records.len(), CAPACITY);
+        assert_eq!(ring.dropped, 3);
+        assert_eq!(ring.records.front().map(|r| r.seq), Some(4));
+        assert_eq!(
+            ring.records.back().map(|r| r.seq),
+            Some(CAPACITY as u64 + 3)
+        );
+    }
+
+    #[test_case]
+    fn test_sink_full() {
+        info!("TESTING fs::fat::audit::test_sink_full");
+        let fs = FileSystem::mount_rw(format_volume(512, 1)).unwrap();
+        append(&fs, "audit.log", "line\n").unwrap();
+        let data = fs
+            .open("audit.log")
+            .unwrap()
+            .reader()
+            .unwrap()
+            .read_to_end();
+        assert_eq!(data.unwrap(), b"line\n");
+
+        let e = append(&fs, "audit.log", &"x".repeat(64 * 512)).unwrap_err();
+        assert_eq!(e, Error::Full);
+        assert!(disables_sink(&e));
+        assert!(!disables_sink(&Error::NotFound(String::from("a"))));
+
+        // The creation of the sink file is not recorded
+        if set_active(true) {
+            let since = last_seq();
+            append_unaudited(&fs, "b.log", "line\n").unwrap();
+            assert!(records_since(since).is_empty());
+            assert!(fs.open("b.log").is_ok());
+            set_active(false);
+        }
+    }
+}
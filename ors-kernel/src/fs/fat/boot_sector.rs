@@ -1,10 +1,13 @@
 use super::{Cluster, Sector, SliceExt};
+use crate::fs::volume::ByteOffset;
 use core::fmt;
 
 /// Error while reading boot sector.
 #[derive(PartialEq, Eq, Debug, Clone, Copy, Hash)]
 pub enum Error {
     SignatureMismatch,
+    /// The buffer is shorter than a boot sector.
+    Truncated,
     Broken(&'static str),
     Unsupported(&'static str),
 }
@@ -13,6 +16,7 @@ impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Error::SignatureMismatch => write!(f, "Boot signature mismatch"),
+            Error::Truncated => write!(f, "Truncated boot sector"),
             Error::Broken(s) => write!(f, "Broken boot sector: {}", s),
             Error::Unsupported(s) => write!(f, "Unsupported feature: {}", s),
         }
@@ -62,7 +66,7 @@ pub struct BootSector {
     /// Sector number of the FSINFO. It must be 1.
     _bpb_fs_info: u16,
     /// Sector number where the boot sector backup is placed. 6 is recommended
-    _bpb_bk_boot_sec: u16,
+    bpb_bk_boot_sec: u16,
     _bpb_reserved: [u8; 12],
     // ------
     /// Drive Number. ignored
@@ -140,6 +144,7 @@ impl BootSector {
 
     /// Data area size in sectors.
     pub fn data_area_size(&self) -> usize {
+        // The geometry is validated on deserialization, thus this never underflows
         self.total_sector_count() - self.data_area_start().index()
     }
 
@@ -148,6 +153,11 @@ impl BootSector {
         self.bpb_sec_per_clus as usize
     }
 
+    /// Cluster size in bytes.
+    pub fn cluster_bytes(&self) -> usize {
+        self.cluster_size() * self.sector_size()
+    }
+
     /// Number of available clusters.
     pub fn cluster_count(&self) -> usize {
         self.data_area_size() / self.cluster_size()
@@ -167,12 +177,15 @@ impl BootSector {
     /// It should also be noted that in FAT32, the upper 4 bits of the FAT entry are reserved.
     pub(super) fn fat_entry_location(&self, n: Cluster) -> (Sector, usize) {
         debug_assert!(self.is_cluster_available(n));
-        let bytes_offset = n.index() * 4; // 32-bit -> 4bytes
-        let sector = self
-            .fat_area_start()
-            .offset(bytes_offset / self.sector_size());
-        let offset = bytes_offset % self.sector_size();
-        (sector, offset)
+        let bytes_offset = ByteOffset::new(n.index() * 4); // 32-bit -> 4bytes
+        let (sector, offset) = Sector::containing(bytes_offset, self.sector_size());
+        (self.fat_area_start().offset(sector.index()), offset)
+    }
+
+    /// Get the location of the reserved FAT entry FAT[0] or FAT[1].
+    pub(super) fn reserved_entry_location(&self, index: usize) -> (Sector, usize) {
+        debug_assert!(index < 2);
+        (self.fat_area_start(), index * 4)
     }
 
     /// Get the location of the data corresponding to the given cluster number.
@@ -185,30 +198,57 @@ impl BootSector {
     pub(super) fn root_dir_cluster(&self) -> Cluster {
         Cluster::from_index(self.bpb_root_clus as usize)
     }
+
+    /// Sector where the backup of this boot sector is placed, if any.
+    pub fn backup_sector(&self) -> Option<Sector> {
+        match self.bpb_bk_boot_sec {
+            0 => None,
+            n if n < self.bpb_rsvd_sec_cnt => Some(Sector::from_index(n as usize)),
+            _ => None,
+        }
+    }
+}
+
+/// Sector where the backup of the boot sector is conventionally placed.
+pub const DEFAULT_BACKUP_SECTOR: usize = 6;
+
+/// Sectors that may hold the backup of a boot sector that cannot be deserialized.
+/// BkBootSec of the broken boot sector is tried first if it looks sane, and then the conventional one.
+pub(super) fn backup_sector_candidates(buf: &[u8]) -> impl Iterator<Item = Sector> {
+    let hint = match buf.try_array::<2>(50).map(u16::from_le_bytes) {
+        Some(n) if n != 0 && n != 0xffff && n as usize != DEFAULT_BACKUP_SECTOR => Some(n as usize),
+        _ => None,
+    };
+    hint.into_iter()
+        .chain(Some(DEFAULT_BACKUP_SECTOR))
+        .map(Sector::from_index)
 }
 
 impl TryFrom<&'_ [u8]> for BootSector {
     type Error = Error;
 
     fn try_from(buf: &'_ [u8]) -> Result<Self, Self::Error> {
-        if buf.len() < 512 || !matches!(buf[510..512], [0x55, 0xaa]) {
+        if buf.len() < 512 {
+            Err(Error::Truncated)?;
+        }
+        if field::<2>(buf, 510)? != [0x55, 0xaa] {
             Err(Error::SignatureMismatch)?;
         }
 
-        let _jmp_boot = buf.array::<3>(0);
-        let _oem_name = buf.array::<8>(3);
-        let bpb_byts_per_sec = u16::from_le_bytes(buf.array::<2>(11));
+        let _jmp_boot = field::<3>(buf, 0)?;
+        let _oem_name = field::<8>(buf, 3)?;
+        let bpb_byts_per_sec = u16::from_le_bytes(field::<2>(buf, 11)?);
         let bpb_sec_per_clus = buf[13];
-        let bpb_rsvd_sec_cnt = u16::from_le_bytes(buf.array::<2>(14));
+        let bpb_rsvd_sec_cnt = u16::from_le_bytes(field::<2>(buf, 14)?);
         let bpb_num_fats = buf[16];
-        let _bpb_root_ent_cnt = u16::from_le_bytes(buf.array::<2>(17));
-        let _bpb_tot_sec_16 = u16::from_le_bytes(buf.array::<2>(19));
+        let _bpb_root_ent_cnt = u16::from_le_bytes(field::<2>(buf, 17)?);
+        let _bpb_tot_sec_16 = u16::from_le_bytes(field::<2>(buf, 19)?);
         let _bpb_media = buf[21];
-        let _bpb_fat_sz_16 = u16::from_le_bytes(buf.array::<2>(22));
-        let _bpb_sec_per_trk = u16::from_le_bytes(buf.array::<2>(24));
-        let _bpb_num_heads = u16::from_le_bytes(buf.array::<2>(26));
-        let _bpb_hidd_sec = u32::from_le_bytes(buf.array::<4>(28));
-        let bpb_tot_sec_32 = u32::from_le_bytes(buf.array::<4>(32));
+        let _bpb_fat_sz_16 = u16::from_le_bytes(field::<2>(buf, 22)?);
+        let _bpb_sec_per_trk = u16::from_le_bytes(field::<2>(buf, 24)?);
+        let _bpb_num_heads = u16::from_le_bytes(field::<2>(buf, 26)?);
+        let _bpb_hidd_sec = u32::from_le_bytes(field::<4>(buf, 28)?);
+        let bpb_tot_sec_32 = u32::from_le_bytes(field::<4>(buf, 32)?);
 
         if !matches!(_jmp_boot, [0xeb, _, 0x90] | [0xe9, _, _]) {
             Err(Error::Broken("JmpBoot"))?;
@@ -226,19 +266,19 @@ impl TryFrom<&'_ [u8]> for BootSector {
             Err(Error::Unsupported("FAT12/16"))?;
         }
 
-        let bpb_fat_sz_32 = u32::from_le_bytes(buf.array::<4>(36));
-        let _bpb_ext_flags = u16::from_le_bytes(buf.array::<2>(40));
-        let _bpb_fs_ver = u16::from_le_bytes(buf.array::<2>(42));
-        let bpb_root_clus = u32::from_le_bytes(buf.array::<4>(44));
-        let _bpb_fs_info = u16::from_le_bytes(buf.array::<2>(48));
-        let _bpb_bk_boot_sec = u16::from_le_bytes(buf.array::<2>(50));
-        let _bpb_reserved = buf.array::<12>(52);
+        let bpb_fat_sz_32 = u32::from_le_bytes(field::<4>(buf, 36)?);
+        let _bpb_ext_flags = u16::from_le_bytes(field::<2>(buf, 40)?);
+        let _bpb_fs_ver = u16::from_le_bytes(field::<2>(buf, 42)?);
+        let bpb_root_clus = u32::from_le_bytes(field::<4>(buf, 44)?);
+        let _bpb_fs_info = u16::from_le_bytes(field::<2>(buf, 48)?);
+        let bpb_bk_boot_sec = u16::from_le_bytes(field::<2>(buf, 50)?);
+        let _bpb_reserved = field::<12>(buf, 52)?;
         let _drv_num = buf[64];
         let _reserved = buf[65];
         let _boot_sig = buf[66];
-        let vol_id = u32::from_le_bytes(buf.array::<4>(67));
-        let vol_lab = buf.array::<11>(71);
-        let _fil_sys_type = buf.array::<8>(82);
+        let vol_id = u32::from_le_bytes(field::<4>(buf, 67)?);
+        let vol_lab = field::<11>(buf, 71)?;
+        let _fil_sys_type = field::<8>(buf, 82)?;
 
         if _bpb_fs_ver != 0x0000 {
             Err(Error::Unsupported("FSVer"))?;
@@ -250,7 +290,24 @@ impl TryFrom<&'_ [u8]> for BootSector {
             Err(Error::Broken("BootSig"))?;
         }
 
-        Ok(Self {
+        // Reserved area | FAT area | Data area must fit in the volume
+        let data_area_start = (bpb_fat_sz_32 as usize)
+            .checked_mul(bpb_num_fats as usize)
+            .and_then(|fat_area_size| fat_area_size.checked_add(bpb_rsvd_sec_cnt as usize));
+        let data_area_size = match data_area_start {
+            Some(n) if n <= bpb_tot_sec_32 as usize => bpb_tot_sec_32 as usize - n,
+            _ => Err(Error::Broken("TotSec"))?,
+        };
+        // Cluster numbers must be representable in 28-bit FAT entries (0x0ffffff7 means a bad cluster)
+        let cluster_count = data_area_size / bpb_sec_per_clus as usize;
+        if cluster_count > 0x0ffffff6 - 1 {
+            Err(Error::Unsupported("Too many clusters"))?;
+        }
+        if !(2..=cluster_count + 1).contains(&(bpb_root_clus as usize)) {
+            Err(Error::Broken("RootClus"))?;
+        }
+
+        let bs = Self {
             _jmp_boot,
             _oem_name,
             bpb_byts_per_sec,
@@ -270,7 +327,7 @@ impl TryFrom<&'_ [u8]> for BootSector {
             _bpb_fs_ver,
             bpb_root_clus,
             _bpb_fs_info,
-            _bpb_bk_boot_sec,
+            bpb_bk_boot_sec,
             _bpb_reserved,
             _drv_num,
             _reserved,
@@ -278,6 +335,145 @@ impl TryFrom<&'_ [u8]> for BootSector {
             vol_id,
             vol_lab,
             _fil_sys_type,
-        })
+        };
+        // Byte offsets in the data area are computed without overflow checks from here on
+        let last = Cluster::from_index(cluster_count + 1);
+        if 0 < cluster_count && last.byte_range(&bs).is_err() {
+            Err(Error::Unsupported("Volume too large"))?;
+        }
+        Ok(bs)
+    }
+}
+
+/// The `N` bytes at `offset` of the boot sector.
+fn field<const N: usize>(buf: &[u8], offset: usize) -> Result<[u8; N], Error> {
+    buf.try_array(offset).ok_or(Error::Truncated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::low_level::ClusterCount;
+    use super::*;
+    use crate::fs::volume::Overflow;
+    use log::info;
+
+    fn boot_sector(sector_size: u16, sec_per_clus: u8) -> [u8; 512] {
+        let mut buf = [0; 512];
+        buf.copy_from_array(0, [0xeb, 0x58, 0x90]);
+        buf.copy_from_array(11, sector_size.to_le_bytes());
+        buf[13] = sec_per_clus;
+        buf.copy_from_array(14, 32u16.to_le_bytes()); // RsvdSecCnt
+        buf[16] = 2; // NumFATs
+        buf.copy_from_array(32, 0x10000u32.to_le_bytes()); // TotSec32
+        buf.copy_from_array(36, 0x100u32.to_le_bytes()); // FATSz32
+        buf.copy_from_array(44, 2u32.to_le_bytes()); // RootClus
+        buf.copy_from_array(48, 1u16.to_le_bytes()); // FSInfo
+        buf[66] = 0x29;
+        buf.copy_from_array(510, [0x55, 0xaa]);
+        buf
+    }
+
+    #[test_case]
+    fn test_geometry() {
+        info!("TESTING fs::fat::boot_sector::test_geometry");
+        for sector_size in [512, 1024, 2048, 4096] {
+            for sec_per_clus in [1, 2, 8, 64] {
+                let bs = BootSector::try_from(&boot_sector(sector_size, sec_per_clus)[..]).unwrap();
+                let sector_size = sector_size as usize;
+                let sec_per_clus = sec_per_clus as usize;
+                assert_eq!(bs.cluster_bytes(), sector_size * sec_per_clus);
+                for n in (2..bs.cluster_count() + 2).step_by(61) {
+                    let c = Cluster::from_index(n);
+                    let (sector, offset) = bs.fat_entry_location(c);
+                    assert_eq!(sector.index(), 32 + n * 4 / sector_size);
+                    assert_eq!(offset, n * 4 % sector_size);
+                    assert_eq!(
+                        bs.cluster_location(c).index(),
+                        32 + 0x100 * 2 + (n - 2) * sec_per_clus
+                    );
+                    let start = (32 + 0x100 * 2 + (n - 2) * sec_per_clus) * sector_size;
+                    let range = c.byte_range(&bs).unwrap();
+                    assert_eq!(range.start.get(), start);
+                    assert_eq!(range.end.get(), start + sector_size * sec_per_clus);
+                }
+            }
+        }
+
+        let mut broken = boot_sector(512, 1);
+        broken.copy_from_array(32, 0x100u32.to_le_bytes()); // TotSec32 < FAT area
+        assert_eq!(
+            BootSector::try_from(&broken[..]),
+            Err(Error::Broken("TotSec"))
+        );
+    }
+
+    #[test_case]
+    fn test_cluster_count_for_bytes() {
+        info!("TESTING fs::fat::boot_sector::test_cluster_count_for_bytes");
+        // Compare with the inline arithmetic replaced by `ClusterCount`
+        for sector_size in (512..=4096).step_by(512) {
+            for sec_per_clus in [1, 2, 8, 64, 128] {
+                let cluster_bytes = sector_size * sec_per_clus;
+                let near_max = usize::MAX - 2 * cluster_bytes..=usize::MAX;
+                for bytes in (0..cluster_bytes * 4).step_by(211).chain(near_max) {
+                    match bytes.checked_add(cluster_bytes - 1) {
+                        Some(b) => assert_eq!(
+                            ClusterCount::for_bytes(bytes, cluster_bytes).map(|c| c.get()),
+                            Ok(b / cluster_bytes)
+                        ),
+                        None => {
+                            assert_eq!(ClusterCount::for_bytes(bytes, cluster_bytes), Err(Overflow))
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test_case]
+    fn test_untrusted_buffer() {
+        info!("TESTING fs::fat::boot_sector::test_untrusted_buffer");
+        let buf = boot_sector(512, 1);
+        for len in [0, 1, 66, 511] {
+            assert_eq!(BootSector::try_from(&buf[..len]), Err(Error::Truncated));
+        }
+        let mut oversized = [0; 1024];
+        oversized[..512].copy_from_slice(&buf);
+        assert!(BootSector::try_from(&oversized[..]).is_ok());
+
+        for root_clus in [0u32, 1, 0x10000, u32::MAX] {
+            let mut broken = boot_sector(512, 1);
+            broken.copy_from_array(44, root_clus.to_le_bytes());
+            assert_eq!(
+                BootSector::try_from(&broken[..]),
+                Err(Error::Broken("RootClus"))
+            );
+        }
+    }
+
+    #[test_case]
+    fn test_backup_sector() {
+        info!("TESTING fs::fat::boot_sector::test_backup_sector");
+        let mut buf = boot_sector(512, 1);
+        let bs = BootSector::try_from(&buf[..]).unwrap();
+        assert_eq!(bs.backup_sector(), None);
+        for (bk_boot_sec, expected) in [(6u16, Some(6)), (31, Some(31)), (32, None)] {
+            buf.copy_from_array(50, bk_boot_sec.to_le_bytes());
+            let bs = BootSector::try_from(&buf[..]).unwrap();
+            assert_eq!(bs.backup_sector(), expected.map(Sector::from_index));
+        }
+
+        let candidates = |buf: &[u8]| {
+            backup_sector_candidates(buf)
+                .map(|s| s.index())
+                .collect::<alloc::vec::Vec<_>>()
+        };
+        buf.copy_from_array(50, 12u16.to_le_bytes());
+        assert_eq!(candidates(&buf), [12, 6]);
+        buf.copy_from_array(50, 6u16.to_le_bytes());
+        assert_eq!(candidates(&buf), [6]);
+        assert_eq!(candidates(&[0; 512]), [6]);
+        assert_eq!(candidates(&[0xff; 512]), [6]);
+        assert_eq!(candidates(&[]), [6]);
     }
 }
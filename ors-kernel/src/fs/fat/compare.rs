@@ -0,0 +1,457 @@
+//! Comparison of file contents without loading the entire files into memory.
+
+use super::{Error, File, FileReader};
+use crate::fs::volume::Volume;
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use core::ops::Range;
+
+/// Number of lines of each file kept to find the next common line in `compare_lines`.
+pub const LINE_WINDOW: usize = 128;
+/// Hunks more than this are counted but not kept by `compare_lines`.
+pub const MAX_HUNKS: usize = 32;
+/// Lines are shown up to this number of bytes.
+const MAX_SHOWN_LINE_BYTES: usize = 80;
+
+fn reader_of<'a, V: Volume>(file: &'a File<'a, V>) -> Result<FileReader<'a, V>, Error> {
+    file.reader()
+        .ok_or_else(|| Error::IsADirectory(file.name.clone()))
+}
+
+/// Read two files in lockstep, chunk by chunk. `f` is called with the offset of the chunks and
+/// the chunks themselves, which have the same length except at the end of the shorter file.
+/// The walk stops when both files are read to the end, or `f` returns false.
+pub fn walk_chunks<V: Volume, W: Volume>(
+    a: &File<'_, V>,
+    b: &File<'_, W>,
+    mut f: impl FnMut(usize, &[u8], &[u8]) -> bool,
+) -> Result<(), Error> {
+    let mut ra = reader_of(a)?;
+    let mut rb = reader_of(b)?;
+    let chunk_size = a
+        .root
+        .boot_sector()
+        .cluster_bytes()
+        .max(b.root.boot_sector().cluster_bytes());
+    let mut ba = vec![0; chunk_size];
+    let mut bb = vec![0; chunk_size];
+    let mut offset = 0;
+    loop {
+        let la = ra.read(&mut ba)?;
+        let lb = rb.read(&mut bb)?;
+        if (la == 0 && lb == 0) || !f(offset, &ba[0..la], &bb[0..lb]) {
+            return Ok(());
+        }
+        offset += la.max(lb);
+    }
+}
+
+/// Result of `compare_bytes`.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct ByteComparison {
+    pub len_a: usize,
+    pub len_b: usize,
+    pub first_difference: Option<usize>,
+    /// Differing bytes in the common length, plus the difference of the lengths.
+    pub differing_bytes: usize,
+}
+
+impl ByteComparison {
+    pub fn is_identical(&self) -> bool {
+        self.first_difference.is_none()
+    }
+}
+
+pub fn compare_bytes<V: Volume, W: Volume>(
+    a: &File<'_, V>,
+    b: &File<'_, W>,
+) -> Result<ByteComparison, Error> {
+    let (len_a, len_b) = (a.file_size(), b.file_size());
+    let mut first_difference = None;
+    let mut differing_bytes = len_a.abs_diff(len_b);
+    walk_chunks(a, b, |offset, ca, cb| {
+        for (i, (x, y)) in ca.iter().zip(cb).enumerate() {
+            if x != y {
+                first_difference.get_or_insert(offset + i);
+                differing_bytes += 1;
+            }
+        }
+        true
+    })?;
+    if first_difference.is_none() && len_a != len_b {
+        first_difference = Some(len_a.min(len_b));
+    }
+    Ok(ByteComparison {
+        len_a,
+        len_b,
+        first_difference,
+        differing_bytes,
+    })
+}
+
+/// A run of differing lines, `a` of the first file replaced by `b` of the second file.
+/// Lines are indexed from 0.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Hunk {
+    pub a: Range<usize>,
+    pub b: Range<usize>,
+}
+
+impl fmt::Display for Hunk {
+    /// In the same form as the normal format of diff(1), such as `3,5c3,4`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        struct Lines<'a>(&'a Range<usize>);
+
+        impl<'a> fmt::Display for Lines<'a> {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                match self.0.len() {
+                    0 => write!(f, "{}", self.0.start),
+                    1 => write!(f, "{}", self.0.end),
+                    _ => write!(f, "{},{}", self.0.start + 1, self.0.end),
+                }
+            }
+        }
+
+        let op = match (self.a.is_empty(), self.b.is_empty()) {
+            (true, _) => 'a',
+            (_, true) => 'd',
+            _ => 'c',
+        };
+        write!(f, "{}{}{}", Lines(&self.a), op, Lines(&self.b))
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum LineChange {
+    Removed(usize, String),
+    Added(usize, String),
+}
+
+impl fmt::Display for LineChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Removed(n, text) => write!(f, "{:>5} < {}", n + 1, text),
+            Self::Added(n, text) => write!(f, "{:>5} > {}", n + 1, text),
+        }
+    }
+}
+
+/// Result of `compare_lines`.
+#[derive(PartialEq, Eq, Debug, Clone, Default)]
+pub struct LineComparison {
+    pub hunks: Vec<Hunk>,
+    /// Number of hunks beyond `MAX_HUNKS`.
+    pub omitted_hunks: usize,
+    /// The first differing lines, truncated to a fixed length.
+    pub shown_lines: Vec<LineChange>,
+    pub removed_lines: usize,
+    pub added_lines: usize,
+    /// Whether a differing region was longer than `LINE_WINDOW`. In this case the files may
+    /// have common lines that are reported as differences.
+    pub window_exceeded: bool,
+}
+
+impl LineComparison {
+    pub fn is_identical(&self) -> bool {
+        self.removed_lines == 0 && self.added_lines == 0
+    }
+}
+
+/// Compare two files line by line. Only `LINE_WINDOW` lines of each file are kept at once,
+/// and the lines are compared by hashes.
+pub fn compare_lines<V: Volume, W: Volume>(
+    a: &File<'_, V>,
+    b: &File<'_, W>,
+    max_shown_lines: usize,
+) -> Result<LineComparison, Error> {
+    let mut la = LineReader::new(reader_of(a)?);
+    let mut lb = LineReader::new(reader_of(b)?);
+    let mut wa = VecDeque::new();
+    let mut wb = VecDeque::new();
+    let mut ret = LineComparison::default();
+
+    loop {
+        la.fill(&mut wa)?;
+        lb.fill(&mut wb)?;
+        let (i, j) = match (wa.front(), wb.front()) {
+            (None, None) => break,
+            (Some(x), Some(y)) if x.hash == y.hash => {
+                wa.pop_front();
+                wb.pop_front();
+                continue;
+            }
+            _ => match first_common_line(&wa, &wb) {
+                Some(ij) => ij,
+                None => {
+                    ret.window_exceeded |= wa.len() == LINE_WINDOW || wb.len() == LINE_WINDOW;
+                    (wa.len(), wb.len())
+                }
+            },
+        };
+
+        let next_a = wa.front().map_or(la.count, |l| l.number);
+        let next_b = wb.front().map_or(lb.count, |l| l.number);
+        let hunk = Hunk {
+            a: next_a..next_a + i,
+            b: next_b..next_b + j,
+        };
+        if ret.hunks.len() < MAX_HUNKS {
+            ret.hunks.push(hunk);
+        } else {
+            ret.omitted_hunks += 1;
+        }
+        ret.removed_lines += i;
+        ret.added_lines += j;
+        for l in wa.drain(0..i) {
+            if ret.shown_lines.len() < max_shown_lines {
+                ret.shown_lines.push(LineChange::Removed(l.number, l.text));
+            }
+        }
+        for l in wb.drain(0..j) {
+            if ret.shown_lines.len() < max_shown_lines {
+                ret.shown_lines.push(LineChange::Added(l.number, l.text));
+            }
+        }
+    }
+    Ok(ret)
+}
+
+/// Find the first pair of common lines in a longest common subsequence of `a` and `b`.
+fn first_common_line(a: &VecDeque<Line>, b: &VecDeque<Line>) -> Option<(usize, usize)> {
+    let (n, m) = (a.len(), b.len());
+    // lcs[i * (m + 1) + j] is the length of the LCS of a[i..] and b[j..]
+    let mut lcs = vec![0u16; (n + 1) * (m + 1)];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            lcs[i * (m + 1) + j] = if a[i].hash == b[j].hash {
+                lcs[(i + 1) * (m + 1) + j + 1] + 1
+            } else {
+                lcs[(i + 1) * (m + 1) + j].max(lcs[i * (m + 1) + j + 1])
+            };
+        }
+    }
+    let (mut i, mut j) = (0, 0);
+    while i < n && j < m {
+        if a[i].hash == b[j].hash {
+            return Some((i, j));
+        } else if lcs[(i + 1) * (m + 1) + j] >= lcs[i * (m + 1) + j + 1] {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    None
+}
+
+#[derive(Debug)]
+struct Line {
+    number: usize,
+    hash: u64,
+    text: String,
+}
+
+/// Split the contents of a file into lines, keeping only the hash and the beginning of each line.
+#[derive(Debug)]
+struct LineReader<'a, V: Volume> {
+    reader: FileReader<'a, V>,
+    buf: Vec<u8>,
+    pos: usize,
+    len: usize,
+    count: usize,
+}
+
+impl<'a, V: Volume> LineReader<'a, V> {
+    fn new(reader: FileReader<'a, V>) -> Self {
+        let buf = vec![0; reader.root.boot_sector().cluster_bytes()];
+        Self {
+            reader,
+            buf,
+            pos: 0,
+            len: 0,
+            count: 0,
+        }
+    }
+
+    fn fill(&mut self, window: &mut VecDeque<Line>) -> Result<(), Error> {
+        while window.len() < LINE_WINDOW {
+            match self.next()? {
+                Some(line) => window.push_back(line),
+                None => break,
+            }
+        }
+        Ok(())
+    }
+
+    fn next(&mut self) -> Result<Option<Line>, Error> {
+        // FNV-1a
+        let mut hash = 0xcbf29ce484222325u64;
+        let mut shown = Vec::new();
+        let mut empty = true;
+        loop {
+            if self.pos == self.len {
+                self.pos = 0;
+                self.len = self.reader.read(&mut self.buf)?;
+                if self.len == 0 {
+                    break;
+                }
+            }
+            let byte = self.buf[self.pos];
+            self.pos += 1;
+            empty = false;
+            if byte == b'\n' {
+                break;
+            }
+            hash = (hash ^ byte as u64).wrapping_mul(0x100000001b3);
+            if shown.len() < MAX_SHOWN_LINE_BYTES {
+                shown.push(byte);
+            }
+        }
+        if empty {
+            return Ok(None);
+        }
+        let number = self.count;
+        self.count += 1;
+        Ok(Some(Line {
+            number,
+            hash,
+            text: String::from_utf8_lossy(&shown).into_owned(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::fat::tests::format_volume;
+    use crate::fs::fat::FileSystem;
+    use crate::fs::volume::mem::MemVolume;
+    use alloc::format;
+    use log::info;
+
+    fn put(fs: &FileSystem<MemVolume>, name: &str, data: &[u8]) {
+        let mut root = fs.root_dir();
+        root.create_file(name).unwrap();
+        let mut file = root.find(name).unwrap();
+        file.overwriter().unwrap().write(data).unwrap();
+    }
+
+    fn bytes(fs: &FileSystem<MemVolume>, a: &str, b: &str) -> ByteComparison {
+        let root = fs.root_dir();
+        compare_bytes(&root.find(a).unwrap(), &root.find(b).unwrap()).unwrap()
+    }
+
+    fn lines(fs: &FileSystem<MemVolume>, a: &str, b: &str) -> LineComparison {
+        let root = fs.root_dir();
+        compare_lines(&root.find(a).unwrap(), &root.find(b).unwrap(), 4).unwrap()
+    }
+
+    #[test_case]
+    fn test_compare_bytes() {
+        info!("TESTING fs::fat::compare::test_compare_bytes");
+        let fs = FileSystem::new(format_volume(512, 1)).unwrap();
+        let cluster_bytes = fs.boot_sector().cluster_bytes();
+        let data = (0..cluster_bytes * 2 + 100)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+        put(&fs, "a", &data);
+        put(&fs, "same", &data);
+        put(&fs, "empty", &[]);
+        put(&fs, "empty2", &[]);
+        let mut changed = data.clone();
+        changed[cluster_bytes - 1] ^= 1;
+        changed[cluster_bytes] ^= 1;
+        put(&fs, "changed", &changed);
+        put(&fs, "short", &data[0..cluster_bytes]);
+
+        let c = bytes(&fs, "a", "same");
+        assert!(c.is_identical());
+        assert_eq!(c.differing_bytes, 0);
+        assert!(bytes(&fs, "empty", "empty2").is_identical());
+
+        let c = bytes(&fs, "a", "changed");
+        assert_eq!(c.first_difference, Some(cluster_bytes - 1));
+        assert_eq!(c.differing_bytes, 2);
+
+        let c = bytes(&fs, "a", "short");
+        assert_eq!((c.len_a, c.len_b), (data.len(), cluster_bytes));
+        assert_eq!(c.first_difference, Some(cluster_bytes));
+        assert_eq!(c.differing_bytes, cluster_bytes + 100);
+        assert_eq!(
+            bytes(&fs, "short", "a").first_difference,
+            Some(cluster_bytes)
+        );
+        assert_eq!(bytes(&fs, "empty", "a").differing_bytes, data.len());
+
+        let mut root = fs.root_dir();
+        root.create_dir("dir").unwrap();
+        let dir = root.find("dir").unwrap();
+        assert_eq!(
+            compare_bytes(&root.find("a").unwrap(), &dir),
+            Err(Error::IsADirectory("dir".into()))
+        );
+    }
+
+    #[test_case]
+    fn test_compare_lines() {
+        info!("TESTING fs::fat::compare::test_compare_lines");
+        let fs = FileSystem::new(format_volume(512, 1)).unwrap();
+        let text = (0..100)
+            .map(|i| format!("line {}\n", i))
+            .collect::<String>();
+        put(&fs, "a", text.as_bytes());
+        put(&fs, "same", text.as_bytes());
+        let c = lines(&fs, "a", "same");
+        assert!(c.is_identical());
+        assert_eq!(c.hunks, vec![]);
+
+        // A line is changed, a line is removed, and lines are appended
+        let b = text
+            .replace("line 10\n", "line ten\n")
+            .replace("line 50\n", "")
+            + "extra 1\nextra 2";
+        put(&fs, "b", b.as_bytes());
+        let c = lines(&fs, "a", "b");
+        assert_eq!(
+            c.hunks,
+            vec![
+                Hunk {
+                    a: 10..11,
+                    b: 10..11
+                },
+                Hunk {
+                    a: 50..51,
+                    b: 50..50
+                },
+                Hunk {
+                    a: 100..100,
+                    b: 99..101
+                },
+            ]
+        );
+        let hunks = c.hunks.iter().map(|h| format!("{}", h)).collect::<Vec<_>>();
+        assert_eq!(hunks, vec!["11c11", "51d50", "100a100,101"]);
+        assert_eq!((c.removed_lines, c.added_lines), (2, 3));
+        assert_eq!(
+            c.shown_lines,
+            vec![
+                LineChange::Removed(10, "line 10".into()),
+                LineChange::Added(10, "line ten".into()),
+                LineChange::Removed(50, "line 50".into()),
+                LineChange::Added(99, "extra 1".into()),
+            ]
+        );
+        assert!(!c.window_exceeded);
+
+        // No common lines in the window
+        let other = (0..200)
+            .map(|i| format!("other {}\n", i))
+            .collect::<String>();
+        put(&fs, "other", other.as_bytes());
+        let c = lines(&fs, "a", "other");
+        assert_eq!((c.removed_lines, c.added_lines), (100, 200));
+        assert!(c.window_exceeded);
+    }
+}
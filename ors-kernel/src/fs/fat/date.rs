@@ -0,0 +1,143 @@
+use core::fmt;
+
+/// A date in the FAT format, 1980-01-01 to 2107-12-31.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy, Hash)]
+pub struct Date(u16);
+
+impl Date {
+    pub fn new(year: u16, month: u8, day: u8) -> Option<Self> {
+        if !(1980..=2107).contains(&year) || !(1..=12).contains(&month) || !(1..=31).contains(&day)
+        {
+            return None;
+        }
+        Some(Self((year - 1980) << 9 | (month as u16) << 5 | day as u16))
+    }
+
+    /// Decode the date field of a directory entry. 0 means that the date is not recorded, and
+    /// other values with month 0 or day 0 are invalid.
+    pub(super) fn from_raw(raw: u16) -> Option<Self> {
+        let date = Self(raw);
+        ((1..=12).contains(&date.month()) && date.day() != 0).then(|| date)
+    }
+
+    pub(super) fn to_raw(self) -> u16 {
+        self.0
+    }
+
+    pub fn year(self) -> u16 {
+        1980 + (self.0 >> 9)
+    }
+
+    pub fn month(self) -> u8 {
+        (self.0 >> 5 & 0xf) as u8
+    }
+
+    pub fn day(self) -> u8 {
+        (self.0 & 0x1f) as u8
+    }
+}
+
+impl fmt::Display for Date {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{:02}-{:02}", self.year(), self.month(), self.day())
+    }
+}
+
+/// The source of the current date of a file system. `None` means that the date is unknown.
+pub type Clock = fn() -> Option<Date>;
+
+/// The clock of file systems unless `FileSystem::set_clock` is called. There is no real-time
+/// clock yet, so dates are never recorded by default.
+pub(super) fn no_clock() -> Option<Date> {
+    None
+}
+
+/// When the last access date of a file is updated, see `FileSystem::set_access_time_policy`.
+/// The date is updated on opening a reader of the file, not on each read.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum AccessTimePolicy {
+    Never,
+    /// Every time a reader is opened.
+    OnRead,
+    /// Only when the recorded date is older than the current date, so that a file read many
+    /// times a day is written at most once a day.
+    Relative,
+}
+
+impl AccessTimePolicy {
+    /// The date to be recorded as the last access date, if it should be updated.
+    pub(super) fn update(self, recorded: Option<Date>, today: Option<Date>) -> Option<Date> {
+        let today = today?;
+        match self {
+            Self::Never => None,
+            Self::OnRead => Some(today),
+            Self::Relative => recorded.map_or(true, |d| d < today).then(|| today),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Never => "noatime",
+            Self::OnRead => "strictatime",
+            Self::Relative => "relatime",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        [Self::Never, Self::OnRead, Self::Relative]
+            .into_iter()
+            .find(|p| p.name() == name)
+    }
+}
+
+impl Default for AccessTimePolicy {
+    fn default() -> Self {
+        Self::Relative
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::info;
+
+    #[test_case]
+    fn test_date() {
+        info!("TESTING fs::fat::date::test_date");
+        let d = Date::new(2022, 3, 14).unwrap();
+        assert_eq!(d.to_raw(), 42 << 9 | 3 << 5 | 14);
+        assert_eq!((d.year(), d.month(), d.day()), (2022, 3, 14));
+        assert_eq!(alloc::format!("{}", d), "2022-03-14");
+        assert_eq!(Date::from_raw(d.to_raw()), Some(d));
+        assert_eq!(Date::from_raw(0), None);
+        assert_eq!(Date::from_raw(42 << 9 | 14), None);
+        assert_eq!(Date::from_raw(42 << 9 | 3 << 5), None);
+        assert!(Date::new(1979, 12, 31).is_none());
+        assert!(Date::new(2108, 1, 1).is_none());
+        assert!(Date::new(2000, 13, 1).is_none());
+        assert!(Date::new(2000, 1, 0).is_none());
+        assert!(Date::new(2021, 12, 31) < Date::new(2022, 1, 1));
+    }
+
+    #[test_case]
+    fn test_access_time_policy() {
+        info!("TESTING fs::fat::date::test_access_time_policy");
+        let d1 = Date::new(2022, 3, 14);
+        let d2 = Date::new(2022, 3, 15);
+        let p = AccessTimePolicy::Relative;
+        assert_eq!(p.update(None, d1), d1);
+        assert_eq!(p.update(d1, d1), None);
+        assert_eq!(p.update(d1, d2), d2);
+        assert_eq!(p.update(d2, d1), None);
+        assert_eq!(p.update(d1, None), None);
+        assert_eq!(AccessTimePolicy::OnRead.update(d1, d1), d1);
+        assert_eq!(AccessTimePolicy::Never.update(None, d1), None);
+        for p in [
+            AccessTimePolicy::Never,
+            AccessTimePolicy::OnRead,
+            AccessTimePolicy::Relative,
+        ] {
+            assert_eq!(AccessTimePolicy::from_name(p.name()), Some(p));
+        }
+    }
+}
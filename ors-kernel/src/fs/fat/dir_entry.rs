@@ -1,7 +1,17 @@
-use super::{Cluster, SliceExt};
+use super::{Cluster, Date, Error, SliceExt};
 use alloc::string::String;
 use alloc::vec;
 use alloc::vec::Vec;
+use ors_common::fat;
+
+/// Error while deserializing a directory entry.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub(super) enum EntryError {
+    /// The buffer is not exactly `DirEntry::SIZE` bytes.
+    Length(usize),
+    NotSfn,
+    NotLfn,
+}
 
 /// Deserialized Directory entry.
 #[derive(PartialEq, Eq, Debug, Clone, Copy)]
@@ -43,30 +53,32 @@ impl DirEntry {
         }
     }
 
-    const READ_ONLY: u8 = 0x01;
-    const HIDDEN: u8 = 0x02;
-    const SYSTEM: u8 = 0x04;
-    const VOLUME_ID: u8 = 0x08;
-    const DIRECTORY: u8 = 0x10;
-    const ARCHIVE: u8 = 0x20;
-    const LONG_FILE_NAME: u8 = 0x0f;
-    const LONG_FILE_NAME_MASK: u8 = 0x3f;
+    const READ_ONLY: u8 = fat::ATTR_READ_ONLY;
+    const HIDDEN: u8 = fat::ATTR_HIDDEN;
+    const SYSTEM: u8 = fat::ATTR_SYSTEM;
+    const VOLUME_ID: u8 = fat::ATTR_VOLUME_ID;
+    const DIRECTORY: u8 = fat::ATTR_DIRECTORY;
+    const ARCHIVE: u8 = fat::ATTR_ARCHIVE;
+    const LONG_FILE_NAME: u8 = fat::ATTR_LONG_FILE_NAME;
+    const LONG_FILE_NAME_MASK: u8 = fat::ATTR_LONG_FILE_NAME_MASK;
 }
 
-impl From<[u8; 32]> for DirEntry {
-    fn from(buf: [u8; 32]) -> Self {
-        let status = buf[0];
-        let attr = buf[11];
+impl TryFrom<&'_ [u8]> for DirEntry {
+    type Error = EntryError;
+
+    fn try_from(buf: &'_ [u8]) -> Result<Self, Self::Error> {
+        let [status] = field::<1>(buf, 0)?;
+        let [attr] = field::<1>(buf, 11)?;
 
-        if status == 0xe5 {
+        Ok(if status == 0xe5 {
             Self::Unused
         } else if status == 0x00 {
             Self::UnusedTerminal
         } else if (attr & Self::LONG_FILE_NAME_MASK) == Self::LONG_FILE_NAME {
-            Self::Lfn(buf.try_into().expect("LFN"))
+            Self::Lfn(buf.try_into()?)
         } else {
-            Self::Sfn(buf.try_into().expect("SFN"))
-        }
+            Self::Sfn(buf.try_into()?)
+        })
     }
 }
 
@@ -103,8 +115,8 @@ pub(super) struct SfnEntry {
 }
 
 impl SfnEntry {
-    const BASE_LOWER: u8 = 0x08;
-    const EXT_LOWER: u8 = 0x10;
+    const BASE_LOWER: u8 = fat::NT_RES_BASE_LOWER;
+    const EXT_LOWER: u8 = fat::NT_RES_EXT_LOWER;
 
     pub(super) fn new() -> Self {
         Self {
@@ -123,25 +135,32 @@ impl SfnEntry {
         }
     }
 
-    pub(super) fn current(c: Option<Cluster>) -> SfnEntry {
+    pub(super) fn current(c: Option<Cluster>) -> Result<SfnEntry, Error> {
         let mut entry = Self::new();
         entry.name = *b".          ";
         entry.set_is_directory(true);
-        entry.set_cluster(c);
-        entry
+        entry.set_cluster(c)?;
+        Ok(entry)
     }
 
-    pub(super) fn parent(c: Option<Cluster>) -> SfnEntry {
+    pub(super) fn parent(c: Option<Cluster>) -> Result<SfnEntry, Error> {
         let mut entry = Self::new();
         entry.name = *b"..         ";
         entry.set_is_directory(true);
-        entry.set_cluster(c);
-        entry
+        entry.set_cluster(c)?;
+        Ok(entry)
     }
 
     pub(super) fn name(&self) -> (bool, String) {
-        let mut is_irreversible = false;
         let mut dest = String::with_capacity(12);
+        let is_irreversible = self.write_name(&mut dest);
+        (is_irreversible, dest)
+    }
+
+    /// Same as `name`, but writes the name into `dest` to reuse its allocation.
+    pub(super) fn write_name(&self, dest: &mut String) -> bool {
+        let mut is_irreversible = false;
+        dest.clear();
         let mut put = |seq: &[u8], is_lower: bool| {
             for c in seq {
                 dest.push(match *c {
@@ -167,7 +186,7 @@ impl SfnEntry {
             &self.name[8..11],
             (self.nt_res & Self::EXT_LOWER) == Self::EXT_LOWER,
         );
-        (is_irreversible, dest)
+        is_irreversible
     }
 
     pub(super) fn set_or_generate_name(&mut self, name: &str) -> bool {
@@ -247,13 +266,18 @@ impl SfnEntry {
         (index != 0).then(|| Cluster::from_index(index))
     }
 
-    pub(super) fn set_cluster(&mut self, cluster: Option<Cluster>) {
+    pub(super) fn set_cluster(&mut self, cluster: Option<Cluster>) -> Result<(), Error> {
         let (lo, hi) = match cluster {
-            Some(c) => (c.index() as u16, (c.index() >> 16) as u16),
+            Some(c) => {
+                let index =
+                    u32::try_from(c.index()).map_err(|_| Error::InvalidCluster(c.index()))?;
+                ((index & 0xffff) as u16, (index >> 16) as u16)
+            }
             None => (0, 0),
         };
         self.fst_clus_lo = lo;
         self.fst_clus_hi = hi;
+        Ok(())
     }
 
     pub(super) fn is_read_only(&self) -> bool {
@@ -268,6 +292,26 @@ impl SfnEntry {
         (self.attr & DirEntry::SYSTEM) == DirEntry::SYSTEM
     }
 
+    pub(super) fn set_is_read_only(&mut self, is_read_only: bool) {
+        self.set_attr(DirEntry::READ_ONLY, is_read_only);
+    }
+
+    pub(super) fn set_is_hidden(&mut self, is_hidden: bool) {
+        self.set_attr(DirEntry::HIDDEN, is_hidden);
+    }
+
+    pub(super) fn set_is_system(&mut self, is_system: bool) {
+        self.set_attr(DirEntry::SYSTEM, is_system);
+    }
+
+    fn set_attr(&mut self, attr: u8, value: bool) {
+        if value {
+            self.attr |= attr;
+        } else {
+            self.attr &= !attr;
+        }
+    }
+
     pub(super) fn is_volume_id(&self) -> bool {
         (self.attr & DirEntry::VOLUME_ID) == DirEntry::VOLUME_ID
     }
@@ -292,22 +336,32 @@ impl SfnEntry {
         self.attr |= DirEntry::ARCHIVE;
     }
 
+    pub(super) fn clear_archive(&mut self) {
+        self.attr &= !DirEntry::ARCHIVE;
+    }
+
     pub(super) fn checksum(&self) -> u8 {
-        self.name.iter().fold(0u8, |sum, c| {
-            (sum >> 1).wrapping_add(sum << 7).wrapping_add(*c)
-        })
+        fat::lfn_checksum(&self.name)
     }
 
-    // TODO: Support create_datetime, last_access_date
+    pub(super) fn last_access_date(&self) -> Option<Date> {
+        Date::from_raw(self.lst_acc_date)
+    }
+
+    pub(super) fn set_last_access_date(&mut self, date: Date) {
+        self.lst_acc_date = date.to_raw();
+    }
+
+    // TODO: Support create_datetime
     // FIXME: Support update_datetime (it is mandatory)
 
     pub(super) fn file_size(&self) -> usize {
         self.file_size as usize
     }
 
-    pub(super) fn set_file_size(&mut self, size: usize) {
-        assert!(size <= u32::MAX as usize);
-        self.file_size = size as u32;
+    pub(super) fn set_file_size(&mut self, size: usize) -> Result<(), Error> {
+        self.file_size = u32::try_from(size).map_err(|_| Error::FileTooLarge)?;
+        Ok(())
     }
 
     fn is_sfn_compatible_char(c: char) -> bool {
@@ -315,25 +369,23 @@ impl SfnEntry {
     }
 }
 
-impl TryFrom<[u8; 32]> for SfnEntry {
-    type Error = &'static str;
-
-    fn try_from(buf: [u8; 32]) -> Result<Self, Self::Error> {
-        let name = buf.array::<11>(0);
-        let attr = buf[11];
-        let nt_res = buf[12];
-        let crt_time_tenth = buf[13];
-        let crt_time = u16::from_le_bytes(buf.array::<2>(14));
-        let crt_date = u16::from_le_bytes(buf.array::<2>(16));
-        let lst_acc_date = u16::from_le_bytes(buf.array::<2>(18));
-        let fst_clus_hi = u16::from_le_bytes(buf.array::<2>(20));
-        let wrt_time = u16::from_le_bytes(buf.array::<2>(22));
-        let wrt_date = u16::from_le_bytes(buf.array::<2>(24));
-        let fst_clus_lo = u16::from_le_bytes(buf.array::<2>(26));
-        let file_size = u32::from_le_bytes(buf.array::<4>(28));
+impl TryFrom<&'_ [u8]> for SfnEntry {
+    type Error = EntryError;
+
+    fn try_from(buf: &'_ [u8]) -> Result<Self, Self::Error> {
+        let name = field::<11>(buf, 0)?;
+        let [attr, nt_res, crt_time_tenth] = field::<3>(buf, 11)?;
+        let crt_time = u16::from_le_bytes(field::<2>(buf, 14)?);
+        let crt_date = u16::from_le_bytes(field::<2>(buf, 16)?);
+        let lst_acc_date = u16::from_le_bytes(field::<2>(buf, 18)?);
+        let fst_clus_hi = u16::from_le_bytes(field::<2>(buf, 20)?);
+        let wrt_time = u16::from_le_bytes(field::<2>(buf, 22)?);
+        let wrt_date = u16::from_le_bytes(field::<2>(buf, 24)?);
+        let fst_clus_lo = u16::from_le_bytes(field::<2>(buf, 26)?);
+        let file_size = u32::from_le_bytes(field::<4>(buf, 28)?);
 
         if (attr & DirEntry::LONG_FILE_NAME_MASK) == DirEntry::LONG_FILE_NAME {
-            Err("LFN")?;
+            Err(EntryError::NotSfn)?;
         }
 
         Ok(Self {
@@ -386,7 +438,7 @@ pub(super) struct LfnEntry {
 }
 
 impl LfnEntry {
-    const LAST_LONG_ENTRY: u8 = 0x40;
+    const LAST_LONG_ENTRY: u8 = fat::LAST_LONG_ENTRY;
 
     pub(super) fn new(order: usize, last: bool, chksum: u8) -> Self {
         assert!(1 <= order && order <= 20);
@@ -417,13 +469,13 @@ impl LfnEntry {
 
     pub(super) fn read_name_parts(&self, buf: &mut [u16]) {
         debug_assert_eq!(buf.len(), 13);
-        for i in 0..13 {
-            buf[i] = u16::from_le_bytes(match i {
-                0..=4 => self.name1.array::<2>(i * 2),
-                5..=10 => self.name2.array::<2>((i - 5) * 2),
-                11..=12 => self.name3.array::<2>((i - 11) * 2),
-                _ => unreachable!(),
-            });
+        let bytes = self.name1.iter().chain(&self.name2).chain(&self.name3);
+        let mut bytes = bytes.copied();
+        for c in buf.iter_mut() {
+            *c = match (bytes.next(), bytes.next()) {
+                (Some(lo), Some(hi)) => u16::from_le_bytes([lo, hi]),
+                _ => break,
+            };
         }
     }
 
@@ -445,21 +497,19 @@ impl LfnEntry {
     }
 }
 
-impl TryFrom<[u8; 32]> for LfnEntry {
-    type Error = &'static str;
+impl TryFrom<&'_ [u8]> for LfnEntry {
+    type Error = EntryError;
 
-    fn try_from(buf: [u8; 32]) -> Result<Self, Self::Error> {
-        let ord = buf[0];
-        let name1 = buf.array::<10>(1);
-        let attr = buf[11];
-        let ty = buf[12];
-        let chksum = buf[13];
-        let name2 = buf.array::<12>(14);
-        let _fst_clus_lo = u16::from_le_bytes(buf.array::<2>(26));
-        let name3 = buf.array::<4>(28);
+    fn try_from(buf: &'_ [u8]) -> Result<Self, Self::Error> {
+        let [ord] = field::<1>(buf, 0)?;
+        let name1 = field::<10>(buf, 1)?;
+        let [attr, ty, chksum] = field::<3>(buf, 11)?;
+        let name2 = field::<12>(buf, 14)?;
+        let _fst_clus_lo = u16::from_le_bytes(field::<2>(buf, 26)?);
+        let name3 = field::<4>(buf, 28)?;
 
         if (attr & DirEntry::LONG_FILE_NAME_MASK) != DirEntry::LONG_FILE_NAME {
-            Err("SFN")?;
+            Err(EntryError::NotLfn)?;
         }
 
         Ok(Self {
@@ -489,58 +539,160 @@ impl Into<[u8; 32]> for LfnEntry {
     }
 }
 
-#[derive(Debug)]
-pub(super) enum LfnReader {
-    Init,
-    LfnSequence(u8, usize, Vec<u16>),
+/// The `N` bytes at `offset` of a directory entry.
+fn field<const N: usize>(buf: &[u8], offset: usize) -> Result<[u8; N], EntryError> {
+    if buf.len() != DirEntry::SIZE {
+        Err(EntryError::Length(buf.len()))?;
+    }
+    buf.try_array(offset).ok_or(EntryError::Length(buf.len()))
+}
+
+/// Reads names from sequences of LFN entries followed by SFN entries. The buffers are reused
+/// across names, so that reading names does not allocate once the buffers are large enough.
+#[derive(Debug, Default)]
+pub(super) struct LfnReader {
+    /// The checksum and the order of the rest of the sequence being read.
+    sequence: Option<(u8, usize)>,
+    buf: Vec<u16>,
+    name: String,
 }
 
 #[derive(Debug)]
-pub(super) enum ReadLfnResult {
+pub(super) enum ReadLfnResult<'a> {
     Meta(DirEntry),
-    Complete(String, SfnEntry),
+    Complete(&'a str, SfnEntry),
     Incomplete,
-    Broken(LfnReader, DirEntry),
+    /// The sequence being read is broken by the entry, which should be read again.
+    Broken(DirEntry),
+    /// An LFN entry that does not start a sequence.
+    Orphaned(DirEntry),
 }
 
 impl LfnReader {
+    pub(super) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Discard the sequence being read.
+    pub(super) fn reset(&mut self) {
+        self.sequence = None;
+    }
+
     pub(super) fn read(&mut self, e: DirEntry) -> ReadLfnResult {
-        match (core::mem::replace(self, Self::Init), e) {
-            (Self::Init, DirEntry::Lfn(lfn)) if lfn.is_last_entry() => {
-                let checksum = lfn.checksum();
+        match (self.sequence.take(), e) {
+            (None, DirEntry::Lfn(lfn)) if lfn.is_last_entry() => {
                 let order = lfn.order();
-                let mut buf = vec![0; order * 13];
-                lfn.read_name_parts(&mut buf[(order - 1) * 13..order * 13]);
-                *self = Self::LfnSequence(checksum, order - 1, buf);
+                self.buf.clear();
+                self.buf.resize(order * 13, 0);
+                lfn.read_name_parts(&mut self.buf[(order - 1) * 13..order * 13]);
+                self.sequence = Some((lfn.checksum(), order - 1));
                 ReadLfnResult::Incomplete
             }
-            (Self::Init, e @ DirEntry::Lfn(_)) => ReadLfnResult::Broken(Self::Init, e),
-            (Self::Init, DirEntry::Sfn(sfn)) if !sfn.is_volume_id() => {
-                let (_, name) = sfn.name();
-                ReadLfnResult::Complete(name, sfn)
+            (None, e @ DirEntry::Lfn(_)) => ReadLfnResult::Orphaned(e),
+            (None, DirEntry::Sfn(sfn)) if !sfn.is_volume_id() => {
+                sfn.write_name(&mut self.name);
+                ReadLfnResult::Complete(&self.name, sfn)
             }
-            (Self::Init, e) => ReadLfnResult::Meta(e),
-            (Self::LfnSequence(checksum, order, mut buf), DirEntry::Lfn(lfn))
-                if order == lfn.order() && checksum == lfn.checksum() =>
+            (None, e) => ReadLfnResult::Meta(e),
+            (Some((checksum, order)), DirEntry::Lfn(lfn))
+                if order != 0 && order == lfn.order() && checksum == lfn.checksum() =>
             {
-                lfn.read_name_parts(&mut buf[(order - 1) * 13..order * 13]);
-                *self = Self::LfnSequence(checksum, order - 1, buf);
+                lfn.read_name_parts(&mut self.buf[(order - 1) * 13..order * 13]);
+                self.sequence = Some((checksum, order - 1));
                 ReadLfnResult::Incomplete
             }
-            (Self::LfnSequence(checksum, order, mut buf), DirEntry::Sfn(sfn))
-                if order == 0 && checksum == sfn.checksum() =>
-            {
+            (Some((checksum, 0)), DirEntry::Sfn(sfn)) if checksum == sfn.checksum() => {
                 // LFN is 0x0000-terminated and padded with 0xffff
-                while matches!(buf.last(), Some(0xffff)) {
-                    buf.pop();
+                let mut units = self.buf.as_slice();
+                while let [rest @ .., 0xffff] = units {
+                    units = rest;
                 }
-                if matches!(buf.last(), Some(0x0000)) {
-                    buf.pop();
+                if let [rest @ .., 0x0000] = units {
+                    units = rest;
                 }
-                let name = String::from_utf16_lossy(buf.as_slice());
-                ReadLfnResult::Complete(name, sfn)
+                self.name.clear();
+                self.name.extend(
+                    char::decode_utf16(units.iter().copied())
+                        .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER)),
+                );
+                ReadLfnResult::Complete(&self.name, sfn)
+            }
+            (Some(_), e) => ReadLfnResult::Broken(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::info;
+
+    #[test_case]
+    fn test_untrusted_buffer() {
+        info!("TESTING fs::fat::dir_entry::test_untrusted_buffer");
+        let mut sfn = SfnEntry::new();
+        assert!(sfn.set_name("FILE.TXT"));
+        let lfn = LfnEntry::new(1, true, sfn.checksum());
+        let sfn_buf: [u8; 32] = sfn.into();
+        let lfn_buf: [u8; 32] = lfn.into();
+        assert_eq!(DirEntry::try_from(&sfn_buf[..]), Ok(DirEntry::Sfn(sfn)));
+        assert_eq!(DirEntry::try_from(&lfn_buf[..]), Ok(DirEntry::Lfn(lfn)));
+        assert_eq!(SfnEntry::try_from(&lfn_buf[..]), Err(EntryError::NotSfn));
+        assert_eq!(LfnEntry::try_from(&sfn_buf[..]), Err(EntryError::NotLfn));
+
+        for buf in [sfn_buf, lfn_buf, [0; 32], [0xe5; 32]] {
+            for len in [0, 1, 12, 31] {
+                let e = EntryError::Length(len);
+                assert_eq!(DirEntry::try_from(&buf[..len]), Err(e));
+                assert_eq!(SfnEntry::try_from(&buf[..len]), Err(e));
+                assert_eq!(LfnEntry::try_from(&buf[..len]), Err(e));
             }
-            (state @ Self::LfnSequence(..), e) => ReadLfnResult::Broken(state, e),
+            let mut oversized = [0; 33];
+            oversized[..32].copy_from_slice(&buf);
+            let e = EntryError::Length(33);
+            assert_eq!(DirEntry::try_from(&oversized[..]), Err(e));
+            assert_eq!(SfnEntry::try_from(&oversized[..]), Err(e));
+            assert_eq!(LfnEntry::try_from(&oversized[..]), Err(e));
         }
     }
+
+    #[test_case]
+    fn test_broken_lfn_order() {
+        info!("TESTING fs::fat::dir_entry::test_broken_lfn_order");
+        let mut sfn = SfnEntry::new();
+        assert!(sfn.set_name("FILE.TXT"));
+        let lfn = LfnEntry::new(1, true, sfn.checksum());
+        let mut buf: [u8; 32] = lfn.into();
+        buf[0] = 0x40; // The last entry of order 0
+        let broken = DirEntry::try_from(&buf[..]).unwrap();
+
+        let mut reader = LfnReader::new();
+        assert!(matches!(
+            reader.read(DirEntry::Lfn(lfn)),
+            ReadLfnResult::Incomplete
+        ));
+        assert!(matches!(reader.read(broken), ReadLfnResult::Broken(..)));
+    }
+
+    #[test_case]
+    fn test_out_of_range_fields() {
+        info!("TESTING fs::fat::dir_entry::test_out_of_range_fields");
+        let mut sfn = SfnEntry::new();
+        assert_eq!(sfn.set_file_size(u32::MAX as usize), Ok(()));
+        assert_eq!(
+            sfn.set_file_size(u32::MAX as usize + 1),
+            Err(Error::FileTooLarge)
+        );
+        assert_eq!(sfn.file_size(), u32::MAX as usize);
+
+        let c = Cluster::from_index(0x1234_5678);
+        assert_eq!(sfn.set_cluster(Some(c)), Ok(()));
+        let index = u32::MAX as usize + 1;
+        assert_eq!(
+            sfn.set_cluster(Some(Cluster::from_index(index))),
+            Err(Error::InvalidCluster(index))
+        );
+        assert_eq!(sfn.cluster(), Some(c));
+        assert!(SfnEntry::current(Some(Cluster::from_index(index))).is_err());
+    }
 }
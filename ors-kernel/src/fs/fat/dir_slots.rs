@@ -0,0 +1,178 @@
+use super::DirEntry;
+use alloc::vec;
+use alloc::vec::Vec;
+
+/// Summary of the directory entry slots of a directory cluster, to find a space for new entries
+/// without reading the directory.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub(super) struct DirSlots {
+    /// Bit `n` is set if the `n`-th entry is `Unused`.
+    unused: Vec<u64>,
+    unused_count: usize,
+    len: usize,
+    /// The first `UnusedTerminal` entry. The entries after it are not tracked.
+    terminal: Option<usize>,
+}
+
+/// The result of `DirSlots::scan`.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub(super) enum Scan {
+    /// Found enough slots. The run starts at the slot if any, or in the preceding clusters.
+    Found(Option<usize>),
+    /// Reached the terminal. The run before the terminal starts at the slot if any, or in the
+    /// preceding clusters.
+    Terminal(Option<usize>),
+    /// Reached the end of the cluster with a run of the length, which starts at the slot if any,
+    /// or in the preceding clusters.
+    Continue(usize, Option<usize>),
+}
+
+impl DirSlots {
+    /// A summary of `len` live entries.
+    pub(super) fn new(len: usize) -> Self {
+        Self {
+            unused: vec![0; (len + 63) / 64],
+            unused_count: 0,
+            len,
+            terminal: None,
+        }
+    }
+
+    fn is_unused(&self, n: usize) -> bool {
+        self.unused[n / 64] & (1 << (n % 64)) != 0
+    }
+
+    fn set_unused(&mut self, n: usize, unused: bool) {
+        if self.is_unused(n) != unused {
+            self.unused[n / 64] ^= 1 << (n % 64);
+            if unused {
+                self.unused_count += 1;
+            } else {
+                self.unused_count -= 1;
+            }
+        }
+    }
+
+    /// Record that `entry` is written at `n`. Returns `false` if the summary cannot follow the
+    /// write, which happens when a live entry overwrites the terminal, since the entries after
+    /// the terminal are not tracked.
+    pub(super) fn update(&mut self, n: usize, entry: &DirEntry) -> bool {
+        debug_assert!(n < self.len);
+        match self.terminal {
+            Some(t) if t < n => return true, // not tracked
+            Some(t) if t == n && !matches!(entry, DirEntry::UnusedTerminal) => return false,
+            _ => {}
+        }
+        match entry {
+            DirEntry::UnusedTerminal => {
+                for i in n..self.len {
+                    self.set_unused(i, false);
+                }
+                self.terminal = Some(n);
+            }
+            DirEntry::Unused => self.set_unused(n, true),
+            DirEntry::Lfn(_) | DirEntry::Sfn(_) => self.set_unused(n, false),
+        }
+        true
+    }
+
+    /// Continue scanning for `required` consecutive `Unused` slots with a run of `run` slots that
+    /// precedes this cluster.
+    pub(super) fn scan(&self, mut run: usize, required: usize) -> Scan {
+        debug_assert!(run < required);
+        let mut start = None;
+        let end = self.terminal.unwrap_or(self.len);
+        // Jump over the cluster with no Unused slots
+        if self.unused_count == 0 {
+            return match self.terminal {
+                Some(0) if run != 0 => Scan::Terminal(None),
+                Some(t) => Scan::Terminal(Some(t)),
+                None => Scan::Continue(0, None),
+            };
+        }
+        for n in 0..end {
+            if self.is_unused(n) {
+                if run == 0 {
+                    start = Some(n);
+                }
+                run += 1;
+                if run == required {
+                    return Scan::Found(start);
+                }
+            } else {
+                run = 0;
+                start = None;
+            }
+        }
+        match self.terminal {
+            Some(t) if run == 0 => Scan::Terminal(Some(t)),
+            Some(_) => Scan::Terminal(start),
+            None => Scan::Continue(run, start),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::fat::dir_entry::SfnEntry;
+    use log::info;
+
+    fn slots(entries: &[DirEntry]) -> DirSlots {
+        let mut slots = DirSlots::new(entries.len());
+        for (n, entry) in entries.iter().enumerate() {
+            if slots.terminal.is_none() {
+                assert!(slots.update(n, entry));
+            }
+        }
+        slots
+    }
+
+    #[test_case]
+    fn test_scan() {
+        info!("TESTING fs::fat::dir_slots::test_scan");
+        let live = DirEntry::Sfn(SfnEntry::new());
+        let (u, t) = (DirEntry::Unused, DirEntry::UnusedTerminal);
+
+        let s = slots(&[live, u, u, live, u, u, u, t]);
+        assert_eq!(s.scan(0, 2), Scan::Found(Some(1)));
+        assert_eq!(s.scan(0, 3), Scan::Found(Some(4)));
+        assert_eq!(s.scan(0, 4), Scan::Terminal(Some(4)));
+        let s = slots(&[u, u, live, t, u, u, u, u]);
+        assert_eq!(s.scan(1, 3), Scan::Found(None));
+        assert_eq!(s.scan(1, 4), Scan::Terminal(Some(3)));
+        let s = slots(&[live, live, u, u]);
+        assert_eq!(s.scan(1, 3), Scan::Continue(2, Some(2)));
+        let s = slots(&[u, u, u, u]);
+        assert_eq!(s.scan(1, 8), Scan::Continue(5, None));
+        let s = slots(&[t, live, live, live]);
+        assert_eq!(s.scan(0, 1), Scan::Terminal(Some(0)));
+        assert_eq!(s.scan(2, 3), Scan::Terminal(None));
+        let s = slots(&[live; 4]);
+        assert_eq!(s.scan(2, 3), Scan::Continue(0, None));
+    }
+
+    #[test_case]
+    fn test_update() {
+        info!("TESTING fs::fat::dir_slots::test_update");
+        let live = DirEntry::Sfn(SfnEntry::new());
+        let (u, t) = (DirEntry::Unused, DirEntry::UnusedTerminal);
+
+        let mut s = slots(&[live, live, u, t, live, live, live, live]);
+        assert!(s.update(2, &live));
+        assert_eq!(s.unused_count, 0);
+        assert!(s.update(6, &live)); // after the terminal
+        assert_eq!(s.scan(0, 1), Scan::Terminal(Some(3)));
+        assert!(!s.update(3, &live));
+
+        let mut s = slots(&[live, u, u, u, live, u, u, t]);
+        assert!(s.update(3, &t));
+        assert_eq!(s.unused_count, 2);
+        assert_eq!(s.scan(0, 3), Scan::Terminal(Some(1)));
+        assert!(s.update(1, &live));
+        assert_eq!(s.scan(0, 3), Scan::Terminal(Some(2)));
+        let large = slots(&[u; 200]);
+        assert_eq!(large.unused_count, 200);
+        assert_eq!(large.scan(0, 130), Scan::Found(Some(0)));
+    }
+}
@@ -56,7 +56,10 @@ impl Into<u32> for FatEntry {
         match self {
             Self::Unused => 0,
             Self::Reserved => 1,
-            Self::UsedChained(cluster) => cluster.index() as u32,
+            Self::UsedChained(cluster) => {
+                debug_assert!((2..=0x0ffffff6).contains(&cluster.index()));
+                u32::try_from(cluster.index()).expect("Cluster number out of range")
+            }
             Self::UsedEoc => 0x0fffffff,
             Self::Bad => 0x0ffffff7,
         }
@@ -0,0 +1,112 @@
+//! Table-driven tests over disk images built by `ors-mkimage` from `fixtures/*.manifest`.
+//!
+//! The images and their expectations are generated by the build script when the `fat-fixtures`
+//! feature is enabled, e.g. `cargo test --features fat-fixtures`.
+
+use super::{Error, FileSystem};
+use crate::fs::volume::mem::MemVolume;
+use crate::fs::volume::{Sector, Volume};
+use alloc::string::String;
+use alloc::vec::Vec;
+use log::info;
+
+#[derive(Debug)]
+struct Fixture {
+    name: &'static str,
+    sector_size: usize,
+    image: &'static [u8],
+    expectations: &'static [Expectation],
+}
+
+/// See `ors_mkimage::Expectation`.
+#[derive(Debug)]
+enum Expectation {
+    Dir {
+        path: &'static str,
+        entries: &'static [&'static str],
+    },
+    File {
+        path: &'static str,
+        size: usize,
+        len: usize,
+        hash: u64,
+        read_only: bool,
+        hidden: bool,
+        system: bool,
+    },
+    Missing {
+        path: &'static str,
+    },
+}
+
+include!(concat!(env!("OUT_DIR"), "/fat_fixtures.rs"));
+
+impl Fixture {
+    fn mount(&self) -> FileSystem<MemVolume> {
+        let volume = MemVolume::new(self.sector_size, self.image.len() / self.sector_size);
+        for (i, sector) in self.image.chunks(self.sector_size).enumerate() {
+            volume.write(Sector::from_index(i), sector).unwrap();
+        }
+        FileSystem::new(volume).unwrap()
+    }
+}
+
+/// Same as `ors_mkimage::content_hash`.
+fn content_hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, b| {
+        (hash ^ *b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[test_case]
+fn test_fixtures() {
+    info!("TESTING fs::fat::fixtures::test_fixtures");
+    assert!(!FIXTURES.is_empty());
+    for fixture in FIXTURES {
+        info!("fixture: {}", fixture.name);
+        let fs = fixture.mount();
+        for e in fixture.expectations {
+            match *e {
+                Expectation::Dir { path, entries } => {
+                    let dir = fs.open_dir(path).unwrap();
+                    let names = dir
+                        .files()
+                        .map(|f| String::from(f.name()))
+                        .collect::<Vec<_>>();
+                    assert_eq!(names, entries, "{}: {}", fixture.name, path);
+                }
+                Expectation::File {
+                    path,
+                    size,
+                    len,
+                    hash,
+                    read_only,
+                    hidden,
+                    system,
+                } => {
+                    let file = fs.open(path).unwrap();
+                    assert!(!file.is_dir(), "{}: {}", fixture.name, path);
+                    assert_eq!(file.file_size(), size, "{}: {}", fixture.name, path);
+                    assert_eq!(
+                        (file.is_read_only(), file.is_hidden(), file.is_system()),
+                        (read_only, hidden, system),
+                        "{}: {}",
+                        fixture.name,
+                        path
+                    );
+                    let contents = file.reader().unwrap().read_to_end().unwrap();
+                    assert_eq!(contents.len(), len, "{}: {}", fixture.name, path);
+                    assert_eq!(content_hash(&contents), hash, "{}: {}", fixture.name, path);
+                }
+                Expectation::Missing { path } => {
+                    assert!(
+                        matches!(fs.open(path), Err(Error::NotFound(_))),
+                        "{}: {}",
+                        fixture.name,
+                        path
+                    );
+                }
+            }
+        }
+    }
+}
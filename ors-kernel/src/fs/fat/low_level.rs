@@ -1,8 +1,29 @@
-use super::{BootSector, BootSectorError, DirEntry, Error, FatEntry, Sector, SliceExt, Volume};
-use crate::fs::volume::{BufferedSectorRef, BufferedVolume};
+use super::boot_sector::backup_sector_candidates;
+use super::date::{self, AccessTimePolicy, Clock, Date};
+use super::dir_slots::DirSlots;
+use super::{
+    BootSector, BootSectorError, DirEntry, Error, FatEntry, OpenFile, Sector, SliceExt, Volume,
+};
+use crate::fs::volume::{BufferedSectorRef, BufferedVolume, ByteOffset, CacheStats, Overflow};
+use crate::sync::mutex::{Mutex, MutexGuard};
+use crate::sync::spin::Spin;
+use alloc::collections::BTreeMap;
 use alloc::vec;
+use alloc::vec::Vec;
 use core::fmt;
-use log::trace;
+use core::ops::Range;
+use core::panic::Location;
+use core::sync::atomic::{AtomicBool, Ordering};
+use log::{trace, warn, Level};
+use ors_common::fat::CLEAN_SHUTDOWN;
+
+/// Maximum number of directory clusters whose `DirSlots` are kept. They are all dropped when
+/// exceeded, and built again on the next insertion.
+const MAX_DIR_SLOTS: usize = 1024;
+
+/// Maximum number of clusters for which `Visited` keeps a bitmap (16 KiB). Walks on larger
+/// volumes detect cycles only by the number of steps.
+const MAX_VISITED_BITMAP_CLUSTERS: usize = 128 * 1024;
 
 #[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy, Hash)]
 pub(super) struct Cluster(usize);
@@ -19,6 +40,30 @@ impl Cluster {
     pub(super) fn offset(self, s: usize) -> Self {
         Self(self.0 + s)
     }
+
+    /// The bytes of this cluster in the volume.
+    pub(super) fn byte_range(self, bs: &BootSector) -> Result<Range<ByteOffset>, Overflow> {
+        let start = bs.cluster_location(self).byte_offset(bs.sector_size())?;
+        let end = start.checked_add(bs.cluster_bytes())?;
+        Ok(start..end)
+    }
+}
+
+/// A number of clusters.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy, Default)]
+pub(super) struct ClusterCount(usize);
+
+impl ClusterCount {
+    /// Number of clusters required to hold `bytes` bytes.
+    pub(super) fn for_bytes(bytes: usize, cluster_bytes: usize) -> Result<Self, Overflow> {
+        debug_assert_ne!(cluster_bytes, 0);
+        let bytes = bytes.checked_add(cluster_bytes - 1).ok_or(Overflow)?;
+        Ok(Self(bytes / cluster_bytes))
+    }
+
+    pub(super) fn get(self) -> usize {
+        self.0
+    }
 }
 
 impl fmt::Display for Cluster {
@@ -27,39 +72,325 @@ impl fmt::Display for Cluster {
     }
 }
 
+/// Sector accesses are serialized by `BufferedVolume`, but check-then-act sequences over several
+/// accesses are not. Such sequences are serialized by the locks of `Root`, which must be taken in
+/// the order of `dir_lock`, `open_files`, and then `fat_lock`.
 #[derive(Debug)]
 pub(super) struct Root<V> {
     volume: BufferedVolume<V>,
     bs: BootSector,
+    /// The backup sector from which `bs` is loaded, while the primary boot sector is left broken.
+    recovered_from: Mutex<Option<Sector>>,
+    /// Whether the volume is not cleanly unmounted when this file system is loaded.
+    was_dirty: bool,
+    /// Set by `revalidate` when the volume is found smaller than the file system. The file system
+    /// is read-only from then on.
+    volume_shrunk: AtomicBool,
+    /// Whether the volume dirty flag is set by this file system, and must be cleared on `close`.
+    dirty_marked: AtomicBool,
+    /// Set by `close`. The file system is read-only from then on.
+    closed: AtomicBool,
+    /// Guards every mutation of the FAT, such as allocation (find an unused entry and use it),
+    /// and the count of unused clusters maintained by them.
+    fat_lock: Mutex<FreeClusters>,
+    /// Guards every mutation of directory entries, such as insertion (find a space and fill it).
+    dir_lock: Mutex<()>,
+    /// Guards read-modify-writes of SFN entries, which are shared by every `File` of the entry.
+    entry_lock: Mutex<()>,
+    /// Files that have readers or writers.
+    open_files: Mutex<Vec<OpenFile>>,
+    /// Summaries of the slots of directory clusters, built on insertion of directory entries and
+    /// kept up to date by every write to the clusters.
+    dir_slots: Spin<BTreeMap<Cluster, DirSlots>>,
+    /// When and by which clock the last access dates of files are updated.
+    access_time: Spin<(AccessTimePolicy, Clock)>,
 }
 
 impl<V: Volume> Root<V> {
     pub(super) fn new(volume: V) -> Result<Self, Error> {
-        let sector_size = volume.sector_size();
-        let mut buf = vec![0; sector_size];
-
+        let mut buf = vec![0; volume.sector_size()];
         volume.read(Sector::from_index(0), buf.as_mut())?;
-        let bs = BootSector::try_from(buf.as_ref())?;
 
-        if bs.sector_size() != sector_size {
+        let (bs, recovered_from) = match Self::load_boot_sector(&volume, &buf) {
+            Ok(bs) => (bs, None),
+            Err(
+                e @ (BootSectorError::SignatureMismatch
+                | BootSectorError::Truncated
+                | BootSectorError::Broken(_)),
+            ) => {
+                let mut backup = vec![0; buf.len()];
+                let found = backup_sector_candidates(&buf).find_map(|sector| {
+                    volume.read(sector, backup.as_mut()).ok()?;
+                    let bs = Self::load_boot_sector(&volume, &backup).ok()?;
+                    // A genuine backup points to itself
+                    (bs.backup_sector() == Some(sector)).then(|| (bs, sector))
+                });
+                match found {
+                    Some((bs, sector)) => {
+                        warn!(
+                            "fat: {}, the backup boot sector at sector {} is used instead",
+                            e,
+                            sector.index()
+                        );
+                        (bs, Some(sector))
+                    }
+                    None => Err(e)?,
+                }
+            }
+            Err(e) => Err(e)?,
+        };
+
+        let volume = BufferedVolume::new(volume);
+        let mut root = Self {
+            volume,
+            bs,
+            recovered_from: Mutex::named(recovered_from, "fs.fat.boot"),
+            was_dirty: false,
+            volume_shrunk: AtomicBool::new(false),
+            dirty_marked: AtomicBool::new(false),
+            closed: AtomicBool::new(false),
+            fat_lock: Mutex::named(FreeClusters::default(), "fs.fat.fat"),
+            dir_lock: Mutex::named((), "fs.fat.dir"),
+            entry_lock: Mutex::named((), "fs.fat.entry"),
+            open_files: Mutex::named(Vec::new(), "fs.fat.open_files"),
+            dir_slots: Spin::new(BTreeMap::new()),
+            access_time: Spin::new((AccessTimePolicy::Never, date::no_clock)),
+        };
+        let flags = root.fat().read_reserved(1)?;
+        root.was_dirty = flags & CLEAN_SHUTDOWN == 0;
+        Ok(root)
+    }
+
+    fn load_boot_sector(volume: &V, buf: &[u8]) -> Result<BootSector, BootSectorError> {
+        let bs = BootSector::try_from(buf)?;
+        if bs.sector_size() != volume.sector_size() {
             Err(BootSectorError::Broken("BytsPerSec (mismatch)"))?;
         }
         if volume.sector_count() < bs.total_sector_count() {
             Err(BootSectorError::Broken("TotSec (mismatch)"))?;
         }
-
-        let volume = BufferedVolume::new(volume);
-        Ok(Self { volume, bs })
+        Ok(bs)
     }
 
     pub(super) fn commit(&self) -> Result<(), Error> {
         Ok(self.volume.commit()?)
     }
 
+    /// Every access to the sectors of the volume goes through this, so that a closed file system
+    /// no longer fills the sector cache released by `close`.
+    fn sector(&self, sector: Sector) -> Result<BufferedSectorRef, Error> {
+        if self.closed.load(Ordering::Acquire) {
+            Err(Error::Unmounted)?;
+        }
+        Ok(self.volume.sector(sector)?)
+    }
+
+    pub(super) fn cache_stats(&self) -> CacheStats {
+        self.volume.stats()
+    }
+
+    pub(super) fn trim_cache(&self) -> usize {
+        self.volume.trim()
+    }
+
+    pub(super) fn evict_cache(&self, sector: Sector, count: usize) -> usize {
+        self.volume.evict(sector, count)
+    }
+
+    pub(super) fn access_time_policy(&self) -> AccessTimePolicy {
+        self.access_time.lock().0
+    }
+
+    pub(super) fn set_access_time_policy(&self, policy: AccessTimePolicy) {
+        self.access_time.lock().0 = policy;
+    }
+
+    pub(super) fn set_clock(&self, clock: Clock) {
+        self.access_time.lock().1 = clock;
+    }
+
+    /// The current date by the clock, see `FileSystem::set_clock`.
+    pub(super) fn today(&self) -> Option<Date> {
+        let clock = self.access_time.lock().1;
+        clock()
+    }
+
+    /// The last access date to be recorded over `recorded`, if it should be updated.
+    pub(super) fn access_date_update(&self, recorded: Option<Date>) -> Option<Date> {
+        self.check_writable().ok()?;
+        let (policy, clock) = *self.access_time.lock();
+        policy.update(recorded, clock())
+    }
+
+    pub(super) fn was_dirty(&self) -> bool {
+        self.was_dirty
+    }
+
+    /// Set or clear the volume dirty flag in FAT[1], and commit it immediately.
+    pub(super) fn set_dirty(&self, dirty: bool) -> Result<(), Error> {
+        self.fat().update_reserved(1, |flags| match dirty {
+            true => flags & !CLEAN_SHUTDOWN,
+            false => flags | CLEAN_SHUTDOWN,
+        })?;
+        self.dirty_marked.store(dirty, Ordering::Release);
+        self.commit()
+    }
+
+    /// Commit the changes, clear the volume dirty flag if it is set by this file system, and
+    /// release the sector cache. Every access fails with `Error::Unmounted` afterwards, see
+    /// `sector`. Fails while any file is open, in which case the file system is left untouched.
+    pub(super) fn close(&self) -> Result<(), Error> {
+        let _lock = self.lock_dirs();
+        // Held until closed, so that no file is opened in the meantime
+        let open_files = self.open_files();
+        if let Some(file) = open_files.first() {
+            Err(Error::FileInUse(file.name.clone()))?;
+        }
+        if self.closed.load(Ordering::Acquire) {
+            return Ok(());
+        }
+        if self.dirty_marked.load(Ordering::Acquire) {
+            self.set_dirty(false)?;
+        } else {
+            self.commit()?;
+        }
+        self.closed.store(true, Ordering::Release);
+        // Everything is committed, so the whole sector cache can be released. A closed file
+        // system is kept alive by the mount table, and should not keep its cache alive with it.
+        let released = self
+            .volume
+            .evict(Sector::from_index(0), self.volume.sector_count());
+        trace!("fat: {} cached sectors are released on close", released);
+        Ok(())
+    }
+
+    /// Check that the volume still holds the whole file system. Once the volume is found smaller,
+    /// the file system stays read-only even if the volume grows back, since the changes beyond
+    /// the end may have been lost. Returns whether the file system is writable.
+    pub(super) fn revalidate(&self) -> bool {
+        let (count, total) = (self.volume.sector_count(), self.bs.total_sector_count());
+        if count < total && !self.volume_shrunk.swap(true, Ordering::AcqRel) {
+            warn!(
+                "fat: The volume shrank to {} sectors while the file system has {} sectors, \
+                 the file system is now read-only",
+                count, total
+            );
+        }
+        !self.volume_shrunk.load(Ordering::Acquire)
+    }
+
+    /// Every mutation of the file system must be checked by this. The volume size is checked
+    /// every time, so that a shrink is noticed before anything is written beyond the new end.
+    pub(super) fn check_writable(&self) -> Result<(), Error> {
+        if !self.revalidate() {
+            Err(Error::VolumeShrunk)?;
+        }
+        if self.closed.load(Ordering::Acquire) {
+            Err(Error::Unmounted)?;
+        }
+        Ok(())
+    }
+
+    /// Whether the primary boot sector is broken and the backup is used instead.
+    pub(super) fn is_boot_sector_recovered(&self) -> bool {
+        self.recovered_from.lock().is_some()
+    }
+
+    /// Overwrite the broken primary boot sector with the backup that this file system is loaded
+    /// from. Returns `false` if the primary boot sector is not broken.
+    pub(super) fn repair_boot_sector(&self) -> Result<bool, Error> {
+        let mut recovered_from = self.recovered_from.lock();
+        let backup = match *recovered_from {
+            Some(sector) => self.sector(sector)?,
+            None => return Ok(false),
+        };
+        let bytes = backup.bytes().to_vec();
+        self.write_boot_sector(&bytes)?;
+        *recovered_from = None;
+        Ok(true)
+    }
+
+    /// Write the boot sector to both the primary and the backup sector. Every rewrite of the boot
+    /// sector must go through this to keep the backup in sync.
+    fn write_boot_sector(&self, bytes: &[u8]) -> Result<(), Error> {
+        self.check_writable()?;
+        let sectors = Some(Sector::from_index(0))
+            .into_iter()
+            .chain(self.bs.backup_sector());
+        for sector in sectors {
+            let s = self.sector(sector)?;
+            s.bytes().copy_from_slice(bytes);
+            s.mark_as_dirty();
+        }
+        self.commit()
+    }
+
     pub(super) fn boot_sector(&self) -> &BootSector {
         &self.bs
     }
 
+    /// Lock the directory entries of this file system. FAT operations can be performed while
+    /// holding it, but not vice versa.
+    pub(super) fn lock_dirs(&self) -> MutexGuard<()> {
+        self.dir_lock.lock()
+    }
+
+    /// Lock the SFN entries to read, modify and write one of them. This can be taken while holding
+    /// `lock_dirs`, but not vice versa.
+    pub(super) fn lock_entries(&self) -> MutexGuard<()> {
+        self.entry_lock.lock()
+    }
+
+    /// Lock the table of files that have readers or writers. Files must not be removed or moved
+    /// while they are in the table, thus such operations hold it until they are done.
+    pub(super) fn open_files(&self) -> MutexGuard<Vec<OpenFile>> {
+        self.open_files.lock()
+    }
+
+    /// Call `f` with the summary of the slots of the directory cluster, which is built by reading
+    /// the cluster if it is not kept. Must be called while holding `Root::lock_dirs`.
+    pub(super) fn with_dir_slots<R>(
+        &self,
+        cluster: Cluster,
+        f: impl FnOnce(&DirSlots) -> R,
+    ) -> Result<R, Error> {
+        if let Some(slots) = self.dir_slots.lock().get(&cluster) {
+            return Ok(f(slots));
+        }
+
+        // Every write to directory entries holds Root::lock_dirs, except rewrites of live entries
+        // that do not change the summary, thus the cluster can be read without the lock
+        let mut c = self.cluster(cluster);
+        let mut slots = DirSlots::new(c.dir_entries_count());
+        for n in 0..c.dir_entries_count() {
+            let entry = match c.read_dir_entry(n) {
+                Ok(entry) => entry,
+                Err(Error::BrokenDirEntry) => continue, // considered as live
+                Err(e) => Err(e)?,
+            };
+            slots.update(n, &entry);
+            if matches!(entry, DirEntry::UnusedTerminal) {
+                break;
+            }
+        }
+        let r = f(&slots);
+        let mut dir_slots = self.dir_slots.lock();
+        if MAX_DIR_SLOTS <= dir_slots.len() {
+            dir_slots.clear();
+        }
+        dir_slots.insert(cluster, slots);
+        Ok(r)
+    }
+
+    fn update_dir_slots(&self, cluster: Cluster, n: usize, entry: Option<&DirEntry>) {
+        let mut dir_slots = self.dir_slots.lock();
+        if let Some(slots) = dir_slots.get_mut(&cluster) {
+            if !entry.map_or(false, |entry| slots.update(n, entry)) {
+                dir_slots.remove(&cluster);
+            }
+        }
+    }
+
     pub(super) fn fat(&self) -> BufferedFat<V> {
         BufferedFat {
             root: self,
@@ -71,7 +402,7 @@ impl<V: Volume> Root<V> {
         let first_sector = self.bs.cluster_location(cluster);
         BufferedCluster {
             cluster,
-            volume: &self.volume,
+            root: self,
             first_sector,
             sector_count: self.bs.cluster_size(),
             sector_size: self.bs.sector_size(),
@@ -86,10 +417,33 @@ impl<V: Volume> Root<V> {
         }
     }
 
+    /// Clusters of the chain starting at `start`.
+    pub(super) fn chain(&self, start: Option<Cluster>) -> ChainWalker<V> {
+        ChainWalker {
+            root: self,
+            start,
+            current: None,
+            visited: Visited::new(self.bs.cluster_count()),
+        }
+    }
+
+    /// Clusters chained after `cluster`, which is counted as visited.
+    pub(super) fn chain_after(&self, cluster: Cluster) -> ChainWalker<V> {
+        let mut chain = self.chain(Some(cluster));
+        let _ = chain.try_next(); // the first step never fails
+        chain
+    }
+
     pub(super) fn dir_entries(&self, cluster: Cluster) -> DirEntries<V> {
+        self.dir_entries_at(cluster, 0)
+    }
+
+    /// Directory entries from the `index`-th entry of `cluster` to the end of the directory.
+    pub(super) fn dir_entries_at(&self, cluster: Cluster, index: usize) -> DirEntries<V> {
         DirEntries {
             root: self,
-            cursor: Some((self.cluster(cluster), 0)),
+            chain: self.chain_after(cluster),
+            cursor: Some((self.cluster(cluster), index)),
         }
     }
 }
@@ -109,50 +463,290 @@ impl<'a, V: Volume> BufferedFat<'a, V> {
     }
 
     fn entry(&mut self, cluster: Cluster) -> Result<(&BufferedSectorRef<'a>, usize), Error> {
+        if !self.root.bs.is_cluster_available(cluster) {
+            Err(Error::InvalidCluster(cluster.index()))?;
+        }
         let (sector, offset) = self.root.bs.fat_entry_location(cluster);
+        self.entry_at(sector, offset)
+    }
+
+    fn entry_at(
+        &mut self,
+        sector: Sector,
+        offset: usize,
+    ) -> Result<(&BufferedSectorRef<'a>, usize), Error> {
         if !matches!(self.last, Some(ref r) if r.sector() == sector) {
-            self.last = Some(self.root.volume.sector(sector)?);
+            self.last = Some(self.root.sector(sector)?);
         }
         Ok((self.last.as_ref().unwrap(), offset))
     }
 
+    /// The number of unused clusters, including the ones reserved by `reserve`. The FAT is
+    /// scanned only for the first time, and the count is maintained from then on.
+    pub(super) fn free_cluster_count(&mut self) -> Result<usize, Error> {
+        let root = self.root;
+        let mut free = root.fat_lock.lock();
+        self.count_free(&mut free)
+    }
+
+    fn count_free(&mut self, free: &mut FreeClusters) -> Result<usize, Error> {
+        if let Some(count) = free.count {
+            return Ok(count);
+        }
+        let mut count = 0;
+        for (_, entry) in self.entries() {
+            count += matches!(entry, FatEntry::Unused) as usize;
+        }
+        free.count = Some(count);
+        Ok(count)
+    }
+
+    /// Reserve `n` unused clusters, which are then allocated by `allocate_chain` without
+    /// competing with other allocations. Fails with `Error::Full` if there are not enough
+    /// clusters that are unused and not reserved.
+    pub(super) fn reserve(&mut self, n: usize) -> Result<Reservation<'a, V>, Error> {
+        let root = self.root;
+        let mut free = root.fat_lock.lock();
+        root.check_writable()?;
+        if self.count_free(&mut free)? < free.reserved + n {
+            Err(Error::Full)?;
+        }
+        free.reserved += n;
+        Ok(Reservation { root, count: n })
+    }
+
     pub(super) fn allocate(&mut self) -> Result<Cluster, Error> {
-        // FIXME: This implementation is too slow since it always searches from the start
+        let root = self.root;
+        let mut free = root.fat_lock.lock();
+        let (c, _) = self.allocate_chain_unlocked(&mut free, 1, None)?;
+        Ok(c)
+    }
+
+    /// Allocate `count` clusters chained to each other, and return the first and the last of
+    /// them. Clusters are taken from `reservation` as long as it remains.
+    pub(super) fn allocate_chain(
+        &mut self,
+        count: usize,
+        reservation: Option<&mut Reservation<'a, V>>,
+    ) -> Result<(Cluster, Cluster), Error> {
+        let root = self.root;
+        let mut free = root.fat_lock.lock();
+        self.allocate_chain_unlocked(&mut free, count, reservation)
+    }
+
+    fn allocate_chain_unlocked(
+        &mut self,
+        free: &mut FreeClusters,
+        count: usize,
+        reservation: Option<&mut Reservation<'a, V>>,
+    ) -> Result<(Cluster, Cluster), Error> {
+        debug_assert!(count != 0);
+        let available = self.count_free(free)?;
+        let from_reservation = reservation.as_ref().map_or(0, |r| r.count.min(count));
+        // Clusters reserved by others are not available
+        if available.saturating_sub(free.reserved) < count - from_reservation {
+            Err(Error::Full)?;
+        }
+        let clusters = self.find_unused(count);
+        if clusters.len() < count {
+            // The count disagrees with the FAT, which is modified by something else
+            warn!("fat: The count of unused clusters is outdated");
+            free.count = None;
+            Err(Error::Full)?;
+        }
+        // The clusters are in ascending order, thus the entries are written sector by sector and
+        // each FAT sector is fetched once through `last`
+        for (i, c) in clusters.iter().enumerate() {
+            let entry = match clusters.get(i + 1) {
+                Some(next) => (*next).into(),
+                None => FatEntry::UsedEoc,
+            };
+            self.write(*c, entry)?;
+        }
+        free.count = Some(available - count);
+        if let Some(reservation) = reservation {
+            reservation.count -= from_reservation;
+            free.reserved -= from_reservation;
+        }
+        Ok((clusters[0], clusters[count - 1]))
+    }
+
+    /// Find `count` unused clusters in ascending order in a single pass: the first run of
+    /// `count` consecutive ones if any, which keeps files unfragmented, or the first `count`
+    /// ones otherwise. Fewer clusters are returned if the FAT does not have enough.
+    fn find_unused(&mut self, count: usize) -> Vec<Cluster> {
+        let mut first = Vec::with_capacity(count);
+        let mut run: Option<(Cluster, usize)> = None;
         for (c, entry) in self.entries() {
-            if matches!(entry, FatEntry::Unused) {
-                self.write(c, FatEntry::UsedEoc)?;
-                return Ok(c);
+            if !matches!(entry, FatEntry::Unused) {
+                run = None;
+                continue;
+            }
+            if first.len() < count {
+                first.push(c);
+            }
+            let (start, len) = match run {
+                Some((start, len)) => (start, len + 1),
+                None => (c, 1),
+            };
+            if len == count {
+                return (0..count).map(|i| start.offset(i)).collect();
+            }
+            run = Some((start, len));
+        }
+        first
+    }
+
+    /// The cluster chained after `src`. If `src` is the end of the chain, `count` new clusters
+    /// are allocated (from `reservation` if given) and chained to it, with one more write to the
+    /// FAT entry of `src`. Returns the first of them.
+    pub(super) fn extend(
+        &mut self,
+        src: Cluster,
+        count: usize,
+        reservation: Option<&mut Reservation<'a, V>>,
+    ) -> Result<Cluster, Error> {
+        let root = self.root;
+        let mut free = root.fat_lock.lock();
+        match self.read_chain(src)? {
+            Some(c) => Ok(c),
+            None => {
+                let (c, _) = self.allocate_chain_unlocked(&mut free, count, reservation)?;
+                self.write(src, c.into())?;
+                Ok(c)
             }
         }
-        Err(Error::Full)
     }
 
     pub(super) fn release(&mut self, c: Cluster) -> Result<(), Error> {
-        let mut next_c = Some(c);
+        let root = self.root;
+        let mut free = root.fat_lock.lock();
+        self.release_unlocked(&mut free, c)
+    }
+
+    /// Release the clusters chained after `src`, making `src` the end of the chain.
+    pub(super) fn truncate(&mut self, src: Cluster) -> Result<(), Error> {
+        let root = self.root;
+        let mut free = root.fat_lock.lock();
+        if let Some(c) = self.read_chain(src)? {
+            self.write(src, FatEntry::UsedEoc)?;
+            self.release_unlocked(&mut free, c)?;
+        }
+        Ok(())
+    }
+
+    fn release_unlocked(&mut self, free: &mut FreeClusters, c: Cluster) -> Result<(), Error> {
+        let mut released = 0;
+        let result = self.walk_chain(c, |fat, c, entry| {
+            if !matches!(entry, FatEntry::UsedChained(_) | FatEntry::UsedEoc) {
+                return Ok(false);
+            }
+            fat.write(c, FatEntry::Unused)?;
+            released += 1;
+            Ok(true)
+        });
+        if let Some(ref mut count) = free.count {
+            *count += released;
+        }
+        match result {
+            // Every visited cluster is already released, thus the release is complete
+            Err(Error::CorruptedVolume(ChainError::Cycle)) => Ok(()),
+            result => result,
+        }
+    }
+
+    /// Walk the cluster chain starting at `start`, calling `f` with each cluster and its FAT entry.
+    /// The walk continues while `f` returns true and the FAT entry is chained.
+    /// Since a broken FAT may contain a cycle, the walk is guarded by `Visited`.
+    pub(super) fn walk_chain(
+        &mut self,
+        start: Cluster,
+        mut f: impl FnMut(&mut Self, Cluster, FatEntry) -> Result<bool, Error>,
+    ) -> Result<(), Error> {
+        let mut visited = Visited::new(self.root.bs.cluster_count());
+        let mut next_c = Some(start);
         while let Some(c) = next_c {
-            match self.read(c)? {
-                FatEntry::UsedChained(c) => next_c = Some(c),
-                FatEntry::UsedEoc => next_c = None,
-                _ => break,
+            visited.visit(c)?;
+            let entry = self.read(c)?;
+            if !f(self, c, entry)? {
+                break;
             }
-            self.write(c, FatEntry::Unused)?;
+            next_c = self.validate_chain(entry)?;
         }
         Ok(())
     }
 
     pub(super) fn read(&mut self, cluster: Cluster) -> Result<FatEntry, Error> {
         let (sector, offset) = self.entry(cluster)?;
-        Ok(u32::from_le_bytes(sector.bytes().array::<4>(offset)).into())
+        let bytes = sector
+            .bytes()
+            .try_array::<4>(offset)
+            .ok_or(Error::OutOfRange)?;
+        Ok(u32::from_le_bytes(bytes).into())
+    }
+
+    /// The cluster chained after `cluster`. A chain to a cluster out of the data area is an error.
+    pub(super) fn read_chain(&mut self, cluster: Cluster) -> Result<Option<Cluster>, Error> {
+        let entry = self.read(cluster)?;
+        self.validate_chain(entry)
+    }
+
+    fn validate_chain(&self, entry: FatEntry) -> Result<Option<Cluster>, Error> {
+        match entry.chain() {
+            Some(c) if !self.root.bs.is_cluster_available(c) => {
+                Err(Error::InvalidCluster(c.index()))
+            }
+            next => Ok(next),
+        }
     }
 
     pub(super) fn write(&mut self, cluster: Cluster, value: FatEntry) -> Result<(), Error> {
+        self.root.check_writable()?;
         let (sector, offset) = self.entry(cluster)?;
+        Self::write_at(sector, offset, value.into())
+    }
+
+    fn write_at(sector: &BufferedSectorRef, offset: usize, value: u32) -> Result<(), Error> {
         sector
             .bytes()
-            .copy_from_array::<4>(offset, u32::to_le_bytes(value.into()));
+            .try_copy_from_array::<4>(offset, u32::to_le_bytes(value))
+            .ok_or(Error::OutOfRange)?;
         sector.mark_as_dirty();
         Ok(())
     }
+
+    fn reserved_entry(&mut self, index: usize) -> Result<(&BufferedSectorRef<'a>, usize), Error> {
+        if 2 <= index {
+            Err(Error::InvalidCluster(index))?;
+        }
+        let (sector, offset) = self.root.bs.reserved_entry_location(index);
+        self.entry_at(sector, offset)
+    }
+
+    /// The raw value of the reserved entry FAT[0] or FAT[1], which is not a part of cluster
+    /// chains: FAT[0] holds the media type, and FAT[1] holds the volume flags.
+    pub(super) fn read_reserved(&mut self, index: usize) -> Result<u32, Error> {
+        let (sector, offset) = self.reserved_entry(index)?;
+        let bytes = sector
+            .bytes()
+            .try_array::<4>(offset)
+            .ok_or(Error::OutOfRange)?;
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    /// Rewrite the reserved entry FAT[0] or FAT[1] with `f`.
+    pub(super) fn update_reserved(
+        &mut self,
+        index: usize,
+        f: impl FnOnce(u32) -> u32,
+    ) -> Result<(), Error> {
+        let root = self.root;
+        let _free = root.fat_lock.lock();
+        root.check_writable()?;
+        let value = f(self.read_reserved(index)?);
+        let (sector, offset) = self.reserved_entry(index)?;
+        Self::write_at(sector, offset, value)
+    }
 }
 
 #[derive(Debug)]
@@ -179,7 +773,7 @@ impl<'f, 'a, V: Volume> Iterator for FatEntries<'f, 'a, V> {
 #[derive(Debug, Clone)]
 pub(super) struct BufferedCluster<'a, V> {
     cluster: Cluster,
-    volume: &'a BufferedVolume<V>,
+    root: &'a Root<V>,
     first_sector: Sector,
     sector_count: usize,
     sector_size: usize,
@@ -191,7 +785,7 @@ impl<'a, V: Volume> BufferedCluster<'a, V> {
         debug_assert!(index < self.sector_count);
         let sector = self.first_sector.offset(index);
         if !matches!(self.last, Some(ref r) if r.sector() == sector) {
-            self.last = Some(self.volume.sector(sector)?);
+            self.last = Some(self.root.sector(sector)?);
         }
         Ok(self.last.as_ref().unwrap())
     }
@@ -202,10 +796,9 @@ impl<'a, V: Volume> BufferedCluster<'a, V> {
         end: usize,
     ) -> impl Iterator<Item = (usize, usize, usize)> {
         debug_assert!(start <= end && end <= self.size());
-        let ss = start / self.sector_size;
-        let es = end / self.sector_size;
-        let so = start % self.sector_size;
-        let eo = end % self.sector_size;
+        let (ss, so) = Sector::containing(ByteOffset::new(start), self.sector_size);
+        let (es, eo) = Sector::containing(ByteOffset::new(end), self.sector_size);
+        let (ss, es) = (ss.index(), es.index());
         let s = self.sector_size;
         (ss..=es).filter_map(move |sector| {
             let i = if sector == ss { so } else { 0 };
@@ -222,7 +815,15 @@ impl<'a, V: Volume> BufferedCluster<'a, V> {
         self.sector_size * self.sector_count
     }
 
+    fn check_range(&self, offset: usize, len: usize) -> Result<(), Error> {
+        match offset.checked_add(len) {
+            Some(end) if end <= self.size() => Ok(()),
+            _ => Err(Error::OutOfRange),
+        }
+    }
+
     pub(super) fn read(&mut self, offset: usize, mut buf: &mut [u8]) -> Result<(), Error> {
+        self.check_range(offset, buf.len())?;
         for (sector, i, j) in self.sector_range(offset, offset + buf.len()) {
             let s = self.sector(sector)?;
             buf[0..j - i].copy_from_slice(&s.bytes()[i..j]);
@@ -231,7 +832,16 @@ impl<'a, V: Volume> BufferedCluster<'a, V> {
         Ok(())
     }
 
-    pub(super) fn write(&mut self, offset: usize, mut buf: &[u8]) -> Result<(), Error> {
+    /// Write to the cluster. The summary of the slots of the cluster is dropped, since the bytes
+    /// may not be directory entries.
+    pub(super) fn write(&mut self, offset: usize, buf: &[u8]) -> Result<(), Error> {
+        self.root.update_dir_slots(self.cluster, 0, None);
+        self.write_bytes(offset, buf)
+    }
+
+    fn write_bytes(&mut self, offset: usize, mut buf: &[u8]) -> Result<(), Error> {
+        self.root.check_writable()?;
+        self.check_range(offset, buf.len())?;
         for (sector, i, j) in self.sector_range(offset, offset + buf.len()) {
             let s = self.sector(sector)?;
             s.bytes()[i..j].copy_from_slice(&buf[0..j - i]);
@@ -241,6 +851,18 @@ impl<'a, V: Volume> BufferedCluster<'a, V> {
         Ok(())
     }
 
+    /// Fill the whole cluster with zeros. This bypasses the sector buffers, which is much cheaper
+    /// than writing zeros through them.
+    pub(super) fn fill_zeros(&mut self) -> Result<(), Error> {
+        self.root.check_writable()?;
+        self.root.update_dir_slots(self.cluster, 0, None);
+        self.last = None;
+        Ok(self
+            .root
+            .volume
+            .write_zeros(self.first_sector, self.sector_count)?)
+    }
+
     // for directory
 
     pub(super) fn dir_entries_count(&self) -> usize {
@@ -249,13 +871,48 @@ impl<'a, V: Volume> BufferedCluster<'a, V> {
 
     pub(super) fn read_dir_entry(&mut self, index: usize) -> Result<DirEntry, Error> {
         let mut buf = [0; DirEntry::SIZE];
-        self.read(index * DirEntry::SIZE, buf.as_mut())?;
-        Ok(buf.into())
+        self.read(Self::dir_entry_offset(index)?, &mut buf)?;
+        Ok(DirEntry::try_from(&buf[..])?)
     }
 
     pub(super) fn write_dir_entry(&mut self, index: usize, entry: DirEntry) -> Result<(), Error> {
         let buf: [u8; 32] = entry.into();
-        self.write(index * DirEntry::SIZE, buf.as_ref())
+        let result = self.write_bytes(Self::dir_entry_offset(index)?, buf.as_ref());
+        let written = result.is_ok().then(|| &entry);
+        self.root.update_dir_slots(self.cluster, index, written);
+        result
+    }
+
+    fn dir_entry_offset(index: usize) -> Result<usize, Error> {
+        index.checked_mul(DirEntry::SIZE).ok_or(Error::OutOfRange)
+    }
+}
+
+/// The count of unused clusters, which is unknown until the FAT is scanned for the first time.
+#[derive(Debug, Default)]
+pub(super) struct FreeClusters {
+    count: Option<usize>,
+    /// The sum of the counts of the living `Reservation`s.
+    reserved: usize,
+}
+
+/// Unused clusters set aside by `BufferedFat::reserve`. The rest of them are returned on drop.
+#[derive(Debug)]
+pub(super) struct Reservation<'a, V> {
+    root: &'a Root<V>,
+    count: usize,
+}
+
+impl<'a, V> Reservation<'a, V> {
+    /// The number of clusters left in this reservation.
+    pub(super) fn count(&self) -> usize {
+        self.count
+    }
+}
+
+impl<'a, V> Drop for Reservation<'a, V> {
+    fn drop(&mut self) {
+        self.root.fat_lock.lock().reserved -= self.count;
     }
 }
 
@@ -267,7 +924,7 @@ pub(super) struct ChainedCluster<'a, V> {
 
 impl<'a, V: Volume> ChainedCluster<'a, V> {
     fn read(&self) -> Result<Option<Cluster>, Error> {
-        Ok(self.root.fat().read(self.src)?.chain())
+        self.root.fat().read_chain(self.src)
     }
 
     pub(super) fn get(self) -> Result<Option<BufferedCluster<'a, V>>, Error> {
@@ -275,48 +932,192 @@ impl<'a, V: Volume> ChainedCluster<'a, V> {
     }
 
     pub(super) fn prepare(self) -> Result<BufferedCluster<'a, V>, Error> {
-        match self.read()? {
-            Some(c) => Ok(self.root.cluster(c)),
-            None => {
-                let c = self.root.fat().allocate()?;
-                self.root.fat().write(self.src, c.into())?;
-                Ok(self.root.cluster(c))
-            }
+        self.prepare_chain(1, None)
+    }
+
+    /// Same as `prepare`, except that `count` clusters are allocated at once (from `reservation`
+    /// if given) when `src` is the end of the chain. The rest of them are reached by
+    /// `ChainedCluster`s of the returned cluster.
+    pub(super) fn prepare_chain(
+        self,
+        count: usize,
+        reservation: Option<&mut Reservation<'a, V>>,
+    ) -> Result<BufferedCluster<'a, V>, Error> {
+        let c = self.root.fat().extend(self.src, count, reservation)?;
+        Ok(self.root.cluster(c))
+    }
+
+    /// Same as `prepare`, except that a newly allocated cluster is filled with zeros as required
+    /// for directories. Must be called while holding `Root::lock_dirs`.
+    pub(super) fn prepare_zeroed(self) -> Result<BufferedCluster<'a, V>, Error> {
+        if let Some(c) = self.read()? {
+            return Ok(self.root.cluster(c));
         }
+        let mut c = self.prepare()?;
+        c.fill_zeros()?;
+        Ok(c)
     }
 
+    /// Release the clusters chained after `src`, making `src` the end of the chain.
     pub(super) fn release(self) -> Result<(), Error> {
-        if let Some(c) = self.read()? {
-            self.root.fat().write(self.src, FatEntry::Unused)?;
-            self.root.fat().release(c)?;
+        self.root.fat().truncate(self.src)
+    }
+}
+
+/// Inconsistencies of cluster chains found by walking them.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum ChainError {
+    /// The chain reaches a cluster visited before, or is longer than the number of clusters.
+    Cycle,
+}
+
+impl fmt::Display for ChainError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Cycle => write!(f, "Cluster chain cycle detected"),
+        }
+    }
+}
+
+/// Guard against cycles in a broken FAT, which every loop following a cluster chain must pass
+/// each cluster to. The visited clusters are tracked by a bitmap, which is allocated on the
+/// second step since most chains are short. On large volumes, where the bitmap is too large, the
+/// number of steps is bounded by the number of clusters instead.
+#[derive(Debug, Clone)]
+pub(super) struct Visited {
+    bitmap: Option<Vec<u64>>,
+    steps: usize,
+    cluster_count: usize,
+    first: Option<Cluster>,
+}
+
+impl Visited {
+    pub(super) fn new(cluster_count: usize) -> Self {
+        Self {
+            bitmap: None,
+            steps: 0,
+            cluster_count,
+            first: None,
+        }
+    }
+
+    pub(super) fn visit(&mut self, c: Cluster) -> Result<(), ChainError> {
+        self.steps += 1;
+        if self.cluster_count < self.steps {
+            Err(ChainError::Cycle)?;
+        }
+        if MAX_VISITED_BITMAP_CLUSTERS < self.cluster_count {
+            return Ok(());
+        }
+        let first = match self.first {
+            Some(first) => first,
+            None => {
+                self.first = Some(c);
+                return Ok(());
+            }
+        };
+        let bitmap = self.bitmap.get_or_insert_with(|| {
+            // Cluster numbers start at 2
+            let mut bitmap = vec![0; (self.cluster_count + 2 + 63) / 64];
+            Self::insert(&mut bitmap, first);
+            bitmap
+        });
+        if !Self::insert(bitmap, c) {
+            Err(ChainError::Cycle)?;
         }
         Ok(())
     }
+
+    /// Returns `false` if `c` is already in the bitmap. Clusters out of the bitmap, which fail on
+    /// reading their FAT entries anyway, are ignored.
+    fn insert(bitmap: &mut [u64], c: Cluster) -> bool {
+        match bitmap.get_mut(c.index() / 64) {
+            Some(bits) if *bits & (1 << (c.index() % 64)) != 0 => false,
+            Some(bits) => {
+                *bits |= 1 << (c.index() % 64);
+                true
+            }
+            None => true,
+        }
+    }
+}
+
+/// Clusters of a chain, guarded by `Visited`. Each FAT entry is read when the next cluster is
+/// requested, thus clusters chained while walking are also walked.
+#[derive(Debug)]
+pub(super) struct ChainWalker<'a, V> {
+    root: &'a Root<V>,
+    start: Option<Cluster>,
+    current: Option<Cluster>,
+    visited: Visited,
+}
+
+impl<'a, V: Volume> ChainWalker<'a, V> {
+    /// Same as `Iterator::next`, but reports errors instead of terminating the iteration.
+    pub(super) fn try_next(&mut self) -> Result<Option<Cluster>, Error> {
+        let next = match (self.start.take(), core::mem::take(&mut self.current)) {
+            (Some(start), _) => start,
+            (None, Some(c)) => match self.root.fat().read_chain(c)? {
+                Some(next) => next,
+                None => return Ok(None),
+            },
+            (None, None) => return Ok(None),
+        };
+        self.visited.visit(next)?;
+        self.current = Some(next);
+        Ok(Some(next))
+    }
+
+    /// The clusters visited so far, to keep following the chain by other means.
+    pub(super) fn into_visited(self) -> Visited {
+        self.visited
+    }
+}
+
+impl<'a, V: Volume> Iterator for ChainWalker<'a, V> {
+    type Item = Result<Cluster, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.try_next().transpose()
+    }
 }
 
 #[derive(Debug)]
 pub(super) struct DirEntries<'a, V> {
     root: &'a Root<V>,
+    chain: ChainWalker<'a, V>,
     cursor: Option<(BufferedCluster<'a, V>, usize)>,
 }
 
+impl<'a, V: Volume> DirEntries<'a, V> {
+    /// Same as `Iterator::next`, but reports errors instead of terminating the iteration.
+    pub(super) fn try_next(&mut self) -> Result<Option<(Cluster, usize, DirEntry)>, Error> {
+        loop {
+            let (mut c, n) = match core::mem::take(&mut self.cursor) {
+                Some(cursor) => cursor,
+                None => return Ok(None),
+            };
+            if n < c.dir_entries_count() {
+                let cluster = c.cluster;
+                let entry = c.read_dir_entry(n)?;
+                if !matches!(entry, DirEntry::UnusedTerminal) {
+                    self.cursor = Some((c, n + 1));
+                }
+                return Ok(Some((cluster, n, entry)));
+            }
+            match self.chain.try_next()? {
+                Some(next) => self.cursor = Some((self.root.cluster(next), 0)),
+                None => return Ok(None),
+            }
+        }
+    }
+}
+
 impl<'a, V: Volume> Iterator for DirEntries<'a, V> {
     type Item = (Cluster, usize, DirEntry);
 
     fn next(&mut self) -> Option<Self::Item> {
-        let (mut c, n) = core::mem::take(&mut self.cursor)?;
-        if n < c.dir_entries_count() {
-            let cluster = c.cluster;
-            let entry = c.read_dir_entry(n).trace_err()?;
-            if !matches!(entry, DirEntry::UnusedTerminal) {
-                self.cursor = Some((c, n + 1));
-            }
-            Some((cluster, n, entry))
-        } else {
-            let fat_entry = self.root.fat().read(c.cluster).trace_err()?;
-            self.cursor = Some((self.root.cluster(fat_entry.chain()?), 0));
-            self.next()
-        }
+        self.try_next().trace_err()?
     }
 }
 
@@ -328,11 +1129,14 @@ trait ResultExt {
 impl<T, E: fmt::Display> ResultExt for Result<T, E> {
     type Result = Option<T>;
 
+    #[track_caller]
     fn trace_err(self) -> Self::Result {
         match self {
             Ok(r) => Some(r),
             Err(e) => {
-                trace!("{}", e);
+                // A broken volume can fail on every iteration, which floods the log
+                let caller = Location::caller();
+                log_rate_limited!(Level::Trace, (caller.file(), caller.line()), "{}", e);
                 None
             }
         }
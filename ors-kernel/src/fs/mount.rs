@@ -0,0 +1,618 @@
+//! Mounted file systems and the fstab-like configuration read at boot.
+//!
+//! Each line of the configuration is `<source> <mountpoint> [<options>]`, where `<source>` is
+//! a device name `vblkN`, `LABEL=<volume label>`, or `UUID=<volume id>` (such as `1234-ABCD`).
+//! Options are comma-separated: `defaults`, `rw`, `ro`, `auto`, `noauto`, and the policy of
+//! the last access date `relatime` (default), `strictatime`, or `noatime`.
+//! Text after `#` is a comment.
+//!
+//! Writable file systems are committed periodically by the kernel work queue, and the sector
+//! caches grown by bursts of I/O are trimmed back likewise.
+
+use super::fat;
+use super::volume::virtio::VirtIOBlockVolume;
+use super::volume::DynVolume;
+use crate::devices::virtio::block;
+use crate::interrupts::TIMER_FREQ;
+use crate::sync::mutex::Mutex;
+use crate::sync::spin::Spin;
+use crate::task::workqueue;
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt;
+use log::{info, trace, warn};
+
+/// Path of the configuration file in the boot volume.
+pub const FSTAB_PATH: &str = "/ors.fstab";
+
+/// The boot volume is always mounted at `/`.
+const BOOT_DEVICE: usize = 0;
+
+/// Interval in ticks of committing the writable file systems in the background.
+const COMMIT_INTERVAL: usize = 5 * TIMER_FREQ;
+
+/// Interval in ticks of trimming the sector caches of the file systems.
+const TRIM_INTERVAL: usize = 30 * TIMER_FREQ;
+
+// Mounts are never released even after unmounted, since `resolve` and `mounts` hand out
+// `&'static Mount` and their borrows of the file system may outlive the entry. Unmounted file
+// systems are closed, so that such stale references can no longer modify the volume, and their
+// sector caches are released on close so that only the `Mount` itself is leaked.
+static MOUNTS: Spin<Vec<&'static Mount>> = Spin::new(Vec::new());
+
+/// Held while mounting and unmounting, so that the checks on the current mounts still hold when
+/// the mount table is updated. Unlike `MOUNTS`, this is held across the I/O on the volumes.
+static MOUNT_LOCK: Mutex<()> = Mutex::named((), "fs.mount");
+
+/// Mount the boot volume at `/`, and then mount the file systems listed in `FSTAB_PATH`.
+/// Errors are logged and do not stop the boot.
+pub fn initialize() {
+    trace!("INITIALIZING Mounts");
+    workqueue::schedule_repeating(commit_all, 0, COMMIT_INTERVAL);
+    workqueue::schedule_repeating(trim_caches, 0, TRIM_INTERVAL);
+    let root = Entry::new(Source::Device(BOOT_DEVICE), "/", Options::default());
+    if let Err(e) = mount(&root) {
+        warn!("mount: Failed to mount the boot volume: {}", e);
+        return;
+    }
+
+    let config = match read_config() {
+        Ok(Some(config)) => config,
+        Ok(None) => {
+            trace!("mount: {} not found", FSTAB_PATH);
+            return;
+        }
+        Err(e) => {
+            warn!("mount: Failed to read {}: {}", FSTAB_PATH, e);
+            return;
+        }
+    };
+    for (line, entry) in parse_fstab(&config) {
+        match entry {
+            Ok(entry) if entry.options.noauto => {}
+            Ok(entry) => match mount(&entry) {
+                Ok(m) => info!("mount: {} mounted at {}", m.source, m.mountpoint),
+                Err(e) => warn!(
+                    "mount: {}:{}: Failed to mount {}: {}",
+                    FSTAB_PATH, line, entry, e
+                ),
+            },
+            Err(e) => warn!("mount: {}:{}: {}", FSTAB_PATH, line, e),
+        }
+    }
+}
+
+fn read_config() -> Result<Option<String>, Error> {
+    let (root, path) = resolve(FSTAB_PATH).ok_or(Error::NoParentMount)?;
+    let file = match root.fs.open(&path) {
+        Ok(file) => file,
+        Err(fat::Error::NotFound(_)) => return Ok(None),
+        Err(e) => Err(e)?,
+    };
+    let buf = match file.reader() {
+        Some(reader) => reader.read_to_end()?,
+        None => return Ok(None), // directory
+    };
+    String::from_utf8(buf)
+        .map(Some)
+        .map_err(|_| Error::InvalidEncoding)
+}
+
+/// Mount the file system described by `entry`. A missing mountpoint directory is created.
+pub fn mount(entry: &Entry) -> Result<&'static Mount, Error> {
+    let _guard = MOUNT_LOCK.lock();
+    let mountpoint = normalize_mountpoint(&entry.mountpoint)?;
+    if find(|m| m.mountpoint == mountpoint).is_some() {
+        Err(Error::DuplicateMountpoint(mountpoint.clone()))?;
+    }
+    let device = entry
+        .source
+        .resolve()
+        .ok_or_else(|| Error::DeviceNotFound(entry.source.clone()))?;
+    if find(|m| m.device == device).is_some() {
+        Err(if device == BOOT_DEVICE {
+            Error::BootVolume
+        } else {
+            Error::AlreadyMounted(device)
+        })?;
+    }
+
+    let block = block::try_list()
+        .and_then(|blocks| blocks.get(device))
+        .ok_or_else(|| Error::DeviceNotFound(entry.source.clone()))?;
+    let volume = if entry.options.read_only {
+        VirtIOBlockVolume::new(block).into_read_only()
+    } else {
+        VirtIOBlockVolume::new(block)
+    };
+    let volume = Box::new(volume) as DynVolume;
+    let fs = if entry.options.read_only {
+        fat::FileSystem::new(volume)?
+    } else {
+        let fs = fat::FileSystem::mount_rw(volume)?;
+        fs.set_access_time_policy(entry.options.atime);
+        fs
+    };
+    if fs.was_dirty() {
+        warn!(
+            "mount: {} was not cleanly unmounted, checking the volume is advisable",
+            entry.source
+        );
+    }
+    if mountpoint != "/" {
+        create_mountpoint(&mountpoint)?;
+    }
+
+    let m = Box::leak(Box::new(Mount {
+        source: entry.source.clone(),
+        device,
+        mountpoint,
+        options: entry.options,
+        fs,
+    }));
+    MOUNTS.lock().push(m);
+    Ok(m)
+}
+
+fn commit_all(_: u64) -> bool {
+    for m in mounts().into_iter().filter(|m| !m.options.read_only) {
+        if let Err(e) = m.commit() {
+            warn!("mount: Failed to commit {}: {}", m.mountpoint, e);
+        }
+    }
+    true
+}
+
+fn trim_caches(_: u64) -> bool {
+    let released = mounts().iter().map(|m| m.fs.trim_cache()).sum::<usize>();
+    if released != 0 {
+        trace!("mount: {} cached sectors released", released);
+    }
+    true
+}
+
+/// Unmount the file system at `mountpoint`. The file system is committed and its volume is
+/// marked as cleanly unmounted. Fails while the file system has open files or other file systems
+/// are mounted in it, and `/` is never unmounted except by `shutdown`.
+pub fn unmount(mountpoint: &str) -> Result<(), Error> {
+    let _guard = MOUNT_LOCK.lock();
+    let mountpoint = normalize_mountpoint(mountpoint)?;
+    if mountpoint == "/" {
+        Err(Error::Busy(mountpoint.clone()))?;
+    }
+    let m = find(|m| m.mountpoint == mountpoint).ok_or(Error::NotMounted(mountpoint))?;
+    if let Some(nested) = find(|n| {
+        n.mountpoint != m.mountpoint && relative_path(&m.mountpoint, &n.mountpoint).is_some()
+    }) {
+        Err(Error::Busy(nested.mountpoint.clone()))?;
+    }
+    m.fs.close()?;
+    MOUNTS.lock().retain(|n| !core::ptr::eq(*n, m));
+    Ok(())
+}
+
+/// Close every file system and mark its volume as cleanly unmounted, the most recently mounted
+/// first. File systems must not be modified afterwards.
+pub fn shutdown() {
+    for m in mounts().into_iter().rev() {
+        let result = match m.fs.close() {
+            // Files still open at shutdown, such as the crash dump file, are abandoned, but the
+            // volume is consistent once committed
+            Err(fat::Error::FileInUse(_)) if !m.options.read_only => m.fs.mark_clean(),
+            result => result,
+        };
+        if let Err(e) = result {
+            warn!("mount: Failed to unmount {}: {}", m.mountpoint, e);
+            // The volume is left dirty, but the changes so far are kept at least
+            if let Err(e) = m.commit() {
+                warn!("mount: Failed to commit {}: {}", m.mountpoint, e);
+            }
+        }
+    }
+}
+
+/// Create the directory at `mountpoint` and its missing parents in the file system containing it.
+fn create_mountpoint(mountpoint: &str) -> Result<(), Error> {
+    let (parent, path) = resolve(mountpoint).ok_or(Error::NoParentMount)?;
+    match parent.fs.open_dir(&path) {
+        Ok(_) => return Ok(()),
+        Err(fat::Error::NotFound(_)) => {}
+        Err(e) => Err(e)?,
+    }
+    if parent.options.read_only {
+        Err(Error::ReadOnly(parent.mountpoint.clone()))?;
+    }
+    let mut dir = parent.fs.root_dir();
+    for name in path.split('/') {
+        if !dir.contains(name)? {
+            dir.create_dir(name)?;
+        }
+        dir = dir
+            .find(name)?
+            .as_dir()
+            .ok_or_else(|| fat::Error::NotADirectory(String::from(name)))?;
+    }
+    parent.commit()?;
+    Ok(())
+}
+
+/// Current mounts in the order they were mounted.
+pub fn mounts() -> Vec<&'static Mount> {
+    MOUNTS.lock().clone()
+}
+
+fn find(f: impl Fn(&Mount) -> bool) -> Option<&'static Mount> {
+    MOUNTS.lock().iter().copied().find(|m| f(m))
+}
+
+/// Find the mount containing the absolute `path`, and get the path relative to its mountpoint.
+pub fn resolve(path: &str) -> Option<(&'static Mount, String)> {
+    let path = normalize_mountpoint(path).ok()?;
+    let mounts = MOUNTS.lock();
+    let (m, relative) = mounts
+        .iter()
+        .filter_map(|m| Some((*m, relative_path(&m.mountpoint, &path)?)))
+        .max_by_key(|(m, _)| m.mountpoint.len())?;
+    Some((m, String::from(relative)))
+}
+
+/// `path` relative to `mountpoint`, if `path` is in `mountpoint`. Both must be normalized.
+fn relative_path<'a>(mountpoint: &str, path: &'a str) -> Option<&'a str> {
+    if mountpoint == "/" {
+        return Some(&path[1..]);
+    }
+    match path.strip_prefix(mountpoint)? {
+        "" => Some(""),
+        rest => rest.strip_prefix('/'),
+    }
+}
+
+/// Resolve `.`, `..`, and empty components of the absolute path.
+fn normalize_mountpoint(path: &str) -> Result<String, Error> {
+    if !path.starts_with('/') {
+        Err(Error::NotAbsolute(String::from(path)))?;
+    }
+    let mut components = Vec::new();
+    for c in path.split('/') {
+        match c {
+            ".." => {
+                components.pop();
+            }
+            "" | "." => {}
+            c => components.push(c),
+        }
+    }
+    Ok(String::from("/") + &components.join("/"))
+}
+
+/// Parse the configuration. Each entry is paired with its line number (1-origin).
+/// Blank lines and comments are skipped.
+pub fn parse_fstab(s: &str) -> Vec<(usize, Result<Entry, Error>)> {
+    s.lines()
+        .enumerate()
+        .filter_map(|(i, line)| {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                None
+            } else {
+                Some((i + 1, line.parse()))
+            }
+        })
+        .collect()
+}
+
+#[derive(Debug)]
+pub struct Mount {
+    pub source: Source,
+    pub device: usize,
+    pub mountpoint: String,
+    pub options: Options,
+    pub fs: fat::DynFileSystem,
+}
+
+impl Mount {
+    /// Commit the changes of the file system. If the volume turns out to be resized, the file
+    /// system is revalidated against the new size.
+    pub fn commit(&self) -> Result<(), fat::Error> {
+        let result = self.fs.commit();
+        if matches!(result, Err(ref e) if e.is_resized()) && !self.fs.revalidate() {
+            warn!("mount: {} is now read-only", self.mountpoint);
+        }
+        result
+    }
+}
+
+impl fmt::Display for Mount {
+    /// Same format as the configuration, so that the output can be copied into it.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} {}", self.source, self.mountpoint, self.options)?;
+        if self.source != Source::Device(self.device) {
+            write!(f, " # {}", Source::Device(self.device))?;
+        }
+        Ok(())
+    }
+}
+
+/// An entry of the configuration.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Entry {
+    pub source: Source,
+    pub mountpoint: String,
+    pub options: Options,
+}
+
+impl Entry {
+    pub fn new(source: Source, mountpoint: impl Into<String>, options: Options) -> Self {
+        Self {
+            source,
+            mountpoint: mountpoint.into(),
+            options,
+        }
+    }
+}
+
+impl core::str::FromStr for Entry {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_whitespace().collect::<Vec<_>>()[..] {
+            [source, mountpoint] => Ok(Self::new(source.parse()?, mountpoint, Options::default())),
+            [source, mountpoint, options] => {
+                Ok(Self::new(source.parse()?, mountpoint, options.parse()?))
+            }
+            _ => Err(Error::InvalidEntry(String::from(s))),
+        }
+    }
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} {}", self.source, self.mountpoint, self.options)
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum Source {
+    Device(usize),
+    Label(String),
+    Uuid(String),
+}
+
+impl Source {
+    /// Find the index of the block device specified by this source.
+    pub fn resolve(&self) -> Option<usize> {
+        let blocks = block::try_list()?;
+        match self {
+            Self::Device(index) => Some(*index).filter(|i| *i < blocks.len()),
+            Self::Label(label) => (0..blocks.len()).find(|i| {
+                volume_label(&blocks[*i]).map_or(false, |l| l.eq_ignore_ascii_case(label))
+            }),
+            Self::Uuid(uuid) => (0..blocks.len())
+                .find(|i| volume_uuid(&blocks[*i]).map_or(false, |u| u.eq_ignore_ascii_case(uuid))),
+        }
+    }
+}
+
+impl core::str::FromStr for Source {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(label) = s.strip_prefix("LABEL=") {
+            Ok(Self::Label(String::from(label)))
+        } else if let Some(uuid) = s.strip_prefix("UUID=") {
+            Ok(Self::Uuid(String::from(uuid)))
+        } else {
+            s.strip_prefix("vblk")
+                .and_then(|n| n.parse().ok())
+                .map(Self::Device)
+                .ok_or_else(|| Error::InvalidSource(String::from(s)))
+        }
+    }
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Device(index) => write!(f, "vblk{}", index),
+            Self::Label(label) => write!(f, "LABEL={}", label),
+            Self::Uuid(uuid) => write!(f, "UUID={}", uuid),
+        }
+    }
+}
+
+fn volume_label(block: &'static block::Block) -> Option<String> {
+    let fs = fat::FileSystem::new(VirtIOBlockVolume::new(block).into_read_only()).ok()?;
+    let label = fs.boot_sector().volume_label();
+    let label = core::str::from_utf8(&label).ok()?;
+    Some(label.trim_end().to_string())
+}
+
+fn volume_uuid(block: &'static block::Block) -> Option<String> {
+    let fs = fat::FileSystem::new(VirtIOBlockVolume::new(block).into_read_only()).ok()?;
+    Some(format_volume_id(fs.boot_sector().volume_id()))
+}
+
+/// FAT volume IDs are conventionally written as `XXXX-XXXX`.
+fn format_volume_id(id: u32) -> String {
+    alloc::format!("{:04X}-{:04X}", id >> 16, id & 0xffff)
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub struct Options {
+    pub read_only: bool,
+    pub noauto: bool,
+    /// Ignored for read-only mounts, where the last access dates are never updated.
+    pub atime: fat::AccessTimePolicy,
+}
+
+impl core::str::FromStr for Options {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut options = Self::default();
+        for option in s.split(',') {
+            match option {
+                "defaults" => options = Self::default(),
+                "rw" => options.read_only = false,
+                "ro" => options.read_only = true,
+                "auto" => options.noauto = false,
+                "noauto" => options.noauto = true,
+                _ => match fat::AccessTimePolicy::from_name(option) {
+                    Some(atime) => options.atime = atime,
+                    None => Err(Error::UnknownOption(String::from(option)))?,
+                },
+            }
+        }
+        Ok(options)
+    }
+}
+
+impl fmt::Display for Options {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", if self.read_only { "ro" } else { "rw" })?;
+        if self.noauto {
+            write!(f, ",noauto")?;
+        }
+        if self.atime != fat::AccessTimePolicy::default() {
+            write!(f, ",{}", self.atime.name())?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum Error {
+    Fat(fat::Error),
+    InvalidEncoding,
+    InvalidEntry(String),
+    InvalidSource(String),
+    UnknownOption(String),
+    NotAbsolute(String),
+    DeviceNotFound(Source),
+    BootVolume,
+    AlreadyMounted(usize),
+    DuplicateMountpoint(String),
+    NotMounted(String),
+    /// The mountpoint is `/` or contains another mountpoint.
+    Busy(String),
+    NoParentMount,
+    ReadOnly(String),
+}
+
+impl From<fat::Error> for Error {
+    fn from(e: fat::Error) -> Self {
+        Self::Fat(e)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Fat(e) => write!(f, "{}", e),
+            Self::InvalidEncoding => write!(f, "Invalid UTF-8"),
+            Self::InvalidEntry(s) => write!(f, "Invalid entry: {}", s),
+            Self::InvalidSource(s) => write!(f, "Invalid source: {}", s),
+            Self::UnknownOption(s) => write!(f, "Unknown option: {}", s),
+            Self::NotAbsolute(s) => write!(f, "Mountpoint must be absolute: {}", s),
+            Self::DeviceNotFound(s) => write!(f, "Device not found: {}", s),
+            Self::BootVolume => write!(f, "The boot volume is already mounted at /"),
+            Self::AlreadyMounted(i) => write!(f, "Already mounted: {}", Source::Device(*i)),
+            Self::DuplicateMountpoint(s) => write!(f, "Mountpoint is already used: {}", s),
+            Self::NotMounted(s) => write!(f, "Not mounted: {}", s),
+            Self::Busy(s) => write!(f, "Mountpoint is busy: {}", s),
+            Self::NoParentMount => write!(f, "No file system is mounted at /"),
+            Self::ReadOnly(s) => write!(f, "Read-only file system: {}", s),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::info;
+
+    #[test_case]
+    fn test_parse_fstab() {
+        info!("TESTING fs::mount::test_parse_fstab");
+        let entries = parse_fstab(
+            "# comment\n\
+             vblk1 /data\n\
+             \n\
+             LABEL=BACKUP /mnt/backup ro,noauto # trailing comment\n\
+             UUID=1234-ABCD /mnt/x defaults,ro\n\
+             vblk /a\n\
+             vblk2 /b rw,sync\n\
+             vblk2\n",
+        );
+        assert_eq!(
+            entries,
+            [
+                (
+                    2,
+                    Ok(Entry::new(Source::Device(1), "/data", Options::default()))
+                ),
+                (
+                    4,
+                    Ok(Entry::new(
+                        Source::Label(String::from("BACKUP")),
+                        "/mnt/backup",
+                        Options {
+                            read_only: true,
+                            noauto: true,
+                            ..Options::default()
+                        }
+                    ))
+                ),
+                (
+                    5,
+                    Ok(Entry::new(
+                        Source::Uuid(String::from("1234-ABCD")),
+                        "/mnt/x",
+                        Options {
+                            read_only: true,
+                            noauto: false,
+                            ..Options::default()
+                        }
+                    ))
+                ),
+                (6, Err(Error::InvalidSource(String::from("vblk")))),
+                (7, Err(Error::UnknownOption(String::from("sync")))),
+                (8, Err(Error::InvalidEntry(String::from("vblk2")))),
+            ]
+        );
+
+        let entry = entries[1].1.clone().unwrap();
+        assert_eq!(entry.to_string(), "LABEL=BACKUP /mnt/backup ro,noauto");
+        assert_eq!(entry.to_string().parse(), Ok(entry));
+        assert_eq!(format_volume_id(0x1234abcd), "1234-ABCD");
+
+        let options = "rw,noatime".parse::<Options>().unwrap();
+        assert_eq!(options.atime, fat::AccessTimePolicy::Never);
+        assert_eq!(options.to_string(), "rw,noatime");
+        assert_eq!(options.to_string().parse(), Ok(options));
+        assert_eq!("relatime".parse::<Options>(), Ok(Options::default()));
+        assert_eq!(Options::default().to_string(), "rw");
+    }
+
+    #[test_case]
+    fn test_mountpoint() {
+        info!("TESTING fs::mount::test_mountpoint");
+        assert_eq!(normalize_mountpoint("/"), Ok(String::from("/")));
+        assert_eq!(
+            normalize_mountpoint("/a//b/./c/../"),
+            Ok(String::from("/a/b"))
+        );
+        assert_eq!(
+            normalize_mountpoint("a/b"),
+            Err(Error::NotAbsolute(String::from("a/b")))
+        );
+
+        assert_eq!(relative_path("/", "/"), Some(""));
+        assert_eq!(relative_path("/", "/a/b"), Some("a/b"));
+        assert_eq!(relative_path("/a", "/a"), Some(""));
+        assert_eq!(relative_path("/a", "/a/b"), Some("b"));
+        assert_eq!(relative_path("/a", "/ab"), None);
+        assert_eq!(relative_path("/a/b", "/a"), None);
+    }
+}
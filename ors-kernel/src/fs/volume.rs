@@ -1,5 +1,6 @@
 use crate::sync::mutex::{Mutex, MutexGuard};
 use crate::sync::spin::Spin;
+use alloc::boxed::Box;
 use alloc::collections::VecDeque;
 use alloc::sync::Arc;
 use alloc::vec;
@@ -7,8 +8,11 @@ use alloc::vec::Vec;
 use core::fmt;
 use core::mem::ManuallyDrop;
 use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use derive_new::new;
+use log::warn;
 
+pub mod mem;
 pub mod virtio;
 
 /// A unit of volume read/write.
@@ -28,7 +32,31 @@ impl Sector {
         Self(self.0 + s)
     }
 
-    pub const INVALID: Self = Self(usize::MAX);
+    /// Same as `offset` except that the overflow is reported as an `OutOfRange` error.
+    pub fn checked_offset(self, s: usize) -> Result<Self, VolumeError> {
+        match self.0.checked_add(s) {
+            Some(index) => Ok(Self(index)),
+            None => Err(VolumeError::new(self, VolumeErrorKind::OutOfRange)),
+        }
+    }
+
+    /// Get the sector containing the byte at `offset` and the byte offset within that sector.
+    pub fn containing(offset: ByteOffset, sector_size: usize) -> (Self, usize) {
+        debug_assert_ne!(sector_size, 0);
+        (Self(offset.0 / sector_size), offset.0 % sector_size)
+    }
+
+    /// Number of sectors required to hold `bytes` bytes.
+    pub fn count_for_bytes(bytes: usize, sector_size: usize) -> Result<SectorCount, Overflow> {
+        debug_assert_ne!(sector_size, 0);
+        let bytes = bytes.checked_add(sector_size - 1).ok_or(Overflow)?;
+        Ok(SectorCount(bytes / sector_size))
+    }
+
+    /// Byte offset of the beginning of this sector.
+    pub fn byte_offset(self, sector_size: usize) -> Result<ByteOffset, Overflow> {
+        Ok(ByteOffset(self.0.checked_mul(sector_size).ok_or(Overflow)?))
+    }
 }
 
 impl fmt::Display for Sector {
@@ -37,12 +65,157 @@ impl fmt::Display for Sector {
     }
 }
 
+/// A position in bytes, such as in a volume or in a cluster. This is distinguished from sector
+/// and cluster numbers, which are converted from and into this only with the sizes of them.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy, Hash, Default)]
+pub struct ByteOffset(usize);
+
+impl ByteOffset {
+    pub fn new(bytes: usize) -> Self {
+        Self(bytes)
+    }
+
+    pub fn get(self) -> usize {
+        self.0
+    }
+
+    pub fn checked_add(self, bytes: usize) -> Result<Self, Overflow> {
+        Ok(Self(self.0.checked_add(bytes).ok_or(Overflow)?))
+    }
+}
+
+/// A number of sectors.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy, Hash, Default)]
+pub struct SectorCount(usize);
+
+impl SectorCount {
+    pub fn new(count: usize) -> Self {
+        Self(count)
+    }
+
+    pub fn get(self) -> usize {
+        self.0
+    }
+
+    /// Number of bytes of the sectors.
+    pub fn bytes(self, sector_size: usize) -> Result<usize, Overflow> {
+        self.0.checked_mul(sector_size).ok_or(Overflow)
+    }
+}
+
+/// A conversion between bytes, sectors, and clusters does not fit in `usize`.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct Overflow;
+
+impl fmt::Display for Overflow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Size overflow")
+    }
+}
+
 /// Storage area used by the file system.
 pub trait Volume {
     fn sector_count(&self) -> usize;
     fn sector_size(&self) -> usize;
     fn read(&self, sector: Sector, buf: &mut [u8]) -> Result<(), VolumeError>;
     fn write(&self, sector: Sector, buf: &[u8]) -> Result<(), VolumeError>;
+
+    /// Fill `count` sectors from `sector` with zeros. Volumes that can do this without
+    /// transferring zeros should override this.
+    fn write_zeros(&self, sector: Sector, count: usize) -> Result<(), VolumeError> {
+        let buf = vec![0; self.sector_size()];
+        for i in 0..count {
+            self.write(sector.checked_offset(i)?, &buf)?;
+        }
+        Ok(())
+    }
+
+    /// Write the sectors, returning the result of each write in order. Volumes that can submit
+    /// writes together should override this.
+    fn write_batch(&self, writes: &[(Sector, &[u8])]) -> Vec<Result<(), VolumeError>> {
+        writes
+            .iter()
+            .map(|(sector, buf)| self.write(*sector, buf))
+            .collect()
+    }
+
+    /// A counter that changes whenever the geometry of this volume, such as `sector_count`,
+    /// changes. Volumes that are never resized can leave this as is.
+    fn generation(&self) -> u64 {
+        0
+    }
+}
+
+/// A volume of any type, for file systems that are stored together regardless of the volume type.
+pub type DynVolume = Box<dyn Volume + Send + Sync>;
+
+impl<V: Volume + ?Sized> Volume for Box<V> {
+    fn sector_count(&self) -> usize {
+        (**self).sector_count()
+    }
+
+    fn sector_size(&self) -> usize {
+        (**self).sector_size()
+    }
+
+    fn read(&self, sector: Sector, buf: &mut [u8]) -> Result<(), VolumeError> {
+        (**self).read(sector, buf)
+    }
+
+    fn write(&self, sector: Sector, buf: &[u8]) -> Result<(), VolumeError> {
+        (**self).write(sector, buf)
+    }
+
+    fn write_zeros(&self, sector: Sector, count: usize) -> Result<(), VolumeError> {
+        (**self).write_zeros(sector, count)
+    }
+
+    fn write_batch(&self, writes: &[(Sector, &[u8])]) -> Vec<Result<(), VolumeError>> {
+        (**self).write_batch(writes)
+    }
+
+    fn generation(&self) -> u64 {
+        (**self).generation()
+    }
+}
+
+impl<V: Volume + ?Sized> Volume for &V {
+    fn sector_count(&self) -> usize {
+        (**self).sector_count()
+    }
+
+    fn sector_size(&self) -> usize {
+        (**self).sector_size()
+    }
+
+    fn read(&self, sector: Sector, buf: &mut [u8]) -> Result<(), VolumeError> {
+        (**self).read(sector, buf)
+    }
+
+    fn write(&self, sector: Sector, buf: &[u8]) -> Result<(), VolumeError> {
+        (**self).write(sector, buf)
+    }
+
+    fn write_zeros(&self, sector: Sector, count: usize) -> Result<(), VolumeError> {
+        (**self).write_zeros(sector, count)
+    }
+
+    fn write_batch(&self, writes: &[(Sector, &[u8])]) -> Vec<Result<(), VolumeError>> {
+        (**self).write_batch(writes)
+    }
+
+    fn generation(&self) -> u64 {
+        (**self).generation()
+    }
+}
+
+impl fmt::Debug for dyn Volume + Send + Sync {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("dyn Volume")
+            .field("sector_count", &self.sector_count())
+            .field("sector_size", &self.sector_size())
+            .finish()
+    }
 }
 
 /// Error during volume operations.
@@ -57,6 +230,8 @@ impl fmt::Display for VolumeError {
         match self.kind {
             VolumeErrorKind::Io => write!(f, "I/O error")?,
             VolumeErrorKind::OutOfRange => write!(f, "Out of range")?,
+            VolumeErrorKind::ReadOnly => write!(f, "Read-only volume")?,
+            VolumeErrorKind::Resized => write!(f, "Out of the resized volume")?,
             VolumeErrorKind::Unknown => write!(f, "Unknown error")?,
         }
         write!(f, " at sector={}", self.sector)
@@ -67,6 +242,9 @@ impl fmt::Display for VolumeError {
 pub enum VolumeErrorKind {
     Io,
     OutOfRange,
+    ReadOnly,
+    /// The sector is beyond the end of the volume since it was resized.
+    Resized,
     Unknown,
 }
 
@@ -75,23 +253,122 @@ pub enum VolumeErrorKind {
 pub struct BufferedVolume<V> {
     volume: V,
     sectors: Spin<BufferedSectors>,
+    counters: CacheCounters,
+    /// The generation of the volume that the buffered sectors are validated against.
+    generation: AtomicU64,
+    /// Sectors from this index are rejected as `VolumeErrorKind::Resized`. There is no bound
+    /// until the volume is resized.
+    bound: AtomicUsize,
+}
+
+/// Counters of a `BufferedVolume` since it is created.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub struct CacheStats {
+    /// Sector lookups served from the buffered sectors.
+    pub hits: u64,
+    pub misses: u64,
+    /// Sectors read from the underlying volume.
+    pub sectors_read: u64,
+    /// Sectors written back to the underlying volume.
+    pub sectors_written: u64,
+}
+
+#[derive(Debug, Default)]
+struct CacheCounters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    sectors_read: AtomicU64,
+    sectors_written: AtomicU64,
+}
+
+/// The underlying volume of a `BufferedVolume`, counting the sectors read and written.
+struct CountingVolume<'a, V> {
+    volume: &'a V,
+    counters: &'a CacheCounters,
+}
+
+impl<'a, V: Volume> CountingVolume<'a, V> {
+    fn count(&self, counter: &AtomicU64, len: usize) {
+        // The length of a buffer never overflows since it is smaller than isize::MAX
+        let sectors =
+            Sector::count_for_bytes(len, self.volume.sector_size()).map_or(0, |c| c.get());
+        counter.fetch_add(sectors as u64, Ordering::Relaxed);
+    }
+}
+
+impl<'a, V: Volume> Volume for CountingVolume<'a, V> {
+    fn sector_count(&self) -> usize {
+        self.volume.sector_count()
+    }
+
+    fn sector_size(&self) -> usize {
+        self.volume.sector_size()
+    }
+
+    fn read(&self, sector: Sector, buf: &mut [u8]) -> Result<(), VolumeError> {
+        self.count(&self.counters.sectors_read, buf.len());
+        self.volume.read(sector, buf)
+    }
+
+    fn write(&self, sector: Sector, buf: &[u8]) -> Result<(), VolumeError> {
+        self.count(&self.counters.sectors_written, buf.len());
+        self.volume.write(sector, buf)
+    }
+
+    fn write_zeros(&self, sector: Sector, count: usize) -> Result<(), VolumeError> {
+        let counter = &self.counters.sectors_written;
+        counter.fetch_add(count as u64, Ordering::Relaxed);
+        self.volume.write_zeros(sector, count)
+    }
+
+    fn write_batch(&self, writes: &[(Sector, &[u8])]) -> Vec<Result<(), VolumeError>> {
+        for (_, buf) in writes {
+            self.count(&self.counters.sectors_written, buf.len());
+        }
+        self.volume.write_batch(writes)
+    }
+
+    fn generation(&self) -> u64 {
+        self.volume.generation()
+    }
 }
 
 impl<V> BufferedVolume<V> {
     const EXPECTED_CACHE_SIZE: usize = 8;
 
+    pub fn stats(&self) -> CacheStats {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        CacheStats {
+            hits: load(&self.counters.hits),
+            misses: load(&self.counters.misses),
+            sectors_read: load(&self.counters.sectors_read),
+            sectors_written: load(&self.counters.sectors_written),
+        }
+    }
+
+    fn counting(&self) -> CountingVolume<V> {
+        CountingVolume {
+            volume: &self.volume,
+            counters: &self.counters,
+        }
+    }
+}
+
+impl<V: Volume> BufferedVolume<V> {
     pub fn new(volume: V) -> Self {
+        let generation = volume.generation();
         Self {
             volume,
             sectors: Spin::new(BufferedSectors {
                 lent: Vec::with_capacity(8),
                 cached: VecDeque::with_capacity(Self::EXPECTED_CACHE_SIZE),
             }),
+            counters: CacheCounters::default(),
+            generation: AtomicU64::new(generation),
+            bound: AtomicUsize::new(usize::MAX),
         }
     }
-}
 
-impl<V: Volume> BufferedVolume<V> {
     pub fn sector_count(&self) -> usize {
         self.volume.sector_count()
     }
@@ -103,17 +380,29 @@ impl<V: Volume> BufferedVolume<V> {
     pub fn sector(&self, sector: Sector) -> Result<BufferedSectorRef, VolumeError> {
         // NOTE: How can we optimize reading and writing of consecutive sectors?
 
+        self.check_bound(sector, 1)?;
         let mut sectors = self.sectors.lock();
 
-        if let Some(s) = sectors.lent.iter().find(|s| s.sector() == sector) {
-            let r = BufferedSectorRef::new(&self.sectors, s);
+        if let Some(index) = sectors
+            .lent
+            .iter()
+            .position(|l| l.sector.sector() == sector)
+        {
+            let r = BufferedSectorRef::new(&self.sectors, sectors.share(index));
             drop(sectors);
+            self.counters.hits.fetch_add(1, Ordering::Relaxed);
             // This is necessary since the first initialize happens after drop(sectors) at (*1)
-            r.initialize(&self.volume)?;
+            r.initialize(&self.counting())?;
             return Ok(r);
         }
 
-        let s = match sectors.cached.iter().position(|s| s.sector() == sector) {
+        let position = sectors.cached.iter().position(|s| s.sector() == sector);
+        let counter = match position {
+            Some(_) => &self.counters.hits,
+            None => &self.counters.misses,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        let s = match position {
             // Found a cached BufferedSector, use it
             Some(index) => sectors.cached.remove(index).unwrap(),
             // Recycle the least recently used BufferedSector
@@ -126,34 +415,249 @@ impl<V: Volume> BufferedVolume<V> {
             // Create a new BufferedSector
             None => Arc::new(BufferedSector::new(sector, &self.volume)),
         };
-        let r = BufferedSectorRef::new(&self.sectors, &s);
-        sectors.lent.push(s);
+        let r = BufferedSectorRef::new(&self.sectors, sectors.lend(s));
         drop(sectors); // (*1)
 
         // This must happen after drop(sectors) to perform (blocking) volume reading/writing
-        r.initialize(&self.volume)?;
+        r.initialize(&self.counting())?;
         Ok(r)
     }
 
+    /// Fill `count` sectors from `sector` with zeros. Unlike writing through `sector`, this
+    /// bypasses the buffering: cached sectors in the range are dropped without being written back,
+    /// and the underlying volume is zero-filled at once.
+    pub fn write_zeros(&self, sector: Sector, count: usize) -> Result<(), VolumeError> {
+        let end = sector.checked_offset(count)?;
+        self.check_bound(sector, count)?;
+        let in_range = |s: &Arc<BufferedSector>| sector <= s.sector() && s.sector() < end;
+
+        let mut sectors = self.sectors.lock();
+        let lent = sectors.share_if(in_range);
+        let mut dropped = Vec::new();
+        sectors.cached.retain(|s| {
+            if in_range(s) {
+                dropped.push(Arc::clone(s));
+            }
+            !in_range(s)
+        });
+        drop(sectors);
+        let lent = lent
+            .into_iter()
+            .map(|s| BufferedSectorRef::new(&self.sectors, s))
+            .collect::<Vec<_>>();
+
+        // A BufferedSector may still hold the dirty bytes of the sector before recycling, so
+        // every BufferedSector in the range is zero-filled through BufferedSectorData. Lent ones
+        // remain usable as zeroed sectors.
+        for s in lent.iter().map(|r| &*r.sector).chain(dropped.iter()) {
+            s.data.lock().fill_zeros(s.sector, &self.counting())?;
+        }
+        self.counting().write_zeros(sector, count)
+    }
+
+    /// Write back the dirty cached sectors. They are written by a single batch in the order of
+    /// sectors.
     pub fn commit(&self) -> Result<(), VolumeError> {
-        let sectors = self.sectors.lock();
-        // This temporary Vec is necessary since the cached sectors must be uniquely owned by BufferedVolume.
-        let cached = sectors.cached.iter().map(|s| s.sector).collect::<Vec<_>>();
+        self.revalidate();
+
+        // The cached sectors are lent during the commit, since they must be uniquely owned by
+        // BufferedVolume while cached
+        let mut sectors = self.sectors.lock();
+        let mut refs = Vec::with_capacity(sectors.cached.len());
+        while let Some(s) = sectors.cached.pop_front() {
+            let s = sectors.lend(s);
+            refs.push(BufferedSectorRef::new(&self.sectors, s));
+        }
         drop(sectors);
 
-        for s in cached {
-            self.sector(s)?.commit(&self.volume)?;
+        let mut order = (0..refs.len()).collect::<Vec<_>>();
+        order.sort_by_key(|i| refs[*i].sector());
+        let mut result = Ok(());
+        let mut data = Vec::with_capacity(order.len());
+        for i in order {
+            let mut d = refs[i].data.lock();
+            match d.sector {
+                _ if !d.is_dirty => {}
+                Some(_) => data.push(d),
+                // Written after the sector is invalidated by a resize
+                None => {
+                    d.is_dirty = false;
+                    let e = VolumeError::new(refs[i].sector(), VolumeErrorKind::Resized);
+                    result = result.and(Err(e));
+                }
+            }
+        }
+        let writes = data
+            .iter()
+            .map(|d| (d.sector.unwrap(), d.bytes.as_ref()))
+            .collect::<Vec<_>>();
+        let results = self.counting().write_batch(&writes);
+        drop(writes);
+
+        for (d, r) in data.iter_mut().zip(results) {
+            match r {
+                Ok(()) => d.is_dirty = false,
+                Err(e) if result.is_ok() => result = Err(e),
+                Err(_) => {}
+            }
+        }
+        drop(data);
+        // Returning the sectors in the reverse order keeps the order of recent use
+        while let Some(r) = refs.pop() {
+            drop(r);
+        }
+        result
+    }
+
+    /// Release the clean cached sectors exceeding the expected cache size, least recently used
+    /// first. The cache grows beyond it while many sectors are lent at once. Dirty sectors are
+    /// kept until they are committed. Returns the number of released sectors.
+    pub fn trim(&self) -> usize {
+        let mut sectors = self.sectors.lock();
+        let mut released = Vec::new();
+        let mut i = sectors.cached.len();
+        while 0 < i && Self::EXPECTED_CACHE_SIZE < sectors.cached.len() {
+            i -= 1;
+            let s = Arc::get_mut(&mut sectors.cached[i]).unwrap();
+            if !s.data.get_mut().is_dirty {
+                released.push(sectors.cached.remove(i).unwrap());
+            }
+        }
+        drop(sectors);
+        released.len()
+    }
+
+    /// Release the clean cached sectors in the `count` sectors from `sector`, so that they are
+    /// read from the volume again and writes made directly to the volume are seen. Dirty sectors
+    /// and sectors lent at the moment are kept. Returns the number of released sectors.
+    pub fn evict(&self, sector: Sector, count: usize) -> usize {
+        let end = sector.offset(count);
+        let mut sectors = self.sectors.lock();
+        let mut released = Vec::new();
+        let mut i = sectors.cached.len();
+        while 0 < i {
+            i -= 1;
+            let s = Arc::get_mut(&mut sectors.cached[i]).unwrap();
+            if sector <= s.sector && s.sector < end && !s.data.get_mut().is_dirty {
+                released.push(sectors.cached.remove(i).unwrap());
+            }
+        }
+        drop(sectors);
+        released.len()
+    }
+
+    /// Check that the `count` sectors from `sector` are in the volume, if the volume has been
+    /// resized since it is buffered.
+    fn check_bound(&self, sector: Sector, count: usize) -> Result<(), VolumeError> {
+        self.revalidate();
+        let end = sector.checked_offset(count)?;
+        if self.bound.load(Ordering::Acquire) < end.index() {
+            Err(VolumeError::new(sector, VolumeErrorKind::Resized))?;
         }
         Ok(())
     }
+
+    /// Follow the change of the volume geometry. Buffered sectors beyond the new end of the
+    /// volume are invalidated, and their unwritten changes are discarded.
+    fn revalidate(&self) {
+        let generation = self.volume.generation();
+        if generation == self.generation.load(Ordering::Acquire) {
+            return;
+        }
+
+        let mut sectors = self.sectors.lock();
+        // Another task may have followed the change in the meantime
+        if self.generation.swap(generation, Ordering::AcqRel) == generation {
+            return;
+        }
+        let count = self.volume.sector_count();
+        self.bound.store(count, Ordering::Release);
+        let beyond = |s: &Arc<BufferedSector>| count <= s.sector().index();
+        let lent = sectors.share_if(beyond);
+        let mut dropped = 0;
+        sectors.cached.retain(|s| {
+            dropped += beyond(s) as usize;
+            !beyond(s)
+        });
+        drop(sectors);
+        let lent = lent
+            .into_iter()
+            .map(|s| BufferedSectorRef::new(&self.sectors, s))
+            .collect::<Vec<_>>();
+
+        for s in lent.iter() {
+            s.data.lock().invalidate();
+        }
+        warn!(
+            "volume: Resized to {} sectors, {} buffered sectors beyond the end are invalidated",
+            count,
+            lent.len() + dropped
+        );
+    }
 }
 
+/// Every `Arc<BufferedSector>` outside of `BufferedSectors` is owned by a `BufferedSectorRef`
+/// and counted by `LentSector::count`, so that a sector is moved to `cached` exactly when the
+/// last `BufferedSectorRef` is dropped. The counts are never inferred from `Arc::strong_count`,
+/// which also counts the references being created or dropped concurrently.
 #[derive(Debug)]
 struct BufferedSectors {
-    lent: Vec<Arc<BufferedSector>>,        // shared
+    lent: Vec<LentSector>,                 // shared
     cached: VecDeque<Arc<BufferedSector>>, // uniquely owned
 }
 
+#[derive(Debug)]
+struct LentSector {
+    sector: Arc<BufferedSector>,
+    /// The number of `BufferedSectorRef`s of this sector.
+    count: usize,
+}
+
+impl BufferedSectors {
+    /// Start lending `sector`, which must be uniquely owned. The returned reference must be
+    /// owned by a `BufferedSectorRef`.
+    fn lend(&mut self, sector: Arc<BufferedSector>) -> Arc<BufferedSector> {
+        let r = Arc::clone(&sector);
+        self.lent.push(LentSector { sector, count: 1 });
+        r
+    }
+
+    /// Add a reference to `lent[index]`. The returned reference must be owned by a
+    /// `BufferedSectorRef`.
+    fn share(&mut self, index: usize) -> Arc<BufferedSector> {
+        let l = &mut self.lent[index];
+        l.count += 1;
+        Arc::clone(&l.sector)
+    }
+
+    fn share_if(&mut self, f: impl Fn(&Arc<BufferedSector>) -> bool) -> Vec<Arc<BufferedSector>> {
+        (0..self.lent.len())
+            .filter(|i| f(&self.lent[*i].sector))
+            .collect::<Vec<_>>()
+            .into_iter()
+            .map(|i| self.share(i))
+            .collect()
+    }
+
+    fn position(&self, sector: *const BufferedSector) -> usize {
+        self.lent
+            .iter()
+            .position(|l| Arc::as_ptr(&l.sector) == sector)
+            .expect("BufferedSectorRef of a sector not lent")
+    }
+
+    /// Remove a reference of `lent[index]`. The sector is moved to the front of `cached` when the
+    /// last reference is returned.
+    fn give_back(&mut self, index: usize) {
+        let l = &mut self.lent[index];
+        l.count -= 1;
+        if l.count == 0 {
+            let l = self.lent.swap_remove(index);
+            self.cached.push_front(l.sector);
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct BufferedSector {
     sector: Sector,
@@ -164,11 +668,14 @@ impl BufferedSector {
     fn new(sector: Sector, volume: &impl Volume) -> Self {
         Self {
             sector,
-            data: Mutex::new(BufferedSectorData {
-                sector: None,
-                is_dirty: false,
-                bytes: vec![0; volume.sector_size()],
-            }),
+            data: Mutex::named(
+                BufferedSectorData {
+                    sector: None,
+                    is_dirty: false,
+                    bytes: vec![0; volume.sector_size()],
+                },
+                "fs.sector",
+            ),
         }
     }
 
@@ -180,10 +687,6 @@ impl BufferedSector {
         self.data.lock().initialize(self.sector, volume)
     }
 
-    fn commit(&self, volume: &impl Volume) -> Result<(), VolumeError> {
-        self.data.lock().commit(volume)
-    }
-
     pub fn sector(&self) -> Sector {
         self.sector
     }
@@ -218,13 +721,35 @@ impl BufferedSectorData {
         Ok(())
     }
 
+    /// Make this a clean copy of `sector` that is filled with zeros.
+    fn fill_zeros(&mut self, sector: Sector, volume: &impl Volume) -> Result<(), VolumeError> {
+        if self.sector != Some(sector) {
+            self.commit(volume)?;
+        }
+        self.bytes.fill(0);
+        self.sector = Some(sector);
+        self.is_dirty = false;
+        Ok(())
+    }
+
     fn commit(&mut self, volume: &impl Volume) -> Result<(), VolumeError> {
-        if self.is_dirty {
-            volume.write(self.sector.unwrap(), self.bytes.as_ref())?;
-            self.is_dirty = false;
+        match self.sector {
+            Some(sector) if self.is_dirty => {
+                volume.write(sector, self.bytes.as_ref())?;
+                self.is_dirty = false;
+            }
+            // Changes to an invalidated sector have nowhere to be written
+            None => self.is_dirty = false,
+            Some(_) => {}
         }
         Ok(())
     }
+
+    /// Forget the contents, which are read again on the next `initialize`.
+    fn invalidate(&mut self) {
+        self.sector = None;
+        self.is_dirty = false;
+    }
 }
 
 impl Deref for BufferedSectorData {
@@ -248,38 +773,37 @@ pub struct BufferedSectorRef<'a> {
 }
 
 impl<'a> BufferedSectorRef<'a> {
-    fn new(sectors: &'a Spin<BufferedSectors>, sector: &Arc<BufferedSector>) -> Self {
+    /// `sector` must be a reference counted by `BufferedSectors::lend` or `share`.
+    fn new(sectors: &'a Spin<BufferedSectors>, sector: Arc<BufferedSector>) -> Self {
         Self {
             sectors,
-            sector: ManuallyDrop::new(Arc::clone(sector)),
+            sector: ManuallyDrop::new(sector),
         }
     }
 }
 
 impl<'a> Clone for BufferedSectorRef<'a> {
     fn clone(&self) -> Self {
-        Self::new(self.sectors, &self.sector)
+        let mut sectors = self.sectors.lock();
+        let index = sectors.position(Arc::as_ptr(&self.sector));
+        let sector = sectors.share(index);
+        drop(sectors);
+        Self::new(self.sectors, sector)
     }
 }
 
 impl<'a> Drop for BufferedSectorRef<'a> {
     fn drop(&mut self) {
-        let mut sectors = self.sectors.lock();
+        // The reference is dropped before taking the lock. This is never the last one since the
+        // sector stays in sectors.lent until the count reaches zero, and once it reaches zero,
+        // the sector moved to sectors.cached is uniquely owned.
         let sector = unsafe { ManuallyDrop::take(&mut self.sector) };
+        let ptr = Arc::as_ptr(&sector);
+        drop(sector);
 
-        // This is the last owner except sectors.lent
-        if Arc::strong_count(&sector) == 2 {
-            let index = sectors
-                .lent
-                .iter()
-                .position(|s| s.sector() == sector.sector())
-                .unwrap();
-            drop(sector); // 2 -> 1
-
-            // Move this BufferedSector from sectors.lent to the front of sectors.cached
-            let sector = sectors.lent.swap_remove(index);
-            sectors.cached.push_front(sector);
-        }
+        let mut sectors = self.sectors.lock();
+        let index = sectors.position(ptr);
+        sectors.give_back(index);
     }
 }
 
@@ -290,3 +814,429 @@ impl<'a> Deref for BufferedSectorRef<'a> {
         &self.sector
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::mem::MemVolume;
+    use super::*;
+    use crate::task;
+    use core::sync::atomic::AtomicBool;
+    use log::info;
+
+    #[test_case]
+    fn test_sector_conversion() {
+        info!("TESTING fs::volume::test_sector_conversion");
+        // Compare with the inline arithmetic replaced by the helpers, including the edge of usize
+        let near_max = |sector_size: usize| usize::MAX - 2 * sector_size..=usize::MAX;
+        for sector_size in (512..=4096).step_by(512) {
+            let samples = (0..sector_size * 8)
+                .step_by(97)
+                .chain(near_max(sector_size));
+            for bytes in samples {
+                let (sector, offset) = Sector::containing(ByteOffset::new(bytes), sector_size);
+                assert_eq!(sector.index(), bytes / sector_size);
+                assert_eq!(offset, bytes % sector_size);
+                assert_eq!(
+                    sector.byte_offset(sector_size),
+                    Ok(ByteOffset::new(bytes - offset))
+                );
+                match bytes.checked_add(sector_size - 1) {
+                    Some(b) => {
+                        let count = Sector::count_for_bytes(bytes, sector_size).unwrap();
+                        assert_eq!(count.get(), b / sector_size);
+                        assert_eq!(
+                            count.bytes(sector_size).ok(),
+                            count.get().checked_mul(sector_size)
+                        );
+                        assert!(bytes <= count.bytes(sector_size).unwrap_or(usize::MAX));
+                    }
+                    None => assert_eq!(Sector::count_for_bytes(bytes, sector_size), Err(Overflow)),
+                }
+            }
+        }
+        assert!(Sector::from_index(usize::MAX).checked_offset(1).is_err());
+        assert_eq!(
+            Sector::from_index(3).checked_offset(4),
+            Ok(Sector::from_index(7))
+        );
+        assert_eq!(
+            Sector::from_index(usize::MAX).byte_offset(512),
+            Err(Overflow)
+        );
+        assert_eq!(ByteOffset::new(usize::MAX).checked_add(1), Err(Overflow));
+        assert_eq!(SectorCount::new(usize::MAX).bytes(512), Err(Overflow));
+    }
+
+    #[test_case]
+    fn test_write_zeros() {
+        info!("TESTING fs::volume::test_write_zeros");
+        let s = Sector::from_index;
+        let inner = MemVolume::new(512, 16);
+        for i in 0..16 {
+            inner.write(s(i), &[0xff; 512]).unwrap();
+        }
+        let volume = BufferedVolume::new(&inner);
+        let is_zeroed = |sector: &BufferedSectorRef| sector.bytes().iter().all(|b| *b == 0);
+
+        // Lent, cached (dirty) and cached (clean, out of the range) sectors
+        let lent = volume.sector(s(2)).unwrap();
+        lent.bytes()[0] = 1;
+        lent.mark_as_dirty();
+        let cached = volume.sector(s(3)).unwrap();
+        cached.bytes()[0] = 1;
+        cached.mark_as_dirty();
+        drop(cached);
+        drop(volume.sector(s(5)).unwrap());
+
+        let written = volume.stats().sectors_written;
+        volume.write_zeros(s(2), 3).unwrap();
+        assert_eq!(volume.stats().sectors_written, written + 3);
+        assert!(is_zeroed(&lent) && !lent.is_dirty());
+        drop(lent);
+        for i in 2..5 {
+            assert!(is_zeroed(&volume.sector(s(i)).unwrap()));
+        }
+        assert!(!is_zeroed(&volume.sector(s(5)).unwrap()));
+        assert!(volume.write_zeros(s(15), 2).is_err());
+
+        // The discarded writes are not written back
+        volume.commit().unwrap();
+        let mut buf = [0; 512];
+        for i in 0..16 {
+            inner.read(s(i), &mut buf).unwrap();
+            let expected = if (2..5).contains(&i) { 0 } else { 0xff };
+            assert!(buf.iter().all(|b| *b == expected), "sector {}", i);
+        }
+    }
+
+    /// A volume recording the sectors of each batch, which fails writes to sector 7.
+    struct BatchVolume {
+        inner: MemVolume,
+        batches: Spin<Vec<Vec<usize>>>,
+    }
+
+    impl Volume for BatchVolume {
+        fn sector_count(&self) -> usize {
+            self.inner.sector_count()
+        }
+
+        fn sector_size(&self) -> usize {
+            self.inner.sector_size()
+        }
+
+        fn read(&self, sector: Sector, buf: &mut [u8]) -> Result<(), VolumeError> {
+            self.inner.read(sector, buf)
+        }
+
+        fn write(&self, sector: Sector, buf: &[u8]) -> Result<(), VolumeError> {
+            if sector.index() == 7 {
+                return Err(VolumeError::new(sector, VolumeErrorKind::Io));
+            }
+            self.inner.write(sector, buf)
+        }
+
+        fn write_batch(&self, writes: &[(Sector, &[u8])]) -> Vec<Result<(), VolumeError>> {
+            let sectors = writes.iter().map(|(s, _)| s.index()).collect();
+            self.batches.lock().push(sectors);
+            writes.iter().map(|(s, buf)| self.write(*s, buf)).collect()
+        }
+    }
+
+    #[test_case]
+    fn test_trim() {
+        info!("TESTING fs::volume::test_trim");
+        let s = Sector::from_index;
+        let inner = MemVolume::new(512, 16);
+        let volume = BufferedVolume::new(&inner);
+        let size = BufferedVolume::<MemVolume>::EXPECTED_CACHE_SIZE;
+        assert_eq!(volume.trim(), 0);
+
+        // Lending many sectors at once grows the cache, the first one becomes the least recent
+        let lent = (0..size + 4)
+            .map(|i| volume.sector(s(i)).unwrap())
+            .collect::<Vec<_>>();
+        lent[0].bytes()[0] = 1;
+        lent[0].mark_as_dirty();
+        drop(lent);
+        assert_eq!(volume.sectors.lock().cached.len(), size + 4);
+
+        // The dirty sector is kept even though it is the least recently used
+        assert_eq!(volume.trim(), 4);
+        let cached = volume.sectors.lock().cached.len();
+        assert_eq!(cached, size);
+        assert_eq!(volume.trim(), 0);
+        volume.commit().unwrap();
+        let mut buf = [0; 512];
+        inner.read(s(0), &mut buf).unwrap();
+        assert_eq!(buf[0], 1);
+
+        let misses = volume.stats().misses;
+        assert!(!volume.sector(s(0)).unwrap().is_dirty());
+        assert_eq!(volume.stats().misses, misses);
+    }
+
+    #[test_case]
+    fn test_evict() {
+        info!("TESTING fs::volume::test_evict");
+        let s = Sector::from_index;
+        let inner = MemVolume::new(512, 16);
+        let volume = BufferedVolume::new(&inner);
+        for i in 0..8 {
+            volume.sector(s(i)).unwrap();
+        }
+        let dirty = volume.sector(s(3)).unwrap();
+        dirty.bytes()[0] = 1;
+        dirty.mark_as_dirty();
+        drop(dirty);
+        let lent = volume.sector(s(4)).unwrap();
+        inner.write(s(2), &[2; 512]).unwrap();
+        inner.write(s(4), &[2; 512]).unwrap();
+
+        // The dirty one and the lent one are kept
+        assert_eq!(volume.evict(s(2), 4), 2);
+        assert_eq!(volume.evict(s(2), 4), 0);
+        assert_eq!(volume.sector(s(2)).unwrap().bytes()[0], 2);
+        assert_eq!(volume.sector(s(3)).unwrap().bytes()[0], 1);
+        assert_eq!(lent.bytes()[0], 0);
+        drop(lent);
+        volume.commit().unwrap();
+        assert_eq!(volume.evict(s(2), 4), 3);
+        assert_eq!(volume.sector(s(4)).unwrap().bytes()[0], 2);
+        assert_eq!(volume.sectors.lock().cached.len(), 5);
+    }
+
+    /// Check that every lent sector is counted exactly by the live refs, and every cached sector
+    /// is uniquely owned.
+    fn assert_consistent<V>(volume: &BufferedVolume<V>, refs: &[BufferedSectorRef]) {
+        let sectors = volume.sectors.lock();
+        for l in sectors.lent.iter() {
+            let count = refs
+                .iter()
+                .filter(|r| Arc::ptr_eq(&r.sector, &l.sector))
+                .count();
+            assert_eq!(l.count, count, "sector {}", l.sector.sector().index());
+            assert_eq!(Arc::strong_count(&l.sector), count + 1);
+        }
+        assert!(refs.iter().all(|r| sectors
+            .lent
+            .iter()
+            .any(|l| Arc::ptr_eq(&r.sector, &l.sector))));
+        for s in sectors.cached.iter() {
+            assert_eq!(Arc::strong_count(s), 1);
+            assert!(sectors.lent.iter().all(|l| l.sector.sector() != s.sector()));
+        }
+    }
+
+    #[test_case]
+    fn test_lent_count() {
+        info!("TESTING fs::volume::test_lent_count");
+        let s = Sector::from_index;
+        let inner = MemVolume::new(512, 16);
+        let volume = BufferedVolume::new(&inner);
+
+        // Refs to a few sectors are created, cloned and dropped in an interleaved order
+        let mut refs = Vec::<BufferedSectorRef>::new();
+        let mut seed = 0x2545f491u32;
+        for i in 0..4000 {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            let n = seed as usize;
+            match n % 8 {
+                0..=2 => refs.push(volume.sector(s(n / 8 % 4)).unwrap()),
+                3..=5 if !refs.is_empty() => refs.push(refs[n / 8 % refs.len()].clone()),
+                _ if !refs.is_empty() => drop(refs.swap_remove(n / 8 % refs.len())),
+                _ => {}
+            }
+            // Clones dropped while another clone of the same sector is alive
+            if let Some(r) = refs.last() {
+                let a = r.clone();
+                let b = a.clone();
+                drop(a);
+                drop(b);
+            }
+            if i % 500 == 0 {
+                volume.commit().unwrap();
+                volume.write_zeros(s(2), 1).unwrap();
+            }
+            assert_consistent(&volume, &refs);
+        }
+
+        drop(refs);
+        assert_consistent(&volume, &[]);
+        let sectors = volume.sectors.lock();
+        assert!(sectors.lent.is_empty());
+        assert_eq!(sectors.cached.len(), 4);
+    }
+
+    struct LendingJob {
+        volume: &'static BufferedVolume<MemVolume>,
+        index: usize,
+        writes: [AtomicUsize; LENDING_SECTORS],
+        done: AtomicBool,
+    }
+
+    const LENDING_SECTORS: usize = 4;
+    const LENDING_TASKS: usize = 4;
+
+    extern "C" fn run_lending_job(arg: u64) -> ! {
+        let job = unsafe { &*(arg as *const LendingJob) };
+        let mut refs = Vec::<BufferedSectorRef>::new();
+        let mut seed = 0x2545f491u32 + job.index as u32;
+        for i in 0..2000 {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            let n = seed as usize;
+            match n % 8 {
+                0..=2 => refs.push(job.volume.sector(Sector(n / 8 % LENDING_SECTORS)).unwrap()),
+                3..=4 if !refs.is_empty() => refs.push(refs[n / 8 % refs.len()].clone()),
+                5 if !refs.is_empty() => {
+                    // Each task counts its writes in its own byte of the sector
+                    let r = &refs[n / 8 % refs.len()];
+                    let mut bytes = r.bytes();
+                    bytes[job.index] = bytes[job.index].wrapping_add(1);
+                    drop(bytes);
+                    r.mark_as_dirty();
+                    job.writes[r.sector().index()].fetch_add(1, Ordering::SeqCst);
+                }
+                _ if !refs.is_empty() => drop(refs.swap_remove(n / 8 % refs.len())),
+                _ => {}
+            }
+            if i % 200 == 0 {
+                job.volume.commit().unwrap();
+            }
+            // Switch while holding the refs, so that the same sectors are lent to the other tasks
+            if i % 8 == 0 {
+                task::scheduler().r#yield();
+            }
+        }
+        drop(refs);
+        job.done.store(true, Ordering::SeqCst);
+        loop {
+            task::scheduler().sleep(100);
+        }
+    }
+
+    #[test_case]
+    fn test_lent_count_concurrent() {
+        info!("TESTING fs::volume::test_lent_count_concurrent");
+        let volume = Box::leak(Box::new(BufferedVolume::new(MemVolume::new(512, 16))));
+        let jobs = (0..LENDING_TASKS)
+            .map(|index| {
+                let job = Box::leak(Box::new(LendingJob {
+                    volume,
+                    index,
+                    writes: Default::default(),
+                    done: AtomicBool::new(false),
+                }));
+                let arg = job as *const LendingJob as u64;
+                task::scheduler().add(task::Priority::MAX, run_lending_job, arg);
+                &*job
+            })
+            .collect::<Vec<_>>();
+        while !jobs.iter().all(|job| job.done.load(Ordering::SeqCst)) {
+            task::scheduler().sleep(1);
+        }
+
+        // Every sector is cached exactly once, and no write is lost to a duplicated sector
+        assert_consistent(volume, &[]);
+        {
+            let sectors = volume.sectors.lock();
+            assert!(sectors.lent.is_empty());
+            for (i, s) in sectors.cached.iter().enumerate() {
+                assert!(sectors
+                    .cached
+                    .iter()
+                    .skip(i + 1)
+                    .all(|t| t.sector() != s.sector()));
+            }
+        }
+        for i in 0..LENDING_SECTORS {
+            let sector = volume.sector(Sector(i)).unwrap();
+            let bytes = sector.bytes();
+            for job in jobs.iter() {
+                let writes = job.writes[i].load(Ordering::SeqCst);
+                assert_eq!(bytes[job.index], writes as u8, "sector {}", i);
+            }
+        }
+    }
+
+    #[test_case]
+    fn test_commit_batch() {
+        info!("TESTING fs::volume::test_commit_batch");
+        let s = Sector::from_index;
+        let inner = BatchVolume {
+            inner: MemVolume::new(512, 16),
+            batches: Spin::new(Vec::new()),
+        };
+        let volume = BufferedVolume::new(&inner);
+        for i in [9, 3, 7, 1, 5] {
+            let sector = volume.sector(s(i)).unwrap();
+            if i != 1 {
+                sector.bytes()[0] = i as u8;
+                sector.mark_as_dirty();
+            }
+        }
+
+        // Dirty sectors are written by a single batch in order, and failures are per sector
+        let written = volume.stats().sectors_written;
+        let e = volume.commit().unwrap_err();
+        assert_eq!(e, VolumeError::new(s(7), VolumeErrorKind::Io));
+        assert_eq!(*inner.batches.lock(), [vec![3, 5, 7, 9]]);
+        assert_eq!(volume.stats().sectors_written, written + 4);
+        let mut buf = [0; 512];
+        for i in [3, 5, 9] {
+            inner.read(s(i), &mut buf).unwrap();
+            assert_eq!(buf[0], i as u8);
+        }
+
+        // Only the failed sector remains dirty
+        assert!(volume.commit().is_err());
+        assert_eq!(inner.batches.lock().last(), Some(&vec![7]));
+        assert!(!volume.sector(s(9)).unwrap().is_dirty());
+    }
+
+    #[test_case]
+    fn test_resize() {
+        info!("TESTING fs::volume::test_resize");
+        let s = Sector::from_index;
+        let inner = MemVolume::new(512, 16);
+        let volume = BufferedVolume::new(&inner);
+        let lent = volume.sector(s(14)).unwrap();
+        for i in [4, 12] {
+            let sector = volume.sector(s(i)).unwrap();
+            sector.bytes()[0] = 1;
+            sector.mark_as_dirty();
+        }
+
+        // Shrink: sectors beyond the end are rejected and their changes are discarded
+        inner.resize(10);
+        assert_eq!(
+            volume.sector(s(12)).unwrap_err(),
+            VolumeError::new(s(12), VolumeErrorKind::Resized)
+        );
+        assert_eq!(
+            volume.write_zeros(s(8), 4),
+            Err(VolumeError::new(s(8), VolumeErrorKind::Resized))
+        );
+        assert!(!lent.is_dirty());
+        lent.bytes()[0] = 1;
+        lent.mark_as_dirty();
+        drop(lent);
+        assert_eq!(
+            volume.commit(),
+            Err(VolumeError::new(s(14), VolumeErrorKind::Resized))
+        );
+        assert_eq!(volume.sector(s(4)).unwrap().bytes()[0], 1);
+        assert!(!volume.sector(s(4)).unwrap().is_dirty());
+        assert_eq!(volume.sector_count(), 10);
+
+        // Grow: sectors are available again, without the discarded changes
+        inner.resize(16);
+        assert_eq!(volume.sector(s(12)).unwrap().bytes()[0], 0);
+        assert_eq!(volume.sector(s(14)).unwrap().bytes()[0], 0);
+        assert!(volume.sector(s(16)).is_err());
+        volume.commit().unwrap();
+    }
+}
@@ -0,0 +1,75 @@
+use super::{Sector, Volume, VolumeError, VolumeErrorKind};
+use crate::sync::spin::Spin;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// A volume on memory. Mainly used for testing file systems.
+#[derive(Debug)]
+pub struct MemVolume {
+    sector_size: usize,
+    bytes: Spin<Vec<u8>>,
+    generation: AtomicU64,
+}
+
+impl MemVolume {
+    pub fn new(sector_size: usize, sector_count: usize) -> Self {
+        Self {
+            sector_size,
+            bytes: Spin::new(vec![0; sector_size * sector_count]),
+            generation: AtomicU64::new(0),
+        }
+    }
+
+    /// Change the number of sectors, like resizing a block device. New sectors are filled with
+    /// zeros.
+    pub fn resize(&self, sector_count: usize) {
+        self.bytes.lock().resize(self.sector_size * sector_count, 0);
+        self.generation.fetch_add(1, Ordering::Release);
+    }
+
+    fn range(&self, sector: Sector, len: usize) -> Result<(usize, usize), VolumeError> {
+        let start = sector.byte_offset(self.sector_size);
+        match start.and_then(|start| Ok((start, start.checked_add(len)?))) {
+            Ok((start, end)) if end.get() <= self.bytes.lock().len() => {
+                Ok((start.get(), end.get()))
+            }
+            _ => Err(VolumeError::new(sector, VolumeErrorKind::OutOfRange)),
+        }
+    }
+}
+
+impl Volume for MemVolume {
+    fn sector_count(&self) -> usize {
+        self.bytes.lock().len() / self.sector_size
+    }
+
+    fn sector_size(&self) -> usize {
+        self.sector_size
+    }
+
+    fn read(&self, sector: Sector, buf: &mut [u8]) -> Result<(), VolumeError> {
+        let (start, end) = self.range(sector, buf.len())?;
+        buf.copy_from_slice(&self.bytes.lock()[start..end]);
+        Ok(())
+    }
+
+    fn write(&self, sector: Sector, buf: &[u8]) -> Result<(), VolumeError> {
+        let (start, end) = self.range(sector, buf.len())?;
+        self.bytes.lock()[start..end].copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn write_zeros(&self, sector: Sector, count: usize) -> Result<(), VolumeError> {
+        let len = count
+            .checked_mul(self.sector_size)
+            .ok_or(VolumeError::new(sector, VolumeErrorKind::OutOfRange))?;
+        let (start, end) = self.range(sector, len)?;
+        self.bytes.lock()[start..end].fill(0);
+        Ok(())
+    }
+
+    fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+}
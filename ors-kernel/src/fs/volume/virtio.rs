@@ -2,25 +2,97 @@ mod virtio {
     pub use crate::devices::virtio::block::*;
 }
 use super::{Sector, Volume, VolumeError, VolumeErrorKind};
-use derive_new::new;
+use alloc::vec::Vec;
 
 impl From<virtio::Error> for VolumeErrorKind {
     fn from(e: virtio::Error) -> Self {
         match e {
             virtio::Error::Io => Self::Io,
             virtio::Error::OutOfRange => Self::OutOfRange,
-            _ => Self::Unknown,
+            virtio::Error::Unknown => Self::Unknown,
+            virtio::Error::Unsupported | virtio::Error::Busy | virtio::Error::Timeout => {
+                Self::Unknown
+            }
         }
     }
 }
 
-/// Let the entire VirtIO block as a single volume.
-#[derive(Debug, Clone, Copy, new)]
-pub struct VirtIOBlockVolume(&'static virtio::Block);
+/// Let the entire VirtIO block, or a partition of it, as a single volume.
+#[derive(Debug, Clone, Copy)]
+pub struct VirtIOBlockVolume {
+    block: &'static virtio::Block,
+    start_sector: u64,
+    num_sectors: u64,
+    /// Whether this volume is the entire block, which follows the capacity of the block.
+    entire: bool,
+    read_only: bool,
+}
+
+impl VirtIOBlockVolume {
+    pub fn new(block: &'static virtio::Block) -> Self {
+        Self {
+            entire: true,
+            ..Self::new_with_partition(block, 0, block.capacity())
+        }
+    }
+
+    /// A volume on the `num_sectors` sectors from `start_sector` of the block.
+    pub fn new_with_partition(
+        block: &'static virtio::Block,
+        start_sector: u64,
+        num_sectors: u64,
+    ) -> Self {
+        Self {
+            block,
+            start_sector,
+            num_sectors,
+            entire: false,
+            read_only: false,
+        }
+    }
+
+    /// Make this volume reject every write with `VolumeErrorKind::ReadOnly`.
+    pub fn into_read_only(self) -> Self {
+        Self {
+            read_only: true,
+            ..self
+        }
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn num_sectors(&self) -> u64 {
+        if self.entire {
+            self.block.capacity()
+        } else {
+            self.num_sectors
+        }
+    }
+
+    fn block_sector(&self, sector: Sector, len: usize) -> Result<u64, VolumeError> {
+        block_sector(self.start_sector, self.num_sectors(), sector, len)
+            .ok_or(VolumeError::new(sector, VolumeErrorKind::OutOfRange))
+    }
+}
+
+/// Translate the sector of a partition into the sector of the block, if the `len` bytes from
+/// `sector` are in the partition.
+fn block_sector(start_sector: u64, num_sectors: u64, sector: Sector, len: usize) -> Option<u64> {
+    let count = Sector::count_for_bytes(len.max(1), virtio::Block::SECTOR_SIZE).ok()?;
+    let count = count.get() as u64;
+    let end = (sector.index() as u64).checked_add(count)?;
+    if end <= num_sectors {
+        start_sector.checked_add(sector.index() as u64)
+    } else {
+        None
+    }
+}
 
 impl Volume for VirtIOBlockVolume {
     fn sector_count(&self) -> usize {
-        self.0.capacity() as usize
+        self.num_sectors() as usize
     }
 
     fn sector_size(&self) -> usize {
@@ -28,14 +100,109 @@ impl Volume for VirtIOBlockVolume {
     }
 
     fn read(&self, sector: Sector, buf: &mut [u8]) -> Result<(), VolumeError> {
-        self.0
-            .read(sector.index() as u64, buf)
+        let block_sector = self.block_sector(sector, buf.len())?;
+        self.block
+            .read(block_sector, buf, virtio::IoPriority::Normal)
             .map_err(|k| VolumeError::new(sector, k.into()))
     }
 
     fn write(&self, sector: Sector, buf: &[u8]) -> Result<(), VolumeError> {
-        self.0
-            .write(sector.index() as u64, buf)
+        if self.read_only {
+            return Err(VolumeError::new(sector, VolumeErrorKind::ReadOnly));
+        }
+        let block_sector = self.block_sector(sector, buf.len())?;
+        self.block
+            .write(block_sector, buf, virtio::IoPriority::Normal)
             .map_err(|k| VolumeError::new(sector, k.into()))
     }
+
+    fn write_zeros(&self, sector: Sector, count: usize) -> Result<(), VolumeError> {
+        if self.read_only {
+            return Err(VolumeError::new(sector, VolumeErrorKind::ReadOnly));
+        }
+        let len = count
+            .checked_mul(virtio::Block::SECTOR_SIZE)
+            .ok_or(VolumeError::new(sector, VolumeErrorKind::OutOfRange))?;
+        let block_sector = self.block_sector(sector, len)?;
+        self.block
+            .write_zeros(block_sector, count as u64)
+            .map_err(|k| VolumeError::new(sector, k.into()))
+    }
+
+    /// Batches are the commits of the sector cache, which carry the metadata of the file system
+    /// together, thus they are prioritized over the other requests.
+    fn write_batch(&self, writes: &[(Sector, &[u8])]) -> Vec<Result<(), VolumeError>> {
+        let mut results = writes
+            .iter()
+            .map(|(sector, buf)| {
+                if self.read_only {
+                    Err(VolumeError::new(*sector, VolumeErrorKind::ReadOnly))
+                } else {
+                    self.block_sector(*sector, buf.len())
+                }
+            })
+            .collect::<Vec<_>>();
+        let mut reqs = writes
+            .iter()
+            .zip(results.iter())
+            .filter_map(|((_, buf), r)| match r {
+                Ok(block_sector) => Some(
+                    virtio::BlockRequest::write(*block_sector, buf)
+                        .with_priority(virtio::IoPriority::Metadata),
+                ),
+                Err(_) => None,
+            })
+            .collect::<Vec<_>>();
+        // Errors are reported per request
+        let _ = self.block.submit_batch(&mut reqs);
+
+        let mut reqs = reqs.into_iter();
+        writes
+            .iter()
+            .zip(results.iter_mut())
+            .map(|((sector, _), r)| match r {
+                Ok(_) => match reqs.next().unwrap().result().unwrap() {
+                    Ok(()) => Ok(()),
+                    Err(k) => Err(VolumeError::new(*sector, k.into())),
+                },
+                Err(e) => Err(*e),
+            })
+            .collect()
+    }
+
+    fn generation(&self) -> u64 {
+        if self.entire {
+            self.block.capacity_generation()
+        } else {
+            0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::info;
+
+    #[test_case]
+    fn test_block_sector() {
+        info!("TESTING fs::volume::virtio::test_block_sector");
+        let s = Sector::from_index;
+        assert_eq!(block_sector(0, 8, s(0), 512), Some(0));
+        assert_eq!(block_sector(100, 8, s(3), 512), Some(103));
+        assert_eq!(block_sector(100, 8, s(7), 512), Some(107));
+        assert_eq!(block_sector(100, 8, s(7), 513), None);
+        assert_eq!(block_sector(100, 8, s(6), 1024), Some(106));
+        assert_eq!(block_sector(100, 8, s(8), 512), None);
+        assert_eq!(block_sector(100, 8, s(8), 0), None);
+        assert_eq!(block_sector(100, 8, s(usize::MAX), 512), None);
+        assert_eq!(
+            VolumeErrorKind::from(virtio::Error::OutOfRange),
+            VolumeErrorKind::OutOfRange
+        );
+        assert_eq!(
+            VolumeErrorKind::from(virtio::Error::Timeout),
+            VolumeErrorKind::Unknown
+        );
+    }
 }
@@ -1,15 +1,21 @@
+pub mod bmp;
 mod color;
+pub mod display;
 mod font;
 mod frame_buffer;
 mod rect;
 mod text_buffer;
 
 pub use color::Color;
-pub use font::{FontStyle, MonospaceFont};
+pub use font::{CacheStats, FontError, FontFace, MonospaceFont};
+pub use ors_common::term::{char_width, FontStyle};
 pub use frame_buffer::{FrameBuffer, FrameBufferFormat, ScreenBuffer, VecBuffer};
 pub use rect::Rect;
 pub use text_buffer::MonospaceTextBuffer;
 
+/// Number of pixels encoded at once by `FrameBufferExt::fill_rect`.
+const FILL_CHUNK: usize = 16;
+
 pub trait FrameBufferExt: FrameBuffer {
     fn rect(&self) -> Rect {
         Rect::new(0, 0, self.width() as u32, self.height() as u32)
@@ -44,53 +50,78 @@ pub trait FrameBufferExt: FrameBuffer {
     }
 
     fn blit(&mut self, x: i32, y: i32, fb: &impl FrameBuffer) {
-        if let Some(rect) = self.rect().intersect(fb.rect().offset(x, y)) {
-            let oy = (rect.y - y) as usize;
-            let ox = (rect.x - x) as usize;
+        self.blit_rect(x, y, fb, fb.rect());
+    }
+
+    /// Copy the `src` rectangle of `fb` to `(x, y)`.
+    fn blit_rect(&mut self, x: i32, y: i32, fb: &impl FrameBuffer, src: Rect) {
+        let src_clipped = match fb.rect().intersect(src) {
+            Some(rect) => rect,
+            None => return,
+        };
+        let x = x + (src_clipped.x - src.x);
+        let y = y + (src_clipped.y - src.y);
+        let src = src_clipped;
+        if let Some(rect) = self.rect().intersect(Rect::new(x, y, src.w, src.h)) {
+            let oy = (rect.y - y + src.y) as usize;
+            let ox = (rect.x - x + src.x) as usize;
             let src_stride = fb.stride();
             let src = fb.bytes();
+            let src_format = fb.format();
             let dest_stride = self.stride();
+            let dest_format = self.format();
             let dest = self.bytes_mut();
             let l = rect.w as usize * 4;
 
             for dy in 0..rect.h as usize {
                 let i = ((rect.y as usize + dy) * dest_stride + rect.x as usize) * 4;
                 let j = ((oy + dy) * src_stride + ox) * 4;
-                dest[i..i + l].copy_from_slice(&src[j..j + l]);
+                if src_format == dest_format {
+                    dest[i..i + l].copy_from_slice(&src[j..j + l]);
+                } else {
+                    let (decode, encode) = (src_format.decoder(), dest_format.encoder());
+                    for (d, s) in dest[i..i + l]
+                        .chunks_exact_mut(4)
+                        .zip(src[j..j + l].chunks_exact(4))
+                    {
+                        d.copy_from_slice(&encode(decode([s[0], s[1], s[2], s[3]])));
+                    }
+                }
             }
         }
     }
 
     fn fill_rect(&mut self, rect: Rect, color: Color) {
-        if let Some(rect) = self.rect().intersect(rect) {
-            let x = rect.x as usize;
-            let y = rect.y as usize;
-            let w = rect.w as usize;
-            let h = rect.h as usize;
-            let stride = self.stride();
-            let color = self.format().encoder()(color);
-            let dest = self.bytes_mut();
-            for oy in 0..h {
-                let i = ((y + oy) * stride + x) * 4;
-                if oy == 0 {
-                    const CHUNK: usize = 16;
-                    for ox in 0..w.min(CHUNK) {
-                        dest[i + ox * 4..i + ox * 4 + 4].copy_from_slice(&color);
-                    }
-                    if 16 < w {
-                        for ox in (1..w / CHUNK).map(|n| n * CHUNK) {
-                            let (a, b) = dest.split_at_mut(i + ox * 4);
-                            b[0..CHUNK * 4].copy_from_slice(&mut a[i..i + CHUNK * 4]);
-                        }
-                        let mw = w % CHUNK;
-                        let (a, b) = dest.split_at_mut(i + (w - mw) * 4);
-                        b[0..mw * 4].copy_from_slice(&mut a[i..i + mw * 4]);
-                    }
-                } else {
-                    let (a, b) = dest.split_at_mut(i);
-                    b[0..w * 4].copy_from_slice(&mut a[i - stride * 4..i - (stride - w) * 4]);
-                }
-            }
+        let rect = match self.rect().intersect(rect) {
+            Some(rect) if rect.w != 0 && rect.h != 0 => rect,
+            _ => return,
+        };
+        let x = rect.x as usize;
+        let y = rect.y as usize;
+        let w = rect.w as usize;
+        let h = rect.h as usize;
+        let stride = self.stride();
+        debug_assert!(self.width() <= stride, "stride is narrower than width");
+        let color = self.format().encoder()(color);
+        let dest = self.bytes_mut();
+        let l = w * 4;
+        let i = (y * stride + x) * 4;
+        debug_assert!(i + ((h - 1) * stride * 4 + l) <= dest.len());
+
+        // Fill the first row from a chunk of encoded colors, and then copy it to the other rows.
+        // Each row after the first one starts `stride * 4 - l` bytes after the end of the
+        // previous one, thus `rest` never overlaps with `first` as long as `w <= stride`.
+        let (first, rest) = dest[i..].split_at_mut(l);
+        let mut chunk = [0; FILL_CHUNK * 4];
+        for px in chunk.chunks_exact_mut(4) {
+            px.copy_from_slice(&color);
+        }
+        for d in first.chunks_mut(chunk.len()) {
+            d.copy_from_slice(&chunk[..d.len()]);
+        }
+        for oy in 1..h {
+            let j = oy * stride * 4 - l;
+            rest[j..j + l].copy_from_slice(first);
         }
     }
 
@@ -100,3 +131,138 @@ pub trait FrameBufferExt: FrameBuffer {
 }
 
 impl<T: FrameBuffer + ?Sized> FrameBufferExt for T {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+    use alloc::vec::Vec;
+    use log::info;
+
+    /// A frame buffer with padding pixels at the end of each row, as the screen may have.
+    struct StridedBuffer {
+        data: Vec<u8>,
+        width: usize,
+        height: usize,
+        stride: usize,
+    }
+
+    impl FrameBuffer for StridedBuffer {
+        fn bytes(&self) -> &[u8] {
+            &self.data
+        }
+
+        fn bytes_mut(&mut self) -> &mut [u8] {
+            &mut self.data
+        }
+
+        fn width(&self) -> usize {
+            self.width
+        }
+
+        fn height(&self) -> usize {
+            self.height
+        }
+
+        fn stride(&self) -> usize {
+            self.stride
+        }
+
+        fn format(&self) -> FrameBufferFormat {
+            FrameBufferFormat::Bgrx
+        }
+    }
+
+    const PADDING: u8 = 0xcc;
+
+    /// Fill `rect` with a color and check every pixel, including the padding of each row.
+    fn check_fill_rect(width: usize, height: usize, stride: usize, rect: Rect) {
+        let (bg, fg) = (Color::new(1, 2, 3), Color::new(200, 100, 50));
+        let mut buf = StridedBuffer {
+            data: vec![PADDING; stride * height * 4],
+            width,
+            height,
+            stride,
+        };
+        for y in 0..height as i32 {
+            for x in 0..width as i32 {
+                buf.write_pixel(x, y, bg);
+            }
+        }
+        buf.fill_rect(rect, fg);
+        for y in 0..height as i32 {
+            for x in 0..width as i32 {
+                let expected = if rect.contains(x, y) { fg } else { bg };
+                assert_eq!(
+                    buf.read_pixel(x, y),
+                    Some(expected),
+                    "({}, {}) of {}x{} (stride {}) filled with {:?}",
+                    x,
+                    y,
+                    width,
+                    height,
+                    stride,
+                    rect
+                );
+            }
+            let padding = (y as usize * stride + width) * 4..(y as usize + 1) * stride * 4;
+            assert!(buf.data[padding].iter().all(|b| *b == PADDING));
+        }
+    }
+
+    #[test_case]
+    fn test_fill_rect() {
+        info!("TESTING graphics::test_fill_rect");
+        let widths = [
+            1,
+            2,
+            FILL_CHUNK - 1,
+            FILL_CHUNK,
+            FILL_CHUNK + 1,
+            FILL_CHUNK * 3 + 5,
+        ];
+        for width in widths {
+            for stride in [width, width + 1, width + FILL_CHUNK + 3] {
+                let height = 3;
+                let (w, h) = (width as i32, height as i32);
+                let rects = [
+                    Rect::new(0, 0, width as u32, height as u32),
+                    Rect::new(-5, -5, width as u32 + 10, height as u32 + 10),
+                    Rect::new(w / 2, 1, 1, 1),
+                    Rect::new(w / 2, 1, width as u32, 1),
+                    // Partially off each edge
+                    Rect::new(-3, 0, 4, 2),
+                    Rect::new(w - 1, 1, 5, 5),
+                    Rect::new(0, -2, width as u32, 3),
+                    Rect::new(1, h - 1, width as u32, 4),
+                    // Degenerate or entirely off
+                    Rect::new(0, 0, 0, 0),
+                    Rect::new(1, 1, 0, 2),
+                    Rect::new(0, 1, 2, 0),
+                    Rect::new(w, 0, 3, 3),
+                    Rect::new(-3, 0, 3, 3),
+                    Rect::new(0, h, 3, 3),
+                    Rect::new(0, -3, 3, 3),
+                    Rect::new(-100, -100, 1, 1),
+                ];
+                for rect in rects {
+                    check_fill_rect(width, height, stride, rect);
+                }
+            }
+        }
+    }
+
+    #[test_case]
+    fn test_clear() {
+        info!("TESTING graphics::test_clear");
+        let color = Color::new(12, 34, 56);
+        for (width, height) in [(1, 1), (1, 7), (FILL_CHUNK + 1, 1), (33, 17)] {
+            let mut buf = VecBuffer::new(width, height, FrameBufferFormat::Rgbx);
+            buf.clear(color);
+            let encoded = FrameBufferFormat::Rgbx.encoder()(color);
+            assert!(buf.bytes().chunks_exact(4).all(|px| px == encoded));
+        }
+        let mut buf = VecBuffer::new(0, 0, FrameBufferFormat::Rgbx);
+        buf.clear(color);
+    }
+}
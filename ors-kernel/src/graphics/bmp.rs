@@ -0,0 +1,228 @@
+//! Loader of BMP (Windows bitmap) images.
+//! Only uncompressed 24/32-bit images with BITMAPINFOHEADER (BMP v3) are supported.
+
+use super::{Color, FrameBufferExt, FrameBufferFormat, VecBuffer};
+use core::convert::TryInto;
+use core::fmt;
+
+const FILE_HEADER_SIZE: usize = 14;
+const INFO_HEADER_SIZE: usize = 40;
+
+const COMPRESSION_RGB: u32 = 0;
+const COMPRESSION_BITFIELDS: u32 = 3;
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum BmpError {
+    InvalidMagic,
+    UnsupportedVersion,
+    UnsupportedCompression,
+    UnsupportedFormat,
+    Truncated,
+}
+
+impl fmt::Display for BmpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidMagic => write!(f, "Not a BMP image"),
+            Self::UnsupportedVersion => write!(f, "Unsupported BMP header version"),
+            Self::UnsupportedCompression => write!(f, "Unsupported BMP compression"),
+            Self::UnsupportedFormat => write!(f, "Unsupported BMP pixel format"),
+            Self::Truncated => write!(f, "BMP image is truncated"),
+        }
+    }
+}
+
+/// Decode a BMP image into a `VecBuffer` of `FrameBufferFormat::Rgbx`.
+pub fn load(data: &[u8]) -> Result<VecBuffer, BmpError> {
+    if data.len() < 2 || &data[0..2] != b"BM" {
+        Err(BmpError::InvalidMagic)?;
+    }
+    if data.len() < FILE_HEADER_SIZE + INFO_HEADER_SIZE {
+        Err(BmpError::Truncated)?;
+    }
+    // The file size field (offset 2) is unreliable in practice and is not checked.
+    let pixel_offset = read_u32(data, 10) as usize;
+
+    if read_u32(data, 14) as usize != INFO_HEADER_SIZE {
+        Err(BmpError::UnsupportedVersion)?;
+    }
+    let width = read_u32(data, 18) as i32;
+    let height = read_u32(data, 22) as i32;
+    let planes = read_u16(data, 26);
+    let bpp = read_u16(data, 28);
+    let compression = read_u32(data, 30);
+
+    if width <= 0 || height == 0 || planes != 1 {
+        Err(BmpError::UnsupportedFormat)?;
+    }
+    let masks = match (compression, bpp) {
+        (COMPRESSION_RGB, 24 | 32) => [0xff0000, 0x00ff00, 0x0000ff],
+        (COMPRESSION_BITFIELDS, 32) => {
+            // BI_BITFIELDS masks follow the BITMAPINFOHEADER
+            let o = FILE_HEADER_SIZE + INFO_HEADER_SIZE;
+            if data.len() < o + 12 {
+                Err(BmpError::Truncated)?;
+            }
+            [
+                read_u32(data, o),
+                read_u32(data, o + 4),
+                read_u32(data, o + 8),
+            ]
+        }
+        (COMPRESSION_RGB | COMPRESSION_BITFIELDS, _) => Err(BmpError::UnsupportedFormat)?,
+        _ => Err(BmpError::UnsupportedCompression)?,
+    };
+    let channels = [
+        Channel::new(masks[0]),
+        Channel::new(masks[1]),
+        Channel::new(masks[2]),
+    ];
+
+    let width = width as usize;
+    let bottom_up = height > 0;
+    let height = height.unsigned_abs() as usize;
+    let bytes_per_pixel = bpp as usize / 8;
+    let row_size = (bytes_per_pixel * width + 3) / 4 * 4; // rows are 4-byte aligned
+    let end = row_size
+        .checked_mul(height)
+        .and_then(|size| size.checked_add(pixel_offset))
+        .ok_or(BmpError::Truncated)?;
+    if data.len() < end {
+        Err(BmpError::Truncated)?;
+    }
+
+    let mut buf = VecBuffer::new(width, height, FrameBufferFormat::Rgbx);
+    for row in 0..height {
+        let y = if bottom_up { height - 1 - row } else { row };
+        let src = &data[pixel_offset + row * row_size..];
+        for x in 0..width {
+            let p = &src[x * bytes_per_pixel..];
+            let value = match bytes_per_pixel {
+                3 => u32::from_le_bytes([p[0], p[1], p[2], 0]),
+                _ => read_u32(p, 0),
+            };
+            let color = Color::new(
+                channels[0].extract(value),
+                channels[1].extract(value),
+                channels[2].extract(value),
+            );
+            buf.write_pixel(x as i32, y as i32, color);
+        }
+    }
+    Ok(buf)
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+/// A color channel described by a bit mask, scaled to 8 bits.
+#[derive(Debug, Clone, Copy)]
+struct Channel {
+    mask: u32,
+    shift: u32,
+    max: u32,
+}
+
+impl Channel {
+    fn new(mask: u32) -> Self {
+        let shift = if mask == 0 { 0 } else { mask.trailing_zeros() };
+        Self {
+            mask,
+            shift,
+            max: mask >> shift,
+        }
+    }
+
+    fn extract(self, value: u32) -> u8 {
+        if self.max == 0 {
+            0
+        } else {
+            (((value & self.mask) >> self.shift) as u64 * 255 / self.max as u64) as u8
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graphics::FrameBuffer;
+    use alloc::vec::Vec;
+    use log::info;
+
+    fn header(width: i32, height: i32, bpp: u16, compression: u32, extra: usize) -> Vec<u8> {
+        let offset = (FILE_HEADER_SIZE + INFO_HEADER_SIZE + extra) as u32;
+        let mut data = Vec::new();
+        data.extend_from_slice(b"BM");
+        data.extend_from_slice(&0u32.to_le_bytes()); // file size (unchecked)
+        data.extend_from_slice(&0u32.to_le_bytes());
+        data.extend_from_slice(&offset.to_le_bytes());
+        data.extend_from_slice(&(INFO_HEADER_SIZE as u32).to_le_bytes());
+        data.extend_from_slice(&width.to_le_bytes());
+        data.extend_from_slice(&height.to_le_bytes());
+        data.extend_from_slice(&1u16.to_le_bytes());
+        data.extend_from_slice(&bpp.to_le_bytes());
+        data.extend_from_slice(&compression.to_le_bytes());
+        data.extend_from_slice(&[0; 20]);
+        data
+    }
+
+    #[test_case]
+    fn test_load() {
+        info!("TESTING graphics::bmp::test_load");
+        // 2x2, 24-bit, bottom-up (each row is padded to 8 bytes)
+        let mut data = header(2, 2, 24, COMPRESSION_RGB, 0);
+        data.extend_from_slice(&[0, 0, 255, 0, 255, 0, 0, 0]); // bottom: red, green
+        data.extend_from_slice(&[255, 0, 0, 255, 255, 255, 0, 0]); // top: blue, white
+        let img = load(&data).unwrap();
+        assert_eq!((img.width(), img.height()), (2, 2));
+        assert_eq!(img.format(), FrameBufferFormat::Rgbx);
+        assert_eq!(img.read_pixel(0, 0), Some(Color::new(0, 0, 255)));
+        assert_eq!(img.read_pixel(1, 0), Some(Color::new(255, 255, 255)));
+        assert_eq!(img.read_pixel(0, 1), Some(Color::new(255, 0, 0)));
+        assert_eq!(img.read_pixel(1, 1), Some(Color::new(0, 255, 0)));
+
+        // 1x2, 32-bit BITFIELDS (RGBA byte order), top-down
+        let mut data = header(1, -2, 32, COMPRESSION_BITFIELDS, 12);
+        data.extend_from_slice(&0x000000ffu32.to_le_bytes());
+        data.extend_from_slice(&0x0000ff00u32.to_le_bytes());
+        data.extend_from_slice(&0x00ff0000u32.to_le_bytes());
+        data.extend_from_slice(&[10, 20, 30, 255]);
+        data.extend_from_slice(&[40, 50, 60, 255]);
+        let img = load(&data).unwrap();
+        assert_eq!(img.read_pixel(0, 0), Some(Color::new(10, 20, 30)));
+        assert_eq!(img.read_pixel(0, 1), Some(Color::new(40, 50, 60)));
+    }
+
+    #[test_case]
+    fn test_load_error() {
+        info!("TESTING graphics::bmp::test_load_error");
+        let mut data = header(1, 1, 24, COMPRESSION_RGB, 0);
+        data.extend_from_slice(&[0; 4]);
+        assert!(load(&data).is_ok());
+
+        let mut invalid = data.clone();
+        invalid[0] = b'X';
+        assert_eq!(load(&invalid).unwrap_err(), BmpError::InvalidMagic);
+        let mut invalid = data.clone();
+        invalid[14] = 108; // BITMAPV4HEADER
+        assert_eq!(load(&invalid).unwrap_err(), BmpError::UnsupportedVersion);
+        let mut invalid = data.clone();
+        invalid[30] = 1; // RLE8
+        assert_eq!(
+            load(&invalid).unwrap_err(),
+            BmpError::UnsupportedCompression
+        );
+        let mut invalid = data.clone();
+        invalid[28] = 8;
+        assert_eq!(load(&invalid).unwrap_err(), BmpError::UnsupportedFormat);
+        assert_eq!(
+            load(&data[0..data.len() - 1]).unwrap_err(),
+            BmpError::Truncated
+        );
+    }
+}
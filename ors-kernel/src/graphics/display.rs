@@ -0,0 +1,699 @@
+//! The arbiter of the screen, shared by the console and the other drawers.
+//!
+//! `Display` owns the screen buffer. The console draws into the `ConsoleSurface`, the lowest
+//! layer, only within `Display::draw_console`. Any other drawer takes an `Overlay`, an opaque
+//! rectangle placed above the console in the order of its `z`, and draws into it. Each overlay
+//! saves the pixels under it, so that dropping the overlay restores what is under it exactly.
+//!
+//! Every drawing operation records the rectangle it damaged, and only that part of the screen is
+//! composed again: the layers above the damaged rectangle are lifted, the damaged layer is
+//! drawn, and the layers above are put back. Since all of this is done under a single lock,
+//! overlays are never left partially overwritten by the console or by each other.
+//!
+//! The lock is released on every exit path of a drawing operation. A task killed by an oops
+//! never returns from the operation though, so the lock of a holder that no longer exists is
+//! taken over by the next task waiting for it, see `Display::lock`.
+
+use super::{Color, FrameBuffer, FrameBufferExt, FrameBufferFormat, Rect, VecBuffer};
+use crate::graphics::ScreenBuffer;
+use crate::interrupts::TIMER_FREQ;
+use crate::sync::once::Once;
+use crate::sync::spin::Spin;
+use crate::task::{self, TaskId, WaitChannel};
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, Ordering};
+use log::warn;
+
+/// The z of overlays for pointing devices.
+pub const Z_CURSOR: i32 = 100;
+
+/// The z of overlays for output that must be visible above anything else, such as panics.
+/// Such overlays should be placed by `Display::try_overlay`, which never waits for the display.
+pub const Z_EMERGENCY: i32 = 1000;
+
+/// Ticks to wait for the display before checking that the holder of the lock still exists.
+const LOCK_CHECK_INTERVAL: usize = TIMER_FREQ / 10;
+
+static DISPLAY: Once<Display<ScreenBuffer>> = Once::new();
+
+/// Take the ownership of the screen. Without this, the console falls back to the serial port.
+pub fn initialize(screen: ScreenBuffer) {
+    DISPLAY.call_once(|| Display::new(screen));
+}
+
+/// The display of the screen, or `None` if the screen is unavailable.
+pub fn get() -> Option<&'static Display<ScreenBuffer>> {
+    DISPLAY.get()
+}
+
+pub struct Display<T> {
+    /// Accessed only while `lock` is held.
+    screen: UnsafeCell<T>,
+    /// Accessed only while `lock` is held.
+    state: UnsafeCell<State>,
+    lock: Spin<LockState>,
+    console_taken: AtomicBool,
+    /// Set while the console is drawing, during which the console surface is accessible.
+    console_drawing: AtomicBool,
+}
+
+unsafe impl<T: Send> Sync for Display<T> {}
+
+unsafe impl<T: Send> Send for Display<T> {}
+
+#[derive(Debug)]
+struct LockState {
+    locked: bool,
+    /// `None` if the lock is taken before the first task switch.
+    owner: Option<TaskId>,
+}
+
+#[derive(Debug)]
+struct State {
+    next_id: u64,
+    /// Sorted by z, and then by the creation order.
+    overlays: Vec<OverlayState>,
+}
+
+#[derive(Debug)]
+struct OverlayState {
+    id: u64,
+    z: i32,
+    /// The area of the overlay, within the screen.
+    rect: Rect,
+    pixels: VecBuffer,
+    /// The pixels of the lower layers.
+    under: VecBuffer,
+}
+
+impl<T> Display<T> {
+    fn chan(&self) -> WaitChannel {
+        WaitChannel::from_ptr_index(task::ChannelDomain::Mutex, self, 0).named("display")
+    }
+}
+
+impl<T: FrameBuffer> Display<T> {
+    pub fn new(screen: T) -> Self {
+        Self {
+            screen: UnsafeCell::new(screen),
+            state: UnsafeCell::new(State {
+                next_id: 0,
+                overlays: Vec::new(),
+            }),
+            lock: Spin::new(LockState {
+                locked: false,
+                owner: None,
+            }),
+            console_taken: AtomicBool::new(false),
+            console_drawing: AtomicBool::new(false),
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        unsafe { &*self.screen.get() }.width()
+    }
+
+    pub fn height(&self) -> usize {
+        unsafe { &*self.screen.get() }.height()
+    }
+
+    pub fn format(&self) -> FrameBufferFormat {
+        unsafe { &*self.screen.get() }.format()
+    }
+
+    /// Lock the display, blocking the current task until it is available. The lock held by a task
+    /// that no longer exists, such as one killed by an oops in the middle of drawing, is taken
+    /// over. The screen may be left partially drawn by such a task.
+    fn lock(&self) -> DisplayGuard<'_, T> {
+        let current = task::current_id();
+        loop {
+            let mut lock = self.lock.lock();
+            if !lock.locked {
+                lock.locked = true;
+                lock.owner = current;
+                return DisplayGuard { display: self };
+            }
+            let owner = lock.owner;
+            assert!(
+                owner.is_none() || owner != current,
+                "display: Locked again by the holder"
+            );
+            task::scheduler().block(self.chan(), Some(LOCK_CHECK_INTERVAL), lock);
+
+            let abandoned = match owner {
+                Some(owner) => !task::scheduler().tasks().iter().any(|t| t.id == owner),
+                None => false,
+            };
+            let mut lock = self.lock.lock();
+            if abandoned && lock.locked && lock.owner == owner {
+                warn!(
+                    "display: Lock of the exited task {} is taken over",
+                    owner.unwrap().as_u64()
+                );
+                lock.owner = current;
+                self.console_drawing.store(false, Ordering::Release);
+                return DisplayGuard { display: self };
+            }
+        }
+    }
+
+    /// Same as `lock`, but fails immediately if the display is locked.
+    fn try_lock(&self) -> Option<DisplayGuard<'_, T>> {
+        let mut lock = self.lock.lock();
+        if lock.locked {
+            return None;
+        }
+        lock.locked = true;
+        lock.owner = task::current_id();
+        Some(DisplayGuard { display: self })
+    }
+
+    /// Take the surface of the console. There is only one console surface for a display.
+    pub fn console_surface(&self) -> Option<ConsoleSurface<'_, T>> {
+        if self.console_taken.swap(true, Ordering::AcqRel) {
+            return None;
+        }
+        Some(ConsoleSurface { display: self })
+    }
+
+    /// Draw the console by `f`, which draws into the console surface and returns the rectangle
+    /// it damaged. The overlays in the damaged rectangle are drawn again.
+    pub fn draw_console(&self, f: impl FnOnce() -> Option<Rect>) {
+        let mut state = self.lock();
+        // Reset by the guard even if `f` does not return here
+        self.console_drawing.store(true, Ordering::Release);
+        let damage = f();
+        self.console_drawing.store(false, Ordering::Release);
+        if let Some(damage) = damage {
+            let screen = unsafe { &mut *self.screen.get() };
+            apply(screen, &mut state.overlays, damage);
+        }
+    }
+
+    /// Place an overlay of `rect` above the console and the overlays of lower or equal `z`.
+    /// The overlay initially shows what is under it.
+    pub fn overlay(&self, rect: Rect, z: i32) -> Overlay<'_, T> {
+        let state = self.lock();
+        self.place_overlay(state, rect, z, true)
+    }
+
+    /// Same as `overlay`, but fails immediately if the display is in use, such as by the task that
+    /// panicked. Drawing into the overlay is skipped while the display is in use, so that the
+    /// emergency output never waits for the display. Dropping the overlay still waits for it.
+    pub fn try_overlay(&self, rect: Rect, z: i32) -> Option<Overlay<'_, T>> {
+        let state = self.try_lock()?;
+        Some(self.place_overlay(state, rect, z, false))
+    }
+
+    fn place_overlay(
+        &self,
+        mut state: DisplayGuard<'_, T>,
+        rect: Rect,
+        z: i32,
+        blocking: bool,
+    ) -> Overlay<'_, T> {
+        let screen = unsafe { &mut *self.screen.get() };
+        let rect = screen
+            .rect()
+            .intersect(rect)
+            .unwrap_or(Rect::new(0, 0, 0, 0));
+        let id = state.next_id;
+        state.next_id += 1;
+        let i = state.overlays.partition_point(|o| o.z <= z);
+        lift(screen, &state.overlays[i..], rect);
+        let mut under = VecBuffer::new(rect.w as usize, rect.h as usize, screen.format());
+        under.blit_rect(0, 0, screen, rect);
+        let overlay = OverlayState {
+            id,
+            z,
+            rect,
+            pixels: under.clone(),
+            under,
+        };
+        state.overlays.insert(i, overlay);
+        apply(screen, &mut state.overlays[i + 1..], rect);
+        Overlay {
+            display: self,
+            id,
+            blocking,
+        }
+    }
+
+    /// Draw into the overlay by `f`, and compose the `damage` (relative to the overlay).
+    fn draw_overlay(
+        &self,
+        mut state: DisplayGuard<'_, T>,
+        id: u64,
+        damage: Rect,
+        f: impl FnOnce(&mut VecBuffer),
+    ) {
+        let screen = unsafe { &mut *self.screen.get() };
+        let i = state.index(id);
+        let o = &mut state.overlays[i];
+        f(&mut o.pixels);
+        let damage = match o.pixels.rect().intersect(damage) {
+            Some(damage) => damage.offset(o.rect.x, o.rect.y),
+            None => return,
+        };
+        lift(screen, &state.overlays[i + 1..], damage);
+        let o = &state.overlays[i];
+        let src = damage.offset(-o.rect.x, -o.rect.y);
+        screen.blit_rect(damage.x, damage.y, &o.pixels, src);
+        apply(screen, &mut state.overlays[i + 1..], damage);
+    }
+
+    fn remove_overlay(&self, id: u64) {
+        let mut state = self.lock();
+        let screen = unsafe { &mut *self.screen.get() };
+        let i = state.index(id);
+        let rect = state.overlays[i].rect;
+        lift(screen, &state.overlays[i..], rect);
+        state.overlays.remove(i);
+        apply(screen, &mut state.overlays[i..], rect);
+    }
+
+    /// Read a pixel of the screen, as composed.
+    pub fn read_pixel(&self, x: i32, y: i32) -> Option<Color> {
+        let _state = self.lock();
+        unsafe { &*self.screen.get() }.read_pixel(x, y)
+    }
+}
+
+/// The lock of `Display`, which gives access to the state. The console surface is no longer
+/// accessible once the guard is dropped.
+struct DisplayGuard<'a, T> {
+    display: &'a Display<T>,
+}
+
+impl<'a, T> Deref for DisplayGuard<'a, T> {
+    type Target = State;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.display.state.get() }
+    }
+}
+
+impl<'a, T> DerefMut for DisplayGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.display.state.get() }
+    }
+}
+
+impl<'a, T> Drop for DisplayGuard<'a, T> {
+    fn drop(&mut self) {
+        self.display.console_drawing.store(false, Ordering::Release);
+        let mut lock = self.display.lock.lock();
+        lock.locked = false;
+        lock.owner = None;
+        drop(lock);
+        task::scheduler().release(self.display.chan());
+    }
+}
+
+impl State {
+    fn index(&self, id: u64) -> usize {
+        self.overlays
+            .iter()
+            .position(|o| o.id == id)
+            .expect("Unknown overlay")
+    }
+}
+
+/// Restore the pixels under the overlays within `clip`, from the top.
+fn lift(screen: &mut impl FrameBuffer, overlays: &[OverlayState], clip: Rect) {
+    for o in overlays.iter().rev() {
+        if let Some(r) = o.rect.intersect(clip) {
+            screen.blit_rect(r.x, r.y, &o.under, r.offset(-o.rect.x, -o.rect.y));
+        }
+    }
+}
+
+/// Save the pixels under the overlays within `clip` and draw the overlays, from the bottom.
+fn apply(screen: &mut impl FrameBuffer, overlays: &mut [OverlayState], clip: Rect) {
+    for o in overlays.iter_mut() {
+        if let Some(r) = o.rect.intersect(clip) {
+            let local = r.offset(-o.rect.x, -o.rect.y);
+            o.under.blit_rect(local.x, local.y, screen, r);
+            screen.blit_rect(r.x, r.y, &o.pixels, local);
+        }
+    }
+}
+
+/// The lowest layer of the display, drawn by the console. This is accessible only within
+/// `Display::draw_console`.
+pub struct ConsoleSurface<'a, T> {
+    display: &'a Display<T>,
+}
+
+impl<'a, T: FrameBuffer> ConsoleSurface<'a, T> {
+    fn screen(&self) -> &'a mut T {
+        assert!(
+            self.display.console_drawing.load(Ordering::Acquire),
+            "Console surface is accessed outside of Display::draw_console"
+        );
+        unsafe { &mut *self.display.screen.get() }
+    }
+}
+
+impl<'a, T: FrameBuffer> FrameBuffer for ConsoleSurface<'a, T> {
+    fn bytes(&self) -> &[u8] {
+        self.screen().bytes()
+    }
+
+    fn bytes_mut(&mut self) -> &mut [u8] {
+        self.screen().bytes_mut()
+    }
+
+    fn width(&self) -> usize {
+        self.display.width()
+    }
+
+    fn height(&self) -> usize {
+        self.display.height()
+    }
+
+    fn stride(&self) -> usize {
+        unsafe { &*self.display.screen.get() }.stride()
+    }
+
+    fn format(&self) -> FrameBufferFormat {
+        self.display.format()
+    }
+}
+
+/// An exclusive area of the screen above the console. Drawing operations show up on the screen
+/// immediately, as far as they are not covered by higher overlays. The overlay is removed on drop.
+pub struct Overlay<'a, T: FrameBuffer> {
+    display: &'a Display<T>,
+    id: u64,
+    /// Unset for the overlays placed by `Display::try_overlay`.
+    blocking: bool,
+}
+
+impl<'a, T: FrameBuffer> Overlay<'a, T> {
+    /// The area of the overlay in the screen. This may be smaller than the requested area,
+    /// since it is clipped by the screen.
+    pub fn rect(&self) -> Rect {
+        let state = self.display.lock();
+        state.overlays[state.index(self.id)].rect
+    }
+
+    /// Copy `fb` to `(x, y)` relative to the overlay.
+    pub fn blit(&mut self, x: i32, y: i32, fb: &impl FrameBuffer) {
+        let damage = fb.rect().offset(x, y);
+        self.draw(damage, |pixels| pixels.blit(x, y, fb));
+    }
+
+    /// Fill `rect` relative to the overlay.
+    pub fn fill_rect(&mut self, rect: Rect, color: Color) {
+        self.draw(rect, |pixels| pixels.fill_rect(rect, color));
+    }
+
+    pub fn clear(&mut self, color: Color) {
+        let rect = Rect::new(0, 0, u32::MAX / 2, u32::MAX / 2);
+        self.draw(rect, |pixels| pixels.clear(color));
+    }
+
+    fn draw(&mut self, damage: Rect, f: impl FnOnce(&mut VecBuffer)) {
+        let state = if self.blocking {
+            self.display.lock()
+        } else {
+            match self.display.try_lock() {
+                Some(state) => state,
+                None => return,
+            }
+        };
+        self.display.draw_overlay(state, self.id, damage, f);
+    }
+}
+
+impl<'a, T: FrameBuffer> Drop for Overlay<'a, T> {
+    fn drop(&mut self) {
+        self.display.remove_overlay(self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::boxed::Box;
+    use log::info;
+
+    const W: usize = 40;
+    const H: usize = 30;
+    const RED: Color = Color::new(255, 0, 0);
+    const BLUE: Color = Color::new(0, 0, 255);
+    const GREEN: Color = Color::new(0, 255, 0);
+
+    /// A pattern that differs in every pixel.
+    fn pattern(seed: u8) -> VecBuffer {
+        let mut buf = VecBuffer::new(W, H, FrameBufferFormat::Bgrx);
+        for y in 0..H as i32 {
+            for x in 0..W as i32 {
+                let c = Color::new(x as u8 * 5, y as u8 * 7, seed);
+                buf.write_pixel(x, y, c);
+            }
+        }
+        buf
+    }
+
+    /// The expected screen: `console` with the (rect, color) overlays drawn from the bottom.
+    fn compose(console: &VecBuffer, overlays: &[(Rect, Color)]) -> VecBuffer {
+        let mut buf = console.clone();
+        for (rect, color) in overlays {
+            buf.fill_rect(*rect, *color);
+        }
+        buf
+    }
+
+    fn assert_screen(display: &Display<VecBuffer>, expected: &VecBuffer) {
+        for y in 0..H as i32 {
+            for x in 0..W as i32 {
+                assert_eq!(
+                    display.read_pixel(x, y),
+                    expected.read_pixel(x, y),
+                    "at ({}, {})",
+                    x,
+                    y
+                );
+            }
+        }
+    }
+
+    #[test_case]
+    fn test_display_overlay_restore() {
+        info!("TESTING graphics::display::test_display_overlay_restore");
+        let display = Display::new(VecBuffer::new(W, H, FrameBufferFormat::Bgrx));
+        let mut surface = display.console_surface().unwrap();
+        assert!(display.console_surface().is_none());
+        let console = pattern(1);
+        display.draw_console(|| {
+            surface.blit(0, 0, &console);
+            Some(surface.rect())
+        });
+        assert_screen(&display, &console);
+
+        let a_rect = Rect::new(5, 5, 20, 10);
+        let b_rect = Rect::new(15, 8, 30, 30); // clipped by the screen
+        let mut a = display.overlay(a_rect, Z_CURSOR);
+        assert_screen(&display, &console);
+        a.clear(RED);
+        let mut b = display.overlay(b_rect, Z_EMERGENCY);
+        b.clear(BLUE);
+        assert_eq!(b.rect(), Rect::new(15, 8, 25, 22));
+        assert_screen(
+            &display,
+            &compose(&console, &[(a_rect, RED), (b_rect, BLUE)]),
+        );
+
+        // Drawing into the lower overlay never covers the higher one
+        a.fill_rect(Rect::new(8, 0, 12, 10), GREEN);
+        let a_green = Rect::new(13, 5, 12, 10);
+        let expected = compose(&console, &[(a_rect, RED), (a_green, GREEN), (b_rect, BLUE)]);
+        assert_screen(&display, &expected);
+
+        // An overlay of the same z is placed above
+        let c_rect = Rect::new(0, 0, 8, 8);
+        let mut c = display.overlay(c_rect, Z_CURSOR);
+        c.clear(GREEN);
+        drop(a);
+        assert_screen(
+            &display,
+            &compose(&console, &[(c_rect, GREEN), (b_rect, BLUE)]),
+        );
+        drop(b);
+        assert_screen(&display, &compose(&console, &[(c_rect, GREEN)]));
+        drop(c);
+        assert_screen(&display, &console);
+    }
+
+    #[test_case]
+    fn test_display_console_under_overlays() {
+        info!("TESTING graphics::display::test_display_console_under_overlays");
+        let display = Display::new(VecBuffer::new(W, H, FrameBufferFormat::Rgbx));
+        let mut surface = display.console_surface().unwrap();
+        let mut console = pattern(1);
+        display.draw_console(|| {
+            surface.blit(0, 0, &console);
+            Some(surface.rect())
+        });
+
+        let a_rect = Rect::new(10, 10, 10, 10);
+        let b_rect = Rect::new(15, 0, 10, 15);
+        let mut a = display.overlay(a_rect, 1);
+        a.clear(RED);
+        let mut b = display.overlay(b_rect, 2);
+        b.clear(BLUE);
+
+        // The console draws rows across the overlays in steps, interleaved with the overlays
+        let next = pattern(2);
+        for (i, rows) in [(0, 6), (6, 12), (12, 30)].into_iter().enumerate() {
+            let rect = Rect::new(0, rows.0, W as u32, (rows.1 - rows.0) as u32);
+            display.draw_console(|| {
+                surface.blit_rect(rect.x, rect.y, &next, rect);
+                Some(rect)
+            });
+            console.blit_rect(rect.x, rect.y, &next, rect);
+            let color = [GREEN, RED, BLUE][i];
+            a.clear(color);
+            let expected = compose(&console, &[(a_rect, color), (b_rect, BLUE)]);
+            assert_screen(&display, &expected);
+        }
+
+        // The pixels saved under the overlays follow the console
+        drop(b);
+        assert_screen(&display, &compose(&console, &[(a_rect, BLUE)]));
+        drop(a);
+        assert_screen(&display, &next);
+    }
+
+    struct DrawJob {
+        display: &'static Display<VecBuffer>,
+        done: AtomicBool,
+    }
+
+    const DRAW_ROUNDS: usize = 100;
+    const LAST_PATTERN: u8 = 9;
+
+    extern "C" fn draw_console_job(arg: u64) -> ! {
+        let job = unsafe { &*(arg as *const DrawJob) };
+        let mut surface = job.display.console_surface().unwrap();
+        for i in 0..DRAW_ROUNDS {
+            let next = pattern(if i + 1 == DRAW_ROUNDS {
+                LAST_PATTERN
+            } else {
+                i as u8
+            });
+            for rows in [(0, 6), (6, 12), (12, 30)] {
+                let rect = Rect::new(0, rows.0, W as u32, (rows.1 - rows.0) as u32);
+                job.display.draw_console(|| {
+                    surface.blit_rect(rect.x, rect.y, &next, rect);
+                    Some(rect)
+                });
+                task::scheduler().r#yield();
+            }
+        }
+        job.done.store(true, Ordering::SeqCst);
+        loop {
+            task::scheduler().sleep(100);
+        }
+    }
+
+    extern "C" fn draw_overlays_job(arg: u64) -> ! {
+        let job = unsafe { &*(arg as *const DrawJob) };
+        for i in 0..DRAW_ROUNDS {
+            let rect = Rect::new(i as i32 % 20, i as i32 % 15, 20, 15);
+            let mut a = job.display.overlay(rect, Z_CURSOR);
+            a.clear([RED, GREEN, BLUE][i % 3]);
+            task::scheduler().r#yield();
+            let mut b = job.display.overlay(rect.offset(5, 5), Z_CURSOR);
+            b.clear(BLUE);
+            drop(a);
+            task::scheduler().r#yield();
+            drop(b);
+        }
+        job.done.store(true, Ordering::SeqCst);
+        loop {
+            task::scheduler().sleep(100);
+        }
+    }
+
+    #[test_case]
+    fn test_display_concurrent_drawers() {
+        info!("TESTING graphics::display::test_display_concurrent_drawers");
+        let display = &*Box::leak(Box::new(Display::new(VecBuffer::new(
+            W,
+            H,
+            FrameBufferFormat::Rgbx,
+        ))));
+        let jobs = [draw_console_job, draw_overlays_job].map(|entry| {
+            let job = &*Box::leak(Box::new(DrawJob {
+                display,
+                done: AtomicBool::new(false),
+            }));
+            task::scheduler().add(task::Priority::MAX, entry, job as *const DrawJob as u64);
+            job
+        });
+        while !jobs.iter().all(|job| job.done.load(Ordering::SeqCst)) {
+            task::scheduler().sleep(1);
+        }
+
+        // Every overlay is removed, restoring exactly what the console drew last
+        assert!(display.lock().overlays.is_empty());
+        assert_screen(display, &pattern(LAST_PATTERN));
+    }
+
+    static ABANDONED: AtomicBool = AtomicBool::new(false);
+
+    extern "C" fn abandon_display(arg: u64) -> ! {
+        let display = unsafe { &*(arg as *const Display<VecBuffer>) };
+        display.draw_console(|| {
+            ABANDONED.store(true, Ordering::SeqCst);
+            // Same as being killed by an oops while drawing
+            task::scheduler().exit()
+        });
+        unreachable!()
+    }
+
+    #[test_case]
+    fn test_display_abandoned_lock() {
+        info!("TESTING graphics::display::test_display_abandoned_lock");
+        let display = &*Box::leak(Box::new(Display::new(VecBuffer::new(
+            W,
+            H,
+            FrameBufferFormat::Bgrx,
+        ))));
+        let id = task::scheduler().add(
+            task::Priority::MAX,
+            abandon_display,
+            display as *const Display<VecBuffer> as u64,
+        );
+        while !ABANDONED.load(Ordering::SeqCst)
+            || task::scheduler().tasks().iter().any(|t| t.id == id)
+        {
+            task::scheduler().sleep(1);
+        }
+
+        // The emergency path never waits for the display
+        assert!(display.lock.lock().locked);
+        assert!(display
+            .try_overlay(Rect::new(0, 0, 4, 4), Z_EMERGENCY)
+            .is_none());
+
+        // The lock of the exited task is taken over
+        let mut a = display.overlay(Rect::new(0, 0, 10, 10), Z_CURSOR);
+        assert!(!display.console_drawing.load(Ordering::SeqCst));
+        a.clear(RED);
+        assert_eq!(display.read_pixel(5, 5), Some(RED));
+        let mut b = display
+            .try_overlay(Rect::new(0, 0, 4, 4), Z_EMERGENCY)
+            .unwrap();
+        b.clear(GREEN);
+        assert_eq!(display.read_pixel(1, 1), Some(GREEN));
+        drop(b);
+        drop(a);
+        assert!(!display.lock.lock().locked);
+    }
+}
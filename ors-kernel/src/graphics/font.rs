@@ -1,27 +1,179 @@
-use super::{Color, FrameBufferExt, FrameBufferFormat, VecBuffer};
-use ab_glyph::{Font, FontRef, ScaleFont};
+use super::{
+    char_width, Color, FontStyle, FrameBuffer, FrameBufferExt, FrameBufferFormat, VecBuffer,
+};
+use ab_glyph::{Font, FontRef, FontVec, OutlinedGlyph, ScaleFont};
 use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::fmt;
+use core::ops::RangeInclusive;
+
+mod fallback;
+
+/// Horizontal pixels per vertical pixel of the shear used to emulate italic glyphs.
+const ITALIC_SLOPE: u32 = 2;
+
+/// The default number of glyphs kept by the cache of `MonospaceFont`.
+pub const DEFAULT_CACHE_CAPACITY: usize = 4096;
+
+/// The range of font sizes (the height of a cell in pixels) accepted by `MonospaceFont`.
+pub const FONT_SIZE_RANGE: RangeInclusive<u32> = 6..=64;
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum FontError {
+    /// The data is not a TrueType or OpenType font.
+    Invalid,
+    /// The font has no visible glyph for ASCII characters, such as a zero-width one.
+    NoAsciiGlyphs,
+    InvalidSize(u32),
+}
+
+impl fmt::Display for FontError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Invalid => write!(f, "Not a TrueType or OpenType font"),
+            Self::NoAsciiGlyphs => write!(f, "The font has no usable ASCII glyphs"),
+            Self::InvalidSize(size) => write!(
+                f,
+                "Invalid font size {}, must be {}..={}",
+                size,
+                FONT_SIZE_RANGE.start(),
+                FONT_SIZE_RANGE.end()
+            ),
+        }
+    }
+}
+
+/// A parsed font, either embedded in the kernel image or loaded at runtime.
+#[derive(Debug)]
+pub enum FontFace<'a> {
+    Embedded(FontRef<'a>),
+    Loaded(FontVec),
+}
+
+impl<'a> FontFace<'a> {
+    pub fn from_slice(data: &'a [u8]) -> Result<Self, FontError> {
+        let font = FontRef::try_from_slice(data).map_err(|_| FontError::Invalid)?;
+        validate(&font)?;
+        Ok(Self::Embedded(font))
+    }
+
+    pub fn from_vec(data: Vec<u8>) -> Result<FontFace<'static>, FontError> {
+        let font = FontVec::try_from_vec(data).map_err(|_| FontError::Invalid)?;
+        validate(&font)?;
+        Ok(FontFace::Loaded(font))
+    }
+
+    /// Whether the font has a glyph for the character other than .notdef.
+    fn contains(&self, ch: char) -> bool {
+        // Characters missing in the font are mapped to the glyph 0 (.notdef)
+        match self {
+            Self::Embedded(font) => font.glyph_id(ch).0 != 0,
+            Self::Loaded(font) => font.glyph_id(ch).0 != 0,
+        }
+    }
+
+    fn outline(&self, ch: char, size: u32) -> Option<OutlinedGlyph> {
+        match self {
+            Self::Embedded(font) => outline(font, ch, size),
+            Self::Loaded(font) => outline(font, ch, size),
+        }
+    }
+}
+
+/// Check that the font can render text at all. Glyphs of each cell are scaled by the font size
+/// regardless of the advance widths, thus the font only needs a visible glyph.
+fn validate(font: &impl Font) -> Result<(), FontError> {
+    let id = font.glyph_id('M');
+    let visible = id.0 != 0
+        && font.h_advance_unscaled(id) > 0.0
+        && font.height_unscaled() > 0.0
+        && font.outline(id).is_some();
+    if !visible {
+        Err(FontError::NoAsciiGlyphs)?;
+    }
+    Ok(())
+}
+
+/// The outline of the character placed on the baseline of a cell of `size` pixels high.
+fn outline<F: Font>(font: &F, ch: char, size: u32) -> Option<OutlinedGlyph> {
+    let font = font.as_scaled(size as f32);
+    let mut glyph = font.scaled_glyph(ch);
+    glyph.position = ab_glyph::point(0.0, font.ascent());
+    font.outline_glyph(glyph)
+}
 
 #[derive(Debug)]
 pub struct MonospaceFont<'a> {
     size: u32,
-    normal: FontRef<'a>, // TODO: Use <T: Font> instead of FontRef
-    bold: FontRef<'a>,
+    normal: FontFace<'a>,
+    bold: FontFace<'a>,
     format: FrameBufferFormat,
-    cache: BTreeMap<CacheKey, VecBuffer>,
+    cache: GlyphCache,
+    palette: Vec<Color>,
 }
 
 impl<'a> MonospaceFont<'a> {
+    /// Create a font from font data known to be valid, such as the embedded ones.
     pub fn new(size: u32, normal: &'a [u8], bold: &'a [u8], format: FrameBufferFormat) -> Self {
-        Self {
+        let normal = FontFace::from_slice(normal).unwrap();
+        let bold = FontFace::from_slice(bold).unwrap();
+        Self::with_faces(size, normal, bold, format).unwrap()
+    }
+
+    pub fn with_faces(
+        size: u32,
+        normal: FontFace<'a>,
+        bold: FontFace<'a>,
+        format: FrameBufferFormat,
+    ) -> Result<Self, FontError> {
+        if !FONT_SIZE_RANGE.contains(&size) {
+            Err(FontError::InvalidSize(size))?;
+        }
+        Ok(Self {
             size,
-            normal: FontRef::try_from_slice(normal).unwrap(),
-            bold: FontRef::try_from_slice(bold).unwrap(),
+            normal,
+            bold,
             format,
-            cache: BTreeMap::new(),
+            cache: GlyphCache::new(DEFAULT_CACHE_CAPACITY),
+            palette: Vec::new(),
+        })
+    }
+
+    /// Keep at most `capacity` glyphs in the cache (at least 1).
+    pub fn with_cache_capacity(mut self, capacity: usize) -> Self {
+        self.set_cache_capacity(capacity);
+        self
+    }
+
+    /// Change the number of cached glyphs. Least recently used glyphs are evicted if exceeded.
+    pub fn set_cache_capacity(&mut self, capacity: usize) {
+        self.cache.capacity = capacity.max(1);
+        self.cache.shrink();
+    }
+
+    /// Set the colors that are cached by their index in `palette` rather than by their value.
+    /// Changing the palette drops every cached glyph.
+    pub fn set_palette(&mut self, palette: &[Color]) {
+        if self.palette != palette {
+            self.palette = palette.to_vec();
+            self.cache.clear();
         }
     }
 
+    pub fn cache_stats(&self) -> CacheStats {
+        CacheStats {
+            entries: self.cache.entries.len(),
+            capacity: self.cache.capacity,
+            bytes: self.cache.bytes,
+            hits: self.cache.hits,
+            misses: self.cache.misses,
+        }
+    }
+
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
     pub fn unit_width(&self) -> u32 {
         (self.size + 1) / 2
     }
@@ -34,49 +186,316 @@ impl<'a> MonospaceFont<'a> {
         self.format
     }
 
+    /// Get the rendered glyph of the character. The glyph of a wide character spans two units.
     pub fn get(&mut self, ch: char, fg: Color, bg: Color, style: FontStyle) -> &VecBuffer {
-        let key = CacheKey { ch, fg, bg, style };
-        let Self { size, format, .. } = *self;
-        let unit_width = self.unit_width();
-        let unit_height = self.unit_height();
-        let font = match style {
-            FontStyle::Normal => &self.normal,
-            FontStyle::Bold => &self.bold,
-        }
-        .as_scaled(size as f32);
-        self.cache.entry(key).or_insert_with(|| {
-            let mut glyph = font.scaled_glyph(ch);
-            glyph.position = ab_glyph::point(0.0, font.ascent());
-            let mut buf = VecBuffer::new(unit_width as usize, unit_height as usize, format);
-            buf.clear(bg);
-            if let Some(q) = font.outline_glyph(glyph) {
-                let min_x = q.px_bounds().min.x as i32;
-                let min_y = q.px_bounds().min.y as i32;
-                q.draw(|x, y, c| {
-                    buf.write_pixel(min_x + x as i32, min_y + y as i32, bg.mix(fg, c));
-                });
+        let key = self.cache_key(ch, fg, bg, style);
+        if self.cache.touch(key) {
+            self.cache.hits += 1;
+        } else {
+            self.cache.misses += 1;
+            let glyph = self.rasterize(ch, fg, bg, style);
+            self.cache.insert(key, glyph);
+        }
+        &self.cache.entries[&key].0
+    }
+
+    /// Rasterize the characters in advance so that the first use of them does not stall.
+    /// Pre-warming is not counted in the cache statistics.
+    pub fn prewarm(&mut self, chars: impl IntoIterator<Item = char>, fg: Color, bg: Color) {
+        for ch in chars {
+            let key = self.cache_key(ch, fg, bg, FontStyle::Normal);
+            if !self.cache.touch(key) {
+                let glyph = self.rasterize(ch, fg, bg, FontStyle::Normal);
+                self.cache.insert(key, glyph);
             }
-            buf
-        })
+        }
+    }
+
+    fn cache_key(&self, ch: char, fg: Color, bg: Color, style: FontStyle) -> CacheKey {
+        let key_color = |c| match self.palette.iter().position(|p| *p == c) {
+            Some(i) if i <= u8::MAX as usize => KeyColor::Palette(i as u8),
+            _ => KeyColor::Rgb(c),
+        };
+        CacheKey {
+            ch,
+            fg: key_color(fg),
+            bg: key_color(bg),
+            style,
+        }
     }
+
+    fn rasterize(&self, ch: char, fg: Color, bg: Color, style: FontStyle) -> VecBuffer {
+        let width = self.unit_width() * char_width(ch) as u32;
+        let font = if style.is_bold() {
+            &self.bold
+        } else {
+            &self.normal
+        };
+        let mut buf = VecBuffer::new(width as usize, self.unit_height() as usize, self.format);
+        buf.clear(bg);
+        let missing = !font.contains(ch);
+        if !missing || !fallback::draw(&mut buf, ch, fg, style.is_bold()) {
+            match font.outline(ch, self.size) {
+                Some(q) => {
+                    let min_x = q.px_bounds().min.x as i32;
+                    let min_y = q.px_bounds().min.y as i32;
+                    q.draw(|x, y, c| {
+                        buf.write_pixel(min_x + x as i32, min_y + y as i32, bg.mix(fg, c));
+                    });
+                }
+                // The .notdef glyph of the font may be empty
+                None if missing => fallback::draw_replacement(&mut buf, fg),
+                None => {}
+            }
+        }
+        if style.is_italic() {
+            // There are no italic fonts, so the glyph is sheared and then cropped to the cell
+            // keeping the middle row in place
+            let sheared = buf.shear_horizontal(ITALIC_SLOPE, bg);
+            let ofs_x = (sheared.width() - width as usize) / 2;
+            buf.blit(-(ofs_x as i32), 0, &sheared);
+        }
+        buf
+    }
+}
+
+/// Statistics of the glyph cache of `MonospaceFont`.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub struct CacheStats {
+    pub entries: usize,
+    pub capacity: usize,
+    /// Total size of the pixel data of the cached glyphs.
+    pub bytes: usize,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl CacheStats {
+    /// The ratio of lookups served from the cache, or `None` if nothing has been looked up.
+    pub fn hit_rate(&self) -> Option<f32> {
+        let total = self.hits + self.misses;
+        (total != 0).then(|| self.hits as f32 / total as f32)
+    }
+}
+
+/// A glyph cache that evicts the least recently used glyph when it exceeds the capacity.
+#[derive(Debug)]
+struct GlyphCache {
+    capacity: usize,
+    entries: BTreeMap<CacheKey, (VecBuffer, u64)>,
+    recency: BTreeMap<u64, CacheKey>, // last used time -> key
+    clock: u64,
+    bytes: usize,
+    hits: u64,
+    misses: u64,
+}
+
+impl GlyphCache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: BTreeMap::new(),
+            recency: BTreeMap::new(),
+            clock: 0,
+            bytes: 0,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// Mark the glyph as the most recently used one. Returns false if it is not cached.
+    fn touch(&mut self, key: CacheKey) -> bool {
+        match self.entries.get_mut(&key) {
+            Some((_, last_used)) => {
+                self.recency.remove(last_used);
+                self.clock += 1;
+                *last_used = self.clock;
+                self.recency.insert(self.clock, key);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn insert(&mut self, key: CacheKey, glyph: VecBuffer) {
+        self.clock += 1;
+        self.bytes += glyph_bytes(&glyph);
+        if let Some((old, last_used)) = self.entries.insert(key, (glyph, self.clock)) {
+            self.bytes -= glyph_bytes(&old);
+            self.recency.remove(&last_used);
+        }
+        self.recency.insert(self.clock, key);
+        self.shrink();
+    }
+
+    fn shrink(&mut self) {
+        while self.entries.len() > self.capacity {
+            let (&last_used, &key) = self.recency.iter().next().unwrap();
+            self.recency.remove(&last_used);
+            let (glyph, _) = self.entries.remove(&key).unwrap();
+            self.bytes -= glyph_bytes(&glyph);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.recency.clear();
+        self.bytes = 0;
+    }
+}
+
+fn glyph_bytes(glyph: &VecBuffer) -> usize {
+    glyph.width() * glyph.height() * 4
+}
+
+/// Colors of the glyph cache key. Colors in the palette are keyed by their index, which is
+/// cheaper to compare than the color itself.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy, Hash)]
+enum KeyColor {
+    Palette(u8),
+    Rgb(Color),
 }
 
 #[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy, Hash)]
 struct CacheKey {
     ch: char,
-    fg: Color,
-    bg: Color,
+    fg: KeyColor,
+    bg: KeyColor,
     style: FontStyle,
 }
 
-#[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy, Hash)]
-pub enum FontStyle {
-    Normal,
-    Bold,
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+    use log::info;
+
+    fn tamzen(capacity: usize) -> MonospaceFont<'static> {
+        MonospaceFont::new(
+            14,
+            include_bytes!("../console/Tamzen7x14r.ttf"),
+            include_bytes!("../console/Tamzen7x14b.ttf"),
+            FrameBufferFormat::Rgbx,
+        )
+        .with_cache_capacity(capacity)
+    }
+
+    fn pixels(buf: &VecBuffer) -> Vec<Option<Color>> {
+        (0..buf.height() as i32)
+            .flat_map(|y| (0..buf.width() as i32).map(move |x| buf.read_pixel(x, y)))
+            .collect()
+    }
+
+    #[test_case]
+    fn test_font_faces() {
+        info!("TESTING graphics::font::test_font_faces");
+        let data = include_bytes!("../console/Tamzen7x14r.ttf");
+        let face = || FontFace::from_vec(data.to_vec()).unwrap();
+        assert!(matches!(face(), FontFace::Loaded(_)));
+        assert_eq!(
+            FontFace::from_vec(vec![0; 64]).err(),
+            Some(FontError::Invalid)
+        );
+        // The glyph data of a truncated font is missing
+        assert_eq!(
+            FontFace::from_slice(&data[..data.len() / 2]).err(),
+            Some(FontError::NoAsciiGlyphs)
+        );
+        let format = FrameBufferFormat::Rgbx;
+        for size in [0, 5, 65] {
+            assert_eq!(
+                MonospaceFont::with_faces(size, face(), face(), format).err(),
+                Some(FontError::InvalidSize(size))
+            );
+        }
+        let mut font = MonospaceFont::with_faces(20, face(), face(), format).unwrap();
+        assert_eq!((font.unit_width(), font.unit_height()), (10, 20));
+        let glyph = font.get(
+            'a',
+            Color::new(255, 255, 255),
+            Color::new(0, 0, 0),
+            FontStyle::Bold,
+        );
+        assert_eq!((glyph.width(), glyph.height()), (10, 20));
+    }
+
+    #[test_case]
+    fn test_glyph_cache_eviction() {
+        info!("TESTING graphics::font::test_glyph_cache_eviction");
+        let mut font = tamzen(16);
+        let bg = Color::new(0, 0, 0);
+        let fg = |i: u8| Color::new(i, 255 - i, 128);
+        let a = pixels(font.get('a', fg(0), bg, FontStyle::Normal));
+        for i in 0..64 {
+            for ch in "xyz".chars() {
+                font.get(ch, fg(i), bg, FontStyle::Bold);
+            }
+            let stats = font.cache_stats();
+            assert!(stats.entries <= 16);
+            assert_eq!(stats.bytes, stats.entries * 7 * 14 * 4);
+        }
+        let stats = font.cache_stats();
+        assert_eq!((stats.hits, stats.misses), (0, 1 + 64 * 3));
+
+        // 'a' was evicted long ago
+        assert_eq!(pixels(font.get('a', fg(0), bg, FontStyle::Normal)), a);
+        assert_eq!(font.cache_stats().misses, 2 + 64 * 3);
+        font.get('a', fg(0), bg, FontStyle::Normal);
+        assert_eq!(font.cache_stats().hits, 1);
+
+        font.set_cache_capacity(4);
+        assert_eq!(font.cache_stats().entries, 4);
+        assert_eq!(font.cache_stats().bytes, 4 * 7 * 14 * 4);
+        assert_eq!(font.cache_stats().hit_rate(), Some(1.0 / 195.0));
+    }
+
+    #[test_case]
+    fn test_glyph_cache_recency() {
+        info!("TESTING graphics::font::test_glyph_cache_recency");
+        let mut font = tamzen(2);
+        let (fg, bg) = (Color::new(255, 255, 255), Color::new(0, 0, 0));
+        font.set_palette(&[bg, fg]);
+        font.prewarm(['a', 'b'], fg, bg);
+        assert_eq!(font.cache_stats().entries, 2);
+        assert_eq!(font.cache_stats().hit_rate(), None);
+        font.get('a', fg, bg, FontStyle::Normal); // 'b' becomes the least recently used
+        font.get('c', fg, bg, FontStyle::Normal);
+        font.get('a', fg, bg, FontStyle::Normal);
+        font.get('b', fg, bg, FontStyle::Normal);
+        let stats = font.cache_stats();
+        assert_eq!((stats.hits, stats.misses), (2, 2));
+        assert_eq!(
+            font.cache_key('a', fg, Color::new(1, 2, 3), FontStyle::Normal),
+            CacheKey {
+                ch: 'a',
+                fg: KeyColor::Palette(1),
+                bg: KeyColor::Rgb(Color::new(1, 2, 3)),
+                style: FontStyle::Normal,
+            }
+        );
+        font.set_palette(&[fg, bg]);
+        assert_eq!(font.cache_stats().entries, 0);
+    }
 
-impl FontStyle {
-    pub fn is_bold(self) -> bool {
-        matches!(self, Self::Bold)
+    #[test_case]
+    fn test_fallback_glyphs() {
+        info!("TESTING graphics::font::test_fallback_glyphs");
+        let mut font = tamzen(16);
+        let (fg, bg) = (Color::new(255, 255, 0), Color::new(0, 0, 128));
+        // Tamzen has no box drawing characters
+        let corner = font.get('┌', fg, bg, FontStyle::Normal);
+        assert_eq!(corner.read_pixel(3, 6), Some(fg));
+        assert_eq!(corner.read_pixel(6, 6), Some(fg));
+        assert_eq!(corner.read_pixel(3, 13), Some(fg));
+        assert_eq!(corner.read_pixel(0, 6), Some(bg));
+        assert_eq!(corner.read_pixel(3, 0), Some(bg));
+        assert_eq!(corner.read_pixel(4, 7), Some(bg));
+        let bold = font.get('┌', fg, bg, FontStyle::Bold);
+        assert_eq!(bold.read_pixel(4, 7), Some(fg));
+        let block = pixels(font.get('█', fg, bg, FontStyle::Normal));
+        assert!(block.iter().all(|c| *c == Some(fg)));
+        // Other missing characters still show the replacement box
+        let notdef = pixels(font.get('\u{e000}', fg, bg, FontStyle::Normal));
+        assert!(notdef.iter().any(|c| *c == Some(fg)));
     }
 }
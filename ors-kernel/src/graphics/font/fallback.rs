@@ -0,0 +1,473 @@
+//! Glyphs synthesized for the characters that the fonts lack: the common arrows (U+2190-U+2195),
+//! box drawing (U+2500-U+257F), and block elements (U+2580-U+259F). They are made of lines and
+//! rectangles at positions relative to the cell, so that adjacent cells connect seamlessly.
+//! Any other missing character is drawn as a replacement box.
+
+use super::super::{Color, FrameBuffer, FrameBufferExt, Rect};
+
+/// Draw the glyph of `ch` in `fg` over `buf`, which is already filled with the background.
+/// Lines are thickened by one pixel if `bold`. Returns false if `ch` is not supported.
+pub(super) fn draw(buf: &mut impl FrameBuffer, ch: char, fg: Color, bold: bool) -> bool {
+    let (w, h) = (buf.width() as i32, buf.height() as i32);
+    let bold = bold as i32;
+    let mut cell = Cell {
+        buf,
+        w,
+        h,
+        fg,
+        bold,
+    };
+    match ch as u32 {
+        c @ 0x2190..=0x2195 => cell.arrow(c - 0x2190),
+        c @ 0x2571..=0x2573 => cell.diagonal(c != 0x2571, c != 0x2572),
+        c @ 0x2500..=0x257f => {
+            let arms = BOX_DRAWINGS[(c - 0x2500) as usize].map(Weight::from_byte);
+            let dashes = match c {
+                0x2504..=0x2507 => 3,
+                0x2508..=0x250b => 4,
+                0x254c..=0x254f => 2,
+                _ => 0,
+            };
+            cell.box_drawing(arms, dashes);
+        }
+        c @ 0x2580..=0x259f => cell.block(c),
+        _ => return false,
+    }
+    true
+}
+
+/// Draw the replacement box of the characters that neither the font nor `draw` supports, since
+/// the .notdef glyphs of the fonts may be empty.
+pub(super) fn draw_replacement(buf: &mut impl FrameBuffer, fg: Color) {
+    let (w, h) = (buf.width() as i32, buf.height() as i32);
+    let mut cell = Cell {
+        buf,
+        w,
+        h,
+        fg,
+        bold: 0,
+    };
+    let (x, y, w, h) = (1, h / 7, w - 2, h - h / 7 * 2);
+    cell.fill(x, y, w, 1);
+    cell.fill(x, y + h - 1, w, 1);
+    cell.fill(x, y, 1, h);
+    cell.fill(x + w - 1, y, 1, h);
+}
+
+/// Weights of the left, up, right, and down arms of box drawing characters from U+2500:
+/// `.` none, `l` light, `h` heavy, and `d` double. Diagonals (U+2571-U+2573) have no arms.
+const BOX_DRAWINGS: [&[u8; 4]; 128] = [
+    b"l.l.", b"h.h.", b".l.l", b".h.h", // ─━│┃
+    b"l.l.", b"h.h.", b".l.l", b".h.h", // ┄┅┆┇
+    b"l.l.", b"h.h.", b".l.l", b".h.h", // ┈┉┊┋
+    b"..ll", b"..hl", b"..lh", b"..hh", // ┌┍┎┏
+    b"l..l", b"h..l", b"l..h", b"h..h", // ┐┑┒┓
+    b".ll.", b".lh.", b".hl.", b".hh.", // └┕┖┗
+    b"ll..", b"hl..", b"lh..", b"hh..", // ┘┙┚┛
+    b".lll", b".lhl", b".hll", b".llh", // ├┝┞┟
+    b".hlh", b".hhl", b".lhh", b".hhh", // ┠┡┢┣
+    b"ll.l", b"hl.l", b"lh.l", b"ll.h", // ┤┥┦┧
+    b"lh.h", b"hh.l", b"hl.h", b"hh.h", // ┨┩┪┫
+    b"l.ll", b"h.ll", b"l.hl", b"h.hl", // ┬┭┮┯
+    b"l.lh", b"h.lh", b"l.hh", b"h.hh", // ┰┱┲┳
+    b"lll.", b"hll.", b"llh.", b"hlh.", // ┴┵┶┷
+    b"lhl.", b"hhl.", b"lhh.", b"hhh.", // ┸┹┺┻
+    b"llll", b"hlll", b"llhl", b"hlhl", // ┼┽┾┿
+    b"lhll", b"lllh", b"lhlh", b"hhll", // ╀╁╂╃
+    b"lhhl", b"hllh", b"llhh", b"hhhl", // ╄╅╆╇
+    b"hlhh", b"hhlh", b"lhhh", b"hhhh", // ╈╉╊╋
+    b"l.l.", b"h.h.", b".l.l", b".h.h", // ╌╍╎╏
+    b"d.d.", b".d.d", b"..dl", b"..ld", // ═║╒╓
+    b"..dd", b"d..l", b"l..d", b"d..d", // ╔╕╖╗
+    b".ld.", b".dl.", b".dd.", b"dl..", // ╘╙╚╛
+    b"ld..", b"dd..", b".ldl", b".dld", // ╜╝╞╟
+    b".ddd", b"dl.l", b"ld.d", b"dd.d", // ╠╡╢╣
+    b"d.dl", b"l.ld", b"d.dd", b"dld.", // ╤╥╦╧
+    b"ldl.", b"ddd.", b"dldl", b"ldld", // ╨╩╪╫
+    b"dddd", b"..ll", b"l..l", b"ll..", // ╬╭╮╯
+    b".ll.", b"....", b"....", b"....", // ╰╱╲╳
+    b"l...", b".l..", b"..l.", b"...l", // ╴╵╶╷
+    b"h...", b".h..", b"..h.", b"...h", // ╸╹╺╻
+    b"l.h.", b".l.h", b"h.l.", b".h.l", // ╼╽╾╿
+];
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+enum Weight {
+    None,
+    Light,
+    Heavy,
+    Double,
+}
+
+impl Weight {
+    fn from_byte(b: u8) -> Self {
+        match b {
+            b'l' => Self::Light,
+            b'h' => Self::Heavy,
+            b'd' => Self::Double,
+            _ => Self::None,
+        }
+    }
+}
+
+struct Cell<'a, B> {
+    buf: &'a mut B,
+    w: i32,
+    h: i32,
+    fg: Color,
+    /// 1 if bold, which is added to the thickness of lines.
+    bold: i32,
+}
+
+impl<'a, B: FrameBuffer> Cell<'a, B> {
+    fn center(&self) -> (i32, i32) {
+        ((self.w - 1) / 2, (self.h - 1) / 2)
+    }
+
+    fn fill(&mut self, x: i32, y: i32, w: i32, h: i32) {
+        if 0 < w && 0 < h {
+            let rect = Rect::new(x, y, w as u32, h as u32);
+            self.buf.fill_rect(rect, self.fg);
+        }
+    }
+
+    /// Spans of the strokes of a line across it, relative to the center of the line.
+    fn strokes(&self, weight: Weight) -> &'static [(i32, i32)] {
+        match (weight, self.bold) {
+            (Weight::None, _) => &[],
+            (Weight::Light, 0) => &[(0, 0)],
+            (Weight::Light, _) => &[(0, 1)],
+            (Weight::Heavy, 0) => &[(-1, 1)],
+            (Weight::Heavy, _) => &[(-1, 2)],
+            (Weight::Double, _) => &[(-1, -1), (1, 1)],
+        }
+    }
+
+    /// The extent of the strokes of the lines across them, relative to the center.
+    fn reach(&self, weights: [Weight; 2]) -> (i32, i32) {
+        weights
+            .into_iter()
+            .flat_map(|w| self.strokes(w))
+            .fold((0, 0), |(a, b), (s, e)| (a.min(*s), b.max(*e)))
+    }
+
+    /// Draw the arms from the edges of the cell to the center. Each arm goes through the
+    /// strokes of the arms perpendicular to it so that they are joined without gaps, except
+    /// that a stroke of a double line stops at a double line on its side, leaving the inside
+    /// of the corner open.
+    fn box_drawing(&mut self, [left, up, right, down]: [Weight; 4], dashes: i32) {
+        let (cx, cy) = self.center();
+        let (va, vb) = self.reach([up, down]);
+        let (ha, hb) = self.reach([left, right]);
+        let (w, h) = (self.w, self.h);
+        let side = |s: i32, before: Weight, after: Weight| match s {
+            -1 => before == Weight::Double,
+            1 => after == Weight::Double,
+            _ => false,
+        };
+        for (weight, is_left) in [(left, true), (right, false)] {
+            for &(s, e) in self.strokes(weight) {
+                let range = match (is_left, side(s, up, down)) {
+                    (true, false) => 0..cx + vb + 1,
+                    (true, true) => 0..cx,
+                    (false, false) => cx + va..w,
+                    (false, true) => cx + 1..w,
+                };
+                self.dashed(dashes, w, range, |c, x, len| {
+                    c.fill(x, cy + s, len, e - s + 1)
+                });
+            }
+        }
+        for (weight, is_up) in [(up, true), (down, false)] {
+            for &(s, e) in self.strokes(weight) {
+                let range = match (is_up, side(s, left, right)) {
+                    (true, false) => 0..cy + hb + 1,
+                    (true, true) => 0..cy,
+                    (false, false) => cy + ha..h,
+                    (false, true) => cy + 1..h,
+                };
+                self.dashed(dashes, h, range, |c, y, len| {
+                    c.fill(cx + s, y, e - s + 1, len)
+                });
+            }
+        }
+    }
+
+    /// Draw the runs of `range` that are not in the gaps of `dashes` dashes over `len` pixels.
+    fn dashed(
+        &mut self,
+        dashes: i32,
+        len: i32,
+        range: core::ops::Range<i32>,
+        mut draw: impl FnMut(&mut Self, i32, i32),
+    ) {
+        if dashes == 0 {
+            return draw(self, range.start, range.end - range.start);
+        }
+        // Sampled at the center of each pixel
+        for i in range.filter(|i| (i * 2 + 1) * dashes / len % 2 == 0) {
+            draw(self, i, 1);
+        }
+    }
+
+    fn diagonal(&mut self, down_right: bool, up_right: bool) {
+        let (w, h) = (self.w, self.h);
+        for y in 0..h {
+            // Rounded to the nearest pixel
+            let x = (y * (w - 1) * 2 + h - 1) / ((h - 1) * 2);
+            if down_right {
+                self.fill(x, y, 1 + self.bold, 1);
+            }
+            if up_right {
+                self.fill(w - 1 - x - self.bold, y, 1 + self.bold, 1);
+            }
+        }
+    }
+
+    /// Draw ←, ↑, →, ↓, ↔, or ↕. Vertical arrows are inset from the top and the bottom so that
+    /// they look as long as horizontal ones.
+    fn arrow(&mut self, index: u32) {
+        let (cx, cy) = self.center();
+        let (w, h) = (self.w, self.h);
+        let head = cx;
+        // Whether the arrow has the head at the left or top, and at the right or bottom
+        let (start, end) = match index {
+            0 | 1 => (true, false),
+            2 | 3 => (false, true),
+            _ => (true, true),
+        };
+        if matches!(index, 0 | 2 | 4) {
+            self.fill(0, cy, w, 1 + self.bold);
+            for i in 1..=head {
+                for x in [start.then(|| i), end.then(|| w - 1 - i)]
+                    .into_iter()
+                    .flatten()
+                {
+                    self.fill(x, cy - i, 1, 1 + self.bold);
+                    self.fill(x, cy + i, 1, 1 + self.bold);
+                }
+            }
+        } else {
+            let inset = (h - w) / 2;
+            self.fill(cx, inset, 1 + self.bold, h - inset * 2);
+            for i in 1..=head {
+                for y in [start.then(|| inset + i), end.then(|| h - 1 - inset - i)]
+                    .into_iter()
+                    .flatten()
+                {
+                    self.fill(cx - i, y, 1 + self.bold, 1);
+                    self.fill(cx + i, y, 1 + self.bold, 1);
+                }
+            }
+        }
+    }
+
+    fn block(&mut self, c: u32) {
+        let (w, h) = (self.w, self.h);
+        let eighths = |len: i32, n: u32| (len * n as i32 + 4) / 8;
+        // Halves are split at the same positions as the quadrants
+        let (mx, my) = (eighths(w, 4), h - eighths(h, 4));
+        match c {
+            0x2580 => self.fill(0, 0, w, my),
+            0x2581..=0x2588 => {
+                let n = eighths(h, c - 0x2580);
+                self.fill(0, h - n, w, n);
+            }
+            0x2589..=0x258f => self.fill(0, 0, eighths(w, 0x2590 - c), h),
+            0x2590 => self.fill(mx, 0, w - mx, h),
+            0x2591..=0x2593 => {
+                let shade = c - 0x2590;
+                for y in 0..h {
+                    for x in 0..w {
+                        let on = match shade {
+                            1 => x % 2 == 0 && y % 2 == 0,
+                            2 => (x + y) % 2 == 0,
+                            _ => x % 2 == 0 || y % 2 == 0,
+                        };
+                        if on {
+                            self.fill(x, y, 1, 1);
+                        }
+                    }
+                }
+            }
+            0x2594 => self.fill(0, 0, w, eighths(h, 1)),
+            0x2595 => {
+                let n = eighths(w, 1);
+                self.fill(w - n, 0, n, h);
+            }
+            _ => {
+                // Upper left, upper right, lower left, and lower right
+                let quadrants = match c {
+                    0x2596 => 0b0010,
+                    0x2597 => 0b0001,
+                    0x2598 => 0b1000,
+                    0x2599 => 0b1011,
+                    0x259a => 0b1001,
+                    0x259b => 0b1110,
+                    0x259c => 0b1101,
+                    0x259d => 0b0100,
+                    0x259e => 0b0110,
+                    _ => 0b0111,
+                };
+                let rects = [
+                    (0, 0, mx, my),
+                    (mx, 0, w - mx, my),
+                    (0, my, mx, h - my),
+                    (mx, my, w - mx, h - my),
+                ];
+                for (i, (x, y, w, h)) in rects.into_iter().enumerate() {
+                    if quadrants & (0b1000 >> i) != 0 {
+                        self.fill(x, y, w, h);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::{FrameBufferFormat, VecBuffer};
+    use super::*;
+    use alloc::string::String;
+    use alloc::vec::Vec;
+    use log::info;
+
+    const FG: Color = Color::new(255, 255, 255);
+    const BG: Color = Color::new(0, 0, 128);
+
+    /// Render the glyph at the 7x14 cell of Tamzen7x14 as rows of `#` (fg) and `.` (bg).
+    fn render(ch: char, bold: bool) -> Vec<String> {
+        let mut buf = VecBuffer::new(7, 14, FrameBufferFormat::Rgbx);
+        buf.clear(BG);
+        assert!(draw(&mut buf, ch, FG, bold), "{} is not supported", ch);
+        (0..14)
+            .map(|y| {
+                (0..7)
+                    .map(|x| match buf.read_pixel(x, y) {
+                        Some(FG) => '#',
+                        Some(BG) => '.',
+                        _ => '?',
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test_case]
+    fn test_box_drawing() {
+        info!("TESTING graphics::font::fallback::test_box_drawing");
+        #[rustfmt::skip]
+        let cases = [
+            ('┌', false, [
+                ".......", ".......", ".......", ".......", ".......", ".......", "...####",
+                "...#...", "...#...", "...#...", "...#...", "...#...", "...#...", "...#...",
+            ]),
+            ('┼', true, [
+                "...##..", "...##..", "...##..", "...##..", "...##..", "...##..", "#######",
+                "#######", "...##..", "...##..", "...##..", "...##..", "...##..", "...##..",
+            ]),
+            ('╔', false, [
+                ".......", ".......", ".......", ".......", ".......", "..#####", "..#....",
+                "..#.###", "..#.#..", "..#.#..", "..#.#..", "..#.#..", "..#.#..", "..#.#..",
+            ]),
+            ('┥', false, [
+                "...#...", "...#...", "...#...", "...#...", "...#...", "####...", "####...",
+                "####...", "...#...", "...#...", "...#...", "...#...", "...#...", "...#...",
+            ]),
+            ('┄', false, [
+                ".......", ".......", ".......", ".......", ".......", ".......", "#.#..#.",
+                ".......", ".......", ".......", ".......", ".......", ".......", ".......",
+            ]),
+        ];
+        for (ch, bold, expected) in cases {
+            assert_eq!(render(ch, bold), expected, "{}", ch);
+        }
+        // Every box drawing character touches the edges of the cell where it has arms, except
+        // for the dashed lines and the diagonals
+        let is_solid = |ch: &char| !matches!(*ch as u32, 0x2504..=0x250b | 0x254c..=0x254f);
+        let chars = ('\u{2500}'..='\u{2570}').chain('\u{2574}'..='\u{257f}');
+        for ch in chars.filter(is_solid) {
+            let rows = render(ch, false);
+            let arms = BOX_DRAWINGS[ch as usize - 0x2500];
+            assert_eq!(
+                rows.iter().any(|r| r.starts_with('#')),
+                arms[0] != b'.',
+                "{}",
+                ch
+            );
+            assert_eq!(rows[0].contains('#'), arms[1] != b'.', "{}", ch);
+            assert_eq!(
+                rows.iter().any(|r| r.ends_with('#')),
+                arms[2] != b'.',
+                "{}",
+                ch
+            );
+            assert_eq!(rows[13].contains('#'), arms[3] != b'.', "{}", ch);
+        }
+    }
+
+    #[test_case]
+    fn test_block_elements() {
+        info!("TESTING graphics::font::fallback::test_block_elements");
+        let count = |ch| {
+            render(ch, false)
+                .iter()
+                .map(|row| row.matches('#').count())
+                .sum::<usize>()
+        };
+        assert_eq!(count('█'), 7 * 14);
+        assert_eq!(count('▀') + count('▄'), 7 * 14);
+        assert_eq!(count('▌') + count('▐'), 7 * 14);
+        assert_eq!(count('▘') + count('▝') + count('▖') + count('▗'), 7 * 14);
+        assert_eq!(count('▚') + count('▞'), 7 * 14);
+        let lower = render('▁', false);
+        assert!(lower[..12].iter().all(|row| row == "......."));
+        assert!(lower[12..].iter().all(|row| row == "#######"));
+        assert_eq!(count('░'), 4 * 7);
+        assert_eq!(count('▒'), 49);
+        assert_eq!(count('▓'), 7 * 14 - 3 * 7);
+        let medium = render('▒', false);
+        assert_eq!(medium[0], "#.#.#.#");
+        assert_eq!(medium[1], ".#.#.#.");
+        // Blocks are not thickened
+        assert_eq!(render('▚', true), render('▚', false));
+    }
+
+    #[test_case]
+    fn test_arrows() {
+        info!("TESTING graphics::font::fallback::test_arrows");
+        #[rustfmt::skip]
+        let cases = [
+            ('→', [
+                ".......", ".......", ".......", "...#...", "....#..", ".....#.", "#######",
+                ".....#.", "....#..", "...#...", ".......", ".......", ".......", ".......",
+            ]),
+            ('↓', [
+                ".......", ".......", ".......", "...#...", "...#...", "...#...", "...#...",
+                "#..#..#", ".#.#.#.", "..###..", "...#...", ".......", ".......", ".......",
+            ]),
+        ];
+        for (ch, expected) in cases {
+            assert_eq!(render(ch, false), expected, "{}", ch);
+        }
+        let mut buf = VecBuffer::new(7, 14, FrameBufferFormat::Rgbx);
+        assert!(!draw(&mut buf, 'a', FG, false));
+        assert!(!draw(&mut buf, '\u{2196}', FG, false));
+    }
+
+    #[test_case]
+    fn test_replacement() {
+        info!("TESTING graphics::font::fallback::test_replacement");
+        let mut buf = VecBuffer::new(7, 14, FrameBufferFormat::Rgbx);
+        buf.clear(BG);
+        draw_replacement(&mut buf, FG);
+        let rows = (0..14)
+            .map(|y| (0..7).filter(|x| buf.read_pixel(*x, y) == Some(FG)).count())
+            .collect::<Vec<_>>();
+        assert_eq!(rows, [0, 0, 5, 2, 2, 2, 2, 2, 2, 2, 2, 5, 0, 0]);
+        assert_eq!(buf.read_pixel(1, 5), Some(FG));
+        assert_eq!(buf.read_pixel(5, 5), Some(FG));
+        assert_eq!(buf.read_pixel(0, 5), Some(BG));
+    }
+}
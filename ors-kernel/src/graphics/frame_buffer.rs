@@ -1,6 +1,7 @@
-use super::Color;
+use super::{Color, FrameBufferExt};
 use alloc::vec;
 use alloc::vec::Vec;
+use core::convert::TryFrom;
 use core::slice;
 use ors_common::frame_buffer::{FrameBuffer as RawFrameBuffer, PixelFormat as RawPixelFormat};
 
@@ -61,6 +62,23 @@ impl VecBuffer {
             format,
         }
     }
+
+    /// Create a copy of this buffer sheared horizontally, leaning to the right as italic glyphs.
+    /// The `y`-th row from the bottom is shifted right by `y / slope` pixels, and the result is
+    /// wider by `height / slope` pixels. The uncovered area is filled with `bg`.
+    pub fn shear_horizontal(&self, slope: u32, bg: Color) -> VecBuffer {
+        let slope = slope.max(1) as usize;
+        let mut buf = VecBuffer::new(self.width + self.height / slope, self.height, self.format);
+        buf.clear(bg);
+        let row_len = self.width * 4;
+        for y in 0..self.height {
+            let shift = (self.height - 1 - y) / slope;
+            let i = (y * buf.width + shift) * 4;
+            let j = y * row_len;
+            buf.data[i..i + row_len].copy_from_slice(&self.data[j..j + row_len]);
+        }
+        buf
+    }
 }
 
 impl FrameBuffer for VecBuffer {
@@ -129,18 +147,74 @@ impl FrameBuffer for ScreenBuffer {
     }
 }
 
-impl From<RawFrameBuffer> for ScreenBuffer {
-    fn from(fb: RawFrameBuffer) -> Self {
-        Self {
+impl TryFrom<RawFrameBuffer> for ScreenBuffer {
+    type Error = &'static str;
+
+    fn try_from(fb: RawFrameBuffer) -> Result<Self, Self::Error> {
+        fb.check()?;
+        Ok(Self {
             ptr: fb.frame_buffer,
             stride: fb.stride as usize,
             width: fb.resolution.0 as usize,
             height: fb.resolution.1 as usize,
             format: fb.format.into(),
-        }
+        })
     }
 }
 
 unsafe impl Send for ScreenBuffer {}
 
 unsafe impl Sync for ScreenBuffer {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::info;
+
+    #[test_case]
+    fn test_shear_horizontal() {
+        info!("TESTING graphics::frame_buffer::test_shear_horizontal");
+        let fg = Color::new(255, 255, 255);
+        let bg = Color::new(0, 0, 64);
+        let mut buf = VecBuffer::new(2, 5, FrameBufferFormat::Rgbx);
+        buf.clear(fg);
+        let sheared = buf.shear_horizontal(2, bg);
+        assert_eq!((sheared.width(), sheared.height()), (4, 5));
+        let rows = (0..5)
+            .map(|y| {
+                (0..4)
+                    .map(|x| sheared.read_pixel(x, y) == Some(fg))
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            rows,
+            [
+                [false, false, true, true],
+                [false, true, true, false],
+                [false, true, true, false],
+                [true, true, false, false],
+                [true, true, false, false],
+            ]
+        );
+        assert_eq!(sheared.read_pixel(3, 4), Some(bg));
+    }
+
+    #[test_case]
+    fn test_screen_buffer_try_from() {
+        info!("TESTING graphics::frame_buffer::test_screen_buffer_try_from");
+        let mut data = [0u8; 3 * 2 * 4];
+        let fb = RawFrameBuffer {
+            frame_buffer: data.as_mut_ptr(),
+            stride: 3,
+            resolution: (2, 2),
+            format: RawPixelFormat::Bgr,
+        };
+        let buf = ScreenBuffer::try_from(fb).unwrap();
+        assert_eq!((buf.width(), buf.height(), buf.stride()), (2, 2, 3));
+        assert_eq!(buf.format(), FrameBufferFormat::Bgrx);
+
+        assert!(ScreenBuffer::try_from(RawFrameBuffer::NONE).is_err());
+        assert!(ScreenBuffer::try_from(RawFrameBuffer { stride: 0, ..fb }).is_err());
+    }
+}
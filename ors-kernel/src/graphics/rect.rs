@@ -25,6 +25,20 @@ impl Rect {
         })
     }
 
+    /// The smallest rectangle containing both `self` and `other`.
+    pub fn union(self, other: Self) -> Self {
+        let lx = self.x.min(other.x);
+        let ly = self.y.min(other.y);
+        let rx = (self.x + self.w as i32).max(other.x + other.w as i32);
+        let ry = (self.y + self.h as i32).max(other.y + other.h as i32);
+        Self {
+            x: lx,
+            y: ly,
+            w: (rx - lx) as u32,
+            h: (ry - ly) as u32,
+        }
+    }
+
     pub fn contains(self, x: i32, y: i32) -> bool {
         self.x <= x && x < self.x + self.w as i32 && self.y <= y && y < self.y + self.h as i32
     }
@@ -52,5 +66,13 @@ mod tests {
             Rect::new(30, 40, 60, 60).intersect(Rect::new(10, 10, 80, 20)),
             None
         );
+        assert_eq!(
+            Rect::new(0, 0, 35, 42).union(Rect::new(0, 20, 30, 20)),
+            Rect::new(0, 0, 35, 42)
+        );
+        assert_eq!(
+            Rect::new(10, 10, 10, 10).union(Rect::new(5, 30, 10, 5)),
+            Rect::new(5, 10, 15, 25)
+        );
     }
 }
@@ -1,266 +1,213 @@
-use super::{Color, FontStyle, FrameBuffer, FrameBufferExt, MonospaceFont, VecBuffer};
+use super::{Color, FrameBuffer, FrameBufferExt, MonospaceFont, Rect, VecBuffer};
 use alloc::collections::VecDeque;
-use alloc::vec;
-use alloc::vec::Vec;
+use ors_common::term::{Cell, Grid};
 
+/// Renders a `Grid` of the terminal to the frame buffer in a monospace font. Each line of the
+/// grid is rendered into its own buffer, so that only the changed cells are rendered again.
 #[derive(Debug)]
 pub struct MonospaceTextBuffer<'a, T> {
-    lines: VecDeque<Line>,
+    lines: VecDeque<VecBuffer>,
     buf: T,
-    render_diff: RenderDiff,
     font: MonospaceFont<'a>,
-    cursor: (usize, usize),
+    /// Set when the whole frame buffer must be reported as damaged by the next render.
+    cleared: bool,
 }
 
 impl<'a, T: FrameBuffer> MonospaceTextBuffer<'a, T> {
     pub fn new(buf: T, font: MonospaceFont<'a>) -> Self {
         assert_eq!(buf.format(), font.format());
-        let height = buf.height() / font.unit_height() as usize;
-        let lines = vec![Line::new(&buf, &font); height].into();
-        Self {
-            lines,
+        let mut text_buf = Self {
+            lines: VecDeque::new(),
             buf,
-            render_diff: None,
             font,
-            cursor: (0, 0),
-        }
-    }
-
-    pub fn move_cursor(&mut self, dx: i32, dy: i32) {
-        let (x, y) = self.cursor;
-        let y = (y as i32 + dy).clamp(0, self.lines.len() as i32 - 1) as usize;
-        let x = (x as i32 + dx).clamp(0, self.lines[y].chars.len() as i32 - 1) as usize;
-        self.cursor = (x, y);
-    }
-
-    pub fn set_cursor(&mut self, x: Option<u32>, y: Option<u32>) {
-        let y = y
-            .map(|n| n as usize)
-            .unwrap_or(self.cursor.1)
-            .clamp(0, self.lines.len() - 1);
-        let x = x
-            .map(|n| n as usize)
-            .unwrap_or(self.cursor.0)
-            .clamp(0, self.lines[y].chars.len() - 1);
-        self.cursor = (x, y);
-    }
-
-    pub fn erase(
-        &mut self,
-        bg: Color,
-        before_cursor_lines: bool,
-        before_cursor_chars: bool,
-        after_cursor_chars: bool,
-        after_cursor_lines: bool,
-    ) {
-        let (x, y) = self.cursor;
-        let mut start = usize::MAX;
-        let mut end = 0;
-        if before_cursor_lines {
-            for (i, l) in self.lines.iter_mut().enumerate().take(y) {
-                if l.erase(bg, 0, usize::MAX) {
-                    start = start.min(i);
-                    end = end.max(i + 1);
-                }
-            }
-        }
-        {
-            let a = if before_cursor_chars { 0 } else { x };
-            let b = if after_cursor_chars { usize::MAX } else { x };
-            if self.lines[y].erase(bg, a, b) {
-                start = start.min(y);
-                end = end.max(y + 1);
-            }
-        }
-        if after_cursor_lines {
-            for (i, l) in self.lines.iter_mut().enumerate().skip(y + 1) {
-                if l.erase(bg, 0, usize::MAX) {
-                    start = start.min(i);
-                    end = end.max(i + 1);
-                }
-            }
-        }
-        if start < end {
-            extend_render_diff(&mut self.render_diff, start, end);
-        }
-    }
-
-    pub fn next_line(&mut self, bg: Color) {
-        let (_, y) = self.cursor;
-        if y + 1 >= self.lines.len() {
-            let mut first_line = self.lines.pop_front().unwrap(); // remove the first line
-            first_line.erase(bg, 0, usize::MAX);
-            self.lines.push_back(first_line);
-            self.render_diff = Some((0, self.lines.len())); // all lines
-            self.cursor = (0, self.lines.len() - 1);
-        } else {
-            self.cursor = (0, y + 1);
-        }
+            cleared: false,
+        };
+        text_buf.reset_lines();
+        text_buf
+    }
+
+    /// Replace the font. The grid must be resized to the new `size`, so that the whole grid is
+    /// rendered again. The whole frame buffer is cleared with `bg`.
+    pub fn set_font(&mut self, font: MonospaceFont<'a>, bg: Color) {
+        assert_eq!(self.buf.format(), font.format());
+        self.font = font;
+        self.reset_lines();
+        self.buf.clear(bg);
+        self.cleared = true;
+    }
+
+    fn reset_lines(&mut self) {
+        let (columns, lines) = self.size();
+        let (w, h) = (self.font.unit_width(), self.font.unit_height());
+        self.lines = (0..lines)
+            .map(|_| VecBuffer::new(columns * w as usize, h as usize, self.buf.format()))
+            .collect();
+    }
+
+    /// The number of columns and lines of the grid to be rendered.
+    pub fn size(&self) -> (usize, usize) {
+        (
+            self.buf.width() / self.font.unit_width() as usize,
+            self.buf.height() / self.font.unit_height() as usize,
+        )
     }
 
-    pub fn put(&mut self, c: char, fg: Color, bg: Color, style: FontStyle) {
-        let (x, y) = self.cursor;
-        match self.lines[y].put(c, fg, bg, style, x) {
-            LinePutResult::LineFeed => self.next_line(bg),
-            LinePutResult::Wrapping => {
-                self.next_line(bg);
-                self.put(c, fg, bg, style);
-            }
-            LinePutResult::Next(changed, x) => {
-                self.cursor = (x, y);
-                if changed {
-                    extend_render_diff(&mut self.render_diff, y, y + 1);
+    pub fn font(&self) -> &MonospaceFont<'a> {
+        &self.font
+    }
+
+    pub fn font_mut(&mut self) -> &mut MonospaceFont<'a> {
+        &mut self.font
+    }
+
+    /// The frame buffer under the grid. What is drawn directly stays until the lines over it are
+    /// rendered again.
+    pub fn buf_mut(&mut self) -> &mut T {
+        &mut self.buf
+    }
+
+    /// Render the changed lines of `grid` to the frame buffer, returning the rectangle rendered.
+    pub fn render(&mut self, grid: &mut Grid) -> Option<Rect> {
+        let damage = grid.take_damage()?;
+        // The scrolled lines are reused at the bottom along with their buffers
+        self.lines
+            .rotate_left(damage.scrolled % self.lines.len().max(1));
+        let pad_y =
+            (self.buf.height() - self.lines.len() * self.font.unit_height() as usize) as i32;
+        let mut damage_rect = core::mem::take(&mut self.cleared).then(|| self.buf.rect());
+        for i in damage.lines {
+            let line = &mut self.lines[i];
+            if let Some(cells) = grid.take_line_damage(i) {
+                let chars = grid.line(i);
+                // The right half of a wide character is rendered with its left half
+                let start = if chars[cells.start].is_wide_continue() {
+                    cells.start.saturating_sub(1)
+                } else {
+                    cells.start
+                };
+                for (j, c) in chars.iter().enumerate().take(cells.end).skip(start) {
+                    let ofs_x = (j * self.font.unit_width() as usize) as i32;
+                    render_cell(c, line, ofs_x, 0, &mut self.font);
                 }
             }
+            let pad_x = (self.buf.width() - line.width()) as i32;
+            let ofs_y = (i * self.font.unit_height() as usize) as i32;
+            let rect = line.rect().offset(pad_x / 2, pad_y / 2 + ofs_y);
+            self.buf.blit(rect.x, rect.y, &*line);
+            damage_rect = Some(match damage_rect {
+                Some(d) => d.union(rect),
+                None => rect,
+            });
         }
-    }
-
-    pub fn render(&mut self) {
-        if let Some((a, b)) = self.render_diff {
-            let pad_y =
-                (self.buf.height() - self.lines.len() * self.font.unit_height() as usize) as i32;
-            for (i, line) in self.lines.iter_mut().enumerate().skip(a).take(b - a) {
-                line.render(&mut self.font);
-                let pad_x =
-                    (self.buf.width() - line.chars.len() * self.font.unit_width() as usize) as i32;
-                let ofs_y = (i * self.font.unit_height() as usize) as i32;
-                self.buf.blit(pad_x / 2, pad_y / 2 + ofs_y, &line.buf);
-            }
-            self.render_diff = None;
-        }
+        damage_rect
     }
 }
 
-#[derive(Debug, Clone)]
-struct Line {
-    chars: Vec<Char>,
-    buf: VecBuffer,
-    render_diff: RenderDiff,
-}
-
-impl Line {
-    fn new(parent_buf: &impl FrameBuffer, font: &MonospaceFont) -> Self {
-        let width = parent_buf.width() / font.unit_width() as usize;
-        Self {
-            chars: vec![Char::void(); width],
-            buf: VecBuffer::new(
-                width * font.unit_width() as usize,
-                font.unit_height() as usize,
-                parent_buf.format(),
-            ),
-            render_diff: None,
-        }
-    }
-
-    fn erase(&mut self, bg: Color, a: usize, b: usize /* inclusive */) -> bool {
-        let b = b.saturating_add(1);
-        let mut start = usize::MAX;
-        let mut end = 0;
-        for (i, c) in self.chars.iter_mut().enumerate().take(b).skip(a) {
-            if c.erase(bg) {
-                start = start.min(i);
-                end = end.max(i + 1);
-            }
-        }
-        if start < end {
-            extend_render_diff(&mut self.render_diff, start, end);
-            true
-        } else {
-            false
-        }
+fn render_cell(c: &Cell, buf: &mut impl FrameBuffer, x: i32, y: i32, font: &mut MonospaceFont) {
+    if c.is_wide_continue() {
+        return; // already rendered as a part of the preceding wide character
     }
-
-    fn put(&mut self, c: char, fg: Color, bg: Color, style: FontStyle, i: usize) -> LinePutResult {
-        if c == '\n' {
-            LinePutResult::LineFeed
-        } else if i >= self.chars.len() {
-            LinePutResult::Wrapping
-        } else if self.chars[i].update(c, fg, bg, style) {
-            extend_render_diff(&mut self.render_diff, i, i + 1);
-            LinePutResult::Next(true, i + 1)
-        } else {
-            LinePutResult::Next(false, i + 1)
-        }
-    }
-
-    fn render(&mut self, font: &mut MonospaceFont) {
-        if let Some((a, b)) = self.render_diff {
-            for (i, c) in self.chars.iter().copied().enumerate().take(b).skip(a) {
-                let ofs_x = (i * font.unit_width() as usize) as i32;
-                c.render_to(&mut self.buf, ofs_x, 0, font);
-            }
-            self.render_diff = None;
-        }
-    }
-}
-
-#[derive(Debug, Clone, Copy)]
-enum LinePutResult {
-    LineFeed,
-    Wrapping,
-    Next(bool, usize),
+    buf.blit(x, y, font.get(c.value, c.fg.into(), c.bg.into(), c.style));
 }
 
-#[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy)]
-struct Char {
-    // Since MonospaceFont caches the rendered glyphs, Char does not hold a VecBuffer.
-    value: char,
-    fg: Color,
-    bg: Color,
-    font_style: FontStyle,
-}
-
-impl Char {
-    const fn new(value: char, fg: Color, bg: Color, font_style: FontStyle) -> Self {
-        Self {
-            value,
-            fg,
-            bg,
-            font_style,
-        }
-    }
-
-    const fn void() -> Self {
-        Self::new(
-            '\0',
-            Color::new(255, 255, 255),
-            Color::new(0, 0, 0),
-            FontStyle::Normal,
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graphics::{FontStyle, FrameBufferFormat};
+    use log::info;
+
+    const FG: (u8, u8, u8) = (10, 20, 30);
+    const BG: (u8, u8, u8) = (40, 50, 60);
+    const ERASE_BG: Color = Color::new(0, 0, 128);
+
+    fn tamzen(size: u32) -> MonospaceFont<'static> {
+        MonospaceFont::new(
+            size,
+            include_bytes!("../console/Tamzen7x14r.ttf"),
+            include_bytes!("../console/Tamzen7x14b.ttf"),
+            FrameBufferFormat::Rgbx,
         )
     }
 
-    fn erase(&mut self, bg: Color) -> bool {
-        self.update(' ', self.fg, bg, self.font_style)
+    // 5 columns, 3 lines
+    fn text_buffer() -> MonospaceTextBuffer<'static, VecBuffer> {
+        MonospaceTextBuffer::new(VecBuffer::new(35, 42, FrameBufferFormat::Rgbx), tamzen(14))
     }
 
-    fn update(&mut self, c: char, fg: Color, bg: Color, style: FontStyle) -> bool {
-        let new_self = Self::new(c, fg, bg, style);
-        if *self != new_self {
-            *self = new_self;
-            true
-        } else {
-            false
+    fn put_str(grid: &mut Grid, s: &str) {
+        for c in s.chars() {
+            grid.put(c, FG, BG, FontStyle::Bold, &mut |_| {});
         }
     }
 
-    fn render_to(&self, buf: &mut impl FrameBuffer, x: i32, y: i32, font: &mut MonospaceFont) {
-        buf.blit(
-            x,
-            y,
-            font.get(self.value, self.fg, self.bg, self.font_style),
-        );
+    /// The pixels of the cell at (x, y) of the frame buffer of `buf`.
+    fn rendered_cell(buf: &MonospaceTextBuffer<VecBuffer>, x: i32, y: i32) -> VecBuffer {
+        let (w, h) = (buf.font.unit_width(), buf.font.unit_height());
+        let mut rendered = VecBuffer::new(w as usize, h as usize, FrameBufferFormat::Rgbx);
+        rendered.blit_rect(0, 0, &buf.buf, Rect::new(x, y, w, h));
+        rendered
+    }
+
+    #[test_case]
+    fn test_render() {
+        info!("TESTING graphics::text_buffer::test_render");
+        let mut buf = text_buffer();
+        assert_eq!(buf.size(), (5, 3));
+        let mut grid = Grid::new(5, 3);
+        assert_eq!(buf.render(&mut grid), None);
+
+        put_str(&mut grid, "a\nb");
+        assert_eq!(buf.render(&mut grid), Some(Rect::new(0, 0, 35, 28)));
+        assert_eq!(buf.render(&mut grid), None);
+        let glyph = |buf: &mut MonospaceTextBuffer<VecBuffer>, c| {
+            let glyph = buf.font_mut().get(c, FG.into(), BG.into(), FontStyle::Bold);
+            glyph.bytes().to_vec()
+        };
+        assert_eq!(rendered_cell(&buf, 0, 0).bytes(), glyph(&mut buf, 'a'));
+        assert_eq!(rendered_cell(&buf, 0, 14).bytes(), glyph(&mut buf, 'b'));
+
+        // Scrolled lines are shown along with their rendered cells
+        put_str(&mut grid, "\n\nc");
+        assert_eq!(buf.render(&mut grid), Some(Rect::new(0, 0, 35, 42)));
+        assert_eq!(rendered_cell(&buf, 0, 0).bytes(), glyph(&mut buf, 'b'));
+        assert_eq!(rendered_cell(&buf, 0, 28).bytes(), glyph(&mut buf, 'c'));
+    }
+
+    #[test_case]
+    fn test_set_font() {
+        info!("TESTING graphics::text_buffer::test_set_font");
+        let mut buf = text_buffer();
+        let mut grid = Grid::new(5, 3);
+        for y in 0..3 {
+            grid.set_cursor(Some(0), Some(y));
+            put_str(&mut grid, "abcde");
+        }
+        grid.set_cursor(Some(1), Some(2));
+        buf.font_mut()
+            .get('a', FG.into(), BG.into(), FontStyle::Normal);
+        buf.render(&mut grid);
+
+        // 10x20 units: 3 columns, 2 lines
+        buf.set_font(tamzen(20), ERASE_BG);
+        assert_eq!(buf.size(), (3, 2));
+        grid.resize(3, 2);
+        assert_eq!(buf.font().cache_stats().entries, 0);
+        assert_eq!(buf.buf.read_pixel(34, 41), Some(ERASE_BG));
+        assert_eq!(buf.render(&mut grid), Some(Rect::new(0, 0, 35, 42)));
+        assert_eq!(buf.render(&mut grid), None);
+        let glyph = buf
+            .font_mut()
+            .get('a', FG.into(), BG.into(), FontStyle::Bold)
+            .clone();
+        assert_eq!((glyph.width(), glyph.height()), (10, 20));
+        assert_eq!(rendered_cell(&buf, 2, 1).bytes(), glyph.bytes());
+
+        // 4x7 units: 8 columns, 6 lines
+        buf.set_font(tamzen(7), ERASE_BG);
+        assert_eq!(buf.size(), (8, 6));
     }
 }
 
-type RenderDiff = Option<(usize, usize)>;
-
-fn extend_render_diff(a: &mut RenderDiff, start: usize, end: usize) {
-    *a = match *a {
-        None => Some((start, end)),
-        Some((a, b)) => Some((a.min(start), b.max(end))),
-    };
-}
-
 // Workaround for linker error
 
 #[no_mangle]
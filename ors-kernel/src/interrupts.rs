@@ -1,12 +1,24 @@
 use crate::acpi;
 use crate::console;
+use crate::context::Context;
 use crate::cpu::Cpu;
-use crate::segmentation::DOUBLE_FAULT_IST_INDEX;
+use crate::entropy::{self, Source};
+use crate::segmentation::{self, InterruptStack};
+use crate::sync::spin::Spin;
 use crate::task;
-use crate::x64;
-use core::ops::Range;
-use core::sync::atomic::{AtomicUsize, Ordering};
+use crate::trace::Category;
+use crate::x64::{self, PageSize};
+use ::acpi::platform::interrupt::{Polarity, TriggerMode};
+use core::arch::x86_64::_rdtsc;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use spin::Lazy;
+use vectors::{
+    Handler, Kind, IRQ_ACPI_SCI, IRQ_COM1, IRQ_KBD, IRQ_LEGACY, IRQ_TIMER, IRQ_VIRTIO_BLOCK,
+    IRQ_VIRTIO_INPUT, PIC_8259_IRQ_OFFSET, VIRTIO_BLOCK_IRQ_OFFSET, VIRTIO_INPUT_IRQ_OFFSET,
+};
+
+pub mod vectors;
 
 pub const TIMER_FREQ: usize = 250;
 
@@ -16,6 +28,131 @@ pub fn ticks() -> usize {
     TICKS.load(Ordering::SeqCst)
 }
 
+/// Ticks left until `deadline`, or 0 if it has passed.
+pub fn ticks_until(deadline: usize) -> usize {
+    deadline.saturating_sub(ticks())
+}
+
+/// The deadline `delta` ticks after now.
+pub fn deadline_after(delta: usize) -> Deadline {
+    Deadline::after(ticks(), delta)
+}
+
+/// A point of time in ticks, such as the timeout of a wait. Computations on deadlines saturate
+/// instead of overflowing, so that a passed deadline is never taken as a far future one.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy, Hash)]
+pub struct Deadline(usize);
+
+impl Deadline {
+    pub const fn at(ticks: usize) -> Self {
+        Self(ticks)
+    }
+
+    /// The deadline `delta` ticks after `now`.
+    pub const fn after(now: usize, delta: usize) -> Self {
+        Self(now.saturating_add(delta))
+    }
+
+    pub const fn ticks(self) -> usize {
+        self.0
+    }
+
+    pub fn is_expired(self) -> bool {
+        self.is_expired_at(ticks())
+    }
+
+    pub const fn is_expired_at(self, now: usize) -> bool {
+        self.0 <= now
+    }
+
+    /// Ticks left until the deadline, or 0 if it has passed.
+    pub fn remaining(self) -> usize {
+        self.remaining_at(ticks())
+    }
+
+    pub const fn remaining_at(self, now: usize) -> usize {
+        self.0.saturating_sub(now)
+    }
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const IRQ_COUNT_ZERO: AtomicU64 = AtomicU64::new(0);
+static IRQ_COUNTS: [AtomicU64; 256] = [IRQ_COUNT_ZERO; 256];
+
+/// The number of interrupts handled on the vector since boot.
+pub fn irq_count(vector: u8) -> u64 {
+    IRQ_COUNTS[vector as usize].load(Ordering::Relaxed)
+}
+
+fn count_irq(vector: u32) {
+    IRQ_COUNTS[vector as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+static TIMER_HANDLER_MAX_CYCLES: AtomicU64 = AtomicU64::new(0);
+
+/// The longest time the timer handler took until the preemption at its end since boot or the last
+/// reset, in TSC cycles. This does not depend on the number of expiring timeouts, see
+/// `timer_handler`.
+pub fn timer_handler_max_cycles() -> u64 {
+    TIMER_HANDLER_MAX_CYCLES.load(Ordering::Relaxed)
+}
+
+pub fn reset_timer_handler_max_cycles() {
+    TIMER_HANDLER_MAX_CYCLES.store(0, Ordering::Relaxed);
+}
+
+/// Interrupt sources that the kernel assigns vectors to.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Irq {
+    Timer,
+    Keyboard,
+    Com1,
+    AcpiSci,
+    VirtIOBlock(usize),
+    VirtIOInput(usize),
+}
+
+impl Irq {
+    pub fn from_vector(vector: u8) -> Option<Self> {
+        let vector = vector as u32;
+        Some(match vector {
+            IRQ_TIMER => Self::Timer,
+            IRQ_KBD => Self::Keyboard,
+            IRQ_COM1 => Self::Com1,
+            IRQ_ACPI_SCI => Self::AcpiSci,
+            v if IRQ_VIRTIO_BLOCK.contains(&v) => {
+                Self::VirtIOBlock((v - IRQ_VIRTIO_BLOCK.start) as usize)
+            }
+            v if IRQ_VIRTIO_INPUT.contains(&v) => {
+                Self::VirtIOInput((v - IRQ_VIRTIO_INPUT.start) as usize)
+            }
+            _ => None?,
+        })
+    }
+}
+
+impl fmt::Display for Irq {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Timer => write!(f, "timer"),
+            Self::Keyboard => write!(f, "keyboard"),
+            Self::Com1 => write!(f, "com1"),
+            Self::AcpiSci => write!(f, "acpi-sci"),
+            Self::VirtIOBlock(i) => write!(f, "virtio-block{}", i),
+            Self::VirtIOInput(i) => write!(f, "virtio-input{}", i),
+        }
+    }
+}
+
+#[cfg(debug_assertions)]
+static CLI_COUNT: AtomicU64 = AtomicU64::new(0);
+
+/// The total number of `Cli` acquired so far. This is used to check that a path takes no locks.
+#[cfg(debug_assertions)]
+pub fn cli_count() -> u64 {
+    CLI_COUNT.load(Ordering::SeqCst)
+}
+
 /// Clear Interrupt Flag. Interrupts are disabled while this value is alive.
 #[derive(Debug)]
 pub struct Cli;
@@ -29,6 +166,8 @@ impl Cli {
             cpu.thread_state.zcli = cli;
         }
         cpu.thread_state.ncli += 1;
+        #[cfg(debug_assertions)]
+        CLI_COUNT.fetch_add(1, Ordering::SeqCst);
         Self
     }
 }
@@ -49,6 +188,41 @@ impl Drop for Cli {
     }
 }
 
+/// Marks that an interrupt handler is being processed, while this value is alive.
+///
+/// The depth is a part of `CpuThreadState`, so that it follows the task across context switches:
+/// the timer handler preempts the current task by switching to another task in the middle of the
+/// handler, and the handler is resumed on the stack of the preempted task when it is scheduled
+/// again. This is sound as long as every handler that may switch tasks
+/// * is at the outermost interrupt level, since the interrupted handler would be resumed by
+///   another task otherwise (asserted by the scheduler, see `interrupt_depth`),
+/// * has sent the EOI and holds no locks, since the switched-to task runs with interrupts enabled,
+/// * keeps no CPU state other than what `Context` saves, which are the general purpose registers
+///   saved by the `x86-interrupt` prologue and the FPU/SSE state saved by `fxsave`. The kernel is
+///   built with soft-float, so floating-point computations do not touch the FPU/SSE registers.
+#[derive(Debug)]
+pub struct InterruptLevel;
+
+impl InterruptLevel {
+    pub fn enter() -> Self {
+        Cpu::current().state().lock().thread_state.nint += 1;
+        Self
+    }
+}
+
+impl Drop for InterruptLevel {
+    fn drop(&mut self) {
+        Cpu::current().state().lock().thread_state.nint -= 1;
+    }
+}
+
+/// The depth of interrupt handlers being processed by the current task. NMIs and CPU exceptions
+/// are not counted: they never switch tasks, and NMIs may arrive while the CPU state is locked.
+pub fn interrupt_depth() -> u32 {
+    let _cli = Cli::new();
+    Cpu::current().state().lock().thread_state.nint
+}
+
 pub unsafe fn initialize() {
     IDT.load();
     disable_pic_8259();
@@ -56,17 +230,44 @@ pub unsafe fn initialize() {
     initialize_io_apic();
 }
 
-const PIC_8259_IRQ_OFFSET: u32 = 32; // first 32 entries are reserved by CPU
-const IRQ_TIMER: u32 = PIC_8259_IRQ_OFFSET + 0;
-const IRQ_KBD: u32 = PIC_8259_IRQ_OFFSET + 1; // Keyboard on PS/2 port
-const IRQ_COM1: u32 = PIC_8259_IRQ_OFFSET + 4; // First serial port
-
-const VIRTIO_BLOCK_IRQ_OFFSET: u32 = PIC_8259_IRQ_OFFSET + 16; // next 16 entries are for 8259 PIC interrupts
-const IRQ_VIRTIO_BLOCK: Range<u32> = VIRTIO_BLOCK_IRQ_OFFSET..VIRTIO_BLOCK_IRQ_OFFSET + 8;
+const NMI: u32 = 2;
 
 static IDT: Lazy<x64::InterruptDescriptorTable> = Lazy::new(|| unsafe { prepare_idt() });
 
+/// Reserve the fixed assignments of the vector map, see `vectors`.
+fn reserve_vectors() {
+    use vectors::reserve_fixed;
+
+    let direct = |handler| Some(Handler::Direct(handler));
+    reserve_fixed(IRQ_TIMER, "timer", Kind::Local, direct(timer_handler));
+    reserve_fixed(IRQ_KBD, "keyboard", Kind::Legacy, direct(kbd_handler));
+    reserve_fixed(IRQ_COM1, "com1", Kind::Legacy, direct(com1_handler));
+    reserve_fixed(
+        IRQ_ACPI_SCI,
+        "acpi-sci",
+        Kind::Legacy,
+        direct(acpi_sci_handler),
+    );
+    for (i, irq) in IRQ_VIRTIO_BLOCK.enumerate() {
+        let handler = direct(get_virtio_block_handler(i));
+        reserve_fixed(irq, "virtio-block", Kind::Msi, handler);
+    }
+    for (i, irq) in IRQ_VIRTIO_INPUT.enumerate() {
+        let handler = direct(get_virtio_input_handler(i));
+        reserve_fixed(irq, "virtio-input", Kind::Msi, handler);
+    }
+    // The I/O APIC routes every ISA IRQ to these vectors, even if disabled
+    for irq in IRQ_LEGACY.filter(|irq| vectors::get(*irq as u8).is_none()) {
+        reserve_fixed(irq, "isa", Kind::Legacy, None);
+    }
+    for v in vectors::IPI {
+        reserve_fixed(v as u32, "ipi", Kind::Ipi, None);
+    }
+    reserve_fixed(vectors::SPURIOUS as u32, "spurious", Kind::Local, None);
+}
+
 unsafe fn prepare_idt() -> x64::InterruptDescriptorTable {
+    reserve_vectors();
     let mut idt = x64::InterruptDescriptorTable::new();
     idt.breakpoint
         .set_handler_fn(breakpoint_handler)
@@ -76,27 +277,66 @@ unsafe fn prepare_idt() -> x64::InterruptDescriptorTable {
         .disable_interrupts(true);
     idt.double_fault
         .set_handler_fn(double_fault_handler)
-        .set_stack_index(DOUBLE_FAULT_IST_INDEX)
-        .disable_interrupts(true);
-    idt[IRQ_TIMER as usize]
-        .set_handler_fn(timer_handler)
+        .set_stack_index(InterruptStack::DoubleFault.index())
         .disable_interrupts(true);
-    idt[IRQ_KBD as usize]
-        .set_handler_fn(kbd_handler)
+    idt.non_maskable_interrupt
+        .set_handler_fn(nmi_handler)
+        .set_stack_index(InterruptStack::Nmi.index())
         .disable_interrupts(true);
-    idt[IRQ_COM1 as usize]
-        .set_handler_fn(com1_handler)
+    idt.machine_check
+        .set_handler_fn(machine_check_handler)
+        .set_stack_index(InterruptStack::MachineCheck.index())
         .disable_interrupts(true);
 
-    for (i, irq) in IRQ_VIRTIO_BLOCK.enumerate() {
-        idt[irq as usize]
-            .set_handler_fn(get_virtio_block_handler(i))
+    // Vectors without a direct handler are handled by the generic handlers, so that vectors
+    // allocated later need no change to the IDT
+    for (i, dispatch) in DISPATCHERS.iter().flatten().enumerate() {
+        let vector = vectors::FIRST + i as u8;
+        let handler = match vectors::get(vector).and_then(|e| e.handler) {
+            Some(Handler::Direct(handler)) => handler,
+            _ => *dispatch,
+        };
+        idt[vector as usize]
+            .set_handler_fn(handler)
             .disable_interrupts(true);
     }
 
     idt
 }
 
+/// Generate the generic handlers for the vectors `row * 16 + 0..16` of each row.
+macro_rules! dispatchers {
+    ($($row:literal)*) => {
+        [$(dispatchers!(@row $row; 0 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15)),*]
+    };
+    (@row $row:literal; $($column:literal)*) => {
+        [$(dispatch::<{ $row * 16 + $column }>),*]
+    };
+}
+
+/// The generic handlers of the vectors from `vectors::FIRST`.
+#[allow(clippy::type_complexity)]
+static DISPATCHERS: [[extern "x86-interrupt" fn(x64::InterruptStackFrame); 16]; 14] =
+    dispatchers!(2 3 4 5 6 7 8 9 10 11 12 13 14 15);
+
+/// The generic handler of `VECTOR`, which calls the handler registered by `vectors::allocate`.
+extern "x86-interrupt" fn dispatch<const VECTOR: u8>(_stack_frame: x64::InterruptStackFrame) {
+    let _level = InterruptLevel::enter();
+    count_irq(VECTOR as u32);
+    match vectors::get(VECTOR).and_then(|e| e.handler) {
+        Some(Handler::Dispatched(handler)) => handler(),
+        // Spurious interrupts must not be acknowledged
+        _ if VECTOR == vectors::SPURIOUS => return,
+        _ => log_rate_limited!(
+            log::Level::Warn,
+            VECTOR,
+            "interrupts: Unexpected interrupt on vector {:#04x}",
+            VECTOR
+        ),
+    }
+    unsafe { LAPIC.set_eoi(0) };
+}
+
 unsafe fn disable_pic_8259() {
     x64::Port::new(0xa1).write(0xffu8);
     x64::Port::new(0x21).write(0xffu8);
@@ -119,7 +359,7 @@ unsafe fn initialize_local_apic() {
     const DELIVS: u32 = 0x01000;
 
     // Enable the Local APIC to receive interrupts by configuring the Spurious Interrupt Vector Register.
-    LAPIC.set_svr(ENABLE | 0xFF);
+    LAPIC.set_svr(ENABLE | vectors::SPURIOUS as u32);
 
     // Measure the frequency of the Local APIC Timer
     LAPIC.set_tdcr(X1);
@@ -163,8 +403,8 @@ unsafe fn initialize_io_apic() {
     // https://wiki.osdev.org/APIC
     // https://github.com/mit-pdos/xv6-public/blob/master/ioapic.c#L49
 
-    // const ACTIVELOW: u64 = 0x00002000; // Active low (vs high)
     // const LOGICAL: u64 = 0x00000800; // Destination is CPU id (vs APIC ID)
+    const ACTIVELOW: u64 = 0x00002000; // Active low (vs high)
     const LEVEL: u64 = 0x00008000; // Level-triggered (vs edge-)
     const DISABLED: u64 = 0x00010000; // Interrupt disabled
 
@@ -181,6 +421,25 @@ unsafe fn initialize_io_apic() {
         IRQ_COM1 - PIC_8259_IRQ_OFFSET,
         IRQ_COM1 as u64 | bsp | LEVEL,
     );
+
+    // The SCI is a level-triggered, active-low interrupt unless overridden by the MADT.
+    // https://wiki.osdev.org/ACPI#Enabling_ACPI
+    if let Some(mut gsi) = acpi::sci_interrupt() {
+        let mut flags = LEVEL | ACTIVELOW;
+        let overrides = &acpi::apic_info().interrupt_source_overrides;
+        if let Some(o) = overrides.iter().find(|o| o.isa_source as u32 == gsi) {
+            gsi = o.global_system_interrupt;
+            if matches!(o.polarity, Polarity::ActiveHigh) {
+                flags &= !ACTIVELOW;
+            }
+            if matches!(o.trigger_mode, TriggerMode::Edge) {
+                flags &= !LEVEL;
+            }
+        }
+        if gsi <= max_intr {
+            ioapic.set_redirection_table_at(gsi, IRQ_ACPI_SCI as u64 | bsp | flags);
+        }
+    }
 }
 
 // Be careful to avoid deadlocks:
@@ -191,12 +450,62 @@ extern "x86-interrupt" fn breakpoint_handler(stack_frame: x64::InterruptStackFra
     sprintln!("{:#?}", stack_frame);
 }
 
+/// A page fault recorded by the page fault handler.
+#[derive(Debug, Clone, Copy)]
+pub struct PageFault {
+    pub address: x64::VirtAddr,
+    pub error_code: x64::PageFaultErrorCode,
+    pub instruction_pointer: x64::VirtAddr,
+}
+
+impl fmt::Display for PageFault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Page fault at {:?} ({:?}) by the instruction at {:?}",
+            self.address, self.error_code, self.instruction_pointer
+        )
+    }
+}
+
+static LAST_PAGE_FAULT: Spin<Option<PageFault>> = Spin::new(None);
+
+/// The last page fault since boot.
+pub fn last_page_fault() -> Option<PageFault> {
+    *LAST_PAGE_FAULT.lock()
+}
+
 extern "x86-interrupt" fn page_fault_handler(
-    stack_frame: x64::InterruptStackFrame,
+    mut stack_frame: x64::InterruptStackFrame,
     error_code: x64::PageFaultErrorCode,
 ) {
+    let fault = PageFault {
+        address: x64::Cr2::read(),
+        error_code,
+        instruction_pointer: stack_frame.instruction_pointer,
+    };
+    *LAST_PAGE_FAULT.lock() = Some(fault);
+
+    // A fault in a context with interrupts enabled is turned into a panic in that context, so
+    // that `task::oops` can kill the task. Faults near the stack pointer are excluded, since they
+    // are probably stack overflows and panicking on the same stack faults again.
+    let stack_pointer = stack_frame.stack_pointer.as_u64();
+    let near_stack = fault.address.as_u64() < stack_pointer + x64::Size4KiB::SIZE
+        && stack_pointer < fault.address.as_u64() + x64::Size4KiB::SIZE;
+    if stack_frame.cpu_flags & Context::INTERRUPT_FLAG != 0 && !near_stack {
+        unsafe {
+            stack_frame.as_mut().update(|frame| {
+                frame.instruction_pointer = x64::VirtAddr::new(page_fault_panic as usize as u64);
+                // Aligned as if `page_fault_panic` is called
+                frame.stack_pointer = x64::VirtAddr::new((frame.stack_pointer.as_u64() & !0xf) - 8);
+                frame.cpu_flags &= !Context::INTERRUPT_FLAG;
+            });
+        }
+        return;
+    }
+
     sprintln!("EXCEPTION: PAGE FAULT");
-    sprintln!("Address: {:?}", x64::Cr2::read());
+    sprintln!("Address: {:?}", fault.address);
     sprintln!("Error Code: {:?}", error_code);
     sprintln!("{:#?}", stack_frame);
 
@@ -205,37 +514,145 @@ extern "x86-interrupt" fn page_fault_handler(
     }
 }
 
+/// Entered in place of the faulting instruction by `page_fault_handler`, with interrupts disabled
+/// until the fault is taken.
+extern "C" fn page_fault_panic() -> ! {
+    let fault = last_page_fault().unwrap();
+    x64::interrupts::enable();
+    panic!("{}", fault);
+}
+
 extern "x86-interrupt" fn double_fault_handler(
     stack_frame: x64::InterruptStackFrame,
     _error_code: u64,
 ) -> ! {
     sprintln!("EXCEPTION: DOUBLE FAULT");
     sprintln!("{:#?}", stack_frame);
+    print_interrupt_stack(InterruptStack::DoubleFault);
 
     loop {
         x64::hlt()
     }
 }
 
+#[cfg(test)]
+static LAST_NMI_STACK_POINTER: AtomicU64 = AtomicU64::new(0);
+
+extern "x86-interrupt" fn nmi_handler(_stack_frame: x64::InterruptStackFrame) {
+    // An NMI may interrupt any code holding locks, thus nothing but counting is done here
+    count_irq(NMI);
+    #[cfg(test)]
+    LAST_NMI_STACK_POINTER.store(x64::stack_pointer().as_u64(), Ordering::SeqCst);
+}
+
+extern "x86-interrupt" fn machine_check_handler(stack_frame: x64::InterruptStackFrame) -> ! {
+    sprintln!("EXCEPTION: MACHINE CHECK");
+    sprintln!("{:#?}", stack_frame);
+    print_interrupt_stack(InterruptStack::MachineCheck);
+
+    loop {
+        x64::hlt()
+    }
+}
+
+fn print_interrupt_stack(stack: InterruptStack) {
+    let cpu = Cpu::current();
+    sprintln!(
+        "Stack pointer: {:?} (CPU {}, {} stack: {:?})",
+        x64::stack_pointer(),
+        cpu.index(),
+        stack.name(),
+        segmentation::current().map(|s| s.interrupt_stack(stack))
+    );
+}
+
+/// Send an NMI to the current CPU and wait for it to be handled. Returns the stack pointer
+/// observed by the NMI handler.
+#[cfg(test)]
+pub fn send_nmi_to_self() -> x64::VirtAddr {
+    const NMI_DELIVERY: u32 = 0x00400;
+    const ASSERT: u32 = 0x04000;
+    const DELIVS: u32 = 0x01000;
+
+    let count = irq_count(NMI as u8);
+    let lapic_id = Cpu::current().lapic_id().unwrap();
+    unsafe {
+        LAPIC.set_icrhi(lapic_id << 24);
+        LAPIC.set_icrlo(NMI_DELIVERY | ASSERT);
+        while (LAPIC.icrlo() & DELIVS) != 0 {}
+    }
+    while irq_count(NMI as u8) == count {
+        core::hint::spin_loop();
+    }
+    x64::VirtAddr::new(LAST_NMI_STACK_POINTER.load(Ordering::SeqCst))
+}
+
+/// The handler only advances the ticks. Expired timeouts are not processed here but by the
+/// timeouts task, which is woken by the preemption at the end of the handler, so that the time
+/// spent in the handler does not grow with the number of expiring timeouts (see
+/// `TaskScheduler::switch`).
 extern "x86-interrupt" fn timer_handler(_stack_frame: x64::InterruptStackFrame) {
-    TICKS.fetch_add(1, Ordering::SeqCst);
-    task::scheduler().elapse();
+    let start = unsafe { _rdtsc() };
+    let _level = InterruptLevel::enter();
+    count_irq(IRQ_TIMER);
+    trace_event!(Category::Irq, "enter {}", IRQ_TIMER);
+    let ticks = TICKS.fetch_add(1, Ordering::SeqCst);
+    entropy::add(Source::Timer, ticks as u64);
     unsafe { LAPIC.set_eoi(0) };
+    trace_event!(Category::Irq, "exit {}", IRQ_TIMER);
+    let cycles = unsafe { _rdtsc() }.wrapping_sub(start);
+    TIMER_HANDLER_MAX_CYCLES.fetch_max(cycles, Ordering::Relaxed);
+    // Preempt the current task, see `InterruptLevel` for why this is allowed here
     task::scheduler().r#yield();
 }
 
 extern "x86-interrupt" fn kbd_handler(_stack_frame: x64::InterruptStackFrame) {
+    let _level = InterruptLevel::enter();
+    count_irq(IRQ_KBD);
+    trace_event!(Category::Irq, "enter {}", IRQ_KBD);
     let v = unsafe { x64::Port::new(0x60).read() };
+    entropy::add(Source::Keyboard, v as u64);
     console::accept_raw_input(console::RawInput::Kbd(v));
     unsafe { LAPIC.set_eoi(0) };
+    trace_event!(Category::Irq, "exit {}", IRQ_KBD);
 }
 
 extern "x86-interrupt" fn com1_handler(_stack_frame: x64::InterruptStackFrame) {
-    use crate::devices::serial::default_port;
+    use crate::devices::serial;
+
+    let _level = InterruptLevel::enter();
+    count_irq(IRQ_COM1);
+    trace_event!(Category::Irq, "enter {}", IRQ_COM1);
+    serial::handle_interrupt(|v| {
+        entropy::add(Source::Com1, v as u64);
+        console::accept_raw_input(console::RawInput::Com1(v));
+    });
+    unsafe { LAPIC.set_eoi(0) };
+    trace_event!(Category::Irq, "exit {}", IRQ_COM1);
+}
 
-    let v = default_port().receive();
-    console::accept_raw_input(console::RawInput::Com1(v));
+extern "x86-interrupt" fn acpi_sci_handler(_stack_frame: x64::InterruptStackFrame) {
+    let _level = InterruptLevel::enter();
+    count_irq(IRQ_ACPI_SCI);
+    trace_event!(Category::Irq, "enter {}", IRQ_ACPI_SCI);
+    acpi::handle_sci();
     unsafe { LAPIC.set_eoi(0) };
+    trace_event!(Category::Irq, "exit {}", IRQ_ACPI_SCI);
+}
+
+/// Send an SCI to the current CPU, which is handled in the same way as the one by the hardware.
+pub fn send_acpi_sci_to_self() {
+    const ASSERT: u32 = 0x04000;
+    const DELIVS: u32 = 0x01000;
+
+    // The IPI must be sent to the CPU that the ICR belongs to
+    let _cli = Cli::new();
+    let lapic_id = Cpu::current().lapic_id().unwrap();
+    unsafe {
+        LAPIC.set_icrhi(lapic_id << 24);
+        LAPIC.set_icrlo(ASSERT | IRQ_ACPI_SCI);
+        while (LAPIC.icrlo() & DELIVS) != 0 {}
+    }
 }
 
 extern "x86-interrupt" fn virtio_block_handler<const N: usize>(
@@ -243,8 +660,24 @@ extern "x86-interrupt" fn virtio_block_handler<const N: usize>(
 ) {
     use crate::devices::virtio::block;
 
-    block::list()[N].collect();
+    let _level = InterruptLevel::enter();
+    count_irq(VIRTIO_BLOCK_IRQ_OFFSET + N as u32);
+    trace_event!(
+        Category::Irq,
+        "enter {}",
+        VIRTIO_BLOCK_IRQ_OFFSET as usize + N
+    );
+    entropy::add(Source::VirtIOBlock, N as u64);
+    // Interrupts may arrive while the detection of the blocks is given up by the timeout
+    if let Some(block) = block::try_list().and_then(|blocks| blocks.get(N)) {
+        block.collect();
+    }
     unsafe { LAPIC.set_eoi(0) };
+    trace_event!(
+        Category::Irq,
+        "exit {}",
+        VIRTIO_BLOCK_IRQ_OFFSET as usize + N
+    );
 }
 
 fn get_virtio_block_handler(index: usize) -> extern "x86-interrupt" fn(x64::InterruptStackFrame) {
@@ -268,3 +701,102 @@ pub fn virtio_block_irq(index: usize) -> Option<u32> {
         None
     }
 }
+
+extern "x86-interrupt" fn virtio_input_handler<const N: usize>(
+    _stack_frame: x64::InterruptStackFrame,
+) {
+    use crate::devices::virtio::input;
+
+    let _level = InterruptLevel::enter();
+    count_irq(VIRTIO_INPUT_IRQ_OFFSET + N as u32);
+    trace_event!(
+        Category::Irq,
+        "enter {}",
+        VIRTIO_INPUT_IRQ_OFFSET as usize + N
+    );
+    entropy::add(Source::VirtIOInput, N as u64);
+    input::list()[N].collect();
+    unsafe { LAPIC.set_eoi(0) };
+    trace_event!(
+        Category::Irq,
+        "exit {}",
+        VIRTIO_INPUT_IRQ_OFFSET as usize + N
+    );
+}
+
+fn get_virtio_input_handler(index: usize) -> extern "x86-interrupt" fn(x64::InterruptStackFrame) {
+    match index {
+        0 => virtio_input_handler::<0>,
+        1 => virtio_input_handler::<1>,
+        2 => virtio_input_handler::<2>,
+        3 => virtio_input_handler::<3>,
+        _ => panic!("Unsupported index"),
+    }
+}
+
+pub fn virtio_input_irq(index: usize) -> Option<u32> {
+    if index < IRQ_VIRTIO_INPUT.len() {
+        Some(IRQ_VIRTIO_INPUT.start + index as u32)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::info;
+
+    #[test_case]
+    fn test_interrupt_level() {
+        info!("TESTING interrupts::test_interrupt_level");
+        let depth = interrupt_depth();
+        // Without interrupts, since the timer handler must not preempt at a nested level
+        let cli = Cli::new();
+        let level = InterruptLevel::enter();
+        assert_eq!(interrupt_depth(), depth + 1);
+        let nested = InterruptLevel::enter();
+        assert_eq!(interrupt_depth(), depth + 2);
+        drop((nested, level, cli));
+        assert_eq!(interrupt_depth(), depth);
+        // Not counted across timer preemptions of this task
+        let ticks = ticks();
+        while ticks + 2 > super::ticks() {
+            core::hint::spin_loop();
+        }
+        assert_eq!(interrupt_depth(), depth);
+    }
+
+    #[test_case]
+    fn test_deadline() {
+        info!("TESTING interrupts::test_deadline");
+        let d = Deadline::after(100, 20);
+        assert_eq!(d, Deadline::at(120));
+        assert!(!d.is_expired_at(119));
+        assert!(d.is_expired_at(120));
+        assert!(d.is_expired_at(usize::MAX));
+        assert_eq!(d.remaining_at(100), 20);
+        assert_eq!(d.remaining_at(120), 0);
+        assert_eq!(d.remaining_at(usize::MAX), 0);
+
+        // Saturates at the end of the ticks rather than wrapping around to the past
+        let d = Deadline::after(usize::MAX - 1, 5);
+        assert_eq!(d.ticks(), usize::MAX);
+        assert!(!d.is_expired_at(usize::MAX - 1));
+        assert_eq!(d.remaining_at(0), usize::MAX);
+        assert_eq!(Deadline::after(usize::MAX, usize::MAX).ticks(), usize::MAX);
+        assert!(Deadline::at(0).is_expired_at(0));
+        assert!(Deadline::at(1) < Deadline::at(2));
+    }
+
+    #[test_case]
+    fn test_ticks_until() {
+        info!("TESTING interrupts::test_ticks_until");
+        let now = ticks();
+        assert_eq!(ticks_until(0), 0);
+        assert!(ticks_until(now + 1000) <= 1000);
+        assert!(deadline_after(1000).remaining() <= 1000);
+        assert!(!deadline_after(usize::MAX).is_expired());
+        assert!(Deadline::at(now).is_expired());
+    }
+}
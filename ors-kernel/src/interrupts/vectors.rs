@@ -0,0 +1,267 @@
+//! The registry of the interrupt vectors. Every vector used by the kernel must be reserved here,
+//! so that a collision is caught at boot instead of showing up as an interrupt of another device.
+//!
+//! The vector map (the first 32 vectors are CPU exceptions):
+//!
+//! | Vectors       | Kind   | Owner                                                        |
+//! | ------------- | ------ | ------------------------------------------------------------ |
+//! | `0x20`        | local  | Local APIC timer                                             |
+//! | `0x21..=0x2f` | legacy | ISA IRQ 1-15 routed by the I/O APIC (keyboard, COM1, SCI)    |
+//! | `0x30..=0x37` | msi    | VirtIO block devices                                         |
+//! | `0x38..=0x3b` | msi    | VirtIO input devices                                         |
+//! | `0x3c..=0xef` | -      | Dynamically allocated by `allocate`                          |
+//! | `0xf0..=0xfe` | ipi    | Reserved for inter-processor interrupts                      |
+//! | `0xff`        | local  | Spurious interrupts of the Local APIC                        |
+
+use crate::sync::spin::Spin;
+use crate::x64;
+use alloc::vec::Vec;
+use core::fmt;
+use core::ops::{Range, RangeInclusive};
+
+/// The first vector available for interrupts. The vectors below are CPU exceptions.
+pub const FIRST: u8 = 32;
+const COUNT: usize = 256 - FIRST as usize;
+
+pub const PIC_8259_IRQ_OFFSET: u32 = FIRST as u32;
+pub const IRQ_TIMER: u32 = PIC_8259_IRQ_OFFSET + 0;
+pub const IRQ_KBD: u32 = PIC_8259_IRQ_OFFSET + 1; // Keyboard on PS/2 port
+pub const IRQ_COM1: u32 = PIC_8259_IRQ_OFFSET + 4; // First serial port
+pub const IRQ_ACPI_SCI: u32 = PIC_8259_IRQ_OFFSET + 9; // Usually wired to IRQ 9, see `initialize_io_apic`
+pub const IRQ_LEGACY: Range<u32> = PIC_8259_IRQ_OFFSET + 1..PIC_8259_IRQ_OFFSET + 16;
+
+pub const VIRTIO_BLOCK_IRQ_OFFSET: u32 = PIC_8259_IRQ_OFFSET + 16; // next 16 entries are for 8259 PIC interrupts
+pub const IRQ_VIRTIO_BLOCK: Range<u32> = VIRTIO_BLOCK_IRQ_OFFSET..VIRTIO_BLOCK_IRQ_OFFSET + 8;
+pub const VIRTIO_INPUT_IRQ_OFFSET: u32 = IRQ_VIRTIO_BLOCK.end;
+pub const IRQ_VIRTIO_INPUT: Range<u32> = VIRTIO_INPUT_IRQ_OFFSET..VIRTIO_INPUT_IRQ_OFFSET + 4;
+
+/// Reserved for the inter-processor interrupts, such as TLB shootdowns.
+pub const IPI: RangeInclusive<u8> = 0xf0..=0xfe;
+pub const SPURIOUS: u8 = 0xff;
+
+static VECTORS: Spin<Registry> = Spin::new(Registry::new());
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Kind {
+    /// Interrupts of the Local APIC itself, such as the timer.
+    Local,
+    /// ISA IRQs routed by the I/O APIC.
+    Legacy,
+    /// Message signaled interrupts of PCI devices, assigned statically.
+    Msi,
+    /// Inter-processor interrupts.
+    Ipi,
+    /// Allocated at runtime by `allocate`.
+    Dynamic,
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Padded so that the kinds are aligned by `vectors` of the shell
+        f.pad(match self {
+            Self::Local => "local",
+            Self::Legacy => "legacy",
+            Self::Msi => "msi",
+            Self::Ipi => "ipi",
+            Self::Dynamic => "dynamic",
+        })
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum Handler {
+    /// Set to the IDT entry directly. The handler must count the interrupt and send the EOI.
+    Direct(extern "x86-interrupt" fn(x64::InterruptStackFrame)),
+    /// Called by the generic handler of the vector, which counts the interrupt and sends the EOI.
+    Dispatched(fn()),
+}
+
+impl fmt::Debug for Handler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Direct(h) => write!(f, "Direct({:p})", *h as *const ()),
+            Self::Dispatched(h) => write!(f, "Dispatched({:p})", *h as *const ()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Entry {
+    pub name: &'static str,
+    pub kind: Kind,
+    /// `None` for vectors reserved for future use.
+    pub handler: Option<Handler>,
+}
+
+#[derive(Debug)]
+struct Registry {
+    entries: [Option<Entry>; COUNT],
+}
+
+impl Registry {
+    const fn new() -> Self {
+        Self {
+            entries: [None; COUNT],
+        }
+    }
+
+    fn slot(&mut self, vector: u8) -> &mut Option<Entry> {
+        assert!(FIRST <= vector, "Vector {:#04x} is a CPU exception", vector);
+        &mut self.entries[(vector - FIRST) as usize]
+    }
+
+    fn get(&self, vector: u8) -> Option<Entry> {
+        vector
+            .checked_sub(FIRST)
+            .and_then(|i| self.entries[i as usize])
+    }
+
+    fn reserve(&mut self, vector: u8, entry: Entry) -> Result<(), Entry> {
+        let slot = self.slot(vector);
+        match slot {
+            Some(owner) => Err(*owner),
+            None => {
+                *slot = Some(entry);
+                Ok(())
+            }
+        }
+    }
+
+    fn allocate(&mut self, entry: Entry) -> Option<u8> {
+        let i = self.entries.iter().position(|e| e.is_none())?;
+        self.entries[i] = Some(entry);
+        Some(FIRST + i as u8)
+    }
+
+    fn release(&mut self, vector: u8) -> bool {
+        let slot = self.slot(vector);
+        match slot {
+            Some(Entry {
+                kind: Kind::Dynamic,
+                ..
+            }) => {
+                *slot = None;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Reserve `vector` for a fixed assignment. Panics if the vector is already reserved, since the
+/// vector map is broken then.
+pub fn reserve_fixed(vector: u32, name: &'static str, kind: Kind, handler: Option<Handler>) {
+    let vector = u8::try_from(vector).expect("Vector out of range");
+    let entry = Entry {
+        name,
+        kind,
+        handler,
+    };
+    if let Err(owner) = VECTORS.lock().reserve(vector, entry) {
+        panic!(
+            "interrupts: Vector {:#04x} for {} is already reserved by {}",
+            vector, name, owner.name
+        );
+    }
+}
+
+/// Allocate an unused vector for `handler`. Returns `None` if the vectors are exhausted.
+pub fn allocate(name: &'static str, handler: fn()) -> Option<u8> {
+    VECTORS.lock().allocate(Entry {
+        name,
+        kind: Kind::Dynamic,
+        handler: Some(Handler::Dispatched(handler)),
+    })
+}
+
+/// Release a vector allocated by `allocate`. Returns `false` if the vector is not allocated
+/// dynamically.
+pub fn release(vector: u8) -> bool {
+    VECTORS.lock().release(vector)
+}
+
+pub fn get(vector: u8) -> Option<Entry> {
+    VECTORS.lock().get(vector)
+}
+
+/// Reserved vectors in ascending order.
+pub fn entries() -> Vec<(u8, Entry)> {
+    let registry = VECTORS.lock();
+    (FIRST..=u8::MAX)
+        .zip(registry.entries.iter())
+        .filter_map(|(v, e)| Some((v, (*e)?)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::info;
+
+    fn entry(name: &'static str, kind: Kind) -> Entry {
+        Entry {
+            name,
+            kind,
+            handler: None,
+        }
+    }
+
+    #[test_case]
+    fn test_registry() {
+        info!("TESTING interrupts::vectors::test_registry");
+        let mut registry = Registry::new();
+        assert!(registry.reserve(0x20, entry("a", Kind::Local)).is_ok());
+        assert_eq!(
+            registry
+                .reserve(0x20, entry("b", Kind::Legacy))
+                .map_err(|e| e.name),
+            Err("a")
+        );
+        assert_eq!(registry.get(0x20).map(|e| e.name), Some("a"));
+        assert_eq!(registry.get(0x10).map(|e| e.name), None);
+        for v in IPI {
+            registry.reserve(v, entry("ipi", Kind::Ipi)).unwrap();
+        }
+
+        // Dynamic allocations skip the reserved vectors
+        let mut allocated = Vec::new();
+        while let Some(v) = registry.allocate(entry("d", Kind::Dynamic)) {
+            allocated.push(v);
+        }
+        assert_eq!(allocated.len(), COUNT - 1 - IPI.count());
+        assert_eq!(allocated[0], 0x21);
+        assert!(allocated.iter().all(|v| *v != 0x20 && !IPI.contains(v)));
+        assert_eq!(allocated.last(), Some(&SPURIOUS));
+
+        // Only dynamic allocations are released
+        assert!(!registry.release(0x20));
+        assert!(!registry.release(*IPI.start()));
+        assert!(registry.release(0x30));
+        assert!(!registry.release(0x30));
+        assert_eq!(registry.allocate(entry("e", Kind::Dynamic)), Some(0x30));
+        assert_eq!(registry.allocate(entry("f", Kind::Dynamic)), None);
+    }
+
+    #[test_case]
+    fn test_vector_map() {
+        info!("TESTING interrupts::vectors::test_vector_map");
+        let kind = |v: u32| get(v as u8).map(|e| e.kind);
+        assert_eq!(kind(IRQ_TIMER), Some(Kind::Local));
+        assert_eq!(kind(IRQ_KBD), Some(Kind::Legacy));
+        assert_eq!(kind(IRQ_VIRTIO_BLOCK.start), Some(Kind::Msi));
+        assert_eq!(kind(IRQ_VIRTIO_INPUT.end - 1), Some(Kind::Msi));
+        assert!(IPI
+            .clone()
+            .all(|v| get(v).map(|e| e.kind) == Some(Kind::Ipi)));
+        assert_eq!(get(SPURIOUS).map(|e| e.kind), Some(Kind::Local));
+
+        let v = allocate("test", || {}).unwrap();
+        assert!(!IPI.contains(&v));
+        assert_eq!(
+            get(v).map(|e| (e.name, e.kind)),
+            Some(("test", Kind::Dynamic))
+        );
+        assert!(release(v));
+        assert!(get(v).is_none());
+    }
+}
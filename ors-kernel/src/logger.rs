@@ -1,6 +1,40 @@
+use crate::interrupts::{ticks, TIMER_FREQ};
+use crate::sync::spin::Spin;
+use core::hash::{Hash, Hasher};
+use core::mem;
+use ors_common::non_contiguous::Array;
+use spin::Lazy;
+
+/// The period in ticks during which repeated messages from the same callsite are suppressed.
+const RATE_LIMIT_WINDOW: usize = TIMER_FREQ;
+const RATE_LIMIT_ENTRIES: usize = 64;
+
+static RATE_LIMITS: Lazy<Spin<RateLimiter<RATE_LIMIT_ENTRIES>>> =
+    Lazy::new(|| Spin::new(RateLimiter::new(RATE_LIMIT_WINDOW)));
+
+/// Same as `log::log!`, except that the messages from the same callsite with the same `key` are
+/// emitted at most once per `RATE_LIMIT_WINDOW`. The next emitted message reports the number of
+/// messages suppressed in between.
+macro_rules! log_rate_limited {
+    ($level:expr, $key:expr, $fmt:literal $(, $arg:expr)* $(,)?) => {
+        if ::log::log_enabled!($level) {
+            let key = $crate::logger::callsite_key(file!(), line!(), &$key);
+            match $crate::logger::rate_limit(key) {
+                Some(0) => ::log::log!($level, $fmt $(, $arg)*),
+                Some(suppressed) => ::log::log!(
+                    $level,
+                    concat!($fmt, " ({} similar messages suppressed)") $(, $arg)*,
+                    suppressed
+                ),
+                None => {}
+            }
+        }
+    };
+}
+
 pub fn register() {
     log::set_logger(&KernelLogger).unwrap();
-    log::set_max_level(log::LevelFilter::Info);
+    log::set_max_level(crate::config::LOG_LEVEL);
 }
 
 struct KernelLogger;
@@ -16,3 +50,148 @@ impl log::Log for KernelLogger {
 
     fn flush(&self) {}
 }
+
+/// Returns `Some(n)` if a message with the key should be emitted, where `n` is the number of
+/// the suppressed messages since the last emission. Used by `log_rate_limited!`.
+pub fn rate_limit(key: usize) -> Option<usize> {
+    // Spin disables interrupts, so that this can be called from interrupt handlers
+    RATE_LIMITS.lock().check(key, ticks())
+}
+
+/// Identify a `log_rate_limited!` callsite and its key.
+pub fn callsite_key(file: &str, line: u32, key: &impl Hash) -> usize {
+    let mut hasher = Fnv1a::new();
+    file.hash(&mut hasher);
+    line.hash(&mut hasher);
+    key.hash(&mut hasher);
+    // The top bit is dropped to avoid overflows on the open addressing of Array
+    (hasher.finish() >> 1) as usize
+}
+
+#[derive(Debug)]
+struct RateLimiter<const N: usize> {
+    window: usize,
+    entries: Array<usize, RateLimitEntry, N>,
+}
+
+#[derive(Debug)]
+struct RateLimitEntry {
+    last_emission: usize,
+    suppressed: usize,
+}
+
+impl<const N: usize> RateLimiter<N> {
+    fn new(window: usize) -> Self {
+        Self {
+            window,
+            entries: Array::new(),
+        }
+    }
+
+    fn check(&mut self, key: usize, now: usize) -> Option<usize> {
+        match self.entries.get_mut(key) {
+            Some(e) if now.wrapping_sub(e.last_emission) < self.window => {
+                e.suppressed += 1;
+                None
+            }
+            Some(e) => {
+                e.last_emission = now;
+                Some(mem::take(&mut e.suppressed))
+            }
+            None => {
+                // Array does not support removal. Forgetting the entries at worst lets a few
+                // messages through, which is better than dropping them forever
+                if self.entries.len() == N {
+                    self.entries.clear();
+                }
+                let entry = RateLimitEntry {
+                    last_emission: now,
+                    suppressed: 0,
+                };
+                self.entries.insert(key, entry);
+                Some(0)
+            }
+        }
+    }
+}
+
+/// Allocation-free FNV-1a hasher.
+#[derive(Debug)]
+struct Fnv1a(u64);
+
+impl Fnv1a {
+    fn new() -> Self {
+        Self(0xcbf29ce484222325)
+    }
+}
+
+impl Hasher for Fnv1a {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.0 ^= *b as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::info;
+
+    #[test_case]
+    fn test_rate_limiter_burst() {
+        info!("TESTING logger::test_rate_limiter_burst");
+        let mut limiter = RateLimiter::<4>::new(10);
+        let mut emitted = 0;
+        for i in 0..1000 {
+            if limiter.check(1, i / 100).is_some() {
+                emitted += 1;
+            }
+        }
+        assert_eq!(emitted, 1); // within a single window
+        assert_eq!(limiter.check(1, 10), Some(999));
+        assert_eq!(limiter.check(1, 11), None);
+        assert_eq!(limiter.check(1, 25), Some(1));
+
+        // A burst lasting several windows
+        let mut emitted = 0;
+        let mut suppressed = 0;
+        for i in 0..1000 {
+            if let Some(n) = limiter.check(2, 100 + i / 20) {
+                emitted += 1;
+                suppressed += n;
+            }
+        }
+        assert_eq!(emitted, 5);
+        assert_eq!(emitted + suppressed, 801);
+        assert_eq!(limiter.entries.get(2).unwrap().suppressed, 199);
+    }
+
+    #[test_case]
+    fn test_rate_limiter_keys() {
+        info!("TESTING logger::test_rate_limiter_keys");
+        let mut limiter = RateLimiter::<4>::new(10);
+        assert_eq!(limiter.check(1, 0), Some(0));
+        assert_eq!(limiter.check(2, 0), Some(0));
+        assert_eq!(limiter.check(1, 1), None);
+        assert_eq!(limiter.check(3, 1), Some(0));
+        assert_eq!(limiter.check(2, 10), Some(0));
+        assert_eq!(limiter.check(1, 10), Some(1));
+
+        // The table is reset when it is full
+        assert_eq!(limiter.check(4, 10), Some(0));
+        assert_eq!(limiter.check(5, 10), Some(0));
+        assert_eq!(limiter.check(1, 10), Some(0));
+
+        let a = callsite_key("a.rs", 1, &());
+        assert_ne!(a, callsite_key("a.rs", 2, &()));
+        assert_ne!(a, callsite_key("b.rs", 1, &()));
+        assert_ne!(a, callsite_key("a.rs", 1, &0));
+        assert_eq!(a, callsite_key("a.rs", 1, &()));
+    }
+}
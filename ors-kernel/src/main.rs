@@ -14,16 +14,26 @@ extern crate alloc;
 
 #[macro_use]
 pub mod print;
+#[macro_use]
+pub mod config;
+#[macro_use]
+pub mod trace;
+#[macro_use]
+pub mod logger;
 pub mod acpi;
 pub mod allocator;
+pub mod boot_params;
 pub mod console;
 pub mod context;
 pub mod cpu;
+pub mod crashdump;
 pub mod devices;
+mod editor;
+pub mod entropy;
 pub mod fs;
 pub mod graphics;
 pub mod interrupts;
-pub mod logger;
+pub mod memtest;
 pub mod paging;
 pub mod phys_memory;
 pub mod segmentation;
@@ -32,34 +42,79 @@ pub mod sync;
 pub mod task;
 pub mod x64;
 
+use ors_common::command_line::CommandLine;
 use ors_common::frame_buffer::FrameBuffer as RawFrameBuffer;
 use ors_common::memory_map::MemoryMap;
 
 #[no_mangle]
-pub extern "sysv64" fn kernel_main2(fb: &RawFrameBuffer, mm: &MemoryMap, rsdp: u64) {
+pub extern "sysv64" fn kernel_main2(
+    fb: &RawFrameBuffer,
+    mm: &MemoryMap,
+    rsdp: u64,
+    cl: &CommandLine,
+) {
     x64::interrupts::enable(); // To ensure that interrupts are enabled by default
 
     let cli = interrupts::Cli::new();
     logger::register();
-    unsafe { segmentation::initialize() };
+    // The command line is in the memory of the loader, which is freed for the frame manager
+    boot_params::initialize(cl);
+    let screen = console::select_output(
+        fb,
+        boot_params::get("console")
+            .and_then(console::OutputMode::from_name)
+            .unwrap_or_default(),
+    );
+    log::info!("{}", config::describe());
+    let cpu = x64::cpuid::info();
+    log::info!("CPU: {}", cpu);
+    // Context switches save the SSE state by FXSAVE
+    assert!(
+        cpu.features.sse2 && cpu.features.fxsr,
+        "The CPU does not support SSE2 and FXSAVE, which are required by ors"
+    );
     unsafe { paging::initialize() };
     unsafe { phys_memory::frame_manager().initialize(mm) };
+    // Splitting the pages of the kernel image requires page tables from the frame manager
+    unsafe { paging::protect_kernel_image() };
+    // Interrupt stacks are allocated from the frame manager
+    unsafe { segmentation::initialize() };
+    if let Some(mode) = boot_params::get("memtest").and_then(memtest::Mode::parse) {
+        memtest::run(mode);
+    }
+    allocator::reserve_heap(allocator::heap_reservation(boot_params::get("heap")));
     unsafe { acpi::initialize(paging::KernelAcpiHandler, rsdp as usize) };
     cpu::initialize();
     unsafe { interrupts::initialize() };
     task::initialize_scheduler();
+    task::workqueue::initialize(task::workqueue::DEFAULT_PRIORITY);
+    // Fixed events are handled by the work queue
+    unsafe { acpi::enable_fixed_events() };
     devices::pci::initialize_devices();
     devices::virtio::block::initialize();
+    devices::virtio::input::initialize();
     devices::serial::default_port().init();
-    console::initialize((*fb).into());
-    task::scheduler().add(task::Priority::L1, shell::run, 0);
+    if let Some(screen) = screen {
+        graphics::display::initialize(screen);
+    }
+    console::initialize();
     drop(cli);
 
+    // Block I/O requires task switching, which is not allowed while interrupts are disabled
+    fs::initialize();
+    crashdump::initialize();
+    console::load_logo();
+    console::load_initial_font();
+    task::scheduler().add(task::Priority::L1, shell::run, 0);
+
     #[cfg(test)]
     test_main();
 
     loop {
-        x64::hlt()
+        x64::hlt();
+        // Run the tasks woken by the interrupt, such as by I/O completions, without waiting for
+        // the next preemption
+        task::scheduler().r#yield();
     }
 }
 
@@ -67,6 +122,15 @@ pub extern "sysv64" fn kernel_main2(fb: &RawFrameBuffer, mm: &MemoryMap, rsdp: u
 fn panic(info: &core::panic::PanicInfo) -> ! {
     sprintln!("{}", info);
 
+    // A panic in a supervised task only kills the task if possible
+    task::oops(info);
+
+    #[cfg(not(test))]
+    match crashdump::write() {
+        Ok(()) => sprintln!("Crash dump written at sector {}", crashdump::start_sector()),
+        Err(e) => sprintln!("Failed to write crash dump: {}", e),
+    }
+
     #[cfg(test)]
     devices::qemu::exit(devices::qemu::ExitCode::Failure);
 
@@ -0,0 +1,178 @@
+//! A simple memory test for catching bad RAM and mapping errors.
+
+use crate::phys_memory::{frame_manager, Frame};
+use crate::sync::spin::Spin;
+use alloc::vec::Vec;
+use core::{ptr, slice};
+use log::{info, warn};
+
+static LAST_REPORT: Spin<Option<Report>> = Spin::new(None);
+
+/// The result of the last memory test.
+pub fn last_report() -> Option<Report> {
+    LAST_REPORT.lock().clone()
+}
+
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy)]
+pub enum Mode {
+    /// Test only a sampled fraction of frames.
+    Quick,
+    /// Test all frames.
+    Full,
+}
+
+impl Mode {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "quick" => Some(Self::Quick),
+            "full" => Some(Self::Full),
+            _ => None,
+        }
+    }
+
+    fn sampling_interval(self) -> usize {
+        match self {
+            Self::Quick => 64,
+            Self::Full => 1,
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Report {
+    pub mode: Mode,
+    pub tested_frames: usize,
+    /// Frames that failed the test. These frames remain allocated permanently.
+    pub bad_frames: Vec<Frame>,
+}
+
+/// Test the currently available frames.
+///
+/// Each frame is reserved in the frame manager while it is being tested, so this function
+/// can run concurrently with other allocations. The frame manager lock is held only during
+/// the reservation and release of each frame.
+pub fn run(mode: Mode) -> Report {
+    info!("memtest: running in {:?} mode", mode);
+    let frames = frame_manager().frames();
+    let total = frames.len();
+    let progress_step = (total / 100).max(1);
+    let mut report = Report {
+        mode,
+        tested_frames: 0,
+        bad_frames: Vec::new(),
+    };
+
+    for (i, frame) in frames.enumerate().step_by(mode.sampling_interval()) {
+        if mode == Mode::Full && i % progress_step == 0 {
+            sprint!("\rmemtest: {}%", i * 100 / total);
+        }
+
+        if !frame_manager().try_reserve(frame) {
+            continue;
+        }
+        match unsafe { test_frame(frame) } {
+            Some(true) => {
+                report.tested_frames += 1;
+                frame_manager().free(frame, 1);
+            }
+            Some(false) => {
+                report.tested_frames += 1;
+                warn!("memtest: bad frame at {:?}", frame.phys_addr());
+                report.bad_frames.push(frame);
+            }
+            None => frame_manager().free(frame, 1),
+        }
+    }
+    if mode == Mode::Full {
+        sprintln!("\rmemtest: 100%");
+    }
+
+    info!(
+        "memtest: {} frames tested, {} bad frames",
+        report.tested_frames,
+        report.bad_frames.len()
+    );
+    *LAST_REPORT.lock() = Some(report.clone());
+    report
+}
+
+/// Returns `None` if the frame is not accessible.
+/// The caller must ensure that the frame is not used by others.
+unsafe fn test_frame(mut frame: Frame) -> Option<bool> {
+    let base = frame.phys_addr().as_u64();
+    let bytes = frame.as_slice_mut()?;
+    let words = slice::from_raw_parts_mut(bytes.as_mut_ptr() as *mut u64, bytes.len() / 8);
+    for pattern in PATTERNS {
+        fill(words, base, pattern);
+        if !verify(words, base, pattern) {
+            return Some(false);
+        }
+    }
+    Some(true)
+}
+
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy)]
+enum Pattern {
+    AddressAsData,
+    InvertedAddress,
+    AlternatingBits,
+    InvertedAlternatingBits,
+}
+
+const PATTERNS: [Pattern; 4] = [
+    Pattern::AddressAsData,
+    Pattern::InvertedAddress,
+    Pattern::AlternatingBits,
+    Pattern::InvertedAlternatingBits,
+];
+
+impl Pattern {
+    fn word(self, addr: u64) -> u64 {
+        match self {
+            Self::AddressAsData => addr,
+            Self::InvertedAddress => !addr,
+            Self::AlternatingBits => 0xaaaa_aaaa_aaaa_aaaa,
+            Self::InvertedAlternatingBits => 0x5555_5555_5555_5555,
+        }
+    }
+}
+
+fn fill(words: &mut [u64], base: u64, pattern: Pattern) {
+    for (i, w) in words.iter_mut().enumerate() {
+        unsafe { ptr::write_volatile(w, pattern.word(base + i as u64 * 8)) };
+    }
+}
+
+fn verify(words: &[u64], base: u64, pattern: Pattern) -> bool {
+    words
+        .iter()
+        .enumerate()
+        .all(|(i, w)| unsafe { ptr::read_volatile(w) } == pattern.word(base + i as u64 * 8))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::info;
+
+    #[test_case]
+    fn test_pattern() {
+        info!("TESTING memtest::test_pattern");
+
+        let mut frame = frame_manager().allocate(1).unwrap();
+        assert_eq!(unsafe { test_frame(frame) }, Some(true));
+
+        let base = frame.phys_addr().as_u64();
+        let bytes = unsafe { frame.as_slice_mut() }.unwrap();
+        let words =
+            unsafe { slice::from_raw_parts_mut(bytes.as_mut_ptr() as *mut u64, bytes.len() / 8) };
+        for pattern in PATTERNS {
+            fill(words, base, pattern);
+            assert!(verify(words, base, pattern));
+            // Inject a mismatch
+            unsafe { ptr::write_volatile(&mut words[123], !words[123]) };
+            assert!(!verify(words, base, pattern));
+        }
+        frame_manager().free(frame, 1);
+    }
+}
@@ -1,7 +1,11 @@
-use crate::x64::{self, PageSize};
+use crate::phys_memory::{frame_manager, AllocateError, Tag};
+use crate::sync::spin::Spin;
+use crate::x64::{self, PageSize, Translate};
 use acpi::{AcpiHandler, PhysicalMapping};
+use core::ops::Range;
 use core::ptr::NonNull;
-use log::trace;
+use log::{trace, warn};
+use ors_common::elf::{self, Permissions};
 use spin::Lazy;
 
 const EMPTY_PAGE_TABLE: x64::PageTable = x64::PageTable::new();
@@ -11,8 +15,24 @@ static mut PML4_TABLE: x64::PageTable = x64::PageTable::new();
 static mut PDP_TABLE: x64::PageTable = x64::PageTable::new();
 static mut PAGE_DIRECTORY: [x64::PageTable; 64] = [EMPTY_PAGE_TABLE; 64]; // supports up to 64GiB
 
+/// Serializes modifications of the identity mapping after initialization.
+static MAPPING_LOCK: Spin<()> = Spin::new(());
+
+extern "C" {
+    /// The ELF header of the kernel image, defined by the linker.
+    static __ehdr_start: u8;
+}
+
 pub unsafe fn initialize() {
     trace!("INITIALIZING paging");
+    // NO_EXECUTE is a reserved bit unless NXE is set. WP makes read-only pages read-only for the
+    // kernel too
+    if x64::cpuid::info().features.nx {
+        x64::Efer::update(|flags| flags.insert(x64::EferFlags::NO_EXECUTE_ENABLE));
+    } else {
+        warn!("paging: NX is not supported, every page is executable");
+    }
+    x64::Cr0::update(|flags| flags.insert(x64::Cr0Flags::WRITE_PROTECT));
     x64::Cr3::write(*PAGE_TABLE, x64::Cr3Flags::empty());
 }
 
@@ -49,7 +69,6 @@ unsafe fn initialize_identity_mapping() -> x64::PhysFrame {
     phys_frame(&PML4_TABLE)
 }
 
-#[allow(dead_code)]
 unsafe fn mapper() -> impl x64::Mapper<x64::Size4KiB> + x64::Translate {
     let _ = Lazy::force(&PAGE_TABLE);
     // Since ors uses identity mapping, we can use OffsetPageTable with offset=0.
@@ -57,6 +76,169 @@ unsafe fn mapper() -> impl x64::Mapper<x64::Size4KiB> + x64::Translate {
     x64::OffsetPageTable::new(&mut PML4_TABLE, x64::VirtAddr::zero())
 }
 
+/// Whether `addr` is mapped to a physical address.
+pub fn is_mapped(addr: x64::VirtAddr) -> bool {
+    unsafe { mapper().translate_addr(addr).is_some() }
+}
+
+/// Unmap the 4KiB page at `addr` so that any access to it causes a page fault. This is used to
+/// place a guard page below a stack. The 2MiB page containing it is split into 4KiB pages.
+///
+/// Other CPUs may keep the page in their TLBs, thus this should be called before the page is
+/// shared with them.
+pub unsafe fn set_guard_page(addr: x64::VirtAddr) -> Result<(), AllocateError> {
+    use x64::PageTableFlags as Flags;
+
+    let addr = addr.as_u64();
+    assert!(
+        addr < x64::Size1GiB::SIZE * 64,
+        "Guard page out of the mapping"
+    );
+    assert!(addr % x64::Size4KiB::SIZE == 0, "Unaligned guard page");
+
+    let _lock = MAPPING_LOCK.lock();
+    let p = page_entry(addr)?;
+    p.set_flags(p.flags() - Flags::PRESENT);
+    x64::tlb::flush(x64::VirtAddr::new(addr));
+    Ok(())
+}
+
+/// Map the 4KiB pages overlapping `range` with `permissions`. The 2MiB pages containing them are
+/// split into 4KiB pages.
+///
+/// As with `set_guard_page`, other CPUs may keep the pages in their TLBs.
+pub unsafe fn protect(
+    range: Range<x64::VirtAddr>,
+    permissions: Permissions,
+) -> Result<(), AllocateError> {
+    use x64::PageTableFlags as Flags;
+
+    let start = range.start.align_down(x64::Size4KiB::SIZE).as_u64();
+    let end = range.end.align_up(x64::Size4KiB::SIZE).as_u64();
+    assert!(
+        end <= x64::Size1GiB::SIZE * 64,
+        "Protected range out of the mapping"
+    );
+
+    let _lock = MAPPING_LOCK.lock();
+    for addr in (start..end).step_by(x64::Size4KiB::SIZE as usize) {
+        let p = page_entry(addr)?;
+        let mut flags = p.flags() - Flags::WRITABLE - Flags::NO_EXECUTE;
+        if permissions.writable {
+            flags |= Flags::WRITABLE;
+        }
+        if !permissions.executable && is_nx_enabled() {
+            flags |= Flags::NO_EXECUTE;
+        }
+        p.set_flags(flags);
+        x64::tlb::flush(x64::VirtAddr::new(addr));
+    }
+    Ok(())
+}
+
+fn is_nx_enabled() -> bool {
+    x64::Efer::read().contains(x64::EferFlags::NO_EXECUTE_ENABLE)
+}
+
+/// The 4KiB page table entry of the identity mapping at `addr`. The 2MiB page containing it is
+/// split into 4KiB pages. `MAPPING_LOCK` must be held.
+unsafe fn page_entry(addr: u64) -> Result<&'static mut x64::PageTableEntry, AllocateError> {
+    use x64::PageTableFlags as Flags;
+
+    let _ = Lazy::force(&PAGE_TABLE);
+    let i = (addr / x64::Size1GiB::SIZE) as usize;
+    let j = (addr % x64::Size1GiB::SIZE / x64::Size2MiB::SIZE) as usize;
+    let entry = &mut PAGE_DIRECTORY[i][j];
+    if entry.flags().contains(Flags::HUGE_PAGE) {
+        let frame = frame_manager().allocate_tagged(1, Tag::PageTable)?;
+        let table = &mut *as_virt_addr(frame.phys_addr())
+            .unwrap()
+            .as_mut_ptr::<x64::PageTable>();
+        *table = x64::PageTable::new();
+        let flags = entry.flags() - Flags::HUGE_PAGE;
+        for (k, p) in table.iter_mut().enumerate() {
+            p.set_addr(entry.addr() + k as u64 * x64::Size4KiB::SIZE, flags);
+        }
+        entry.set_frame(frame.phys_frame(), flags);
+    }
+
+    // The page table is identity-mapped
+    let table = &mut *as_virt_addr(entry.addr())
+        .unwrap()
+        .as_mut_ptr::<x64::PageTable>();
+    Ok(&mut table[(addr % x64::Size2MiB::SIZE / x64::Size4KiB::SIZE) as usize])
+}
+
+/// Remap the kernel image with the permissions of its segments, so that the code and the
+/// read-only data are not writable and the other data is not executable. A page shared by
+/// segments gets the permissions of all of them.
+///
+/// The segments are found by the program headers, which lld loads along with the ELF header at
+/// `__ehdr_start`. This works without a linker script exporting the section boundaries.
+pub unsafe fn protect_kernel_image() {
+    trace!("INITIALIZING kernel image protection");
+    let ehdr = &__ehdr_start as *const u8;
+    let headers = match elf::loaded_program_headers(ehdr) {
+        Some(headers) => headers,
+        None => {
+            warn!("paging: Kernel program headers are not loaded, the image is not protected");
+            return;
+        }
+    };
+    let segments = headers
+        .iter()
+        .enumerate()
+        .filter(|(_, ph)| ph.p_type == elf::PT_LOAD);
+    // The ELF header is at the start of the segment at the file offset 0
+    let base = match segments.clone().find(|(_, ph)| ph.p_offset == 0) {
+        Some((_, ph)) => ehdr as u64 - ph.p_vaddr,
+        None => ehdr as u64,
+    };
+    let start = segments
+        .clone()
+        .map(|(_, ph)| ph.p_vaddr)
+        .min()
+        .unwrap_or(0);
+    let end = segments
+        .clone()
+        .map(|(_, ph)| ph.p_vaddr + ph.p_memsz)
+        .max()
+        .unwrap_or(0);
+    let start = x64::VirtAddr::new(base + start).align_down(x64::Size4KiB::SIZE);
+    let end = x64::VirtAddr::new(base + end).align_up(x64::Size4KiB::SIZE);
+
+    let mut writable_executable_pages = 0;
+    for page in (start.as_u64()..end.as_u64()).step_by(x64::Size4KiB::SIZE as usize) {
+        let page = x64::VirtAddr::new(page)..x64::VirtAddr::new(page + x64::Size4KiB::SIZE);
+        let permissions = segments
+            .clone()
+            .filter(|(_, ph)| {
+                let vaddr = x64::VirtAddr::new(base + ph.p_vaddr);
+                vaddr < page.end && page.start < vaddr + ph.p_memsz
+            })
+            .map(|(i, ph)| {
+                // The loader rejects such segments
+                Permissions::from_flags(i, ph.p_flags).unwrap_or(Permissions {
+                    writable: true,
+                    executable: true,
+                })
+            })
+            .reduce(Permissions::union);
+        if let Some(permissions) = permissions {
+            if permissions.writable && permissions.executable {
+                writable_executable_pages += 1;
+            }
+            protect(page, permissions).unwrap();
+        }
+    }
+    if writable_executable_pages != 0 {
+        warn!(
+            "paging: {} pages of the kernel image are writable and executable",
+            writable_executable_pages
+        );
+    }
+}
+
 pub fn as_virt_addr(addr: x64::PhysAddr) -> Option<x64::VirtAddr> {
     if addr.as_u64() < x64::Size1GiB::SIZE * 64 {
         // Physical memory areas of up to 64 GiB are identity-mapped.
@@ -90,3 +272,65 @@ impl AcpiHandler for KernelAcpiHandler {
 
     fn unmap_physical_region<T>(_region: &PhysicalMapping<Self, T>) {}
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interrupts::{self, PageFault};
+    use crate::task::{self, Priority, RestartPolicy};
+    use core::ptr;
+    use core::sync::atomic::{AtomicBool, Ordering};
+    use log::info;
+
+    static READ_ONLY: u64 = 0x1234;
+    static mut DATA: [u8; 16] = [0xc3; 16]; // ret
+    static RESUMED: AtomicBool = AtomicBool::new(false);
+
+    /// Run `entry` in a supervised task that is never restarted, and wait for it to be killed.
+    fn run_to_fault(entry: extern "C" fn(u64) -> !) -> PageFault {
+        let policy = RestartPolicy {
+            max_restarts: 0,
+            backoff: 0,
+        };
+        let id = task::scheduler().add_supervised(Priority::L1, entry, 0, policy);
+        while task::scheduler().tasks().iter().any(|info| info.id == id) {
+            task::scheduler().sleep(1);
+        }
+        interrupts::last_page_fault().unwrap()
+    }
+
+    extern "C" fn write_read_only(_: u64) -> ! {
+        unsafe { ptr::write_volatile(&READ_ONLY as *const u64 as *mut u64, 0) };
+        RESUMED.store(true, Ordering::SeqCst);
+        task::scheduler().exit()
+    }
+
+    extern "C" fn jump_to_data(_: u64) -> ! {
+        let f: extern "C" fn() = unsafe { core::mem::transmute(DATA.as_ptr()) };
+        f();
+        RESUMED.store(true, Ordering::SeqCst);
+        task::scheduler().exit()
+    }
+
+    #[test_case]
+    fn test_kernel_image_protection() {
+        use x64::PageFaultErrorCode as Code;
+
+        info!("TESTING paging::test_kernel_image_protection");
+        let fault = run_to_fault(write_read_only);
+        assert_eq!(fault.address, x64::VirtAddr::from_ptr(&READ_ONLY));
+        assert!(fault
+            .error_code
+            .contains(Code::PROTECTION_VIOLATION | Code::CAUSED_BY_WRITE));
+        assert!(!RESUMED.load(Ordering::SeqCst));
+        assert_eq!(unsafe { ptr::read_volatile(&READ_ONLY) }, 0x1234);
+
+        let fault = run_to_fault(jump_to_data);
+        assert_eq!(
+            fault.address,
+            x64::VirtAddr::from_ptr(unsafe { DATA.as_ptr() })
+        );
+        assert!(fault.error_code.contains(Code::INSTRUCTION_FETCH));
+        assert!(!RESUMED.load(Ordering::SeqCst));
+    }
+}
@@ -1,9 +1,16 @@
 // A frame represents a memory section on a physical address,
 // and does not manage the usage of linear (virtual) addresses.
 
+use crate::config;
+use crate::paging::as_virt_addr;
 use crate::sync::spin::{Spin, SpinGuard};
 use crate::x64;
-use core::mem;
+use alloc::alloc::Layout;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::convert::TryInto;
+use core::ops::Range;
+use core::{fmt, mem, slice};
 use log::trace;
 
 static FRAME_MANAGER: Spin<BitmapFrameManager> = Spin::new(BitmapFrameManager::new());
@@ -12,6 +19,11 @@ pub fn frame_manager() -> SpinGuard<'static, BitmapFrameManager> {
     FRAME_MANAGER.lock()
 }
 
+/// Same as `frame_manager`, but returns `None` instead of waiting if the frame manager is locked.
+pub fn try_frame_manager() -> Option<SpinGuard<'static, BitmapFrameManager>> {
+    FRAME_MANAGER.try_lock()
+}
+
 #[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy, Hash)]
 pub struct Frame(usize);
 
@@ -28,10 +40,36 @@ impl Frame {
         x64::PhysFrame::from_start_address(self.phys_addr()).unwrap()
     }
 
-    fn offset(self, offset: usize) -> Self {
+    pub fn offset(self, offset: usize) -> Self {
         Self(self.0 + offset)
     }
 
+    /// Get the contents of this frame. Returns `None` if the frame is not identity-mapped.
+    ///
+    /// Physical frames do not participate in Rust's aliasing rules, thus the caller must ensure
+    /// that the frame is not modified through other references while the slice is alive.
+    pub unsafe fn as_slice(&self) -> Option<&[u8]> {
+        let ptr = as_virt_addr(self.phys_addr())?.as_ptr();
+        Some(slice::from_raw_parts(ptr, Self::SIZE))
+    }
+
+    /// Get the contents of this frame mutably. Returns `None` if the frame is not identity-mapped.
+    ///
+    /// Physical frames do not participate in Rust's aliasing rules, thus the caller must ensure
+    /// that the frame is not accessed through other references while the slice is alive.
+    pub unsafe fn as_slice_mut(&mut self) -> Option<&mut [u8]> {
+        let ptr = as_virt_addr(self.phys_addr())?.as_mut_ptr();
+        Some(slice::from_raw_parts_mut(ptr, Self::SIZE))
+    }
+
+    /// Number of frames required to hold `bytes` bytes.
+    pub fn count_for_bytes(bytes: usize) -> Result<usize, AllocateError> {
+        let bytes = bytes
+            .checked_add(Self::SIZE - 1)
+            .ok_or(AllocateError::TooLarge)?;
+        Ok(bytes / Self::SIZE)
+    }
+
     const MIN: Self = Self(1); // TODO: Why 1 instead of 0?
     const MAX: Self = Self(FRAME_COUNT);
 
@@ -49,11 +87,109 @@ pub struct BitmapFrameManager {
     alloc_map: [MapLine; MAP_LINE_COUNT],
     begin: Frame,
     end: Frame,
+    tag_frames: [usize; Tag::COUNT],
+}
+
+/// The purpose of allocated frames, used to track frame usage per subsystem.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy, Hash)]
+pub enum Tag {
+    HeapBlock,
+    HeapLarge,
+    /// Frames reserved for the kernel heap at boot, see `allocator::reserve_heap`.
+    HeapReserved,
+    VirtQueue,
+    TaskStack,
+    PageTable,
+    Other,
+}
+
+impl Tag {
+    pub const COUNT: usize = 7;
+    pub const ALL: [Self; Self::COUNT] = [
+        Self::HeapBlock,
+        Self::HeapLarge,
+        Self::HeapReserved,
+        Self::VirtQueue,
+        Self::TaskStack,
+        Self::PageTable,
+        Self::Other,
+    ];
+
+    pub fn index(self) -> usize {
+        self as usize
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::HeapBlock => "heap-block",
+            Self::HeapLarge => "heap-large",
+            Self::HeapReserved => "heap-rsvd",
+            Self::VirtQueue => "virtqueue",
+            Self::TaskStack => "task-stack",
+            Self::PageTable => "page-table",
+            Self::Other => "other",
+        }
+    }
 }
 
 #[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone)]
 pub enum AllocateError {
     NotEnoughFrame,
+    /// The size does not fit in the address space.
+    TooLarge,
+}
+
+const DUMP_MAGIC: &[u8; 8] = b"ORSFRMAP";
+const DUMP_HEADER_SIZE: usize = 32;
+
+/// Destination of `BitmapFrameManager::write_dump`.
+pub trait DumpWrite {
+    fn write(&mut self, buf: &[u8]) -> Result<(), fmt::Error>;
+}
+
+impl DumpWrite for Vec<u8> {
+    fn write(&mut self, buf: &[u8]) -> Result<(), fmt::Error> {
+        self.extend_from_slice(buf);
+        Ok(())
+    }
+}
+
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy)]
+pub enum DumpError {
+    InvalidMagic,
+    Truncated,
+    OutOfRange,
+}
+
+impl fmt::Display for DumpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidMagic => write!(f, "Not a frame manager dump"),
+            Self::Truncated => write!(f, "Frame manager dump is truncated"),
+            Self::OutOfRange => write!(f, "Frame manager dump is out of range"),
+        }
+    }
+}
+
+/// A summary of free frames, see `BitmapFrameManager::summary`.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct FrameSummary {
+    pub total_frames: usize,
+    pub free_frames: usize,
+    pub first_free_frame: Option<Frame>,
+    pub last_free_frame: Option<Frame>,
+    pub largest_free_run: usize,
+}
+
+impl FrameSummary {
+    /// Percentage of free frames that are not part of the largest contiguous free run.
+    pub fn fragmentation(&self) -> f64 {
+        if self.free_frames == 0 {
+            0.0
+        } else {
+            100.0 * (1.0 - self.largest_free_run as f64 / self.free_frames as f64)
+        }
+    }
 }
 
 impl BitmapFrameManager {
@@ -62,9 +198,15 @@ impl BitmapFrameManager {
             alloc_map: [0; MAP_LINE_COUNT],
             begin: Frame::MIN,
             end: Frame::MAX,
+            tag_frames: [0; Tag::COUNT],
         }
     }
 
+    /// Number of frames currently held by allocations with the given tag.
+    pub fn tagged_frames(&self, tag: Tag) -> usize {
+        self.tag_frames[tag.index()]
+    }
+
     pub fn total_frames(&self) -> usize {
         self.end.0 - self.begin.0
     }
@@ -83,6 +225,117 @@ impl BitmapFrameManager {
         n as f64 / (b - a) as f64
     }
 
+    /// All frames in the managed memory range, regardless of their availability.
+    pub fn frames(&self) -> impl ExactSizeIterator<Item = Frame> {
+        (self.begin.0..self.end.0).map(Frame)
+    }
+
+    pub fn summary(&self) -> FrameSummary {
+        let mut summary = FrameSummary {
+            total_frames: self.total_frames(),
+            free_frames: 0,
+            first_free_frame: None,
+            last_free_frame: None,
+            largest_free_run: 0,
+        };
+        let mut run = 0;
+        for frame in self.frames() {
+            if self.get_bit(frame) {
+                run = 0;
+                continue;
+            }
+            run += 1;
+            summary.free_frames += 1;
+            summary.first_free_frame.get_or_insert(frame);
+            summary.last_free_frame = Some(frame);
+            summary.largest_free_run = summary.largest_free_run.max(run);
+        }
+        summary
+    }
+
+    /// Serialize the allocation state for crash dumps.
+    /// Only the map lines covering the managed memory range are written.
+    pub fn write_dump(&self, w: &mut impl DumpWrite) -> Result<(), fmt::Error> {
+        let lines = self.dump_lines();
+        w.write(DUMP_MAGIC)?;
+        w.write(&(self.begin.0 as u64).to_le_bytes())?;
+        w.write(&(self.end.0 as u64).to_le_bytes())?;
+        w.write(&(lines.len() as u64).to_le_bytes())?;
+        for line in &self.alloc_map[lines] {
+            w.write(&(*line as u64).to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Size of the dump written by `write_dump` in bytes.
+    pub fn dump_size(&self) -> usize {
+        DUMP_HEADER_SIZE + self.dump_lines().len() * 8
+    }
+
+    /// Size of the whole dump in bytes, determined by the beginning of the dump.
+    pub fn dump_size_from_header(data: &[u8]) -> Result<usize, DumpError> {
+        let (_, _, num_lines) = Self::read_dump_header(data)?;
+        Ok(DUMP_HEADER_SIZE + num_lines * 8)
+    }
+
+    /// Restore the state serialized by `write_dump`. Tag statistics are not restored.
+    pub fn read_dump(data: &[u8]) -> Result<Box<Self>, DumpError> {
+        let (begin, end, num_lines) = Self::read_dump_header(data)?;
+        if data.len() < DUMP_HEADER_SIZE + num_lines * 8 {
+            Err(DumpError::Truncated)?;
+        }
+        // Self is too large to be constructed on the stack
+        let layout = Layout::new::<Self>();
+        let mut fm = unsafe {
+            let ptr = alloc::alloc::alloc_zeroed(layout) as *mut Self;
+            if ptr.is_null() {
+                alloc::alloc::handle_alloc_error(layout);
+            }
+            Box::from_raw(ptr)
+        };
+        fm.set_memory_range(Frame(begin), Frame(end));
+        let lines = fm.dump_lines();
+        if lines.len() != num_lines {
+            Err(DumpError::OutOfRange)?;
+        }
+        let body = &data[DUMP_HEADER_SIZE..];
+        for (i, line) in fm.alloc_map[lines].iter_mut().enumerate() {
+            *line = u64::from_le_bytes(body[i * 8..i * 8 + 8].try_into().unwrap()) as MapLine;
+        }
+        Ok(fm)
+    }
+
+    fn read_dump_header(data: &[u8]) -> Result<(usize, usize, usize), DumpError> {
+        if data.len() < DUMP_MAGIC.len() || &data[0..DUMP_MAGIC.len()] != DUMP_MAGIC {
+            Err(DumpError::InvalidMagic)?;
+        }
+        if data.len() < DUMP_HEADER_SIZE {
+            Err(DumpError::Truncated)?;
+        }
+        let read = |i: usize| u64::from_le_bytes(data[i..i + 8].try_into().unwrap()) as usize;
+        let (begin, end, num_lines) = (read(8), read(16), read(24));
+        if end < begin || FRAME_COUNT < end || MAP_LINE_COUNT < num_lines {
+            Err(DumpError::OutOfRange)?;
+        }
+        Ok((begin, end, num_lines))
+    }
+
+    fn dump_lines(&self) -> Range<usize> {
+        let first = self.begin.0 / BITS_PER_MAP_LINE;
+        let last = (self.end.0 + BITS_PER_MAP_LINE - 1) / BITS_PER_MAP_LINE;
+        first..last.min(MAP_LINE_COUNT)
+    }
+
+    /// Mark the frame as allocated if it is available. Returns whether the frame is reserved.
+    pub fn try_reserve(&mut self, frame: Frame) -> bool {
+        if frame < self.begin || self.end <= frame || self.get_bit(frame) {
+            return false;
+        }
+        self.mark_allocated(frame, 1, false);
+        self.tag_frames[Tag::Other.index()] += 1;
+        true
+    }
+
     fn set_memory_range(&mut self, begin: Frame, end: Frame) {
         self.begin = begin;
         self.end = end;
@@ -110,6 +363,10 @@ impl BitmapFrameManager {
     }
 
     pub fn allocate(&mut self, num_frames: usize) -> Result<Frame, AllocateError> {
+        self.allocate_tagged(num_frames, Tag::Other)
+    }
+
+    pub fn allocate_tagged(&mut self, num_frames: usize, tag: Tag) -> Result<Frame, AllocateError> {
         // Doing the first fit allocation
         let mut frame = self.begin;
         'search: loop {
@@ -123,6 +380,7 @@ impl BitmapFrameManager {
                 }
             }
             self.mark_allocated(frame, num_frames, false);
+            self.tag_frames[tag.index()] += num_frames;
             return Ok(frame);
         }
     }
@@ -130,17 +388,33 @@ impl BitmapFrameManager {
     fn mark_allocated(&mut self, frame: Frame, num_frames: usize, init: bool) {
         for i in 0..num_frames {
             if !init {
-                trace!("phys_memory: allocate {:?}", frame.offset(i).phys_addr());
+                if config::TRACE_ALLOCATIONS {
+                    trace!("phys_memory: allocate {:?}", frame.offset(i).phys_addr());
+                }
             }
             self.set_bit(frame.offset(i), true);
         }
     }
 
     pub fn free(&mut self, frame: Frame, num_frames: usize) {
+        self.free_tagged(frame, num_frames, Tag::Other)
+    }
+
+    /// `tag` must be the same as the one used on allocation.
+    pub fn free_tagged(&mut self, frame: Frame, num_frames: usize, tag: Tag) {
         for i in 0..num_frames {
-            trace!("phys_memory: deallocate {:?}", frame.offset(i).phys_addr());
+            if config::TRACE_ALLOCATIONS {
+                trace!("phys_memory: deallocate {:?}", frame.offset(i).phys_addr());
+            }
+            kensure!(
+                self.get_bit(frame.offset(i)),
+                "phys_memory: deallocate a free frame {:?}",
+                frame.offset(i).phys_addr()
+            );
             self.set_bit(frame.offset(i), false);
         }
+        let count = &mut self.tag_frames[tag.index()];
+        *count = count.saturating_sub(num_frames);
     }
 
     /// Caller must ensure that the given MemoryMap is valid.
@@ -182,9 +456,73 @@ impl x64::FrameDeallocator<x64::Size4KiB> for BitmapFrameManager {
 
 #[cfg(test)]
 mod tests {
-    use super::frame_manager;
+    use super::{frame_manager, AllocateError, BitmapFrameManager, DumpError, Frame, Tag};
+    use alloc::vec::Vec;
     use log::info;
 
+    #[test_case]
+    fn test_count_for_bytes() {
+        info!("TESTING phys_memory::test_count_for_bytes");
+        // Compare with the inline arithmetic replaced by `count_for_bytes`
+        let near_max = usize::MAX - 2 * Frame::SIZE..=usize::MAX;
+        for bytes in (0..Frame::SIZE * 8).step_by(389).chain(near_max) {
+            match bytes.checked_add(Frame::SIZE - 1) {
+                Some(b) => assert_eq!(Frame::count_for_bytes(bytes), Ok(b / Frame::SIZE)),
+                None => assert_eq!(Frame::count_for_bytes(bytes), Err(AllocateError::TooLarge)),
+            }
+        }
+    }
+
+    #[test_case]
+    fn test_dump() {
+        info!("TESTING phys_memory::test_dump");
+
+        // Allocating while the frame manager is locked may cause a deadlock
+        let mut data = Vec::with_capacity(frame_manager().dump_size());
+        let summary = {
+            let fm = frame_manager();
+            fm.write_dump(&mut data).unwrap();
+            assert_eq!(data.len(), fm.dump_size());
+            fm.summary()
+        };
+        assert_eq!(
+            BitmapFrameManager::dump_size_from_header(&data[0..32]),
+            Ok(data.len())
+        );
+        let fm = BitmapFrameManager::read_dump(&data).unwrap();
+        assert_eq!(fm.summary(), summary);
+
+        assert_eq!(
+            BitmapFrameManager::read_dump(&data[0..data.len() - 1]).err(),
+            Some(DumpError::Truncated)
+        );
+        data[0] = b'X';
+        assert_eq!(
+            BitmapFrameManager::read_dump(&data).err(),
+            Some(DumpError::InvalidMagic)
+        );
+    }
+
+    #[test_case]
+    fn test_summary() {
+        info!("TESTING phys_memory::test_summary");
+
+        // 3 free runs of 2, 1, and 4 frames
+        let mut data = Vec::new();
+        data.extend_from_slice(b"ORSFRMAP");
+        data.extend_from_slice(&64u64.to_le_bytes());
+        data.extend_from_slice(&80u64.to_le_bytes());
+        data.extend_from_slice(&1u64.to_le_bytes());
+        data.extend_from_slice(&0b1111_0000_1011_0011u64.to_le_bytes());
+        let fm = BitmapFrameManager::read_dump(&data).unwrap();
+        let summary = fm.summary();
+        assert_eq!(summary.total_frames, 16);
+        assert_eq!(summary.free_frames, 7);
+        assert_eq!(summary.first_free_frame, Some(Frame(66)));
+        assert_eq!(summary.last_free_frame, Some(Frame(75)));
+        assert_eq!(summary.largest_free_run, 4);
+    }
+
     #[test_case]
     fn test_frame_manager() {
         info!("TESTING phys_memory::test_frame_manager");
@@ -200,4 +538,20 @@ mod tests {
         frame_manager().free(b, 1);
         frame_manager().free(c, 3);
     }
+
+    #[test_case]
+    fn test_tagged_frames() {
+        info!("TESTING phys_memory::test_tagged_frames");
+
+        for tag in Tag::ALL {
+            let baseline = frame_manager().tagged_frames(tag);
+            let a = frame_manager().allocate_tagged(2, tag).unwrap();
+            let b = frame_manager().allocate_tagged(1, tag).unwrap();
+            assert_eq!(frame_manager().tagged_frames(tag), baseline + 3);
+            frame_manager().free_tagged(a, 2, tag);
+            assert_eq!(frame_manager().tagged_frames(tag), baseline + 1);
+            frame_manager().free_tagged(b, 1, tag);
+            assert_eq!(frame_manager().tagged_frames(tag), baseline);
+        }
+    }
 }
@@ -1,5 +1,4 @@
 use crate::console;
-use crate::devices;
 use core::fmt;
 
 #[derive(Debug)]
@@ -7,9 +6,8 @@ pub struct KernelWrite;
 
 impl fmt::Write for KernelWrite {
     fn write_str(&mut self, s: &str) -> fmt::Result {
-        devices::serial::default_port().write_str(s)?;
-        console::ConsoleWrite.write_str(s)?;
-        Ok(())
+        console::mirror_to_serial(s);
+        console::ConsoleWrite.write_str(s)
     }
 }
 
@@ -1,14 +1,24 @@
+//! Each CPU has its own GDT and TSS, since the TSS holds the stacks that the CPU switches to on
+//! interrupts. Every interrupt stack is placed on frames with an unmapped guard page below it.
+
+use crate::cpu::{self, Cpu};
+use crate::paging;
+use crate::phys_memory::{frame_manager, Frame, Tag};
 use crate::x64::{self, Segment};
+use alloc::boxed::Box;
+use core::ops::Range;
 use log::trace;
 use spin::Once;
 
-static mut GDT: x64::GlobalDescriptorTable = x64::GlobalDescriptorTable::new();
-static mut TSS: x64::TaskStateSegment = x64::TaskStateSegment::new();
-
 static KERNEL_CS: Once<x64::SegmentSelector> = Once::new();
 static KERNEL_SS: Once<x64::SegmentSelector> = Once::new();
 
-pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+#[allow(clippy::declare_interior_mutable_const)]
+const UNINITIALIZED: Once<&'static PerCpuSegmentation> = Once::new();
+static SEGMENTATIONS: [Once<&'static PerCpuSegmentation>; cpu::MAX_COUNT] =
+    [UNINITIALIZED; cpu::MAX_COUNT];
+
+const INTERRUPT_STACK_SIZE: usize = 4096 * 5;
 
 pub fn cs() -> x64::SegmentSelector {
     *KERNEL_CS
@@ -22,29 +32,153 @@ pub fn ss() -> x64::SegmentSelector {
         .expect("segmentation::ss is called before segmentation::initialize")
 }
 
+/// The segmentation of the current CPU. Returns `None` if `initialize` is not called on it yet.
+pub fn current() -> Option<&'static PerCpuSegmentation> {
+    SEGMENTATIONS
+        .get(Cpu::current().index())
+        .and_then(|s| s.get())
+        .copied()
+}
+
+/// Set up the GDT and TSS of the current CPU. This must be called once on each CPU: by the BSP
+/// after the frame manager is initialized, and by each AP during its bring-up.
 pub unsafe fn initialize() {
-    // TODO: GDT needs to be created for each processor.
-    trace!("INITIALIZING segmentation");
-    TSS.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-        const STACK_SIZE: usize = 4096 * 5;
-        static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-        let stack_start = x64::VirtAddr::from_ptr(&STACK[0]);
-        let stack_end = stack_start + STACK_SIZE;
-        stack_end
-    };
-    let code_selector = GDT.add_entry(x64::Descriptor::kernel_code_segment());
-    let data_selector = GDT.add_entry(x64::Descriptor::kernel_data_segment());
-    let tss_selector = GDT.add_entry(x64::Descriptor::tss_segment(&TSS));
-    let null_ss = x64::SegmentSelector::new(0, x64::PrivilegeLevel::Ring0);
-    GDT.load();
-    x64::DS::set_reg(null_ss);
-    x64::ES::set_reg(null_ss);
-    x64::FS::set_reg(null_ss);
-    x64::GS::set_reg(null_ss);
-    x64::CS::set_reg(code_selector);
-    x64::SS::set_reg(data_selector);
-    x64::load_tss(tss_selector);
-
-    KERNEL_CS.call_once(|| code_selector);
-    KERNEL_SS.call_once(|| data_selector);
+    let cpu = Cpu::current();
+    trace!("INITIALIZING segmentation (CPU {})", cpu.index());
+    let s = PerCpuSegmentation::new();
+    assert!(
+        !SEGMENTATIONS[cpu.index()].is_completed(),
+        "Segmentation is initialized twice"
+    );
+    s.load();
+    SEGMENTATIONS[cpu.index()].call_once(|| s);
+
+    // Every GDT has the same layout, thus the selectors are shared by all CPUs
+    assert_eq!(*KERNEL_CS.call_once(|| s.code_selector), s.code_selector);
+    assert_eq!(*KERNEL_SS.call_once(|| s.data_selector), s.data_selector);
+}
+
+/// Interrupts handled on dedicated stacks, since the current stack may not be usable.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum InterruptStack {
+    DoubleFault,
+    Nmi,
+    MachineCheck,
+}
+
+impl InterruptStack {
+    pub const COUNT: usize = 3;
+    pub const ALL: [Self; Self::COUNT] = [Self::DoubleFault, Self::Nmi, Self::MachineCheck];
+
+    /// Index in the interrupt stack table, to be given to `set_stack_index` of IDT entries.
+    pub fn index(self) -> u16 {
+        self as u16
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::DoubleFault => "double-fault",
+            Self::Nmi => "nmi",
+            Self::MachineCheck => "machine-check",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct PerCpuSegmentation {
+    gdt: x64::GlobalDescriptorTable,
+    tss: x64::TaskStateSegment,
+    code_selector: x64::SegmentSelector,
+    data_selector: x64::SegmentSelector,
+    tss_selector: x64::SegmentSelector,
+    interrupt_stacks: [Range<x64::VirtAddr>; InterruptStack::COUNT],
+}
+
+impl PerCpuSegmentation {
+    fn new() -> &'static Self {
+        let interrupt_stacks = InterruptStack::ALL.map(|_| allocate_interrupt_stack());
+        let mut tss = x64::TaskStateSegment::new();
+        for (i, stack) in interrupt_stacks.iter().enumerate() {
+            tss.interrupt_stack_table[i] = stack.end;
+        }
+
+        // The TSS descriptor refers to the TSS, thus the TSS must be placed before building the GDT
+        let null_ss = x64::SegmentSelector::new(0, x64::PrivilegeLevel::Ring0);
+        let s = Box::leak(Box::new(Self {
+            gdt: x64::GlobalDescriptorTable::new(),
+            tss,
+            code_selector: null_ss,
+            data_selector: null_ss,
+            tss_selector: null_ss,
+            interrupt_stacks,
+        }));
+        s.code_selector = s.gdt.add_entry(x64::Descriptor::kernel_code_segment());
+        s.data_selector = s.gdt.add_entry(x64::Descriptor::kernel_data_segment());
+        s.tss_selector = s.gdt.add_entry(x64::Descriptor::tss_segment(&s.tss));
+        s
+    }
+
+    unsafe fn load(&'static self) {
+        let null_ss = x64::SegmentSelector::new(0, x64::PrivilegeLevel::Ring0);
+        self.gdt.load();
+        x64::DS::set_reg(null_ss);
+        x64::ES::set_reg(null_ss);
+        x64::FS::set_reg(null_ss);
+        x64::GS::set_reg(null_ss);
+        x64::CS::set_reg(self.code_selector);
+        x64::SS::set_reg(self.data_selector);
+        x64::load_tss(self.tss_selector);
+    }
+
+    /// The address range of the stack used for the interrupt.
+    pub fn interrupt_stack(&self, stack: InterruptStack) -> Range<x64::VirtAddr> {
+        self.interrupt_stacks[stack as usize].clone()
+    }
+}
+
+fn allocate_interrupt_stack() -> Range<x64::VirtAddr> {
+    let num_frames = Frame::count_for_bytes(INTERRUPT_STACK_SIZE).unwrap();
+    // The first frame is used as a guard page to catch stack overflows
+    let guard = frame_manager()
+        .allocate_tagged(1 + num_frames, Tag::TaskStack)
+        .expect("Failed to allocate an interrupt stack");
+    let guard = paging::as_virt_addr(guard.phys_addr()).unwrap();
+    unsafe { paging::set_guard_page(guard) }.expect("Failed to place a guard page");
+    let start = guard + Frame::SIZE;
+    start..start + num_frames * Frame::SIZE
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interrupts;
+    use log::info;
+
+    #[test_case]
+    fn test_interrupt_stacks() {
+        info!("TESTING segmentation::test_interrupt_stacks");
+        let s = current().unwrap();
+        let interrupt_stack_table = s.tss.interrupt_stack_table;
+        for (i, a) in InterruptStack::ALL.into_iter().enumerate() {
+            let stack = s.interrupt_stack(a);
+            assert_eq!(interrupt_stack_table[i], stack.end);
+            assert!(stack.end - stack.start >= INTERRUPT_STACK_SIZE as u64);
+            assert!(paging::is_mapped(stack.start));
+            assert!(paging::is_mapped(stack.end - 1u64));
+            assert!(!paging::is_mapped(stack.start - 1u64));
+            for b in InterruptStack::ALL.into_iter().skip(i + 1) {
+                let other = s.interrupt_stack(b);
+                assert!(stack.end <= other.start || other.end <= stack.start);
+            }
+        }
+    }
+
+    #[test_case]
+    fn test_nmi_stack() {
+        info!("TESTING segmentation::test_nmi_stack");
+        let stack = current().unwrap().interrupt_stack(InterruptStack::Nmi);
+        let sp = interrupts::send_nmi_to_self();
+        trace!("NMI handled at {:?} (stack: {:?})", sp, stack);
+        assert!(stack.contains(&sp));
+    }
 }
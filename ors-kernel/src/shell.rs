@@ -1,35 +1,103 @@
 //! A rough shell implementation for debugging.
 
-use crate::console::{input_queue, Input};
+use crate::acpi;
+use crate::allocator;
+use crate::console::{self, read_input, Input, MediaKey};
+use crate::cpu::Cpu;
+use crate::crashdump;
 use crate::devices;
+use crate::devices::serial;
 use crate::devices::virtio::block;
+use crate::editor;
 use crate::fs::fat;
-use crate::fs::volume::virtio::VirtIOBlockVolume;
-use crate::interrupts::{ticks, TIMER_FREQ};
-use crate::phys_memory::frame_manager;
+use crate::fs::mount;
+use crate::fs::volume::{CacheStats, DynVolume, Volume};
+use crate::interrupts::{self, ticks, TIMER_FREQ};
+use crate::memtest;
+use crate::phys_memory::{frame_manager, Tag};
+use crate::print::KernelWrite;
+use crate::task;
+use crate::trace;
+use crate::x64;
 use alloc::borrow::ToOwned;
-use alloc::string::String;
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::{String, ToString};
 use alloc::vec::Vec;
+use args::{ArgKind, Usage};
+use bit_field::BitField;
+use core::cell::Cell;
 use core::fmt;
+use core::ops::ControlFlow;
+use ors_common::pattern::{Glob, Lite, PatternError};
 
 static CLEAR: &str = "\x1b[H\x1b[2J";
+static CLEAR_LINE: &str = "\x1b[G";
 static INPUT_START: &str = "\x1b[G\x1b[32m$\x1b[0m ";
 static INPUT_END: &str = "\x1b[K";
 static CURSOR_START: &str = "\x1b[30;47m";
 static CURSOR_END: &str = "\x1b[0m";
 
+/// Tasks blocked without timeout for this duration are considered to be stuck.
+const STUCK_THRESHOLD: usize = 10 * TIMER_FREQ;
+
+/// Files larger than this are not loaded by `edit`.
+const MAX_EDIT_SIZE: usize = 64 * 1024;
+
+/// Number of differing lines shown by `diff -l`.
+const DIFF_SHOWN_LINES: usize = 20;
+
+const STUCK_USAGE: Usage = Usage::new("stuck [<ticks>]", &[("ticks", ArgKind::Int)]);
+const RENICE_USAGE: Usage = Usage::new("renice <task_id> <nice>", &[("task_id", ArgKind::Int)]);
+const SLEEP_USAGE: Usage = Usage::new("sleep <ms>", &[("ms", ArgKind::Int)]);
+const TRACE_DUMP_USAGE: Usage = Usage::new("trace dump [<n>]", &[("n", ArgKind::Int)]);
+const KBDRATE_USAGE: Usage = Usage::new(
+    "kbdrate [<delay> <rate>]",
+    &[("delay", ArgKind::Int), ("rate", ArgKind::Int)],
+);
+const HEXDUMP_USAGE: Usage = Usage::new("hexdump <file> [<range>]", &[("range", ArgKind::Range)]);
+const FALLOCATE_USAGE: Usage = Usage::new("fallocate <file> <size>", &[("size", ArgKind::Size)]);
+const SERIAL_USAGE: Usage = Usage::new("serial [baud <rate>]", &[("rate", ArgKind::Int)]);
+const FONT_USAGE: Usage = Usage::new(
+    "font <path> [<size>] | font default",
+    &[("size", ArgKind::Int)],
+);
+
+/// Same as `kprint!`, but writes to the output of the running command, which may be redirected.
+/// Write errors are recorded by the output itself.
+macro_rules! out {
+    ($out:expr, $( $t:tt )*) => {{
+        let _ = write!($out, $( $t )*);
+    }};
+}
+
+/// Same as `kprintln!`, but writes to the output of the running command.
+macro_rules! outln {
+    ($out:expr) => {{
+        let _ = writeln!($out);
+    }};
+    ($out:expr, $( $t:tt )*) => {{
+        let _ = writeln!($out, $( $t )*);
+    }};
+}
+
+mod args;
+mod history;
+mod jobs;
+
 pub extern "C" fn run(_: u64) -> ! {
     let mut command_buf = String::new();
     let mut cursor = 0;
-    let mut ctx = Context {
-        wd: Path::new(),
-        fs: fat::FileSystem::new(VirtIOBlockVolume::new(&block::list()[0])).unwrap(),
-    };
+    let mut ctx = Context::new();
 
     cprint!("{}", CLEAR);
     kprintln!("[ors shell]");
+    ctx.history.load();
 
     loop {
+        for job in jobs::notifications() {
+            kprintln!("{}{}{}", CLEAR_LINE, job, INPUT_END);
+        }
         kprint!("{}", INPUT_START);
         for (i, c) in command_buf.chars().enumerate() {
             if i == cursor {
@@ -43,18 +111,39 @@ pub extern "C" fn run(_: u64) -> ! {
         }
         kprint!("{}", INPUT_END);
 
-        match input_queue().dequeue() {
+        match read_input() {
             Input::Char('\n') => {
                 kprintln!("{}{}{}", INPUT_START, &command_buf, INPUT_END);
-                let t = ticks();
-                execute_command(&command_buf, &mut ctx);
-                let t = ticks() - t;
+                let command = match ctx.history.expand(&command_buf) {
+                    Ok(command) => Some(command.to_string()),
+                    Err(e) => {
+                        kprintln!("{}", e);
+                        None
+                    }
+                };
+                if let Some(command) = command {
+                    if command != command_buf {
+                        kprintln!("{}", command);
+                    }
+                    ctx.history.add(&command);
+                    match command.trim().strip_suffix('&') {
+                        Some(command) => spawn_job(command, &ctx),
+                        None => {
+                            // The command may toggle ctx.verbose
+                            let verbose = ctx.verbose;
+                            let before = ResourceSnapshot::take(verbose);
+                            execute_command(&command, &mut ctx, &mut KernelWrite);
+                            let usage = ResourceSnapshot::take(verbose).usage_since(&before);
+                            if verbose {
+                                kprintln!("{}", usage);
+                            } else {
+                                kprintln!("elapsed = {}ms", usage.elapsed_ms());
+                            }
+                        }
+                    }
+                }
                 command_buf.clear();
                 cursor = 0;
-                kprintln!(
-                    "elapsed = {}ms",
-                    (t as f64 / TIMER_FREQ as f64 * 1000.0) as u32
-                );
             }
             Input::Char('\x08' /* BS */) if 0 < cursor => {
                 cursor -= 1;
@@ -71,102 +160,443 @@ pub extern "C" fn run(_: u64) -> ! {
             Input::End => cursor = command_buf.len(),
             Input::ArrowLeft if 0 < cursor => cursor -= 1,
             Input::ArrowRight if cursor < command_buf.len() => cursor += 1,
+            Input::ArrowUp => {
+                if let Some(command) = ctx.history.prev(&command_buf) {
+                    command_buf = command.to_string();
+                    cursor = command_buf.len();
+                }
+            }
+            Input::ArrowDown => {
+                if let Some(command) = ctx.history.next() {
+                    command_buf = command.to_string();
+                    cursor = command_buf.len();
+                }
+            }
+            // Function keys are not bound in the shell
+            Input::Function(_) | Input::AltFunction(_) => {}
+            Input::MediaKey(key) => {
+                if let Some(command) = ctx.media_bindings.get(&key).cloned() {
+                    kprintln!("{}[{}] {}{}", INPUT_START, key.name(), command, INPUT_END);
+                    execute_command(&command, &mut ctx, &mut KernelWrite);
+                }
+            }
             _ => {}
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct Context {
     wd: Path,
-    fs: fat::FileSystem<VirtIOBlockVolume>, // TODO: Move to appropriate static location
+    media_bindings: BTreeMap<MediaKey, String>,
+    /// Report the resource usage of each command, toggled by `set -v` and `set +v`.
+    verbose: bool,
+    history: history::History,
+}
+
+impl Context {
+    fn new() -> Self {
+        Self {
+            wd: Path::new(),
+            media_bindings: BTreeMap::new(),
+            verbose: false,
+            history: history::History::new(),
+        }
+    }
+}
+
+/// Counters captured before and after a command. Each of them is read at once, thus no locks are
+/// held while the command is running.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+struct ResourceSnapshot {
+    ticks: usize,
+    /// Summed over the mounted file systems.
+    cache: CacheStats,
+    /// Summed over the block devices.
+    block: block::Stats,
+    heap_used: usize,
+    switches: u64,
+}
+
+impl ResourceSnapshot {
+    /// Take a snapshot. Only the tick count is captured unless `full` is true.
+    fn take(full: bool) -> Self {
+        let mut snapshot = Self {
+            ticks: ticks(),
+            ..Self::default()
+        };
+        if full {
+            for s in mount::mounts().into_iter().map(|m| m.fs.cache_stats()) {
+                snapshot.cache.hits += s.hits;
+                snapshot.cache.misses += s.misses;
+                snapshot.cache.sectors_read += s.sectors_read;
+                snapshot.cache.sectors_written += s.sectors_written;
+            }
+            for s in block::try_list().into_iter().flatten().map(|b| b.stats()) {
+                snapshot.block.reads += s.reads;
+                snapshot.block.writes += s.writes;
+                snapshot.block.bytes_read += s.bytes_read;
+                snapshot.block.bytes_written += s.bytes_written;
+            }
+            snapshot.switches = task::scheduler().switch_count();
+            // Read last, after the temporary allocations above are released
+            snapshot.heap_used = allocator::heap_info().used;
+        }
+        snapshot
+    }
+
+    /// Counters may wrap around, or even decrease when a file system is unmounted in between,
+    /// thus the differences are wrapping.
+    fn usage_since(&self, before: &Self) -> ResourceUsage {
+        ResourceUsage {
+            ticks: self.ticks.wrapping_sub(before.ticks),
+            cache: CacheStats {
+                hits: self.cache.hits.wrapping_sub(before.cache.hits),
+                misses: self.cache.misses.wrapping_sub(before.cache.misses),
+                sectors_read: self
+                    .cache
+                    .sectors_read
+                    .wrapping_sub(before.cache.sectors_read),
+                sectors_written: self
+                    .cache
+                    .sectors_written
+                    .wrapping_sub(before.cache.sectors_written),
+            },
+            block: block::Stats {
+                reads: self.block.reads.wrapping_sub(before.block.reads),
+                writes: self.block.writes.wrapping_sub(before.block.writes),
+                bytes_read: self.block.bytes_read.wrapping_sub(before.block.bytes_read),
+                bytes_written: self
+                    .block
+                    .bytes_written
+                    .wrapping_sub(before.block.bytes_written),
+            },
+            heap_delta: self.heap_used.wrapping_sub(before.heap_used) as isize,
+            switches: self.switches.wrapping_sub(before.switches),
+        }
+    }
+}
+
+/// The differences between two `ResourceSnapshot`s, printed in a single line.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+struct ResourceUsage {
+    ticks: usize,
+    cache: CacheStats,
+    block: block::Stats,
+    heap_delta: isize,
+    switches: u64,
+}
+
+impl ResourceUsage {
+    fn elapsed_ms(&self) -> usize {
+        self.ticks.saturating_mul(1000) / TIMER_FREQ
+    }
+}
+
+impl fmt::Display for ResourceUsage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "elapsed = {}ms, block = {}r/{}w ({}/{}), cache = {}hit/{}miss ({}r/{}w), heap = {}{}, switches = {}",
+            self.elapsed_ms(),
+            self.block.reads,
+            self.block.writes,
+            PrettySize(self.block.bytes_read as usize),
+            PrettySize(self.block.bytes_written as usize),
+            self.cache.hits,
+            self.cache.misses,
+            self.cache.sectors_read,
+            self.cache.sectors_written,
+            if self.heap_delta < 0 { "-" } else { "+" },
+            PrettySize(self.heap_delta.unsigned_abs()),
+            self.switches,
+        )
+    }
+}
+
+/// A redirection of the command output, written at the end of the command line.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+enum Redirect<'a> {
+    /// `> <path>`
+    Overwrite(&'a str),
+    /// `>> <path>`
+    Append(&'a str),
+}
+
+impl<'a> Redirect<'a> {
+    /// Split the trailing redirection (`> <path>`, `>> <path>`, `><path>` or `>><path>`) off the
+    /// arguments.
+    fn split<'b>(args: &'b [&'a str]) -> Result<(&'b [&'a str], Option<Self>), &'static str> {
+        let i = match args.iter().position(|arg| arg.starts_with('>')) {
+            Some(i) => i,
+            None => return Ok((args, None)),
+        };
+        let (op, path) = match args[i..] {
+            [">" | ">>"] => Err("Missing redirect target")?,
+            [op @ (">" | ">>"), path] => (op, path),
+            [op_path] => match op_path.strip_prefix(">>") {
+                Some(path) => (">>", path),
+                None => (">", &op_path[1..]),
+            },
+            _ => Err("Redirection must be at the end of the command")?,
+        };
+        let redirect = match op {
+            ">" => Self::Overwrite(path),
+            _ => Self::Append(path),
+        };
+        Ok((&args[..i], Some(redirect)))
+    }
+}
+
+/// The output of a command redirected to a file. The file is finished by `finish`, or on drop.
+struct FileOutput<'a, V: Volume> {
+    writer: fat::FileWriter<'a, V>,
+    error: Option<fat::Error>,
 }
 
-fn execute_command(command_buf: &str, ctx: &mut Context) {
+impl<'a, V: Volume> FileOutput<'a, V> {
+    fn new(writer: fat::FileWriter<'a, V>) -> Self {
+        Self {
+            writer,
+            error: None,
+        }
+    }
+
+    /// Finish the file, reporting the first write error if any.
+    fn finish(self) -> Result<(), fat::Error> {
+        match self.error {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<'a, V: Volume> fmt::Write for FileOutput<'a, V> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        // The rest of the output is discarded after an error so that the command runs to the end
+        if self.error.is_none() {
+            if let Err(e) = self.writer.write(s.as_bytes()) {
+                self.error = Some(e);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Run the command line `command` (without the trailing `&`) as a background job.
+fn spawn_job(command: &str, ctx: &Context) {
+    match command.split_whitespace().next() {
+        None => kprintln!("Missing command before &"),
+        Some("edit") => kprintln!("edit cannot run in the background"),
+        Some(_) => {
+            let job = jobs::spawn(command.trim(), ctx);
+            kprintln!("[{}] {}", job.id, job.task.as_u64());
+        }
+    }
+}
+
+fn execute_command(command_buf: &str, ctx: &mut Context, out: &mut dyn fmt::Write) {
     let command_and_args = command_buf.trim().split_whitespace().collect::<Vec<_>>();
     let (command, args) = match command_and_args.first() {
         Some(c) => (*c, &command_and_args[1..]),
         None => return,
     };
+    let (args, redirect) = match Redirect::split(args) {
+        Ok(r) => r,
+        Err(e) => {
+            outln!(out, "{}", e);
+            return;
+        }
+    };
 
+    let (path, append) = match redirect {
+        Some(Redirect::Overwrite(path)) => (ctx.wd.joined(path), false),
+        Some(Redirect::Append(path)) => (ctx.wd.joined(path), true),
+        None => return run_command(command, args, ctx, out),
+    };
+    // The target is opened before running the command, so that the command is not run in vain
+    let (m, relative_path) = match path.resolve() {
+        Some(_) if !is_writable(out, &path) => return,
+        Some(r) => r,
+        None => {
+            outln!(out, "No file system is mounted: {}", path);
+            return;
+        }
+    };
+    let mut file = match m.fs.open_or_create(&relative_path) {
+        Ok(file) => file,
+        Err(e) => {
+            outln!(out, "Failed to open {}: {}", path, e);
+            return;
+        }
+    };
+    let writer = match if append {
+        file.appender()
+    } else {
+        file.overwriter()
+    } {
+        Some(writer) => writer,
+        None => {
+            outln!(out, "This is a directory: {}", path);
+            return;
+        }
+    };
+    let mut file_out = FileOutput::new(writer);
+    run_command(command, args, ctx, &mut file_out);
+    if let Err(e) = file_out.finish() {
+        outln!(out, "Write error: {}: {}", path, e);
+    }
+    path.commit(out);
+}
+
+/// Print the last `n` records of the audit log.
+fn show_audit_records(out: &mut dyn fmt::Write, n: usize) {
+    outln!(
+        out,
+        "{:>6} {:>10} {:<10} {:>4} {:<8} DIR/NAME",
+        "SEQ",
+        "TICKS",
+        "DATE",
+        "TASK",
+        "OP"
+    );
+    for record in fat::audit::records(n) {
+        outln!(out, "{}", record);
+    }
+}
+
+fn run_command(command: &str, args: &[&str], ctx: &mut Context, out: &mut dyn fmt::Write) {
     match command {
-        "clear" => kprint!("{}", CLEAR),
-        "pwd" => kprintln!("{}", ctx.wd),
+        "clear" => out!(out, "{}", CLEAR),
+        "pwd" => outln!(out, "{}", ctx.wd),
         "cd" => match args.first() {
             Some(path) => {
                 let path = ctx.wd.joined(path);
-                match path.get_dir(&ctx.fs) {
+                match path.get_dir() {
                     Some(_) => ctx.wd = path,
-                    None => kprintln!("Not a directory: {}", path),
+                    None => outln!(out, "Not a directory: {}", path),
                 }
             }
             None => ctx.wd.parts.clear(),
         },
-        "ls" => match ctx.wd.get_dir(&ctx.fs) {
-            Some(dir) => {
-                for f in dir.files() {
+        "ls" => match (ctx.wd.get_dir(), compile_glob(args.first())) {
+            (_, Err(e)) => outln!(out, "Invalid pattern: {}", e),
+            (Some(dir), Ok(glob)) => {
+                let result = dir.for_each_entry(|f| {
+                    if !glob.as_ref().map_or(true, |g| g.is_match(&f.name())) {
+                        return ControlFlow::Continue(());
+                    }
                     if f.is_dir() {
-                        kprintln!("{}/", f.name());
+                        outln!(out, "{}/", f.name());
                     } else {
-                        kprintln!("{} ({})", f.name(), PrettySize(f.file_size()));
+                        outln!(out, "{} ({})", f.name(), PrettySize(f.file_size()));
                     }
+                    ControlFlow::Continue(())
+                });
+                if let Err(e) = result {
+                    outln!(out, "Read error: {}", e);
                 }
             }
-            None => kprintln!("Directory not found: {}", ctx.wd),
+            (None, _) => outln!(out, "Directory not found: {}", ctx.wd),
         },
+        "tree" => {
+            let path = ctx.wd.joined(args.first().copied().unwrap_or("."));
+            match path.get_dir() {
+                Some(dir) => {
+                    outln!(out, "{}", path);
+                    if let Err(e) = print_tree(out, dir, &mut String::new()) {
+                        outln!(out, "Read error: {}", e);
+                    }
+                }
+                None => outln!(out, "Directory not found: {}", path),
+            }
+        }
         "touch" => match args.first() {
             Some(path) => match ctx.wd.joined(path).dir_and_file_name() {
-                Some((path, name)) => match path.get_dir(&ctx.fs) {
+                Some((path, name)) => match path.get_dir() {
+                    Some(_) if !is_writable(out, &path) => {}
                     Some(mut dir) => match dir.create_file(&name) {
                         Ok(()) => {
-                            let _ = ctx.fs.commit();
+                            path.commit(out);
                         }
-                        Err(e) => kprintln!("Failed to create a file: {}", e),
+                        Err(e) => outln!(out, "Failed to create a file: {}", e),
                     },
-                    None => kprintln!("Directory not found: {}", path),
+                    None => outln!(out, "Directory not found: {}", path),
                 },
-                None => kprintln!("This is a root directory"),
+                None => outln!(out, "This is a root directory"),
             },
-            None => kprintln!("touch <path>"),
+            None => outln!(out, "touch <path>"),
         },
         "mkdir" => match args.first() {
             Some(path) => match ctx.wd.joined(path).dir_and_file_name() {
-                Some((path, name)) => match path.get_dir(&ctx.fs) {
+                Some((path, name)) => match path.get_dir() {
+                    Some(_) if !is_writable(out, &path) => {}
                     Some(mut dir) => match dir.create_dir(&name) {
                         Ok(()) => {
-                            let _ = ctx.fs.commit();
+                            path.commit(out);
                         }
-                        Err(e) => kprintln!("Failed to create a directory: {}", e),
+                        Err(e) => outln!(out, "Failed to create a directory: {}", e),
                     },
-                    None => kprintln!("Directory not found: {}", path),
+                    None => outln!(out, "Directory not found: {}", path),
                 },
                 None => {}
             },
-            None => kprintln!("mkdir <path>"),
+            None => outln!(out, "mkdir <path>"),
         },
         "read" => match args.first() {
             Some(path) => {
                 let path = ctx.wd.joined(path);
-                match path.get_file(&ctx.fs) {
+                match path.get_file() {
                     Some(file) => match file.reader() {
                         Some(reader) => match reader.read_to_end() {
                             Ok(buf) => match String::from_utf8(buf) {
-                                Ok(s) => kprintln!("{}", s),
-                                Err(e) => kprintln!("<binary file ({} bytes)>", e.as_bytes().len()),
+                                Ok(s) => outln!(out, "{}", s),
+                                Err(e) => {
+                                    outln!(out, "<binary file ({} bytes)>", e.as_bytes().len())
+                                }
                             },
-                            Err(e) => kprintln!("Read error: {}", e),
+                            Err(e) => outln!(out, "Read error: {}", e),
                         },
-                        None => kprintln!("This is a directory: {}", path),
+                        None => outln!(out, "This is a directory: {}", path),
+                    },
+                    None => outln!(out, "File not found: {}", path),
+                }
+            }
+            None => outln!(out, "read <file>"),
+        },
+        "hexdump" => match args {
+            [path, range @ ..] if range.len() <= 1 => {
+                let range = match range.first().map(|r| args::parse_range(r)) {
+                    Some(Ok(range)) => range,
+                    Some(Err(e)) => {
+                        outln!(out, "{}\n{}", e.named("range"), HEXDUMP_USAGE);
+                        return;
+                    }
+                    None => 0..u64::MAX,
+                };
+                let path = ctx.wd.joined(path);
+                match path.get_file() {
+                    Some(file) => match file.reader() {
+                        Some(reader) => {
+                            let result = read_range(reader, range, |chunk, offset| {
+                                out!(out, "{}", HexDump(chunk, offset as usize))
+                            });
+                            if let Err(e) = result {
+                                outln!(out, "Read error: {}", e);
+                            }
+                        }
+                        None => outln!(out, "This is a directory: {}", path),
                     },
-                    None => kprintln!("File not found: {}", path),
+                    None => outln!(out, "File not found: {}", path),
                 }
             }
-            None => kprintln!("read <file>"),
+            _ => outln!(out, "{}", HEXDUMP_USAGE),
         },
         "write" | "append" => match args.first() {
             Some(path) => {
                 let path = ctx.wd.joined(path);
-                match path.get_file(&ctx.fs) {
+                match path.get_file() {
+                    Some(_) if !is_writable(out, &path) => {}
                     Some(mut file) => match if command == "write" {
                         file.overwriter()
                     } else {
@@ -180,73 +610,444 @@ fn execute_command(command_buf: &str, ctx: &mut Context) {
                             match writer.write(s.as_bytes()) {
                                 Ok(_) => {
                                     drop(writer);
-                                    let _ = ctx.fs.commit();
+                                    path.commit(out);
                                 }
-                                Err(e) => kprintln!("Write error: {}", e),
+                                Err(e) => outln!(out, "Write error: {}", e),
                             }
                         }
-                        None => kprintln!("This is a directory: {}", path),
+                        None => outln!(out, "This is a directory: {}", path),
                     },
-                    None => kprintln!("File not found: {}", path),
+                    None => outln!(out, "File not found: {}", path),
                 }
             }
-            None => kprintln!("write|append <file> <text>"),
+            None => outln!(out, "write|append <file> <text>"),
+        },
+        "edit" => match args.first() {
+            Some(path) => edit_file(out, &ctx.wd.joined(path)),
+            None => outln!(out, "edit <file>"),
+        },
+        "stat" => match args.first() {
+            Some(path) => {
+                let path = ctx.wd.joined(path);
+                match path.get_file() {
+                    Some(file) => print_metadata(out, &file.metadata()),
+                    None => outln!(out, "File not found: {}", path),
+                }
+            }
+            None => outln!(out, "stat <path>"),
+        },
+        "fallocate" => match args {
+            [path, size] => match args::parse_size(size) {
+                Ok(size) => {
+                    let path = ctx.wd.joined(path);
+                    match path.get_file() {
+                        Some(_) if !is_writable(out, &path) => {}
+                        Some(mut file) => match file.preallocate(size as usize) {
+                            Ok(runs) => {
+                                path.commit(out);
+                                let sectors = runs.iter().map(|(_, n)| n).sum::<usize>();
+                                outln!(out, "{} sectors in {} runs", sectors, runs.len());
+                            }
+                            Err(e) => outln!(out, "Failed to preallocate {}: {}", path, e),
+                        },
+                        None => outln!(out, "File not found: {}", path),
+                    }
+                }
+                Err(e) => outln!(out, "{}\n{}", e.named("size"), FALLOCATE_USAGE),
+            },
+            _ => outln!(out, "{}", FALLOCATE_USAGE),
         },
         "rm" | "rmr" => match args.first() {
             Some(path) => {
                 let path = ctx.wd.joined(path);
-                match path.get_file(&ctx.fs) {
+                match path.get_file() {
+                    Some(_) if !is_writable(out, &path) => {}
                     Some(file) => match file.remove(command == "rmr") {
                         Ok(_) => {
-                            let _ = ctx.fs.commit();
+                            path.commit(out);
                         }
-                        Err(e) => kprintln!("Failed to remove {}: {}", path, e),
+                        Err(e) => outln!(out, "Failed to remove {}: {}", path, e),
                     },
-                    None => kprintln!("File not found: {}", path),
+                    None => outln!(out, "File not found: {}", path),
                 }
             }
-            None => kprintln!("rm|rmr <file>"),
+            None => outln!(out, "rm|rmr <file>"),
+        },
+        "compactdir" => match args.first() {
+            Some(path) => {
+                let path = ctx.wd.joined(path);
+                match path.get_dir() {
+                    Some(_) if !is_writable(out, &path) => {}
+                    Some(dir) => match dir.compact() {
+                        Ok(report) => {
+                            path.commit(out);
+                            outln!(out, "{}", report);
+                        }
+                        Err(e) => outln!(out, "Failed to compact {}: {}", path, e),
+                    },
+                    None => outln!(out, "Directory not found: {}", path),
+                }
+            }
+            None => outln!(out, "compactdir <path>"),
+        },
+        "set" => match args {
+            [] => outln!(out, "verbose: {}", if ctx.verbose { "on" } else { "off" }),
+            ["-v"] => ctx.verbose = true,
+            ["+v"] => ctx.verbose = false,
+            _ => outln!(out, "set [-v|+v]"),
+        },
+        "fixboot" => match args.first() {
+            Some(path) => {
+                let path = ctx.wd.joined(path);
+                match path.resolve() {
+                    Some(_) if !is_writable(out, &path) => {}
+                    Some((m, _)) => match m.fs.repair_boot_sector() {
+                        Ok(true) => outln!(out, "Boot sector of {} is repaired", m.mountpoint),
+                        Ok(false) => outln!(out, "Boot sector of {} is not broken", m.mountpoint),
+                        Err(e) => outln!(out, "Failed to repair boot sector: {}", e),
+                    },
+                    None => outln!(out, "Mount not found: {}", path),
+                }
+            }
+            None => outln!(out, "fixboot <path>"),
         },
         "mv" => match &args[..] {
             [src, dest] => {
                 let src = ctx.wd.joined(src);
                 let dest = ctx.wd.joined(dest);
-                match src.get_file(&ctx.fs) {
-                    Some(src) => match dest.get_dir(&ctx.fs) {
-                        Some(dest) => match src.mv(Some(dest), None) {
-                            Ok(_) => {
-                                let _ = ctx.fs.commit();
-                            }
-                            Err(e) => kprintln!("Failed to move file: {}", e),
+                match src.get_file() {
+                    Some(_) if !is_writable(out, &src) => {}
+                    Some(file) => match dest.get_dir() {
+                        Some(_) if !is_same_mount(&src, &dest) => {
+                            outln!(out, "Cannot move files across mounts: {}", dest)
+                        }
+                        Some(dir) => match file.mv(Some(dir), None) {
+                            Ok(_) => src.commit(out),
+                            Err(e) => outln!(out, "Failed to move file: {}", e),
                         },
-                        None => match dest.get_file(&ctx.fs) {
-                            Some(_) => kprintln!("File already exists: {}", dest),
+                        None => match dest.get_file() {
+                            Some(_) => outln!(out, "File already exists: {}", dest),
                             None => {
                                 let (dest_dir, file_name) = dest.dir_and_file_name().unwrap();
-                                match dest_dir.get_dir(&ctx.fs) {
-                                    Some(dest_dir) => {
-                                        match src.mv(Some(dest_dir), Some(file_name.as_str())) {
-                                            Ok(_) => {
-                                                let _ = ctx.fs.commit();
-                                            }
-                                            Err(e) => kprintln!("Failed to move file: {}", e),
-                                        }
+                                match dest_dir.get_dir() {
+                                    Some(_) if !is_same_mount(&src, &dest_dir) => {
+                                        outln!(out, "Cannot move files across mounts: {}", dest_dir)
                                     }
+                                    Some(dir) => match file.mv(Some(dir), Some(file_name.as_str()))
+                                    {
+                                        Ok(_) => src.commit(out),
+                                        Err(e) => outln!(out, "Failed to move file: {}", e),
+                                    },
                                     None => {
-                                        kprintln!("Destination directory not found: {}", dest_dir);
+                                        outln!(
+                                            out,
+                                            "Destination directory not found: {}",
+                                            dest_dir
+                                        );
+                                    }
+                                }
+                            }
+                        },
+                    },
+                    None => outln!(out, "Source file not found: {}", src),
+                }
+            }
+            _ => outln!(out, "mv <src> <dest>"),
+        },
+        "cp" => match &args[..] {
+            [src, dest] => {
+                let src = ctx.wd.joined(src);
+                let dest = ctx.wd.joined(dest);
+                match src.get_file() {
+                    Some(file) => {
+                        let (dest_dir, file_name) = match dest.get_dir() {
+                            Some(_) => (dest, file.name().to_owned()),
+                            None => dest.dir_and_file_name().unwrap(),
+                        };
+                        match dest_dir.get_dir() {
+                            Some(_) if !is_writable(out, &dest_dir) => {}
+                            Some(mut dir) => {
+                                // The progress is shown on the console even if the output
+                                // is redirected, except for background jobs
+                                let show_progress = !jobs::in_background();
+                                let percent = Cell::new(None);
+                                let result = file.copy_to_with_progress(
+                                    &mut dir,
+                                    &file_name,
+                                    |done, total| {
+                                        let p = done * 100 / total;
+                                        if show_progress && percent.replace(Some(p)) != Some(p) {
+                                            kprint!("\r{}%", p);
+                                        }
+                                    },
+                                );
+                                if percent.get().is_some() {
+                                    kprintln!();
+                                }
+                                match result {
+                                    Ok(_) => dest_dir.commit(out),
+                                    Err(e) => outln!(out, "Failed to copy file: {}", e),
+                                }
+                            }
+                            None => outln!(out, "Destination directory not found: {}", dest_dir),
+                        }
+                    }
+                    None => outln!(out, "Source file not found: {}", src),
+                }
+            }
+            _ => outln!(out, "cp <src> <dest>"),
+        },
+        "diff" => {
+            let (lines, paths) = match args {
+                ["-l", paths @ ..] => (true, paths),
+                paths => (false, paths),
+            };
+            match paths {
+                [a, b] => {
+                    let (a, b) = (ctx.wd.joined(a), ctx.wd.joined(b));
+                    match (a.get_file(), b.get_file()) {
+                        (Some(fa), Some(fb)) if lines => {
+                            match fat::compare::compare_lines(&fa, &fb, DIFF_SHOWN_LINES) {
+                                Ok(c) if c.is_identical() => outln!(out, "identical"),
+                                Ok(c) => {
+                                    for hunk in c.hunks.iter() {
+                                        outln!(out, "{}", hunk);
+                                    }
+                                    if c.omitted_hunks != 0 {
+                                        outln!(out, "... and {} more hunks", c.omitted_hunks);
+                                    }
+                                    for line in c.shown_lines.iter() {
+                                        outln!(out, "{}", line);
+                                    }
+                                    outln!(out,
+                                        "{} lines removed, {} lines added",
+                                        c.removed_lines,
+                                        c.added_lines
+                                    );
+                                    if c.window_exceeded {
+                                        outln!(out,
+                                            "Differences longer than {} lines are not resynchronized, so common lines may be reported as changed",
+                                            fat::compare::LINE_WINDOW
+                                        );
+                                    }
+                                }
+                                Err(e) => outln!(out, "Failed to compare: {}", e),
+                            }
+                        }
+                        (Some(fa), Some(fb)) => match fat::compare::compare_bytes(&fa, &fb) {
+                            Ok(c) if c.is_identical() => outln!(out, "identical"),
+                            Ok(c) => outln!(out,
+                                "differ: first difference at byte {}, {} bytes differ (sizes {} and {})",
+                                c.first_difference.unwrap(),
+                                c.differing_bytes,
+                                c.len_a,
+                                c.len_b
+                            ),
+                            Err(e) => outln!(out, "Failed to compare: {}", e),
+                        },
+                        (None, _) => outln!(out, "File not found: {}", a),
+                        (_, None) => outln!(out, "File not found: {}", b),
+                    }
+                }
+                _ => outln!(out, "diff [-l] <a> <b>"),
+            }
+        }
+        "ps" => {
+            outln!(out, "{:>4} {:>4} {:>16}  STATE", "ID", "NICE", "AFFINITY");
+            for info in task::scheduler().tasks() {
+                outln!(
+                    out,
+                    "{:>4} {:>4} {:>16x}  {}",
+                    info.id.as_u64(),
+                    info.priority.to_nice(),
+                    info.affinity,
+                    info
+                );
+            }
+        }
+        "schedstat" => match args {
+            [] => {
+                let stats = task::latency_stats();
+                for priority in task::Priority::iter() {
+                    let i = priority.index();
+                    outln!(
+                        out,
+                        "{:?} (nice {}): max runnable = {}",
+                        priority,
+                        priority.to_nice(),
+                        stats.max_runnable[i]
+                    );
+                    for (bucket, count) in stats.histograms[i].iter().enumerate() {
+                        if *count == 0 {
+                            continue;
+                        }
+                        let range = task::LatencyStats::bucket_range(bucket);
+                        if range.end == usize::MAX {
+                            outln!(
+                                out,
+                                "  >= {} ticks (>= {}us): {}",
+                                range.start,
+                                task::LatencyStats::ticks_to_us(range.start),
+                                count
+                            );
+                        } else {
+                            outln!(
+                                out,
+                                "  {}..{} ticks ({}..{}us): {}",
+                                range.start,
+                                range.end,
+                                task::LatencyStats::ticks_to_us(range.start),
+                                task::LatencyStats::ticks_to_us(range.end),
+                                count
+                            );
+                        }
+                    }
+                }
+            }
+            ["reset"] => task::scheduler().reset_latency_stats(),
+            _ => outln!(out, "schedstat [reset]"),
+        },
+        "stuck" => match args {
+            [] => print_stuck_tasks(out, STUCK_THRESHOLD),
+            [threshold] => match args::parse_int(threshold) {
+                Ok(threshold) => print_stuck_tasks(out, threshold),
+                Err(e) => outln!(out, "{}\n{}", e.named("ticks"), STUCK_USAGE),
+            },
+            _ => outln!(out, "{}", STUCK_USAGE),
+        },
+        "renice" => match &args[..] {
+            [id, nice] => match (args::parse_int::<u64>(id), nice.parse::<i8>()) {
+                (Ok(id), Ok(nice)) => {
+                    if task::scheduler().renice(task::TaskId::new(id), nice) {
+                        let priority = task::Priority::from_nice(nice);
+                        outln!(
+                            out,
+                            "{}: priority {:?} (nice {})",
+                            id,
+                            priority,
+                            priority.to_nice()
+                        );
+                    } else {
+                        outln!(out, "Task not found: {}", id);
+                    }
+                }
+                (Err(e), _) => outln!(out, "{}\n{}", e.named("task_id"), RENICE_USAGE),
+                (_, Err(_)) => outln!(out, "{}", RENICE_USAGE),
+            },
+            _ => outln!(out, "{}", RENICE_USAGE),
+        },
+        "history" => match args.first().map(|p| Lite::compile(p)).transpose() {
+            Ok(pattern) => {
+                for (i, command) in ctx.history.entries() {
+                    if pattern.as_ref().map_or(true, |p| p.is_match(command)) {
+                        outln!(out, "{:>5}  {}", i, command);
+                    }
+                }
+            }
+            Err(e) => outln!(out, "Invalid pattern: {}", e),
+        },
+        "jobs" => match args {
+            [] => {
+                for job in jobs::list() {
+                    outln!(out, "{}", job);
+                }
+            }
+            ["-o", id] => match jobs::parse_id(id) {
+                Some(id) => {
+                    if let Err(e) = jobs::print_output(out, id) {
+                        outln!(out, "{}", e);
+                    }
+                }
+                None => outln!(out, "jobs [-o %<job>]"),
+            },
+            _ => outln!(out, "jobs [-o %<job>]"),
+        },
+        "kill" => match args.first().and_then(|id| jobs::parse_id(id)) {
+            Some(id) => match jobs::kill(id) {
+                Ok(()) => outln!(out, "[{}] Interrupt requested", id),
+                Err(e) => outln!(out, "{}", e),
+            },
+            None => outln!(out, "kill %<job>"),
+        },
+        "wait" => match args.first().and_then(|id| jobs::parse_id(id)) {
+            Some(id) => match jobs::wait(id) {
+                Ok(job) => outln!(out, "{}", job),
+                Err(e) => outln!(out, "{}", e),
+            },
+            None => outln!(out, "wait %<job>"),
+        },
+        "sleep" => match args.first().map(|ms| args::parse_int::<usize>(ms)) {
+            Some(Ok(ms)) => {
+                let deadline = interrupts::deadline_after(ms.saturating_mul(TIMER_FREQ) / 1000);
+                while !deadline.is_expired() {
+                    if jobs::interrupted() {
+                        outln!(out, "Interrupted");
+                        break;
+                    }
+                    task::scheduler().sleep(1);
+                }
+            }
+            Some(Err(e)) => outln!(out, "{}\n{}", e.named("ms"), SLEEP_USAGE),
+            None => outln!(out, "{}", SLEEP_USAGE),
+        },
+        "trace" => match args {
+            [] => {
+                out!(out, "enabled:");
+                for c in trace::enabled_categories() {
+                    out!(out, " {}", c.name());
+                }
+                outln!(out);
+            }
+            ["on"] => trace::enable(trace::Category::ALL),
+            ["on", categories] => {
+                match categories
+                    .split(',')
+                    .map(|name| trace::Category::from_name(name).ok_or(name))
+                    .collect::<Result<Vec<_>, _>>()
+                {
+                    Ok(categories) => trace::enable(categories),
+                    Err(name) => outln!(out, "Unknown category: {}", name),
+                }
+            }
+            ["off"] => trace::disable_all(),
+            ["dump", rest @ ..] => match rest.first().map_or(Ok(20), |n| args::parse_int(n)) {
+                Ok(n) => {
+                    for r in trace::last_events(n) {
+                        outln!(out, "{}", r);
+                    }
+                }
+                Err(e) => outln!(out, "{}\n{}", e.named("n"), TRACE_DUMP_USAGE),
+            },
+            ["save", path] => {
+                let path = ctx.wd.joined(path);
+                let events = trace::last_events(usize::MAX);
+                match path.resolve() {
+                    Some(_) if !is_writable(out, &path) => {}
+                    Some((m, relative_path)) => match m.fs.open_or_create(&relative_path) {
+                        Ok(mut file) => match file.overwriter() {
+                            Some(mut writer) => {
+                                match events.iter().try_for_each(|r| writer.write(r.as_bytes())) {
+                                    Ok(()) => {
+                                        drop(writer);
+                                        path.commit(out);
+                                        outln!(out, "{} events saved to {}", events.len(), path);
                                     }
+                                    Err(e) => outln!(out, "Write error: {}", e),
                                 }
                             }
+                            None => outln!(out, "This is a directory: {}", path),
                         },
+                        Err(e) => outln!(out, "Failed to open {}: {}", path, e),
                     },
-                    None => kprintln!("Source file not found: {}", src),
+                    None => outln!(out, "No file system is mounted: {}", path),
                 }
             }
-            _ => kprintln!("mv <src> <dest>"),
+            _ => outln!(out, "trace [on [<category>,..]|off|dump [n]|save <file>]"),
         },
         "memstats" => {
-            kprintln!("[phys_memory]");
+            outln!(out, "[phys_memory]");
             let mut graph = [0.0; 100];
+            let mut tagged = [0; Tag::COUNT];
             let (total, available) = {
                 let fm = frame_manager();
                 let total = fm.total_frames();
@@ -254,85 +1055,903 @@ fn execute_command(command_buf: &str, ctx: &mut Context) {
                 for i in 0..100 {
                     graph[i] = fm.availability_in_range(i as f64 / 100.0, (i + 1) as f64 / 100.0);
                 }
+                for tag in Tag::ALL {
+                    tagged[tag.index()] = fm.tagged_frames(tag);
+                }
                 (total, available)
             };
             for a in graph {
-                kprint!("\x1b[48;5;{}m \x1b[0m", 232 + (23.0 * a) as usize);
+                out!(out, "\x1b[48;5;{}m \x1b[0m", 232 + (23.0 * a) as usize);
             }
-            kprintln!();
-            kprintln!(
+            outln!(out);
+            outln!(
+                out,
                 "{}/{} frames ({}/{})",
                 available,
                 total,
                 PrettySize(available * 4096),
                 PrettySize(total * 4096)
             );
+            for tag in Tag::ALL {
+                let n = tagged[tag.index()];
+                outln!(
+                    out,
+                    "{:>12}: {:>8} frames ({})",
+                    tag.name(),
+                    n,
+                    PrettySize(n * 4096)
+                );
+            }
+            outln!(out, "[heap]");
+            let heap = allocator::heap_info();
+            outln!(
+                out,
+                "reserved: {}, used: {} (overflow: {}), peak: {}, allocations: {}",
+                PrettySize(heap.reserved),
+                PrettySize(heap.used),
+                PrettySize(heap.overflow),
+                PrettySize(heap.peak),
+                heap.allocations
+            );
         }
-        "lspci" => {
-            for d in devices::pci::devices() {
-                unsafe {
-                    let ty = d.device_type();
-                    kprintln!("{:02x}:{:02x}.{:02x} = {{", d.bus, d.device, d.function);
-                    kprint!("  vendor_id = {:x}", d.vendor_id());
-                    if d.is_vendor_intel() {
-                        kprint!(" (intel)");
-                    }
-                    kprintln!();
-                    kprint!("  device_id = {:x}", d.device_id());
-                    if d.is_virtio() {
-                        kprint!(" (virtio)");
+        "memtest" => match args.first() {
+            Some(mode) => match memtest::Mode::parse(mode) {
+                Some(mode) => print_memtest_report(out, &memtest::run(mode)),
+                None => outln!(out, "Unknown mode: {} (quick or full)", mode),
+            },
+            None => match memtest::last_report() {
+                Some(report) => print_memtest_report(out, &report),
+                None => outln!(out, "memtest has not been run"),
+            },
+        },
+        "mediabind" => match args {
+            [] => {
+                for (key, command) in ctx.media_bindings.iter() {
+                    outln!(out, "{}: {}", key.name(), command);
+                }
+            }
+            [key, command @ ..] => match MediaKey::from_name(key) {
+                Some(key) if command.is_empty() => {
+                    ctx.media_bindings.remove(&key);
+                }
+                Some(key) => {
+                    ctx.media_bindings.insert(key, command.join(" "));
+                }
+                None => outln!(
+                    out,
+                    "Unknown key: {} ({})",
+                    key,
+                    MediaKey::ALL.map(|k| k.name()).join(", ")
+                ),
+            },
+        },
+        "mount" => match args {
+            [] => {
+                for m in mount::mounts() {
+                    outln!(out, "{}", m);
+                }
+            }
+            [source, mountpoint, options @ ..] if options.len() <= 1 => {
+                let mountpoint = ctx.wd.joined(mountpoint).to_string();
+                let entry = source.parse().and_then(|source| {
+                    let options = options
+                        .first()
+                        .map_or(Ok(Default::default()), |o| o.parse())?;
+                    Ok(mount::Entry::new(source, mountpoint, options))
+                });
+                match entry.and_then(|entry| mount::mount(&entry)) {
+                    Ok(m) => outln!(out, "{}", m),
+                    Err(e) => outln!(out, "Failed to mount: {}", e),
+                }
+            }
+            _ => outln!(out, "mount [<source> <mountpoint> [<options>]]"),
+        },
+        "umount" => match args {
+            [mountpoint] => {
+                let mountpoint = ctx.wd.joined(mountpoint).to_string();
+                if let Err(e) = mount::unmount(&mountpoint) {
+                    outln!(out, "Failed to unmount: {}", e);
+                }
+            }
+            _ => outln!(out, "umount <mountpoint>"),
+        },
+        "auditctl" => match args {
+            _ if !fat::audit::ENABLED => {
+                outln!(out, "auditctl: Not compiled in (the fat-audit feature)")
+            }
+            ["on", sink @ ..] if sink.len() <= 1 => {
+                let sink = sink.first().map(|p| ctx.wd.joined(p).to_string());
+                fat::audit::set_sink(sink.as_deref());
+                fat::audit::set_active(true);
+            }
+            ["off"] => {
+                fat::audit::set_active(false);
+                fat::audit::set_sink(None);
+            }
+            ["status"] => {
+                let stats = fat::audit::stats();
+                let state = if fat::audit::is_active() { "on" } else { "off" };
+                outln!(out, "auditing: {}", state);
+                outln!(
+                    out,
+                    "records: {} ({} dropped from the ring of {})",
+                    stats.recorded,
+                    stats.dropped,
+                    fat::audit::CAPACITY
+                );
+                match stats.sink {
+                    Some(path) => outln!(out, "sink: {} ({} lost)", path, stats.sink_lost),
+                    None => outln!(out, "sink: none"),
+                }
+            }
+            _ => outln!(out, "auditctl on [<file>] | off | status"),
+        },
+        "audit" => match args {
+            ["show"] => show_audit_records(out, 20),
+            ["show", n] => match n.parse() {
+                Ok(n) => show_audit_records(out, n),
+                Err(_) => outln!(out, "audit show [<n>]"),
+            },
+            _ => outln!(out, "audit show [<n>]"),
+        },
+        "df" => {
+            outln!(out, "{:>10} {:>10} {:>10}  MOUNT", "SIZE", "USED", "FREE");
+            for m in mount::mounts() {
+                let bs = m.fs.boot_sector();
+                let total = bs.cluster_count() * bs.cluster_bytes();
+                match m.fs.free_cluster_count() {
+                    Ok(free) => {
+                        let free = free * bs.cluster_bytes();
+                        outln!(
+                            out,
+                            "{:>10} {:>10} {:>10}  {}",
+                            PrettySize(total).to_string(),
+                            PrettySize(total - free).to_string(),
+                            PrettySize(free).to_string(),
+                            m.mountpoint
+                        );
                     }
-                    kprintln!();
-                    kprintln!(
-                        "  device_type = {{ class_code = {:02x}, subclass = {:02x}, interface = {:02x} }}",
-                        ty.class_code,
-                        ty.subclass,
-                        ty.prog_interface
+                    Err(e) => outln!(
+                        out,
+                        "Failed to count free clusters of {}: {}",
+                        m.mountpoint,
+                        e
+                    ),
+                }
+            }
+        }
+        "lsof" => {
+            outln!(out, "{:>7} {:>7}  MOUNT NAME", "READERS", "WRITERS");
+            for m in mount::mounts() {
+                for f in m.fs.open_files() {
+                    outln!(
+                        out,
+                        "{:>7} {:>7}  {} {}",
+                        f.readers,
+                        f.writers,
+                        m.mountpoint,
+                        f.name
                     );
-                    if d.is_virtio() {
-                        kprintln!("  subsystem_id = {}", d.subsystem_id());
+                }
+            }
+        }
+        "archive" => match args {
+            ["list", pattern @ ..] if pattern.len() <= 1 => match compile_glob(pattern.first()) {
+                Ok(glob) => {
+                    for m in mount::mounts() {
+                        let prefix = m.mountpoint.trim_end_matches('/');
+                        for path in m.fs.changed_files() {
+                            let path = format!("{}{}", prefix, path);
+                            if glob.as_ref().map_or(true, |g| g.is_match(&path)) {
+                                outln!(out, "{}", path);
+                            }
+                        }
+                    }
+                }
+                Err(e) => outln!(out, "Invalid pattern: {}", e),
+            },
+            ["clear", "--all"] => {
+                for m in mount::mounts() {
+                    if m.options.read_only {
+                        continue;
+                    }
+                    for path in m.fs.changed_files().collect::<Vec<_>>() {
+                        if let Err(e) = m.fs.open(&path).and_then(|mut f| f.clear_archive()) {
+                            outln!(out, "Failed to clear {}: {}", path, e);
+                        }
+                    }
+                    if let Err(e) = m.commit() {
+                        outln!(out, "Failed to commit {}: {}", m.mountpoint, e);
                     }
-                    if let Some(msi_x) = d.msi_x() {
-                        kprintln!("  msi-x = {{ table_size = {} }}", msi_x.table_size());
+                }
+            }
+            ["clear", path] => {
+                let path = ctx.wd.joined(path);
+                match path.get_file() {
+                    Some(_) if !is_writable(out, &path) => {}
+                    Some(mut file) => match file.clear_archive() {
+                        Ok(()) => path.commit(out),
+                        Err(e) => outln!(out, "Failed to clear {}: {}", path, e),
+                    },
+                    None => outln!(out, "File not found: {}", path),
+                }
+            }
+            _ => outln!(out, "archive list [<glob>]|clear <path>|--all"),
+        },
+        "dumpinfo" => match crashdump::read_frame_manager() {
+            Ok(fm) => {
+                let summary = fm.summary();
+                outln!(
+                    out,
+                    "total: {} frames ({})",
+                    summary.total_frames,
+                    PrettySize(summary.total_frames * 4096)
+                );
+                outln!(
+                    out,
+                    "free: {} frames ({})",
+                    summary.free_frames,
+                    PrettySize(summary.free_frames * 4096)
+                );
+                outln!(out, "fragmentation: {:.1}%", summary.fragmentation());
+                match (summary.first_free_frame, summary.last_free_frame) {
+                    (Some(first), Some(last)) => {
+                        outln!(out, "first free frame: {:?}", first.phys_addr());
+                        outln!(out, "last free frame: {:?}", last.phys_addr());
+                    }
+                    _ => outln!(out, "no free frames"),
+                }
+            }
+            Err(e) => outln!(out, "dumpinfo: {}", e),
+        },
+        "console" => match args {
+            [] => outln!(out, "{}", console::output_mode()),
+            [mode] => match console::OutputMode::from_name(mode) {
+                Some(mode) if console::set_output_mode(mode) => {}
+                Some(_) => outln!(out, "console: No screen (serial-only mode)"),
+                None => outln!(
+                    out,
+                    "Unknown mode: {} ({})",
+                    mode,
+                    console::OutputMode::ALL.map(|m| m.name()).join(", ")
+                ),
+            },
+            _ => outln!(out, "console [screen|serial|both]"),
+        },
+        "constat" => {
+            let stats = console::stats();
+            outln!(out, "serial: {}", stats.serial);
+            if console::is_serial_only() {
+                outln!(out, "constat: No screen (serial-only mode)");
+                return;
+            }
+            let stats = stats.glyph_cache;
+            outln!(
+                out,
+                "glyph cache: {}/{} entries, {} bytes",
+                stats.entries,
+                stats.capacity,
+                stats.bytes
+            );
+            match stats.hit_rate() {
+                Some(rate) => outln!(
+                    out,
+                    "hits: {}, misses: {} ({:.1}% hit)",
+                    stats.hits,
+                    stats.misses,
+                    rate * 100.0
+                ),
+                None => outln!(out, "hits: 0, misses: 0"),
+            }
+        }
+        "wqstat" => {
+            let stats = task::workqueue::stats();
+            outln!(out, "scheduled: {}", stats.scheduled);
+            outln!(out, "executed: {}", stats.executed);
+            outln!(out, "dropped: {}", stats.dropped);
+        }
+        "irqstat" => {
+            for vector in 0..=u8::MAX {
+                let count = interrupts::irq_count(vector);
+                if count != 0 {
+                    match interrupts::Irq::from_vector(vector) {
+                        Some(irq) => outln!(out, "{:#04x}: {} = {}", vector, irq, count),
+                        None => outln!(out, "{:#04x}: unknown = {}", vector, count),
+                    }
+                }
+            }
+            outln!(
+                out,
+                "timer handler: max {} cycles",
+                interrupts::timer_handler_max_cycles()
+            );
+        }
+        "vectors" => {
+            outln!(out, "VECTOR KIND    {:>10}  OWNER", "COUNT");
+            for (vector, entry) in interrupts::vectors::entries() {
+                outln!(
+                    out,
+                    "{:#04x}   {:<7} {:>10}  {}",
+                    vector,
+                    entry.kind,
+                    interrupts::irq_count(vector),
+                    entry.name
+                );
+            }
+        }
+        "iostat" => match block::try_list() {
+            Some(blocks) => {
+                for (i, b) in blocks.iter().enumerate() {
+                    let s = b.stats();
+                    let q = b.queue_stats();
+                    outln!(
+                        out,
+                        "virtio-blk{}: {}r/{}w ({}/{}), queue = {} pending/{} in flight (max {} pending)",
+                        i,
+                        s.reads,
+                        s.writes,
+                        PrettySize(s.bytes_read as usize),
+                        PrettySize(s.bytes_written as usize),
+                        q.pending,
+                        q.in_flight,
+                        q.max_pending
+                    );
+                    for priority in block::IoPriority::iter() {
+                        let w = q.waits[priority.index()];
+                        if w.count != 0 {
+                            outln!(
+                                out,
+                                "  {:?}: {} requests, wait avg = {}us, max = {}us",
+                                priority,
+                                w.count,
+                                w.total_ticks * 1_000_000 / w.count / TIMER_FREQ as u64,
+                                w.max_ticks * 1_000_000 / TIMER_FREQ as u64
+                            );
+                        }
                     }
-                    kprintln!("}}");
+                }
+            }
+            None => outln!(out, "Blocks are not initialized"),
+        },
+        "cpuinfo" => {
+            let info = x64::cpuid::info();
+            outln!(out, "vendor: {}", info.vendor());
+            outln!(out, "brand: {}", info.brand());
+            outln!(
+                out,
+                "family: {:#x}, model: {:#x}, stepping: {}",
+                info.family,
+                info.model,
+                info.stepping
+            );
+            let flags = info.features.flags();
+            out!(out, "flags:");
+            for (name, _) in flags.iter().filter(|(_, enabled)| *enabled) {
+                out!(out, " {}", name);
+            }
+            outln!(out);
+            out!(out, "missing:");
+            for (name, _) in flags.iter().filter(|(_, enabled)| !*enabled) {
+                out!(out, " {}", name);
+            }
+            outln!(out);
+            for cpu in Cpu::list() {
+                match cpu.lapic_id() {
+                    Some(id) => outln!(out, "cpu{}: APIC id = {}", cpu.index(), id),
+                    None => outln!(out, "cpu{}: APIC id = unknown", cpu.index()),
                 }
             }
         }
+        "lspci" => match args {
+            [] => {
+                for d in devices::pci::devices() {
+                    print_pci_device(out, *d, false);
+                }
+            }
+            ["-v"] => {
+                for d in devices::pci::devices() {
+                    print_pci_device(out, *d, true);
+                }
+            }
+            ["-v", selector] => {
+                if let Some(d) = find_pci_device(out, selector) {
+                    print_pci_device(out, d, true);
+                }
+            }
+            ["-x", selector] => {
+                if let Some(d) = find_pci_device(out, selector) {
+                    let mut buf = [0; 256];
+                    unsafe { d.read_config(0, &mut buf) };
+                    out!(out, "{}", HexDump(&buf, 0));
+                }
+            }
+            _ => outln!(out, "lspci [-v [<bus:dev.fn>]|-x <bus:dev.fn>]"),
+        },
         "color" => {
-            fn p(n: i32) {
-                kprint!("\x1b[48;5;{}m{:>4}\x1b[0m", n, n);
+            fn p(out: &mut dyn fmt::Write, n: i32) {
+                out!(out, "\x1b[48;5;{}m{:>4}\x1b[0m", n, n);
             }
 
             for i in 0..16 {
-                p(i);
+                p(out, i);
                 if i % 8 == 7 {
-                    kprintln!();
+                    outln!(out);
                 }
             }
-            kprintln!();
+            outln!(out);
 
             for i in 0..2 {
                 for j in 0..6 {
                     for k in 0..3 {
                         for l in 0..6 {
-                            p(16 + l + 36 * k + 6 * j + 108 * i);
+                            p(out, 16 + l + 36 * k + 6 * j + 108 * i);
                         }
-                        kprint!(" ");
+                        out!(out, " ");
                     }
-                    kprintln!();
+                    outln!(out);
                 }
-                kprintln!();
+                outln!(out);
             }
 
             for i in 232..256 {
-                p(i);
+                p(out, i);
             }
-            kprintln!();
-            kprintln!();
+            outln!(out);
+            outln!(out);
+        }
+        "theme" => match args {
+            [] => {
+                outln!(out, "current: {}", console::active_theme());
+                for t in console::Theme::NAMED {
+                    outln!(out, "{}", t);
+                }
+            }
+            ["custom", colors @ ..] => {
+                match colors
+                    .iter()
+                    .map(|c| console::parse_color(c))
+                    .collect::<Option<Vec<_>>>()
+                    .and_then(|colors| colors.try_into().ok())
+                {
+                    Some(colors) => console::set_theme(console::Theme::Custom(
+                        console::CustomTheme::new(colors),
+                    )),
+                    None => outln!(out, "theme custom <#RRGGBB x16>"),
+                }
+            }
+            [name] => match console::Theme::from_name(name) {
+                Some(t) => console::set_theme(t),
+                None => outln!(out, "Unknown theme: {}", name),
+            },
+            _ => outln!(out, "theme [<name>|custom <#RRGGBB x16>]"),
+        },
+        "kbdrate" => match args {
+            [] => outln!(out, "{}", console::typematic()),
+            [delay, rate] => match (args::parse_int(delay), args::parse_int(rate)) {
+                (Ok(delay), Ok(rate)) => match console::Typematic::new(delay, rate) {
+                    Some(t) => {
+                        console::set_typematic(t);
+                        outln!(out, "{}", t);
+                    }
+                    None => outln!(
+                        out,
+                        "delay must be 250, 500, 750 or 1000; rate must be 2-30"
+                    ),
+                },
+                (Err(e), _) => outln!(out, "{}\n{}", e.named("delay"), KBDRATE_USAGE),
+                (_, Err(e)) => outln!(out, "{}\n{}", e.named("rate"), KBDRATE_USAGE),
+            },
+            _ => outln!(out, "{}", KBDRATE_USAGE),
+        },
+        "serial" => match args {
+            [] => outln!(
+                out,
+                "baud rate: {}, {}",
+                serial::baud_rate(),
+                serial::stats()
+            ),
+            ["baud", rate] => match args::parse_int(rate) {
+                Ok(rate) => match serial::set_baud_rate(rate) {
+                    Ok(()) => outln!(out, "baud rate: {}", rate),
+                    Err(e) => outln!(out, "serial: {}", e),
+                },
+                Err(e) => outln!(out, "{}\n{}", e.named("rate"), SERIAL_USAGE),
+            },
+            _ => outln!(out, "{}", SERIAL_USAGE),
+        },
+        "font" => match args {
+            ["default"] => console::reset_font(),
+            [path] => load_font(out, &ctx.wd.joined(path), None),
+            [path, size] => match args::parse_int(size) {
+                Ok(size) => load_font(out, &ctx.wd.joined(path), Some(size)),
+                Err(e) => outln!(out, "{}\n{}", e.named("size"), FONT_USAGE),
+            },
+            _ => outln!(out, "{}", FONT_USAGE),
+        },
+        "powerbtn" => {
+            let event = match args {
+                [] => Some(acpi::FixedEvent::PowerButton),
+                ["sleep"] => Some(acpi::FixedEvent::SleepButton),
+                _ => None,
+            };
+            match event {
+                Some(event) if acpi::simulate_fixed_event(event) => {}
+                Some(_) => outln!(out, "ACPI fixed events are unavailable"),
+                None => outln!(out, "powerbtn [sleep]"),
+            }
+        }
+        "shutdown" => {
+            mount::shutdown();
+            devices::qemu::exit(devices::qemu::ExitCode::Success);
+        }
+        cmd => outln!(out, "Unsupported command: {}", cmd),
+    }
+}
+
+/// Read `range` of the file in fixed-size chunks, calling `f` with each chunk and its offset.
+/// Every chunk but the last is full, so that the lines of a hex dump stay aligned.
+fn read_range<V: Volume>(
+    mut reader: fat::FileReader<V>,
+    range: core::ops::Range<u64>,
+    mut f: impl FnMut(&[u8], u64),
+) -> Result<(), fat::Error> {
+    let mut chunk = [0; 512];
+    let mut pos = 0;
+    while pos < range.start {
+        let len = (range.start - pos).min(chunk.len() as u64) as usize;
+        match reader.read(&mut chunk[..len])? {
+            0 => return Ok(()),
+            n => pos += n as u64,
+        }
+    }
+    while pos < range.end {
+        let len = (range.end - pos).min(chunk.len() as u64) as usize;
+        let mut filled = 0;
+        while filled < len {
+            match reader.read(&mut chunk[filled..len])? {
+                0 => break,
+                n => filled += n,
+            }
+        }
+        if filled != 0 {
+            f(&chunk[..filled], pos);
+            pos += filled as u64;
+        }
+        if filled < len {
+            break; // end of the file
+        }
+    }
+    Ok(())
+}
+
+/// Directories deeper than this are not expanded by `tree`, in case of a corrupted directory
+/// containing its ancestor.
+const TREE_MAX_DEPTH: usize = 32;
+
+/// Print the files under `dir` recursively with the branches drawn by box drawing characters.
+/// `indent` is the prefix of the lines, which has a column for each ancestor directory.
+fn print_tree(
+    out: &mut dyn fmt::Write,
+    dir: fat::Dir<'static, DynVolume>,
+    indent: &mut String,
+) -> Result<(), fat::Error> {
+    // The last entry is drawn differently, so the entries are collected first
+    let mut entries = Vec::new();
+    dir.for_each_entry(|f| {
+        entries.push((f.name().to_owned(), f.is_dir(), f.as_dir(), f.file_size()));
+        ControlFlow::Continue(())
+    })?;
+    let count = entries.len();
+    for (i, (name, is_dir, subdir, size)) in entries.into_iter().enumerate() {
+        let is_last = i + 1 == count;
+        let branch = if is_last { "└── " } else { "├── " };
+        if !is_dir {
+            outln!(out, "{}{}{} ({})", indent, branch, name, PrettySize(size));
+            continue;
+        }
+        outln!(out, "{}{}{}/", indent, branch, name);
+        let depth = indent.chars().count() / 4;
+        if let Some(subdir) = subdir.filter(|_| depth < TREE_MAX_DEPTH) {
+            let len = indent.len();
+            indent.push_str(if is_last { "    " } else { "│   " });
+            print_tree(out, subdir, indent)?;
+            indent.truncate(len);
         }
-        "shutdown" => devices::qemu::exit(devices::qemu::ExitCode::Success),
-        cmd => kprintln!("Unsupported command: {}", cmd),
+    }
+    Ok(())
+}
+
+fn print_metadata(out: &mut dyn fmt::Write, m: &fat::Metadata) {
+    const MAX_RUNS: usize = 8;
+
+    outln!(out, "name: {}", m.name);
+    outln!(
+        out,
+        "sfn: {}{}",
+        m.sfn_name,
+        if m.is_irreversible {
+            " (irreversible)"
+        } else {
+            ""
+        }
+    );
+    outln!(out, "type: {}", if m.is_dir { "directory" } else { "file" });
+    outln!(out, "size: {} ({} bytes)", PrettySize(m.size), m.size);
+    out!(out, "attributes:");
+    for (flag, name) in [
+        (m.is_read_only, "read-only"),
+        (m.is_hidden, "hidden"),
+        (m.is_system, "system"),
+        (m.archive, "archive"),
+    ] {
+        if flag {
+            out!(out, " {}", name);
+        }
+    }
+    outln!(out);
+    if let Some(date) = m.last_access_date {
+        outln!(out, "last access: {}", date);
+    }
+    match m.first_cluster {
+        Some(c) => outln!(out, "first cluster: {}", c),
+        None => outln!(out, "first cluster: none"),
+    }
+    outln!(out, "clusters: {} ({} runs)", m.cluster_count, m.runs.len());
+    for (start, len) in m.runs.iter().take(MAX_RUNS) {
+        outln!(out, "  {}..={}", start, start + len - 1);
+    }
+    if MAX_RUNS < m.runs.len() {
+        outln!(out, "  ... ({} more runs)", m.runs.len() - MAX_RUNS);
+    }
+    if let Some(ref e) = m.chain_error {
+        outln!(out, "chain: {}", e);
+    }
+}
+
+/// Compile an optional glob argument. File names are matched case-insensitively as FAT does.
+fn compile_glob(pattern: Option<&&str>) -> Result<Option<Glob>, PatternError> {
+    pattern.map(|p| Glob::compile_ignore_case(p)).transpose()
+}
+
+fn find_pci_device(out: &mut dyn fmt::Write, selector: &str) -> Option<devices::pci::Device> {
+    match devices::pci::Device::parse_selector(selector) {
+        // The device enumerated at boot is returned, since it holds the probed BARs
+        Some(d) if devices::pci::devices().contains(&d) => {
+            devices::pci::devices().iter().copied().find(|e| *e == d)
+        }
+        Some(d) => {
+            outln!(out, "Device not found: {}", d);
+            None
+        }
+        None => {
+            outln!(out, "Invalid device (expected <bus:dev.fn>): {}", selector);
+            None
+        }
+    }
+}
+
+fn print_pci_device(out: &mut dyn fmt::Write, d: devices::pci::Device, verbose: bool) {
+    use devices::pci::{Bar, BarKind, COMMAND_BITS, STATUS_BITS};
+
+    unsafe {
+        let ty = d.device_type();
+        outln!(
+            out,
+            "{:02x}:{:02x}.{:02x} {} = {{",
+            d.bus,
+            d.device,
+            d.function,
+            d.description()
+        );
+        out!(out, "  vendor_id = {:x}", d.vendor_id());
+        if let Some(name) = devices::pci::names::vendor_name(d.vendor_id()) {
+            out!(out, " ({})", name);
+        }
+        outln!(out);
+        out!(out, "  device_id = {:x}", d.device_id());
+        if d.is_virtio() {
+            out!(out, " (virtio)");
+        }
+        outln!(out);
+        outln!(
+            out,
+            "  device_type = {{ class_code = {:02x}, subclass = {:02x}, interface = {:02x} }}",
+            ty.class_code,
+            ty.subclass,
+            ty.prog_interface
+        );
+        if d.is_virtio() {
+            outln!(out, "  subsystem_id = {}", d.subsystem_id());
+        }
+        if !verbose {
+            if let Some(msi_x) = d.msi_x() {
+                outln!(out, "  msi-x = {{ table_size = {} }}", msi_x.table_size());
+            }
+            outln!(out, "}}");
+            return;
+        }
+
+        for (name, value, bits) in [
+            ("command", d.command(), &COMMAND_BITS),
+            ("status", d.status(), &STATUS_BITS),
+        ] {
+            out!(out, "  {} = {:04x} {{", name, value);
+            for (bit, name) in bits.iter() {
+                out!(
+                    out,
+                    " {}{}",
+                    name,
+                    if value.get_bit(*bit) { '+' } else { '-' }
+                );
+            }
+            outln!(out, " }}");
+        }
+        let pin = match d.interrupt_pin() {
+            0 => String::from("none"),
+            pin @ 1..=4 => format!("INT{}", (b'A' + pin - 1) as char),
+            pin => format!("invalid ({})", pin),
+        };
+        outln!(
+            out,
+            "  interrupt = {{ line = {}, pin = {} }}",
+            d.interrupt_line(),
+            pin
+        );
+        for i in 0..d.num_bars() {
+            if let Some(bar) = d.bar_info(i) {
+                let kind = match bar.kind {
+                    BarKind::Io => "io",
+                    BarKind::Memory32 => "mem32",
+                    BarKind::Memory64 => "mem64",
+                };
+                let address = match bar.bar {
+                    Bar::MemoryAddress(addr) => addr,
+                    Bar::IoPort(port) => port as u64,
+                };
+                outln!(
+                    out,
+                    "  bar[{}] = {{ {}{}, address = {:x}, size = {} }}",
+                    i,
+                    kind,
+                    if bar.prefetchable {
+                        " prefetchable"
+                    } else {
+                        ""
+                    },
+                    address,
+                    PrettySize(bar.size as usize)
+                );
+            }
+        }
+        for c in d.capabilities() {
+            out!(
+                out,
+                "  capability[{:02x}] = {{ id = {:02x} ({})",
+                c.pointer(),
+                c.id(),
+                c.name()
+            );
+            if let Some(msi_x) = c.msi_x() {
+                out!(
+                    out,
+                    ", enabled = {}, table_size = {}, table = bar[{}]+{:x}, pba = bar[{}]+{:x}",
+                    msi_x.is_enabled(),
+                    msi_x.table_size(),
+                    msi_x.table_bir(),
+                    msi_x.table_offset(),
+                    msi_x.pba_bir(),
+                    msi_x.pba_offset()
+                );
+            }
+            outln!(out, " }}");
+        }
+        outln!(out, "}}");
+    }
+}
+
+fn print_memtest_report(out: &mut dyn fmt::Write, report: &memtest::Report) {
+    outln!(
+        out,
+        "{:?}: {} frames tested, {} bad frames",
+        report.mode,
+        report.tested_frames,
+        report.bad_frames.len()
+    );
+    for frame in report.bad_frames.iter() {
+        outln!(out, "  {:?}", frame.phys_addr());
+    }
+}
+
+fn load_font(out: &mut dyn fmt::Write, path: &Path, size: Option<u32>) {
+    if let Err(e) = console::load_font(&path.to_string(), size) {
+        outln!(out, "Failed to load the font: {}", e);
+    }
+}
+
+fn print_stuck_tasks(out: &mut dyn fmt::Write, threshold: usize) {
+    let tasks = task::scheduler().tasks();
+    let stuck = tasks
+        .iter()
+        .filter(|info| info.is_stuck(threshold))
+        .collect::<Vec<_>>();
+    if stuck.is_empty() {
+        outln!(out, "No tasks blocked for {} ticks or more", threshold);
+    }
+    for info in stuck {
+        outln!(out, "{}: {}", info.id.as_u64(), info);
+    }
+}
+
+/// Edit the file with the editor. The file is created on save if it does not exist.
+fn edit_file(out: &mut dyn fmt::Write, path: &Path) {
+    let (m, relative_path) = match path.resolve() {
+        Some(_) if !is_writable(out, path) => return,
+        Some(r) => r,
+        None => {
+            outln!(out, "No file system is mounted: {}", path);
+            return;
+        }
+    };
+    let text = match m.fs.open(&relative_path) {
+        Ok(file) if file.is_dir() => {
+            outln!(out, "This is a directory: {}", path);
+            return;
+        }
+        Ok(file) if file.file_size() > MAX_EDIT_SIZE => {
+            outln!(
+                out,
+                "Too large to edit: {} ({} > {})",
+                path,
+                PrettySize(file.file_size()),
+                PrettySize(MAX_EDIT_SIZE)
+            );
+            return;
+        }
+        Ok(file) => match file.reader().unwrap().read_to_end() {
+            Ok(buf) => match String::from_utf8(buf) {
+                Ok(s) => s,
+                Err(_) => {
+                    outln!(out, "Not a UTF-8 text file: {}", path);
+                    return;
+                }
+            },
+            Err(e) => {
+                outln!(out, "Read error: {}", e);
+                return;
+            }
+        },
+        Err(fat::Error::NotFound(_)) => String::new(),
+        Err(e) => {
+            outln!(out, "Failed to open {}: {}", path, e);
+            return;
+        }
+    };
+    let name = path.to_string();
+    editor::edit(&name, &text, |text| {
+        let mut file = m.fs.open_or_create(&relative_path)?;
+        let mut writer = file
+            .overwriter()
+            .ok_or_else(|| fat::Error::IsADirectory(relative_path.clone()))?;
+        writer.write(text.as_bytes())?;
+        drop(writer);
+        m.commit()
+    });
+}
+
+fn is_writable(out: &mut dyn fmt::Write, path: &Path) -> bool {
+    match path.resolve() {
+        Some((m, _)) if m.options.read_only => {
+            outln!(out, "Read-only file system: {}", m.mountpoint);
+            false
+        }
+        _ => true,
+    }
+}
+
+fn is_same_mount(a: &Path, b: &Path) -> bool {
+    match (a.resolve(), b.resolve()) {
+        (Some((a, _)), Some((b, _))) => core::ptr::eq(a, b),
+        _ => false,
     }
 }
 
@@ -369,30 +1988,27 @@ impl Path {
         Some((self, file_name))
     }
 
-    fn get_dir<'a>(
-        &self,
-        fs: &'a fat::FileSystem<VirtIOBlockVolume>,
-    ) -> Option<fat::Dir<'a, VirtIOBlockVolume>> {
-        if self.parts.is_empty() {
-            Some(fs.root_dir())
-        } else {
-            self.get_file(fs)?.as_dir()
-        }
+    /// Get the mount containing this path and the path relative to its mountpoint.
+    fn resolve(&self) -> Option<(&'static mount::Mount, String)> {
+        mount::resolve(&self.to_string())
     }
 
-    fn get_file<'a>(
-        &self,
-        fs: &'a fat::FileSystem<VirtIOBlockVolume>,
-    ) -> Option<fat::File<'a, VirtIOBlockVolume>> {
-        if self.parts.is_empty() {
-            None
-        } else {
-            let mut dir = fs.root_dir();
-            let last_index = self.parts.len() - 1;
-            for p in self.parts[0..last_index].iter() {
-                dir = dir.files().find(|f| f.name() == p)?.as_dir()?;
+    fn get_dir(&self) -> Option<fat::Dir<'static, DynVolume>> {
+        let (m, path) = self.resolve()?;
+        m.fs.open_dir(&path).ok()
+    }
+
+    fn get_file(&self) -> Option<fat::File<'static, DynVolume>> {
+        let (m, path) = self.resolve()?;
+        m.fs.open(&path).ok()
+    }
+
+    /// Commit the changes of the file system containing this path.
+    fn commit(&self, out: &mut dyn fmt::Write) {
+        if let Some((m, _)) = self.resolve() {
+            if let Err(e) = m.commit() {
+                outln!(out, "Failed to commit {}: {}", m.mountpoint, e);
             }
-            dir.files().find(|f| f.name() == &self.parts[last_index])
         }
     }
 }
@@ -410,6 +2026,38 @@ impl fmt::Display for Path {
     }
 }
 
+/// Lines of 16 bytes with their offsets, in hexadecimal and in ASCII.
+/// The offsets start from the second field.
+struct HexDump<'a>(&'a [u8], usize);
+
+impl<'a> fmt::Display for HexDump<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, line) in self.0.chunks(16).enumerate() {
+            write!(f, "{:08x} ", self.1 + i * 16)?;
+            for j in 0..16 {
+                if j == 8 {
+                    write!(f, " ")?;
+                }
+                match line.get(j) {
+                    Some(b) => write!(f, " {:02x}", b)?,
+                    None => write!(f, "   ")?,
+                }
+            }
+            write!(f, "  |")?;
+            for b in line {
+                let c = if b.is_ascii_graphic() || *b == b' ' {
+                    *b as char
+                } else {
+                    '.'
+                };
+                write!(f, "{}", c)?;
+            }
+            writeln!(f, "|")?;
+        }
+        Ok(())
+    }
+}
+
 struct PrettySize(usize);
 
 impl fmt::Display for PrettySize {
@@ -425,3 +2073,179 @@ impl fmt::Display for PrettySize {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::format;
+    use log::info;
+
+    fn snapshot(base: u64) -> ResourceSnapshot {
+        ResourceSnapshot {
+            ticks: base as usize,
+            cache: CacheStats {
+                hits: base + 1,
+                misses: base + 2,
+                sectors_read: base + 3,
+                sectors_written: base + 4,
+            },
+            block: block::Stats {
+                reads: base + 5,
+                writes: base + 6,
+                bytes_read: base + 7,
+                bytes_written: base + 8,
+            },
+            heap_used: base as usize + 9,
+            switches: base + 10,
+        }
+    }
+
+    #[test_case]
+    fn test_resource_usage() {
+        info!("TESTING shell::test_resource_usage");
+        let mut after = snapshot(10000 + TIMER_FREQ as u64);
+        after.cache.misses += 5;
+        after.block.bytes_read += 4096;
+        after.heap_used -= 2048 + TIMER_FREQ;
+        let usage = after.usage_since(&snapshot(10000));
+        assert_eq!(usage.ticks, TIMER_FREQ);
+        assert_eq!(usage.elapsed_ms(), 1000);
+        assert_eq!(usage.cache.hits, TIMER_FREQ as u64);
+        assert_eq!(usage.cache.misses, TIMER_FREQ as u64 + 5);
+        assert_eq!(usage.block.bytes_read, TIMER_FREQ as u64 + 4096);
+        assert_eq!(usage.heap_delta, -2048);
+        assert_eq!(usage.switches, TIMER_FREQ as u64);
+        let line = format!("{}", usage);
+        assert!(line.starts_with("elapsed = 1000ms, "));
+        assert!(line.contains("heap = -2.00KiB"));
+        assert!(!line.contains('\n'));
+
+        // Counters wrapping around
+        let before = snapshot(u64::MAX - 20);
+        let usage = snapshot(10).usage_since(&before);
+        assert_eq!(usage.ticks, 31);
+        assert_eq!(usage.cache.sectors_written, 31);
+        assert_eq!(usage.heap_delta, 31);
+        assert_eq!(usage.switches, 31);
+
+        // Only ticks are captured unless full
+        let partial = ResourceSnapshot::take(false);
+        assert_eq!(partial.cache, CacheStats::default());
+        assert_eq!(partial.switches, 0);
+        assert_eq!(
+            ResourceSnapshot::take(false)
+                .usage_since(&partial)
+                .heap_delta,
+            0
+        );
+    }
+
+    #[test_case]
+    fn test_redirect_split() {
+        info!("TESTING shell::test_redirect_split");
+        let split = |line: &'static str| {
+            let args = line.split_whitespace().collect::<Vec<_>>();
+            Redirect::split(&args).map(|(args, r)| (args.join(" "), r))
+        };
+        assert_eq!(split("a b"), Ok((String::from("a b"), None)));
+        assert_eq!(
+            split("a > /x"),
+            Ok((String::from("a"), Some(Redirect::Overwrite("/x"))))
+        );
+        assert_eq!(
+            split(">> x"),
+            Ok((String::new(), Some(Redirect::Append("x"))))
+        );
+        assert_eq!(
+            split("a b >x"),
+            Ok((String::from("a b"), Some(Redirect::Overwrite("x"))))
+        );
+        assert_eq!(
+            split("a >>x"),
+            Ok((String::from("a"), Some(Redirect::Append("x"))))
+        );
+        assert!(split("a >").is_err());
+        assert!(split("a > x y").is_err());
+        assert!(split("a >x y").is_err());
+    }
+
+    #[test_case]
+    fn test_read_range() {
+        info!("TESTING shell::test_read_range");
+        let fs = fat::FileSystem::new(fat::tests::format_volume(512, 1)).unwrap();
+        let data = (0..1300).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let mut file = fs.open_or_create("data").unwrap();
+        file.overwriter().unwrap().write(&data).unwrap();
+        let chunks = |range: core::ops::Range<u64>| {
+            let file = fs.open("data").unwrap();
+            let reader = file.reader().unwrap();
+            let mut chunks = Vec::new();
+            read_range(reader, range, |chunk, offset| {
+                chunks.push((offset, chunk.to_vec()))
+            })
+            .unwrap();
+            chunks
+        };
+
+        // Streamed in full chunks but the last one
+        let read = chunks(100..1200);
+        let offsets = read.iter().map(|(o, c)| (*o, c.len())).collect::<Vec<_>>();
+        assert_eq!(offsets, [(100, 512), (612, 512), (1124, 76)]);
+        let bytes = read.iter().flat_map(|(_, c)| c.iter().copied());
+        assert!(bytes.eq(data[100..1200].iter().copied()));
+
+        let read = chunks(1000..u64::MAX);
+        assert_eq!(read.len(), 1);
+        assert_eq!(read[0], (1000, data[1000..].to_vec()));
+        assert!(chunks(1300..u64::MAX).is_empty());
+        assert!(chunks(2000..3000).is_empty());
+    }
+
+    #[test_case]
+    fn test_file_output() {
+        info!("TESTING shell::test_file_output");
+        let fs = fat::FileSystem::new(fat::tests::format_volume(512, 1)).unwrap();
+        let mut ctx = Context::new();
+        let read = || {
+            fs.open("out")
+                .unwrap()
+                .reader()
+                .unwrap()
+                .read_to_end()
+                .unwrap()
+        };
+        let mut run_into_file = |command: &str, args: &[&str], append: bool| {
+            let mut file = fs.open_or_create("out").unwrap();
+            let writer = if append {
+                file.appender()
+            } else {
+                file.overwriter()
+            };
+            let mut out = FileOutput::new(writer.unwrap());
+            run_command(command, args, &mut ctx, &mut out);
+            out.finish().unwrap();
+        };
+
+        // The file receives exactly what the console would receive
+        for (command, args) in [
+            ("pwd", &[][..]),
+            ("set", &[]),
+            ("color", &[]),
+            ("mediabind", &["nokey", "pwd"]),
+            ("lspci", &["-v", "zz"]),
+        ] {
+            let mut console = String::new();
+            run_command(command, args, &mut Context::new(), &mut console);
+            assert!(!console.is_empty());
+            run_into_file(command, args, false);
+            assert_eq!(read(), console.as_bytes(), "{}", command);
+        }
+
+        // A command without output truncates the file, and >> appends to it
+        run_into_file("set", &["+v"], false);
+        assert_eq!(read(), b"");
+        run_into_file("pwd", &[], true);
+        run_into_file("pwd", &[], true);
+        assert_eq!(read(), b"/\n/\n");
+    }
+}
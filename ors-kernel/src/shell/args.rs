@@ -0,0 +1,280 @@
+//! Parsers of command arguments shared by the shell commands.
+
+use core::convert::TryFrom;
+use core::fmt;
+use core::ops::Range;
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub(super) enum ArgKind {
+    /// An integer in decimal or 0x-prefixed hex.
+    Int,
+    /// An integer with an optional binary unit suffix: k, m, g, or ki, mi, gi followed by B.
+    Size,
+    /// `<start>..<end>` or `<start>+<len>`, where both sides are sizes.
+    Range,
+    /// `<key>=<value>`.
+    Option,
+}
+
+impl ArgKind {
+    /// The accepted forms, shown in usage messages.
+    pub(super) fn forms(self) -> &'static str {
+        match self {
+            Self::Int => "123, 0x7b",
+            Self::Size => "512, 0x200, 4k, 16M, 1GiB",
+            Self::Range => "10..20, 10+5",
+            Self::Option => "key=value",
+        }
+    }
+}
+
+impl fmt::Display for ArgKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Int => write!(f, "integer"),
+            Self::Size => write!(f, "size"),
+            Self::Range => write!(f, "range"),
+            Self::Option => write!(f, "option"),
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub(super) struct ArgError<'a> {
+    pub kind: ArgKind,
+    pub value: &'a str,
+    /// The name of the argument, if known.
+    pub name: Option<&'a str>,
+}
+
+impl<'a> ArgError<'a> {
+    fn new(kind: ArgKind, value: &'a str) -> Self {
+        Self {
+            kind,
+            value,
+            name: None,
+        }
+    }
+
+    /// Attach the name of the argument, which is shown in the message.
+    pub(super) fn named(self, name: &'a str) -> Self {
+        Self {
+            name: Some(name),
+            ..self
+        }
+    }
+}
+
+impl<'a> fmt::Display for ArgError<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid {} '{}'", self.kind, self.value)?;
+        if let Some(name) = self.name {
+            write!(f, " for argument '{}'", name)?;
+        }
+        Ok(())
+    }
+}
+
+/// The usage of a command, with the descriptors of its typed arguments.
+/// This is displayed as the syntax followed by the accepted forms of each argument.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub(super) struct Usage {
+    pub syntax: &'static str,
+    pub args: &'static [(&'static str, ArgKind)],
+}
+
+impl Usage {
+    pub(super) const fn new(
+        syntax: &'static str,
+        args: &'static [(&'static str, ArgKind)],
+    ) -> Self {
+        Self { syntax, args }
+    }
+}
+
+impl fmt::Display for Usage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.syntax)?;
+        for (name, kind) in self.args {
+            write!(f, "\n  <{}>: {} ({})", name, kind, kind.forms())?;
+        }
+        Ok(())
+    }
+}
+
+/// Parse an integer in decimal or 0x-prefixed hex. Values out of the range of `T` are rejected.
+pub(super) fn parse_int<T: TryFrom<u64>>(s: &str) -> Result<T, ArgError> {
+    parse_u64(s)
+        .and_then(|n| T::try_from(n).ok())
+        .ok_or(ArgError::new(ArgKind::Int, s))
+}
+
+/// Parse a size in bytes, an integer optionally followed by a binary unit suffix.
+/// Suffixes are case-insensitive: `4k`, `4K`, `4KiB` and `4kib` are all 4096.
+pub(super) fn parse_size(s: &str) -> Result<u64, ArgError> {
+    let err = ArgError::new(ArgKind::Size, s);
+    let (digits, unit) = split_unit(s).ok_or(err)?;
+    parse_u64(digits)
+        .and_then(|n| n.checked_mul(unit))
+        .ok_or(err)
+}
+
+/// Parse `<start>..<end>` or `<start>+<len>` into `start..end`. Both sides are sizes.
+pub(super) fn parse_range(s: &str) -> Result<Range<u64>, ArgError> {
+    let err = ArgError::new(ArgKind::Range, s);
+    let range = if let Some((start, end)) = s.split_once("..") {
+        parse_size(start).map_err(|_| err)?..parse_size(end).map_err(|_| err)?
+    } else if let Some((start, len)) = s.split_once('+') {
+        let start = parse_size(start).map_err(|_| err)?;
+        let len = parse_size(len).map_err(|_| err)?;
+        start..start.checked_add(len).ok_or(err)?
+    } else {
+        Err(err)?
+    };
+    if range.end < range.start {
+        Err(err)?;
+    }
+    Ok(range)
+}
+
+/// Split a dd-style `<key>=<value>` option. The key must not be empty.
+#[allow(dead_code)] // no command takes dd-style options yet
+pub(super) fn parse_option(s: &str) -> Result<(&str, &str), ArgError> {
+    match s.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key, value)),
+        _ => Err(ArgError::new(ArgKind::Option, s)),
+    }
+}
+
+fn parse_u64(s: &str) -> Option<u64> {
+    let (digits, radix) = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(digits) => (digits, 16),
+        None => (s, 10),
+    };
+    // from_str_radix accepts a sign, which is not a part of our syntax
+    if digits.is_empty() || !digits.chars().all(|c| c.is_digit(radix)) {
+        return None;
+    }
+    u64::from_str_radix(digits, radix).ok()
+}
+
+/// Split the unit suffix of a size, returning the rest and the multiplier.
+fn split_unit(s: &str) -> Option<(&str, u64)> {
+    let lower = |n: usize| {
+        s.get(s.len().checked_sub(n)?..)
+            .map(|t| t.to_ascii_lowercase())
+    };
+    // "iB" requires a unit before it, so that "0x1b" is still a hex number
+    let (s, binary) = match lower(2).as_deref() {
+        Some("ib") => (&s[..s.len() - 2], true),
+        _ => (s, false),
+    };
+    let unit = match s.bytes().last().map(|b| b.to_ascii_lowercase()) {
+        Some(b'k') => 1 << 10,
+        Some(b'm') => 1 << 20,
+        Some(b'g') => 1 << 30,
+        _ if binary => return None,
+        _ => return Some((s, 1)),
+    };
+    Some((&s[..s.len() - 1], unit))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::format;
+    use log::info;
+
+    #[test_case]
+    fn test_parse_int() {
+        info!("TESTING shell::args::test_parse_int");
+        assert_eq!(parse_int::<u64>("0"), Ok(0));
+        assert_eq!(parse_int::<u64>("123"), Ok(123));
+        assert_eq!(parse_int::<u64>("0x7b"), Ok(123));
+        assert_eq!(parse_int::<u64>("0X7B"), Ok(123));
+        assert_eq!(parse_int::<u64>("18446744073709551615"), Ok(u64::MAX));
+        assert_eq!(parse_int::<u64>("0xffffffffffffffff"), Ok(u64::MAX));
+        assert!(parse_int::<u64>("18446744073709551616").is_err());
+        assert!(parse_int::<u64>("0x10000000000000000").is_err());
+        assert_eq!(parse_int::<u8>("255"), Ok(255));
+        assert!(parse_int::<u8>("256").is_err());
+        for s in ["", "0x", "+1", "-1", "1 ", " 1", "12a", "0x1g", "1k", "1.0"] {
+            assert_eq!(parse_int::<u64>(s), Err(ArgError::new(ArgKind::Int, s)));
+        }
+    }
+
+    #[test_case]
+    fn test_parse_size() {
+        info!("TESTING shell::args::test_parse_size");
+        assert_eq!(parse_size("512"), Ok(512));
+        assert_eq!(parse_size("0x200"), Ok(512));
+        assert_eq!(parse_size("0x1b"), Ok(27));
+        for s in ["4k", "4K", "4kib", "4KiB", "4KIB", "4kIb"] {
+            assert_eq!(parse_size(s), Ok(4096), "{}", s);
+        }
+        assert_eq!(parse_size("16m"), Ok(16 << 20));
+        assert_eq!(parse_size("16MiB"), Ok(16 << 20));
+        assert_eq!(parse_size("1G"), Ok(1 << 30));
+        assert_eq!(parse_size("0x10k"), Ok(16 << 10));
+        assert_eq!(parse_size("0k"), Ok(0));
+        assert_eq!(parse_size("18446744073709551615"), Ok(u64::MAX));
+        assert_eq!(parse_size("17179869183g"), Ok(17179869183 << 30));
+        // Overflowing suffixes
+        assert!(parse_size("17179869184g").is_err());
+        assert!(parse_size("18014398509481984k").is_err());
+        assert!(parse_size("18446744073709551616").is_err());
+        for s in [
+            "", "k", "KiB", "4ib", "4b", "4kb", "4KiBx", "4 k", "4kk", "4t", "-4k", "0xk",
+        ] {
+            assert_eq!(parse_size(s), Err(ArgError::new(ArgKind::Size, s)), "{}", s);
+        }
+    }
+
+    #[test_case]
+    fn test_parse_range() {
+        info!("TESTING shell::args::test_parse_range");
+        assert_eq!(parse_range("10..20"), Ok(10..20));
+        assert_eq!(parse_range("10+5"), Ok(10..15));
+        assert_eq!(parse_range("0x10..1k"), Ok(16..1024));
+        assert_eq!(parse_range("1k+1k"), Ok(1024..2048));
+        assert_eq!(parse_range("5..5"), Ok(5..5));
+        assert_eq!(parse_range("0+18446744073709551615"), Ok(0..u64::MAX));
+        assert!(parse_range("1+18446744073709551615").is_err());
+        for s in [
+            "", "10", "20..10", "..20", "10..", "10+", "+5", "1..2..3", "1..2x", "1+-1",
+        ] {
+            assert_eq!(
+                parse_range(s),
+                Err(ArgError::new(ArgKind::Range, s)),
+                "{}",
+                s
+            );
+        }
+    }
+
+    #[test_case]
+    fn test_parse_option() {
+        info!("TESTING shell::args::test_parse_option");
+        assert_eq!(parse_option("bs=4k"), Ok(("bs", "4k")));
+        assert_eq!(parse_option("if="), Ok(("if", "")));
+        assert_eq!(parse_option("a=b=c"), Ok(("a", "b=c")));
+        assert!(parse_option("=4k").is_err());
+        assert!(parse_option("bs").is_err());
+    }
+
+    #[test_case]
+    fn test_arg_error() {
+        info!("TESTING shell::args::test_arg_error");
+        let (key, value) = parse_option("bs=foo").unwrap();
+        let e = parse_size(value).map_err(|e| e.named(key)).unwrap_err();
+        assert_eq!(format!("{}", e), "invalid size 'foo' for argument 'bs'");
+        let e = parse_int::<usize>("1x").unwrap_err();
+        assert_eq!(format!("{}", e), "invalid integer '1x'");
+
+        let usage = Usage::new("dd [bs=<size>]", &[("size", ArgKind::Size)]);
+        assert_eq!(
+            format!("{}", usage),
+            "dd [bs=<size>]\n  <size>: size (512, 0x200, 4k, 16M, 1GiB)"
+        );
+    }
+}
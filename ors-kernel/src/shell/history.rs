@@ -0,0 +1,382 @@
+//! Command history of the shell, persisted in `HISTORY_PATH` on the root file system.
+//!
+//! The file is a list of commands separated by newlines. Each command is appended to the file as
+//! it is executed, and the file is trimmed to the last `MAX_ENTRIES` commands when it grows past
+//! twice of that. Lines that cannot be commands, such as binary garbage, are skipped on loading.
+//! Failures on the file are ignored, since the history must never break the shell.
+
+use crate::fs::fat::{self, FileSystem};
+use crate::fs::mount;
+use crate::fs::volume::Volume;
+use crate::sync::mutex::Mutex;
+use alloc::collections::VecDeque;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt;
+use log::trace;
+
+const HISTORY_PATH: &str = "/.ors_history";
+
+/// Number of commands kept in memory and loaded from the file.
+const MAX_ENTRIES: usize = 200;
+
+/// Longer lines in the file are considered to be broken.
+const MAX_LINE_LEN: usize = 1024;
+
+/// Serializes the accesses to the history file among shells, so that lines are never interleaved.
+static FILE: Mutex<FileState> = Mutex::new(FileState::new());
+
+#[derive(Debug, Clone)]
+pub(super) struct History {
+    entries: VecDeque<String>,
+    /// The entry shown by `prev` and `next`, and the input before the navigation started.
+    browsing: Option<(usize, String)>,
+    /// Whether the commands are appended to the history file.
+    persistent: bool,
+}
+
+impl History {
+    pub(super) fn new() -> Self {
+        Self {
+            entries: VecDeque::new(),
+            browsing: None,
+            persistent: false,
+        }
+    }
+
+    /// Load the history file, and persist the subsequent commands if the file system is writable.
+    pub(super) fn load(&mut self) {
+        let (m, path) = match mount::resolve(HISTORY_PATH) {
+            Some((m, _)) if m.options.read_only => return,
+            Some(r) => r,
+            None => return,
+        };
+        match FILE.lock().load(&m.fs, &path) {
+            Ok(entries) => {
+                self.entries.extend(entries);
+                self.persistent = true;
+            }
+            Err(e) => trace!("shell: Failed to load {}: {}", HISTORY_PATH, e),
+        }
+    }
+
+    /// Record an executed command.
+    pub(super) fn add(&mut self, command: &str) {
+        self.browsing = None;
+        let command = command.trim();
+        if command.is_empty() || self.entries.back().map(|s| s.as_str()) == Some(command) {
+            return;
+        }
+        if MAX_ENTRIES <= self.entries.len() {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(command.to_string());
+
+        if self.persistent {
+            if let Some((m, path)) = mount::resolve(HISTORY_PATH) {
+                let result = FILE
+                    .lock()
+                    .append(&m.fs, &path, command)
+                    .and_then(|_| m.commit());
+                if let Err(e) = result {
+                    trace!("shell: Failed to write {}: {}", HISTORY_PATH, e);
+                }
+            }
+        }
+    }
+
+    /// Entries with their numbers, which are used by `!N`.
+    pub(super) fn entries(&self) -> impl Iterator<Item = (usize, &str)> {
+        self.entries
+            .iter()
+            .enumerate()
+            .map(|(i, s)| (i + 1, s.as_str()))
+    }
+
+    /// Move to the previous entry. `input` is kept to be restored by `next`.
+    pub(super) fn prev(&mut self, input: &str) -> Option<&str> {
+        let i = match self.browsing {
+            Some((0, _)) => return None,
+            Some((i, _)) => i - 1,
+            None if self.entries.is_empty() => return None,
+            None => self.entries.len() - 1,
+        };
+        let draft = match self.browsing.take() {
+            Some((_, draft)) => draft,
+            None => input.to_string(),
+        };
+        self.browsing = Some((i, draft));
+        Some(&self.entries[i])
+    }
+
+    /// Move to the next entry, or back to the input after the last entry.
+    pub(super) fn next(&mut self) -> Option<&str> {
+        let len = self.entries.len();
+        match self.browsing.take()? {
+            (i, draft) if i + 1 < len => {
+                self.browsing = Some((i + 1, draft));
+                Some(&self.entries[i + 1])
+            }
+            (i, draft) if i < len => {
+                // `len` stands for the input
+                self.browsing = Some((len, draft));
+                self.browsing.as_ref().map(|(_, draft)| draft.as_str())
+            }
+            _ => None,
+        }
+    }
+
+    /// Expand `!N` to the `N`-th entry. Other commands are returned as is.
+    pub(super) fn expand<'a>(&'a self, command: &'a str) -> Result<&'a str, ExpandError> {
+        let n = match command.trim().strip_prefix('!') {
+            Some(n) => n,
+            None => return Ok(command),
+        };
+        match n.parse::<usize>() {
+            Ok(i) if 1 <= i && i <= self.entries.len() => Ok(&self.entries[i - 1]),
+            Ok(i) => Err(ExpandError::NotFound(i)),
+            Err(_) => Err(ExpandError::Invalid),
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub(super) enum ExpandError {
+    NotFound(usize),
+    Invalid,
+}
+
+impl fmt::Display for ExpandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotFound(i) => write!(f, "!{}: event not found", i),
+            Self::Invalid => write!(f, "!<number>"),
+        }
+    }
+}
+
+/// What is known about the history file.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+struct FileState {
+    /// Number of lines in the file, including the broken ones.
+    lines: usize,
+    /// Whether the file ends in the middle of a line, which is dropped before appending.
+    partial: bool,
+}
+
+impl FileState {
+    const fn new() -> Self {
+        Self {
+            lines: 0,
+            partial: false,
+        }
+    }
+
+    /// Read the file, returning the last `MAX_ENTRIES` commands. A missing file is empty.
+    fn load<V: Volume>(
+        &mut self,
+        fs: &FileSystem<V>,
+        path: &str,
+    ) -> Result<Vec<String>, fat::Error> {
+        let bytes = match fs.open(path) {
+            Ok(file) => match file.reader() {
+                Some(reader) => reader.read_to_end()?,
+                None => Err(fat::Error::IsADirectory(path.into()))?,
+            },
+            Err(fat::Error::NotFound(_)) => Vec::new(),
+            Err(e) => Err(e)?,
+        };
+        let (entries, state) = parse(&bytes);
+        *self = state;
+        Ok(entries)
+    }
+
+    fn append<V: Volume>(
+        &mut self,
+        fs: &FileSystem<V>,
+        path: &str,
+        command: &str,
+    ) -> Result<(), fat::Error> {
+        if self.partial {
+            // The tail is what remains of an interrupted write, not a command
+            self.trim(fs, path)?;
+        }
+        let mut line = String::from(command);
+        line.push('\n');
+        let mut file = fs.open_or_create(path)?;
+        match file.appender() {
+            Some(mut writer) => writer.write(line.as_bytes())?,
+            None => Err(fat::Error::IsADirectory(path.into()))?,
+        }
+        self.lines += 1;
+        self.partial = false;
+        if 2 * MAX_ENTRIES < self.lines {
+            self.trim(fs, path)?;
+        }
+        Ok(())
+    }
+
+    /// Rewrite the file with the last `MAX_ENTRIES` commands in it.
+    fn trim<V: Volume>(&mut self, fs: &FileSystem<V>, path: &str) -> Result<(), fat::Error> {
+        let entries = self.load(fs, path)?;
+        let mut text = String::new();
+        for entry in entries.iter() {
+            text.push_str(entry);
+            text.push('\n');
+        }
+        let mut file = fs.open_or_create(path)?;
+        match file.overwriter() {
+            Some(mut writer) => writer.write(text.as_bytes())?,
+            None => Err(fat::Error::IsADirectory(path.into()))?,
+        }
+        *self = FileState {
+            lines: entries.len(),
+            partial: false,
+        };
+        Ok(())
+    }
+}
+
+/// Parse the content of the history file into the last `MAX_ENTRIES` commands.
+fn parse(bytes: &[u8]) -> (Vec<String>, FileState) {
+    let mut entries = VecDeque::new();
+    let mut lines = bytes.split(|b| *b == b'\n').collect::<Vec<_>>();
+    // The part after the last newline, which is empty unless the last write was interrupted
+    let partial = !lines.pop().unwrap_or_default().is_empty();
+    let state = FileState {
+        lines: lines.len() + partial as usize,
+        partial,
+    };
+    for line in lines {
+        if let Some(command) = parse_line(line) {
+            if MAX_ENTRIES <= entries.len() {
+                entries.pop_front();
+            }
+            entries.push_back(command);
+        }
+    }
+    (entries.into(), state)
+}
+
+/// The shell only accepts printable ASCII characters.
+fn parse_line(line: &[u8]) -> Option<String> {
+    if line.is_empty()
+        || MAX_LINE_LEN < line.len()
+        || !line.iter().all(|b| (b' '..=b'~').contains(b))
+    {
+        return None;
+    }
+    let line = core::str::from_utf8(line).ok()?.trim();
+    if line.is_empty() {
+        None
+    } else {
+        Some(line.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::fat::tests::format_volume;
+    use alloc::format;
+    use alloc::vec;
+    use log::info;
+
+    fn read(fs: &FileSystem<impl Volume>) -> String {
+        let file = fs.open(".ors_history").unwrap();
+        String::from_utf8(file.reader().unwrap().read_to_end().unwrap()).unwrap()
+    }
+
+    #[test_case]
+    fn test_history_file() {
+        info!("TESTING shell::history::test_history_file");
+        let fs = FileSystem::new(format_volume(512, 1)).unwrap();
+        let path = ".ors_history";
+        let mut state = FileState::new();
+        assert_eq!(state.load(&fs, path), Ok(vec![]));
+
+        state.append(&fs, path, "ls").unwrap();
+        state.append(&fs, path, "cd foo").unwrap();
+        assert_eq!(read(&fs), "ls\ncd foo\n");
+        let mut loaded = FileState::new();
+        assert_eq!(
+            loaded.load(&fs, path),
+            Ok(vec!["ls".into(), "cd foo".into()])
+        );
+        assert_eq!(loaded, state);
+
+        // Trimmed to the last entries when it grows past twice of them
+        for i in 0..2 * MAX_ENTRIES - 2 {
+            state.append(&fs, path, &format!("echo {}", i)).unwrap();
+        }
+        assert_eq!(state.lines, 2 * MAX_ENTRIES);
+        state.append(&fs, path, "last").unwrap();
+        assert_eq!(state.lines, MAX_ENTRIES);
+        let entries = FileState::new().load(&fs, path).unwrap();
+        assert_eq!(entries.len(), MAX_ENTRIES);
+        assert_eq!(entries[0], format!("echo {}", MAX_ENTRIES - 1));
+        assert_eq!(entries[MAX_ENTRIES - 1], "last");
+        assert!(read(&fs).ends_with("echo 397\nlast\n"));
+    }
+
+    #[test_case]
+    fn test_history_corruption() {
+        info!("TESTING shell::history::test_history_corruption");
+        let fs = FileSystem::new(format_volume(512, 1)).unwrap();
+        let path = ".ors_history";
+        {
+            let mut file = fs.open_or_create(path).unwrap();
+            let mut writer = file.overwriter().unwrap();
+            writer.write(b"ls\n\x00\xff\xfe\x01garbage\n\n").unwrap();
+            writer.write(&[b'x'; MAX_LINE_LEN + 1]).unwrap();
+            writer
+                .write(b"\n  pwd  \n\xe3\x81\x82\ncd /\nhalf-writ")
+                .unwrap();
+        }
+        let mut state = FileState::new();
+        let entries = state.load(&fs, path).unwrap();
+        assert_eq!(entries, vec!["ls", "pwd", "cd /"]);
+        assert_eq!(state.lines, 8);
+        assert!(state.partial);
+
+        // The partial line is dropped, so that the next command is not merged into it
+        state.append(&fs, path, "mkdir a").unwrap();
+        assert_eq!(read(&fs), "ls\npwd\ncd /\nmkdir a\n");
+        assert_eq!(state.lines, 4);
+        assert!(!state.partial);
+        let entries = FileState::new().load(&fs, path).unwrap();
+        assert_eq!(entries, vec!["ls", "pwd", "cd /", "mkdir a"]);
+    }
+
+    #[test_case]
+    fn test_history_navigation() {
+        info!("TESTING shell::history::test_history_navigation");
+        let mut history = History::new();
+        assert_eq!(history.prev("draft"), None);
+        history.add("ls");
+        history.add("ls");
+        history.add("  ");
+        history.add("cd foo");
+        assert_eq!(
+            history.entries().collect::<Vec<_>>(),
+            vec![(1, "ls"), (2, "cd foo")]
+        );
+
+        assert_eq!(history.prev("draft"), Some("cd foo"));
+        assert_eq!(history.prev("cd foo"), Some("ls"));
+        assert_eq!(history.prev("ls"), None);
+        assert_eq!(history.next(), Some("cd foo"));
+        assert_eq!(history.next(), Some("draft"));
+        assert_eq!(history.next(), None);
+        assert_eq!(history.prev("draft"), Some("cd foo"));
+        history.add("pwd");
+        assert_eq!(history.next(), None);
+        assert_eq!(history.prev(""), Some("pwd"));
+
+        assert_eq!(history.expand("pwd"), Ok("pwd"));
+        assert_eq!(history.expand("!1"), Ok("ls"));
+        assert_eq!(history.expand(" !2 "), Ok("cd foo"));
+        assert_eq!(history.expand("!4"), Err(ExpandError::NotFound(4)));
+        assert_eq!(history.expand("!x"), Err(ExpandError::Invalid));
+    }
+}
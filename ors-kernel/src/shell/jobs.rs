@@ -0,0 +1,435 @@
+//! Background jobs of the shell, started by a trailing `&` on the command line.
+//!
+//! Each job runs the command on a new task with a copy of the shell context. The output of the
+//! command is kept in a bounded buffer of the job, shown by `jobs -o`, instead of being written
+//! to the console where it would be interleaved with the prompt.
+
+use super::{execute_command, Context};
+use crate::interrupts::{ticks, TIMER_FREQ};
+use crate::sync::spin::Spin;
+use crate::task::{self, TaskId};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+
+/// Bytes of the output kept for each job. The oldest output is dropped first.
+const MAX_OUTPUT: usize = 16 * 1024;
+
+/// Completed jobs kept for `jobs` after their completion is notified.
+const MAX_COMPLETED_JOBS: usize = 8;
+
+static JOBS: Spin<Jobs> = Spin::new(Jobs::new());
+
+#[derive(Debug)]
+struct Jobs {
+    next_id: usize,
+    jobs: Vec<Job>,
+}
+
+impl Jobs {
+    const fn new() -> Self {
+        Self {
+            next_id: 1,
+            jobs: Vec::new(),
+        }
+    }
+
+    fn get_mut(&mut self, id: usize) -> Result<&mut Job, JobError> {
+        self.jobs
+            .iter_mut()
+            .find(|job| job.id == id)
+            .ok_or(JobError::NotFound(id))
+    }
+
+    /// Forget the oldest completed jobs whose completion is already notified.
+    fn prune(&mut self) {
+        let mut completed = self.jobs.iter().filter(|job| job.notified).count();
+        self.jobs.retain(|job| {
+            if job.notified && MAX_COMPLETED_JOBS < completed {
+                completed -= 1;
+                false
+            } else {
+                true
+            }
+        });
+    }
+}
+
+#[derive(Debug)]
+struct Job {
+    id: usize,
+    task: TaskId,
+    command: String,
+    /// Taken by the task of the job when it starts.
+    ctx: Option<Context>,
+    started_at: usize,
+    finished_at: Option<usize>,
+    /// Set by `kill`. Commands that poll `interrupted` stop on it.
+    interrupted: bool,
+    notified: bool,
+    output: Output,
+}
+
+impl Job {
+    fn status(&self) -> Status {
+        match (self.finished_at, self.interrupted) {
+            (None, _) => Status::Running,
+            (Some(_), false) => Status::Done,
+            (Some(_), true) => Status::Killed,
+        }
+    }
+
+    fn info(&self) -> JobInfo {
+        let end = self.finished_at.unwrap_or_else(ticks);
+        JobInfo {
+            id: self.id,
+            task: self.task,
+            command: self.command.clone(),
+            status: self.status(),
+            ticks: end.wrapping_sub(self.started_at),
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub(super) enum Status {
+    Running,
+    Done,
+    /// Completed after `kill`.
+    Killed,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Running => write!(f, "Running"),
+            Self::Done => write!(f, "Done"),
+            Self::Killed => write!(f, "Killed"),
+        }
+    }
+}
+
+/// A snapshot of a job, printed in a single line.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub(super) struct JobInfo {
+    pub(super) id: usize,
+    pub(super) task: TaskId,
+    pub(super) command: String,
+    pub(super) status: Status,
+    /// Elapsed ticks since the start, or until the completion.
+    pub(super) ticks: usize,
+}
+
+impl fmt::Display for JobInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "[{}] {:<7} {:>8}ms  {}",
+            self.id,
+            self.status,
+            self.ticks.saturating_mul(1000) / TIMER_FREQ,
+            self.command
+        )
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub(super) enum JobError {
+    NotFound(usize),
+    Finished(usize),
+    /// The task of the job is gone without completing the command, such as by a panic.
+    Aborted(usize),
+}
+
+impl fmt::Display for JobError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotFound(id) => write!(f, "No such job: %{}", id),
+            Self::Finished(id) => write!(f, "Job %{} has already finished", id),
+            Self::Aborted(id) => write!(f, "Job %{} was aborted", id),
+        }
+    }
+}
+
+/// The output of a job. Only the last `MAX_OUTPUT` bytes are kept.
+#[derive(PartialEq, Eq, Debug, Default)]
+struct Output {
+    text: String,
+    /// Bytes dropped from the beginning of `text`.
+    dropped: usize,
+}
+
+impl Output {
+    fn push(&mut self, s: &str) {
+        self.text.push_str(s);
+        if MAX_OUTPUT < self.text.len() {
+            let mut excess = self.text.len() - MAX_OUTPUT;
+            while !self.text.is_char_boundary(excess) {
+                excess += 1;
+            }
+            self.text.drain(..excess);
+            self.dropped += excess;
+        }
+    }
+}
+
+/// The output of the running job, appended to the buffer of the job.
+struct JobOutput(usize);
+
+impl fmt::Write for JobOutput {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if let Ok(job) = JOBS.lock().get_mut(self.0) {
+            job.output.push(s);
+        }
+        Ok(())
+    }
+}
+
+/// Parse a job specifier, `%N` or `N`.
+pub(super) fn parse_id(s: &str) -> Option<usize> {
+    s.strip_prefix('%').unwrap_or(s).parse().ok()
+}
+
+/// Run `command` as a job on a new task, with a copy of `ctx`. Changes to the context by the
+/// command, such as `cd`, do not affect the shell.
+pub(super) fn spawn(command: &str, ctx: &Context) -> JobInfo {
+    let mut jobs = JOBS.lock();
+    jobs.prune();
+    let id = jobs.next_id;
+    jobs.next_id += 1;
+    // The table is locked until the job is recorded, so that the task always finds its job.
+    // Background jobs run below the shell, so that the prompt stays responsive.
+    let task = task::scheduler().add(task::Priority::L0, run, id as u64);
+    let job = Job {
+        id,
+        task,
+        command: command.into(),
+        ctx: Some(ctx.clone()),
+        started_at: ticks(),
+        finished_at: None,
+        interrupted: false,
+        notified: false,
+        output: Output::default(),
+    };
+    let info = job.info();
+    jobs.jobs.push(job);
+    info
+}
+
+extern "C" fn run(id: u64) -> ! {
+    execute(id as usize);
+    task::scheduler().exit()
+}
+
+fn execute(id: usize) {
+    let (command, ctx) = match JOBS.lock().get_mut(id) {
+        Ok(job) => (job.command.clone(), job.ctx.take()),
+        Err(_) => return,
+    };
+    if let Some(mut ctx) = ctx {
+        execute_command(&command, &mut ctx, &mut JobOutput(id));
+    }
+    if let Ok(job) = JOBS.lock().get_mut(id) {
+        job.finished_at = Some(ticks());
+    }
+}
+
+/// Whether the running command is a background job.
+pub(super) fn in_background() -> bool {
+    let current = task::current_id();
+    JOBS.lock().jobs.iter().any(|job| Some(job.task) == current)
+}
+
+/// Whether the running command is requested to stop by `kill`. Long-running commands should
+/// poll this and return early. Always false on the shell itself, which has no way to interrupt
+/// the foreground command.
+pub(super) fn interrupted() -> bool {
+    let current = task::current_id();
+    JOBS.lock()
+        .jobs
+        .iter()
+        .any(|job| Some(job.task) == current && job.interrupted)
+}
+
+pub(super) fn list() -> Vec<JobInfo> {
+    JOBS.lock().jobs.iter().map(|job| job.info()).collect()
+}
+
+/// Write the buffered output of the job.
+pub(super) fn print_output(out: &mut dyn fmt::Write, id: usize) -> Result<(), JobError> {
+    // Copied out, since writing to `out` may lock the table again
+    let (text, dropped) = {
+        let mut jobs = JOBS.lock();
+        let job = jobs.get_mut(id)?;
+        (job.output.text.clone(), job.output.dropped)
+    };
+    if dropped != 0 {
+        let _ = writeln!(out, "({} bytes of earlier output dropped)", dropped);
+    }
+    let _ = write!(out, "{}", text);
+    Ok(())
+}
+
+/// Request the job to stop. The job stops only if the command polls `interrupted`.
+pub(super) fn kill(id: usize) -> Result<(), JobError> {
+    let mut jobs = JOBS.lock();
+    let job = jobs.get_mut(id)?;
+    if job.finished_at.is_some() {
+        Err(JobError::Finished(id))?;
+    }
+    job.interrupted = true;
+    Ok(())
+}
+
+/// Block until the job completes, or until the waiting job itself is killed. The completion is
+/// reported by the result and is not notified again. Fails if the completion is already
+/// notified, or if the task of the job is gone without completing it.
+pub(super) fn wait(id: usize) -> Result<JobInfo, JobError> {
+    loop {
+        let task = {
+            let mut jobs = JOBS.lock();
+            let job = jobs.get_mut(id)?;
+            if job.notified {
+                Err(JobError::Finished(id))?;
+            }
+            if job.finished_at.is_some() {
+                job.notified = true;
+                return Ok(job.info());
+            }
+            if Some(job.task) == task::current_id() {
+                // Waiting for itself never completes
+                return Ok(job.info());
+            }
+            job.task
+        };
+        if task::scheduler().tasks().iter().all(|t| t.id != task) {
+            let mut jobs = JOBS.lock();
+            let job = jobs.get_mut(id)?;
+            // The job may have completed right before its task exited
+            if job.finished_at.is_none() {
+                job.finished_at = Some(ticks());
+                job.notified = true;
+                Err(JobError::Aborted(id))?;
+            }
+            continue;
+        }
+        if interrupted() {
+            return JOBS.lock().get_mut(id).map(|job| job.info());
+        }
+        task::scheduler().sleep(1);
+    }
+}
+
+/// Take the jobs completed since the last call.
+pub(super) fn notifications() -> Vec<JobInfo> {
+    let mut jobs = JOBS.lock();
+    let completed = jobs
+        .jobs
+        .iter_mut()
+        .filter(|job| job.finished_at.is_some() && !job.notified)
+        .map(|job| {
+            job.notified = true;
+            job.info()
+        })
+        .collect();
+    jobs.prune();
+    completed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::info;
+
+    #[test_case]
+    fn test_output() {
+        info!("TESTING shell::jobs::test_output");
+        let mut output = Output::default();
+        output.push("hello\n");
+        assert_eq!(output.text, "hello\n");
+        assert_eq!(output.dropped, 0);
+
+        let line = "0123456789abcde\n";
+        for _ in 0..MAX_OUTPUT / line.len() {
+            output.push(line);
+        }
+        assert_eq!(output.text.len(), MAX_OUTPUT);
+        assert_eq!(output.dropped, 6);
+        assert!(output.text.starts_with(line));
+
+        // Never split a character
+        let mut output = Output::default();
+        for _ in 0..MAX_OUTPUT / 3 {
+            output.push("\u{3042}");
+        }
+        assert_eq!(output.text.len(), MAX_OUTPUT - 1);
+        output.push("ab");
+        assert_eq!(output.dropped, 3);
+        assert_eq!(output.text.len(), MAX_OUTPUT - 1);
+        assert!(output.text.ends_with("\u{3042}ab"));
+    }
+
+    #[test_case]
+    fn test_jobs() {
+        info!("TESTING shell::jobs::test_jobs");
+        let ctx = Context::new();
+        let job = spawn("pwd", &ctx);
+        assert_eq!(job.status, Status::Running);
+        let job = wait(job.id).unwrap();
+        assert_eq!(job.status, Status::Done);
+        assert_eq!(kill(job.id), Err(JobError::Finished(job.id)));
+        let mut s = String::new();
+        print_output(&mut s, job.id).unwrap();
+        assert_eq!(s, "/\n");
+        // Already reported by wait
+        assert!(notifications().iter().all(|j| j.id != job.id));
+        assert_eq!(wait(job.id), Err(JobError::Finished(job.id)));
+        assert_eq!(wait(usize::MAX), Err(JobError::NotFound(usize::MAX)));
+
+        // A job whose task is gone without completing, such as by a panic, is not waited forever
+        while task::scheduler().tasks().iter().any(|t| t.id == job.task) {
+            task::scheduler().sleep(1);
+        }
+        let aborted = {
+            let mut jobs = JOBS.lock();
+            let id = jobs.next_id;
+            jobs.next_id += 1;
+            jobs.jobs.push(Job {
+                id,
+                task: job.task,
+                command: "pwd".into(),
+                ctx: None,
+                started_at: ticks(),
+                finished_at: None,
+                interrupted: false,
+                notified: false,
+                output: Output::default(),
+            });
+            id
+        };
+        assert_eq!(wait(aborted), Err(JobError::Aborted(aborted)));
+        assert_eq!(wait(aborted), Err(JobError::Finished(aborted)));
+
+        let job = spawn("sleep 100000", &ctx);
+        assert!(!interrupted());
+        kill(job.id).unwrap();
+        let job = wait(job.id).unwrap();
+        assert_eq!(job.status, Status::Killed);
+        assert!(job.ticks < 100 * TIMER_FREQ);
+
+        let job = spawn("pwd", &ctx);
+        while list()
+            .iter()
+            .any(|j| j.id == job.id && j.status == Status::Running)
+        {
+            task::scheduler().sleep(1);
+        }
+        let notified = notifications();
+        assert!(notified.iter().any(|j| j.id == job.id));
+        assert!(notifications().iter().all(|j| j.id != job.id));
+        assert_eq!(kill(0), Err(JobError::NotFound(0)));
+        assert_eq!(parse_id("%3"), Some(3));
+        assert_eq!(parse_id("3"), Some(3));
+        assert_eq!(parse_id("%"), None);
+    }
+}
@@ -1,34 +1,82 @@
 use super::spin::Spin;
+use crate::config;
 use crate::task;
+use crate::trace::Category;
 use core::cell::UnsafeCell;
 use core::fmt;
 use core::ops::{Deref, DerefMut};
 
 /// A mutex implementation based on `spin::Spin` and `task::scheduler`.
+///
+/// With `config::LOCK_DIAGNOSTICS`, the owner task is recorded so that an attempt to lock the
+/// mutex again by the owner is detected instead of deadlocking silently.
 #[derive(Debug)]
 pub struct Mutex<T: ?Sized> {
-    locked: Spin<bool>,
+    state: Spin<LockState>,
+    name: Option<&'static str>,
     data: UnsafeCell<T>,
 }
 
+#[derive(Debug)]
+struct LockState {
+    locked: bool,
+    /// Recorded only with `config::LOCK_DIAGNOSTICS`.
+    owner: Option<task::TaskId>,
+}
+
+impl LockState {
+    const fn new() -> Self {
+        Self {
+            locked: false,
+            owner: None,
+        }
+    }
+}
+
 impl<T: ?Sized> Mutex<T> {
     fn chan(&self) -> task::WaitChannel {
-        task::WaitChannel::from_ptr(self)
+        chan(self, self.name)
     }
 
     pub fn get_mut(&mut self) -> &mut T {
         self.data.get_mut()
     }
 
+    /// Lock the mutex, blocking the current task until it is available.
+    ///
+    /// Panics if the current task already holds the lock and `config::LOCK_DIAGNOSTICS` is on.
+    /// Otherwise such a lock attempt deadlocks.
     pub fn lock(&self) -> MutexGuard<T> {
+        match MutexGuard::new(self) {
+            Ok(guard) => guard,
+            Err(e) => panic!("sync: {}", e),
+        }
+    }
+
+    /// Same as `lock`, but the recursive lock attempt is reported as an error.
+    pub fn lock_checked(&self) -> Result<MutexGuard<T>, LockError> {
         MutexGuard::new(self)
     }
 }
 
+unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+
+unsafe impl<T: ?Sized + Send> Send for Mutex<T> {}
+
 impl<T> Mutex<T> {
     pub const fn new(value: T) -> Self {
         Self {
-            locked: Spin::new(false),
+            state: Spin::new(LockState::new()),
+            name: None,
+            data: UnsafeCell::new(value),
+        }
+    }
+
+    /// Same as `new`, but tasks blocked on this mutex are shown with the name.
+    pub const fn named(value: T, name: &'static str) -> Self {
+        Self {
+            state: Spin::new(LockState::new()),
+            name: Some(name),
             data: UnsafeCell::new(value),
         }
     }
@@ -43,22 +91,43 @@ pub struct MutexGuard<'a, T: 'a + ?Sized> {
 }
 
 impl<'a, T: 'a + ?Sized> MutexGuard<'a, T> {
-    fn new(mutex: &'a Mutex<T>) -> Self {
+    fn new(mutex: &'a Mutex<T>) -> Result<Self, LockError> {
+        let current = if config::LOCK_DIAGNOSTICS {
+            task::current_id()
+        } else {
+            None
+        };
+        let mut contended = false;
         loop {
-            let mut locked = mutex.locked.lock();
-            if !*locked {
-                *locked = true; // acquire lock
+            let mut state = mutex.state.lock();
+            if !state.locked {
+                // acquire lock
+                state.locked = true;
+                state.owner = current;
                 break;
             }
-            task::scheduler().block(mutex.chan(), None, locked);
+            match current {
+                Some(task) if state.owner == Some(task) => {
+                    Err(LockError::recursive(mutex, mutex.name, task))?
+                }
+                _ => {}
+            }
+            if config::LOCK_DIAGNOSTICS {
+                trace_event!(Category::Mutex, "blocked {:p}", mutex);
+            }
+            contended = true;
+            task::scheduler().block(mutex.chan(), None, state);
         }
-        Self { mutex }
+        if config::LOCK_DIAGNOSTICS && contended {
+            trace_event!(Category::Mutex, "acquired {:p}", mutex);
+        }
+        Ok(Self { mutex })
     }
 }
 
 impl<'a, T: 'a + ?Sized> Drop for MutexGuard<'a, T> {
     fn drop(&mut self) {
-        *self.mutex.locked.lock() = false;
+        *self.mutex.state.lock() = LockState::new();
         task::scheduler().release(self.mutex.chan());
     }
 }
@@ -88,3 +157,275 @@ impl<'a, T: 'a + fmt::Display + ?Sized> fmt::Display for MutexGuard<'a, T> {
         fmt::Display::fmt(&**self, f)
     }
 }
+
+/// An error of `Mutex::lock_checked`.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum LockError {
+    /// The task tried to lock the mutex it already holds, which would never be unlocked.
+    Recursive {
+        addr: usize,
+        name: Option<&'static str>,
+        task: task::TaskId,
+    },
+}
+
+impl LockError {
+    fn recursive<M: ?Sized>(mutex: &M, name: Option<&'static str>, task: task::TaskId) -> Self {
+        Self::Recursive {
+            addr: mutex as *const M as *const () as usize,
+            name,
+            task,
+        }
+    }
+}
+
+impl fmt::Display for LockError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Recursive {
+                addr,
+                name: Some(name),
+                task,
+            } => write!(
+                f,
+                "Task {} locks the mutex {} ({:#x}) recursively",
+                task.as_u64(),
+                name,
+                addr
+            ),
+            Self::Recursive {
+                addr,
+                name: None,
+                task,
+            } => write!(
+                f,
+                "Task {} locks the mutex {:#x} recursively",
+                task.as_u64(),
+                addr
+            ),
+        }
+    }
+}
+
+/// A mutex that can be locked again by the task holding it. The mutex is unlocked when the
+/// outermost guard is dropped. Since guards may be nested, they only give shared references.
+#[derive(Debug)]
+pub struct ReentrantMutex<T: ?Sized> {
+    state: Spin<ReentrantState>,
+    name: Option<&'static str>,
+    data: UnsafeCell<T>,
+}
+
+#[derive(Debug)]
+struct ReentrantState {
+    /// `None` while unlocked, or while locked before the first task switch.
+    owner: Option<task::TaskId>,
+    depth: usize,
+}
+
+impl<T: ?Sized> ReentrantMutex<T> {
+    fn chan(&self) -> task::WaitChannel {
+        chan(self, self.name)
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+
+    pub fn lock(&self) -> ReentrantMutexGuard<T> {
+        ReentrantMutexGuard::new(self)
+    }
+
+    /// The number of guards of this mutex alive.
+    pub fn depth(&self) -> usize {
+        self.state.lock().depth
+    }
+}
+
+unsafe impl<T: ?Sized + Send> Sync for ReentrantMutex<T> {}
+
+unsafe impl<T: ?Sized + Send> Send for ReentrantMutex<T> {}
+
+impl<T> ReentrantMutex<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            state: Spin::new(ReentrantState {
+                owner: None,
+                depth: 0,
+            }),
+            name: None,
+            data: UnsafeCell::new(value),
+        }
+    }
+
+    /// Same as `new`, but tasks blocked on this mutex are shown with the name.
+    pub const fn named(value: T, name: &'static str) -> Self {
+        Self {
+            state: Spin::new(ReentrantState {
+                owner: None,
+                depth: 0,
+            }),
+            name: Some(name),
+            data: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+pub struct ReentrantMutexGuard<'a, T: 'a + ?Sized> {
+    mutex: &'a ReentrantMutex<T>,
+}
+
+impl<'a, T: 'a + ?Sized> ReentrantMutexGuard<'a, T> {
+    fn new(mutex: &'a ReentrantMutex<T>) -> Self {
+        let current = task::current_id();
+        loop {
+            let mut state = mutex.state.lock();
+            if state.depth == 0 || state.owner == current {
+                state.owner = current;
+                state.depth += 1;
+                break;
+            }
+            task::scheduler().block(mutex.chan(), None, state);
+        }
+        Self { mutex }
+    }
+}
+
+impl<'a, T: 'a + ?Sized> Drop for ReentrantMutexGuard<'a, T> {
+    fn drop(&mut self) {
+        let mut state = self.mutex.state.lock();
+        state.depth -= 1;
+        if state.depth == 0 {
+            state.owner = None;
+            drop(state);
+            task::scheduler().release(self.mutex.chan());
+        }
+    }
+}
+
+impl<'a, T: 'a + ?Sized> Deref for ReentrantMutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<'a, T: 'a + fmt::Debug + ?Sized> fmt::Debug for ReentrantMutexGuard<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+fn chan<M: ?Sized>(mutex: &M, name: Option<&'static str>) -> task::WaitChannel {
+    let chan = task::WaitChannel::from_ptr_index(task::ChannelDomain::Mutex, mutex, 0);
+    match name {
+        Some(name) => chan.named(name),
+        None => chan,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interrupts::TIMER_FREQ;
+    use alloc::format;
+    use core::cell::Cell;
+    use log::info;
+
+    #[test_case]
+    fn test_lock_checked() {
+        info!("TESTING sync::mutex::test_lock_checked");
+        let m = Mutex::named(0, "test.lock_checked");
+        let mut guard = m.lock_checked().unwrap();
+        *guard += 1;
+        if config::LOCK_DIAGNOSTICS {
+            let task = task::current_id().unwrap();
+            match m.lock_checked() {
+                Err(e) => {
+                    assert_eq!(e, LockError::recursive(&m, Some("test.lock_checked"), task));
+                    let message = format!("{}", e);
+                    assert!(message.contains("test.lock_checked"));
+                    assert!(message.starts_with(&format!("Task {} ", task.as_u64())));
+                }
+                Ok(_) => panic!("Recursive lock is not detected"),
+            }
+        }
+        drop(guard);
+        assert_eq!(*m.lock_checked().unwrap(), 1);
+    }
+
+    static DOUBLE_LOCKED: Mutex<()> = Mutex::named((), "test.double_locked");
+
+    extern "C" fn lock_twice(_: u64) -> ! {
+        let _a = DOUBLE_LOCKED.lock();
+        let _b = DOUBLE_LOCKED.lock();
+        task::scheduler().exit()
+    }
+
+    #[test_case]
+    fn test_recursive_lock_panics() {
+        info!("TESTING sync::mutex::test_recursive_lock_panics");
+        if !config::LOCK_DIAGNOSTICS {
+            return; // the task would deadlock
+        }
+        let policy = task::RestartPolicy {
+            max_restarts: 0,
+            backoff: 0,
+        };
+        let id = task::scheduler().add_supervised(task::Priority::MAX, lock_twice, 0, policy);
+        // The task is killed by the oops instead of being blocked forever
+        let is_alive = || task::scheduler().tasks().iter().any(|info| info.id == id);
+        for _ in 0..TIMER_FREQ {
+            if !is_alive() {
+                break;
+            }
+            task::scheduler().sleep(1);
+        }
+        assert!(!is_alive());
+        // Locks held by a killed task are never released, see `task::oops`
+        assert_eq!(DOUBLE_LOCKED.state.lock().owner, Some(id));
+    }
+
+    static REENTRANT: ReentrantMutex<Cell<u32>> =
+        ReentrantMutex::named(Cell::new(0), "test.reentrant");
+
+    extern "C" fn add_ten(_: u64) -> ! {
+        {
+            let value = REENTRANT.lock();
+            value.set(value.get() + 10);
+        }
+        task::scheduler().exit()
+    }
+
+    #[test_case]
+    fn test_reentrant_mutex() {
+        info!("TESTING sync::mutex::test_reentrant_mutex");
+        let a = REENTRANT.lock();
+        a.set(1);
+        let b = REENTRANT.lock();
+        b.set(b.get() + 1);
+        assert_eq!(REENTRANT.depth(), 2);
+
+        // Another task waits until the outermost guard is dropped
+        task::scheduler().add(task::Priority::MAX, add_ten, 0);
+        task::scheduler().sleep(10);
+        drop(b);
+        assert_eq!(REENTRANT.depth(), 1);
+        task::scheduler().sleep(10);
+        assert_eq!(a.get(), 2);
+        drop(a);
+        for _ in 0..TIMER_FREQ {
+            if REENTRANT.lock().get() == 12 {
+                break;
+            }
+            task::scheduler().sleep(1);
+        }
+        assert_eq!(REENTRANT.lock().get(), 12);
+        assert_eq!(REENTRANT.depth(), 0);
+    }
+}
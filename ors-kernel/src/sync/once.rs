@@ -1,9 +1,22 @@
-use crate::interrupts::Cli;
+use crate::interrupts::{deadline_after, Cli};
+use crate::task;
+use crate::x64;
 use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
 
 /// `spin::Once` with `crate::interrupts::Cli` to avoid deadlocks.
 pub struct Once<T> {
     inner: spin::Once<T>,
+    started: AtomicBool,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct TimeoutError;
+
+impl fmt::Display for TimeoutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Timed out")
+    }
 }
 
 impl<T> Once<T> {
@@ -12,12 +25,14 @@ impl<T> Once<T> {
     pub const fn new() -> Self {
         Self {
             inner: spin::Once::new(),
+            started: AtomicBool::new(false),
         }
     }
 
     pub const fn initialized(data: T) -> Self {
         Self {
             inner: spin::Once::initialized(data),
+            started: AtomicBool::new(true),
         }
     }
 
@@ -46,14 +61,39 @@ impl<T> Once<T> {
     }
 
     pub fn call_once<F: FnOnce() -> T>(&self, f: F) -> &T {
+        self.call_once_with_timeout(f, usize::MAX).unwrap()
+    }
+
+    /// Same as `call_once`, but gives up waiting after `timeout_ticks` ticks if the
+    /// initialization is being performed by another context.
+    /// The timeout does not apply to `f` called by this context.
+    pub fn call_once_with_timeout<F: FnOnce() -> T>(
+        &self,
+        f: F,
+        timeout_ticks: usize,
+    ) -> Result<&T, TimeoutError> {
         // We try get() at first to avoid Cli overhead
-        match self.inner.get() {
-            Some(data) => data,
-            None => {
-                let cli = Cli::new();
-                let data = self.inner.call_once(f);
-                drop(cli);
-                data
+        if let Some(data) = self.inner.get() {
+            return Ok(data);
+        }
+        if !self.started.swap(true, Ordering::SeqCst) {
+            let cli = Cli::new();
+            let data = self.inner.call_once(f);
+            drop(cli);
+            return Ok(data);
+        }
+        let deadline = deadline_after(timeout_ticks);
+        loop {
+            if let Some(data) = self.inner.poll() {
+                return Ok(data);
+            }
+            if deadline.is_expired() {
+                return Err(TimeoutError);
+            }
+            if x64::interrupts::are_enabled() {
+                task::scheduler().sleep(1);
+            } else {
+                core::hint::spin_loop(); // We cannot switch tasks here
             }
         }
     }
@@ -78,3 +118,25 @@ impl<T> From<T> for Once<T> {
         Self::initialized(data)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::info;
+
+    #[test_case]
+    fn test_call_once_with_timeout() {
+        info!("TESTING sync::once::test_call_once_with_timeout");
+        let once = Once::new();
+        assert!(!once.is_completed());
+        assert_eq!(once.call_once_with_timeout(|| 1, 0), Ok(&1));
+        assert_eq!(once.call_once(|| 2), &1);
+        assert!(once.is_completed());
+
+        // Simulate an initialization that never completes in another context
+        let once = Once::<u32>::new();
+        once.started.store(true, Ordering::SeqCst);
+        assert_eq!(once.call_once_with_timeout(|| 3, 5), Err(TimeoutError));
+        assert!(!once.is_completed());
+    }
+}
@@ -4,21 +4,46 @@ use heapless::mpmc::MpMcQueue;
 /// `heapless::mpmc::MpMcQueue` with task scheduler integration.
 pub struct Queue<T, const N: usize> {
     inner: MpMcQueue<T, N>,
+    name: Option<&'static str>,
 }
 
 impl<T, const N: usize> Queue<T, N> {
     pub const fn new() -> Self {
         Self {
             inner: MpMcQueue::new(),
+            name: None,
+        }
+    }
+
+    /// Same as `new`, but tasks blocked on this queue are shown with the name.
+    pub const fn named(name: &'static str) -> Self {
+        Self {
+            inner: MpMcQueue::new(),
+            name: Some(name),
         }
     }
 
     fn empty_chan(&self) -> task::WaitChannel {
-        task::WaitChannel::from_ptr_index(self, 0)
+        self.named_chan(task::WaitChannel::from_ptr_index(
+            task::ChannelDomain::Queue,
+            self,
+            0,
+        ))
     }
 
     fn full_chan(&self) -> task::WaitChannel {
-        task::WaitChannel::from_ptr_index(self, 1)
+        self.named_chan(task::WaitChannel::from_ptr_index(
+            task::ChannelDomain::Queue,
+            self,
+            1,
+        ))
+    }
+
+    fn named_chan(&self, chan: task::WaitChannel) -> task::WaitChannel {
+        match self.name {
+            Some(name) => chan.named(name),
+            None => chan,
+        }
     }
 
     pub fn enqueue(&self, mut item: T) {
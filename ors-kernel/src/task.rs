@@ -1,27 +1,52 @@
 use crate::context::{Context, EntryPoint};
 use crate::cpu::Cpu;
-use crate::interrupts::{ticks, Cli};
+use crate::interrupts::{self, ticks, Cli, Deadline, TIMER_FREQ};
+use crate::sync::queue::Queue;
 use crate::sync::spin::{Spin, SpinGuard};
+use crate::trace::Category;
+use crate::x64;
 use alloc::boxed::Box;
 use alloc::collections::{BTreeMap, BinaryHeap, VecDeque};
+use alloc::format;
+use alloc::string::String;
 use alloc::vec;
 use alloc::vec::Vec;
 use core::cell::UnsafeCell;
-use core::cmp::Reverse;
+use core::cmp::{Ordering as CmpOrdering, Reverse};
+use core::fmt;
+use core::hash::{Hash, Hasher};
 use core::mem::MaybeUninit;
-use core::sync::atomic::{AtomicI64, AtomicU64, Ordering};
-use log::trace;
+use core::ops::Range;
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, Ordering};
+use log::{error, trace, warn};
 use spin::Once;
 
+pub mod workqueue;
+
 const DEFAULT_STACK_SIZE: usize = 4096 * 256; // 1MiB
 
+/// Number of buckets of the wakeup latency histograms, see `LatencyStats`.
+pub const LATENCY_BUCKETS: usize = 12;
+
+/// Maximum number of expired timeouts processed by an entry to the scheduler, see
+/// `TaskQueue::elapse`.
+const MAX_TIMEOUTS_PER_ELAPSE: usize = 32;
+
+/// The channel that the timeouts task blocks on, see `process_timeouts`.
+const TIMEOUTS_CHAN: WaitChannel =
+    WaitChannel::scoped(ChannelDomain::Scheduler, 0).named("task.timeouts");
+
 static SCHEDULER: Once<TaskScheduler> = Once::new();
+static OOPSES: Queue<Oops, 16> = Queue::named("task.oops");
 
 pub fn initialize_scheduler() {
     SCHEDULER.call_once(|| {
         trace!("INITIALIZING Task Scheduler");
         TaskScheduler::new()
     });
+    scheduler().add(Priority::MAX, supervise, 0);
+    scheduler().add(Priority::MAX, process_timeouts, 0);
 }
 
 pub fn scheduler() -> &'static TaskScheduler {
@@ -30,11 +55,18 @@ pub fn scheduler() -> &'static TaskScheduler {
         .expect("task::scheduler is called before task::initialize_scheduler")
 }
 
+/// Take a snapshot of the wakeup latency histograms and the run queue depths.
+pub fn latency_stats() -> LatencyStats {
+    scheduler().latency_stats()
+}
+
 #[derive(Debug)]
 pub struct TaskScheduler {
     queue: Spin<TaskQueue>,
     task_id_gen: AtomicU64,
-    wait_channel_gen: AtomicI64,
+    wait_channel_gen: AtomicU64,
+    latency_histograms: [[AtomicU64; LATENCY_BUCKETS]; Priority::SIZE],
+    switch_count: AtomicU64,
 }
 
 impl TaskScheduler {
@@ -42,7 +74,9 @@ impl TaskScheduler {
         Self {
             queue: Spin::new(TaskQueue::new()),
             task_id_gen: AtomicU64::new(0),
-            wait_channel_gen: AtomicI64::new(-1),
+            wait_channel_gen: AtomicU64::new(0),
+            latency_histograms: Default::default(),
+            switch_count: AtomicU64::new(0),
         }
     }
 
@@ -51,7 +85,13 @@ impl TaskScheduler {
     }
 
     pub fn issue_wait_channel(&self) -> WaitChannel {
-        WaitChannel(self.wait_channel_gen.fetch_sub(1, Ordering::SeqCst))
+        let key = self.wait_channel_gen.fetch_add(1, Ordering::SeqCst);
+        WaitChannel::scoped(ChannelDomain::Issued, key)
+    }
+
+    /// Same as `issue_wait_channel`, but the channel has a name for debugging.
+    pub fn issue_named_wait_channel(&self, name: &'static str) -> WaitChannel {
+        self.issue_wait_channel().named(name)
     }
 
     pub fn add(
@@ -59,14 +99,114 @@ impl TaskScheduler {
         priority: Priority,
         entry_point: extern "C" fn(u64) -> !,
         entry_arg: u64,
+    ) -> TaskId {
+        self.add_with_affinity(priority, entry_point, entry_arg, AFFINITY_ALL)
+    }
+
+    /// Add a task that only runs on the specified CPU.
+    pub fn add_pinned(
+        &self,
+        priority: Priority,
+        entry_point: extern "C" fn(u64) -> !,
+        entry_arg: u64,
+        cpu: Cpu,
+    ) -> TaskId {
+        self.add_with_affinity(priority, entry_point, entry_arg, affinity_of(cpu))
+    }
+
+    /// Same as `add`, but the task is restarted according to `policy` when it is killed by an
+    /// oops, see `oops`. The restarted task runs `entry_point` from the beginning with a new ID,
+    /// thus the state that must survive restarts should be kept outside of the task stack.
+    pub fn add_supervised(
+        &self,
+        priority: Priority,
+        entry_point: extern "C" fn(u64) -> !,
+        entry_arg: u64,
+        policy: RestartPolicy,
+    ) -> TaskId {
+        let supervision = Supervision {
+            entry_point,
+            entry_arg,
+            policy,
+            restarts: 0,
+        };
+        self.add_supervised_with(priority, supervision)
+    }
+
+    fn add_supervised_with(&self, priority: Priority, supervision: Supervision) -> TaskId {
+        let id = self.issue_task_id();
+        let entry_point = TaskEntryPoint(supervision.entry_point);
+        let mut task = Task::new(id, priority, entry_point, supervision.entry_arg);
+        task.0.supervision = Some(supervision);
+        self.queue.lock().enqueue(task);
+        id
+    }
+
+    fn add_with_affinity(
+        &self,
+        priority: Priority,
+        entry_point: extern "C" fn(u64) -> !,
+        entry_arg: u64,
+        affinity: u64,
     ) -> TaskId {
         let id = self.issue_task_id();
         let entry_point = TaskEntryPoint(entry_point);
-        let task = Task::new(id, priority, entry_point, entry_arg);
+        let mut task = Task::new(id, priority, entry_point, entry_arg);
+        task.0.affinity = affinity;
         self.queue.lock().enqueue(task);
         id
     }
 
+    /// Change the set of CPUs the task is allowed to run on.
+    /// `mask` is a bitmap over the indices of `Cpu::list()`, and must contain at least one of them.
+    /// If the task is running, the change takes effect at its next task switch.
+    pub fn set_affinity(&self, id: TaskId, mask: u64) -> Result<(), AffinityError> {
+        if mask == 0 {
+            return Err(AffinityError::EmptyMask);
+        }
+        if Cpu::list().all(|cpu| mask & affinity_of(cpu) == 0) {
+            return Err(AffinityError::NoSuchCpu);
+        }
+        let mut queue = self.queue.lock();
+        if let Some(task) = queue.find_mut(id) {
+            task.0.affinity = mask;
+            return Ok(());
+        }
+        self.with_running_task(&mut queue, id, |task| task.0.affinity = mask)
+            .ok_or(AffinityError::UnknownTask)
+    }
+
+    /// Change the priority of the task by a nice value. Returns whether the task is found.
+    /// If the task is running, the change takes effect at its next task switch.
+    pub fn renice(&self, id: TaskId, nice: i8) -> bool {
+        let priority = Priority::from_nice(nice);
+        let mut queue = self.queue.lock();
+        if queue.set_priority(id, priority) {
+            return true;
+        }
+        self.with_running_task(&mut queue, id, |task| task.0.priority = priority)
+            .is_some()
+    }
+
+    /// Apply `f` to the task if it is running on any CPU. This takes `queue` to ensure that
+    /// self.queue is locked, since `switch` moves a task between self.queue and running_task
+    /// only while self.queue is locked. Thus a task that is not in the queue must be running.
+    fn with_running_task<R>(
+        &self,
+        _queue: &mut TaskQueue,
+        id: TaskId,
+        f: impl FnOnce(&mut Task) -> R,
+    ) -> Option<R> {
+        for cpu in Cpu::list() {
+            if let Some(task) = cpu.state().lock().running_task.as_mut() {
+                if task.id() == id {
+                    return Some(f(task));
+                }
+            }
+        }
+        None
+    }
+
     pub fn switch<T>(
         &self,
         scheduling_op: impl FnOnce() -> (Option<Switch>, T),
@@ -77,29 +217,50 @@ impl TaskScheduler {
         let cpu_state = Cpu::current().state();
         assert_eq!(cpu_state.lock().thread_state.ncli, 1 + other_cli); // To ensure that this context does not hold locks (*1)
 
-        let cpu_task = {
-            // This assignment is necessary to avoid deadlocks
-            let task = cpu_state.lock().running_task.take();
-            task.unwrap_or_else(|| Task::new_current(self.issue_task_id(), Priority::MIN))
-        };
-        // FIXME: This implicitly relies on the fact that cpu_task is retained (not dropped) by self.queue
-        let current_ctx = cpu_task.ctx().get();
-
-        let (cpu_task, ret) = {
+        let cpu_affinity = affinity_of(Cpu::current());
+        let (current_ctx, current_id, next_ctx, next_id, ret) = {
+            // The running task is taken out and put back while self.queue is locked, so that
+            // every task is found either in self.queue or in running_task under self.queue
+            // (see `with_running_task`)
             let mut queue_lock = self.queue.lock();
+            let cpu_task = {
+                // This assignment is necessary to avoid deadlocks
+                let task = cpu_state.lock().running_task.take();
+                task.unwrap_or_else(|| Task::new_current(self.issue_task_id(), Priority::MIN))
+            };
+            // FIXME: This implicitly relies on the fact that cpu_task is retained (not dropped) by self.queue
+            let current_ctx = cpu_task.ctx().get();
+            let current_id = cpu_task.id();
+
+            // Timeouts expired since the last entry are processed here, except in the timer
+            // handler, which only wakes the timeouts task (see `process_timeouts`)
+            let now = ticks();
+            if cpu_state.lock().thread_state.nint == 0 {
+                queue_lock.elapse(now);
+            } else if queue_lock.has_expired_timeouts(now) {
+                queue_lock.release(TIMEOUTS_CHAN);
+            }
             // scheduling_op is called while self.queue is locked
             let (switch, ret) = scheduling_op();
-            let task = match switch {
-                Some(switch) => queue_lock.dequeue(cpu_task, switch),
+            let mut cpu_task = match switch {
+                Some(switch) => queue_lock.dequeue(cpu_task, switch, cpu_affinity),
                 // Task switching is cancelled, but we need to restore cpu_state.running_task
                 None => cpu_task,
             };
-            (task, ret)
+            if let Some(woken_at) = cpu_task.0.woken_at.take() {
+                let bucket = latency_bucket(ticks().saturating_sub(woken_at));
+                self.latency_histograms[cpu_task.priority().index()][bucket]
+                    .fetch_add(1, Ordering::Relaxed);
+            }
+            let next_ctx = cpu_task.ctx().get();
+            let next_id = cpu_task.id();
+            assert!(cpu_state.lock().running_task.replace(cpu_task).is_none());
+            (current_ctx, current_id, next_ctx, next_id, ret)
         };
-        let next_ctx = cpu_task.ctx().get();
-        assert!(cpu_state.lock().running_task.replace(cpu_task).is_none());
 
         if current_ctx != next_ctx {
+            self.switch_count.fetch_add(1, Ordering::Relaxed);
+            trace_event!(Category::Sched, "switch {} -> {}", current_id.0, next_id.0);
             unsafe { Context::switch(next_ctx, current_ctx) };
         }
 
@@ -107,12 +268,16 @@ impl TaskScheduler {
         ret
     }
 
+    /// Switch to another runnable task if any. This is also used to preempt the current task from
+    /// the timer handler, which is the only switch allowed in an interrupt handler.
     pub fn r#yield(&self) {
+        assert_interrupt_depth(1);
         self.switch(|| (Some(Switch::Yield), ()), 0)
     }
 
     /// Atomically release MutexGuard and block on chan.
     pub fn block<T>(&self, chan: WaitChannel, timeout: Option<usize>, guard: SpinGuard<'_, T>) {
+        assert_interrupt_depth(0);
         self.switch(
             move || {
                 drop(guard);
@@ -123,32 +288,127 @@ impl TaskScheduler {
     }
 
     pub fn sleep(&self, ticks: usize) {
+        assert_interrupt_depth(0);
         self.switch(|| (Some(Switch::Sleep(ticks)), ()), 0)
     }
 
+    /// Terminate the current task. The task must not hold any locks.
+    pub fn exit(&self) -> ! {
+        assert_interrupt_depth(0);
+        loop {
+            // This returns only if there are no other tasks to switch to
+            self.switch(|| (Some(Switch::Exit), ()), 0);
+        }
+    }
+
     pub fn release(&self, chan: WaitChannel) {
         self.queue.lock().release(chan);
     }
 
-    pub fn elapse(&self) {
-        self.queue.lock().elapse();
+    /// Take a snapshot of the states of all tasks, ordered by task ID.
+    pub fn tasks(&self) -> Vec<TaskInfo> {
+        let now = ticks();
+        let queue = self.queue.lock();
+        let mut tasks = queue.task_infos(now);
+        for cpu in Cpu::list() {
+            if let Some(task) = cpu.state().lock().running_task.as_ref() {
+                tasks.push(TaskInfo::new(task, TaskState::Running(cpu.index()), now));
+            }
+        }
+        drop(queue);
+        tasks.sort_by_key(|info| info.id);
+        tasks
+    }
+
+    /// The number of context switches since boot.
+    pub fn switch_count(&self) -> u64 {
+        self.switch_count.load(Ordering::Relaxed)
+    }
+
+    pub fn latency_stats(&self) -> LatencyStats {
+        let max_runnable = self.queue.lock().max_runnable;
+        let mut histograms = [[0; LATENCY_BUCKETS]; Priority::SIZE];
+        for (h, counts) in histograms.iter_mut().zip(self.latency_histograms.iter()) {
+            for (h, count) in h.iter_mut().zip(counts.iter()) {
+                *h = count.load(Ordering::Relaxed);
+            }
+        }
+        LatencyStats {
+            histograms,
+            max_runnable,
+        }
+    }
+
+    pub fn reset_latency_stats(&self) {
+        {
+            let mut queue = self.queue.lock();
+            let queue = &mut *queue;
+            for (max, tasks) in queue
+                .max_runnable
+                .iter_mut()
+                .zip(queue.runnable_tasks.iter())
+            {
+                *max = tasks.len();
+            }
+        }
+        for counts in self.latency_histograms.iter() {
+            for count in counts.iter() {
+                count.store(0, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+/// Wakeup latencies, the ticks from when tasks are made runnable by `TaskScheduler::release` or
+/// by timeouts until they are switched to, and the maximum number of runnable tasks, per priority.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct LatencyStats {
+    /// Indexed by `Priority::index` and then by bucket, see `LatencyStats::bucket_range`.
+    pub histograms: [[u64; LATENCY_BUCKETS]; Priority::SIZE],
+    pub max_runnable: [usize; Priority::SIZE],
+}
+
+impl LatencyStats {
+    /// The range of latencies in ticks counted in the bucket. The first bucket counts tasks
+    /// switched to in the same tick, and the last bucket has no upper bound.
+    pub fn bucket_range(bucket: usize) -> Range<usize> {
+        match bucket {
+            0 => 0..1,
+            b if b == LATENCY_BUCKETS - 1 => 1 << (b - 1)..usize::MAX,
+            b => 1 << (b - 1)..1 << b,
+        }
+    }
+
+    pub fn ticks_to_us(ticks: usize) -> usize {
+        ticks.saturating_mul(1000000 / TIMER_FREQ)
     }
 }
 
+fn latency_bucket(ticks: usize) -> usize {
+    ((usize::BITS - ticks.leading_zeros()) as usize).min(LATENCY_BUCKETS - 1)
+}
+
 #[derive(Debug, Clone, Copy)]
 pub enum Switch {
     Blocked(WaitChannel, Option<usize>),
     Sleep(usize),
     Yield,
+    Exit,
 }
 
 #[derive(Debug)]
 struct TaskQueue {
     pending_id_gen: u64,
     runnable_tasks: [VecDeque<Task>; Priority::SIZE],
-    pending_tasks: BTreeMap<PendingId, Task>,
+    max_runnable: [usize; Priority::SIZE],
+    pending_tasks: BTreeMap<PendingId, (Task, Wait)>,
     blocks: BTreeMap<WaitChannel, Vec<PendingId>>,
     timeouts: BinaryHeap<Reverse<(usize, PendingId, Option<WaitChannel>)>>,
+    /// The ticks until which the timeouts have been processed, see `elapse`.
+    elapsed: usize,
+    /// Exited tasks, which are dropped once their contexts are saved (they are no longer running
+    /// on their stacks).
+    exited_tasks: Vec<Task>,
 }
 
 impl TaskQueue {
@@ -160,9 +420,12 @@ impl TaskQueue {
         Self {
             pending_id_gen: 0,
             runnable_tasks: unsafe { MaybeUninit::array_assume_init(runnable_tasks) },
+            max_runnable: [0; Priority::SIZE],
             pending_tasks: BTreeMap::new(),
             blocks: BTreeMap::new(),
             timeouts: BinaryHeap::new(),
+            elapsed: 0,
+            exited_tasks: Vec::new(),
         }
     }
 
@@ -173,24 +436,42 @@ impl TaskQueue {
     }
 
     fn enqueue(&mut self, task: Task) {
-        self.runnable_tasks[task.priority().index()].push_back(task);
+        let i = task.priority().index();
+        self.runnable_tasks[i].push_back(task);
+        self.max_runnable[i] = self.max_runnable[i].max(self.runnable_tasks[i].len());
+    }
+
+    /// Make a pending task runnable. The time is recorded to measure the wakeup latency.
+    fn wake(&mut self, mut task: Task) {
+        task.0.woken_at = Some(ticks());
+        self.enqueue(task);
     }
 
     /// Dequeuing requires a task that is currently running.
-    fn dequeue(&mut self, current_task: Task, current_switch: Switch) -> Task {
+    /// `cpu_affinity` is the affinity bit of the CPU that will run the dequeued task.
+    fn dequeue(&mut self, current_task: Task, current_switch: Switch, cpu_affinity: u64) -> Task {
+        self.exited_tasks
+            .retain(|task| !unsafe { &*task.ctx().get() }.is_saved());
+
         let minimum_level_index = match current_switch {
-            Switch::Yield => current_task.priority().index(), // current_task is still runnable
+            // current_task is still runnable (unless it is no longer allowed to run on this CPU)
+            Switch::Yield if current_task.runs_on(cpu_affinity) => current_task.priority().index(),
             _ => 0,
         };
 
-        // next_task is runnable, has the highest priority, and is at the front of the queue
+        // next_task is runnable on this CPU, has the highest priority, and is the first such task
+        // in the queue
         if let Some(next_task) = self
             .runnable_tasks
             .iter_mut()
             .enumerate()
             .rev()
             .take_while(|(i, _)| minimum_level_index <= *i)
-            .find_map(|(_, queue)| queue.pop_front())
+            .find_map(|(_, queue)| {
+                // In most cases the front task is runnable on any CPU
+                let i = queue.iter().position(|t| t.runs_on(cpu_affinity))?;
+                queue.remove(i)
+            })
         {
             // current_task.ctx will be saved "after" dequeuing:
             // TaskScheduler::switch -> Context::switch -> switch_context (asm.s)
@@ -199,20 +480,22 @@ impl TaskQueue {
             match current_switch {
                 Switch::Blocked(chan, timeout) => {
                     let id = self.issue_pending_id();
-                    self.pending_tasks.insert(id, current_task);
+                    let wait = Wait::new(Some(chan), timeout);
+                    self.pending_tasks.insert(id, (current_task, wait));
                     self.blocks.entry(chan).or_default().push(id);
-                    if let Some(t) = timeout {
-                        self.timeouts.push(Reverse((ticks() + t, id, Some(chan))));
+                    if let Some(deadline) = wait.deadline {
+                        self.timeouts.push(Reverse((deadline, id, Some(chan))));
                     }
                 }
                 Switch::Sleep(t) => {
                     let id = self.issue_pending_id();
-                    self.pending_tasks.insert(id, current_task);
-                    self.timeouts.push(Reverse((ticks() + t, id, None)));
-                }
-                Switch::Yield => {
-                    self.runnable_tasks[current_task.priority().index()].push_back(current_task);
+                    let wait = Wait::new(None, Some(t));
+                    self.pending_tasks.insert(id, (current_task, wait));
+                    self.timeouts
+                        .push(Reverse((wait.deadline.unwrap(), id, None)));
                 }
+                Switch::Yield => self.enqueue(current_task),
+                Switch::Exit => self.exited_tasks.push(current_task),
             }
 
             unsafe { &*next_task.ctx().get() }.wait_saved();
@@ -222,60 +505,327 @@ impl TaskQueue {
         }
     }
 
+    fn find_mut(&mut self, id: TaskId) -> Option<&mut Task> {
+        self.runnable_tasks
+            .iter_mut()
+            .flat_map(|queue| queue.iter_mut())
+            .chain(self.pending_tasks.values_mut().map(|(task, _)| task))
+            .find(|task| task.id() == id)
+    }
+
+    fn set_priority(&mut self, id: TaskId, priority: Priority) -> bool {
+        for queue in self.runnable_tasks.iter_mut() {
+            if let Some(i) = queue.iter().position(|task| task.id() == id) {
+                let mut task = queue.remove(i).unwrap();
+                task.0.priority = priority;
+                self.enqueue(task);
+                return true;
+            }
+        }
+        match self
+            .pending_tasks
+            .values_mut()
+            .find(|(task, _)| task.id() == id)
+        {
+            Some((task, _)) => {
+                task.0.priority = priority;
+                true
+            }
+            None => false,
+        }
+    }
+
     fn release(&mut self, chan: WaitChannel) {
         if let Some(ids) = self.blocks.remove(&chan) {
             for id in ids {
-                if let Some(task) = self.pending_tasks.remove(&id) {
-                    self.runnable_tasks[task.priority().index()].push_back(task);
+                if let Some((task, _)) = self.pending_tasks.remove(&id) {
+                    self.wake(task);
                 }
             }
         }
     }
 
-    fn elapse(&mut self) {
-        let ticks = ticks();
-        while match self.timeouts.peek() {
-            Some(Reverse((t, id, chan))) if *t <= ticks => {
-                if let Some(task) = self.pending_tasks.remove(id) {
-                    self.runnable_tasks[task.priority().index()].push_back(task);
+    /// Wake the tasks whose timeouts expired by `now`. This is a catch-up step of every entry to
+    /// the scheduler from a task instead of a part of the timer handler, see `process_timeouts`.
+    /// To bound the time spent by an entry after a long `Cli` section, at most
+    /// `MAX_TIMEOUTS_PER_ELAPSE` timeouts are processed at once, and the rest are left to the
+    /// next entry.
+    fn elapse(&mut self, now: usize) {
+        if now <= self.elapsed {
+            return;
+        }
+        for _ in 0..MAX_TIMEOUTS_PER_ELAPSE {
+            let (id, chan) = match self.timeouts.peek() {
+                Some(&Reverse((t, id, chan))) if t <= now => (id, chan),
+                _ => {
+                    self.elapsed = now;
+                    return;
                 }
-                if let Some(chan) = chan {
-                    if let Some(ids) = self.blocks.get_mut(chan) {
-                        ids.retain(|i| i != id);
-                    }
+            };
+            if let Some((task, _)) = self.pending_tasks.remove(&id) {
+                self.wake(task);
+            }
+            if let Some(chan) = chan {
+                if let Some(ids) = self.blocks.get_mut(&chan) {
+                    ids.retain(|i| *i != id);
                 }
-                let _ = self.timeouts.pop();
-                true
             }
-            _ => false,
-        } {}
+            let _ = self.timeouts.pop();
+        }
+    }
+
+    /// Whether `elapse(now)` has any timeouts to process. Unlike `elapse`, this takes a constant
+    /// time, thus this can be used in the timer handler.
+    fn has_expired_timeouts(&self, now: usize) -> bool {
+        matches!(self.timeouts.peek(), Some(&Reverse((t, _, _))) if t <= now)
+    }
+
+    fn task_infos(&self, now: usize) -> Vec<TaskInfo> {
+        let runnable_tasks = self.runnable_tasks.iter().flat_map(|queue| queue.iter());
+        let pending_tasks = self.pending_tasks.values();
+        runnable_tasks
+            .map(|task| TaskInfo::new(task, TaskState::Runnable, now))
+            .chain(pending_tasks.map(|(task, wait)| {
+                let state = match wait.chan {
+                    Some(chan) => TaskState::Blocked(chan, wait.since, wait.deadline),
+                    None => TaskState::Sleeping(wait.since, wait.deadline.unwrap_or(wait.since)),
+                };
+                TaskInfo::new(task, state, now)
+            }))
+            .collect()
     }
 }
 
+/// What a pending task is waiting for.
+#[derive(Debug, Clone, Copy)]
+struct Wait {
+    chan: Option<WaitChannel>, // None for sleeping tasks
+    since: usize,
+    deadline: Option<usize>,
+}
+
+impl Wait {
+    fn new(chan: Option<WaitChannel>, timeout: Option<usize>) -> Self {
+        let since = ticks();
+        Self {
+            chan,
+            since,
+            deadline: timeout.map(|t| Deadline::after(since, t).ticks()),
+        }
+    }
+}
+
+/// A snapshot of the state of a task, see `TaskScheduler::tasks`.
+#[derive(Debug, Clone)]
+pub struct TaskInfo {
+    pub id: TaskId,
+    pub priority: Priority,
+    pub affinity: u64,
+    pub state: TaskState,
+    /// The ticks when the snapshot was taken.
+    pub ticks: usize,
+}
+
+impl TaskInfo {
+    fn new(task: &Task, state: TaskState, ticks: usize) -> Self {
+        Self {
+            id: task.id(),
+            priority: task.priority(),
+            affinity: task.affinity(),
+            state,
+            ticks,
+        }
+    }
+
+    /// How long the task has been blocked or sleeping.
+    pub fn wait_ticks(&self) -> Option<usize> {
+        match self.state {
+            TaskState::Blocked(_, since, _) | TaskState::Sleeping(since, _) => {
+                Some(self.ticks.saturating_sub(since))
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether the task has been blocked without timeout for `threshold` ticks or more.
+    pub fn is_stuck(&self, threshold: usize) -> bool {
+        matches!(self.state, TaskState::Blocked(_, _, None))
+            && self.wait_ticks().unwrap_or(0) >= threshold
+    }
+}
+
+impl fmt::Display for TaskInfo {
+    /// A human-readable description of the state, such as "blocked on console.in for 10 ticks".
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let wait_ticks = self.wait_ticks().unwrap_or(0);
+        match self.state {
+            TaskState::Running(cpu) => write!(f, "running on cpu{}", cpu),
+            TaskState::Runnable => write!(f, "runnable"),
+            TaskState::Sleeping(_, deadline) => write!(
+                f,
+                "sleeping for {} ticks ({} ticks left)",
+                wait_ticks,
+                deadline.saturating_sub(self.ticks)
+            ),
+            TaskState::Blocked(chan, _, deadline) => {
+                write!(f, "blocked on {} for {} ticks", chan, wait_ticks)?;
+                match deadline {
+                    Some(deadline) => write!(
+                        f,
+                        " (timeout in {} ticks)",
+                        deadline.saturating_sub(self.ticks)
+                    ),
+                    None => Ok(()),
+                }
+            }
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum TaskState {
+    /// Running on the CPU of the index.
+    Running(usize),
+    Runnable,
+    /// Sleeping since the first ticks until the second ticks.
+    Sleeping(usize, usize),
+    /// Blocked on the channel since the ticks, with an optional deadline.
+    Blocked(WaitChannel, usize, Option<usize>),
+}
+
 #[repr(transparent)]
 #[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy, Hash)]
 struct PendingId(u64);
 
-#[repr(transparent)]
-#[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy, Hash)]
-pub struct WaitChannel(i64);
+/// A channel that tasks can block on. The optional name is only for debugging, and does not
+/// take part in the identity of the channel.
+#[derive(Debug, Clone, Copy)]
+pub struct WaitChannel(ChannelDomain, u64, Option<&'static str>);
 
 impl WaitChannel {
-    /// Create `WaitChannel` from a pointer.
-    /// The uniqueness of the resulting `WaitChannel` depends on the uniqueness of the pointer.
-    pub fn from_ptr<T: ?Sized>(ptr: *const T) -> Self {
-        Self::from_ptr_index(ptr, 0)
+    /// Create `WaitChannel` from a key in the given domain.
+    /// Channels of different domains never collide even if they share the same key.
+    pub const fn scoped(domain: ChannelDomain, key: u64) -> Self {
+        Self(domain, key, None)
+    }
+
+    /// Create `WaitChannel` from a pointer to an object that is owned by the given domain.
+    /// The uniqueness of the resulting `WaitChannel` depends on the uniqueness of the pointer
+    /// within the domain, so this should be used only for long-lived objects.
+    pub fn from_ptr_index<T: ?Sized>(domain: ChannelDomain, ptr: *const T, index: u32) -> Self {
+        Self::scoped(domain, ptr as *const () as u64 + index as u64)
+    }
+
+    pub const fn named(self, name: &'static str) -> Self {
+        Self(self.0, self.1, Some(name))
+    }
+
+    pub fn domain(self) -> ChannelDomain {
+        self.0
     }
 
-    pub fn from_ptr_index<T: ?Sized>(ptr: *const T, index: u32) -> Self {
-        Self((ptr as *const () as i64 + index as i64) & i64::MAX)
+    pub fn name(self) -> Option<&'static str> {
+        self.2
     }
 }
 
+impl PartialEq for WaitChannel {
+    fn eq(&self, other: &Self) -> bool {
+        (self.0, self.1) == (other.0, other.1)
+    }
+}
+
+impl Eq for WaitChannel {}
+
+impl PartialOrd for WaitChannel {
+    fn partial_cmp(&self, other: &Self) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for WaitChannel {
+    fn cmp(&self, other: &Self) -> CmpOrdering {
+        (self.0, self.1).cmp(&(other.0, other.1))
+    }
+}
+
+impl Hash for WaitChannel {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (self.0, self.1).hash(state)
+    }
+}
+
+impl fmt::Display for WaitChannel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.2 {
+            Some(name) => write!(f, "{}", name),
+            None => write!(f, "{:?}#{:x}", self.0, self.1),
+        }
+    }
+}
+
+/// The purpose of a `WaitChannel`, mixed into the channel to avoid cross-subsystem collisions.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy, Hash)]
+pub enum ChannelDomain {
+    /// Channels issued by `TaskScheduler::issue_wait_channel`.
+    Issued,
+    Mutex,
+    Queue,
+    /// Channels used by the scheduler itself.
+    Scheduler,
+}
+
 #[repr(transparent)]
 #[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy, Hash)]
 pub struct TaskId(u64);
 
+impl TaskId {
+    pub const fn new(id: u64) -> Self {
+        Self(id)
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+/// Task switches in interrupt handlers must be at most `max` levels deep, see
+/// `interrupts::InterruptLevel`.
+fn assert_interrupt_depth(max: u32) {
+    let depth = interrupts::interrupt_depth();
+    assert!(
+        depth <= max,
+        "Task switch in an interrupt handler at depth {}",
+        depth
+    );
+}
+
+/// The ID of the task running on the current CPU. This is `None` until the first task switch on
+/// the CPU.
+pub fn current_id() -> Option<TaskId> {
+    let _cli = Cli::new();
+    let state = Cpu::current().state().lock();
+    state.running_task.as_ref().map(|task| task.id())
+}
+
+/// The affinity mask that allows a task to run on every CPU.
+pub const AFFINITY_ALL: u64 = !0;
+
+/// The affinity mask that only contains the specified CPU.
+pub fn affinity_of(cpu: Cpu) -> u64 {
+    let index = cpu.index();
+    assert!(index < 64, "CPU index out of range of the affinity mask");
+    1 << index
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum AffinityError {
+    EmptyMask,
+    /// The mask contains no existing CPU.
+    NoSuchCpu,
+    UnknownTask,
+}
+
 #[derive(Debug)]
 pub struct Task(Box<TaskData>);
 
@@ -287,6 +837,9 @@ impl Task {
         Self(Box::new(TaskData {
             id,
             priority,
+            affinity: AFFINITY_ALL,
+            woken_at: None,
+            supervision: None,
             stack,
             ctx: UnsafeCell::new(ctx),
         }))
@@ -297,6 +850,9 @@ impl Task {
         Self(Box::new(TaskData {
             id,
             priority,
+            affinity: AFFINITY_ALL,
+            woken_at: None,
+            supervision: None,
             stack: Default::default(),
             ctx: UnsafeCell::new(Context::uninitialized()),
         }))
@@ -310,6 +866,18 @@ impl Task {
         self.0.priority
     }
 
+    pub fn nice(&self) -> i8 {
+        self.priority().to_nice()
+    }
+
+    pub fn affinity(&self) -> u64 {
+        self.0.affinity
+    }
+
+    fn runs_on(&self, cpu_affinity: u64) -> bool {
+        (self.0.affinity & cpu_affinity) != 0
+    }
+
     fn ctx(&self) -> &UnsafeCell<Context> {
         &self.0.ctx
     }
@@ -319,6 +887,9 @@ impl Task {
 struct TaskData {
     id: TaskId,
     priority: Priority,
+    affinity: u64,           // bitmap over the indices of Cpu::list()
+    woken_at: Option<usize>, // ticks when the task is made runnable from pending
+    supervision: Option<Supervision>,
     #[allow(dead_code)]
     stack: Box<[u8]>,
     ctx: UnsafeCell<Context>,
@@ -343,6 +914,118 @@ extern "C" fn task_init(f: extern "C" fn(u64) -> !, _: TaskId, task_arg: u64) ->
     f(task_arg)
 }
 
+/// How a supervised task is restarted, see `TaskScheduler::add_supervised`.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct RestartPolicy {
+    /// The task is given up when it is killed after this number of restarts.
+    pub max_restarts: u32,
+    /// Ticks to wait before the first restart. The wait is doubled on each restart.
+    pub backoff: usize,
+}
+
+impl RestartPolicy {
+    pub const DEFAULT: Self = Self {
+        max_restarts: 5,
+        backoff: TIMER_FREQ / 10,
+    };
+
+    /// Ticks to wait before restarting a task restarted `restarts` times so far.
+    pub fn backoff_ticks(self, restarts: u32) -> usize {
+        self.backoff.saturating_mul(1 << restarts.min(16))
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Supervision {
+    entry_point: extern "C" fn(u64) -> !,
+    entry_arg: u64,
+    policy: RestartPolicy,
+    restarts: u32,
+}
+
+/// A supervised task killed by a panic.
+#[derive(Debug)]
+struct Oops {
+    id: TaskId,
+    priority: Priority,
+    supervision: Supervision,
+    message: String,
+}
+
+/// Called on panic. If the current task is supervised and the panic can be recovered, that is,
+/// the task is not in an interrupt handler and does not hold any spin locks, the task is killed
+/// and then restarted by the supervisor. Otherwise this function returns.
+///
+/// Locks of `sync::mutex::Mutex` held by the task are never released. Supervised tasks should
+/// not panic while holding them.
+pub fn oops(info: &PanicInfo) {
+    if SCHEDULER.get().is_none() || !x64::interrupts::are_enabled() {
+        return;
+    }
+    let (id, priority, supervision) = match Cpu::current().state().try_lock() {
+        Some(state) if state.thread_state.ncli == 0 => match state.running_task {
+            Some(ref task) => (task.id(), task.priority(), task.0.supervision),
+            None => return,
+        },
+        _ => return,
+    };
+    let supervision = match supervision {
+        Some(supervision) => supervision,
+        None => return,
+    };
+    let oops = Oops {
+        id,
+        priority,
+        supervision,
+        message: format!("{}", info),
+    };
+    if OOPSES.try_enqueue(oops).is_ok() {
+        scheduler().exit();
+    }
+}
+
+/// The timeouts task, which processes the expired timeouts on behalf of the timer handler. Every
+/// entry to the scheduler from a task processes the expired timeouts, but the timer handler only
+/// wakes this task, so that the time spent in the handler does not grow with the number of
+/// expiring timeouts. Thus a timeout fires at the first entry at or after its deadline: a task
+/// entering the scheduler, or this task, which is woken by the preemption at the deadline tick.
+extern "C" fn process_timeouts(_: u64) -> ! {
+    loop {
+        // The timeouts are processed on the entry to the scheduler
+        scheduler().switch(|| (Some(Switch::Blocked(TIMEOUTS_CHAN, None)), ()), 0);
+    }
+}
+
+/// The supervisor task, which restarts supervised tasks killed by oopses.
+extern "C" fn supervise(_: u64) -> ! {
+    loop {
+        let oops = OOPSES.dequeue();
+        error!("task: Oops in task {}: {}", oops.id.0, oops.message);
+        let supervision = oops.supervision;
+        if supervision.policy.max_restarts <= supervision.restarts {
+            error!(
+                "task: Task {} is given up after {} restarts",
+                oops.id.0, supervision.restarts
+            );
+            continue;
+        }
+        // Wait for the task to switch away, since a new task may share the state with it
+        while scheduler().tasks().iter().any(|info| info.id == oops.id) {
+            scheduler().sleep(1);
+        }
+        scheduler().sleep(supervision.policy.backoff_ticks(supervision.restarts));
+        let supervision = Supervision {
+            restarts: supervision.restarts + 1,
+            ..supervision
+        };
+        let id = scheduler().add_supervised_with(oops.priority, supervision);
+        warn!(
+            "task: Task {} is restarted as task {} ({}/{})",
+            oops.id.0, id.0, supervision.restarts, supervision.policy.max_restarts
+        );
+    }
+}
+
 #[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy, Hash)]
 pub enum Priority {
     L0,
@@ -361,7 +1044,422 @@ impl Priority {
         }
     }
 
+    /// Map a Unix-like nice value (-20 to 19, lower is prioritized) to a priority.
+    pub fn from_nice(nice: i8) -> Self {
+        match nice {
+            i8::MIN..=-11 => Self::L3,
+            -10..=0 => Self::L2,
+            1..=10 => Self::L1,
+            11..=i8::MAX => Self::L0,
+        }
+    }
+
+    /// The nice value at the middle of the range mapped to this priority.
+    pub fn to_nice(self) -> i8 {
+        match self {
+            Self::L0 => 15,
+            Self::L1 => 5,
+            Self::L2 => -5,
+            Self::L3 => -15,
+        }
+    }
+
+    /// All priorities from the lowest to the highest.
+    pub fn iter() -> impl Iterator<Item = Self> {
+        [Self::L0, Self::L1, Self::L2, Self::L3].into_iter()
+    }
+
     pub const MIN: Self = Self::L0;
     pub const MAX: Self = Self::L3;
     pub const SIZE: usize = 4;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::format;
+    use core::sync::atomic::AtomicBool;
+    use log::info;
+
+    #[test_case]
+    fn test_wait_channel() {
+        info!("TESTING task::test_wait_channel");
+        let a = scheduler().issue_wait_channel();
+        let b = scheduler().issue_wait_channel();
+        assert_ne!(a, b);
+        assert_eq!(a.domain(), ChannelDomain::Issued);
+
+        let x = 0u64;
+        let m = WaitChannel::from_ptr_index(ChannelDomain::Mutex, &x, 0);
+        let q = WaitChannel::from_ptr_index(ChannelDomain::Queue, &x, 0);
+        assert_ne!(m, q);
+        assert_ne!(q, WaitChannel::from_ptr_index(ChannelDomain::Queue, &x, 1));
+        assert_ne!(a, WaitChannel::scoped(ChannelDomain::Mutex, a.1));
+
+        // Names do not take part in the identity
+        let n = scheduler().issue_named_wait_channel("test.named");
+        assert_eq!(n.name(), Some("test.named"));
+        assert_eq!(n, WaitChannel::scoped(ChannelDomain::Issued, n.1));
+        assert_eq!(format!("{}", n), "test.named");
+        assert_eq!(a.name(), None);
+    }
+
+    #[test_case]
+    fn test_nice() {
+        info!("TESTING task::test_nice");
+        assert_eq!(Priority::from_nice(-20), Priority::L3);
+        assert_eq!(Priority::from_nice(-11), Priority::L3);
+        assert_eq!(Priority::from_nice(-10), Priority::L2);
+        assert_eq!(Priority::from_nice(0), Priority::L2);
+        assert_eq!(Priority::from_nice(1), Priority::L1);
+        assert_eq!(Priority::from_nice(10), Priority::L1);
+        assert_eq!(Priority::from_nice(11), Priority::L0);
+        assert_eq!(Priority::from_nice(19), Priority::L0);
+        for p in Priority::iter() {
+            assert_eq!(Priority::from_nice(p.to_nice()), p);
+        }
+        assert_eq!(Priority::iter().count(), Priority::SIZE);
+        assert!(!scheduler().renice(TaskId(u64::MAX), 0));
+    }
+
+    static RENICE_STOP: AtomicBool = AtomicBool::new(false);
+    static RENICE_EXITED: AtomicBool = AtomicBool::new(false);
+
+    extern "C" fn switch_repeatedly(_: u64) -> ! {
+        while !RENICE_STOP.load(Ordering::SeqCst) {
+            scheduler().r#yield();
+        }
+        RENICE_EXITED.store(true, Ordering::SeqCst);
+        scheduler().exit()
+    }
+
+    #[test_case]
+    fn test_renice_switching_task() {
+        info!("TESTING task::test_renice_switching_task");
+        let id = scheduler().add(Priority::MIN, switch_repeatedly, 0);
+        for i in 0..1000 {
+            // The task is always found, even while it is in the middle of `switch`
+            assert!(scheduler().renice(id, if i % 2 == 0 { 19 } else { 10 }));
+            if i % 100 == 0 {
+                scheduler().sleep(1);
+            }
+        }
+        RENICE_STOP.store(true, Ordering::SeqCst);
+        while !RENICE_EXITED.load(Ordering::SeqCst) {
+            scheduler().sleep(1);
+        }
+    }
+
+    static OBSERVED_LAPIC_ID: AtomicU64 = AtomicU64::new(u64::MAX);
+    static MISPLACED: AtomicU64 = AtomicU64::new(0);
+    static RECORDER_STOP: AtomicBool = AtomicBool::new(false);
+    static RECORDER_EXITED: AtomicU64 = AtomicU64::new(0);
+
+    extern "C" fn record_lapic_id(expected: u64) -> ! {
+        while !RECORDER_STOP.load(Ordering::SeqCst) {
+            let id = Cpu::current().lapic_id().unwrap_or(0) as u64;
+            OBSERVED_LAPIC_ID.store(id, Ordering::SeqCst);
+            if id != expected {
+                MISPLACED.fetch_add(1, Ordering::SeqCst);
+            }
+            scheduler().sleep(10);
+        }
+        RECORDER_EXITED.fetch_add(1, Ordering::SeqCst);
+        scheduler().exit()
+    }
+
+    /// Spawn a `record_lapic_id` task pinned to `cpu`. The task runs until `stop_recorder`.
+    fn spawn_recorder(priority: Priority, cpu: Cpu) -> TaskId {
+        RECORDER_STOP.store(false, Ordering::SeqCst);
+        let expected = cpu.lapic_id().unwrap_or(0) as u64;
+        scheduler().add_pinned(priority, record_lapic_id, expected, cpu)
+    }
+
+    fn stop_recorder() {
+        let exited = RECORDER_EXITED.load(Ordering::SeqCst);
+        RECORDER_STOP.store(true, Ordering::SeqCst);
+        while RECORDER_EXITED.load(Ordering::SeqCst) == exited {
+            scheduler().sleep(1);
+        }
+    }
+
+    #[test_case]
+    fn test_pinned_task() {
+        info!("TESTING task::test_pinned_task");
+        // Pin to CPU 1 if exists (-smp 2), otherwise to the only CPU
+        let cpu = Cpu::list().nth(1).unwrap_or_else(Cpu::boot_strap);
+        let expected = cpu.lapic_id().unwrap_or(0) as u64;
+        let id = spawn_recorder(Priority::MAX, cpu);
+        for _ in 0..10 {
+            scheduler().sleep(5);
+        }
+        assert_eq!(MISPLACED.load(Ordering::SeqCst), 0);
+        let observed = OBSERVED_LAPIC_ID.load(Ordering::SeqCst);
+        if cpu.index() == Cpu::current().index() {
+            assert_eq!(observed, expected);
+        } else {
+            assert!(observed == u64::MAX || observed == expected);
+        }
+        // The task is either running on the CPU or sleeping, but it is always found
+        for _ in 0..100 {
+            assert_eq!(scheduler().set_affinity(id, affinity_of(cpu)), Ok(()));
+        }
+        stop_recorder();
+    }
+
+    #[test_case]
+    fn test_affinity_rejects_empty_mask() {
+        info!("TESTING task::test_affinity_rejects_empty_mask");
+        let id = spawn_recorder(Priority::MIN, Cpu::boot_strap());
+        assert_eq!(
+            scheduler().set_affinity(id, 0),
+            Err(AffinityError::EmptyMask)
+        );
+        // CPU indices are contiguous from 0
+        let cpus = Cpu::list().count();
+        if cpus < 64 {
+            assert_eq!(
+                scheduler().set_affinity(id, AFFINITY_ALL << cpus),
+                Err(AffinityError::NoSuchCpu)
+            );
+        }
+        assert_eq!(
+            scheduler().set_affinity(TaskId(u64::MAX), AFFINITY_ALL),
+            Err(AffinityError::UnknownTask)
+        );
+        assert_eq!(affinity_of(Cpu::boot_strap()), 1);
+        stop_recorder();
+    }
+
+    const NAMED_CHANNEL: WaitChannel =
+        WaitChannel::scoped(ChannelDomain::Issued, u64::MAX).named("test.blocked");
+
+    extern "C" fn block_on_named_channel(_: u64) -> ! {
+        loop {
+            scheduler().switch(|| (Some(Switch::Blocked(NAMED_CHANNEL, None)), ()), 0);
+        }
+    }
+
+    #[test_case]
+    fn test_task_info() {
+        info!("TESTING task::test_task_info");
+        let id = scheduler().add(Priority::MAX, block_on_named_channel, 0);
+        scheduler().sleep(10);
+
+        let tasks = scheduler().tasks();
+        assert!(tasks.windows(2).all(|w| w[0].id < w[1].id));
+        assert!(tasks
+            .iter()
+            .any(|info| matches!(info.state, TaskState::Running(_))));
+        let info = tasks.iter().find(|info| info.id == id).unwrap();
+        match info.state {
+            TaskState::Blocked(chan, _, None) => assert_eq!(chan.name(), Some("test.blocked")),
+            state => panic!("Unexpected state: {:?}", state),
+        }
+        let wait_ticks = info.wait_ticks().unwrap();
+        assert!(0 < wait_ticks);
+        assert_eq!(
+            format!("{}", info),
+            format!("blocked on test.blocked for {} ticks", wait_ticks)
+        );
+        assert!(info.is_stuck(wait_ticks));
+        assert!(!info.is_stuck(wait_ticks + 1));
+    }
+
+    #[test_case]
+    fn test_restart_policy() {
+        info!("TESTING task::test_restart_policy");
+        let policy = RestartPolicy {
+            max_restarts: 3,
+            backoff: 10,
+        };
+        assert_eq!(policy.backoff_ticks(0), 10);
+        assert_eq!(policy.backoff_ticks(1), 20);
+        assert_eq!(policy.backoff_ticks(3), 80);
+        assert_eq!(policy.backoff_ticks(u32::MAX), 10 << 16);
+    }
+
+    #[test_case]
+    fn test_latency_bucket() {
+        info!("TESTING task::test_latency_bucket");
+        assert_eq!(latency_bucket(0), 0);
+        assert_eq!(latency_bucket(1), 1);
+        assert_eq!(latency_bucket(2), 2);
+        assert_eq!(latency_bucket(3), 2);
+        assert_eq!(latency_bucket(4), 3);
+        assert_eq!(latency_bucket(usize::MAX), LATENCY_BUCKETS - 1);
+        for b in 0..LATENCY_BUCKETS {
+            let range = LatencyStats::bucket_range(b);
+            assert_eq!(latency_bucket(range.start), b);
+            assert_eq!(latency_bucket(range.end - 1), b);
+        }
+    }
+
+    const SLEEPS: u64 = 10;
+    static SLEEPER_EXITED: AtomicU64 = AtomicU64::new(0);
+
+    extern "C" fn sleep_repeatedly(n: u64) -> ! {
+        for _ in 0..n {
+            scheduler().sleep(1);
+        }
+        SLEEPER_EXITED.fetch_add(1, Ordering::SeqCst);
+        scheduler().exit()
+    }
+
+    #[test_case]
+    fn test_latency_stats() {
+        info!("TESTING task::test_latency_stats");
+        let before = latency_stats();
+        let exited = SLEEPER_EXITED.load(Ordering::SeqCst);
+        scheduler().add(Priority::MAX, sleep_repeatedly, SLEEPS);
+        while SLEEPER_EXITED.load(Ordering::SeqCst) == exited {
+            scheduler().sleep(1);
+        }
+        let after = latency_stats();
+
+        // Histograms accumulate monotonically
+        for (b, a) in before.histograms.iter().zip(after.histograms.iter()) {
+            for (b, a) in b.iter().zip(a.iter()) {
+                assert!(b <= a);
+            }
+        }
+        // Every wakeup of the sleeper is recorded once it is switched to, whatever it took
+        let max = Priority::MAX.index();
+        let count = |stats: &LatencyStats| stats.histograms[max].iter().sum::<u64>();
+        assert!(count(&before) + SLEEPS <= count(&after));
+        assert!(1 <= after.max_runnable[max]);
+    }
+
+    const FLOAT_WORKERS: u64 = 3;
+    /// Context switches to observe while the workers compute.
+    const FLOAT_SWITCHES: u64 = 2000;
+    static FLOAT_ITERATIONS: AtomicU64 = AtomicU64::new(0);
+    static FLOAT_MISMATCHES: AtomicU64 = AtomicU64::new(0);
+    static FLOAT_STOP: AtomicBool = AtomicBool::new(false);
+    static FLOAT_EXITED: AtomicU64 = AtomicU64::new(0);
+
+    /// Floating-point work similar to `PrettySize`, long enough to be preempted in the middle.
+    fn float_work(seed: u64) -> (u64, String) {
+        let mut sum = 0.0f64;
+        for i in 1..200 {
+            let x = (seed + i) as f64;
+            sum += 1.0 / (x * x) + x / 1024.0;
+        }
+        (sum.to_bits(), format!("{:.2}", sum / (1024.0 * 1024.0)))
+    }
+
+    extern "C" fn compute_floats(seed: u64) -> ! {
+        let expected = float_work(seed);
+        while !FLOAT_STOP.load(Ordering::SeqCst) {
+            // Preempted by the timer handler without yielding explicitly
+            if float_work(seed) != expected || interrupts::interrupt_depth() != 0 {
+                FLOAT_MISMATCHES.fetch_add(1, Ordering::SeqCst);
+            }
+            FLOAT_ITERATIONS.fetch_add(1, Ordering::SeqCst);
+        }
+        FLOAT_EXITED.fetch_add(1, Ordering::SeqCst);
+        scheduler().exit()
+    }
+
+    #[test_case]
+    fn test_preemption_keeps_state() {
+        info!("TESTING task::test_preemption_keeps_state");
+        assert_eq!(interrupts::interrupt_depth(), 0);
+        let since = scheduler().switch_count();
+        for seed in 0..FLOAT_WORKERS {
+            scheduler().add(Priority::MIN, compute_floats, seed * 1000);
+        }
+        let deadline = interrupts::deadline_after(30 * TIMER_FREQ);
+        while scheduler().switch_count() < since + FLOAT_SWITCHES && !deadline.is_expired() {
+            scheduler().sleep(10);
+        }
+        FLOAT_STOP.store(true, Ordering::SeqCst);
+        while FLOAT_EXITED.load(Ordering::SeqCst) < FLOAT_WORKERS {
+            scheduler().sleep(1);
+        }
+        assert!(since + FLOAT_SWITCHES <= scheduler().switch_count());
+        assert!(FLOAT_WORKERS <= FLOAT_ITERATIONS.load(Ordering::SeqCst));
+        assert_eq!(FLOAT_MISMATCHES.load(Ordering::SeqCst), 0);
+        assert_eq!(interrupts::interrupt_depth(), 0);
+    }
+
+    #[test_case]
+    fn test_elapse() {
+        info!("TESTING task::test_elapse");
+        let mut queue = TaskQueue::new();
+        let n = 2 * MAX_TIMEOUTS_PER_ELAPSE + 8;
+        for i in 0..n {
+            let id = queue.issue_pending_id();
+            let task = Task::new_current(TaskId(u64::MAX - i as u64), Priority::L0);
+            let deadline = i % 4 + 1;
+            let wait = Wait {
+                chan: None,
+                since: 0,
+                deadline: Some(deadline),
+            };
+            queue.pending_tasks.insert(id, (task, wait));
+            queue.timeouts.push(Reverse((deadline, id, None)));
+        }
+        let runnable = |q: &TaskQueue| q.runnable_tasks.iter().map(|t| t.len()).sum::<usize>();
+
+        queue.elapse(0);
+        assert_eq!(runnable(&queue), 0);
+        queue.elapse(1);
+        assert_eq!(runnable(&queue), (n + 3) / 4);
+        assert_eq!(queue.elapsed, 1);
+
+        // The catch-up after a long gap is bounded, and continued by the next entry
+        queue.elapse(4);
+        assert_eq!(runnable(&queue), (n + 3) / 4 + MAX_TIMEOUTS_PER_ELAPSE);
+        assert_eq!(queue.elapsed, 1);
+        queue.elapse(4);
+        assert_eq!(runnable(&queue), n);
+        assert_eq!(queue.elapsed, 4);
+        assert!(queue.pending_tasks.is_empty() && queue.timeouts.is_empty());
+    }
+
+    const SLEEPERS: u64 = 4;
+    static SLEEP_MAX_LATE: AtomicU64 = AtomicU64::new(0);
+    static SLEEP_EARLY: AtomicU64 = AtomicU64::new(0);
+    static SLEEP_DONE: AtomicU64 = AtomicU64::new(0);
+
+    extern "C" fn sleep_and_measure(ticks_to_sleep: u64) -> ! {
+        for _ in 0..10 {
+            let deadline = ticks() + ticks_to_sleep as usize;
+            scheduler().sleep(ticks_to_sleep as usize);
+            let now = ticks();
+            if now < deadline {
+                SLEEP_EARLY.fetch_add(1, Ordering::SeqCst);
+            }
+            let late = now.saturating_sub(deadline) as u64;
+            SLEEP_MAX_LATE.fetch_max(late, Ordering::SeqCst);
+        }
+        SLEEP_DONE.fetch_add(1, Ordering::SeqCst);
+        scheduler().exit()
+    }
+
+    /// Keep the CPU busy without entering the scheduler, thus the timeouts are only processed by
+    /// the timeouts task woken by the timer handler.
+    extern "C" fn spin_until_sleepers_done(_: u64) -> ! {
+        while SLEEP_DONE.load(Ordering::SeqCst) < SLEEPERS {
+            core::hint::spin_loop();
+        }
+        scheduler().exit()
+    }
+
+    #[test_case]
+    fn test_sleep_wakeup() {
+        info!("TESTING task::test_sleep_wakeup");
+        for i in 0..SLEEPERS {
+            scheduler().add(Priority::MAX, sleep_and_measure, i + 1);
+        }
+        scheduler().add(Priority::MAX, spin_until_sleepers_done, 0);
+        while SLEEP_DONE.load(Ordering::SeqCst) < SLEEPERS {
+            scheduler().sleep(5);
+        }
+        // Woken by the timeouts task at the deadline tick. One more tick is allowed since the
+        // sleep may start in the tick after the deadline is computed.
+        assert_eq!(SLEEP_EARLY.load(Ordering::SeqCst), 0);
+        assert!(SLEEP_MAX_LATE.load(Ordering::SeqCst) <= 1);
+    }
+}
@@ -0,0 +1,408 @@
+//! Deferred work run by a worker task.
+//!
+//! Features that need to run something later or periodically off the hot path schedule work
+//! items here instead of spawning a dedicated task each. A work item is a function and its
+//! argument, run in the worker task where blocking (such as volume I/O) is allowed.
+//! Scheduling never blocks, thus it is also allowed in interrupt handlers: when the queue is
+//! full, the work item is dropped and counted in `WorkQueueStats::dropped`.
+
+use super::{scheduler, Priority};
+use crate::interrupts::{deadline_after, ticks, Deadline};
+use crate::sync::queue::Queue;
+use alloc::collections::BinaryHeap;
+use core::cmp::{Ordering as CmpOrdering, Reverse};
+use core::sync::atomic::{AtomicU64, Ordering};
+use derive_new::new;
+use log::trace;
+
+/// Number of work items that can be queued before the worker picks them up.
+const CAPACITY: usize = 64;
+
+/// The priority of the worker task unless specified by `initialize`.
+pub const DEFAULT_PRIORITY: Priority = Priority::L2;
+
+static WORKQUEUE: WorkQueue<CAPACITY> = WorkQueue::named("task.workqueue");
+
+/// Start the worker task of the kernel work queue. Work items scheduled before this are kept in
+/// the queue and run once the worker starts.
+pub fn initialize(priority: Priority) {
+    trace!("INITIALIZING Work queue");
+    let arg = &WORKQUEUE as *const WorkQueue<CAPACITY> as u64;
+    scheduler().add(priority, run_worker::<CAPACITY>, arg);
+}
+
+/// Run `f(arg)` in the worker task as soon as possible.
+pub fn schedule(f: fn(u64), arg: u64) -> bool {
+    WORKQUEUE.schedule(f, arg)
+}
+
+/// Run `f(arg)` in the worker task after `delay` ticks.
+pub fn schedule_delayed(f: fn(u64), arg: u64, delay: usize) -> bool {
+    WORKQUEUE.schedule_delayed(f, arg, delay)
+}
+
+/// Run `f(arg)` in the worker task every `period` ticks while it returns true.
+pub fn schedule_repeating(f: fn(u64) -> bool, arg: u64, period: usize) -> bool {
+    WORKQUEUE.schedule_repeating(f, arg, period)
+}
+
+pub fn stats() -> WorkQueueStats {
+    WORKQUEUE.stats()
+}
+
+/// Counters of a work queue since it is created.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub struct WorkQueueStats {
+    /// Work items accepted by the queue. A repeating work item is counted once.
+    pub scheduled: u64,
+    /// Runs of work items, including each run of repeating work items.
+    pub executed: u64,
+    /// Work items dropped since the queue was full.
+    pub dropped: u64,
+}
+
+/// A bounded queue of work items drained by a worker task, see `run`.
+pub struct WorkQueue<const N: usize> {
+    queue: Queue<Work, N>,
+    scheduled: AtomicU64,
+    executed: AtomicU64,
+    dropped: AtomicU64,
+}
+
+impl<const N: usize> WorkQueue<N> {
+    pub const fn named(name: &'static str) -> Self {
+        Self {
+            queue: Queue::named(name),
+            scheduled: AtomicU64::new(0),
+            executed: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    /// Run `f(arg)` as soon as possible. Work items scheduled by this method run in the order
+    /// they are scheduled. Returns false if the work item is dropped since the queue is full.
+    pub fn schedule(&self, f: fn(u64), arg: u64) -> bool {
+        self.submit(Work::new(Job::Once(f), arg, None))
+    }
+
+    /// Run `f(arg)` after `delay` ticks from now.
+    pub fn schedule_delayed(&self, f: fn(u64), arg: u64, delay: usize) -> bool {
+        self.submit(Work::new(Job::Once(f), arg, Some(deadline_after(delay))))
+    }
+
+    /// Run `f(arg)` every `period` ticks from now while it returns true. Runs missed by a busy
+    /// worker are skipped rather than caught up.
+    pub fn schedule_repeating(&self, f: fn(u64) -> bool, arg: u64, period: usize) -> bool {
+        assert!(period != 0, "period of a repeating work must not be 0");
+        let job = Job::Repeating(f, period);
+        self.submit(Work::new(job, arg, Some(deadline_after(period))))
+    }
+
+    fn submit(&self, work: Work) -> bool {
+        match self.queue.try_enqueue(work) {
+            Ok(()) => {
+                self.scheduled.fetch_add(1, Ordering::Relaxed);
+                true
+            }
+            Err(_) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                false
+            }
+        }
+    }
+
+    pub fn stats(&self) -> WorkQueueStats {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        WorkQueueStats {
+            scheduled: load(&self.scheduled),
+            executed: load(&self.executed),
+            dropped: load(&self.dropped),
+        }
+    }
+
+    /// Drain the queue forever. Delayed work items are held by the worker until their deadlines,
+    /// which are waited for by the timeout of dequeuing.
+    fn run(&self) -> ! {
+        let mut delayed = BinaryHeap::<Reverse<Delayed>>::new();
+        let mut seq = 0;
+        loop {
+            let now = ticks();
+            while matches!(delayed.peek(), Some(Reverse(d)) if d.work.is_due(now)) {
+                let Reverse(Delayed { work, .. }) = delayed.pop().unwrap();
+                if let Some(work) = self.execute(work, now) {
+                    delayed.push(Reverse(Delayed::new(work, &mut seq)));
+                }
+            }
+
+            let work = match delayed.peek().map(|Reverse(d)| d.work.deadline.unwrap()) {
+                // Running the work items above may have taken ticks
+                Some(deadline) if deadline.is_expired() => continue,
+                Some(deadline) => match self.queue.dequeue_timeout(deadline.remaining()) {
+                    Some(work) => work,
+                    None => continue,
+                },
+                None => self.queue.dequeue(),
+            };
+            let work = if work.is_due(ticks()) {
+                self.execute(work, ticks())
+            } else {
+                Some(work)
+            };
+            if let Some(work) = work {
+                delayed.push(Reverse(Delayed::new(work, &mut seq)));
+            }
+        }
+    }
+
+    /// Run the work item. Returns the re-armed work item if it is repeating.
+    fn execute(&self, work: Work, now: usize) -> Option<Work> {
+        self.executed.fetch_add(1, Ordering::Relaxed);
+        match work.job {
+            Job::Once(f) => {
+                f(work.arg);
+                None
+            }
+            Job::Repeating(f, period) => {
+                if !f(work.arg) {
+                    return None;
+                }
+                let mut deadline =
+                    Deadline::after(work.deadline.map_or(now, Deadline::ticks), period);
+                while deadline.is_expired_at(now) && deadline.ticks() != usize::MAX {
+                    deadline = Deadline::after(deadline.ticks(), period);
+                }
+                if deadline.ticks() == usize::MAX {
+                    // The deadline saturated and would be due forever, so the work item is parked
+                    trace!("workqueue: A repeating work item reached the end of the ticks");
+                    return None;
+                }
+                Some(Work {
+                    deadline: Some(deadline),
+                    ..work
+                })
+            }
+        }
+    }
+}
+
+extern "C" fn run_worker<const N: usize>(arg: u64) -> ! {
+    let wq = unsafe { &*(arg as *const WorkQueue<N>) };
+    wq.run()
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Job {
+    Once(fn(u64)),
+    /// The function and the period in ticks.
+    Repeating(fn(u64) -> bool, usize),
+}
+
+#[derive(Debug, Clone, Copy, new)]
+struct Work {
+    job: Job,
+    arg: u64,
+    /// When to run the work item. `None` means as soon as possible.
+    deadline: Option<Deadline>,
+}
+
+impl Work {
+    fn is_due(&self, now: usize) -> bool {
+        self.deadline.map_or(true, |d| d.is_expired_at(now))
+    }
+}
+
+/// A work item waiting for its deadline. Work items with the same deadline are ordered by `seq`,
+/// the order in which they are held by the worker.
+#[derive(Debug)]
+struct Delayed {
+    work: Work,
+    seq: u64,
+}
+
+impl Delayed {
+    fn new(work: Work, seq: &mut u64) -> Self {
+        *seq += 1;
+        Self { work, seq: *seq }
+    }
+
+    fn key(&self) -> (Option<Deadline>, u64) {
+        (self.work.deadline, self.seq)
+    }
+}
+
+impl PartialEq for Delayed {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for Delayed {}
+
+impl PartialOrd for Delayed {
+    fn partial_cmp(&self, other: &Self) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Delayed {
+    fn cmp(&self, other: &Self) -> CmpOrdering {
+        self.key().cmp(&other.key())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sync::spin::Spin;
+    use alloc::boxed::Box;
+    use alloc::vec::Vec;
+    use log::info;
+
+    static LOG: Spin<Vec<(u64, usize)>> = Spin::new(Vec::new());
+
+    fn record(arg: u64) {
+        LOG.lock().push((arg, ticks()));
+    }
+
+    fn record_three_times(arg: u64) -> bool {
+        let mut log = LOG.lock();
+        log.push((arg, ticks()));
+        log.len() < 3
+    }
+
+    fn start<const N: usize>(wq: &'static WorkQueue<N>) {
+        let arg = wq as *const WorkQueue<N> as u64;
+        scheduler().add(Priority::MAX, run_worker::<N>, arg);
+    }
+
+    static WORKERS_EXITED: AtomicU64 = AtomicU64::new(0);
+
+    /// A work item that ends the worker running it, see `stop`.
+    fn exit_worker(_: u64) {
+        WORKERS_EXITED.fetch_add(1, Ordering::SeqCst);
+        scheduler().exit()
+    }
+
+    /// Stop the worker started by `start`, after the work items scheduled so far.
+    fn stop<const N: usize>(wq: &WorkQueue<N>) {
+        let exited = WORKERS_EXITED.load(Ordering::SeqCst);
+        assert!(wq.schedule(exit_worker, 0));
+        while WORKERS_EXITED.load(Ordering::SeqCst) == exited {
+            scheduler().sleep(1);
+        }
+    }
+
+    fn wait_executed<const N: usize>(wq: &WorkQueue<N>, count: u64) {
+        while wq.stats().executed < count {
+            scheduler().sleep(1);
+        }
+    }
+
+    #[test_case]
+    fn test_fifo() {
+        info!("TESTING task::workqueue::test_fifo");
+        LOG.lock().clear();
+        let wq = Box::leak(Box::new(WorkQueue::<16>::named("test")));
+        start(wq);
+        for i in 0..10 {
+            assert!(wq.schedule(record, i));
+        }
+        wait_executed(wq, 10);
+        let order = LOG.lock().iter().map(|(arg, _)| *arg).collect::<Vec<_>>();
+        assert_eq!(order, (0..10).collect::<Vec<_>>());
+        stop(wq);
+    }
+
+    #[test_case]
+    fn test_delayed() {
+        info!("TESTING task::workqueue::test_delayed");
+        LOG.lock().clear();
+        let wq = Box::leak(Box::new(WorkQueue::<16>::named("test")));
+        start(wq);
+        let t = ticks();
+        assert!(wq.schedule_delayed(record, 2, 10));
+        assert!(wq.schedule_delayed(record, 1, 5));
+        assert!(wq.schedule(record, 0));
+        wait_executed(wq, 3);
+        let log = LOG.lock().clone();
+        assert_eq!(
+            log.iter().map(|(arg, _)| *arg).collect::<Vec<_>>(),
+            [0, 1, 2]
+        );
+        for (arg, fired) in &log[1..] {
+            let deadline = t + *arg as usize * 5;
+            assert!(
+                (deadline..=deadline + 1).contains(fired),
+                "fired at {}, expected {}",
+                fired,
+                deadline
+            );
+        }
+        stop(wq);
+    }
+
+    #[test_case]
+    fn test_repeating() {
+        info!("TESTING task::workqueue::test_repeating");
+        LOG.lock().clear();
+        let wq = Box::leak(Box::new(WorkQueue::<16>::named("test")));
+        start(wq);
+        let t = ticks();
+        assert!(wq.schedule_repeating(record_three_times, 0, 4));
+        wait_executed(wq, 3);
+        scheduler().sleep(10);
+        let log = LOG.lock().clone();
+        assert_eq!(log.len(), 3);
+        for (i, (_, fired)) in log.iter().enumerate() {
+            let deadline = t + (i + 1) * 4;
+            assert!((deadline..=deadline + 1).contains(fired));
+        }
+        assert_eq!(wq.stats().executed, 3);
+        stop(wq);
+    }
+
+    #[test_case]
+    fn test_repeating_saturated() {
+        info!("TESTING task::workqueue::test_repeating_saturated");
+        fn always(_: u64) -> bool {
+            true
+        }
+        let wq = WorkQueue::<4>::named("test");
+        let work = |deadline| Work::new(Job::Repeating(always, 4), 0, Some(Deadline::at(deadline)));
+        let next = wq.execute(work(100), 100).unwrap();
+        assert_eq!(next.deadline, Some(Deadline::at(104)));
+
+        // Re-armed at the saturated deadline, which used to be re-armed again and again
+        assert!(wq.execute(work(usize::MAX - 2), usize::MAX - 2).is_none());
+        assert!(wq.execute(work(usize::MAX), usize::MAX).is_none());
+        assert_eq!(wq.stats().executed, 3);
+    }
+
+    #[test_case]
+    fn test_overflow() {
+        info!("TESTING task::workqueue::test_overflow");
+        LOG.lock().clear();
+        let wq = Box::leak(Box::new(WorkQueue::<4>::named("test")));
+        for i in 0..6 {
+            assert_eq!(wq.schedule(record, i), i < 4);
+        }
+        assert_eq!(
+            wq.stats(),
+            WorkQueueStats {
+                scheduled: 4,
+                executed: 0,
+                dropped: 2
+            }
+        );
+
+        // The accepted ones are still run in order once the worker starts
+        start(wq);
+        wait_executed(wq, 4);
+        let order = LOG.lock().iter().map(|(arg, _)| *arg).collect::<Vec<_>>();
+        assert_eq!(order, [0, 1, 2, 3]);
+        assert!(wq.schedule(record, 4));
+        wait_executed(wq, 5);
+        assert_eq!(wq.stats().dropped, 2);
+        stop(wq);
+    }
+}
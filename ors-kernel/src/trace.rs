@@ -0,0 +1,356 @@
+//! Lightweight event tracing.
+//!
+//! Events are recorded by `trace_event!` into per-CPU ring buffers as fixed-size binary records.
+//! Each category of events can be enabled or disabled at runtime. When a category is disabled,
+//! `trace_event!` costs a single branch on an atomic bitmask.
+
+use crate::config;
+use crate::cpu::Cpu;
+use crate::interrupts::ticks;
+use crate::x64::cpuid;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::arch::x86_64::_rdtsc;
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::sync::atomic::{fence, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use core::{mem, ptr, slice};
+use log::warn;
+use spin::Once;
+
+const RING_CAPACITY: usize = 4096; // 256KiB per CPU
+const PAYLOAD_SIZE: usize = 44;
+
+static ENABLED: AtomicU32 = AtomicU32::new(0);
+static RINGS: Once<Vec<Ring>> = Once::new();
+
+/// Record an event of the given category if the category is compiled in and enabled.
+#[allow(unused_macros)]
+macro_rules! trace_event {
+    ($category:expr, $( $t:tt )*) => {
+        if $crate::trace::is_compiled($category) && $crate::trace::is_enabled($category) {
+            $crate::trace::record($category, format_args!($( $t )*));
+        }
+    };
+}
+
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy, Hash)]
+pub enum Category {
+    Sched,
+    Block,
+    Irq,
+    Mutex,
+}
+
+impl Category {
+    pub const ALL: [Self; 4] = [Self::Sched, Self::Block, Self::Irq, Self::Mutex];
+
+    pub fn id(self) -> u8 {
+        self as u8
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        Self::ALL.get(id as usize).copied()
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Sched => "sched",
+            Self::Block => "block",
+            Self::Irq => "irq",
+            Self::Mutex => "mutex",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|c| c.name() == name)
+    }
+
+    fn bit(self) -> u32 {
+        1 << self.id()
+    }
+}
+
+/// Whether the category is in `config::TRACE_CATEGORIES`.
+pub const fn is_compiled(category: Category) -> bool {
+    let categories = config::TRACE_CATEGORIES;
+    let mut i = 0;
+    while i < categories.len() {
+        if categories[i] as u8 == category as u8 {
+            return true;
+        }
+        i += 1;
+    }
+    false
+}
+
+#[inline(always)]
+pub fn is_enabled(category: Category) -> bool {
+    (ENABLED.load(Ordering::Relaxed) & category.bit()) != 0
+}
+
+/// Enable the given categories in addition to the categories already enabled.
+/// Categories that are not compiled in are ignored.
+pub fn enable(categories: impl IntoIterator<Item = Category>) {
+    // Rings are allocated on the first use, since most of the time tracing is not used at all
+    RINGS.call_once(|| {
+        if !cpuid::info().features.invariant_tsc {
+            warn!("trace: TSC is not invariant, timestamps are in timer ticks");
+        }
+        Cpu::list().map(|_| Ring::new(RING_CAPACITY)).collect()
+    });
+    let mask = categories
+        .into_iter()
+        .filter(|c| is_compiled(*c))
+        .fold(0, |mask, c| mask | c.bit());
+    ENABLED.fetch_or(mask, Ordering::SeqCst);
+}
+
+pub fn disable_all() {
+    ENABLED.store(0, Ordering::SeqCst);
+}
+
+pub fn enabled_categories() -> impl Iterator<Item = Category> {
+    Category::ALL.into_iter().filter(|c| is_enabled(*c))
+}
+
+/// Called by `trace_event!`. Use the macro instead.
+pub fn record(category: Category, args: fmt::Arguments) {
+    let rings = match RINGS.get() {
+        Some(rings) => rings,
+        None => return,
+    };
+    let cpu = Cpu::current();
+    if let Some(ring) = rings.get(cpu.index()) {
+        // The CPU state may be locked by the code being traced, so we never wait for it
+        let task = cpu
+            .state()
+            .try_lock()
+            .and_then(|state| state.running_task.as_ref().map(|t| t.id().as_u64()))
+            .unwrap_or(Record::UNKNOWN_TASK);
+        let timestamp = timestamp();
+        ring.push(Record::new(
+            timestamp,
+            cpu.index() as u16,
+            task,
+            category,
+            args,
+        ));
+    }
+}
+
+/// Records of all CPUs are ordered by this, thus the TSC is used only if it is invariant.
+/// Otherwise timer ticks are used, which are coarse but shared by all CPUs.
+fn timestamp() -> u64 {
+    if cpuid::info().features.invariant_tsc {
+        unsafe { _rdtsc() }
+    } else {
+        ticks() as u64
+    }
+}
+
+/// The last `n` events of all CPUs, ordered by their timestamps.
+pub fn last_events(n: usize) -> Vec<Record> {
+    match RINGS.get() {
+        Some(rings) => merge(rings.iter(), n),
+        None => Vec::new(),
+    }
+}
+
+fn merge<'a>(rings: impl Iterator<Item = &'a Ring>, n: usize) -> Vec<Record> {
+    let mut records = rings.flat_map(|ring| ring.snapshot()).collect::<Vec<_>>();
+    records.sort_by_key(|r| r.timestamp);
+    let skip = records.len().saturating_sub(n);
+    records.drain(0..skip);
+    records
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct Record {
+    pub timestamp: u64, // TSC, or timer ticks if the TSC is not invariant
+    pub task: u64,
+    pub cpu: u16,
+    pub category: u8,
+    len: u8,
+    payload: [u8; PAYLOAD_SIZE],
+}
+
+impl Record {
+    pub const UNKNOWN_TASK: u64 = u64::MAX;
+
+    fn new(timestamp: u64, cpu: u16, task: u64, category: Category, args: fmt::Arguments) -> Self {
+        let mut w = PayloadWriter {
+            payload: [0; PAYLOAD_SIZE],
+            len: 0,
+        };
+        let _ = fmt::write(&mut w, args);
+        Self {
+            timestamp,
+            task,
+            cpu,
+            category: category.id(),
+            len: w.len as u8,
+            payload: w.payload,
+        }
+    }
+
+    pub fn payload(&self) -> &str {
+        // PayloadWriter always truncates at char boundaries
+        core::str::from_utf8(&self.payload[0..self.len as usize]).unwrap_or("")
+    }
+
+    /// The raw representation of this record, used to save records for offline analysis.
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self as *const Self as *const u8, mem::size_of::<Self>()) }
+    }
+}
+
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{:>16}] cpu{} ", self.timestamp, self.cpu)?;
+        match self.task {
+            Self::UNKNOWN_TASK => write!(f, "task-")?,
+            task => write!(f, "task{}", task)?,
+        }
+        match Category::from_id(self.category) {
+            Some(c) => write!(f, " {}: {}", c.name(), self.payload()),
+            None => write!(f, " ?: {}", self.payload()),
+        }
+    }
+}
+
+impl fmt::Debug for Record {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Record({})", self)
+    }
+}
+
+struct PayloadWriter {
+    payload: [u8; PAYLOAD_SIZE],
+    len: usize,
+}
+
+impl fmt::Write for PayloadWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut n = s.len().min(PAYLOAD_SIZE - self.len);
+        while !s.is_char_boundary(n) {
+            n -= 1;
+        }
+        self.payload[self.len..self.len + n].copy_from_slice(&s.as_bytes()[0..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+/// A ring buffer of records. Writers never block; readers skip records being overwritten.
+struct Ring {
+    slots: Box<[Slot]>,
+    head: AtomicUsize,
+}
+
+struct Slot {
+    seq: AtomicU64, // 1 + index of the record written in this slot, or 0 while writing
+    record: UnsafeCell<Record>,
+}
+
+unsafe impl Sync for Ring {}
+
+impl Ring {
+    fn new(capacity: usize) -> Self {
+        let empty = Record::new(0, 0, 0, Category::Sched, format_args!(""));
+        Self {
+            slots: (0..capacity)
+                .map(|_| Slot {
+                    seq: AtomicU64::new(0),
+                    record: UnsafeCell::new(empty),
+                })
+                .collect(),
+            head: AtomicUsize::new(0),
+        }
+    }
+
+    fn push(&self, record: Record) {
+        let i = self.head.fetch_add(1, Ordering::SeqCst);
+        let slot = &self.slots[i % self.slots.len()];
+        slot.seq.store(0, Ordering::SeqCst);
+        unsafe { ptr::write_volatile(slot.record.get(), record) };
+        slot.seq.store(i as u64 + 1, Ordering::Release);
+    }
+
+    /// Records that are currently in the ring, from the oldest to the newest.
+    fn snapshot(&self) -> Vec<Record> {
+        let head = self.head.load(Ordering::Acquire);
+        let start = head.saturating_sub(self.slots.len());
+        let mut records = Vec::with_capacity(head - start);
+        for i in start..head {
+            let slot = &self.slots[i % self.slots.len()];
+            let seq = slot.seq.load(Ordering::Acquire);
+            if seq != i as u64 + 1 {
+                continue; // being written or already overwritten
+            }
+            let record = unsafe { ptr::read_volatile(slot.record.get()) };
+            fence(Ordering::Acquire);
+            if slot.seq.load(Ordering::Relaxed) == seq {
+                records.push(record);
+            }
+        }
+        records
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::info;
+
+    fn record(timestamp: u64, cpu: u16) -> Record {
+        Record::new(
+            timestamp,
+            cpu,
+            0,
+            Category::Sched,
+            format_args!("{}", timestamp),
+        )
+    }
+
+    #[test_case]
+    fn test_ring() {
+        info!("TESTING trace::test_ring");
+        let ring = Ring::new(4);
+        assert!(ring.snapshot().is_empty());
+        for t in 0..6 {
+            ring.push(record(t, 0));
+        }
+        let timestamps = ring
+            .snapshot()
+            .iter()
+            .map(|r| r.timestamp)
+            .collect::<Vec<_>>();
+        assert_eq!(timestamps, [2, 3, 4, 5]);
+        assert_eq!(ring.snapshot()[0].payload(), "2");
+
+        let long = Record::new(0, 0, 0, Category::Irq, format_args!("{:α<60}", ""));
+        assert_eq!(long.payload().len(), PAYLOAD_SIZE); // 'α' is 2 bytes
+        assert_eq!(long.as_bytes().len(), 64);
+    }
+
+    #[test_case]
+    fn test_merge() {
+        info!("TESTING trace::test_merge");
+        let a = Ring::new(8);
+        let b = Ring::new(8);
+        for t in [1, 4, 5, 9] {
+            a.push(record(t, 0));
+        }
+        for t in [2, 3, 6, 7, 8] {
+            b.push(record(t, 1));
+        }
+        let merged = merge([&a, &b].into_iter(), 5);
+        let timestamps = merged.iter().map(|r| r.timestamp).collect::<Vec<_>>();
+        assert_eq!(timestamps, [5, 6, 7, 8, 9]);
+        assert_eq!(merged[0].cpu, 0);
+        assert_eq!(merged[1].cpu, 1);
+        assert_eq!(merge([&a, &b].into_iter(), 100).len(), 9);
+    }
+}
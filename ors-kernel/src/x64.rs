@@ -1,16 +1,20 @@
 //! Re-exporting x86_64 crate items and some additional definitions
 
+pub mod cpuid;
+
 pub use x86_64::instructions::hlt;
 pub use x86_64::instructions::interrupts;
 pub use x86_64::instructions::port::{Port, PortRead, PortWrite, PortWriteOnly};
 pub use x86_64::instructions::segmentation::{Segment, CS, DS, ES, FS, GS, SS};
 pub use x86_64::instructions::tables::load_tss;
-pub use x86_64::registers::control::{Cr2, Cr3, Cr3Flags};
+pub use x86_64::instructions::tlb;
+pub use x86_64::registers::control::{Cr0, Cr0Flags, Cr2, Cr3, Cr3Flags};
+pub use x86_64::registers::model_specific::{Efer, EferFlags};
 pub use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
 pub use x86_64::structures::idt::{
     InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode,
 };
-pub use x86_64::structures::paging::page_table::PageTableFlags;
+pub use x86_64::structures::paging::page_table::{PageTableEntry, PageTableFlags};
 pub use x86_64::structures::paging::{
     FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, PageSize, PageTable, PhysFrame,
     Size1GiB, Size2MiB, Size4KiB, Translate,
@@ -19,8 +23,17 @@ pub use x86_64::structures::tss::TaskStateSegment;
 pub use x86_64::structures::DescriptorTablePointer;
 pub use x86_64::{PhysAddr, PrivilegeLevel, VirtAddr};
 
+use core::arch::asm;
 use core::ptr;
 
+/// The current value of RSP.
+#[inline(always)]
+pub fn stack_pointer() -> VirtAddr {
+    let rsp: u64;
+    unsafe { asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack, preserves_flags)) };
+    VirtAddr::new(rsp)
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct LApic {
     ptr: *mut u32,
@@ -0,0 +1,310 @@
+//! CPU identification and feature flags by the CPUID instruction.
+
+use core::arch::x86_64::{__cpuid_count, CpuidResult};
+use core::fmt;
+use core::str;
+use spin::Lazy;
+
+static INFO: Lazy<CpuInfo> =
+    Lazy::new(|| CpuInfo::parse(|leaf, subleaf| unsafe { __cpuid_count(leaf, subleaf) }));
+
+/// The CPU information of the bootstrap processor, read once on the first call. Application
+/// processors are assumed to be identical.
+pub fn info() -> &'static CpuInfo {
+    &INFO
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct CpuInfo {
+    vendor: [u8; 12],
+    brand: [u8; 48],
+    pub family: u32,
+    pub model: u32,
+    pub stepping: u32,
+    pub features: Features,
+}
+
+impl CpuInfo {
+    /// Decode the leaves given by `cpuid(leaf, subleaf)`.
+    fn parse(cpuid: impl Fn(u32, u32) -> CpuidResult) -> Self {
+        let mut info = Self {
+            vendor: [0; 12],
+            brand: [0; 48],
+            family: 0,
+            model: 0,
+            stepping: 0,
+            features: Features::default(),
+        };
+        let f = &mut info.features;
+
+        let leaf0 = cpuid(0, 0);
+        let max_leaf = leaf0.eax;
+        info.vendor[0..4].copy_from_slice(&leaf0.ebx.to_le_bytes());
+        info.vendor[4..8].copy_from_slice(&leaf0.edx.to_le_bytes());
+        info.vendor[8..12].copy_from_slice(&leaf0.ecx.to_le_bytes());
+
+        if 1 <= max_leaf {
+            let leaf1 = cpuid(1, 0);
+            let base_family = bits(leaf1.eax, 8, 4);
+            let base_model = bits(leaf1.eax, 4, 4);
+            info.stepping = bits(leaf1.eax, 0, 4);
+            info.family = match base_family {
+                0xf => base_family + bits(leaf1.eax, 20, 8),
+                _ => base_family,
+            };
+            info.model = match base_family {
+                0x6 | 0xf => (bits(leaf1.eax, 16, 4) << 4) | base_model,
+                _ => base_model,
+            };
+            let (ecx, edx) = (leaf1.ecx, leaf1.edx);
+            f.sse3 = bit(ecx, 0);
+            f.ssse3 = bit(ecx, 9);
+            f.sse4_1 = bit(ecx, 19);
+            f.sse4_2 = bit(ecx, 20);
+            f.x2apic = bit(ecx, 21);
+            f.popcnt = bit(ecx, 23);
+            f.xsave = bit(ecx, 26);
+            f.avx = bit(ecx, 28);
+            f.rdrand = bit(ecx, 30);
+            f.hypervisor = bit(ecx, 31);
+            f.fpu = bit(edx, 0);
+            f.tsc = bit(edx, 4);
+            f.msr = bit(edx, 5);
+            f.apic = bit(edx, 9);
+            f.fxsr = bit(edx, 24);
+            f.sse = bit(edx, 25);
+            f.sse2 = bit(edx, 26);
+        }
+
+        if 7 <= max_leaf {
+            let ebx = cpuid(7, 0).ebx;
+            f.bmi1 = bit(ebx, 3);
+            f.avx2 = bit(ebx, 5);
+            f.smep = bit(ebx, 7);
+            f.bmi2 = bit(ebx, 8);
+            f.rdseed = bit(ebx, 18);
+            f.smap = bit(ebx, 20);
+        }
+
+        let max_extended_leaf = cpuid(0x8000_0000, 0).eax;
+        if 0x8000_0001 <= max_extended_leaf {
+            let edx = cpuid(0x8000_0001, 0).edx;
+            f.nx = bit(edx, 20);
+            f.page1gb = bit(edx, 26);
+            f.rdtscp = bit(edx, 27);
+            f.long_mode = bit(edx, 29);
+        }
+        if 0x8000_0004 <= max_extended_leaf {
+            for (i, leaf) in (0x8000_0002..=0x8000_0004).enumerate() {
+                let r = cpuid(leaf, 0);
+                for (j, reg) in [r.eax, r.ebx, r.ecx, r.edx].into_iter().enumerate() {
+                    let offset = i * 16 + j * 4;
+                    info.brand[offset..offset + 4].copy_from_slice(&reg.to_le_bytes());
+                }
+            }
+        }
+        if 0x8000_0007 <= max_extended_leaf {
+            f.invariant_tsc = bit(cpuid(0x8000_0007, 0).edx, 8);
+        }
+        info
+    }
+
+    /// Such as `GenuineIntel` and `AuthenticAMD`.
+    pub fn vendor(&self) -> &str {
+        str::from_utf8(&self.vendor).unwrap_or("")
+    }
+
+    /// The processor brand string, or an empty string if unavailable.
+    pub fn brand(&self) -> &str {
+        let len = self.brand.iter().position(|b| *b == 0).unwrap_or(48);
+        str::from_utf8(&self.brand[..len]).unwrap_or("").trim()
+    }
+}
+
+impl fmt::Display for CpuInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} (family {:#x}, model {:#x}, stepping {}) {}",
+            self.vendor(),
+            self.family,
+            self.model,
+            self.stepping,
+            self.brand()
+        )
+    }
+}
+
+fn bit(value: u32, n: u32) -> bool {
+    value & (1 << n) != 0
+}
+
+fn bits(value: u32, start: u32, len: u32) -> u32 {
+    (value >> start) & ((1 << len) - 1)
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub struct Features {
+    pub fpu: bool,
+    pub tsc: bool,
+    pub msr: bool,
+    pub apic: bool,
+    pub fxsr: bool,
+    pub sse: bool,
+    pub sse2: bool,
+    pub sse3: bool,
+    pub ssse3: bool,
+    pub sse4_1: bool,
+    pub sse4_2: bool,
+    pub x2apic: bool,
+    pub popcnt: bool,
+    pub xsave: bool,
+    pub avx: bool,
+    pub rdrand: bool,
+    pub hypervisor: bool,
+    pub bmi1: bool,
+    pub avx2: bool,
+    pub smep: bool,
+    pub bmi2: bool,
+    pub rdseed: bool,
+    pub smap: bool,
+    pub nx: bool,
+    pub page1gb: bool,
+    pub rdtscp: bool,
+    pub long_mode: bool,
+    /// The TSC runs at a constant rate in every power state, thus usable as a clock.
+    pub invariant_tsc: bool,
+}
+
+impl Features {
+    /// Every flag with its name, in the order of the leaves.
+    pub fn flags(&self) -> [(&'static str, bool); 28] {
+        [
+            ("fpu", self.fpu),
+            ("tsc", self.tsc),
+            ("msr", self.msr),
+            ("apic", self.apic),
+            ("fxsr", self.fxsr),
+            ("sse", self.sse),
+            ("sse2", self.sse2),
+            ("sse3", self.sse3),
+            ("ssse3", self.ssse3),
+            ("sse4.1", self.sse4_1),
+            ("sse4.2", self.sse4_2),
+            ("x2apic", self.x2apic),
+            ("popcnt", self.popcnt),
+            ("xsave", self.xsave),
+            ("avx", self.avx),
+            ("rdrand", self.rdrand),
+            ("hypervisor", self.hypervisor),
+            ("bmi1", self.bmi1),
+            ("avx2", self.avx2),
+            ("smep", self.smep),
+            ("bmi2", self.bmi2),
+            ("rdseed", self.rdseed),
+            ("smap", self.smap),
+            ("nx", self.nx),
+            ("page1gb", self.page1gb),
+            ("rdtscp", self.rdtscp),
+            ("lm", self.long_mode),
+            ("invariant_tsc", self.invariant_tsc),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::info;
+
+    /// `(leaf, eax, ebx, ecx, edx)` of subleaf 0.
+    type Leaves = [(u32, u32, u32, u32, u32)];
+
+    /// Intel Xeon (Sapphire Rapids) under KVM.
+    const XEON: &Leaves = &[
+        (0x00000000, 0x00000020, 0x756e6547, 0x6c65746e, 0x49656e69),
+        (0x00000001, 0x000c06f2, 0x00010800, 0xfffa3203, 0x0f8bfbff),
+        (0x00000007, 0x00000002, 0xf1bf27eb, 0x1b415fde, 0xbfd14410),
+        (0x80000000, 0x80000008, 0x00000000, 0x00000000, 0x00000000),
+        (0x80000001, 0x00000000, 0x00000000, 0x00000121, 0x2c100800),
+        (0x80000002, 0x65746e49, 0x2952286c, 0x6f655820, 0x2952286e),
+        (0x80000003, 0x6f725020, 0x73736563, 0x0000726f, 0x00000000),
+        (0x80000004, 0x00000000, 0x00000000, 0x00000000, 0x00000000),
+        (0x80000007, 0x00000000, 0x00000000, 0x00000000, 0x00000100),
+    ];
+
+    /// The default CPU model of QEMU (TCG), `qemu64`.
+    const QEMU64: &Leaves = &[
+        (0x00000000, 0x0000000d, 0x68747541, 0x444d4163, 0x69746e65),
+        (0x00000001, 0x00060fb1, 0x00000800, 0x80202001, 0x078bfbfd),
+        (0x00000007, 0x00000000, 0x00000000, 0x00000000, 0x00000000),
+        (0x80000000, 0x8000000a, 0x68747541, 0x444d4163, 0x69746e65),
+        (0x80000001, 0x00060fb1, 0x00000000, 0x00000005, 0x2193fbfd),
+        (0x80000002, 0x554d4551, 0x72695620, 0x6c617574, 0x55504320),
+        (0x80000003, 0x72657620, 0x6e6f6973, 0x352e3220, 0x0000002b),
+        (0x80000004, 0x00000000, 0x00000000, 0x00000000, 0x00000000),
+        (0x80000007, 0x00000000, 0x00000000, 0x00000000, 0x00000000),
+    ];
+
+    /// A CPU without the extended leaves, which has no brand string.
+    const MINIMAL: &Leaves = &[
+        (0x00000000, 0x00000001, 0x756e6547, 0x6c65746e, 0x49656e69),
+        (0x00000001, 0x00000633, 0x00000000, 0x00000000, 0x07808011),
+        (0x80000000, 0x00000000, 0x00000000, 0x00000000, 0x00000000),
+    ];
+
+    fn parse(leaves: &Leaves) -> CpuInfo {
+        CpuInfo::parse(|leaf, subleaf| {
+            assert_eq!(subleaf, 0);
+            let (_, eax, ebx, ecx, edx) = leaves
+                .iter()
+                .find(|l| l.0 == leaf)
+                .copied()
+                .expect("unexpected leaf");
+            CpuidResult { eax, ebx, ecx, edx }
+        })
+    }
+
+    #[test_case]
+    fn test_cpuid_xeon() {
+        info!("TESTING x64::cpuid::test_cpuid_xeon");
+        let info = parse(XEON);
+        assert_eq!(info.vendor(), "GenuineIntel");
+        assert_eq!(info.brand(), "Intel(R) Xeon(R) Processor");
+        assert_eq!((info.family, info.model, info.stepping), (6, 0xcf, 2));
+        let f = info.features;
+        assert!(f.fpu && f.tsc && f.msr && f.apic && f.fxsr && f.sse && f.sse2);
+        assert!(f.sse3 && f.ssse3 && f.sse4_1 && f.sse4_2 && f.popcnt);
+        assert!(f.x2apic && f.xsave && f.avx && f.rdrand && f.hypervisor);
+        assert!(f.bmi1 && f.avx2 && f.smep && f.bmi2 && f.rdseed && f.smap);
+        assert!(f.nx && f.page1gb && f.rdtscp && f.long_mode && f.invariant_tsc);
+        assert!(f.flags().iter().all(|(_, enabled)| *enabled));
+    }
+
+    #[test_case]
+    fn test_cpuid_qemu64() {
+        info!("TESTING x64::cpuid::test_cpuid_qemu64");
+        let info = parse(QEMU64);
+        assert_eq!(info.vendor(), "AuthenticAMD");
+        assert_eq!(info.brand(), "QEMU Virtual CPU version 2.5+");
+        assert_eq!((info.family, info.model, info.stepping), (15, 107, 1));
+        let f = info.features;
+        assert!(f.fpu && f.tsc && f.fxsr && f.sse && f.sse2 && f.sse3);
+        assert!(f.x2apic && f.hypervisor && f.nx && f.long_mode);
+        assert!(!f.ssse3 && !f.sse4_1 && !f.sse4_2 && !f.popcnt && !f.avx && !f.rdrand);
+        assert!(!f.avx2 && !f.smep && !f.page1gb && !f.rdtscp && !f.invariant_tsc);
+    }
+
+    #[test_case]
+    fn test_cpuid_minimal() {
+        info!("TESTING x64::cpuid::test_cpuid_minimal");
+        let info = parse(MINIMAL);
+        assert_eq!(info.vendor(), "GenuineIntel");
+        assert_eq!(info.brand(), "");
+        assert_eq!((info.family, info.model, info.stepping), (6, 3, 3));
+        let f = info.features;
+        assert!(f.fpu && f.tsc && f.fxsr && f.sse && f.sse2);
+        assert!(!f.apic && !f.msr && !f.sse3 && !f.nx && !f.long_mode && !f.avx2);
+        assert_eq!(f.flags().iter().filter(|(_, enabled)| *enabled).count(), 5);
+    }
+}
@@ -2,6 +2,7 @@
 
 use alloc::boxed::Box;
 use alloc::vec::Vec;
+use core::fmt;
 use uefi::prelude::*;
 use uefi::proto::media::file::{
     Directory, File, FileAttribute, FileInfo, FileMode, FileType, RegularFile,
@@ -21,17 +22,15 @@ pub fn create(dir: &mut Directory, filename: &str, create_dir: bool) -> FileType
     } else {
         FileAttribute::empty()
     };
-    dir.open(filename, FileMode::CreateReadWrite, attr)
-        .expect_success("Failed to create file")
-        .into_type()
-        .unwrap_success()
+    let file = dir.open(filename, FileMode::CreateReadWrite, attr);
+    let file = expect_success(file, "create", filename);
+    expect_success(file.into_type(), "create", filename)
 }
 
 pub fn open(dir: &mut Directory, filename: &str) -> FileType {
-    dir.open(filename, FileMode::Read, FileAttribute::empty())
-        .expect_success("Failed to open file")
-        .into_type()
-        .unwrap_success()
+    let file = dir.open(filename, FileMode::Read, FileAttribute::empty());
+    let file = expect_success(file, "open", filename);
+    expect_success(file.into_type(), "open", filename)
 }
 
 pub fn create_file(dir: &mut Directory, filename: &str) -> RegularFile {
@@ -48,15 +47,44 @@ pub fn open_file(dir: &mut Directory, filename: &str) -> RegularFile {
     }
 }
 
-pub fn read_file_to_vec(file: &mut RegularFile) -> Vec<u8> {
-    let size = get_file_info(file).file_size() as usize;
+pub fn try_open_file(dir: &mut Directory, filename: &str) -> Option<RegularFile> {
+    match dir.open(filename, FileMode::Read, FileAttribute::empty()) {
+        Ok(file) => match file.unwrap().into_type().unwrap_success() {
+            FileType::Regular(file) => Some(file),
+            FileType::Dir(_) => None,
+        },
+        Err(_) => None,
+    }
+}
+
+/// Read the whole file. Panics if the file could not be read up to the size reported by the file system.
+pub fn read_file_to_vec(file: &mut RegularFile, filename: &str) -> Vec<u8> {
+    let size = get_file_info(file, filename).file_size() as usize;
     let mut buf = vec![0; size];
-    file.read(&mut buf).unwrap_success();
+    let mut read_size = 0;
+    while read_size < size {
+        let result = file.read(&mut buf[read_size..]);
+        match expect_success(result, "read", filename) {
+            0 => panic!(
+                "Failed to read {}: expected {} bytes, but got {} bytes",
+                filename, size, read_size
+            ),
+            n => read_size += n,
+        }
+    }
     buf
 }
 
-pub fn get_file_info(file: &mut impl File) -> Box<FileInfo> {
-    file.get_boxed_info::<FileInfo>().unwrap_success()
+pub fn get_file_info(file: &mut impl File, filename: &str) -> Box<FileInfo> {
+    expect_success(file.get_boxed_info::<FileInfo>(), "get info of", filename)
+}
+
+/// Similar to `ResultExt::expect_success`, but the panic message names the file involved.
+fn expect_success<T, E: fmt::Debug>(result: uefi::Result<T, E>, action: &str, filename: &str) -> T {
+    match result {
+        Ok(completion) => completion.log(),
+        Err(e) => panic!("Failed to {} {}: {:?}", action, filename, e.status()),
+    }
 }
 
 macro_rules! fwrite {
@@ -10,10 +10,11 @@ extern crate alloc;
 mod fs;
 
 use alloc::vec::Vec;
-use core::{mem, slice};
+use core::{fmt, mem, slice};
 use goblin::elf;
-use log::trace;
-use ors_common::{frame_buffer, memory_map};
+use log::{trace, warn};
+use ors_common::elf::ElfError;
+use ors_common::{command_line, frame_buffer, memory_map};
 use uefi::prelude::*;
 use uefi::proto::console::gop::{GraphicsOutput, PixelFormat};
 use uefi::table::boot::{AllocateType, MemoryDescriptor, MemoryType};
@@ -36,8 +37,15 @@ fn efi_main(image: Handle, mut st: SystemTable<Boot>) -> Status {
     let entry_point_addr = load_kernel("ors-kernel.elf", image, &st);
 
     trace!("entry_point_addr = 0x{:x}", entry_point_addr);
-    let entry_point: extern "sysv64" fn(&frame_buffer::FrameBuffer, &memory_map::MemoryMap, u64) =
-        unsafe { mem::transmute(entry_point_addr) };
+    let entry_point: extern "sysv64" fn(
+        &frame_buffer::FrameBuffer,
+        &memory_map::MemoryMap,
+        u64,
+        &command_line::CommandLine,
+    ) = unsafe { mem::transmute(entry_point_addr) };
+
+    trace!("load_command_line");
+    let command_line = load_command_line("ors-cmdline.txt", image, &st);
 
     trace!("get_frame_buffer");
     let frame_buffer = get_frame_buffer(st.boot_services());
@@ -48,7 +56,7 @@ fn efi_main(image: Handle, mut st: SystemTable<Boot>) -> Status {
     trace!("exit_boot_services");
     let (_st, memory_map) = exit_boot_services(image, st);
 
-    entry_point(&frame_buffer, &memory_map, rsdp);
+    entry_point(&frame_buffer, &memory_map, rsdp, &command_line);
 
     loop {
         hlt()
@@ -95,30 +103,142 @@ fn dump_memory_map(path: &str, image: Handle, st: &SystemTable<Boot>) {
 fn load_kernel(path: &str, image: Handle, st: &SystemTable<Boot>) -> usize {
     let mut root_dir = fs::open_root_dir(image, st.boot_services());
     let mut file = fs::open_file(&mut root_dir, path);
-    let buf = fs::read_file_to_vec(&mut file);
-    load_elf(&buf, st)
+    let buf = fs::read_file_to_vec(&mut file, path);
+    load_elf(&buf, st).unwrap_or_else(|e| panic!("Failed to load {}: {}", path, e))
 }
 
-fn load_elf(src: &[u8], st: &SystemTable<Boot>) -> usize {
-    let elf = elf::Elf::parse(&src).expect("Failed to parse ELF");
+fn load_command_line(
+    path: &str,
+    image: Handle,
+    st: &SystemTable<Boot>,
+) -> command_line::CommandLine {
+    let mut root_dir = fs::open_root_dir(image, st.boot_services());
+    // The command line file is optional
+    let buf: &[u8] = match fs::try_open_file(&mut root_dir, path) {
+        Some(mut file) => fs::read_file_to_vec(&mut file, path).leak(),
+        None => &[],
+    };
+    command_line::CommandLine {
+        ptr: buf.as_ptr(),
+        len: buf.len() as u64,
+    }
+}
+
+#[derive(Debug)]
+enum LoadError {
+    Parse(goblin::error::Error),
+    NoLoadableSegment,
+    /// The file content of the segment at the index is out of the file, the file is probably truncated.
+    SegmentOutOfFile(usize),
+    InvalidSegment(usize),
+    EntryPointOutOfSegments(u64),
+    /// The kernel range could not be allocated. Memory descriptors overlapping the range are attached.
+    AllocationFailed(Status, usize, usize, Vec<MemoryDescriptor>),
+    /// The segments starting at the first address cannot be placed at the second address.
+    InvalidBase(usize, usize),
+    Relocation(ElfError),
+    Permissions(ElfError),
+}
 
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Parse(e) => write!(f, "Failed to parse ELF: {}", e),
+            Self::NoLoadableSegment => write!(f, "No loadable segment"),
+            Self::SegmentOutOfFile(i) => {
+                write!(f, "Segment {} is out of the file (truncated?)", i)
+            }
+            Self::InvalidSegment(i) => write!(f, "Segment {} is invalid", i),
+            Self::EntryPointOutOfSegments(entry) => {
+                write!(f, "Entry point 0x{:x} is out of loadable segments", entry)
+            }
+            Self::AllocationFailed(status, start, end, conflicts) => {
+                write!(
+                    f,
+                    "Failed to allocate pages at 0x{:x}-0x{:x} ({:?})",
+                    start, end, status
+                )?;
+                for d in conflicts {
+                    write!(
+                        f,
+                        "\n  conflicts with {:?} at 0x{:x} ({} pages)",
+                        d.ty, d.phys_start, d.page_count
+                    )?;
+                }
+                Ok(())
+            }
+            Self::InvalidBase(dest_start, allocated_start) => write!(
+                f,
+                "Segments at 0x{:x} cannot be loaded at 0x{:x}",
+                dest_start, allocated_start
+            ),
+            Self::Relocation(e) => write!(f, "Failed to relocate ELF: {}", e),
+            Self::Permissions(e) => write!(f, "Invalid segment permissions: {}", e),
+        }
+    }
+}
+
+fn load_elf(src: &[u8], st: &SystemTable<Boot>) -> Result<usize, LoadError> {
+    let elf = elf::Elf::parse(&src).map_err(LoadError::Parse)?;
+
+    // Validate the segments before copying anything, so that a broken kernel image is
+    // reported here instead of silently booting into garbage.
     let mut dest_start = usize::MAX;
     let mut dest_end = 0;
-    for ph in elf.program_headers.iter() {
+    for (i, ph) in elf.program_headers.iter().enumerate() {
         if ph.p_type != elf::program_header::PT_LOAD {
             continue;
         }
+        let file_end = ph.p_offset.checked_add(ph.p_filesz);
+        if !matches!(file_end, Some(end) if end <= src.len() as u64) {
+            Err(LoadError::SegmentOutOfFile(i))?;
+        }
+        let mem_end = ph
+            .p_vaddr
+            .checked_add(ph.p_memsz)
+            .ok_or(LoadError::InvalidSegment(i))?;
+        if ph.p_memsz < ph.p_filesz {
+            Err(LoadError::InvalidSegment(i))?;
+        }
+        // The kernel maps its segments with these permissions after boot
+        ors_common::elf::Permissions::from_flags(i, ph.p_flags).map_err(LoadError::Permissions)?;
         dest_start = dest_start.min(ph.p_vaddr as usize);
-        dest_end = dest_end.max((ph.p_vaddr + ph.p_memsz) as usize);
+        dest_end = dest_end.max(mem_end as usize);
+    }
+    if dest_end <= dest_start {
+        Err(LoadError::NoLoadableSegment)?;
+    }
+    if !(dest_start as u64..dest_end as u64).contains(&elf.entry) {
+        Err(LoadError::EntryPointOutOfSegments(elf.entry))?;
     }
 
-    st.boot_services()
+    // Position-independent ELFs are loaded at an arbitrary address and relocated
+    let is_dyn = elf.header.e_type == elf::header::ET_DYN;
+    let num_pages = (dest_end - dest_start + UEFI_PAGE_SIZE - 1) / UEFI_PAGE_SIZE;
+    let allocated_start = st
+        .boot_services()
         .allocate_pages(
-            AllocateType::Address(dest_start),
+            if is_dyn {
+                AllocateType::AnyPages
+            } else {
+                AllocateType::Address(dest_start)
+            },
             MemoryType::LOADER_DATA,
-            (dest_end - dest_start + UEFI_PAGE_SIZE - 1) / UEFI_PAGE_SIZE,
+            num_pages,
         )
-        .expect_success("Failed to allocate pages for kernel");
+        .map_err(|e| {
+            let end = dest_start + num_pages * UEFI_PAGE_SIZE;
+            let conflicts = if is_dyn {
+                Vec::new()
+            } else {
+                conflicting_memory_descriptors(dest_start as u64, end as u64, st)
+            };
+            LoadError::AllocationFailed(e.status(), dest_start, end, conflicts)
+        })?
+        .log() as usize;
+    let base = allocated_start
+        .checked_sub(dest_start)
+        .ok_or(LoadError::InvalidBase(dest_start, allocated_start))?;
 
     for ph in elf.program_headers.iter() {
         if ph.p_type != elf::program_header::PT_LOAD {
@@ -127,29 +247,81 @@ fn load_elf(src: &[u8], st: &SystemTable<Boot>) -> usize {
         let ofs = ph.p_offset as usize;
         let fsize = ph.p_filesz as usize;
         let msize = ph.p_memsz as usize;
-        let dest = unsafe { slice::from_raw_parts_mut(ph.p_vaddr as *mut u8, msize) };
+        let dest =
+            unsafe { slice::from_raw_parts_mut((base + ph.p_vaddr as usize) as *mut u8, msize) };
         dest[..fsize].copy_from_slice(&src[ofs..ofs + fsize]);
         dest[fsize..].fill(0);
     }
 
-    elf.entry as usize
+    if is_dyn {
+        unsafe { ors_common::elf::apply_relocations(src, &elf, base) }
+            .map_err(LoadError::Relocation)?;
+    }
+
+    Ok(base + elf.entry as usize)
 }
 
+/// Memory descriptors that are not free and overlap the given physical address range.
+fn conflicting_memory_descriptors(
+    start: u64,
+    end: u64,
+    st: &SystemTable<Boot>,
+) -> Vec<MemoryDescriptor> {
+    let enough_mmap_size =
+        st.boot_services().memory_map_size().map_size + 8 * mem::size_of::<MemoryDescriptor>();
+    let mut mmap_buf = vec![0; enough_mmap_size];
+    let conflicts = match st.boot_services().memory_map(&mut mmap_buf) {
+        Ok(completion) => {
+            let (_, descriptors) = completion.log();
+            descriptors
+                .filter(|d| d.ty != MemoryType::CONVENTIONAL)
+                .filter(|d| {
+                    d.phys_start < end
+                        && start < d.phys_start + d.page_count * UEFI_PAGE_SIZE as u64
+                })
+                .copied()
+                .collect()
+        }
+        Err(_) => Vec::new(),
+    };
+    conflicts
+}
+
+/// Get the frame buffer of the current graphics mode. `FrameBuffer::NONE` is returned if it is not
+/// available, in which case the kernel falls back to the serial console.
 fn get_frame_buffer(bs: &BootServices) -> frame_buffer::FrameBuffer {
-    let gop = bs.locate_protocol::<GraphicsOutput>().unwrap_success();
+    let gop = match bs.locate_protocol::<GraphicsOutput>() {
+        Ok(gop) => gop.log(),
+        Err(e) => {
+            warn!("GOP is not available: {:?}", e.status());
+            return frame_buffer::FrameBuffer::NONE;
+        }
+    };
     let gop = unsafe { &mut *gop.get() };
-    frame_buffer::FrameBuffer {
+    let mode_info = gop.current_mode_info();
+    let format = match mode_info.pixel_format() {
+        PixelFormat::Rgb => frame_buffer::PixelFormat::Rgb,
+        PixelFormat::Bgr => frame_buffer::PixelFormat::Bgr,
+        f => {
+            warn!("Unsupported pixel format: {:?}", f);
+            return frame_buffer::FrameBuffer::NONE;
+        }
+    };
+    let fb = frame_buffer::FrameBuffer {
         frame_buffer: gop.frame_buffer().as_mut_ptr(),
-        stride: gop.current_mode_info().stride() as u32,
+        stride: mode_info.stride() as u32,
         resolution: (
-            gop.current_mode_info().resolution().0 as u32,
-            gop.current_mode_info().resolution().1 as u32,
+            mode_info.resolution().0 as u32,
+            mode_info.resolution().1 as u32,
         ),
-        format: match gop.current_mode_info().pixel_format() {
-            PixelFormat::Rgb => frame_buffer::PixelFormat::Rgb,
-            PixelFormat::Bgr => frame_buffer::PixelFormat::Bgr,
-            f => panic!("Unsupported pixel format: {:?}", f),
-        },
+        format,
+    };
+    match fb.check() {
+        Ok(()) => fb,
+        Err(e) => {
+            warn!("Unusable frame buffer: {} ({:?})", e, fb);
+            frame_buffer::FrameBuffer::NONE
+        }
     }
 }
 
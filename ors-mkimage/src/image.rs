@@ -0,0 +1,743 @@
+//! Layout and serialization of FAT32 images.
+
+use crate::manifest::{Corruption, Directive, Geometry, Manifest};
+use crate::{content_hash, Error, Expectation, Fixture};
+use ors_common::fat::{self, BootSectorParams};
+use std::collections::BTreeSet;
+
+const ROOT: usize = 0;
+const FS_INFO_SECTOR: usize = 1;
+const BACKUP_BOOT_SECTOR: usize = 6;
+
+pub(crate) fn build(manifest: &Manifest) -> Result<Fixture, Error> {
+    let mut image = Image::new(manifest)?;
+    for directive in &manifest.directives {
+        image.apply(directive)?;
+    }
+    image.layout()?;
+    for corruption in &manifest.corruptions {
+        image.corrupt(corruption)?;
+    }
+    let bytes = image.serialize();
+    let expectations = image.expectations(&bytes);
+    Ok(Fixture {
+        sector_size: manifest.geometry.sector_size,
+        image: bytes,
+        expectations,
+    })
+}
+
+#[derive(Debug)]
+struct Image {
+    geometry: Geometry,
+    fat_size: usize,
+    volume_id: u32,
+    volume_label: [u8; 11],
+    nodes: Vec<Node>,
+    fat: Vec<u32>,
+}
+
+#[derive(Debug)]
+struct Node {
+    name: String,
+    parent: usize,
+    kind: Kind,
+    short_name: [u8; 11],
+    nt_res: u8,
+    has_long_name: bool,
+    attr: u8,
+    fragmented: bool,
+    clusters: Vec<u32>,
+    // The first cluster recorded in the directory entry, which differs from `clusters[0]` if cross-linked
+    entry_cluster: u32,
+    broken_long_name: bool,
+}
+
+#[derive(Debug)]
+enum Kind {
+    Dir(Vec<usize>),
+    File(Vec<u8>),
+}
+
+impl Image {
+    fn new(manifest: &Manifest) -> Result<Self, Error> {
+        let g = manifest.geometry;
+        if !matches!(g.sector_size, 512 | 1024 | 2048 | 4096) {
+            Err(Error::Layout(format!(
+                "Unsupported sector size: {}",
+                g.sector_size
+            )))?;
+        }
+        if !g.sectors_per_cluster.is_power_of_two() || 128 < g.sectors_per_cluster {
+            Err(Error::Layout(format!(
+                "Unsupported cluster size: {}",
+                g.sectors_per_cluster
+            )))?;
+        }
+        if g.reserved_sectors <= BACKUP_BOOT_SECTOR || g.clusters == 0 {
+            Err(Error::Layout(String::from("Geometry too small")))?;
+        }
+        let label = manifest.volume_label.as_bytes();
+        if 11 < label.len() || !label.is_ascii() {
+            Err(Error::Layout(format!(
+                "Invalid volume label: {}",
+                manifest.volume_label
+            )))?;
+        }
+        let mut volume_label = [b' '; 11];
+        volume_label[..label.len()].copy_from_slice(label);
+
+        let mut fat = vec![0; g.clusters + 2];
+        fat[0] = fat::MEDIA_ENTRY;
+        fat[1] = fat::END_OF_CHAIN;
+        Ok(Self {
+            geometry: g,
+            fat_size: ((g.clusters + 2) * 4 + g.sector_size - 1) / g.sector_size,
+            volume_id: manifest.volume_id,
+            volume_label,
+            nodes: vec![Node::new(String::new(), ROOT, Kind::Dir(Vec::new()))],
+            fat,
+        })
+    }
+
+    fn cluster_bytes(&self) -> usize {
+        self.geometry.sector_size * self.geometry.sectors_per_cluster
+    }
+
+    fn apply(&mut self, directive: &Directive) -> Result<(), Error> {
+        match directive {
+            Directive::Dir(path) => {
+                self.create(path, Kind::Dir(Vec::new()))?;
+            }
+            Directive::File {
+                path,
+                contents,
+                fragmented,
+            } => {
+                let node = self.create(path, Kind::File(contents.clone()))?;
+                self.nodes[node].fragmented = *fragmented;
+            }
+            Directive::Attr {
+                path,
+                read_only,
+                hidden,
+                system,
+            } => {
+                let node = self.lookup(path)?;
+                let node = &mut self.nodes[node];
+                for (attr, enabled) in [
+                    (fat::ATTR_READ_ONLY, *read_only),
+                    (fat::ATTR_HIDDEN, *hidden),
+                    (fat::ATTR_SYSTEM, *system),
+                ] {
+                    if enabled {
+                        node.attr |= attr;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Create a node at the path, with its missing parent directories.
+    fn create(&mut self, path: &str, kind: Kind) -> Result<usize, Error> {
+        let components = path_components(path);
+        let (name, parents) = components
+            .split_last()
+            .ok_or_else(|| Error::Layout(format!("Invalid path: {:?}", path)))?;
+        let mut dir = ROOT;
+        for parent in parents {
+            dir = match self.child(dir, parent) {
+                Some(node) if matches!(self.nodes[node].kind, Kind::Dir(_)) => node,
+                Some(_) => Err(Error::Layout(format!("Not a directory: {}", parent)))?,
+                None => self.add_child(dir, parent, Kind::Dir(Vec::new()))?,
+            };
+        }
+        match self.child(dir, name) {
+            Some(_) => Err(Error::Layout(format!("Already exists: {}", path))),
+            None => self.add_child(dir, name, kind),
+        }
+    }
+
+    fn add_child(&mut self, dir: usize, name: &str, kind: Kind) -> Result<usize, Error> {
+        if matches!(name, "." | "..")
+            || name.chars().any(|c| "\\/:*?\"<>|".contains(c))
+            || 255 < name.encode_utf16().count()
+        {
+            Err(Error::Layout(format!("Invalid name: {:?}", name)))?;
+        }
+        let mut node = Node::new(String::from(name), dir, kind);
+        match short_name(name) {
+            Some((short_name, nt_res)) => {
+                node.short_name = short_name;
+                node.nt_res = nt_res;
+            }
+            None => {
+                let taken = self
+                    .children(dir)
+                    .iter()
+                    .map(|c| self.nodes[*c].short_name)
+                    .collect::<BTreeSet<_>>();
+                node.short_name = short_name_alias(name, |n| taken.contains(n));
+                node.has_long_name = true;
+            }
+        }
+        let index = self.nodes.len();
+        self.nodes.push(node);
+        match &mut self.nodes[dir].kind {
+            Kind::Dir(children) => children.push(index),
+            Kind::File(_) => unreachable!(),
+        }
+        Ok(index)
+    }
+
+    fn children(&self, dir: usize) -> &[usize] {
+        match &self.nodes[dir].kind {
+            Kind::Dir(children) => children,
+            Kind::File(_) => &[],
+        }
+    }
+
+    fn child(&self, dir: usize, name: &str) -> Option<usize> {
+        self.children(dir)
+            .iter()
+            .copied()
+            .find(|c| self.nodes[*c].name.eq_ignore_ascii_case(name))
+    }
+
+    fn lookup(&self, path: &str) -> Result<usize, Error> {
+        path_components(path)
+            .into_iter()
+            .try_fold(ROOT, |dir, name| self.child(dir, name))
+            .ok_or_else(|| Error::Layout(format!("Not found: {}", path)))
+    }
+
+    /// Nodes in the order of creation, parents first.
+    fn preorder(&self) -> Vec<usize> {
+        let mut order = Vec::new();
+        let mut stack = vec![ROOT];
+        while let Some(node) = stack.pop() {
+            order.push(node);
+            stack.extend(self.children(node).iter().rev());
+        }
+        order
+    }
+
+    fn layout(&mut self) -> Result<(), Error> {
+        for node in self.preorder() {
+            let bytes = match &self.nodes[node].kind {
+                Kind::Dir(_) => self.dir_entries(node).len() * fat::DIR_ENTRY_SIZE,
+                Kind::File(contents) => contents.len(),
+            };
+            let mut count = (bytes + self.cluster_bytes() - 1) / self.cluster_bytes();
+            if matches!(self.nodes[node].kind, Kind::Dir(_)) {
+                count = count.max(1); // Directories always have a cluster
+            }
+            let clusters = self.allocate(count, self.nodes[node].fragmented)?;
+            let node = &mut self.nodes[node];
+            node.entry_cluster = clusters.first().copied().unwrap_or(0);
+            node.clusters = clusters;
+        }
+        Ok(())
+    }
+
+    /// Allocate a chain of clusters from the lowest free cluster. A fragmented chain skips every
+    /// other free cluster, which is filled by the following allocations.
+    fn allocate(&mut self, count: usize, fragmented: bool) -> Result<Vec<u32>, Error> {
+        let step = if fragmented { 2 } else { 1 };
+        let clusters = (2..self.fat.len() as u32)
+            .filter(|c| self.fat[*c as usize] == 0)
+            .step_by(step)
+            .take(count)
+            .collect::<Vec<_>>();
+        if clusters.len() < count {
+            Err(Error::Layout(String::from("Not enough clusters")))?;
+        }
+        for (i, c) in clusters.iter().enumerate() {
+            self.fat[*c as usize] = clusters.get(i + 1).copied().unwrap_or(fat::END_OF_CHAIN);
+        }
+        Ok(clusters)
+    }
+
+    fn corrupt(&mut self, corruption: &Corruption) -> Result<(), Error> {
+        match corruption {
+            Corruption::LfnChecksum(path) => {
+                let node = self.lookup(path)?;
+                let node = &mut self.nodes[node];
+                if !node.has_long_name {
+                    Err(Error::Layout(format!("No long name: {}", path)))?;
+                }
+                node.broken_long_name = true;
+            }
+            Corruption::CrossLink(src, dest) => {
+                let src = self.lookup_file(src)?;
+                let dest = self.lookup_file(dest)?;
+                self.nodes[dest].entry_cluster = self.nodes[src].entry_cluster;
+            }
+            Corruption::TruncateChain(path, count) => {
+                let node = &self.nodes[self.lookup_file(path)?];
+                if *count == 0 || node.clusters.len() <= *count {
+                    Err(Error::Layout(format!(
+                        "Cannot truncate {} clusters of {} to {}",
+                        node.clusters.len(),
+                        path,
+                        count
+                    )))?;
+                }
+                let last = node.clusters[count - 1];
+                self.fat[last as usize] = fat::END_OF_CHAIN;
+            }
+        }
+        Ok(())
+    }
+
+    fn lookup_file(&self, path: &str) -> Result<usize, Error> {
+        let node = self.lookup(path)?;
+        match self.nodes[node].kind {
+            Kind::File(_) => Ok(node),
+            Kind::Dir(_) => Err(Error::Layout(format!("Not a file: {}", path))),
+        }
+    }
+
+    /// Directory entries of the directory, without the terminal entry.
+    fn dir_entries(&self, dir: usize) -> Vec<[u8; fat::DIR_ENTRY_SIZE]> {
+        let mut entries = Vec::new();
+        if dir != ROOT {
+            let parent = match self.nodes[dir].parent {
+                ROOT => 0,
+                parent => self.nodes[parent].entry_cluster,
+            };
+            for (name, cluster) in [
+                (b".          ", self.nodes[dir].entry_cluster),
+                (b"..         ", parent),
+            ] {
+                entries.push(fat::encode_sfn_entry(
+                    name,
+                    fat::ATTR_DIRECTORY,
+                    0,
+                    cluster,
+                    0,
+                ));
+            }
+        }
+        for child in self.children(dir) {
+            let node = &self.nodes[*child];
+            if node.has_long_name {
+                let mut checksum = fat::lfn_checksum(&node.short_name);
+                if node.broken_long_name {
+                    checksum = !checksum;
+                }
+                let mut name = node.name.encode_utf16().collect::<Vec<_>>();
+                if name.len() % fat::LFN_ENTRY_CHARS != 0 {
+                    name.push(0x0000);
+                }
+                while name.len() % fat::LFN_ENTRY_CHARS != 0 {
+                    name.push(0xffff);
+                }
+                let parts = name.chunks(fat::LFN_ENTRY_CHARS).collect::<Vec<_>>();
+                for (i, part) in parts.iter().enumerate().rev() {
+                    let order = i as u8 + 1;
+                    let part = (*part).try_into().unwrap();
+                    let last = i == parts.len() - 1;
+                    entries.push(fat::encode_lfn_entry(order, last, checksum, part));
+                }
+            }
+            let (attr, size) = match &node.kind {
+                Kind::Dir(_) => (node.attr | fat::ATTR_DIRECTORY, 0),
+                Kind::File(contents) => (node.attr | fat::ATTR_ARCHIVE, contents.len()),
+            };
+            entries.push(fat::encode_sfn_entry(
+                &node.short_name,
+                attr,
+                node.nt_res,
+                node.entry_cluster,
+                size as u32,
+            ));
+        }
+        entries
+    }
+
+    fn data_area_start(&self) -> usize {
+        self.geometry.reserved_sectors + self.fat_size * 2
+    }
+
+    fn cluster_offset(&self, cluster: u32) -> usize {
+        let sector =
+            self.data_area_start() + (cluster as usize - 2) * self.geometry.sectors_per_cluster;
+        sector * self.geometry.sector_size
+    }
+
+    fn serialize(&self) -> Vec<u8> {
+        let g = self.geometry;
+        let total_sectors = self.data_area_start() + g.clusters * g.sectors_per_cluster;
+        let mut bytes = vec![0; total_sectors * g.sector_size];
+
+        let boot_sector = BootSectorParams {
+            bytes_per_sector: g.sector_size as u16,
+            sectors_per_cluster: g.sectors_per_cluster as u8,
+            reserved_sectors: g.reserved_sectors as u16,
+            num_fats: 2,
+            total_sectors: total_sectors as u32,
+            fat_size: self.fat_size as u32,
+            root_cluster: self.nodes[ROOT].entry_cluster,
+            fs_info: FS_INFO_SECTOR as u16,
+            backup_boot_sector: BACKUP_BOOT_SECTOR as u16,
+            volume_id: self.volume_id,
+            volume_label: self.volume_label,
+        };
+        for sector in [0, BACKUP_BOOT_SECTOR] {
+            boot_sector.encode(&mut bytes[sector * g.sector_size..]);
+        }
+
+        let fs_info = &mut bytes[FS_INFO_SECTOR * g.sector_size..];
+        let free_count = self.fat.iter().skip(2).filter(|e| **e == 0).count() as u32;
+        fs_info[0..4].copy_from_slice(&0x41615252u32.to_le_bytes());
+        fs_info[484..488].copy_from_slice(&0x61417272u32.to_le_bytes());
+        fs_info[488..492].copy_from_slice(&free_count.to_le_bytes());
+        fs_info[492..496].copy_from_slice(&0xffffffffu32.to_le_bytes());
+        fs_info[508..512].copy_from_slice(&0xaa550000u32.to_le_bytes());
+
+        for copy in 0..2 {
+            let start = (g.reserved_sectors + self.fat_size * copy) * g.sector_size;
+            for (i, entry) in self.fat.iter().enumerate() {
+                bytes[start + i * 4..start + i * 4 + 4].copy_from_slice(&entry.to_le_bytes());
+            }
+        }
+
+        for node in self.preorder() {
+            let contents = match &self.nodes[node].kind {
+                Kind::Dir(_) => self.dir_entries(node).concat(),
+                Kind::File(contents) => contents.clone(),
+            };
+            let chunks = contents.chunks(self.cluster_bytes());
+            for (cluster, chunk) in self.nodes[node].clusters.iter().zip(chunks) {
+                let offset = self.cluster_offset(*cluster);
+                bytes[offset..offset + chunk.len()].copy_from_slice(chunk);
+            }
+        }
+        bytes
+    }
+
+    /// The contents readable by following the cluster chain recorded in the directory entry.
+    fn read_chain(&self, bytes: &[u8], node: usize, size: usize) -> Vec<u8> {
+        let mut contents = Vec::new();
+        let mut cluster = self.nodes[node].entry_cluster;
+        let mut visited = BTreeSet::new();
+        while contents.len() < size && 2 <= cluster && (cluster as usize) < self.fat.len() {
+            if !visited.insert(cluster) {
+                break;
+            }
+            let offset = self.cluster_offset(cluster);
+            let len = self.cluster_bytes().min(size - contents.len());
+            contents.extend_from_slice(&bytes[offset..offset + len]);
+            cluster = self.fat[cluster as usize];
+        }
+        contents
+    }
+
+    fn visible_name(&self, node: usize) -> String {
+        let node = &self.nodes[node];
+        if !node.broken_long_name {
+            return node.name.clone();
+        }
+        let base = String::from_utf8_lossy(&node.short_name[0..8]);
+        let ext = String::from_utf8_lossy(&node.short_name[8..11]);
+        match ext.trim_end() {
+            "" => String::from(base.trim_end()),
+            ext => format!("{}.{}", base.trim_end(), ext),
+        }
+    }
+
+    fn path(&self, node: usize) -> String {
+        let mut names = Vec::new();
+        let mut n = node;
+        while n != ROOT {
+            names.push(self.visible_name(n));
+            n = self.nodes[n].parent;
+        }
+        names.reverse();
+        format!("/{}", names.join("/"))
+    }
+
+    fn expectations(&self, bytes: &[u8]) -> Vec<Expectation> {
+        let mut expectations = Vec::new();
+        for node in self.preorder() {
+            let path = self.path(node);
+            let n = &self.nodes[node];
+            if n.broken_long_name {
+                let parent = self.path(n.parent);
+                let original = format!("{}/{}", parent.trim_end_matches('/'), n.name);
+                expectations.push(Expectation::Missing { path: original });
+            }
+            match &n.kind {
+                Kind::Dir(children) => expectations.push(Expectation::Dir {
+                    path,
+                    entries: children.iter().map(|c| self.visible_name(*c)).collect(),
+                }),
+                Kind::File(contents) => {
+                    let readable = self.read_chain(bytes, node, contents.len());
+                    expectations.push(Expectation::File {
+                        path,
+                        size: contents.len(),
+                        len: readable.len(),
+                        hash: content_hash(&readable),
+                        read_only: n.attr & fat::ATTR_READ_ONLY != 0,
+                        hidden: n.attr & fat::ATTR_HIDDEN != 0,
+                        system: n.attr & fat::ATTR_SYSTEM != 0,
+                    });
+                }
+            }
+        }
+        expectations
+    }
+}
+
+impl Node {
+    fn new(name: String, parent: usize, kind: Kind) -> Self {
+        Self {
+            name,
+            parent,
+            kind,
+            short_name: [b' '; 11],
+            nt_res: 0,
+            has_long_name: false,
+            attr: 0,
+            fragmented: false,
+            clusters: Vec::new(),
+            entry_cluster: 0,
+            broken_long_name: false,
+        }
+    }
+}
+
+fn path_components(path: &str) -> Vec<&str> {
+    path.split('/').filter(|c| !c.is_empty()).collect()
+}
+
+fn is_short_name_char(c: char) -> bool {
+    matches!(c, '0'..='9' | 'A'..='Z' | 'a'..='z' | '!' | '#' | '$' | '%' | '&' | '\'' | '(' | ')' | '-' | '@' | '^' | '_' | '`' | '{' | '}' | '~')
+}
+
+/// The short name and the NT_RES flags if the name can be stored without a long name. This
+/// follows the rules of the kernel: each part must not mix upper and lower cases.
+fn short_name(name: &str) -> Option<([u8; 11], u8)> {
+    let (base, ext) = name.split_once('.').unwrap_or((name, ""));
+    if !matches!(base.len(), 1..=8)
+        || 3 < ext.len()
+        || (name.contains('.') && ext.is_empty())
+        || !base.chars().chain(ext.chars()).all(is_short_name_char)
+    {
+        return None;
+    }
+    let mut nt_res = 0;
+    for (part, flag) in [(base, fat::NT_RES_BASE_LOWER), (ext, fat::NT_RES_EXT_LOWER)] {
+        let lower = part.chars().any(|c| c.is_ascii_lowercase());
+        if lower && part.chars().any(|c| c.is_ascii_uppercase()) {
+            return None;
+        }
+        if lower {
+            nt_res |= flag;
+        }
+    }
+    let mut short_name = [b' '; 11];
+    for (i, c) in base.bytes().enumerate() {
+        short_name[i] = c.to_ascii_uppercase();
+    }
+    for (i, c) in ext.bytes().enumerate() {
+        short_name[8 + i] = c.to_ascii_uppercase();
+    }
+    Some((short_name, nt_res))
+}
+
+/// A `BASE~N.EXT` alias of a name requiring a long name.
+fn short_name_alias(name: &str, is_taken: impl Fn(&[u8; 11]) -> bool) -> [u8; 11] {
+    let (base, ext) = match name.rsplit_once('.') {
+        Some((base, ext)) if !base.is_empty() => (base, ext),
+        _ => (name, ""),
+    };
+    let filter = |s: &str, n: usize| {
+        s.chars()
+            .filter(|c| is_short_name_char(*c) && *c != '~')
+            .map(|c| c.to_ascii_uppercase() as u8)
+            .take(n)
+            .collect::<Vec<_>>()
+    };
+    let mut base = filter(base, 6);
+    if base.is_empty() {
+        base.push(b'_');
+    }
+    let ext = filter(ext, 3);
+    (1..)
+        .map(|n| {
+            let tail = format!("~{}", n);
+            let mut alias = [b' '; 11];
+            let base = &base[..base.len().min(8 - tail.len())];
+            alias[..base.len()].copy_from_slice(base);
+            alias[base.len()..base.len() + tail.len()].copy_from_slice(tail.as_bytes());
+            alias[8..8 + ext.len()].copy_from_slice(&ext);
+            alias
+        })
+        .find(|alias| !is_taken(alias))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build(src: &str) -> Fixture {
+        super::build(&src.parse().unwrap()).unwrap()
+    }
+
+    fn lookup_expectation<'a>(expectations: &'a [Expectation], p: &str) -> Option<&'a Expectation> {
+        expectations.iter().find(|e| match e {
+            Expectation::Dir { path, .. }
+            | Expectation::File { path, .. }
+            | Expectation::Missing { path } => path == p,
+        })
+    }
+
+    #[test]
+    fn test_short_name() {
+        assert_eq!(short_name("FILE.TXT"), Some((*b"FILE    TXT", 0)));
+        assert_eq!(
+            short_name("file.TXT"),
+            Some((*b"FILE    TXT", fat::NT_RES_BASE_LOWER))
+        );
+        assert_eq!(short_name("README"), Some((*b"README     ", 0)));
+        assert_eq!(short_name("File.txt"), None);
+        assert_eq!(short_name("longfilename"), None);
+        assert_eq!(short_name("a.b.c"), None);
+        assert_eq!(short_name("a."), None);
+        assert_eq!(short_name("a b"), None);
+
+        assert_eq!(
+            short_name_alias("Long File.Text", |_| false),
+            *b"LONGFI~1TEX"
+        );
+        assert_eq!(
+            short_name_alias("Long File.Text", |n| n == b"LONGFI~1TEX"),
+            *b"LONGFI~2TEX"
+        );
+        assert_eq!(short_name_alias(".hidden", |_| false), *b"HIDDEN~1   ");
+    }
+
+    #[test]
+    fn test_layout() {
+        let fixture = build(
+            r#"
+            ors-mkimage 1
+            geometry clusters=64
+            volume label=TEST
+            file /a.bin pattern=1500 fragmented
+            file "/Long Name.txt" text="hello"
+            dir /d/e
+            "#,
+        );
+        assert_eq!(fixture.sector_size, 512);
+        let image = &fixture.image;
+        let fat_size = (66 * 4 + 511) / 512;
+        assert_eq!(image.len(), (32 + fat_size * 2 + 64) * 512);
+        assert_eq!(&image[510..512], &[0x55, 0xaa]);
+        assert_eq!(&image[71..82], b"TEST       ");
+        assert_eq!(image[..512], image[BACKUP_BOOT_SECTOR * 512..][..512]);
+
+        // root: 2, a.bin: 3 5 7, Long Name.txt: 4, d: 6, e: 8
+        let fat = |n: usize| {
+            let offset = 32 * 512 + n * 4;
+            u32::from_le_bytes(image[offset..offset + 4].try_into().unwrap())
+        };
+        assert_eq!(
+            (2..10).map(fat).collect::<Vec<_>>(),
+            vec![
+                fat::END_OF_CHAIN,
+                5,
+                fat::END_OF_CHAIN,
+                7,
+                fat::END_OF_CHAIN,
+                fat::END_OF_CHAIN,
+                fat::END_OF_CHAIN,
+                0
+            ]
+        );
+
+        // The root directory: SFN of a.bin, 1 LFN + SFN of Long Name.txt, SFN of d
+        let root = (32 + fat_size * 2) * 512;
+        assert_eq!(&image[root..root + 11], b"A       BIN");
+        assert_eq!(image[root + 32], 0x41);
+        assert_eq!(&image[root + 64..root + 75], b"LONGNA~1TXT");
+        assert_eq!(image[root + 32 + 13], fat::lfn_checksum(b"LONGNA~1TXT"));
+        assert_eq!(&image[root + 96..root + 107], b"D          ");
+        assert_eq!(image[root + 128], 0);
+
+        assert_eq!(
+            fixture.expectations[0],
+            Expectation::Dir {
+                path: String::from("/"),
+                entries: vec![
+                    String::from("a.bin"),
+                    String::from("Long Name.txt"),
+                    String::from("d")
+                ],
+            }
+        );
+        let data = (0..1500).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        assert!(matches!(
+            lookup_expectation(&fixture.expectations, "/a.bin"),
+            Some(Expectation::File { size: 1500, len: 1500, hash, .. }) if *hash == content_hash(&data)
+        ));
+    }
+
+    #[test]
+    fn test_corruptions() {
+        let fixture = build(
+            r#"
+            ors-mkimage 1
+            file /a.bin pattern=1500
+            file /b.bin pattern=700
+            file "/Long Name.txt" text="hello"
+            corrupt lfn-checksum "/Long Name.txt"
+            corrupt cross-link /b.bin /a.bin
+            corrupt truncate-chain /b.bin 1
+            "#,
+        );
+        let e = &fixture.expectations;
+        assert_eq!(
+            lookup_expectation(e, "/Long Name.txt"),
+            Some(&Expectation::Missing {
+                path: String::from("/Long Name.txt")
+            })
+        );
+        assert!(matches!(
+            lookup_expectation(e, "/LONGNA~1.TXT"),
+            Some(Expectation::File {
+                size: 5,
+                len: 5,
+                ..
+            })
+        ));
+        // a.bin refers to the chain of b.bin, which is truncated to a cluster
+        let data = (0..512).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        assert!(matches!(
+            lookup_expectation(e, "/a.bin"),
+            Some(Expectation::File { size: 1500, len: 512, hash, .. }) if *hash == content_hash(&data)
+        ));
+        assert!(matches!(
+            lookup_expectation(e, "/b.bin"),
+            Some(Expectation::File {
+                size: 700,
+                len: 512,
+                ..
+            })
+        ));
+
+        let manifest = "ors-mkimage 1\nfile /a text=x\ncorrupt lfn-checksum /a"
+            .parse()
+            .unwrap();
+        assert!(matches!(super::build(&manifest), Err(Error::Layout(_))));
+        let manifest = "ors-mkimage 1\nfile /a pattern=600\ncorrupt truncate-chain /a 2"
+            .parse()
+            .unwrap();
+        assert!(matches!(super::build(&manifest), Err(Error::Layout(_))));
+    }
+}
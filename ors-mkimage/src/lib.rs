@@ -0,0 +1,110 @@
+//! Host-side builder of FAT32 disk images used as kernel test fixtures.
+//!
+//! An image is built from a declarative manifest (see `manifest`), optionally with deliberate
+//! corruptions. Along with the image, the results expected to be observed through a correct
+//! FAT implementation are emitted as a Rust expression, which the kernel tests `include!`.
+
+use std::fmt;
+
+mod image;
+pub mod manifest;
+
+pub use manifest::Manifest;
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum Error {
+    /// Syntax error at the given line (1-origin, 0 for the whole manifest).
+    Manifest(usize, String),
+    /// The manifest is well-formed but cannot be realized.
+    Layout(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Manifest(0, message) => write!(f, "manifest: {}", message),
+            Self::Manifest(line, message) => write!(f, "manifest:{}: {}", line, message),
+            Self::Layout(message) => write!(f, "layout: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// A built image and its expected results.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Fixture {
+    pub sector_size: usize,
+    pub image: Vec<u8>,
+    pub expectations: Vec<Expectation>,
+}
+
+impl Fixture {
+    /// The expectations as a Rust expression of type `[Expectation; N]`.
+    pub fn expectations_source(&self) -> String {
+        let mut s = String::from("// Generated by ors-mkimage. Do not edit.\n[\n");
+        for e in &self.expectations {
+            s.push_str(&format!("    {},\n", e));
+        }
+        s.push(']');
+        s
+    }
+}
+
+/// A result expected to be observed through the file system of a fixture.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum Expectation {
+    /// The directory lists exactly these entries in this order (without `.` and `..`).
+    Dir { path: String, entries: Vec<String> },
+    /// The file exists with these attributes. `size` is the size recorded in the directory entry,
+    /// while `len` and `hash` (`content_hash`) describe the contents actually readable.
+    File {
+        path: String,
+        size: usize,
+        len: usize,
+        hash: u64,
+        read_only: bool,
+        hidden: bool,
+        system: bool,
+    },
+    /// Nothing is found at the path.
+    Missing { path: String },
+}
+
+impl fmt::Display for Expectation {
+    /// Rendered as a Rust expression.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Dir { path, entries } => {
+                write!(f, "Expectation::Dir {{ path: {:?}, entries: &{:?} }}", path, entries)
+            }
+            Self::File {
+                path,
+                size,
+                len,
+                hash,
+                read_only,
+                hidden,
+                system,
+            } => write!(
+                f,
+                "Expectation::File {{ path: {:?}, size: {}, len: {}, hash: {:#018x}, read_only: {}, hidden: {}, system: {} }}",
+                path, size, len, hash, read_only, hidden, system
+            ),
+            Self::Missing { path } => write!(f, "Expectation::Missing {{ path: {:?} }}", path),
+        }
+    }
+}
+
+/// Build a fixture from the source of a manifest.
+pub fn build(manifest: &str) -> Result<Fixture, Error> {
+    let manifest = manifest.parse::<Manifest>()?;
+    image::build(&manifest)
+}
+
+/// FNV-1a hash of file contents, used to compare contents without embedding them.
+pub fn content_hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, b| {
+        (hash ^ *b as u64).wrapping_mul(0x100000001b3)
+    })
+}
@@ -0,0 +1,31 @@
+use std::env;
+use std::fs;
+use std::process;
+
+fn main() {
+    let args = env::args().collect::<Vec<_>>();
+    let (manifest, image, expectations) = match args.as_slice() {
+        [_, manifest, image] => (manifest, image, None),
+        [_, manifest, image, expectations] => (manifest, image, Some(expectations)),
+        _ => {
+            eprintln!("Usage: ors-mkimage <manifest> <image> [<expectations.rs>]");
+            process::exit(2);
+        }
+    };
+
+    let fixture = fs::read_to_string(manifest)
+        .map_err(|e| e.to_string())
+        .and_then(|src| ors_mkimage::build(&src).map_err(|e| e.to_string()))
+        .unwrap_or_else(|e| {
+            eprintln!("{}: {}", manifest, e);
+            process::exit(1);
+        });
+    let result = fs::write(image, &fixture.image).and_then(|_| match expectations {
+        Some(path) => fs::write(path, fixture.expectations_source()),
+        None => Ok(()),
+    });
+    if let Err(e) = result {
+        eprintln!("ors-mkimage: {}", e);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,351 @@
+//! Manifest describing the contents of an image.
+//!
+//! A manifest is a sequence of directives, one per line. `#` starts a comment, and a token
+//! containing spaces is written in double quotes (`\n`, `\t`, `\"`, `\\` and `\xHH` escapes are
+//! available in quotes). The first directive must be `ors-mkimage <version>`.
+//!
+//! ```text
+//! ors-mkimage 1
+//! geometry sector-size=512 sectors-per-cluster=1 clusters=256
+//! volume id=0x1234abcd label=FIXTURES
+//! dir /a/b/c
+//! file /hello.txt text="Hello, World!\n"
+//! file "/Long File Name.dat" pattern=3000 fragmented
+//! attr /hello.txt read-only hidden system
+//! corrupt lfn-checksum "/Long File Name.dat"
+//! corrupt cross-link /hello.txt /a/b/c/data.bin
+//! corrupt truncate-chain "/Long File Name.dat" 2
+//! ```
+//!
+//! Directories and files are laid out in the order of the directives, and their parents are
+//! created implicitly. Corruptions are applied after the layout.
+
+use crate::Error;
+use std::str::FromStr;
+
+/// Version of the manifest format understood by this tool.
+pub const FORMAT_VERSION: u32 = 1;
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Manifest {
+    pub geometry: Geometry,
+    pub volume_id: u32,
+    pub volume_label: String,
+    pub directives: Vec<Directive>,
+    pub corruptions: Vec<Corruption>,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct Geometry {
+    pub sector_size: usize,
+    pub sectors_per_cluster: usize,
+    pub clusters: usize,
+    pub reserved_sectors: usize,
+}
+
+impl Default for Geometry {
+    fn default() -> Self {
+        Self {
+            sector_size: 512,
+            sectors_per_cluster: 1,
+            clusters: 256,
+            reserved_sectors: 32,
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum Directive {
+    Dir(String),
+    File {
+        path: String,
+        contents: Vec<u8>,
+        fragmented: bool,
+    },
+    Attr {
+        path: String,
+        read_only: bool,
+        hidden: bool,
+        system: bool,
+    },
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum Corruption {
+    /// Break the checksum of the LFN entries of the file, which exposes its short name alias.
+    LfnChecksum(String),
+    /// Make the second file refer to the first cluster of the first file.
+    CrossLink(String, String),
+    /// Terminate the cluster chain of the file after the given number of clusters.
+    TruncateChain(String, usize),
+}
+
+impl FromStr for Manifest {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut version = None;
+        let mut manifest = Manifest {
+            geometry: Geometry::default(),
+            volume_id: 0,
+            volume_label: String::from("NO NAME"),
+            directives: Vec::new(),
+            corruptions: Vec::new(),
+        };
+        for (i, line) in s.lines().enumerate() {
+            let line_number = i + 1;
+            let error = |message: String| Error::Manifest(line_number, message);
+            let tokens = tokenize(line).map_err(error)?;
+            let (command, args) = match tokens.split_first() {
+                Some((command, args)) => (command.as_str(), args),
+                None => continue,
+            };
+            if version.is_none() && command != "ors-mkimage" {
+                return Err(error(String::from(
+                    "Expected `ors-mkimage <version>` first",
+                )));
+            }
+            match command {
+                "ors-mkimage" => {
+                    let v = match args {
+                        [v] if version.is_none() => parse_number(v).map_err(error)?,
+                        _ => return Err(error(String::from("Unexpected `ors-mkimage`"))),
+                    };
+                    if v != FORMAT_VERSION as usize {
+                        return Err(error(format!("Unsupported format version: {}", v)));
+                    }
+                    version = Some(v);
+                }
+                "geometry" => {
+                    for (key, value) in options(args).map_err(error)? {
+                        let value = parse_number(value).map_err(error)?;
+                        let g = &mut manifest.geometry;
+                        match key {
+                            "sector-size" => g.sector_size = value,
+                            "sectors-per-cluster" => g.sectors_per_cluster = value,
+                            "clusters" => g.clusters = value,
+                            "reserved-sectors" => g.reserved_sectors = value,
+                            _ => return Err(error(format!("Unknown geometry: {}", key))),
+                        }
+                    }
+                }
+                "volume" => {
+                    for (key, value) in options(args).map_err(error)? {
+                        match key {
+                            "id" => {
+                                let id = parse_number(value).map_err(error)?;
+                                manifest.volume_id = u32::try_from(id).map_err(|_| {
+                                    error(format!("Volume id out of range: {}", id))
+                                })?;
+                            }
+                            "label" => manifest.volume_label = String::from(value),
+                            _ => return Err(error(format!("Unknown volume option: {}", key))),
+                        }
+                    }
+                }
+                "dir" => match args {
+                    [path] => manifest.directives.push(Directive::Dir(path.clone())),
+                    _ => return Err(error(String::from("Usage: dir <path>"))),
+                },
+                "file" => {
+                    let (path, args) = args
+                        .split_first()
+                        .ok_or_else(|| error(String::from("Usage: file <path> <contents>..")))?;
+                    let mut contents = None;
+                    let mut fragmented = false;
+                    for arg in args {
+                        match arg.split_once('=') {
+                            Some(("text", text)) => contents = Some(text.as_bytes().to_vec()),
+                            Some(("pattern", size)) => {
+                                let size = parse_number(size).map_err(error)?;
+                                contents = Some((0..size).map(|i| (i % 251) as u8).collect());
+                            }
+                            None if arg == "fragmented" => fragmented = true,
+                            _ => return Err(error(format!("Unknown file option: {}", arg))),
+                        }
+                    }
+                    manifest.directives.push(Directive::File {
+                        path: path.clone(),
+                        contents: contents.unwrap_or_default(),
+                        fragmented,
+                    });
+                }
+                "attr" => {
+                    let (path, args) = args
+                        .split_first()
+                        .ok_or_else(|| error(String::from("Usage: attr <path> <attr>..")))?;
+                    let (mut read_only, mut hidden, mut system) = (false, false, false);
+                    for arg in args {
+                        match arg.as_str() {
+                            "read-only" => read_only = true,
+                            "hidden" => hidden = true,
+                            "system" => system = true,
+                            _ => return Err(error(format!("Unknown attribute: {}", arg))),
+                        }
+                    }
+                    manifest.directives.push(Directive::Attr {
+                        path: path.clone(),
+                        read_only,
+                        hidden,
+                        system,
+                    });
+                }
+                "corrupt" => {
+                    let corruption = match args {
+                        [kind, path] if kind == "lfn-checksum" => {
+                            Corruption::LfnChecksum(path.clone())
+                        }
+                        [kind, src, dest] if kind == "cross-link" => {
+                            Corruption::CrossLink(src.clone(), dest.clone())
+                        }
+                        [kind, path, n] if kind == "truncate-chain" => {
+                            Corruption::TruncateChain(path.clone(), parse_number(n).map_err(error)?)
+                        }
+                        _ => return Err(error(String::from("Unknown corruption"))),
+                    };
+                    manifest.corruptions.push(corruption);
+                }
+                _ => return Err(error(format!("Unknown directive: {}", command))),
+            }
+        }
+        if version.is_none() {
+            return Err(Error::Manifest(0, String::from("Empty manifest")));
+        }
+        Ok(manifest)
+    }
+}
+
+fn tokenize(line: &str) -> Result<Vec<String>, String> {
+    let mut tokens = Vec::new();
+    let mut token: Option<String> = None;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '#' => break,
+            c if c.is_whitespace() => tokens.extend(token.take()),
+            '"' => {
+                let token = token.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => token.push(unescape(&mut chars)?),
+                        Some(c) => token.push(c),
+                        None => Err("Unterminated quote")?,
+                    }
+                }
+            }
+            c => token.get_or_insert_with(String::new).push(c),
+        }
+    }
+    tokens.extend(token);
+    Ok(tokens)
+}
+
+fn unescape(chars: &mut std::str::Chars) -> Result<char, String> {
+    Ok(match chars.next() {
+        Some('n') => '\n',
+        Some('t') => '\t',
+        Some('0') => '\0',
+        Some('"') => '"',
+        Some('\\') => '\\',
+        Some('x') => {
+            let hex = chars.take(2).collect::<String>();
+            u8::from_str_radix(&hex, 16).map_err(|_| format!("Invalid escape: \\x{}", hex))? as char
+        }
+        c => Err(format!("Invalid escape: {:?}", c))?,
+    })
+}
+
+fn options(args: &[String]) -> Result<Vec<(&str, &str)>, String> {
+    args.iter()
+        .map(|arg| {
+            arg.split_once('=')
+                .ok_or_else(|| format!("Expected key=value: {}", arg))
+        })
+        .collect()
+}
+
+fn parse_number(s: &str) -> Result<usize, String> {
+    match s.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+    .map_err(|_| format!("Invalid number: {}", s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tokenize() {
+        assert_eq!(tokenize("  # comment"), Ok(vec![]));
+        assert_eq!(
+            tokenize(r#"file "/a b.txt" text="x\ty\n" # comment"#),
+            Ok(vec![
+                String::from("file"),
+                String::from("/a b.txt"),
+                String::from("text=x\ty\n"),
+            ])
+        );
+        assert_eq!(tokenize(r#"a"\x41""#), Ok(vec![String::from("aA")]));
+        assert!(tokenize(r#"file "/a"#).is_err());
+        assert!(tokenize(r#"file "\q""#).is_err());
+    }
+
+    #[test]
+    fn test_parse() {
+        let manifest = r#"
+            ors-mkimage 1
+            geometry sector-size=4096 sectors-per-cluster=8
+            volume id=0x1234abcd label="MY DISK"
+            dir /a/b
+            file /a/hello.txt text="Hello"
+            file /data.bin pattern=300 fragmented
+            attr /a/hello.txt read-only system
+            corrupt truncate-chain /data.bin 1
+        "#
+        .parse::<Manifest>()
+        .unwrap();
+        assert_eq!(manifest.geometry.sector_size, 4096);
+        assert_eq!(manifest.geometry.sectors_per_cluster, 8);
+        assert_eq!(manifest.geometry.clusters, 256);
+        assert_eq!(manifest.volume_id, 0x1234abcd);
+        assert_eq!(manifest.volume_label, "MY DISK");
+        assert_eq!(manifest.directives.len(), 4);
+        assert!(matches!(
+            &manifest.directives[2],
+            Directive::File { contents, fragmented: true, .. } if contents.len() == 300 && contents[252] == 1
+        ));
+        assert_eq!(
+            manifest.directives[3],
+            Directive::Attr {
+                path: String::from("/a/hello.txt"),
+                read_only: true,
+                hidden: false,
+                system: true,
+            }
+        );
+        assert_eq!(
+            manifest.corruptions,
+            vec![Corruption::TruncateChain(String::from("/data.bin"), 1)]
+        );
+    }
+
+    #[test]
+    fn test_parse_error() {
+        for (src, line) in [
+            ("", 0),
+            ("dir /a", 1),
+            ("ors-mkimage 2", 1),
+            ("ors-mkimage 1\nors-mkimage 1", 2),
+            ("ors-mkimage 1\n\nfile /a size=3", 3),
+            ("ors-mkimage 1\ncorrupt cross-link /a", 2),
+        ] {
+            match src.parse::<Manifest>() {
+                Err(Error::Manifest(l, _)) => assert_eq!(l, line, "{:?}", src),
+                r => panic!("{:?}: {:?}", src, r),
+            }
+        }
+    }
+}
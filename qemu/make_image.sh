@@ -17,9 +17,10 @@ if [ ! -f $BOOTLOADER_EFI ]; then
 fi
 
 # Create a disk image and format it to FAT
+# The last 8MiB (after the first 200MiB) is left unformatted for crash dumps
 rm -f $DISK_IMG
-qemu-img create -f raw $DISK_IMG 200M
-mkfs.fat -n 'ORS' -s 2 -f 2 -R 32 -F 32 $DISK_IMG
+qemu-img create -f raw $DISK_IMG 208M
+mkfs.fat -n 'ORS' -s 2 -f 2 -R 32 -F 32 $DISK_IMG 204800
 
 # Initialize disk image
 mkdir -p $MOUNT_POINT
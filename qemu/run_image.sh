@@ -22,6 +22,8 @@ qemu-system-x86_64 \
   -drive if=none,id=drive0,format=raw,file=$DISK_IMG \
   -device isa-debug-exit,iobase=0xf4,iosize=0x04 \
   -device virtio-blk-pci,drive=drive0 \
+  -device virtio-keyboard-pci \
   -serial mon:stdio \
+  -fw_cfg name=opt/ors/test,string=fw_cfg-ok \
   $QEMU_OPTS
 [ $? -eq 33 -o $? -eq 0 ]